@@ -16,7 +16,7 @@ use serde_json;
 use logger::{Metric, METRICS};
 use mmds::data_store::Mmds;
 use request::actions::ActionBody;
-use request::drive::PatchDrivePayload;
+use request::drive::{DeleteDrivePayload, PatchDrivePayload};
 use request::{GenerateHyperResponse, IntoParsedRequest, ParsedRequest};
 use sys_util::EventFd;
 use vmm::vmm_config::boot_source::BootSourceConfig;
@@ -24,7 +24,10 @@ use vmm::vmm_config::drive::BlockDeviceConfig;
 use vmm::vmm_config::instance_info::InstanceInfo;
 use vmm::vmm_config::logger::LoggerConfig;
 use vmm::vmm_config::machine_config::VmConfig;
-use vmm::vmm_config::net::NetworkInterfaceConfig;
+use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
+use vmm::vmm_config::rate_limit_group::RateLimitGroupConfig;
+use vmm::vmm_config::snapshot::SnapshotConfig;
+use vmm::vmm_config::vm_state::VmStateConfig;
 #[cfg(feature = "vsock")]
 use vmm::vmm_config::vsock::VsockDeviceConfig;
 use vmm::VmmAction;
@@ -227,6 +230,17 @@ fn parse_drives_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'
             })?)
         }
 
+        1 if method == Method::Delete => {
+            METRICS.delete_api_requests.drive_count.inc();
+
+            Ok(DeleteDrivePayload
+                .into_parsed_request(Some(id_from_path.to_string()), method)
+                .map_err(|s| {
+                    METRICS.delete_api_requests.drive_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+
         _ => Err(Error::InvalidPathMethod(path, method)),
     }
 }
@@ -269,6 +283,7 @@ fn parse_machine_config_req<'a>(
                 mem_size_mib: None,
                 ht_enabled: None,
                 cpu_template: None,
+                acpi_only: None,
             };
             Ok(empty_machine_config
                 .into_parsed_request(None, method)
@@ -319,6 +334,114 @@ fn parse_netif_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a
                     Error::Generic(StatusCode::BadRequest, s)
                 })?)
         }
+
+        1 if method == Method::Patch => {
+            METRICS.patch_api_requests.network_count.inc();
+
+            Ok(serde_json::from_slice::<NetworkInterfaceUpdateConfig>(body)
+                .map_err(|e| {
+                    METRICS.patch_api_requests.network_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(Some(id_from_path.to_string()), method)
+                .map_err(|s| {
+                    METRICS.patch_api_requests.network_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
+// Turns a PUT /rate-limit-groups HTTP request into a ParsedRequest
+fn parse_rate_limit_groups_req<'a>(
+    path: &'a str,
+    method: Method,
+    body: &Chunk,
+) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+    let id_from_path = if path_tokens.len() > 1 {
+        checked_id(path_tokens[1])?
+    } else {
+        return Err(Error::EmptyID);
+    };
+
+    match path_tokens[1..].len() {
+        1 if method == Method::Put => {
+            METRICS.put_api_requests.rate_limit_group_count.inc();
+
+            Ok(serde_json::from_slice::<RateLimitGroupConfig>(body)
+                .map_err(|e| {
+                    METRICS.put_api_requests.rate_limit_group_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(Some(id_from_path.to_string()), method)
+                .map_err(|s| {
+                    METRICS.put_api_requests.rate_limit_group_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
+// Turns a PUT /snapshot/create or /snapshot/load HTTP request into a ParsedRequest
+fn parse_snapshot_req<'a>(
+    path: &'a str,
+    method: Method,
+    body: &Chunk,
+) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens[1..].len() {
+        1 if method == Method::Put && path_tokens[1] == "create" => {
+            METRICS.put_api_requests.snapshot_create_count.inc();
+            Ok(serde_json::from_slice::<SnapshotConfig>(body)
+                .map_err(|e| {
+                    METRICS.put_api_requests.snapshot_create_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(Some(path_tokens[1].to_string()), method)
+                .map_err(|s| {
+                    METRICS.put_api_requests.snapshot_create_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        1 if method == Method::Put && path_tokens[1] == "load" => {
+            METRICS.put_api_requests.snapshot_load_count.inc();
+            Ok(serde_json::from_slice::<SnapshotConfig>(body)
+                .map_err(|e| {
+                    METRICS.put_api_requests.snapshot_load_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(Some(path_tokens[1].to_string()), method)
+                .map_err(|s| {
+                    METRICS.put_api_requests.snapshot_load_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
+        _ => Err(Error::InvalidPathMethod(path, method)),
+    }
+}
+
+// Turns a PATCH /vm HTTP request into a ParsedRequest
+fn parse_vm_req<'a>(path: &'a str, method: Method, body: &Chunk) -> Result<'a, ParsedRequest> {
+    let path_tokens: Vec<&str> = path[1..].split_terminator('/').collect();
+
+    match path_tokens[1..].len() {
+        0 if method == Method::Patch => {
+            METRICS.patch_api_requests.vm_count.inc();
+            Ok(serde_json::from_slice::<VmStateConfig>(body)
+                .map_err(|e| {
+                    METRICS.patch_api_requests.vm_fails.inc();
+                    Error::SerdeJson(e)
+                })?
+                .into_parsed_request(None, method)
+                .map_err(|s| {
+                    METRICS.patch_api_requests.vm_fails.inc();
+                    Error::Generic(StatusCode::BadRequest, s)
+                })?)
+        }
         _ => Err(Error::InvalidPathMethod(path, method)),
     }
 }
@@ -390,6 +513,9 @@ fn parse_request<'a>(method: Method, path: &'a str, body: &Chunk) -> Result<'a,
         "machine-config" => parse_machine_config_req(path, method, body),
         "network-interfaces" => parse_netif_req(path, method, body),
         "mmds" => parse_mmds_request(path, method, body),
+        "rate-limit-groups" => parse_rate_limit_groups_req(path, method, body),
+        "snapshot" => parse_snapshot_req(path, method, body),
+        "vm" => parse_vm_req(path, method, body),
         #[cfg(feature = "vsock")]
         "vsocks" => parse_vsocks_req(path, method, body),
         _ => Err(Error::InvalidPathMethod(path, method)),
@@ -827,6 +953,7 @@ mod tests {
         let boot_source_path = "/boot-source";
         let boot_source_json = r#"{
                 "kernel_image_path": "/foo/bar",
+                "initrd_path": "/foo/initrd",
                 "boot_args": "baz"
               }"#;
         let body: Chunk = Chunk::from(boot_source_json);
@@ -888,7 +1015,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: true,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         match drive_desc.into_parsed_request(Some(String::from("id_1")), Method::Put) {
@@ -977,6 +1111,16 @@ mod tests {
         ));
         let body: Chunk = Chunk::from(json);
         assert!(parse_drives_req("/foo/bar", Method::Patch, &body) == expected_error);
+
+        // DELETE
+        match DeleteDrivePayload.into_parsed_request(Some("id_1".to_string()), Method::Delete) {
+            Ok(pr) => match parse_drives_req(valid_drive_path, Method::Delete, &Chunk::from("")) {
+                Ok(pr_drive) => assert!(pr.eq(&pr_drive)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+        assert!(parse_drives_req("/drives", Method::Delete, &body) == Err(Error::EmptyID));
     }
 
     #[test]
@@ -1024,7 +1168,8 @@ mod tests {
                 \"vcpu_count\": 42,
                 \"mem_size_mib\": 1025,
                 \"ht_enabled\": true,
-                \"cpu_template\": \"T2\"
+                \"cpu_template\": \"T2\",
+                \"acpi_only\": true
               }";
         let body: Chunk = Chunk::from(json);
 
@@ -1042,6 +1187,7 @@ mod tests {
             mem_size_mib: Some(1025),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            acpi_only: Some(true),
         };
 
         match vm_config.into_parsed_request(None, Method::Put) {
@@ -1085,8 +1231,16 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str("12:34:56:78:9a:BC").unwrap()),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
             allow_mmds_requests: false,
-            tap: None,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
         };
 
         match netif.into_parsed_request(Some(net_id), Method::Put) {
@@ -1113,13 +1267,183 @@ mod tests {
                 == Err(Error::SerdeJson(get_dummy_serde_error()))
         );
 
+        // PATCH
+        let path = "/network-interfaces/id_1";
+        let json = r#"{
+                "iface_id": "id_1",
+                "rx_rate_limiter": {
+                    "ops": { "size": 10, "refill_time": 1000 }
+                }
+              }"#;
+        let update_config: NetworkInterfaceUpdateConfig = serde_json::from_str(json).unwrap();
+        match update_config.into_parsed_request(Some(String::from("id_1")), Method::Patch) {
+            Ok(pr) => match parse_netif_req(&path, Method::Patch, &Chunk::from(json)) {
+                Ok(pr_netif) => assert!(pr.eq(&pr_netif)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // Error Case: Only the rate limiters can be updated.
+        assert!(
+            parse_netif_req(path, Method::Patch, &body)
+                == Err(Error::SerdeJson(get_dummy_serde_error()))
+        );
+
         // Error Case: Invalid Path.
         assert!(
-            parse_netif_req(path, Method::Patch, &body,)
-                == Err(Error::InvalidPathMethod(path, Method::Patch))
+            parse_netif_req(path, Method::Delete, &body,)
+                == Err(Error::InvalidPathMethod(path, Method::Delete))
         )
     }
 
+    #[test]
+    fn test_parse_rate_limit_groups_req() {
+        let path = "/rate-limit-groups/tenant";
+        let json = r#"{
+                "group_id": "tenant",
+                "ops": { "size": 1000, "refill_time": 1000 }
+              }"#;
+        let body: Chunk = Chunk::from(json);
+
+        // PUT
+        let group: RateLimitGroupConfig = serde_json::from_str(json).unwrap();
+        match group.into_parsed_request(Some(String::from("tenant")), Method::Put) {
+            Ok(pr) => match parse_rate_limit_groups_req(&path, Method::Put, &body) {
+                Ok(pr_group) => assert!(pr.eq(&pr_group)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: The id from the path does not match the id from the body.
+        let expected_err = Err(Error::Generic(
+            StatusCode::BadRequest,
+            String::from("The id from the path does not match the id from the body!"),
+        ));
+        let path = "/rate-limit-groups/foo";
+        assert!(parse_rate_limit_groups_req(path, Method::Put, &body) == expected_err);
+
+        // Error Case: Invalid payload.
+        assert!(
+            parse_rate_limit_groups_req(path, Method::Put, &Chunk::from("foo bar"))
+                == Err(Error::SerdeJson(get_dummy_serde_error()))
+        );
+
+        // Error Case: Invalid method.
+        assert!(
+            parse_rate_limit_groups_req(path, Method::Patch, &body)
+                == Err(Error::InvalidPathMethod(path, Method::Patch))
+        );
+
+        // Error Case: Empty ID.
+        assert!(
+            parse_rate_limit_groups_req("/rate-limit-groups", Method::Put, &body)
+                == Err(Error::EmptyID)
+        );
+    }
+
+    #[test]
+    fn test_parse_snapshot_req() {
+        let json = "{
+                \"snapshot_path\": \"/foo/bar\"
+              }";
+        let body: Chunk = Chunk::from(json);
+        let snapshot_config = SnapshotConfig {
+            snapshot_path: String::from("/foo/bar"),
+        };
+
+        // PUT /snapshot/create
+        let path = "/snapshot/create";
+        match snapshot_config
+            .clone()
+            .into_parsed_request(Some(String::from("create")), Method::Put)
+        {
+            Ok(pr) => match parse_snapshot_req(&path, Method::Put, &body) {
+                Ok(pr_snapshot) => assert!(pr.eq(&pr_snapshot)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // PUT /snapshot/load
+        let path = "/snapshot/load";
+        match snapshot_config
+            .clone()
+            .into_parsed_request(Some(String::from("load")), Method::Put)
+        {
+            Ok(pr) => match parse_snapshot_req(&path, Method::Put, &body) {
+                Ok(pr_snapshot) => assert!(pr.eq(&pr_snapshot)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: Invalid payload.
+        assert!(
+            parse_snapshot_req(path, Method::Put, &Chunk::from("foo bar"))
+                == Err(Error::SerdeJson(get_dummy_serde_error()))
+        );
+
+        // Error Case: Invalid method.
+        assert!(
+            parse_snapshot_req(path, Method::Get, &body)
+                == Err(Error::InvalidPathMethod(path, Method::Get))
+        );
+
+        // Error Case: Invalid path.
+        for path in vec!["/snapshot", "/snapshot/foo", "/snapshot/create/foo"] {
+            assert!(
+                parse_snapshot_req(path, Method::Put, &body)
+                    == Err(Error::InvalidPathMethod(path, Method::Put))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_vm_req() {
+        let path = "/vm";
+        let json = "{
+                \"state\": \"Paused\"
+              }";
+        let body: Chunk = Chunk::from(json);
+
+        // PATCH
+        match serde_json::from_slice::<VmStateConfig>(&body)
+            .unwrap()
+            .into_parsed_request(None, Method::Patch)
+        {
+            Ok(pr) => match parse_vm_req(&path, Method::Patch, &body) {
+                Ok(pr_vm) => assert!(pr.eq(&pr_vm)),
+                _ => assert!(false),
+            },
+            _ => assert!(false),
+        }
+
+        // Error cases
+        // Error Case: Invalid payload.
+        let body = Chunk::from("{ \"state\": \"Stopped\" }");
+        assert!(
+            parse_vm_req(path, Method::Patch, &body)
+                == Err(Error::SerdeJson(get_dummy_serde_error()))
+        );
+
+        // Error Case: Invalid method.
+        assert!(
+            parse_vm_req(path, Method::Put, &body)
+                == Err(Error::InvalidPathMethod(path, Method::Put))
+        );
+
+        // Error Case: Invalid path.
+        let path = "/vm/foo";
+        assert!(
+            parse_vm_req(path, Method::Patch, &body)
+                == Err(Error::InvalidPathMethod(path, Method::Patch))
+        );
+    }
+
     #[test]
     fn test_parse_mmds_request() {
         let path = "/mmds";
@@ -15,6 +15,7 @@ extern crate fc_util;
 #[macro_use]
 extern crate logger;
 extern crate mmds;
+extern crate rate_limiter;
 extern crate sys_util;
 extern crate vmm;
 
@@ -17,6 +17,7 @@ use vmm::VmmAction;
 enum ActionType {
     BlockDeviceRescan,
     InstanceStart,
+    SendCtrlAltDel,
 }
 
 // The model of the json body from a sync request. We use Serde to transform each associated
@@ -53,6 +54,13 @@ fn validate_payload(action_body: &ActionBody) -> Result<(), String> {
             }
             Ok(())
         }
+        ActionType::SendCtrlAltDel => {
+            // SendCtrlAltDel does not have a payload
+            if !action_body.payload.is_none() {
+                return Err("SendCtrlAltDel does not support a payload.".to_string());
+            }
+            Ok(())
+        }
     }
 }
 
@@ -80,6 +88,13 @@ impl IntoParsedRequest for ActionBody {
                     sync_receiver,
                 ))
             }
+            ActionType::SendCtrlAltDel => {
+                let (sync_sender, sync_receiver) = oneshot::channel();
+                Ok(ParsedRequest::Sync(
+                    VmmAction::SendCtrlAltDel(sync_sender),
+                    sync_receiver,
+                ))
+            }
         }
     }
 }
@@ -104,6 +119,19 @@ mod tests {
         };
         assert!(validate_payload(&action_body).is_err());
 
+        // Test SendCtrlAltDel.
+        let action_body = ActionBody {
+            action_type: ActionType::SendCtrlAltDel,
+            payload: None,
+        };
+        assert!(validate_payload(&action_body).is_ok());
+        // Error case: SendCtrlAltDel with payload.
+        let action_body = ActionBody {
+            action_type: ActionType::SendCtrlAltDel,
+            payload: Some(Value::String("dummy-payload".to_string())),
+        };
+        assert!(validate_payload(&action_body).is_err());
+
         // Test BlockDeviceRescan
         let action_body = ActionBody {
             action_type: ActionType::BlockDeviceRescan,
@@ -161,5 +189,22 @@ mod tests {
                 .unwrap()
                 .eq(&req));
         }
+
+        {
+            let json = r#"{
+                "action_type": "SendCtrlAltDel"
+            }"#;
+
+            let (sender, receiver) = oneshot::channel();
+            let req: ParsedRequest =
+                ParsedRequest::Sync(VmmAction::SendCtrlAltDel(sender), receiver);
+            let result: Result<ActionBody, serde_json::Error> = serde_json::from_str(json);
+            assert!(result.is_ok());
+            assert!(result
+                .unwrap()
+                .into_parsed_request(None, Method::Put)
+                .unwrap()
+                .eq(&req));
+        }
     }
 }
@@ -5,9 +5,9 @@ use std::result;
 
 use futures::sync::oneshot;
 use hyper::Method;
-use serde_json::{Map, Value};
+use serde_json::{self, Map, Value};
 
-use vmm::vmm_config::drive::BlockDeviceConfig;
+use vmm::vmm_config::drive::{BlockDeviceConfig, BlockRateLimiterUpdate};
 use vmm::VmmAction;
 
 use request::{IntoParsedRequest, ParsedRequest};
@@ -40,21 +40,24 @@ impl PatchDrivePayload {
         Ok(())
     }
 
-    /// Validates that only path_on_host and drive_id are present in the payload.
+    /// Validates that only drive_id and either path_on_host or rate_limiter are present in the
+    /// payload.
     fn validate(&self) -> result::Result<(), String> {
         match self.fields.as_object() {
             Some(fields_map) => {
                 // Check that field `drive_id` exists and its type is String.
                 PatchDrivePayload::check_field_is_string(fields_map, "drive_id")?;
-                // Check that field `drive_id` exists and its type is String.
-                PatchDrivePayload::check_field_is_string(fields_map, "path_on_host")?;
+                // Check that field `path_on_host` exists and its type is String, unless the
+                // rate limiter is the one being updated.
+                if !fields_map.contains_key("rate_limiter") {
+                    PatchDrivePayload::check_field_is_string(fields_map, "path_on_host")?;
+                }
 
                 // Check that there are no other fields in the object.
                 if fields_map.len() > 2 {
-                    return Err(
-                        "Invalid PATCH payload. Only updates on path_on_host are allowed."
-                            .to_string(),
-                    );
+                    return Err("Invalid PATCH payload. Only updates on path_on_host or \
+                                rate_limiter are allowed, one at a time."
+                        .to_string());
                 }
                 Ok(())
             }
@@ -84,7 +87,6 @@ impl IntoParsedRequest for PatchDrivePayload {
             Method::Patch => {
                 self.validate()?;
                 let drive_id: String = self.get_string_field_unchecked("drive_id");
-                let path_on_host: String = self.get_string_field_unchecked("path_on_host");
 
                 let id_from_path = id_from_path.unwrap_or(String::new());
                 if id_from_path != drive_id {
@@ -94,6 +96,17 @@ impl IntoParsedRequest for PatchDrivePayload {
                 }
 
                 let (sender, receiver) = oneshot::channel();
+                if let Some(rate_limiter) = self.fields.get("rate_limiter") {
+                    let update =
+                        serde_json::from_value::<BlockRateLimiterUpdate>(rate_limiter.clone())
+                            .map_err(|e| format!("Invalid rate_limiter: {}", e))?;
+                    return Ok(ParsedRequest::Sync(
+                        VmmAction::UpdateBlockDeviceRateLimiter(drive_id, update, sender),
+                        receiver,
+                    ));
+                }
+
+                let path_on_host: String = self.get_string_field_unchecked("path_on_host");
                 Ok(ParsedRequest::Sync(
                     VmmAction::UpdateBlockDevicePath(drive_id, path_on_host, sender),
                     receiver,
@@ -104,6 +117,29 @@ impl IntoParsedRequest for PatchDrivePayload {
     }
 }
 
+/// A DELETE request on a drive, which only carries the id of the drive in its path.
+pub struct DeleteDrivePayload;
+
+impl IntoParsedRequest for DeleteDrivePayload {
+    fn into_parsed_request(
+        self,
+        id_from_path: Option<String>,
+        method: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        match method {
+            Method::Delete => {
+                let drive_id = id_from_path.unwrap_or(String::new());
+                let (sender, receiver) = oneshot::channel();
+                Ok(ParsedRequest::Sync(
+                    VmmAction::RemoveBlockDevice(drive_id, sender),
+                    receiver,
+                ))
+            }
+            _ => Err(format!("Invalid method {}!", method)),
+        }
+    }
+}
+
 impl IntoParsedRequest for BlockDeviceConfig {
     fn into_parsed_request(
         self,
@@ -209,10 +245,39 @@ mod tests {
         let patch_payload = PatchDrivePayload {
             fields: Value::Object(payload_map),
         };
-        let expected_err =
-            Err("Invalid PATCH payload. Only updates on path_on_host are allowed.".to_string());
+        let expected_err = Err("Invalid PATCH payload. Only updates on path_on_host or \
+                                rate_limiter are allowed, one at a time."
+            .to_string());
+        assert!(patch_payload.into_parsed_request(None, Method::Patch) == expected_err);
+
+        // PATCH that tries to update both path_on_host and rate_limiter.
+        let patch_payload = PatchDrivePayload {
+            fields: serde_json::from_str(
+                r#"{
+                    "drive_id": "foo",
+                    "path_on_host": "dummy",
+                    "rate_limiter": {}
+                }"#,
+            )
+            .unwrap(),
+        };
         assert!(patch_payload.into_parsed_request(None, Method::Patch) == expected_err);
 
+        // PATCH with an invalid rate_limiter.
+        let patch_payload = PatchDrivePayload {
+            fields: serde_json::from_str(
+                r#"{
+                    "drive_id": "foo",
+                    "rate_limiter": { "bandwidth": { "size": 1000 } }
+                }"#,
+            )
+            .unwrap(),
+        };
+        match patch_payload.into_parsed_request(Some("foo".to_string()), Method::Patch) {
+            Err(e) => assert!(e.starts_with("Invalid rate_limiter: missing field `refill_time`")),
+            _ => assert!(false),
+        }
+
         // PATCH with payload that is not a json.
         let patch_payload = PatchDrivePayload {
             fields: Value::String(String::from("dummy_payload")),
@@ -244,6 +309,43 @@ mod tests {
         assert!(
             pdp.into_parsed_request(None, Method::Put) == Err(String::from("Invalid method PUT!"))
         );
+
+        // PATCH that updates the rate_limiter.
+        let pdp = PatchDrivePayload {
+            fields: serde_json::from_str(
+                r#"{
+                    "drive_id": "foo",
+                    "rate_limiter": { "ops": { "size": 10, "refill_time": 1000 } }
+                }"#,
+            )
+            .unwrap(),
+        };
+        let update = serde_json::from_str::<BlockRateLimiterUpdate>(
+            r#"{ "ops": { "size": 10, "refill_time": 1000 } }"#,
+        )
+        .unwrap();
+        let (sender, receiver) = oneshot::channel();
+        assert!(pdp
+            .into_parsed_request(Some("foo".to_string()), Method::Patch)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::UpdateBlockDeviceRateLimiter("foo".to_string(), update, sender),
+                receiver
+            ))));
+    }
+
+    #[test]
+    fn test_delete_into_parsed_request() {
+        let (sender, receiver) = oneshot::channel();
+        assert!(DeleteDrivePayload
+            .into_parsed_request(Some("foo".to_string()), Method::Delete)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::RemoveBlockDevice("foo".to_string(), sender),
+                receiver
+            ))));
+        assert!(
+            DeleteDrivePayload.into_parsed_request(Some("foo".to_string()), Method::Put)
+                == Err(String::from("Invalid method PUT!"))
+        );
     }
 
     #[test]
@@ -254,7 +356,14 @@ mod tests {
             is_root_device: true,
             is_read_only: true,
             partuuid: None,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         assert!(
             &desc.into_parsed_request(Some(String::from("foo")), Method::Options)
@@ -268,7 +377,14 @@ mod tests {
             is_root_device: true,
             is_read_only: true,
             partuuid: None,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         let same_desc = BlockDeviceConfig {
             drive_id: String::from("foo"),
@@ -276,7 +392,14 @@ mod tests {
             is_root_device: true,
             is_read_only: true,
             partuuid: None,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         let (sender, receiver) = oneshot::channel();
         assert!(desc
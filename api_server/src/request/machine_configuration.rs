@@ -19,12 +19,13 @@ impl GenerateHyperResponse for VmConfig {
         let cpu_template = self
             .cpu_template
             .map_or("Uninitialized".to_string(), |c| c.to_string());
+        let acpi_only = self.acpi_only.unwrap_or(false);
 
         json_response(
             StatusCode::Ok,
             format!(
-                "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?},  \"ht_enabled\": {:?},  \"cpu_template\": {:?} }}",
-                vcpu_count, mem_size, ht_enabled, cpu_template
+                "{{ \"vcpu_count\": {:?}, \"mem_size_mib\": {:?},  \"ht_enabled\": {:?},  \"cpu_template\": {:?},  \"acpi_only\": {:?} }}",
+                vcpu_count, mem_size, ht_enabled, cpu_template, acpi_only
             ),
         )
     }
@@ -47,6 +48,7 @@ impl IntoParsedRequest for VmConfig {
                     && self.mem_size_mib.is_none()
                     && self.cpu_template.is_none()
                     && self.ht_enabled.is_none()
+                    && self.acpi_only.is_none()
                 {
                     return Err(String::from("Empty request."));
                 }
@@ -72,6 +74,7 @@ mod tests {
             mem_size_mib: Some(1024),
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            acpi_only: None,
         };
         let (sender, receiver) = oneshot::channel();
         assert!(body
@@ -86,6 +89,7 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            acpi_only: None,
         };
         assert!(uninitialized
             .clone()
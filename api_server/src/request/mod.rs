@@ -7,6 +7,9 @@ pub mod drive;
 pub mod logger;
 pub mod machine_configuration;
 pub mod net;
+pub mod rate_limit_group;
+pub mod snapshot;
+pub mod vm_state;
 #[cfg(feature = "vsock")]
 pub mod vsock;
 
@@ -57,6 +60,7 @@ impl GenerateHyperResponse for VmmData {
         match *self {
             VmmData::MachineConfiguration(ref machine_config) => machine_config.generate_response(),
             VmmData::Empty => empty_response(StatusCode::NoContent),
+            VmmData::Accepted => empty_response(StatusCode::Accepted),
         }
     }
 }
@@ -115,6 +119,7 @@ mod tests {
     use vmm::vmm_config::logger::LoggerConfigError;
     use vmm::vmm_config::machine_config::{VmConfig, VmConfigError};
     use vmm::vmm_config::net::NetworkInterfaceError;
+    use vmm::vmm_config::rate_limit_group::RateLimitGroupError;
 
     use futures::{Future, Stream};
     use hyper::{Body, Response};
@@ -154,6 +159,12 @@ mod tests {
             "EOF while parsing a value at line 1 column 0"
         );
 
+        // Test OK Accepted response from VMM.
+        let vmm_resp = Ok(VmmData::Accepted);
+        let hyper_resp = vmm_resp.generate_response();
+        assert_eq!(hyper_resp.status(), StatusCode::Accepted);
+        assert!(get_body(hyper_resp).is_err());
+
         // Test OK response from VMM that contains the Machine Configuration.
         let vmm_resp = Ok(VmmData::MachineConfiguration(VmConfig::default()));
         let hyper_resp = vmm_resp.generate_response();
@@ -162,7 +173,8 @@ mod tests {
             "vcpu_count": 1,
             "mem_size_mib": 128,
             "ht_enabled": false,
-            "cpu_template": "Uninitialized"
+            "cpu_template": "Uninitialized",
+            "acpi_only": false
         }"#;
         let vm_config_json: serde_json::Value = serde_json::from_str(vm_config_json).unwrap();
         assert_eq!(get_body(hyper_resp).unwrap(), vm_config_json);
@@ -249,6 +261,23 @@ mod tests {
             NetworkInterfaceError::HostDeviceNameInUse(String::from("tap_name")),
         );
         check_error_response(vmm_resp, StatusCode::BadRequest);
+        let vmm_resp = VmmActionError::NetworkConfig(
+            ErrorKind::User,
+            NetworkInterfaceError::InvalidRateLimitGroup,
+        );
+        check_error_response(vmm_resp, StatusCode::BadRequest);
+        let vmm_resp = VmmActionError::NetworkConfig(
+            ErrorKind::User,
+            NetworkInterfaceError::InvalidNumQueuePairs,
+        );
+        check_error_response(vmm_resp, StatusCode::BadRequest);
+
+        // Tests for RateLimitGroup Errors.
+        let vmm_resp = VmmActionError::RateLimitGroup(
+            ErrorKind::User,
+            RateLimitGroupError::UpdateNotAllowedPostBoot,
+        );
+        check_error_response(vmm_resp, StatusCode::BadRequest);
 
         // Tests for MicrovmStart Errors.
         // RegisterBlockDevice, RegisterNetDevice, and LegacyIOBus cannot be tested because the
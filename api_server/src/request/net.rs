@@ -7,7 +7,7 @@ use futures::sync::oneshot;
 use hyper::Method;
 
 use request::{IntoParsedRequest, ParsedRequest};
-use vmm::vmm_config::net::NetworkInterfaceConfig;
+use vmm::vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceUpdateConfig};
 use vmm::VmmAction;
 
 impl IntoParsedRequest for NetworkInterfaceConfig {
@@ -31,6 +31,32 @@ impl IntoParsedRequest for NetworkInterfaceConfig {
     }
 }
 
+impl IntoParsedRequest for NetworkInterfaceUpdateConfig {
+    fn into_parsed_request(
+        self,
+        id_from_path: Option<String>,
+        method: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        match method {
+            Method::Patch => {
+                let id_from_path = id_from_path.unwrap_or(String::new());
+                if id_from_path != self.iface_id {
+                    return Err(String::from(
+                        "The id from the path does not match the id from the body!",
+                    ));
+                }
+
+                let (sender, receiver) = oneshot::channel();
+                Ok(ParsedRequest::Sync(
+                    VmmAction::UpdateNetworkInterface(self, sender),
+                    receiver,
+                ))
+            }
+            _ => Err(format!("Invalid method {}!", method)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate net_util;
@@ -54,8 +80,16 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str(mac).unwrap()),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
             allow_mmds_requests: false,
-            tap: None,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
         }
     }
 
@@ -90,6 +124,47 @@ mod tests {
             ))));
     }
 
+    #[test]
+    fn test_netif_update_into_parsed_request() {
+        let jstr = r#"{
+            "iface_id": "foo",
+            "tx_rate_limiter": {
+                "bandwidth": { "size": 1000, "refill_time": 100 }
+            }
+        }"#;
+        let update_config: NetworkInterfaceUpdateConfig =
+            serde_json::from_str(jstr).expect("deserialization failed.");
+        assert!(update_config.rx_rate_limiter.is_none());
+        assert!(update_config.tx_rate_limiter.is_some());
+
+        assert!(update_config
+            .clone()
+            .into_parsed_request(Some(String::from("bar")), Method::Patch)
+            .is_err());
+        assert!(
+            update_config
+                .clone()
+                .into_parsed_request(Some(String::from("foo")), Method::Put)
+                == Err(String::from("Invalid method PUT!"))
+        );
+
+        let (sender, receiver) = oneshot::channel();
+        assert!(update_config
+            .clone()
+            .into_parsed_request(Some(String::from("foo")), Method::Patch)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::UpdateNetworkInterface(update_config, sender),
+                receiver
+            ))));
+
+        // Only the rate limiters can be updated.
+        let jstr = r#"{
+            "iface_id": "foo",
+            "host_dev_name": "bar"
+        }"#;
+        assert!(serde_json::from_str::<NetworkInterfaceUpdateConfig>(jstr).is_err());
+    }
+
     #[test]
     fn test_network_interface_body_serialization_and_deserialization() {
         let netif = NetworkInterfaceConfig {
@@ -98,8 +173,16 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str("12:34:56:78:9A:BC").unwrap()),
             rx_rate_limiter: Some(RateLimiter::default()),
             tx_rate_limiter: Some(RateLimiter::default()),
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
             allow_mmds_requests: true,
-            tap: None,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
         };
 
         // This is the json encoding of the netif variable.
@@ -0,0 +1,63 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use futures::sync::oneshot;
+use hyper::Method;
+
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::rate_limit_group::RateLimitGroupConfig;
+use vmm::VmmAction;
+
+impl IntoParsedRequest for RateLimitGroupConfig {
+    fn into_parsed_request(
+        self,
+        id_from_path: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let id_from_path = id_from_path.unwrap_or(String::new());
+        if id_from_path != self.group_id {
+            return Err(String::from(
+                "The id from the path does not match the id from the body!",
+            ));
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        Ok(ParsedRequest::Sync(
+            VmmAction::InsertRateLimitGroup(self, sender),
+            receiver,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    #[test]
+    fn test_rate_limit_group_into_parsed_request() {
+        let group: RateLimitGroupConfig = serde_json::from_str(
+            r#"{
+                "group_id": "tenant",
+                "bandwidth": {"size": 1048576, "refill_time": 1000}
+            }"#,
+        )
+        .unwrap();
+        assert!(group
+            .clone()
+            .into_parsed_request(Some(String::from("foo")), Method::Put)
+            .is_err());
+
+        let (sender, receiver) = oneshot::channel();
+        assert!(group
+            .clone()
+            .into_parsed_request(Some(String::from("tenant")), Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::InsertRateLimitGroup(group, sender),
+                receiver
+            ))));
+    }
+}
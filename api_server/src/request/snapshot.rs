@@ -0,0 +1,69 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use futures::sync::oneshot;
+use hyper::Method;
+
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::snapshot::SnapshotConfig;
+use vmm::VmmAction;
+
+// The resource ID selects the snapshot operation: `create` or `load`.
+impl IntoParsedRequest for SnapshotConfig {
+    fn into_parsed_request(
+        self,
+        resource_id: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let (sender, receiver) = oneshot::channel();
+        match resource_id.as_ref().map(String::as_str) {
+            Some("create") => Ok(ParsedRequest::Sync(
+                VmmAction::CreateSnapshot(self, sender),
+                receiver,
+            )),
+            Some("load") => Ok(ParsedRequest::Sync(
+                VmmAction::LoadSnapshot(self, sender),
+                receiver,
+            )),
+            _ => Err(String::from("Invalid snapshot operation.")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_into_parsed_request() {
+        let body = SnapshotConfig {
+            snapshot_path: String::from("/foo/bar"),
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        assert!(body
+            .clone()
+            .into_parsed_request(Some(String::from("create")), Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::CreateSnapshot(body.clone(), sender),
+                receiver
+            ))));
+
+        let (sender, receiver) = oneshot::channel();
+        assert!(body
+            .clone()
+            .into_parsed_request(Some(String::from("load")), Method::Put)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::LoadSnapshot(body.clone(), sender),
+                receiver
+            ))));
+
+        assert!(body
+            .clone()
+            .into_parsed_request(Some(String::from("foo")), Method::Put)
+            .is_err());
+        assert!(body.into_parsed_request(None, Method::Put).is_err());
+    }
+}
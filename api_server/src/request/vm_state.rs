@@ -0,0 +1,48 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::result;
+
+use futures::sync::oneshot;
+use hyper::Method;
+
+use request::{IntoParsedRequest, ParsedRequest};
+use vmm::vmm_config::vm_state::VmStateConfig;
+use vmm::VmmAction;
+
+impl IntoParsedRequest for VmStateConfig {
+    fn into_parsed_request(
+        self,
+        _: Option<String>,
+        _: Method,
+    ) -> result::Result<ParsedRequest, String> {
+        let (sender, receiver) = oneshot::channel();
+        Ok(ParsedRequest::Sync(
+            VmmAction::UpdateVmState(self, sender),
+            receiver,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use vmm::vmm_config::vm_state::VmStateUpdate;
+
+    #[test]
+    fn test_into_parsed_request() {
+        let body = VmStateConfig {
+            state: VmStateUpdate::Paused,
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        assert!(body
+            .clone()
+            .into_parsed_request(None, Method::Patch)
+            .eq(&Ok(ParsedRequest::Sync(
+                VmmAction::UpdateVmState(body, sender),
+                receiver
+            ))));
+    }
+}
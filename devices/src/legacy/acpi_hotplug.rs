@@ -0,0 +1,208 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use sys_util::{self, EventFd};
+
+use BusDevice;
+
+/// Offset of the bitmap of the slots that hold a device.
+const PRESENT_OFFSET: u64 = 0;
+/// Offset of the bitmap of the slots that changed since it was last read.
+const CHANGED_OFFSET: u64 = 4;
+/// Offset of the register the guest writes the bitmap of the slots it ejected to.
+const EJECT_OFFSET: u64 = 8;
+
+/// The registers through which the guest finds out which virtio-mmio slots hold a device, as
+/// declared in the DSDT. The guest is interrupted whenever a device is plugged or has to be
+/// ejected, and writes the slots it ejected back so that their devices can be removed.
+pub struct AcpiHotplugDevice {
+    present: u32,
+    changed: u32,
+    // The slots whose device the guest was asked to eject.
+    ejecting: u32,
+    // The slots whose device the guest ejected, and which have yet to be emptied.
+    ejected: u32,
+    interrupt_evt: EventFd,
+    eject_evt: EventFd,
+}
+
+impl AcpiHotplugDevice {
+    /// Constructs a device that will signal `interrupt_evt` when a slot changes, and
+    /// `eject_evt` when the guest ejects a device.
+    pub fn new(interrupt_evt: EventFd, eject_evt: EventFd) -> AcpiHotplugDevice {
+        AcpiHotplugDevice {
+            present: 0,
+            changed: 0,
+            ejecting: 0,
+            ejected: 0,
+            interrupt_evt,
+            eject_evt,
+        }
+    }
+
+    /// Returns a clone of the EventFd
+    pub fn get_eventfd_clone(&self) -> sys_util::Result<EventFd> {
+        self.interrupt_evt.try_clone()
+    }
+
+    /// Returns a clone of the EventFd signaled when the guest ejects a device.
+    pub fn get_eject_eventfd_clone(&self) -> sys_util::Result<EventFd> {
+        self.eject_evt.try_clone()
+    }
+
+    /// Marks `slot` as holding a device or not, without telling the guest. Used for the devices
+    /// that are there before the guest boots.
+    pub fn set_present(&mut self, slot: usize, present: bool) {
+        if present {
+            self.present |= 1 << slot;
+        } else {
+            self.present &= !(1 << slot);
+        }
+    }
+
+    /// Tells the guest that a device was plugged in `slot`.
+    pub fn plug(&mut self, slot: usize) -> sys_util::Result<()> {
+        self.set_present(slot, true);
+        self.ejecting &= !(1 << slot);
+        self.notify(slot)
+    }
+
+    /// Asks the guest to eject the device in `slot`. The device has to stay in the slot until
+    /// the guest reports it ejected, which `take_ejected` returns.
+    pub fn request_eject(&mut self, slot: usize) -> sys_util::Result<()> {
+        self.set_present(slot, false);
+        self.ejecting |= 1 << slot;
+        self.notify(slot)
+    }
+
+    /// Returns the bitmap of the slots whose device the guest ejected since the last call.
+    pub fn take_ejected(&mut self) -> u32 {
+        let ejected = self.ejected;
+        self.ejected = 0;
+        ejected
+    }
+
+    fn notify(&mut self, slot: usize) -> sys_util::Result<()> {
+        self.changed |= 1 << slot;
+        self.interrupt_evt.write(1)
+    }
+}
+
+impl BusDevice for AcpiHotplugDevice {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        if data.len() != 4 {
+            for byte in data.iter_mut() {
+                *byte = 0;
+            }
+            return;
+        }
+
+        let value = match offset {
+            PRESENT_OFFSET => self.present,
+            // Reading the changed slots acknowledges them.
+            CHANGED_OFFSET => {
+                let changed = self.changed;
+                self.changed = 0;
+                changed
+            }
+            _ => 0,
+        };
+        LittleEndian::write_u32(data, value);
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        // Only the eject register can be written, 32 bits at a time.
+        if offset != EJECT_OFFSET || data.len() != 4 {
+            return;
+        }
+
+        // The guest can only eject the devices it was asked to.
+        let ejected = LittleEndian::read_u32(data) & self.ejecting;
+        if ejected != 0 {
+            self.ejecting &= !ejected;
+            self.ejected |= ejected;
+            if let Err(e) = self.eject_evt.write(1) {
+                error!("Failed to trigger ACPI eject event: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_u32(device: &mut AcpiHotplugDevice, offset: u64) -> u32 {
+        let mut data = [0xff; 4];
+        device.read(offset, &mut data);
+        LittleEndian::read_u32(&data)
+    }
+
+    fn write_u32(device: &mut AcpiHotplugDevice, offset: u64, value: u32) {
+        let mut data = [0; 4];
+        LittleEndian::write_u32(&mut data, value);
+        device.write(offset, &data);
+    }
+
+    #[test]
+    fn test_acpi_hotplug() {
+        let mut device = AcpiHotplugDevice::new(EventFd::new().unwrap(), EventFd::new().unwrap());
+        let interrupt_evt = device.get_eventfd_clone().unwrap();
+
+        // The devices present at boot don't interrupt the guest.
+        device.set_present(0, true);
+        device.set_present(1, true);
+        assert_eq!(read_u32(&mut device, PRESENT_OFFSET), 0b11);
+        assert_eq!(read_u32(&mut device, CHANGED_OFFSET), 0);
+
+        device.plug(3).unwrap();
+        device.request_eject(0).unwrap();
+        assert_eq!(interrupt_evt.read(), Ok(2));
+        assert_eq!(read_u32(&mut device, PRESENT_OFFSET), 0b1010);
+        assert_eq!(read_u32(&mut device, CHANGED_OFFSET), 0b1001);
+        // The changes were acknowledged by the read.
+        assert_eq!(read_u32(&mut device, CHANGED_OFFSET), 0);
+
+        // The registers can only be read, 32 bits at a time.
+        device.write(PRESENT_OFFSET, &[0, 0, 0, 0]);
+        assert_eq!(read_u32(&mut device, PRESENT_OFFSET), 0b1010);
+        let mut data = [0xff];
+        device.read(PRESENT_OFFSET, &mut data);
+        assert_eq!(data, [0]);
+    }
+
+    #[test]
+    fn test_acpi_eject() {
+        let mut device = AcpiHotplugDevice::new(EventFd::new().unwrap(), EventFd::new().unwrap());
+        let eject_evt = device.get_eject_eventfd_clone().unwrap();
+        device.set_present(0, true);
+        device.set_present(1, true);
+
+        // Nothing is ejected until the guest writes the eject register.
+        device.request_eject(1).unwrap();
+        assert_eq!(device.take_ejected(), 0);
+
+        // The guest cannot eject the devices it wasn't asked to.
+        write_u32(&mut device, EJECT_OFFSET, 0b1);
+        assert_eq!(device.take_ejected(), 0);
+        device.write(EJECT_OFFSET, &[0b10]);
+        assert_eq!(device.take_ejected(), 0);
+
+        write_u32(&mut device, EJECT_OFFSET, 0b11);
+        assert_eq!(eject_evt.read(), Ok(1));
+        assert_eq!(device.take_ejected(), 0b10);
+        assert_eq!(device.take_ejected(), 0);
+        // A device is only ejected once.
+        write_u32(&mut device, EJECT_OFFSET, 0b10);
+        assert_eq!(device.take_ejected(), 0);
+
+        // Plugging a device in the slot cancels an eject that is still pending.
+        device.request_eject(0).unwrap();
+        device.plug(0).unwrap();
+        write_u32(&mut device, EJECT_OFFSET, 0b1);
+        assert_eq!(device.take_ejected(), 0);
+        assert_eq!(read_u32(&mut device, PRESENT_OFFSET), 0b1);
+    }
+}
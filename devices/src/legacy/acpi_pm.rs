@@ -0,0 +1,85 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use sys_util::{self, EventFd};
+
+use BusDevice;
+
+/// Sleep enable bit of the sleep control register.
+const SLP_EN: u8 = 0x20;
+/// Position and mask of the sleep type in the sleep control register.
+const SLP_TYP_SHIFT: u8 = 2;
+const SLP_TYP_MASK: u8 = 0x7;
+/// Sleep type of the S5 (soft off) state. It has to match the `\_S5_` object of the DSDT.
+const S5_SLEEP_TYPE: u8 = 5;
+
+/// The sleep control and status registers of a hardware-reduced ACPI platform, which is all the
+/// guest needs to power itself off.
+pub struct AcpiPmDevice {
+    /// Exit eventfd. We will set this event when the guest enters the S5 sleep state.
+    exit_evt: EventFd,
+}
+
+impl AcpiPmDevice {
+    /// Constructs a device that will signal `exit_evt` when the guest powers off.
+    pub fn new(exit_evt: EventFd) -> AcpiPmDevice {
+        AcpiPmDevice { exit_evt }
+    }
+
+    /// Returns a clone of the EventFd
+    pub fn get_eventfd_clone(&self) -> sys_util::Result<EventFd> {
+        self.exit_evt.try_clone()
+    }
+}
+
+impl BusDevice for AcpiPmDevice {
+    fn read(&mut self, _offset: u64, data: &mut [u8]) {
+        // The sleep status register only has a wake status bit, and the guest never wakes up.
+        for byte in data.iter_mut() {
+            *byte = 0;
+        }
+    }
+
+    fn write(&mut self, _offset: u64, data: &[u8]) {
+        if data.len() != 1 {
+            return;
+        }
+
+        let sleep_type = (data[0] >> SLP_TYP_SHIFT) & SLP_TYP_MASK;
+        if data[0] & SLP_EN != 0 && sleep_type == S5_SLEEP_TYPE {
+            // Firecracker will be exiting as soon as the VMM thread wakes up to handle this
+            // event.
+            if let Err(e) = self.exit_evt.write(1) {
+                error!("Failed to trigger ACPI power off event: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_acpi_power_off() {
+        let mut device = AcpiPmDevice::new(EventFd::new().unwrap());
+        let exit_evt = device.get_eventfd_clone().unwrap();
+
+        let mut data = [0xff];
+        device.read(0, &mut data);
+        assert_eq!(data, [0]);
+
+        // Write 1 to the exit event fd, so that read doesn't block in case the event fd
+        // counter doesn't change (for 0 it blocks).
+        assert!(exit_evt.write(1).is_ok());
+        // Neither setting the sleep type without SLP_EN nor entering another state powers off.
+        device.write(0, &[S5_SLEEP_TYPE << SLP_TYP_SHIFT]);
+        device.write(0, &[SLP_EN | (3 << SLP_TYP_SHIFT)]);
+        device.write(0, &[SLP_EN | (S5_SLEEP_TYPE << SLP_TYP_SHIFT), 0]);
+        assert_eq!(exit_evt.read(), Ok(1));
+
+        assert!(exit_evt.write(1).is_ok());
+        device.write(0, &[SLP_EN | (S5_SLEEP_TYPE << SLP_TYP_SHIFT)]);
+        assert_eq!(exit_evt.read(), Ok(2));
+    }
+}
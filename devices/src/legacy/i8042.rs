@@ -5,50 +5,338 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use std::num::Wrapping;
+use std::result;
+
 use logger::{Metric, METRICS};
-use sys_util::{EventFd, Result};
+use sys_util::{self, EventFd};
 
 use BusDevice;
 
-const RESET_CMD: u8 = 0xfe;
+/// Errors thrown by the i8042 device.
+#[derive(Debug)]
+pub enum Error {
+    /// The i8042 internal buffer is full.
+    InternalBufferFull,
+    /// Cannot trigger the keyboard interrupt.
+    KbdInterruptFailure(sys_util::Error),
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// Offset of the data port, relative to the base address of the device (0x60).
+const OFS_DATA: u64 = 0x0;
+/// Offset of the status / command port, relative to the base address of the device (0x64).
+const OFS_STATUS: u64 = 0x4;
+
+/// i8042 commands.
+/// These values are written by the guest driver to the command port.
+const CMD_READ_CTR: u8 = 0x20;
+const CMD_WRITE_CTR: u8 = 0x60;
+const CMD_READ_OUTP: u8 = 0xD0;
+const CMD_WRITE_OUTP: u8 = 0xD1;
+const CMD_RESET_CPU: u8 = 0xFE;
+
+/// i8042 status register bits.
+const SB_OUT_DATA_AVAIL: u8 = 0x01;
+const SB_I8042_CMD_DATA: u8 = 0x08;
+const SB_KBD_ENABLED: u8 = 0x10;
+
+/// i8042 control register bits.
+const CB_KBD_INT: u8 = 0x01;
+const CB_POST_OK: u8 = 0x04;
+
+/// Key scan codes (set 2), as sent by an AT keyboard. Extended keys carry the 0xE0 prefix in
+/// the high byte.
+const KEY_CTRL: u16 = 0x0014;
+const KEY_ALT: u16 = 0x0011;
+const KEY_DEL: u16 = 0xE071;
+
+/// Byte the keyboard answers with to every command it receives.
+const KBD_ACK: u8 = 0xFA;
+
+/// Internal i8042 buffer size, in bytes.
+const BUF_SIZE: usize = 16;
+
+/// The guest visible state of a `I8042Device`, as saved in a snapshot.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct I8042State {
+    /// The i8042 status register.
+    pub status: u8,
+    /// The i8042 control register.
+    pub control: u8,
+    /// The i8042 output port.
+    pub outp: u8,
+    /// The last command sent to port 0x64.
+    pub cmd: u8,
+    /// The bytes the guest has not read yet from port 0x60, oldest first.
+    pub pending: Vec<u8>,
+}
 
-/// A i8042 PS/2 controller that emulates just enough to shutdown the machine.
+/// A i8042 PS/2 controller that emulates just enough to shutdown the machine and to inject the
+/// Ctrl+Alt+Del key combination.
 pub struct I8042Device {
+    /// CPU reset eventfd. We will set this event when the guest issues CMD_RESET_CPU.
     reset_evt: EventFd,
+
+    /// Keyboard interrupt event (IRQ 1).
+    kbd_interrupt_evt: EventFd,
+
+    /// The i8042 status register.
+    status: u8,
+
+    /// The i8042 control register.
+    control: u8,
+
+    /// The i8042 output port.
+    outp: u8,
+
+    /// The last command sent to port 0x64.
+    cmd: u8,
+
+    /// The internal i8042 data buffer, read by the guest through port 0x60.
+    buf: [u8; BUF_SIZE],
+    bhead: Wrapping<usize>,
+    btail: Wrapping<usize>,
 }
 
 impl I8042Device {
-    /// Constructs a i8042 device that will signal the given event when the guest requests it.
-    pub fn new(reset_evt: EventFd) -> I8042Device {
-        I8042Device { reset_evt }
+    /// Constructs a i8042 device that will signal the given event when the guest requests it
+    /// and raise its interrupt through `kbd_interrupt_evt`.
+    pub fn new(reset_evt: EventFd, kbd_interrupt_evt: EventFd) -> I8042Device {
+        I8042Device {
+            reset_evt,
+            kbd_interrupt_evt,
+            control: CB_POST_OK | CB_KBD_INT,
+            cmd: 0,
+            outp: 0,
+            status: SB_KBD_ENABLED,
+            buf: [0; BUF_SIZE],
+            bhead: Wrapping(0),
+            btail: Wrapping(0),
+        }
     }
 
     /// Returns a clone of the EventFd
-    pub fn get_eventfd_clone(&self) -> Result<EventFd> {
+    pub fn get_eventfd_clone(&self) -> sys_util::Result<EventFd> {
         return self.reset_evt.try_clone();
     }
+
+    /// Returns the guest visible state of the device.
+    pub fn save_state(&self) -> I8042State {
+        I8042State {
+            status: self.status,
+            control: self.control,
+            outp: self.outp,
+            cmd: self.cmd,
+            pending: (0..self.buf_len())
+                .map(|i| self.buf[(self.bhead.0 + i) % BUF_SIZE])
+                .collect(),
+        }
+    }
+
+    /// Brings the device back to a previously saved state. Pending bytes that do not fit in the
+    /// internal buffer are dropped.
+    pub fn restore_state(&mut self, state: &I8042State) {
+        self.status = state.status;
+        self.control = state.control;
+        self.outp = state.outp;
+        self.cmd = state.cmd;
+        self.bhead = Wrapping(0);
+        self.btail = Wrapping(0);
+        for (i, byte) in state.pending.iter().take(BUF_SIZE).enumerate() {
+            self.buf[i] = *byte;
+            self.btail += Wrapping(1usize);
+        }
+    }
+
+    /// Signals the guest that a Ctrl+Alt+Del key combination was pressed.
+    pub fn trigger_ctrl_alt_del(&mut self) -> Result<()> {
+        // The guest could have disabled the keyboard interrupt. Pushing the scan codes anyway
+        // would only fill up the buffer.
+        if (self.control & CB_KBD_INT) == 0 {
+            return Ok(());
+        }
+
+        self.trigger_key(KEY_CTRL)?;
+        self.trigger_key(KEY_ALT)?;
+        self.trigger_key(KEY_DEL)?;
+        Ok(())
+    }
+
+    fn trigger_kbd_interrupt(&self) -> Result<()> {
+        if (self.control & CB_KBD_INT) == 0 {
+            warn!("Failed to trigger i8042 kbd interrupt (disabled by guest OS)");
+            return Ok(());
+        }
+        self.kbd_interrupt_evt
+            .write(1)
+            .map_err(Error::KbdInterruptFailure)
+    }
+
+    fn trigger_key(&mut self, key: u16) -> Result<()> {
+        if key & 0xff00 != 0 {
+            // Check if there is enough room in the buffer, before pushing an extended (2-byte)
+            // key.
+            if BUF_SIZE - self.buf_len() < 2 {
+                return Err(Error::InternalBufferFull);
+            }
+            self.push_byte((key >> 8) as u8)?;
+        }
+        self.push_byte((key & 0xff) as u8)?;
+        self.trigger_kbd_interrupt()
+    }
+
+    #[inline]
+    fn push_byte(&mut self, byte: u8) -> Result<()> {
+        self.status |= SB_OUT_DATA_AVAIL;
+        if self.buf_len() == BUF_SIZE {
+            return Err(Error::InternalBufferFull);
+        }
+        self.buf[self.btail.0 % BUF_SIZE] = byte;
+        self.btail += Wrapping(1usize);
+        Ok(())
+    }
+
+    #[inline]
+    fn pop_byte(&mut self) -> Option<u8> {
+        if self.buf_len() == 0 {
+            return None;
+        }
+        let res = self.buf[self.bhead.0 % BUF_SIZE];
+        self.bhead += Wrapping(1usize);
+        if self.buf_len() == 0 {
+            self.status &= !SB_OUT_DATA_AVAIL;
+        }
+        Some(res)
+    }
+
+    #[inline]
+    fn flush_buf(&mut self) {
+        self.bhead = Wrapping(0usize);
+        self.btail = Wrapping(0usize);
+        self.status &= !SB_OUT_DATA_AVAIL;
+    }
+
+    #[inline]
+    fn buf_len(&self) -> usize {
+        (self.btail - self.bhead).0
+    }
 }
 
 impl BusDevice for I8042Device {
     fn read(&mut self, offset: u64, data: &mut [u8]) {
-        if data.len() == 1 && offset == 0 {
-            data[0] = 0x0;
-            METRICS.i8042.read_count.add(data.len());
-        } else {
+        if data.len() != 1 {
             METRICS.i8042.missed_read_count.inc();
+            return;
         }
+
+        match offset {
+            OFS_STATUS => data[0] = self.status,
+            OFS_DATA => {
+                // The guest wants to read a byte from the output buffer.
+                data[0] = self.pop_byte().unwrap_or(0);
+                // If there is more data to be read, let the guest know.
+                if self.buf_len() > 0 {
+                    if let Err(e) = self.trigger_kbd_interrupt() {
+                        warn!("Failed to trigger i8042 kbd interrupt {:?}", e);
+                        METRICS.i8042.error_count.inc();
+                    }
+                }
+            }
+            _ => {
+                METRICS.i8042.missed_read_count.inc();
+                return;
+            }
+        }
+        METRICS.i8042.read_count.add(data.len());
     }
 
     fn write(&mut self, offset: u64, data: &[u8]) {
-        if data.len() == 1 && data[0] == RESET_CMD && offset == 0 {
-            if let Err(e) = self.reset_evt.write(1) {
-                error!("Failed to trigger i8042 reset event: {:?}", e);
-                METRICS.i8042.error_count.inc();
-            }
-            METRICS.i8042.reset_count.inc();
-        } else {
+        if data.len() != 1 {
             METRICS.i8042.missed_write_count.inc();
+            return;
         }
+
+        match (offset, data[0]) {
+            (OFS_STATUS, CMD_RESET_CPU) => {
+                // The guest wants to assert the CPU reset line. We handle that by triggering
+                // our exit event fd. Meaning Firecracker will be exiting as soon as the VMM
+                // thread wakes up to handle this event.
+                if let Err(e) = self.reset_evt.write(1) {
+                    error!("Failed to trigger i8042 reset event: {:?}", e);
+                    METRICS.i8042.error_count.inc();
+                }
+                METRICS.i8042.reset_count.inc();
+            }
+            (OFS_STATUS, CMD_READ_CTR) => {
+                // The guest wants to read the control register.
+                // Let's make sure only the control register will be available for reading from
+                // the data port, for the next inb(0x60).
+                self.flush_buf();
+                let control = self.control;
+                // Buffer is empty, push() will always succeed.
+                self.push_byte(control).unwrap();
+            }
+            (OFS_STATUS, CMD_WRITE_CTR) => {
+                // The guest wants to write the control register. This is a two-step command:
+                // 1. port 0x64 < CMD_WRITE_CTR
+                // 2. port 0x60 < <control reg value>
+                // Make sure we'll be expecting the control reg value on port 0x60 for the next
+                // write.
+                self.flush_buf();
+                self.status |= SB_I8042_CMD_DATA;
+                self.cmd = data[0];
+            }
+            (OFS_STATUS, CMD_READ_OUTP) => {
+                // The guest wants to read the output port (for lack of a better name - this is
+                // just another register on the 8042, that happens to also have its bits
+                // connected to some output pins of the 8042).
+                self.flush_buf();
+                let outp = self.outp;
+                // Buffer is empty, push() will always succeed.
+                self.push_byte(outp).unwrap();
+            }
+            (OFS_STATUS, CMD_WRITE_OUTP) => {
+                // Similar to writing the control register, this is a two-step command.
+                // I.e. write CMD_WRITE_OUTP at port 0x64, then write the actual out port value
+                // to port 0x60.
+                self.status |= SB_I8042_CMD_DATA;
+                self.cmd = data[0];
+            }
+            (OFS_DATA, _) if (self.status & SB_I8042_CMD_DATA) != 0 => {
+                // The guest is writing to port 0x60. This byte can either be:
+                // 1. the payload byte of a CMD_WRITE_CTR or CMD_WRITE_OUTP command, in which
+                //    case the status reg bit SB_I8042_CMD_DATA will be set, or
+                // 2. a direct command sent to the keyboard
+                // This match arm handles the first option (when the SB_I8042_CMD_DATA bit is
+                // set).
+                match self.cmd {
+                    CMD_WRITE_CTR => self.control = data[0],
+                    CMD_WRITE_OUTP => self.outp = data[0],
+                    _ => (),
+                }
+                self.status &= !SB_I8042_CMD_DATA;
+            }
+            (OFS_DATA, _) => {
+                // The guest is sending a command straight to the keyboard (so this byte is not
+                // addressed to the 8042, but to the keyboard). Since we're emulating a pretty
+                // dumb keyboard, we can get away with blindly ack-in anything (byte 0xFA).
+                // Something along the lines of "Yeah, uhm-uhm, yup, I hear you".
+                self.flush_buf();
+                // Buffer is empty, push() will always succeed.
+                self.push_byte(KBD_ACK).unwrap();
+                if let Err(e) = self.trigger_kbd_interrupt() {
+                    warn!("Failed to trigger i8042 kbd interrupt {:?}", e);
+                    METRICS.i8042.error_count.inc();
+                }
+            }
+            _ => {
+                METRICS.i8042.missed_write_count.inc();
+                return;
+            }
+        }
+        METRICS.i8042.write_count.inc();
     }
 }
 
@@ -56,44 +344,146 @@ impl BusDevice for I8042Device {
 mod tests {
     use super::*;
 
+    fn new_i8042() -> I8042Device {
+        I8042Device::new(EventFd::new().unwrap(), EventFd::new().unwrap())
+    }
+
     #[test]
     fn test_i8042_read_write_and_event() {
-        let mut i8042 = I8042Device::new(EventFd::new().unwrap());
+        let mut i8042 = new_i8042();
         let reset_evt = i8042.get_eventfd_clone().unwrap();
 
         // Check if reading in a 2-length array doesn't have side effects.
         let mut data = [1, 2];
-        i8042.read(0, &mut data);
+        i8042.read(OFS_STATUS, &mut data);
         assert_eq!(data, [1, 2]);
-        i8042.read(1, &mut data);
+        i8042.read(OFS_DATA, &mut data);
         assert_eq!(data, [1, 2]);
 
         // Check if reset works.
         // Write 1 to the reset event fd, so that read doesn't block in case the event fd
         // counter doesn't change (for 0 it blocks).
         assert!(reset_evt.write(1).is_ok());
-        let mut data = [RESET_CMD];
-        i8042.write(0, &mut data);
+        let mut data = [CMD_RESET_CPU];
+        i8042.write(OFS_STATUS, &mut data);
         assert_eq!(reset_evt.read(), Ok(2));
 
-        // Check if reading with offset 1 doesn't have side effects.
-        i8042.read(1, &mut data);
-        assert_eq!(data[0], RESET_CMD);
-
-        // Check if reading in a 1-length array with offset 0 returns [0].
-        i8042.read(0, &mut data);
-        assert_eq!(data[0], 0);
+        // Check if reading the status register returns the keyboard as enabled.
+        i8042.read(OFS_STATUS, &mut data);
+        assert_eq!(data[0], SB_KBD_ENABLED);
 
-        // Check invalid `write`s.
+        // Check invalid `read`s and `write`s.
+        let before = METRICS.i8042.missed_read_count.count();
+        // offset not 0 nor 4.
+        i8042.read(1, &mut data);
+        assert_eq!(METRICS.i8042.missed_read_count.count(), before + 1);
         let before = METRICS.i8042.missed_write_count.count();
-        // offset != 0.
-        i8042.write(1, &mut data);
-        // data != RESET_CMD
-        data[0] = RESET_CMD + 1;
+        // offset not 0 nor 4.
         i8042.write(1, &mut data);
+        // unsupported command.
+        data[0] = CMD_RESET_CPU + 1;
+        i8042.write(OFS_STATUS, &mut data);
         // data.len() != 1
-        let mut data = [RESET_CMD; 2];
-        i8042.write(1, &mut data);
+        let mut data = [CMD_RESET_CPU; 2];
+        i8042.write(OFS_STATUS, &mut data);
         assert_eq!(METRICS.i8042.missed_write_count.count(), before + 3);
     }
+
+    #[test]
+    fn test_i8042_commands() {
+        let mut i8042 = new_i8042();
+        let mut data = [1];
+
+        // Test reading/writing the control register.
+        i8042.write(OFS_STATUS, &[CMD_WRITE_CTR]);
+        assert_ne!(i8042.status & SB_I8042_CMD_DATA, 0);
+        i8042.write(OFS_DATA, &[0x52]);
+        assert_eq!(i8042.status & SB_I8042_CMD_DATA, 0);
+        i8042.write(OFS_STATUS, &[CMD_READ_CTR]);
+        i8042.read(OFS_STATUS, &mut data);
+        assert_ne!(data[0] & SB_OUT_DATA_AVAIL, 0);
+        i8042.read(OFS_DATA, &mut data);
+        assert_eq!(data[0], 0x52);
+        i8042.read(OFS_STATUS, &mut data);
+        assert_eq!(data[0] & SB_OUT_DATA_AVAIL, 0);
+
+        // Test reading/writing the output port.
+        i8042.write(OFS_STATUS, &[CMD_WRITE_OUTP]);
+        i8042.write(OFS_DATA, &[0x12]);
+        i8042.write(OFS_STATUS, &[CMD_READ_OUTP]);
+        i8042.read(OFS_DATA, &mut data);
+        assert_eq!(data[0], 0x12);
+
+        // Commands sent to the keyboard are acknowledged.
+        i8042.write(OFS_DATA, &[0xFF]);
+        i8042.read(OFS_DATA, &mut data);
+        assert_eq!(data[0], KBD_ACK);
+    }
+
+    #[test]
+    fn test_i8042_ctrl_alt_del() {
+        let mut i8042 = new_i8042();
+        let kbd_evt = i8042.kbd_interrupt_evt.try_clone().unwrap();
+
+        assert!(i8042.trigger_ctrl_alt_del().is_ok());
+        // One interrupt per key.
+        assert_eq!(kbd_evt.read(), Ok(3));
+
+        let mut data = [0];
+        for byte in [0x14, 0x11, 0xE0, 0x71].iter() {
+            i8042.read(OFS_DATA, &mut data);
+            assert_eq!(data[0], *byte);
+        }
+        i8042.read(OFS_STATUS, &mut data);
+        assert_eq!(data[0] & SB_OUT_DATA_AVAIL, 0);
+
+        // Fill up the buffer.
+        for _ in 0..BUF_SIZE / 4 {
+            assert!(i8042.trigger_ctrl_alt_del().is_ok());
+        }
+        match i8042.trigger_ctrl_alt_del() {
+            Err(Error::InternalBufferFull) => (),
+            _ => panic!("Unexpected result"),
+        }
+
+        // No keys are pushed while the guest has the keyboard interrupt disabled.
+        i8042.flush_buf();
+        i8042.control &= !CB_KBD_INT;
+        assert!(i8042.trigger_ctrl_alt_del().is_ok());
+        assert_eq!(i8042.buf_len(), 0);
+    }
+
+    #[test]
+    fn test_i8042_save_restore_state() {
+        let mut i8042 = new_i8042();
+        let mut data = [0];
+
+        // Leave a command waiting for its payload and a key half read.
+        i8042.write(OFS_STATUS, &[CMD_WRITE_OUTP]);
+        assert!(i8042.trigger_ctrl_alt_del().is_ok());
+        i8042.read(OFS_DATA, &mut data);
+        let state = i8042.save_state();
+        assert_eq!(state.pending, vec![0x11, 0xE0, 0x71]);
+
+        let mut restored = new_i8042();
+        restored.restore_state(&state);
+        assert_eq!(restored.save_state(), state);
+        for byte in [0x11, 0xE0, 0x71].iter() {
+            restored.read(OFS_DATA, &mut data);
+            assert_eq!(data[0], *byte);
+        }
+        restored.read(OFS_STATUS, &mut data);
+        assert_eq!(data[0] & SB_OUT_DATA_AVAIL, 0);
+        assert_ne!(data[0] & SB_I8042_CMD_DATA, 0);
+        restored.write(OFS_DATA, &[0x34]);
+        restored.write(OFS_STATUS, &[CMD_READ_OUTP]);
+        restored.read(OFS_DATA, &mut data);
+        assert_eq!(data[0], 0x34);
+
+        // Extra pending bytes are dropped.
+        let mut state = I8042State::default();
+        state.pending = vec![0; BUF_SIZE + 1];
+        restored.restore_state(&state);
+        assert_eq!(restored.buf_len(), BUF_SIZE);
+    }
 }
@@ -5,8 +5,14 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+mod acpi_hotplug;
+mod acpi_pm;
 mod i8042;
 mod serial;
 
+pub use self::acpi_hotplug::AcpiHotplugDevice;
+pub use self::acpi_pm::AcpiPmDevice;
+pub use self::i8042::Error as I8042DeviceError;
 pub use self::i8042::I8042Device;
+pub use self::i8042::I8042State;
 pub use self::serial::Serial;
@@ -9,6 +9,9 @@
 extern crate byteorder;
 extern crate epoll;
 extern crate libc;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
 extern crate dumbo;
 #[macro_use]
@@ -18,13 +21,16 @@ extern crate net_gen;
 extern crate net_util;
 extern crate rate_limiter;
 extern crate sys_util;
-#[cfg(feature = "vsock")]
+#[cfg(feature = "vhost")]
 extern crate vhost_backend;
-#[cfg(feature = "vsock")]
+#[cfg(feature = "vhost")]
 extern crate vhost_gen;
 extern crate virtio_gen;
 
 use std::fs::File;
+use std::io;
+
+use rate_limiter::RateLimiterUpdate;
 
 mod bus;
 pub mod legacy;
@@ -39,6 +45,14 @@ pub type DeviceEventT = u16;
 pub enum EpollHandlerPayload {
     /// DrivePayload(disk_image)
     DrivePayload(File),
+    /// DriveRateLimiterPayload(rate_limiter_update)
+    DriveRateLimiterPayload(virtio::BlockRateLimiterUpdate),
+    /// NetRateLimiterPayload(rx_rate_limiter_update, tx_rate_limiter_update)
+    NetRateLimiterPayload(RateLimiterUpdate, RateLimiterUpdate),
+    /// NetCapturePayload(capture), where `None` stops the capture
+    NetCapturePayload(Option<virtio::PcapWriter>),
+    /// NetFirewallPayload(firewall), replacing the rules of the previous one
+    NetFirewallPayload(virtio::Firewall),
     /// Events that do not need a payload.
     Empty,
 }
@@ -50,4 +64,17 @@ pub trait EpollHandler: Send {
         event_flags: u32,
         payload: EpollHandlerPayload,
     );
+
+    /// Completes the requests the handler has in flight, so that the state of its device can be
+    /// saved. Only the handlers which complete requests asynchronously can have any.
+    fn drain(&mut self) -> sys_util::Result<()> {
+        Ok(())
+    }
+
+    /// Applies the rate limiter update in `payload` and polls the timers it creates. Only the
+    /// handlers of the devices which have rate limiters accept one.
+    fn update_rate_limiters(&mut self, _payload: EpollHandlerPayload) -> io::Result<()> {
+        // This path can only be reached if we have a logical problem in our code.
+        panic!("Received a rate limiter update for a device without rate limiters.")
+    }
 }
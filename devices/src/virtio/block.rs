@@ -5,36 +5,60 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use byteorder::{ByteOrder, LittleEndian};
 use epoll;
+use libc::{c_void, iovec, EINVAL, EOPNOTSUPP};
 use std::cmp;
 use std::fs::File;
 use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
 use std::os::linux::fs::MetadataExt;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+use super::direct::DirectFile;
+use super::overlay::Overlay;
+use super::qcow::QcowFile;
 use super::{
     ActivateError, ActivateResult, DescriptorChain, EpollHandlerPayload, Queue, VirtioDevice,
     TYPE_BLOCK, VIRTIO_MMIO_INT_VRING,
 };
 use logger::{Metric, METRICS};
 use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
-use rate_limiter::{RateLimiter, TokenType};
-use sys_util::EventFd;
+use rate_limiter::{RateLimiter, RateLimiterUpdate, TokenBucket, TokenType};
+use serde::{Deserialize, Deserializer};
 use sys_util::Result as SysResult;
+use sys_util::{fallocate, Error as SysError, EventFd, FallocateMode, IoUring, Operation};
 use virtio_gen::virtio_blk::*;
 use virtio_gen::virtio_config::*;
 use {DeviceEventT, EpollHandler};
 
-const CONFIG_SPACE_SIZE: usize = 8;
+const CONFIG_SPACE_SIZE: usize = 60;
+// Offset of the number of queues in the config space.
+const CONFIG_NUM_QUEUES: usize = 34;
+// Offsets of the discard and write zeroes limits in the config space.
+const CONFIG_MAX_DISCARD_SECTORS: usize = 36;
+const CONFIG_MAX_DISCARD_SEG: usize = 40;
+const CONFIG_DISCARD_SECTOR_ALIGNMENT: usize = 44;
+const CONFIG_MAX_WRITE_ZEROES_SECTORS: usize = 48;
+const CONFIG_MAX_WRITE_ZEROES_SEG: usize = 52;
+const CONFIG_WRITE_ZEROES_MAY_UNMAP: usize = 56;
+// A single discard or write zeroes segment can cover the largest range the guest can describe.
+const MAX_SEGMENT_SECTORS: u32 = u32::max_value();
+const MAX_SEGMENTS: u32 = 1;
 const SECTOR_SHIFT: u8 = 9;
 pub const SECTOR_SIZE: u64 = (0x01 as u64) << SECTOR_SHIFT;
-const QUEUE_SIZE: u16 = 256;
-const NUM_QUEUES: usize = 1;
-const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE];
+/// The size of the queues of a block device, unless configured otherwise.
+pub const DEFAULT_QUEUE_SIZE: u16 = 256;
+/// The largest queue size allowed by the virtio specification.
+pub const MAX_QUEUE_SIZE: u16 = 32768;
+/// The largest number of queues of a block device. Each queue takes one of the ioeventfds KVM
+/// supports and a handler in the device event loop.
+pub const MAX_QUEUES: u16 = 16;
 
 // New descriptors are pending on the virtio queue.
 const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
@@ -42,8 +66,14 @@ const QUEUE_AVAIL_EVENT: DeviceEventT = 0;
 const RATE_LIMITER_EVENT: DeviceEventT = 1;
 // Backing file on the host has changed.
 pub const FS_UPDATE_EVENT: DeviceEventT = 2;
+// Asynchronous requests have completed.
+const COMPLETION_EVENT: DeviceEventT = 3;
+// Read rate limiter budget is now available.
+const READ_RATE_LIMITER_EVENT: DeviceEventT = 4;
+// Write rate limiter budget is now available.
+const WRITE_RATE_LIMITER_EVENT: DeviceEventT = 5;
 // Number of DeviceEventT events supported by this implementation.
-pub const BLOCK_EVENTS_COUNT: usize = 3;
+pub const BLOCK_EVENTS_COUNT: usize = 6;
 
 #[derive(Debug)]
 enum Error {
@@ -65,31 +95,45 @@ enum Error {
 
 #[derive(Debug)]
 enum ExecuteError {
+    Discard(io::Error),
     Flush(io::Error),
+    InvalidSegments(u32),
     Read(GuestMemoryError),
     Seek(io::Error),
+    SegmentOutOfRange(u64, u32),
+    Submit(SysError),
     Write(GuestMemoryError),
+    WriteZeroes(io::Error),
     Unsupported(u32),
+    UnsupportedSegmentFlags(u32),
 }
 
 impl ExecuteError {
     fn status(&self) -> u32 {
         match self {
+            &ExecuteError::Discard(_) => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Flush(_) => VIRTIO_BLK_S_IOERR,
+            &ExecuteError::InvalidSegments(_) => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Read(_) => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Seek(_) => VIRTIO_BLK_S_IOERR,
+            &ExecuteError::SegmentOutOfRange(_, _) => VIRTIO_BLK_S_IOERR,
+            &ExecuteError::Submit(_) => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Write(_) => VIRTIO_BLK_S_IOERR,
+            &ExecuteError::WriteZeroes(_) => VIRTIO_BLK_S_IOERR,
             &ExecuteError::Unsupported(_) => VIRTIO_BLK_S_UNSUPP,
+            &ExecuteError::UnsupportedSegmentFlags(_) => VIRTIO_BLK_S_UNSUPP,
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum RequestType {
     In,
     Out,
     Flush,
     GetDeviceID,
+    Discard,
+    WriteZeroes,
     Unsupported(u32),
 }
 
@@ -102,6 +146,8 @@ fn request_type(mem: &GuestMemory, desc_addr: GuestAddress) -> result::Result<Re
         VIRTIO_BLK_T_OUT => Ok(RequestType::Out),
         VIRTIO_BLK_T_FLUSH => Ok(RequestType::Flush),
         VIRTIO_BLK_T_GET_ID => Ok(RequestType::GetDeviceID),
+        VIRTIO_BLK_T_DISCARD => Ok(RequestType::Discard),
+        VIRTIO_BLK_T_WRITE_ZEROES => Ok(RequestType::WriteZeroes),
         t => Ok(RequestType::Unsupported(t)),
     }
 }
@@ -175,7 +221,13 @@ impl Request {
             .next_descriptor()
             .ok_or(Error::DescriptorChainTooShort)?;
 
-        if data_desc.is_write_only() && request_type == RequestType::Out {
+        // The data of OUT requests, and the segments of discard and write zeroes requests, are
+        // read by the device.
+        if data_desc.is_write_only()
+            && (request_type == RequestType::Out
+                || request_type == RequestType::Discard
+                || request_type == RequestType::WriteZeroes)
+        {
             return Err(Error::UnexpectedWriteOnlyDescriptor);
         }
 
@@ -201,12 +253,10 @@ impl Request {
         })
     }
 
-    fn execute<T: Seek + Read + Write>(
-        &self,
-        disk: &mut T,
-        mem: &GuestMemory,
-        disk_id: &Vec<u8>,
-    ) -> result::Result<u32, ExecuteError> {
+    fn execute(&self, disk: &mut Disk, mem: &GuestMemory) -> result::Result<u32, ExecuteError> {
+        let cache_type = disk.cache_type;
+        let disk_id = &disk.id;
+        let disk = &mut disk.image;
         disk.seek(SeekFrom::Start(self.sector << SECTOR_SHIFT))
             .map_err(ExecuteError::Seek)?;
         match self.request_type {
@@ -221,7 +271,7 @@ impl Request {
                     .map_err(ExecuteError::Write)?;
                 METRICS.block.write_count.add(self.data_len as usize);
             }
-            RequestType::Flush => match disk.flush() {
+            RequestType::Flush => match disk.sync(cache_type) {
                 Ok(_) => {
                     METRICS.block.flush_count.inc();
                     return Ok(0);
@@ -232,72 +282,637 @@ impl Request {
                 mem.write_slice_at_addr(&disk_id.as_slice(), self.data_addr)
                     .map_err(ExecuteError::Write)?;
             }
+            RequestType::Discard => {
+                self.execute_segments(disk, mem)?;
+                METRICS.block.discard_count.inc();
+            }
+            RequestType::WriteZeroes => {
+                self.execute_segments(disk, mem)?;
+                METRICS.block.write_zeroes_count.inc();
+            }
             RequestType::Unsupported(t) => return Err(ExecuteError::Unsupported(t)),
         };
         Ok(0)
     }
+
+    // Discards or zeroes the ranges described by the segments of a discard or write zeroes
+    // request.
+    fn execute_segments(
+        &self,
+        disk: &mut DiskImage,
+        mem: &GuestMemory,
+    ) -> result::Result<(), ExecuteError> {
+        let segment_size = mem::size_of::<virtio_blk_discard_write_zeroes>() as u32;
+        if self.data_len == 0 || self.data_len % segment_size != 0 {
+            return Err(ExecuteError::InvalidSegments(self.data_len));
+        }
+        let disk_size = disk.seek(SeekFrom::End(0)).map_err(ExecuteError::Seek)?;
+
+        for i in 0..self.data_len / segment_size {
+            let segment_addr = self.data_addr.unchecked_add((i * segment_size) as usize);
+            let sector: u64 = mem
+                .read_obj_from_addr(segment_addr)
+                .map_err(ExecuteError::Read)?;
+            let num_sectors: u32 = mem
+                .read_obj_from_addr(segment_addr.unchecked_add(8))
+                .map_err(ExecuteError::Read)?;
+            let flags: u32 = mem
+                .read_obj_from_addr(segment_addr.unchecked_add(12))
+                .map_err(ExecuteError::Read)?;
+
+            let unmap = flags & VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0;
+            if flags & !VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP != 0
+                || (unmap && self.request_type == RequestType::Discard)
+            {
+                return Err(ExecuteError::UnsupportedSegmentFlags(flags));
+            }
+
+            if sector > disk_size >> SECTOR_SHIFT {
+                return Err(ExecuteError::SegmentOutOfRange(sector, num_sectors));
+            }
+            let offset = sector << SECTOR_SHIFT;
+            let len = u64::from(num_sectors) << SECTOR_SHIFT;
+            if len > disk_size - offset {
+                return Err(ExecuteError::SegmentOutOfRange(sector, num_sectors));
+            }
+
+            // Punched holes read back as zeroes, so the guest allowing us to unmap the range
+            // turns a write zeroes request into a discard.
+            if self.request_type == RequestType::Discard || unmap {
+                disk.punch_hole(offset, len)
+                    .map_err(ExecuteError::Discard)?;
+            } else {
+                disk.write_zeroes(offset, len)
+                    .map_err(ExecuteError::WriteZeroes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Zeroes a range of `file`, in place when the file system supports it.
+pub(crate) fn zero_file_range(file: &mut File, offset: u64, len: u64) -> io::Result<()> {
+    match fallocate(file, FallocateMode::ZeroRange, offset, len) {
+        Ok(()) => Ok(()),
+        // Not every file system can zero a range in place.
+        Err(ref e) if e.errno() == EOPNOTSUPP => {
+            let zeroes = [0u8; 4096];
+            let mut written = 0;
+            while written < len {
+                let count = cmp::min(len - written, zeroes.len() as u64) as usize;
+                file.write_all_at(&zeroes[..count], offset + written)?;
+                written += count as u64;
+            }
+            Ok(())
+        }
+        Err(e) => Err(io::Error::from_raw_os_error(e.errno())),
+    }
+}
+
+/// The storage backing a block device.
+pub enum DiskImage {
+    /// A raw image, read and written in place.
+    Raw(File),
+    /// A copy-on-write overlay on top of a read-only base image.
+    Overlay(Overlay),
+    /// An image in the qcow2 format.
+    Qcow(QcowFile),
+    /// A raw image opened with `O_DIRECT`.
+    Direct(DirectFile),
+}
+
+impl DiskImage {
+    /// Returns the file that identifies the disk.
+    fn file(&self) -> &File {
+        match self {
+            DiskImage::Raw(file) => file,
+            DiskImage::Overlay(overlay) => overlay.overlay_file(),
+            DiskImage::Qcow(qcow) => qcow.file(),
+            DiskImage::Direct(direct) => direct.file(),
+        }
+    }
+
+    /// Makes the completed writes durable, unless the cache type ignores flushes.
+    fn sync(&mut self, cache_type: CacheType) -> io::Result<()> {
+        if cache_type == CacheType::Unsafe {
+            return Ok(());
+        }
+        self.flush()?;
+        self.file().sync_data()
+    }
+
+    /// Deallocates a range of the disk, which then reads back as zeroes.
+    fn punch_hole(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match self {
+            DiskImage::Raw(file) => fallocate(file, FallocateMode::PunchHole, offset, len)
+                .map_err(|e| io::Error::from_raw_os_error(e.errno())),
+            DiskImage::Overlay(overlay) => overlay.zero_range(offset, len, true),
+            DiskImage::Qcow(qcow) => qcow.zero_range(offset, len, true),
+            DiskImage::Direct(direct) => {
+                fallocate(direct.file(), FallocateMode::PunchHole, offset, len)
+                    .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+            }
+        }
+    }
+
+    /// Zeroes a range of the disk, keeping it allocated.
+    fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match self {
+            DiskImage::Raw(file) => zero_file_range(file, offset, len),
+            DiskImage::Overlay(overlay) => overlay.zero_range(offset, len, false),
+            DiskImage::Qcow(qcow) => qcow.zero_range(offset, len, false),
+            DiskImage::Direct(direct) => direct.write_zeroes(offset, len),
+        }
+    }
+}
+
+impl Read for DiskImage {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            DiskImage::Raw(file) => file.read(buf),
+            DiskImage::Overlay(overlay) => overlay.read(buf),
+            DiskImage::Qcow(qcow) => qcow.read(buf),
+            DiskImage::Direct(direct) => direct.read(buf),
+        }
+    }
+}
+
+impl Write for DiskImage {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            DiskImage::Raw(file) => file.write(buf),
+            DiskImage::Overlay(overlay) => overlay.write(buf),
+            DiskImage::Qcow(qcow) => qcow.write(buf),
+            DiskImage::Direct(direct) => direct.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            DiskImage::Raw(file) => file.flush(),
+            DiskImage::Overlay(overlay) => overlay.flush(),
+            DiskImage::Qcow(qcow) => qcow.flush(),
+            DiskImage::Direct(direct) => direct.flush(),
+        }
+    }
+}
+
+impl Seek for DiskImage {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            DiskImage::Raw(file) => file.seek(pos),
+            DiskImage::Overlay(overlay) => overlay.seek(pos),
+            DiskImage::Qcow(qcow) => qcow.seek(pos),
+            DiskImage::Direct(direct) => direct.seek(pos),
+        }
+    }
+}
+
+/// The way a block device caches the data written by the guest.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum CacheType {
+    /// Writes go through the host page cache and flush requests are ignored, so data the guest
+    /// flushed can be lost if the host crashes.
+    Unsafe,
+    /// Writes go through the host page cache and flush requests sync the image to the disk.
+    Writeback,
+    /// The image is opened with `O_DIRECT`, bypassing the host page cache, and flush requests
+    /// sync the image to the disk. Only raw images support it.
+    Direct,
+}
+
+/// The way a block device executes the requests of the guest.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum IoEngine {
+    /// Requests are executed one after the other, blocking the device thread.
+    Sync,
+    /// Reads, writes and flushes are submitted to an io_uring and completed asynchronously.
+    /// Only raw images support it; other images fall back to `Sync`.
+    Async,
+}
+
+/// The rate limiters of a block device.
+///
+/// Every request is charged to the `shared` limiter. Reads are also charged to the `read`
+/// limiter and writes, discards and write zeroes to the `write` limiter, when configured. A
+/// read or write costs one operation per started `bytes_per_op` bytes if set, one otherwise.
+#[derive(Debug, Default, PartialEq)]
+pub struct BlockRateLimiter {
+    /// Limits all the requests of the device.
+    pub shared: RateLimiter,
+    /// Limits the reads of the device.
+    pub read: Option<RateLimiter>,
+    /// Limits the writes of the device.
+    pub write: Option<RateLimiter>,
+    /// Size of the data transfer charged as one operation.
+    pub bytes_per_op: Option<u64>,
+}
+
+// The json body of a `BlockRateLimiter`. The buckets of the shared limiter are at the top
+// level, for compatibility with the plain `RateLimiter`.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BlockRateLimiterConfig {
+    bandwidth: Option<TokenBucket>,
+    ops: Option<TokenBucket>,
+    read: Option<RateLimiter>,
+    write: Option<RateLimiter>,
+    #[serde(default, deserialize_with = "deserialize_bytes_per_op")]
+    bytes_per_op: Option<u64>,
+}
+
+/// New configurations for the rate limiters of a block device, as passed to
+/// `BlockRateLimiter::update()`.
+///
+/// The buckets of the shared limiter are at the top level, like in the json body of a
+/// `BlockRateLimiter`. A bucket or limiter that is not specified is left unchanged, and a read
+/// or write limiter that is specified for the first time starts off without any bucket.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BlockRateLimiterUpdate {
+    /// New configuration of the bytes bucket of the shared limiter.
+    pub bandwidth: Option<TokenBucket>,
+    /// New configuration of the operations bucket of the shared limiter.
+    pub ops: Option<TokenBucket>,
+    /// New configuration of the read limiter.
+    pub read: Option<RateLimiterUpdate>,
+    /// New configuration of the write limiter.
+    pub write: Option<RateLimiterUpdate>,
+    /// New size of the data transfer charged as one operation.
+    #[serde(default, deserialize_with = "deserialize_bytes_per_op")]
+    pub bytes_per_op: Option<u64>,
+}
+
+fn deserialize_bytes_per_op<'de, D>(deserializer: D) -> result::Result<Option<u64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let bytes_per_op = Option::<u64>::deserialize(deserializer)?;
+    if bytes_per_op == Some(0) {
+        return Err(D::Error::custom("bytes_per_op must be greater than zero"));
+    }
+    Ok(bytes_per_op)
+}
+
+impl<'de> Deserialize<'de> for BlockRateLimiter {
+    fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let config = BlockRateLimiterConfig::deserialize(deserializer)?;
+        let mut shared = RateLimiter::default();
+        shared
+            .update(RateLimiterUpdate {
+                bandwidth: config.bandwidth,
+                ops: config.ops,
+            })
+            .map_err(D::Error::custom)?;
+        Ok(BlockRateLimiter {
+            shared,
+            read: config.read,
+            write: config.write,
+            bytes_per_op: config.bytes_per_op,
+        })
+    }
+}
+
+impl BlockRateLimiter {
+    /// Reconfigures the rate limiters.
+    ///
+    /// # Errors
+    ///
+    /// If the timer of a limiter cannot be created, an error is returned. The other limiters
+    /// are still updated.
+    pub fn update(&mut self, update: BlockRateLimiterUpdate) -> io::Result<()> {
+        let shared = self.shared.update(RateLimiterUpdate {
+            bandwidth: update.bandwidth,
+            ops: update.ops,
+        });
+        if update.bytes_per_op.is_some() {
+            self.bytes_per_op = update.bytes_per_op;
+        }
+        let read = BlockRateLimiter::update_limiter(&mut self.read, update.read);
+        let write = BlockRateLimiter::update_limiter(&mut self.write, update.write);
+        shared.and(read).and(write)
+    }
+
+    fn update_limiter(
+        limiter: &mut Option<RateLimiter>,
+        update: Option<RateLimiterUpdate>,
+    ) -> io::Result<()> {
+        let update = match update {
+            Some(update) => update,
+            None => return Ok(()),
+        };
+        limiter
+            .get_or_insert_with(RateLimiter::default)
+            .update(update)
+    }
+
+    // The fds of the timers of the shared, read and write limiters, or -1 for the limiters
+    // without a timer.
+    fn raw_fds(&self) -> [RawFd; 3] {
+        [
+            self.shared.as_raw_fd(),
+            self.read.as_ref().map_or(-1, AsRawFd::as_raw_fd),
+            self.write.as_ref().map_or(-1, AsRawFd::as_raw_fd),
+        ]
+    }
+
+    // The operations and bytes `request` is charged.
+    fn cost(&self, request: &Request) -> (u64, u64) {
+        match request.request_type {
+            RequestType::In | RequestType::Out => {
+                let bytes = u64::from(request.data_len);
+                let ops = match self.bytes_per_op {
+                    // Rounded up without overflowing, as `bytes_per_op` can be as large as u64::MAX.
+                    Some(bytes_per_op) => {
+                        cmp::max(1, bytes / bytes_per_op + (bytes % bytes_per_op != 0) as u64)
+                    }
+                    None => 1,
+                };
+                (ops, bytes)
+            }
+            // Discard and write zeroes requests are only charged the operation, as no data
+            // goes through the queue.
+            _ => (1, 0),
+        }
+    }
+
+    // Charges `request` to the limiters it goes through. If one of them runs out of budget,
+    // the tokens taken from the others are given back.
+    fn consume(&mut self, request: &Request) -> bool {
+        let (ops, bytes) = self.cost(request);
+        if !BlockRateLimiter::consume_from(&mut self.shared, ops, bytes) {
+            METRICS.block.rate_limiter_throttled_events.inc();
+            return false;
+        }
+        let (limiter, throttled_events) = match request.request_type {
+            RequestType::In => (
+                self.read.as_mut(),
+                &METRICS.block.read_rate_limiter_throttled_events,
+            ),
+            RequestType::Out | RequestType::Discard | RequestType::WriteZeroes => (
+                self.write.as_mut(),
+                &METRICS.block.write_rate_limiter_throttled_events,
+            ),
+            _ => return true,
+        };
+        if let Some(limiter) = limiter {
+            if !BlockRateLimiter::consume_from(limiter, ops, bytes) {
+                throttled_events.inc();
+                self.shared.manual_replenish(ops, TokenType::Ops);
+                self.shared.manual_replenish(bytes, TokenType::Bytes);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn consume_from(rate_limiter: &mut RateLimiter, ops: u64, bytes: u64) -> bool {
+        // If limiter.consume() fails it means there is no more budget of that token type and
+        // rate limiting is in effect.
+        if !rate_limiter.consume(ops, TokenType::Ops) {
+            return false;
+        }
+        if bytes > 0 && !rate_limiter.consume(bytes, TokenType::Bytes) {
+            // Revert the OPS consume().
+            rate_limiter.manual_replenish(ops, TokenType::Ops);
+            return false;
+        }
+        true
+    }
+}
+
+// A request submitted to the io_uring, waiting for its completion.
+struct InFlightRequest {
+    request_type: RequestType,
+    data_len: u32,
+    status_addr: GuestAddress,
+    // The kernel may access the iovec until the request completes.
+    iovec: iovec,
+}
+
+// The io_uring of a block device, along with the requests in flight.
+struct AsyncIo {
+    ring: IoUring,
+    completion_evt: EventFd,
+    // Indexed by the head of the descriptor chain of each request, which the guest can't reuse
+    // before we complete it. The vector is never resized, so the iovecs don't move.
+    requests: Vec<Option<InFlightRequest>>,
+}
+
+// The iovecs only point to guest memory, which outlives the device.
+unsafe impl Send for AsyncIo {}
+
+impl AsyncIo {
+    fn new(queue_size: u16) -> SysResult<AsyncIo> {
+        let ring = IoUring::new(u32::from(queue_size))?;
+        let completion_evt = EventFd::new()?;
+        ring.register_eventfd(&completion_evt)?;
+        Ok(AsyncIo {
+            ring,
+            completion_evt,
+            requests: (0..queue_size).map(|_| None).collect(),
+        })
+    }
+
+    // Whether the request is executed through the io_uring rather than synchronously.
+    fn handles(request: &Request, cache_type: CacheType) -> bool {
+        match request.request_type {
+            RequestType::In | RequestType::Out => request.data_len > 0,
+            RequestType::Flush => cache_type != CacheType::Unsafe,
+            _ => false,
+        }
+    }
+
+    // Queues the request in the io_uring, to be submitted along with the rest of the queue.
+    fn push(
+        &mut self,
+        request: &Request,
+        desc_index: u16,
+        fd: RawFd,
+        mem: &GuestMemory,
+    ) -> result::Result<(), ExecuteError> {
+        let slot = desc_index as usize;
+        if slot >= self.requests.len() || self.requests[slot].is_some() {
+            return Err(ExecuteError::Submit(SysError::new(EINVAL)));
+        }
+
+        let mut in_flight = InFlightRequest {
+            request_type: request.request_type,
+            data_len: request.data_len,
+            status_addr: request.status_addr,
+            iovec: iovec {
+                iov_base: 0 as *mut c_void,
+                iov_len: 0,
+            },
+        };
+        if in_flight.request_type != RequestType::Flush {
+            let map_err = |e| match in_flight.request_type {
+                RequestType::In => ExecuteError::Read(e),
+                _ => ExecuteError::Write(e),
+            };
+            let len = request.data_len as usize;
+            // The kernel accesses the buffer directly, so it has to be contiguous in our address
+            // space as well.
+            let start = mem.get_host_address(request.data_addr).map_err(map_err)?;
+            let last_addr = mem
+                .checked_offset(request.data_addr, len - 1)
+                .ok_or_else(|| map_err(GuestMemoryError::InvalidGuestAddress(request.data_addr)))?;
+            let last = mem.get_host_address(last_addr).map_err(map_err)?;
+            if (last as usize).wrapping_sub(start as usize) != len - 1 {
+                return Err(map_err(GuestMemoryError::InvalidGuestAddress(
+                    request.data_addr,
+                )));
+            }
+            in_flight.iovec.iov_base = start as *mut c_void;
+            in_flight.iovec.iov_len = len;
+        }
+
+        let offset = request
+            .sector
+            .checked_mul(SECTOR_SIZE)
+            .ok_or_else(|| ExecuteError::Seek(io::Error::from_raw_os_error(EINVAL)))?;
+        self.requests[slot] = Some(in_flight);
+        let iovec = match self.requests[slot] {
+            Some(ref r) => &r.iovec as *const iovec,
+            None => unreachable!(),
+        };
+        let op = match request.request_type {
+            RequestType::In => Operation::Readv {
+                fd,
+                iovecs: iovec,
+                count: 1,
+                offset,
+            },
+            RequestType::Out => Operation::Writev {
+                fd,
+                iovecs: iovec,
+                count: 1,
+                offset,
+            },
+            _ => Operation::Fsync { fd, datasync: true },
+        };
+        // This is safe because the iovec lives in `requests` until the request completes, and
+        // points to guest memory, which outlives the ring.
+        if let Err(e) = unsafe { self.ring.push(op, u64::from(desc_index)) } {
+            self.requests[slot] = None;
+            return Err(ExecuteError::Submit(e));
+        }
+        Ok(())
+    }
 }
 
+// The disk image of a block device, shared by the handlers of its queues.
+struct Disk {
+    image: DiskImage,
+    id: Vec<u8>,
+    cache_type: CacheType,
+}
+
+// Services one of the queues of a block device.
 struct BlockEpollHandler {
-    queues: Vec<Queue>,
+    queue: Queue,
     mem: GuestMemory,
-    disk_image: File,
+    disk: Arc<Mutex<Disk>>,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: EventFd,
     queue_evt: EventFd,
-    rate_limiter: RateLimiter,
-    disk_image_id: Vec<u8>,
+    // Shared by the queues of the device.
+    rate_limiter: Arc<Mutex<BlockRateLimiter>>,
+    // Only the handler of the first queue is notified when a rate limiter unblocks. It then
+    // kicks the events of the other queues, which may be blocked as well.
+    rate_limiter_kicks: Vec<EventFd>,
+    // The epoll fd polling the timers of the rate limiters, and the tokens of the shared, read
+    // and write limiters, so that the timers created by an update are polled as well.
+    epoll_raw_fd: RawFd,
+    rate_limiter_tokens: [u64; 3],
+    async_io: Option<AsyncIo>,
 }
 
 impl BlockEpollHandler {
-    fn process_queue(&mut self, queue_index: usize) -> bool {
-        let queue = &mut self.queues[queue_index];
+    fn update_rate_limiter(&mut self, update: BlockRateLimiterUpdate) -> io::Result<()> {
+        // The queues share the rate limiters, so they are updated for all of them.
+        let mut rate_limiter = self
+            .rate_limiter
+            .lock()
+            .expect("Failed to acquire rate limiter lock");
+        let old_rawfds = rate_limiter.raw_fds();
+        let result = rate_limiter.update(update);
+        // The timers created before a failure still have to be polled.
+        for ((&rawfd, _), &token) in rate_limiter
+            .raw_fds()
+            .iter()
+            .zip(old_rawfds.iter())
+            .zip(self.rate_limiter_tokens.iter())
+            .filter(|&((&rawfd, &old_rawfd), _)| rawfd != -1 && rawfd != old_rawfd)
+        {
+            epoll::ctl(
+                self.epoll_raw_fd,
+                epoll::EPOLL_CTL_ADD,
+                rawfd,
+                epoll::Event::new(epoll::EPOLLIN, token),
+            )?;
+        }
+        result
+    }
+
+    fn process_queue(&mut self) -> bool {
+        let queue = &mut self.queue;
+        let mut rate_limiter = self
+            .rate_limiter
+            .lock()
+            .expect("Failed to acquire rate limiter lock");
+        let mut disk = self.disk.lock().expect("Failed to acquire disk lock");
+        let disk = &mut *disk;
         let mut rate_limited = false;
 
-        let mut used_desc_heads = [(0, 0); QUEUE_SIZE as usize];
-        let mut used_count = 0;
+        let mut used_desc_heads = Vec::with_capacity(queue.actual_size() as usize);
+        let mut submitted = false;
         for avail_desc in queue.iter(&self.mem) {
             let len;
             match Request::parse(&avail_desc, &self.mem) {
                 Ok(request) => {
-                    // If limiter.consume() fails it means there is no more TokenType::Ops
-                    // budget and rate limiting is in effect.
-                    if !self.rate_limiter.consume(1, TokenType::Ops) {
+                    if !rate_limiter.consume(&request) {
                         rate_limited = true;
-                        // stop processing the queue
+                        // Stop processing the queue.
                         break;
                     }
-                    // Exercise the rate limiter only if this request is of data transfer type.
-                    if request.request_type == RequestType::In
-                        || request.request_type == RequestType::Out
-                    {
-                        // If limiter.consume() fails it means there is no more TokenType::Bytes
-                        // budget and rate limiting is in effect.
-                        if !self
-                            .rate_limiter
-                            .consume(request.data_len as u64, TokenType::Bytes)
-                        {
-                            rate_limited = true;
-                            // Revert the OPS consume().
-                            self.rate_limiter.manual_replenish(1, TokenType::Ops);
-                            // Stop processing the queue.
-                            break;
+                    let result = match self.async_io {
+                        Some(ref mut async_io) if AsyncIo::handles(&request, disk.cache_type) => {
+                            async_io
+                                .push(
+                                    &request,
+                                    avail_desc.index,
+                                    disk.image.file().as_raw_fd(),
+                                    &self.mem,
+                                )
+                                .map(|()| None)
                         }
-                    }
-                    let status =
-                        match request.execute(&mut self.disk_image, &self.mem, &self.disk_image_id)
-                        {
-                            Ok(l) => {
-                                len = l;
-                                VIRTIO_BLK_S_OK
-                            }
-                            Err(e) => {
-                                error!("Failed to execute request: {:?}", e);
-                                METRICS.block.invalid_reqs_count.inc();
-                                len = 1; // We need at least 1 byte for the status.
-                                e.status()
-                            }
-                        };
+                        _ => request.execute(disk, &self.mem).map(Some),
+                    };
+                    let status = match result {
+                        // The descriptor chain is returned once the request completes.
+                        Ok(None) => {
+                            submitted = true;
+                            continue;
+                        }
+                        Ok(Some(l)) => {
+                            len = l;
+                            VIRTIO_BLK_S_OK
+                        }
+                        Err(e) => {
+                            error!("Failed to execute request: {:?}", e);
+                            METRICS.block.invalid_reqs_count.inc();
+                            len = 1; // We need at least 1 byte for the status.
+                            e.status()
+                        }
+                    };
                     // We use unwrap because the request parsing process already checked that the
                     // status_addr was valid.
                     self.mem
@@ -310,8 +925,7 @@ impl BlockEpollHandler {
                     len = 0;
                 }
             }
-            used_desc_heads[used_count] = (avail_desc.index, len);
-            used_count += 1;
+            used_desc_heads.push((avail_desc.index, len));
         }
         if rate_limited {
             // If rate limiting kicked in, queue had advanced one element that we aborted
@@ -319,10 +933,78 @@ impl BlockEpollHandler {
             queue.go_to_previous_position();
         }
 
-        for &(desc_index, len) in &used_desc_heads[..used_count] {
+        for &(desc_index, len) in &used_desc_heads {
             queue.add_used(&self.mem, desc_index, len);
         }
-        used_count > 0
+
+        if submitted {
+            if let Some(ref mut async_io) = self.async_io {
+                if let Err(e) = async_io.ring.submit() {
+                    error!("Failed to submit requests: {:?}", e);
+                    METRICS.block.execute_fails.inc();
+                }
+            }
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    fn process_completions(&mut self) -> bool {
+        let async_io = match self.async_io {
+            Some(ref mut async_io) => async_io,
+            None => return false,
+        };
+        let queue = &mut self.queue;
+
+        let mut used = false;
+        while let Some(completion) = async_io.ring.pop_completion() {
+            let request = match async_io
+                .requests
+                .get_mut(completion.user_data as usize)
+                .and_then(Option::take)
+            {
+                Some(request) => request,
+                None => {
+                    error!("Received a completion for an unknown request.");
+                    continue;
+                }
+            };
+
+            let expected = match request.request_type {
+                RequestType::Flush => 0,
+                _ => request.data_len,
+            };
+            let (status, len) = if completion.result >= 0 && completion.result as u32 == expected {
+                match request.request_type {
+                    RequestType::In => {
+                        METRICS.block.read_count.add(request.data_len as usize);
+                        (VIRTIO_BLK_S_OK, request.data_len)
+                    }
+                    RequestType::Out => {
+                        METRICS.block.write_count.add(request.data_len as usize);
+                        (VIRTIO_BLK_S_OK, 0)
+                    }
+                    _ => {
+                        METRICS.block.flush_count.inc();
+                        (VIRTIO_BLK_S_OK, 0)
+                    }
+                }
+            } else {
+                error!(
+                    "Failed to execute request: {:?} returned {}",
+                    request.request_type, completion.result
+                );
+                METRICS.block.invalid_reqs_count.inc();
+                (VIRTIO_BLK_S_IOERR, 1)
+            };
+            // We use unwrap because the request parsing process already checked that the
+            // status_addr was valid.
+            self.mem
+                .write_obj_at_addr(status, request.status_addr)
+                .unwrap();
+            queue.add_used(&self.mem, completion.user_data as u16, len);
+            used = true;
+        }
+        used
     }
 
     fn signal_used_queue(&self) {
@@ -335,8 +1017,21 @@ impl BlockEpollHandler {
     }
 
     fn update_disk_image(&mut self, disk_image: File) {
-        self.disk_image = disk_image;
-        self.disk_image_id = build_disk_image_id(&self.disk_image);
+        let mut disk = self.disk.lock().expect("Failed to acquire disk lock");
+        let id = build_disk_image_id(&disk_image);
+        disk.image = match disk.cache_type {
+            // The new image is opened with `O_DIRECT` as well.
+            CacheType::Direct => match DirectFile::new(disk_image) {
+                Ok(direct_file) => DiskImage::Direct(direct_file),
+                Err(e) => {
+                    error!("Failed to update the disk image: {:?}", e);
+                    METRICS.block.update_fails.inc();
+                    return;
+                }
+            },
+            _ => DiskImage::Raw(disk_image),
+        };
+        disk.id = id;
         METRICS.block.update_count.inc();
     }
 }
@@ -352,21 +1047,49 @@ impl EpollHandler for BlockEpollHandler {
                     return;
                 }
 
-                // While limiter is blocked, don't process any more requests.
-                if self.rate_limiter.is_blocked() {
+                // While the shared limiter is blocked, don't process any more requests. The read
+                // and write limiters are only checked against the requests they charge.
+                if self
+                    .rate_limiter
+                    .lock()
+                    .expect("Failed to acquire rate limiter lock")
+                    .shared
+                    .is_blocked()
+                {
                     return;
                 }
 
-                if self.process_queue(0) {
+                if self.process_queue() {
                     self.signal_used_queue();
                 }
             }
-            RATE_LIMITER_EVENT => {
+            RATE_LIMITER_EVENT | READ_RATE_LIMITER_EVENT | WRITE_RATE_LIMITER_EVENT => {
                 METRICS.block.rate_limiter_event_count.inc();
                 // Upon rate limiter event, call the rate limiter handler
-                // and restart processing the queue.
-                if self.rate_limiter.event_handler().is_ok() && self.process_queue(0) {
-                    self.signal_used_queue();
+                // and restart processing the queues.
+                let unblocked = {
+                    let mut rate_limiter = self
+                        .rate_limiter
+                        .lock()
+                        .expect("Failed to acquire rate limiter lock");
+                    let rate_limiter = &mut *rate_limiter;
+                    let limiter = match device_event {
+                        READ_RATE_LIMITER_EVENT => rate_limiter.read.as_mut(),
+                        WRITE_RATE_LIMITER_EVENT => rate_limiter.write.as_mut(),
+                        _ => Some(&mut rate_limiter.shared),
+                    };
+                    limiter.map_or(false, |limiter| limiter.event_handler().is_ok())
+                };
+                if unblocked {
+                    for kick in &self.rate_limiter_kicks {
+                        if let Err(e) = kick.write(1) {
+                            error!("Failed to kick queue: {:?}", e);
+                            METRICS.block.event_fails.inc();
+                        }
+                    }
+                    if self.process_queue() {
+                        self.signal_used_queue();
+                    }
                 }
             }
             FS_UPDATE_EVENT => {
@@ -377,14 +1100,65 @@ impl EpollHandler for BlockEpollHandler {
                     panic!("Received update disk image event with empty payload.")
                 }
             }
+            COMPLETION_EVENT => {
+                if let Some(ref async_io) = self.async_io {
+                    if let Err(e) = async_io.completion_evt.read() {
+                        error!("Failed to get completion event: {:?}", e);
+                        METRICS.block.event_fails.inc();
+                        return;
+                    }
+                }
+
+                if self.process_completions() {
+                    self.signal_used_queue();
+                }
+            }
             _ => panic!("Unknown event type was received."),
         }
     }
+
+    // Waits for the requests submitted to the io_uring to complete, so that the rings of the
+    // queue hold its whole state.
+    fn drain(&mut self) -> SysResult<()> {
+        let mut used = false;
+        let result = loop {
+            match self.async_io {
+                Some(ref mut async_io) if async_io.requests.iter().any(Option::is_some) => {
+                    // Requests which failed to be submitted would never complete.
+                    if let Err(e) = async_io.ring.submit().and_then(|_| async_io.ring.wait(1)) {
+                        METRICS.block.event_fails.inc();
+                        break Err(e);
+                    }
+                }
+                _ => break Ok(()),
+            }
+            used |= self.process_completions();
+        };
+        if used {
+            self.signal_used_queue();
+        }
+        result
+    }
+
+    fn update_rate_limiters(&mut self, payload: EpollHandlerPayload) -> io::Result<()> {
+        if let EpollHandlerPayload::DriveRateLimiterPayload(update) = payload {
+            self.update_rate_limiter(update).map_err(|e| {
+                METRICS.block.update_fails.inc();
+                e
+            })
+        } else {
+            // This path can only be reached if we have a logical problem in our code.
+            panic!("Received rate limiter update with an invalid payload.")
+        }
+    }
 }
 
 pub struct EpollConfig {
     q_avail_token: u64,
     rate_limiter_token: u64,
+    read_rate_limiter_token: u64,
+    write_rate_limiter_token: u64,
+    completion_token: u64,
     epoll_raw_fd: RawFd,
     sender: mpsc::Sender<Box<EpollHandler>>,
 }
@@ -398,43 +1172,90 @@ impl EpollConfig {
         EpollConfig {
             q_avail_token: first_token + QUEUE_AVAIL_EVENT as u64,
             rate_limiter_token: first_token + RATE_LIMITER_EVENT as u64,
+            read_rate_limiter_token: first_token + READ_RATE_LIMITER_EVENT as u64,
+            write_rate_limiter_token: first_token + WRITE_RATE_LIMITER_EVENT as u64,
+            completion_token: first_token + COMPLETION_EVENT as u64,
             epoll_raw_fd,
             sender,
         }
     }
+
+    // The tokens of the shared, read and write rate limiters.
+    fn rate_limiter_tokens(&self) -> [u64; 3] {
+        [
+            self.rate_limiter_token,
+            self.read_rate_limiter_token,
+            self.write_rate_limiter_token,
+        ]
+    }
 }
 
 /// Virtio device for exposing block level read/write operations on a host file.
 pub struct Block {
-    disk_image: Option<File>,
+    disk_image: Option<DiskImage>,
     avail_features: u64,
     acked_features: u64,
     config_space: Vec<u8>,
-    epoll_config: EpollConfig,
-    rate_limiter: Option<RateLimiter>,
+    // One per queue.
+    epoll_configs: Vec<EpollConfig>,
+    rate_limiter: Option<BlockRateLimiter>,
+    queue_sizes: Vec<u16>,
+    cache_type: CacheType,
+    // One per queue when requests are executed through io_uring, empty otherwise.
+    async_ios: Vec<AsyncIo>,
 }
 
-pub fn build_config_space(disk_size: u64) -> Vec<u8> {
-    // We only support disk size, which uses the first two words of the configuration space.
+pub fn build_config_space(disk_size: u64, num_queues: u16) -> Vec<u8> {
+    // We support the disk size, which uses the first two words of the configuration space, the
+    // number of queues and the limits of the discard and write zeroes requests. The fields in
+    // between are zero.
     // If the image is not a multiple of the sector size, the tail bits are not exposed.
     // The config space is little endian.
-    let mut config = Vec::with_capacity(CONFIG_SPACE_SIZE);
-    let num_sectors = disk_size >> SECTOR_SHIFT;
-    for i in 0..8 {
-        config.push((num_sectors >> (8 * i)) as u8);
-    }
+    let mut config = vec![0u8; CONFIG_SPACE_SIZE];
+    LittleEndian::write_u64(&mut config[0..8], disk_size >> SECTOR_SHIFT);
+    LittleEndian::write_u32(
+        &mut config[CONFIG_MAX_DISCARD_SECTORS..CONFIG_MAX_DISCARD_SECTORS + 4],
+        MAX_SEGMENT_SECTORS,
+    );
+    LittleEndian::write_u32(
+        &mut config[CONFIG_MAX_DISCARD_SEG..CONFIG_MAX_DISCARD_SEG + 4],
+        MAX_SEGMENTS,
+    );
+    LittleEndian::write_u32(
+        &mut config[CONFIG_DISCARD_SECTOR_ALIGNMENT..CONFIG_DISCARD_SECTOR_ALIGNMENT + 4],
+        1,
+    );
+    LittleEndian::write_u32(
+        &mut config[CONFIG_MAX_WRITE_ZEROES_SECTORS..CONFIG_MAX_WRITE_ZEROES_SECTORS + 4],
+        MAX_SEGMENT_SECTORS,
+    );
+    LittleEndian::write_u32(
+        &mut config[CONFIG_MAX_WRITE_ZEROES_SEG..CONFIG_MAX_WRITE_ZEROES_SEG + 4],
+        MAX_SEGMENTS,
+    );
+    config[CONFIG_WRITE_ZEROES_MAY_UNMAP] = 1;
+    LittleEndian::write_u16(
+        &mut config[CONFIG_NUM_QUEUES..CONFIG_NUM_QUEUES + 2],
+        num_queues,
+    );
     config
 }
 
 impl Block {
-    /// Create a new virtio block device that operates on the given file.
+    /// Create a new virtio block device that operates on the given disk image.
     ///
-    /// The given file must be seekable and sizable.
+    /// The device has one queue of `queue_size` descriptors for each of the `epoll_configs`.
+    /// If `io_engine` is `IoEngine::Async` but the image or the host does not support it, the
+    /// requests are executed synchronously. Flush requests are only advertised to the guest if
+    /// `cache_type` doesn't ignore them.
     pub fn new(
-        mut disk_image: File,
+        mut disk_image: DiskImage,
         is_disk_read_only: bool,
-        epoll_config: EpollConfig,
-        rate_limiter: Option<RateLimiter>,
+        epoll_configs: Vec<EpollConfig>,
+        rate_limiter: Option<BlockRateLimiter>,
+        io_engine: IoEngine,
+        queue_size: u16,
+        cache_type: CacheType,
     ) -> SysResult<Block> {
         let disk_size = disk_image.seek(SeekFrom::End(0))? as u64;
         if disk_size % SECTOR_SIZE != 0 {
@@ -449,15 +1270,53 @@ impl Block {
 
         if is_disk_read_only {
             avail_features |= 1 << VIRTIO_BLK_F_RO;
+        } else {
+            avail_features |= (1 << VIRTIO_BLK_F_DISCARD) | (1 << VIRTIO_BLK_F_WRITE_ZEROES);
+        };
+
+        if cache_type != CacheType::Unsafe {
+            avail_features |= 1 << VIRTIO_BLK_F_FLUSH;
+        }
+
+        let num_queues = epoll_configs.len();
+        if num_queues > 1 {
+            avail_features |= 1 << VIRTIO_BLK_F_MQ;
+        }
+
+        let async_ios = match (io_engine, &disk_image) {
+            (IoEngine::Async, DiskImage::Raw(_)) => {
+                match (0..num_queues).map(|_| AsyncIo::new(queue_size)).collect() {
+                    Ok(async_ios) => async_ios,
+                    Err(e) => {
+                        warn!(
+                            "Could not set up io_uring: {:?}. \
+                             Requests will be executed synchronously.",
+                            e
+                        );
+                        Vec::new()
+                    }
+                }
+            }
+            (IoEngine::Async, _) => {
+                warn!(
+                    "Only raw images without the Direct cache type support the Async engine. \
+                     Requests will be executed synchronously."
+                );
+                Vec::new()
+            }
+            (IoEngine::Sync, _) => Vec::new(),
         };
 
         Ok(Block {
             disk_image: Some(disk_image),
             avail_features,
             acked_features: 0u64,
-            config_space: build_config_space(disk_size),
-            epoll_config,
+            config_space: build_config_space(disk_size, num_queues as u16),
+            epoll_configs,
             rate_limiter,
+            queue_sizes: vec![queue_size; num_queues],
+            cache_type,
+            async_ios,
         })
     }
 }
@@ -468,7 +1327,7 @@ impl VirtioDevice for Block {
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn features(&self, page: u32) -> u32 {
@@ -528,7 +1387,7 @@ impl VirtioDevice for Block {
             return;
         }
         let (_, right) = self.config_space.split_at_mut(offset as usize);
-        right.copy_from_slice(&data[..]);
+        right[..data.len()].copy_from_slice(&data[..]);
     }
 
     fn activate(
@@ -537,12 +1396,13 @@ impl VirtioDevice for Block {
         interrupt_evt: EventFd,
         status: Arc<AtomicUsize>,
         queues: Vec<Queue>,
-        mut queue_evts: Vec<EventFd>,
+        queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
-        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+        let num_queues = self.queue_sizes.len();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
             error!(
                 "Cannot perform activate. Expected {} queue(s), got {}",
-                NUM_QUEUES,
+                num_queues,
                 queues.len()
             );
             METRICS.block.activate_fails.inc();
@@ -550,51 +1410,113 @@ impl VirtioDevice for Block {
         }
 
         if let Some(disk_image) = self.disk_image.take() {
-            let queue_evt = queue_evts.remove(0);
-            let queue_evt_raw_fd = queue_evt.as_raw_fd();
-
-            let disk_image_id = build_disk_image_id(&disk_image);
-            let handler = BlockEpollHandler {
-                queues,
-                mem,
-                disk_image,
-                interrupt_status: status,
-                interrupt_evt,
-                queue_evt,
-                rate_limiter: self.rate_limiter.take().unwrap_or_default(),
-                disk_image_id,
-            };
-            let rate_limiter_rawfd = handler.rate_limiter.as_raw_fd();
-
-            // The channel should be open at this point.
-            self.epoll_config
-                .sender
-                .send(Box::new(handler))
-                .expect("Failed to send through the channel");
+            let disk = Arc::new(Mutex::new(Disk {
+                id: build_disk_image_id(disk_image.file()),
+                image: disk_image,
+                cache_type: self.cache_type,
+            }));
+            let mut rate_limiter = self.rate_limiter.take().unwrap_or_default();
+            // All the queues resume processing when the rate-limit group unblocks.
+            for queue_evt in queue_evts.iter() {
+                rate_limiter.shared.add_group_kick(queue_evt).map_err(|e| {
+                    METRICS.block.activate_fails.inc();
+                    ActivateError::TryClone(e)
+                })?;
+            }
+            let rate_limiter_rawfds = rate_limiter.raw_fds();
+            let rate_limiter = Arc::new(Mutex::new(rate_limiter));
+            let mut async_ios = mem::replace(&mut self.async_ios, Vec::new()).into_iter();
 
-            //TODO: barrier needed here by any chance?
-            epoll::ctl(
-                self.epoll_config.epoll_raw_fd,
-                epoll::EPOLL_CTL_ADD,
-                queue_evt_raw_fd,
-                epoll::Event::new(epoll::EPOLLIN, self.epoll_config.q_avail_token),
-            )
-            .map_err(|e| {
-                METRICS.block.activate_fails.inc();
-                ActivateError::EpollCtl(e)
-            })?;
+            let mut rate_limiter_kicks = Vec::new();
+            for queue_evt in queue_evts.iter().skip(1) {
+                rate_limiter_kicks.push(queue_evt.try_clone().map_err(|e| {
+                    METRICS.block.activate_fails.inc();
+                    ActivateError::TryClone(e)
+                })?);
+            }
 
-            if rate_limiter_rawfd != -1 {
+            // Every queue gets its own handler, but all the handlers run on the thread of the
+            // device event loop and share the disk. The queues spread the load of the guest
+            // across its vCPUs; they don't add parallelism on the host.
+            for (i, (queue, queue_evt)) in queues.into_iter().zip(queue_evts).enumerate() {
+                let epoll_config = &self.epoll_configs[i];
+                let queue_evt_raw_fd = queue_evt.as_raw_fd();
+
+                let handler = BlockEpollHandler {
+                    queue,
+                    mem: mem.clone(),
+                    disk: disk.clone(),
+                    interrupt_status: status.clone(),
+                    interrupt_evt: interrupt_evt.try_clone().map_err(|e| {
+                        METRICS.block.activate_fails.inc();
+                        ActivateError::TryClone(e)
+                    })?,
+                    queue_evt,
+                    rate_limiter: rate_limiter.clone(),
+                    rate_limiter_kicks: if i == 0 {
+                        mem::replace(&mut rate_limiter_kicks, Vec::new())
+                    } else {
+                        Vec::new()
+                    },
+                    epoll_raw_fd: epoll_config.epoll_raw_fd,
+                    rate_limiter_tokens: epoll_config.rate_limiter_tokens(),
+                    async_io: async_ios.next(),
+                };
+                let completion_rawfd = handler
+                    .async_io
+                    .as_ref()
+                    .map(|async_io| async_io.completion_evt.as_raw_fd());
+
+                // The channel should be open at this point.
+                epoll_config
+                    .sender
+                    .send(Box::new(handler))
+                    .expect("Failed to send through the channel");
+
+                //TODO: barrier needed here by any chance?
                 epoll::ctl(
-                    self.epoll_config.epoll_raw_fd,
+                    epoll_config.epoll_raw_fd,
                     epoll::EPOLL_CTL_ADD,
-                    rate_limiter_rawfd,
-                    epoll::Event::new(epoll::EPOLLIN, self.epoll_config.rate_limiter_token),
+                    queue_evt_raw_fd,
+                    epoll::Event::new(epoll::EPOLLIN, epoll_config.q_avail_token),
                 )
                 .map_err(|e| {
                     METRICS.block.activate_fails.inc();
                     ActivateError::EpollCtl(e)
                 })?;
+
+                // The rate limiters are shared by the queues, their events go to the first one.
+                if i == 0 {
+                    for (&rawfd, &token) in rate_limiter_rawfds
+                        .iter()
+                        .zip(epoll_config.rate_limiter_tokens().iter())
+                        .filter(|&(&rawfd, _)| rawfd != -1)
+                    {
+                        epoll::ctl(
+                            epoll_config.epoll_raw_fd,
+                            epoll::EPOLL_CTL_ADD,
+                            rawfd,
+                            epoll::Event::new(epoll::EPOLLIN, token),
+                        )
+                        .map_err(|e| {
+                            METRICS.block.activate_fails.inc();
+                            ActivateError::EpollCtl(e)
+                        })?;
+                    }
+                }
+
+                if let Some(completion_rawfd) = completion_rawfd {
+                    epoll::ctl(
+                        epoll_config.epoll_raw_fd,
+                        epoll::EPOLL_CTL_ADD,
+                        completion_rawfd,
+                        epoll::Event::new(epoll::EPOLLIN, epoll_config.completion_token),
+                    )
+                    .map_err(|e| {
+                        METRICS.block.activate_fails.inc();
+                        ActivateError::EpollCtl(e)
+                    })?;
+                }
             }
 
             return Ok(());
@@ -606,6 +1528,7 @@ impl VirtioDevice for Block {
 
 #[cfg(test)]
 mod tests {
+    extern crate serde_json;
     extern crate tempfile;
 
     use self::tempfile::{tempfile, NamedTempFile};
@@ -614,6 +1537,7 @@ mod tests {
     use libc;
     use std::fs::{metadata, OpenOptions};
     use std::sync::mpsc::Receiver;
+    use std::sync::MutexGuard;
     use std::thread;
     use std::time::Duration;
     use std::u32;
@@ -630,16 +1554,20 @@ mod tests {
     }
 
     impl BlockEpollHandler {
-        fn set_queue(&mut self, idx: usize, q: Queue) {
-            self.queues[idx] = q;
+        fn set_queue(&mut self, q: Queue) {
+            self.queue = q;
+        }
+
+        fn get_rate_limiter(&self) -> MutexGuard<BlockRateLimiter> {
+            self.rate_limiter.lock().unwrap()
         }
 
-        fn get_rate_limiter(&self) -> &RateLimiter {
-            &self.rate_limiter
+        fn set_rate_limiter(&mut self, rate_limiter: BlockRateLimiter) {
+            self.rate_limiter = Arc::new(Mutex::new(rate_limiter));
         }
 
-        fn set_rate_limiter(&mut self, rate_limiter: RateLimiter) {
-            self.rate_limiter = rate_limiter;
+        fn disk(&self) -> MutexGuard<Disk> {
+            self.disk.lock().unwrap()
         }
     }
 
@@ -662,7 +1590,19 @@ mod tests {
             // Rate limiting is enabled but with a high operation rate (10 million ops/s).
             let rate_limiter = RateLimiter::new(0, None, 0, 100000, None, 10).unwrap();
             DummyBlock {
-                block: Block::new(f, is_disk_read_only, epoll_config, Some(rate_limiter)).unwrap(),
+                block: Block::new(
+                    DiskImage::Raw(f),
+                    is_disk_read_only,
+                    vec![epoll_config],
+                    Some(BlockRateLimiter {
+                        shared: rate_limiter,
+                        ..Default::default()
+                    }),
+                    IoEngine::Sync,
+                    DEFAULT_QUEUE_SIZE,
+                    CacheType::Unsafe,
+                )
+                .unwrap(),
                 epoll_raw_fd,
                 _receiver,
             }
@@ -688,13 +1628,13 @@ mod tests {
 
         assert!(vq.end().0 < 0x1000);
 
-        let queues = vec![vq.create_queue()];
+        let queue = vq.create_queue();
         let disk_image = b.disk_image.take().unwrap();
         let status = Arc::new(AtomicUsize::new(0));
         let interrupt_evt = EventFd::new().unwrap();
         let queue_evt = EventFd::new().unwrap();
 
-        let disk_image_id_str = build_device_id(&disk_image).unwrap();
+        let disk_image_id_str = build_device_id(disk_image.file()).unwrap();
         let mut disk_image_id = vec![0; VIRTIO_BLK_ID_BYTES as usize];
         let disk_image_id_bytes = disk_image_id_str.as_bytes();
         let bytes_to_copy = cmp::min(disk_image_id_bytes.len(), VIRTIO_BLK_ID_BYTES as usize);
@@ -703,14 +1643,25 @@ mod tests {
         }
         (
             BlockEpollHandler {
-                queues,
+                queue,
                 mem: mem.clone(),
-                disk_image,
+                disk: Arc::new(Mutex::new(Disk {
+                    image: disk_image,
+                    id: disk_image_id,
+                    cache_type: CacheType::Unsafe,
+                })),
                 interrupt_status: status,
                 interrupt_evt,
                 queue_evt,
-                rate_limiter: RateLimiter::default(),
-                disk_image_id,
+                rate_limiter: Arc::new(Mutex::new(BlockRateLimiter::default())),
+                rate_limiter_kicks: Vec::new(),
+                epoll_raw_fd: -1,
+                rate_limiter_tokens: [
+                    RATE_LIMITER_EVENT as u64,
+                    READ_RATE_LIMITER_EVENT as u64,
+                    WRITE_RATE_LIMITER_EVENT as u64,
+                ],
+                async_io: None,
             },
             vq,
         )
@@ -754,6 +1705,17 @@ mod tests {
         assert_eq!(h.interrupt_evt.read(), Ok(2));
     }
 
+    fn invoke_handler_for_completion_event(h: &mut BlockEpollHandler) {
+        // leave at least one event here so that reading it later won't block
+        h.interrupt_evt.write(1).unwrap();
+        // wait for the submitted request to complete
+        h.async_io.as_mut().unwrap().ring.wait(1).unwrap();
+        // handle event
+        h.handle_event(COMPLETION_EVENT, 0, EpollHandlerPayload::Empty);
+        // validate the queue operation finished successfully
+        assert_eq!(h.interrupt_evt.read(), Ok(2));
+    }
+
     #[test]
     fn test_request_type() {
         let m = &GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
@@ -774,6 +1736,13 @@ mod tests {
         m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_GET_ID, a).unwrap();
         assert_eq!(request_type(m, a).unwrap(), RequestType::GetDeviceID);
 
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_DISCARD, a).unwrap();
+        assert_eq!(request_type(m, a).unwrap(), RequestType::Discard);
+
+        m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_WRITE_ZEROES, a)
+            .unwrap();
+        assert_eq!(request_type(m, a).unwrap(), RequestType::WriteZeroes);
+
         // The value written here should be invalid.
         m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH + 10, a)
             .unwrap();
@@ -926,7 +1895,7 @@ mod tests {
         // Test `queue_max_sizes()`.
         {
             let x = b.queue_max_sizes();
-            assert_eq!(x, QUEUE_SIZES);
+            assert_eq!(x, &[DEFAULT_QUEUE_SIZE]);
 
             // power of 2?
             for &y in x {
@@ -1021,7 +1990,11 @@ mod tests {
             b.read_config(0, &mut new_config_read);
             assert_eq!(new_config, new_config_read);
             // Invalid write.
-            check_metric_after_block!(&METRICS.block.cfg_fails, 1, b.write_config(5, &new_config));
+            check_metric_after_block!(
+                &METRICS.block.cfg_fails,
+                1,
+                b.write_config(CONFIG_SPACE_SIZE as u64 - 4, &new_config)
+            );
             // Make sure nothing got written.
             new_config_read = [0u8; 8];
             b.read_config(0, &mut new_config_read);
@@ -1029,6 +2002,208 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_discard_write_zeroes_config() {
+        let mut dummy = DummyBlock::new(false);
+        let b = dummy.block();
+
+        let features = b.features(0);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_DISCARD), 0);
+        assert_ne!(features & (1 << VIRTIO_BLK_F_WRITE_ZEROES), 0);
+        assert_eq!(features & (1 << VIRTIO_BLK_F_RO), 0);
+        // Flushes are ignored by default.
+        assert_eq!(features & (1 << VIRTIO_BLK_F_FLUSH), 0);
+
+        let mut data = [0u8; 4];
+        b.read_config(CONFIG_MAX_DISCARD_SEG as u64, &mut data);
+        assert_eq!(LittleEndian::read_u32(&data), MAX_SEGMENTS);
+        b.read_config(CONFIG_MAX_WRITE_ZEROES_SECTORS as u64, &mut data);
+        assert_eq!(LittleEndian::read_u32(&data), MAX_SEGMENT_SECTORS);
+        let mut data = [0u8; 1];
+        b.read_config(CONFIG_WRITE_ZEROES_MAY_UNMAP as u64, &mut data);
+        assert_eq!(data[0], 1);
+    }
+
+    #[test]
+    fn test_block_rate_limiter() {
+        let mut rate_limiter: BlockRateLimiter = serde_json::from_str(
+            r#"{
+                "ops": { "size": 100, "refill_time": 1000 },
+                "read": { "bandwidth": { "size": 1000, "refill_time": 1000 } },
+                "write": { "ops": { "size": 10, "refill_time": 1000 } },
+                "bytes_per_op": 131072
+            }"#,
+        )
+        .unwrap();
+        assert!(rate_limiter.read.is_some());
+        assert!(rate_limiter.write.is_some());
+
+        let mut request = Request {
+            request_type: RequestType::Out,
+            sector: 0,
+            data_addr: GuestAddress(0),
+            data_len: 128 << 10,
+            status_addr: GuestAddress(0),
+        };
+        assert_eq!(rate_limiter.cost(&request), (1, 128 << 10));
+        request.data_len = (128 << 10) + 1;
+        assert_eq!(rate_limiter.cost(&request), (2, (128 << 10) + 1));
+        request.data_len = 512;
+        assert_eq!(rate_limiter.cost(&request), (1, 512));
+        // The cost doesn't overflow with the largest bytes_per_op.
+        rate_limiter.bytes_per_op = Some(u64::max_value());
+        assert_eq!(rate_limiter.cost(&request), (1, 512));
+        rate_limiter.bytes_per_op = Some(128 << 10);
+        request.request_type = RequestType::Discard;
+        assert_eq!(rate_limiter.cost(&request), (1, 0));
+
+        // The write limiter runs out of budget first, and the shared tokens are given back.
+        request.request_type = RequestType::Out;
+        request.data_len = 10 * (128 << 10);
+        assert!(rate_limiter.consume(&request));
+        assert!(!rate_limiter.consume(&request));
+        assert!(rate_limiter.shared.consume(85, TokenType::Ops));
+
+        // Reads only go through the read limiter and the shared one.
+        request.request_type = RequestType::In;
+        request.data_len = 1000;
+        assert!(rate_limiter.consume(&request));
+        assert!(!rate_limiter.consume(&request));
+
+        // Without bytes_per_op, a request costs a single op.
+        let rate_limiter = BlockRateLimiter::default();
+        assert_eq!(rate_limiter.cost(&request), (1, 1000));
+
+        assert!(serde_json::from_str::<BlockRateLimiter>(r#"{"bytes_per_op": 0}"#).is_err());
+        assert!(serde_json::from_str::<BlockRateLimiter>(r#"{"foo": 0}"#).is_err());
+    }
+
+    #[test]
+    fn test_block_rate_limiter_update() {
+        let mut rate_limiter: BlockRateLimiter =
+            serde_json::from_str(r#"{"write": {"ops": {"size": 10, "refill_time": 1000}}}"#)
+                .unwrap();
+        let update: BlockRateLimiterUpdate = serde_json::from_str(
+            r#"{
+                "ops": {"size": 100, "refill_time": 1000},
+                "read": {"ops": {"size": 2, "refill_time": 1000}},
+                "write": {"ops": {"size": 0, "refill_time": 0}},
+                "bytes_per_op": 4096
+            }"#,
+        )
+        .unwrap();
+        assert!(rate_limiter.update(update).is_ok());
+        assert_eq!(rate_limiter.bytes_per_op, Some(4096));
+
+        // The read limiter was created, and the write limiter no longer limits the ops.
+        let mut request = Request {
+            request_type: RequestType::In,
+            sector: 0,
+            data_addr: GuestAddress(0),
+            data_len: 4096,
+            status_addr: GuestAddress(0),
+        };
+        assert!(rate_limiter.consume(&request));
+        assert!(rate_limiter.consume(&request));
+        assert!(!rate_limiter.consume(&request));
+        request.request_type = RequestType::Out;
+        for _ in 0..20 {
+            assert!(rate_limiter.consume(&request));
+        }
+
+        // What is left out stays unchanged.
+        assert!(rate_limiter
+            .update(BlockRateLimiterUpdate::default())
+            .is_ok());
+        assert_eq!(rate_limiter.bytes_per_op, Some(4096));
+        assert!(rate_limiter.read.is_some());
+
+        assert!(serde_json::from_str::<BlockRateLimiterUpdate>(r#"{"bytes_per_op": 0}"#).is_err());
+        assert!(serde_json::from_str::<BlockRateLimiterUpdate>(r#"{"foo": 0}"#).is_err());
+    }
+
+    #[test]
+    fn test_cache_type() {
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        for &cache_type in &[CacheType::Writeback, CacheType::Direct] {
+            let (sender, _receiver) = mpsc::channel();
+            let f: File = tempfile().unwrap();
+            f.set_len(0x1000).unwrap();
+            let b = Block::new(
+                DiskImage::Raw(f),
+                false,
+                vec![EpollConfig::new(0, epoll_raw_fd, sender)],
+                None,
+                IoEngine::Sync,
+                DEFAULT_QUEUE_SIZE,
+                cache_type,
+            )
+            .unwrap();
+            assert_ne!(b.features(0) & (1 << VIRTIO_BLK_F_FLUSH), 0);
+        }
+        unsafe { libc::close(epoll_raw_fd) };
+    }
+
+    #[test]
+    fn test_multi_queue() {
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender0, receiver0) = mpsc::channel();
+        let (sender1, receiver1) = mpsc::channel();
+        let epoll_configs = vec![
+            EpollConfig::new(0, epoll_raw_fd, sender0),
+            EpollConfig::new(BLOCK_EVENTS_COUNT as u64, epoll_raw_fd, sender1),
+        ];
+        let f: File = tempfile().unwrap();
+        f.set_len(0x1000).unwrap();
+        let mut b = Block::new(
+            DiskImage::Raw(f),
+            false,
+            epoll_configs,
+            None,
+            IoEngine::Sync,
+            64,
+            CacheType::Unsafe,
+        )
+        .unwrap();
+
+        assert_ne!(b.features(0) & (1 << VIRTIO_BLK_F_MQ), 0);
+        let mut data = [0u8; 2];
+        b.read_config(CONFIG_NUM_QUEUES as u64, &mut data);
+        assert_eq!(LittleEndian::read_u16(&data), 2);
+        assert_eq!(b.queue_max_sizes(), &[64, 64]);
+
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq0 = VirtQueue::new(GuestAddress(0), &m, 16);
+        let vq1 = VirtQueue::new(GuestAddress(0x1000), &m, 16);
+        assert!(vq1.end().0 < 0x2000);
+
+        // Both queues are needed.
+        assert!(match b.activate(
+            m.clone(),
+            EventFd::new().unwrap(),
+            Arc::new(AtomicUsize::new(0)),
+            vec![vq0.create_queue()],
+            vec![EventFd::new().unwrap()],
+        ) {
+            Err(ActivateError::BadActivate) => true,
+            _ => false,
+        });
+        assert!(b
+            .activate(
+                m.clone(),
+                EventFd::new().unwrap(),
+                Arc::new(AtomicUsize::new(0)),
+                vec![vq0.create_queue(), vq1.create_queue()],
+                vec![EventFd::new().unwrap(), EventFd::new().unwrap()],
+            )
+            .is_ok());
+        // Each queue is serviced by its own handler.
+        assert!(receiver0.try_recv().is_ok());
+        assert!(receiver1.try_recv().is_ok());
+
+        unsafe { libc::close(epoll_raw_fd) };
+    }
+
     #[test]
     #[should_panic]
     fn test_invalid_event_handler() {
@@ -1051,12 +2226,75 @@ mod tests {
         h.handle_event(FS_UPDATE_EVENT, 0, EpollHandlerPayload::Empty);
     }
 
+    #[test]
+    #[should_panic]
+    fn test_update_rate_limiters_payload_error() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _vq) = default_test_blockepollhandler(&m);
+        // This should panic because the payload is not a rate limiter update.
+        let _ = h.update_rate_limiters(EpollHandlerPayload::Empty);
+    }
+
+    #[test]
+    fn test_update_rate_limiters() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _vq) = default_test_blockepollhandler(&m);
+        let rate_limiter = h.rate_limiter.clone();
+        h.epoll_raw_fd = epoll::create(true).unwrap();
+
+        // Limit the shared rate limiter to 1 op every 100ms, and the reads to 1 op every 100ms.
+        let update = BlockRateLimiterUpdate {
+            ops: Some(TokenBucket::new(1, None, 100)),
+            read: Some(RateLimiterUpdate {
+                bandwidth: None,
+                ops: Some(TokenBucket::new(1, None, 100)),
+            }),
+            ..Default::default()
+        };
+        h.update_rate_limiters(EpollHandlerPayload::DriveRateLimiterPayload(update))
+            .unwrap();
+        {
+            let rate_limiter = &mut rate_limiter.lock().unwrap().shared;
+            assert!(rate_limiter.consume(1, TokenType::Ops));
+            assert!(!rate_limiter.consume(1, TokenType::Ops));
+            assert!(rate_limiter.consume(u64::max_value(), TokenType::Bytes));
+        }
+
+        // The timers created by the update, for the shared and the new read limiters, are
+        // polled.
+        let rawfds = rate_limiter.lock().unwrap().raw_fds();
+        assert_eq!(rawfds[2], -1);
+        for &rawfd in &rawfds[..2] {
+            assert!(epoll::ctl(
+                h.epoll_raw_fd,
+                epoll::EPOLL_CTL_ADD,
+                rawfd,
+                epoll::Event::new(epoll::EPOLLIN, 0),
+            )
+            .is_err());
+        }
+        unsafe { libc::close(h.epoll_raw_fd) };
+
+        // The update fails when the timer of the new write limiter cannot be polled.
+        h.epoll_raw_fd = -1;
+        let update = BlockRateLimiterUpdate {
+            write: Some(RateLimiterUpdate {
+                bandwidth: None,
+                ops: Some(TokenBucket::new(1, None, 100)),
+            }),
+            ..Default::default()
+        };
+        assert!(h
+            .update_rate_limiters(EpollHandlerPayload::DriveRateLimiterPayload(update))
+            .is_err());
+    }
+
     #[test]
     fn test_handler() {
         let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
         let (mut h, vq) = default_test_blockepollhandler(&m);
 
-        let blk_metadata = h.disk_image.metadata();
+        let blk_metadata = h.disk().image.file().metadata();
 
         for i in 0..3 {
             vq.avail.ring[i].set(i as u16);
@@ -1100,7 +2338,7 @@ mod tests {
         {
             // reset the queue to reuse descriptors & memory
             vq.used.idx.set(0);
-            h.set_queue(0, vq.create_queue());
+            h.set_queue(vq.create_queue());
 
             // first desc no longer writable
             vq.dtable[0].flags.set(VIRTQ_DESC_F_NEXT);
@@ -1121,7 +2359,7 @@ mod tests {
 
         {
             vq.used.idx.set(0);
-            h.set_queue(0, vq.create_queue());
+            h.set_queue(vq.create_queue());
 
             // set sector to a valid number but large enough that the full 0x1000 read will fail
             m.write_obj_at_addr::<u64>(10, GuestAddress(0x1000 + 8))
@@ -1139,11 +2377,11 @@ mod tests {
         }
 
         // test unsupported block commands
-        // currently 0, 1, 4, 8 are supported
+        // currently 0, 1, 4, 8, 11, 13 are supported
 
         {
             vq.used.idx.set(0);
-            h.set_queue(0, vq.create_queue());
+            h.set_queue(vq.create_queue());
 
             // set sector to 0
             m.write_obj_at_addr::<u64>(0, GuestAddress(0x1000 + 8))
@@ -1169,7 +2407,7 @@ mod tests {
             // write
 
             vq.used.idx.set(0);
-            h.set_queue(0, vq.create_queue());
+            h.set_queue(vq.create_queue());
 
             m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
                 .unwrap();
@@ -1192,7 +2430,7 @@ mod tests {
             // read
 
             vq.used.idx.set(0);
-            h.set_queue(0, vq.create_queue());
+            h.set_queue(vq.create_queue());
 
             m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
                 .unwrap();
@@ -1215,7 +2453,7 @@ mod tests {
             // testing that the flush request completes successfully
 
             vq.used.idx.set(0);
-            h.set_queue(0, vq.create_queue());
+            h.set_queue(vq.create_queue());
 
             m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH, GuestAddress(0x1000))
                 .unwrap();
@@ -1228,13 +2466,120 @@ mod tests {
                 m.read_obj_from_addr::<u32>(status_addr).unwrap(),
                 VIRTIO_BLK_S_OK
             );
+
+            // The flush syncs the image when the cache type doesn't ignore it.
+            h.disk().cache_type = CacheType::Writeback;
+            vq.used.idx.set(0);
+            h.set_queue(vq.create_queue());
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_S_IOERR, status_addr)
+                .unwrap();
+
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            h.disk().cache_type = CacheType::Unsafe;
+        }
+
+        {
+            // testing that discard and write zeroes requests deallocate or zero the disk
+
+            let segment_addr = data_addr;
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(16);
+            m.write_obj_at_addr::<u64>(0, segment_addr).unwrap();
+            m.write_obj_at_addr::<u32>(1, segment_addr.unchecked_add(8))
+                .unwrap();
+            m.write_obj_at_addr::<u32>(0, segment_addr.unchecked_add(12))
+                .unwrap();
+
+            for (request_type, flags, metric) in [
+                (VIRTIO_BLK_T_DISCARD, 0, &METRICS.block.discard_count),
+                (
+                    VIRTIO_BLK_T_WRITE_ZEROES,
+                    0,
+                    &METRICS.block.write_zeroes_count,
+                ),
+                (
+                    VIRTIO_BLK_T_WRITE_ZEROES,
+                    VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP,
+                    &METRICS.block.write_zeroes_count,
+                ),
+            ]
+            .iter()
+            {
+                h.disk().image.seek(SeekFrom::Start(0)).unwrap();
+                h.disk().image.write_all(&[0xff; 8]).unwrap();
+
+                vq.used.idx.set(0);
+                h.set_queue(vq.create_queue());
+                m.write_obj_at_addr::<u32>(*request_type, GuestAddress(0x1000))
+                    .unwrap();
+                m.write_obj_at_addr::<u32>(*flags, segment_addr.unchecked_add(12))
+                    .unwrap();
+
+                check_metric_after_block!(metric, 1, invoke_handler_for_queue_event(&mut h));
+                assert_eq!(vq.used.idx.get(), 1);
+                assert_eq!(vq.used.ring[0].get().len, 0);
+                assert_eq!(
+                    m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                    VIRTIO_BLK_S_OK
+                );
+
+                let mut buf = [0u8; 8];
+                h.disk().image.seek(SeekFrom::Start(0)).unwrap();
+                h.disk().image.read_exact(&mut buf).unwrap();
+                assert_eq!(buf, [0u8; 8]);
+                assert_eq!(h.disk().image.file().metadata().unwrap().len(), 0x1000);
+            }
+
+            // Discard requests cannot ask for unmapping.
+            vq.used.idx.set(0);
+            h.set_queue(vq.create_queue());
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_DISCARD, GuestAddress(0x1000))
+                .unwrap();
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_UNSUPP
+            );
+
+            // The range has to be within the disk.
+            vq.used.idx.set(0);
+            h.set_queue(vq.create_queue());
+            m.write_obj_at_addr::<u32>(0, segment_addr.unchecked_add(12))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(8, segment_addr).unwrap();
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+
+            // The segments have to be whole.
+            vq.used.idx.set(0);
+            h.set_queue(vq.create_queue());
+            m.write_obj_at_addr::<u64>(0, segment_addr).unwrap();
+            vq.dtable[1].len.set(12);
+            invoke_handler_for_queue_event(&mut h);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+            vq.dtable[1].len.set(8);
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
         }
 
         {
             // testing that the driver receives the correct device id
 
             vq.used.idx.set(0);
-            h.set_queue(0, vq.create_queue());
+            h.set_queue(vq.create_queue());
 
             m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_GET_ID, GuestAddress(0x1000))
                 .unwrap();
@@ -1280,8 +2625,14 @@ mod tests {
             assert!(rl.consume(8, TokenType::Bytes));
 
             vq.used.idx.set(0);
-            h.set_queue(0, vq.create_queue());
-            h.set_rate_limiter(rl);
+            h.set_queue(vq.create_queue());
+            h.set_rate_limiter(BlockRateLimiter {
+                shared: rl,
+                ..Default::default()
+            });
+            // the event of another queue sharing the rate limiter
+            let other_queue_evt = EventFd::new().unwrap();
+            h.rate_limiter_kicks = vec![other_queue_evt.try_clone().unwrap()];
 
             m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
                 .unwrap();
@@ -1299,7 +2650,7 @@ mod tests {
                 h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty);
 
                 // assert that limiter is blocked
-                assert!(h.get_rate_limiter().is_blocked());
+                assert!(h.get_rate_limiter().shared.is_blocked());
                 // assert that no operation actually completed (limiter blocked it)
                 assert_eq!(h.interrupt_evt.read(), Ok(1));
                 // make sure the data is still queued for processing
@@ -1316,9 +2667,11 @@ mod tests {
                 h.interrupt_evt.write(1).unwrap();
                 h.handle_event(RATE_LIMITER_EVENT, 0, EpollHandlerPayload::Empty);
                 // validate the rate_limiter is no longer blocked
-                assert!(!h.get_rate_limiter().is_blocked());
+                assert!(!h.get_rate_limiter().shared.is_blocked());
                 // make sure the virtio queue operation completed this time
                 assert_eq!(h.interrupt_evt.read(), Ok(2));
+                // the other queue was kicked
+                assert_eq!(other_queue_evt.read(), Ok(1));
 
                 // make sure the data queue advanced
                 assert_eq!(vq.used.idx.get(), 1);
@@ -1329,6 +2682,7 @@ mod tests {
                     VIRTIO_BLK_S_OK
                 );
             }
+            h.rate_limiter_kicks.clear();
         }
 
         // test the ops/s rate limiter
@@ -1339,8 +2693,11 @@ mod tests {
             assert!(rl.consume(1, TokenType::Ops));
 
             vq.used.idx.set(0);
-            h.set_queue(0, vq.create_queue());
-            h.set_rate_limiter(rl);
+            h.set_queue(vq.create_queue());
+            h.set_rate_limiter(BlockRateLimiter {
+                shared: rl,
+                ..Default::default()
+            });
 
             m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
                 .unwrap();
@@ -1358,7 +2715,7 @@ mod tests {
                 h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty);
 
                 // assert that limiter is blocked
-                assert!(h.get_rate_limiter().is_blocked());
+                assert!(h.get_rate_limiter().shared.is_blocked());
                 // assert that no operation actually completed (limiter blocked it)
                 assert_eq!(h.interrupt_evt.read(), Ok(1));
                 // make sure the data is still queued for processing
@@ -1374,7 +2731,7 @@ mod tests {
                 h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty);
 
                 // assert that limiter is blocked
-                assert!(h.get_rate_limiter().is_blocked());
+                assert!(h.get_rate_limiter().shared.is_blocked());
                 // assert that no operation actually completed (limiter blocked it)
                 assert_eq!(h.interrupt_evt.read(), Ok(1));
                 // make sure the data is still queued for processing
@@ -1391,7 +2748,7 @@ mod tests {
                 h.interrupt_evt.write(1).unwrap();
                 h.handle_event(RATE_LIMITER_EVENT, 0, EpollHandlerPayload::Empty);
                 // validate the rate_limiter is no longer blocked
-                assert!(!h.get_rate_limiter().is_blocked());
+                assert!(!h.get_rate_limiter().shared.is_blocked());
                 // make sure the virtio queue operation completed this time
                 assert_eq!(h.interrupt_evt.read(), Ok(2));
 
@@ -1406,6 +2763,48 @@ mod tests {
             }
         }
 
+        // test the write rate limiter with size-weighted ops
+        {
+            // create a write rate limiter that allows only 20 ops/s with bucket size of 2 ops,
+            // where each started 4 bytes cost an op
+            vq.used.idx.set(0);
+            h.set_queue(vq.create_queue());
+            h.set_rate_limiter(BlockRateLimiter {
+                write: Some(RateLimiter::new(0, None, 0, 2, None, 100).unwrap()),
+                bytes_per_op: Some(4),
+                ..Default::default()
+            });
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+                .unwrap();
+            vq.dtable[1].flags.set(VIRTQ_DESC_F_NEXT);
+            vq.dtable[1].len.set(8);
+
+            // the first write uses up the budget
+            h.queue_evt.write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty);
+            assert_eq!(h.interrupt_evt.read(), Ok(1));
+            assert_eq!(vq.used.idx.get(), 1);
+
+            // the second one is throttled by the write limiter only
+            vq.used.idx.set(0);
+            h.set_queue(vq.create_queue());
+            check_metric_after_block!(&METRICS.block.write_rate_limiter_throttled_events, 1, {
+                h.queue_evt.write(1).unwrap();
+                h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty);
+            });
+            assert!(!h.get_rate_limiter().shared.is_blocked());
+            assert!(h.get_rate_limiter().write.as_ref().unwrap().is_blocked());
+            assert_eq!(vq.used.idx.get(), 0);
+
+            // wait for the write limiter timer to replenish its bucket
+            thread::sleep(Duration::from_millis(150));
+            h.handle_event(WRITE_RATE_LIMITER_EVENT, 0, EpollHandlerPayload::Empty);
+            assert!(!h.get_rate_limiter().write.as_ref().unwrap().is_blocked());
+            assert_eq!(h.interrupt_evt.read(), Ok(1));
+            assert_eq!(vq.used.idx.get(), 1);
+        }
+
         // test block device update handler
         {
             let f = NamedTempFile::new().unwrap();
@@ -1426,8 +2825,160 @@ mod tests {
             let payload = EpollHandlerPayload::DrivePayload(file);
             h.handle_event(FS_UPDATE_EVENT, 0, payload);
 
-            assert_eq!(h.disk_image.metadata().unwrap().st_ino(), mdata.st_ino());
-            assert_eq!(h.disk_image_id, id);
+            assert_eq!(
+                h.disk().image.file().metadata().unwrap().st_ino(),
+                mdata.st_ino()
+            );
+            assert_eq!(h.disk().id, id);
+        }
+    }
+
+    #[test]
+    fn test_async_handler() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, vq) = default_test_blockepollhandler(&m);
+        h.async_io = Some(AsyncIo::new(DEFAULT_QUEUE_SIZE).unwrap());
+
+        for i in 0..3 {
+            vq.avail.ring[i].set(i as u16);
+            vq.dtable[i].set(
+                (0x1000 * (i + 1)) as u64,
+                0x1000,
+                VIRTQ_DESC_F_NEXT,
+                (i + 1) as u16,
+            );
+        }
+        vq.dtable[2].flags.set(VIRTQ_DESC_F_WRITE);
+        vq.avail.idx.set(1);
+
+        let data_addr = GuestAddress(vq.dtable[1].addr.get() as usize);
+        let status_addr = GuestAddress(vq.dtable[2].addr.get() as usize);
+
+        {
+            // write
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_OUT, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(0, GuestAddress(0x1000 + 8))
+                .unwrap();
+            m.write_slice_at_addr(&[0xaa; 0x1000], data_addr).unwrap();
+
+            h.queue_evt.write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty);
+            // The request is in flight, so the descriptor chain is not used yet.
+            assert_eq!(vq.used.idx.get(), 0);
+
+            invoke_handler_for_completion_event(&mut h);
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+
+            let mut buf = [0u8; 0x1000];
+            h.disk().image.file().read_exact_at(&mut buf, 0).unwrap();
+            assert!(buf.iter().all(|&b| b == 0xaa));
+        }
+
+        {
+            // read it back
+            vq.used.idx.set(0);
+            h.set_queue(vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+                .unwrap();
+            vq.dtable[1]
+                .flags
+                .set(VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE);
+            m.write_slice_at_addr(&[0; 0x1000], data_addr).unwrap();
+
+            h.queue_evt.write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty);
+            invoke_handler_for_completion_event(&mut h);
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().id, 0);
+            assert_eq!(vq.used.ring[0].get().len, 0x1000);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+            let mut buf = [0u8; 0x1000];
+            m.read_slice_at_addr(&mut buf, data_addr).unwrap();
+            assert!(buf.iter().all(|&b| b == 0xaa));
+        }
+
+        {
+            // flush
+            h.disk().cache_type = CacheType::Writeback;
+            vq.used.idx.set(0);
+            h.set_queue(vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_FLUSH, GuestAddress(0x1000))
+                .unwrap();
+
+            h.queue_evt.write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty);
+            invoke_handler_for_completion_event(&mut h);
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 0);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_OK
+            );
+        }
+
+        {
+            // reading past the end of the disk completes with an error
+            vq.used.idx.set(0);
+            h.set_queue(vq.create_queue());
+
+            m.write_obj_at_addr::<u32>(VIRTIO_BLK_T_IN, GuestAddress(0x1000))
+                .unwrap();
+            m.write_obj_at_addr::<u64>(8, GuestAddress(0x1000 + 8))
+                .unwrap();
+
+            h.queue_evt.write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty);
+            invoke_handler_for_completion_event(&mut h);
+
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 1);
+            assert_eq!(
+                m.read_obj_from_addr::<u32>(status_addr).unwrap(),
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+
+        {
+            // draining completes the requests in flight
+            vq.used.idx.set(0);
+            h.set_queue(vq.create_queue());
+
+            m.write_obj_at_addr::<u64>(0, GuestAddress(0x1000 + 8))
+                .unwrap();
+
+            h.queue_evt.write(1).unwrap();
+            h.handle_event(QUEUE_AVAIL_EVENT, 0, EpollHandlerPayload::Empty);
+            assert_eq!(vq.used.idx.get(), 0);
+
+            assert!(h.drain().is_ok());
+            assert_eq!(vq.used.idx.get(), 1);
+            assert_eq!(vq.used.ring[0].get().len, 0x1000);
+            assert_eq!(h.interrupt_evt.read(), Ok(1));
+            assert!(h
+                .async_io
+                .as_ref()
+                .unwrap()
+                .requests
+                .iter()
+                .all(Option::is_none));
+
+            // There is nothing left to drain.
+            assert!(h.drain().is_ok());
+            assert_eq!(vq.used.idx.get(), 1);
         }
     }
 }
@@ -0,0 +1,358 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A raw image opened with `O_DIRECT`, bypassing the host page cache.
+//!
+//! Direct I/O requires the buffers, offsets and lengths to be aligned. Guest buffers can be
+//! anywhere in guest memory, so the data goes through an aligned bounce buffer, and a range that
+//! does not start or end on a block boundary is read and written as the blocks holding it.
+//!
+//! The blocks are as large as the logical blocks of the image: the logical block size of a block
+//! device, or the preferred I/O size of a regular file, up to the page size. The size of the image
+//! has to be a multiple of the block size, or writing its last block would grow it.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+
+use libc::{self, EOPNOTSUPP};
+
+use sys_util::{fallocate, ioctl_with_mut_ref, FallocateMode};
+
+// See include/uapi/linux/fs.h
+const BLKSSZGET: libc::c_ulong = 0x1268;
+
+// The smallest alignment of the offsets and lengths of direct I/O.
+const MIN_BLOCK_SIZE: u64 = 512;
+// The largest alignment of the offsets and lengths of direct I/O, which suits any logical block
+// size. It is used when the block size cannot be queried.
+const MAX_BLOCK_SIZE: u64 = 4096;
+// The alignment of the bounce buffer in memory.
+const BUFFER_ALIGNMENT: usize = 4096;
+const BUFFER_SIZE: usize = 128 * 1024;
+
+/// A raw disk image opened with `O_DIRECT`.
+pub struct DirectFile {
+    file: File,
+    // The alignment of the offsets and lengths of direct I/O on `file`.
+    block_size: u64,
+    // The bounce buffer starts at `buffer_offset` in `storage`.
+    storage: Vec<u8>,
+    buffer_offset: usize,
+    position: u64,
+}
+
+impl DirectFile {
+    /// Wraps `file`, which has to have been opened with `O_DIRECT`.
+    pub fn new(file: File) -> io::Result<DirectFile> {
+        Self::check_size(&file)?;
+        let block_size = block_size(&file);
+        let storage = vec![0u8; BUFFER_SIZE + BUFFER_ALIGNMENT];
+        let misalignment = storage.as_ptr() as usize % BUFFER_ALIGNMENT;
+        let buffer_offset = (BUFFER_ALIGNMENT - misalignment) % BUFFER_ALIGNMENT;
+        Ok(DirectFile {
+            file,
+            block_size,
+            storage,
+            buffer_offset,
+            position: 0,
+        })
+    }
+
+    /// Checks that the size of `file` is a multiple of the alignment of direct I/O on it.
+    pub fn check_size(file: &File) -> io::Result<()> {
+        // The size of a block device is always a multiple of its logical block size.
+        if file.metadata()?.len() % block_size(file) != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the image size is not a multiple of the direct I/O block size",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the image file.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Zeroes `len` bytes at `offset`, in place when the file system supports it.
+    pub fn write_zeroes(&mut self, offset: u64, len: u64) -> io::Result<()> {
+        match fallocate(&self.file, FallocateMode::ZeroRange, offset, len) {
+            Ok(()) => Ok(()),
+            // Not every file system can zero a range in place.
+            Err(ref e) if e.errno() == EOPNOTSUPP => {
+                let zeroes = [0u8; BUFFER_SIZE];
+                let position = self.position;
+                self.position = offset;
+                let mut written = 0;
+                while written < len {
+                    let count = cmp::min(len - written, BUFFER_SIZE as u64) as usize;
+                    if let Err(e) = self.write_all(&zeroes[..count]) {
+                        self.position = position;
+                        return Err(e);
+                    }
+                    written += count as u64;
+                }
+                self.position = position;
+                Ok(())
+            }
+            Err(e) => Err(io::Error::from_raw_os_error(e.errno())),
+        }
+    }
+
+    // Returns the aligned blocks covering `len` bytes at the current position, capped to the
+    // size of the bounce buffer, as their offset, their length and the offset of the position
+    // within them.
+    fn blocks(&self, len: usize) -> (u64, usize, usize) {
+        let block_size = self.block_size as usize;
+        let start = self.position & !(self.block_size - 1);
+        let head = (self.position - start) as usize;
+        let end = (head + len + block_size - 1) & !(block_size - 1);
+        (start, cmp::min(end, BUFFER_SIZE), head)
+    }
+
+    // Reads `len` bytes of aligned blocks at `offset` into the bounce buffer. Returns the number
+    // of bytes read, which is smaller at the end of the file.
+    fn read_blocks(&mut self, offset: u64, len: usize) -> io::Result<usize> {
+        let buffer = &mut self.storage[self.buffer_offset..self.buffer_offset + len];
+        loop {
+            match self.file.read_at(buffer, offset) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+impl Read for DirectFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (start, len, head) = self.blocks(buf.len());
+        let read = self.read_blocks(start, len)?;
+        if read <= head {
+            return Ok(0);
+        }
+        let count = cmp::min(read - head, buf.len());
+        let data = self.buffer_offset + head;
+        buf[..count].copy_from_slice(&self.storage[data..data + count]);
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for DirectFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let (start, len, head) = self.blocks(buf.len());
+        let count = cmp::min(len - head, buf.len());
+        // Blocks that are only partly written keep the rest of their contents.
+        if head != 0 || count % self.block_size as usize != 0 {
+            let read = self.read_blocks(start, len)?;
+            for byte in &mut self.storage[self.buffer_offset + read..self.buffer_offset + len] {
+                *byte = 0;
+            }
+        }
+        let data = self.buffer_offset + head;
+        self.storage[data..data + count].copy_from_slice(&buf[..count]);
+        self.file.write_all_at(
+            &self.storage[self.buffer_offset..self.buffer_offset + len],
+            start,
+        )?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for DirectFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_offset(self.file.metadata()?.len(), offset),
+            SeekFrom::Current(offset) => add_offset(self.position, offset),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+// Returns the alignment of the offsets and lengths of direct I/O on `file`: the logical block
+// size of a block device, or the preferred I/O size of a regular file, which is a multiple of
+// the logical block size of its file system.
+fn block_size(file: &File) -> u64 {
+    // Safe because the kernel only writes a `stat` structure, and we check the return value.
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    let ret = unsafe { libc::fstat(file.as_raw_fd(), &mut stat) };
+    if ret < 0 {
+        return MAX_BLOCK_SIZE;
+    }
+    let size = if stat.st_mode & libc::S_IFMT == libc::S_IFBLK {
+        let mut size: libc::c_int = 0;
+        // Safe because the kernel only writes an int, and we check the return value.
+        let ret = unsafe { ioctl_with_mut_ref(file, BLKSSZGET, &mut size) };
+        if ret < 0 {
+            return MAX_BLOCK_SIZE;
+        }
+        size as u64
+    } else {
+        stat.st_blksize as u64
+    };
+    if size < MIN_BLOCK_SIZE || !size.is_power_of_two() {
+        return MAX_BLOCK_SIZE;
+    }
+    // A logical block is never larger than a page.
+    cmp::min(size, MAX_BLOCK_SIZE)
+}
+
+fn add_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    use self::tempfile::NamedTempFile;
+    use super::*;
+
+    use libc;
+
+    fn new_direct_file(size: u64) -> (NamedTempFile, DirectFile) {
+        let temp = NamedTempFile::new().unwrap();
+        temp.as_file().set_len(size).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(temp.path())
+            .unwrap();
+        (temp, DirectFile::new(file).unwrap())
+    }
+
+    #[test]
+    fn test_aligned_buffer() {
+        let (_temp, direct) = new_direct_file(0);
+        let buffer = &direct.storage[direct.buffer_offset..];
+        assert_eq!(buffer.as_ptr() as usize % BUFFER_ALIGNMENT, 0);
+        assert!(buffer.len() >= BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_block_size() {
+        let (temp, mut direct) = new_direct_file(0x4000);
+        assert!(direct.block_size.is_power_of_two());
+        assert!(direct.block_size >= MIN_BLOCK_SIZE && direct.block_size <= MAX_BLOCK_SIZE);
+
+        // Unaligned accesses cover whole blocks of the queried size.
+        direct.block_size = MAX_BLOCK_SIZE;
+        direct.position = 0x1ffe;
+        assert_eq!(direct.blocks(4), (0x1000, 0x2000, 0xffe));
+        direct.write_all(&[0xff; 4]).unwrap();
+        let mut buf = [0u8; 6];
+        temp.as_file().read_exact_at(&mut buf, 0x1ffd).unwrap();
+        assert_eq!(buf, [0, 0xff, 0xff, 0xff, 0xff, 0]);
+    }
+
+    #[test]
+    fn test_unaligned_size() {
+        let temp = NamedTempFile::new().unwrap();
+        temp.as_file().set_len(0x1000 + 1).unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_DIRECT)
+            .open(temp.path())
+            .unwrap();
+        // Writing the last block would grow the image.
+        assert_eq!(
+            DirectFile::new(file).err().unwrap().kind(),
+            io::ErrorKind::InvalidInput
+        );
+        assert_eq!(temp.as_file().metadata().unwrap().len(), 0x1000 + 1);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let (temp, mut direct) = new_direct_file(0x10000);
+        let data: Vec<u8> = (0..0x1000).map(|i| i as u8).collect();
+
+        // Aligned.
+        direct.seek(SeekFrom::Start(0x1000)).unwrap();
+        direct.write_all(&data).unwrap();
+        let mut buf = vec![0u8; 0x1000];
+        direct.seek(SeekFrom::Start(0x1000)).unwrap();
+        direct.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        // Unaligned writes keep the rest of their blocks.
+        direct.seek(SeekFrom::Start(0x1001)).unwrap();
+        direct.write_all(&[0xff; 3]).unwrap();
+        let mut buf = vec![0u8; 8];
+        direct.seek(SeekFrom::Start(0x1000)).unwrap();
+        direct.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0, 0xff, 0xff, 0xff, 4, 5, 6, 7]);
+
+        // Larger than the bounce buffer.
+        let big: Vec<u8> = (0..BUFFER_SIZE * 2 + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        direct.seek(SeekFrom::Start(0x2000 + 10)).unwrap();
+        direct.write_all(&big).unwrap();
+        let mut buf = vec![0u8; big.len()];
+        direct.seek(SeekFrom::Start(0x2000 + 10)).unwrap();
+        direct.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, big);
+
+        // The data reached the file.
+        let mut buf = [0u8; 4];
+        temp.as_file().read_exact_at(&mut buf, 0x1000).unwrap();
+        assert_eq!(buf, [0, 0xff, 0xff, 0xff]);
+
+        // Reads stop at the end of the file.
+        let size = direct.seek(SeekFrom::End(0)).unwrap();
+        direct.seek(SeekFrom::Start(size - 2)).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(direct.read(&mut buf).unwrap(), 2);
+        assert_eq!(direct.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_write_zeroes() {
+        let (_temp, mut direct) = new_direct_file(0x4000);
+        direct.write_all(&[0xaa; 0x4000]).unwrap();
+        direct.write_zeroes(0x1000, 0x1000).unwrap();
+
+        let mut buf = vec![0u8; 0x4000];
+        direct.seek(SeekFrom::Start(0)).unwrap();
+        direct.read_exact(&mut buf).unwrap();
+        assert!(buf[..0x1000].iter().all(|&b| b == 0xaa));
+        assert!(buf[0x1000..0x2000].iter().all(|&b| b == 0));
+        assert!(buf[0x2000..].iter().all(|&b| b == 0xaa));
+    }
+}
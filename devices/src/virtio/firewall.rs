@@ -0,0 +1,520 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A stateless firewall for the frames received and sent by a guest, made of two ordered lists
+//! of allow and deny rules, one for each direction. The first rule matching a frame decides
+//! whether it goes through, and frames matched by no rule go through.
+//!
+//! The rules match the protocol of a frame, along with the address and port of its remote end:
+//! the destination of the frames sent by the guest, and the source of the frames it receives.
+
+use std::cmp;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use byteorder::{BigEndian, ByteOrder};
+use serde::de::{self, Deserialize, Deserializer};
+
+use dumbo::pdu::arp::{EthIPv4ArpFrame, ETH_IPV4_FRAME_LEN};
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use dumbo::pdu::ipv4::IPv4Packet;
+use logger::metrics::{NetFirewallRuleMetrics, SharedMetric};
+use logger::Metric;
+
+const IP_PROTOCOL_ICMP: u8 = 1;
+const IP_PROTOCOL_TCP: u8 = 6;
+const IP_PROTOCOL_UDP: u8 = 17;
+
+/// What a rule does with the frames it matches.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum FirewallAction {
+    /// The frame goes through.
+    Allow,
+    /// The frame is dropped.
+    Deny,
+}
+
+/// The protocols a rule can match.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum FirewallProtocol {
+    /// ARP frames for IPv4 addresses.
+    Arp,
+    /// IPv4 packets carrying ICMP messages.
+    Icmp,
+    /// IPv4 packets carrying TCP segments.
+    Tcp,
+    /// IPv4 packets carrying UDP datagrams.
+    Udp,
+}
+
+/// A range of IPv4 addresses, written as `<address>/<prefix length>`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ipv4Cidr {
+    addr: u32,
+    prefix_len: u8,
+}
+
+impl Ipv4Cidr {
+    fn mask(&self) -> u32 {
+        match self.prefix_len {
+            0 => 0,
+            len => !0u32 << (32 - len),
+        }
+    }
+
+    /// Checks whether `addr` is in the range.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & self.mask() == self.addr & self.mask()
+    }
+}
+
+impl FromStr for Ipv4Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let invalid = || format!("Invalid IPv4 CIDR: {}", s);
+        let mut parts = s.splitn(2, '/');
+        let addr = parts
+            .next()
+            .and_then(|addr| addr.parse::<Ipv4Addr>().ok())
+            .ok_or_else(invalid)?;
+        let prefix_len = parts
+            .next()
+            .and_then(|len| len.parse::<u8>().ok())
+            .filter(|len| *len <= 32)
+            .ok_or_else(invalid)?;
+        Ok(Ipv4Cidr {
+            addr: u32::from(addr),
+            prefix_len,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Ipv4Cidr {
+    fn deserialize<D>(deserializer: D) -> result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// An inclusive range of TCP or UDP ports.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PortRange {
+    /// The first port of the range.
+    pub start: u16,
+    /// The last port of the range.
+    pub end: u16,
+}
+
+/// A rule matching the frames with all of its fields. A missing field matches any frame.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FirewallRule {
+    /// What is done with the matched frames.
+    pub action: FirewallAction,
+    /// The protocol of the frames.
+    pub protocol: Option<FirewallProtocol>,
+    /// The range of the IPv4 address of the remote end, which is the protocol address of the
+    /// target of an ARP frame sent by the guest and of the sender of one it receives.
+    pub cidr: Option<Ipv4Cidr>,
+    /// The range of the port of the remote end, only for the `Tcp` and `Udp` protocols. The
+    /// fragments of an IPv4 packet other than the first one do not carry ports.
+    pub ports: Option<PortRange>,
+}
+
+/// Errors associated with the firewall rules.
+#[derive(Debug, PartialEq)]
+pub enum FirewallError {
+    /// A rule has ports without the `Tcp` or `Udp` protocol.
+    PortsWithoutTcpOrUdp,
+    /// A port range starts after its end.
+    InvalidPortRange,
+}
+
+impl fmt::Display for FirewallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::FirewallError::*;
+        match *self {
+            PortsWithoutTcpOrUdp => write!(f, "Ports can only be matched for TCP and UDP."),
+            InvalidPortRange => write!(f, "A port range cannot start after its end."),
+        }
+    }
+}
+
+impl FirewallRule {
+    fn validate(&self) -> result::Result<(), FirewallError> {
+        if let Some(ports) = self.ports {
+            match self.protocol {
+                Some(FirewallProtocol::Tcp) | Some(FirewallProtocol::Udp) => (),
+                _ => return Err(FirewallError::PortsWithoutTcpOrUdp),
+            }
+            if ports.start > ports.end {
+                return Err(FirewallError::InvalidPortRange);
+            }
+        }
+        Ok(())
+    }
+
+    fn matches(&self, fields: &FrameFields) -> bool {
+        self.protocol
+            .map_or(true, |protocol| fields.protocol == Some(protocol))
+            && self.cidr.map_or(true, |cidr| {
+                fields.remote_addr.map_or(false, |addr| cidr.contains(addr))
+            })
+            && self.ports.map_or(true, |ports| {
+                fields
+                    .remote_port
+                    .map_or(false, |port| ports.start <= port && port <= ports.end)
+            })
+    }
+}
+
+/// The rules of the firewall of a network interface.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FirewallConfig {
+    /// The rules for the frames received by the guest, from the tap device or MMDS.
+    #[serde(default)]
+    pub rx_rules: Vec<FirewallRule>,
+    /// The rules for the frames sent by the guest.
+    #[serde(default)]
+    pub tx_rules: Vec<FirewallRule>,
+}
+
+impl FirewallConfig {
+    /// Checks that the rules are consistent.
+    pub fn validate(&self) -> result::Result<(), FirewallError> {
+        for rule in self.rx_rules.iter().chain(self.tx_rules.iter()) {
+            rule.validate()?;
+        }
+        Ok(())
+    }
+}
+
+// The fields of a frame the rules match, missing when the frame does not carry them.
+#[derive(Debug, PartialEq)]
+struct FrameFields {
+    protocol: Option<FirewallProtocol>,
+    remote_addr: Option<Ipv4Addr>,
+    remote_port: Option<u16>,
+}
+
+impl FrameFields {
+    // Parses the L2 `frame`, whose remote end is its source if `received` is set, and its
+    // destination otherwise.
+    fn parse(frame: &[u8], received: bool) -> FrameFields {
+        let mut fields = FrameFields {
+            protocol: None,
+            remote_addr: None,
+            remote_port: None,
+        };
+        let eth = match EthernetFrame::from_bytes(frame) {
+            Ok(eth) => eth,
+            Err(_) => return fields,
+        };
+
+        // The frames coming from the host network may be padded to the minimum Ethernet frame
+        // size, so the payload is cut at the length of the ARP frame or IPv4 packet.
+        let payload = eth.payload();
+        match eth.ethertype() {
+            ETHERTYPE_ARP => {
+                let len = cmp::min(payload.len(), ETH_IPV4_FRAME_LEN);
+                if let Ok(arp) = EthIPv4ArpFrame::from_bytes(&payload[..len]) {
+                    fields.protocol = Some(FirewallProtocol::Arp);
+                    fields.remote_addr = Some(if received { arp.spa() } else { arp.tpa() });
+                }
+            }
+            ETHERTYPE_IPV4 => {
+                let mut len = payload.len();
+                if len >= 4 {
+                    // The total length field is within the checked length.
+                    let total_len = IPv4Packet::from_bytes_unchecked(payload).total_len();
+                    len = cmp::min(len, total_len as usize);
+                }
+                // The checksum is left to the receiving end.
+                if let Ok(ip) = IPv4Packet::from_bytes(&payload[..len], false) {
+                    fields.remote_addr = Some(if received {
+                        ip.source_address()
+                    } else {
+                        ip.destination_address()
+                    });
+                    fields.protocol = match ip.protocol() {
+                        IP_PROTOCOL_ICMP => Some(FirewallProtocol::Icmp),
+                        IP_PROTOCOL_TCP => Some(FirewallProtocol::Tcp),
+                        IP_PROTOCOL_UDP => Some(FirewallProtocol::Udp),
+                        _ => None,
+                    };
+                    // TCP and UDP both start with the source and destination ports.
+                    let l4 = ip.payload();
+                    if fields
+                        .protocol
+                        .map_or(false, |p| p != FirewallProtocol::Icmp)
+                        && ip.flags_and_fragment_offset().1 == 0
+                        && l4.len() >= 4
+                    {
+                        fields.remote_port = Some(if received {
+                            BigEndian::read_u16(&l4[0..2])
+                        } else {
+                            BigEndian::read_u16(&l4[2..4])
+                        });
+                    }
+                }
+            }
+            _ => (),
+        }
+        fields
+    }
+}
+
+struct Rule {
+    rule: FirewallRule,
+    hits: Arc<SharedMetric>,
+}
+
+/// Evaluates the firewall rules of a network interface, counting the frames matched by each.
+#[derive(Default)]
+pub struct Firewall {
+    rx_rules: Vec<Rule>,
+    tx_rules: Vec<Rule>,
+}
+
+impl Firewall {
+    /// Creates a firewall with the rules of `config`, which should have been validated.
+    pub fn new(config: &FirewallConfig) -> Self {
+        let rules = |rules: &Vec<FirewallRule>| {
+            rules
+                .iter()
+                .map(|rule| Rule {
+                    rule: rule.clone(),
+                    hits: Arc::new(SharedMetric::default()),
+                })
+                .collect()
+        };
+        Firewall {
+            rx_rules: rules(&config.rx_rules),
+            tx_rules: rules(&config.tx_rules),
+        }
+    }
+
+    /// Returns the hit counters of the rules.
+    pub fn metrics(&self) -> NetFirewallRuleMetrics {
+        let hits = |rules: &Vec<Rule>| rules.iter().map(|rule| rule.hits.clone()).collect();
+        NetFirewallRuleMetrics {
+            rx_rule_hits: hits(&self.rx_rules),
+            tx_rule_hits: hits(&self.tx_rules),
+        }
+    }
+
+    /// Checks whether the L2 `frame` received by the guest goes through.
+    pub fn allows_rx(&self, frame: &[u8]) -> bool {
+        Self::allows(&self.rx_rules, frame, true)
+    }
+
+    /// Checks whether the L2 `frame` sent by the guest goes through.
+    pub fn allows_tx(&self, frame: &[u8]) -> bool {
+        Self::allows(&self.tx_rules, frame, false)
+    }
+
+    fn allows(rules: &[Rule], frame: &[u8], received: bool) -> bool {
+        if rules.is_empty() {
+            return true;
+        }
+        let fields = FrameFields::parse(frame, received);
+        match rules.iter().find(|rule| rule.rule.matches(&fields)) {
+            Some(rule) => {
+                rule.hits.inc();
+                rule.rule.action == FirewallAction::Allow
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate serde_json;
+
+    use super::*;
+    use net_util::MacAddr;
+
+    const GUEST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const REMOTE_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+
+    fn mac() -> MacAddr {
+        MacAddr::parse_str("12:34:56:78:9a:bc").unwrap()
+    }
+
+    // Writes a frame sent by the guest to `REMOTE_IP` with a segment of `protocol` from port
+    // 4000 to `port`, padded with `padding` bytes, and returns its length.
+    fn write_tx_frame(buf: &mut [u8], protocol: u8, port: u16, padding: usize) -> usize {
+        let mut eth = EthernetFrame::write_incomplete(&mut buf[..], mac(), mac(), ETHERTYPE_IPV4)
+            .ok()
+            .unwrap();
+        let ip_len = {
+            let mut ip = IPv4Packet::write_header(
+                eth.inner_mut().payload_mut(),
+                protocol,
+                GUEST_IP,
+                REMOTE_IP,
+            )
+            .ok()
+            .unwrap();
+            {
+                let l4 = ip.inner_mut().payload_mut();
+                BigEndian::write_u16(&mut l4[0..2], 4000);
+                BigEndian::write_u16(&mut l4[2..4], port);
+            }
+            ip.with_payload_len_unchecked(8, true).len()
+        };
+        eth.with_payload_len_unchecked(ip_len + padding).len()
+    }
+
+    fn rule(json: &str) -> FirewallRule {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let cidr: Ipv4Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(cidr.contains(Ipv4Addr::new(192, 168, 1, 1)));
+        assert!(!cidr.contains(Ipv4Addr::new(192, 169, 0, 0)));
+        let cidr: Ipv4Cidr = "10.0.0.1/0".parse().unwrap();
+        assert!(cidr.contains(Ipv4Addr::new(192, 169, 0, 0)));
+        let cidr: Ipv4Cidr = "10.0.0.1/32".parse().unwrap();
+        assert!(cidr.contains(Ipv4Addr::new(10, 0, 0, 1)));
+        assert!(!cidr.contains(Ipv4Addr::new(10, 0, 0, 2)));
+
+        for s in vec!["10.0.0.1", "10.0.0.1/33", "10.0.0/8", "10.0.0.1/"] {
+            assert!(s.parse::<Ipv4Cidr>().is_err());
+        }
+        assert!(serde_json::from_str::<FirewallRule>(
+            r#"{"action": "Deny", "cidr": "10.0.0.0/40"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut config = FirewallConfig {
+            rx_rules: vec![rule(
+                r#"{"action": "Allow", "ports": {"start": 1, "end": 2}}"#,
+            )],
+            tx_rules: Vec::new(),
+        };
+        assert_eq!(config.validate(), Err(FirewallError::PortsWithoutTcpOrUdp));
+        config.rx_rules[0].protocol = Some(FirewallProtocol::Udp);
+        assert_eq!(config.validate(), Ok(()));
+        config.rx_rules[0].ports = Some(PortRange { start: 2, end: 1 });
+        assert_eq!(config.validate(), Err(FirewallError::InvalidPortRange));
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut buf = [0u8; 200];
+        let len = write_tx_frame(&mut buf, IP_PROTOCOL_TCP, 443, 0);
+        assert_eq!(
+            FrameFields::parse(&buf[..len], false),
+            FrameFields {
+                protocol: Some(FirewallProtocol::Tcp),
+                remote_addr: Some(REMOTE_IP),
+                remote_port: Some(443),
+            }
+        );
+        // The remote end of a received frame is its source.
+        assert_eq!(
+            FrameFields::parse(&buf[..len], true),
+            FrameFields {
+                protocol: Some(FirewallProtocol::Tcp),
+                remote_addr: Some(GUEST_IP),
+                remote_port: Some(4000),
+            }
+        );
+
+        // Padded frames are parsed as well, ICMP has no ports.
+        let len = write_tx_frame(&mut buf, IP_PROTOCOL_ICMP, 443, 10);
+        assert_eq!(
+            FrameFields::parse(&buf[..len], false),
+            FrameFields {
+                protocol: Some(FirewallProtocol::Icmp),
+                remote_addr: Some(REMOTE_IP),
+                remote_port: None,
+            }
+        );
+
+        let mut eth = EthernetFrame::write_incomplete(&mut buf[..], mac(), mac(), ETHERTYPE_ARP)
+            .ok()
+            .unwrap();
+        EthIPv4ArpFrame::write_request(
+            &mut eth.inner_mut().payload_mut()[..ETH_IPV4_FRAME_LEN],
+            mac(),
+            GUEST_IP,
+            mac(),
+            REMOTE_IP,
+        )
+        .ok()
+        .unwrap();
+        let len = eth.with_payload_len_unchecked(ETH_IPV4_FRAME_LEN).len();
+        assert_eq!(
+            FrameFields::parse(&buf[..len], false),
+            FrameFields {
+                protocol: Some(FirewallProtocol::Arp),
+                remote_addr: Some(REMOTE_IP),
+                remote_port: None,
+            }
+        );
+
+        // Malformed frames only match the rules without protocol, CIDR or ports.
+        assert_eq!(
+            FrameFields::parse(&buf[..len - 1], false),
+            FrameFields {
+                protocol: None,
+                remote_addr: None,
+                remote_port: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_firewall() {
+        let config = FirewallConfig {
+            rx_rules: Vec::new(),
+            tx_rules: vec![
+                rule(r#"{"action": "Deny", "protocol": "Tcp", "ports": {"start": 22, "end": 22}}"#),
+                rule(
+                    r#"{
+                        "action": "Allow",
+                        "protocol": "Tcp",
+                        "cidr": "192.168.0.0/16",
+                        "ports": {"start": 1, "end": 1024}
+                    }"#,
+                ),
+                rule(r#"{"action": "Deny"}"#),
+            ],
+        };
+        let firewall = Firewall::new(&config);
+        let metrics = firewall.metrics();
+        let mut buf = [0u8; 200];
+
+        let len = write_tx_frame(&mut buf, IP_PROTOCOL_TCP, 443, 0);
+        assert!(firewall.allows_tx(&buf[..len]));
+        // Without RX rules, anything is received.
+        assert!(firewall.allows_rx(&buf[..len]));
+        let len = write_tx_frame(&mut buf, IP_PROTOCOL_TCP, 22, 0);
+        assert!(!firewall.allows_tx(&buf[..len]));
+        let len = write_tx_frame(&mut buf, IP_PROTOCOL_UDP, 443, 0);
+        assert!(!firewall.allows_tx(&buf[..len]));
+        assert!(!firewall.allows_tx(&buf[..10]));
+
+        let hits: Vec<usize> = metrics.tx_rule_hits.iter().map(|m| m.count()).collect();
+        assert_eq!(hits, vec![1, 1, 2]);
+        assert!(metrics.rx_rule_hits.is_empty());
+    }
+}
@@ -5,6 +5,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
@@ -68,6 +69,64 @@ pub trait VirtioDevice: Send {
     fn reset(&mut self) -> Option<(EventFd, Vec<EventFd>)> {
         None
     }
+
+    /// The number of queue pairs the driver of a multi-queue device set through its control
+    /// queue, which the transport doesn't see.
+    fn active_queue_pairs(&self) -> Option<u16> {
+        None
+    }
+
+    /// Sets the number of queue pairs the device uses once activated, as returned by
+    /// `active_queue_pairs` when the state of the device was saved.
+    fn set_active_queue_pairs(&mut self, pairs: u16) -> result::Result<(), RestoreError> {
+        Err(RestoreError::InvalidQueuePairs(pairs))
+    }
+}
+
+/// Errors that can occur while restoring the state of a `MmioDevice`.
+#[derive(Debug)]
+pub enum RestoreError {
+    /// The device was already activated by the guest driver.
+    AlreadyActivated,
+    /// The saved state belongs to a device of another type.
+    DeviceTypeMismatch(u32),
+    /// The saved state has a different number of queues than the device.
+    QueueCountMismatch(usize),
+    /// The saved number of queue pairs in use is not valid for the device.
+    InvalidQueuePairs(u16),
+    /// The device could not be activated with the restored queues.
+    Activate(ActivateError),
+}
+
+/// The transport state of a `MmioDevice`, as saved in a snapshot.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MmioDeviceState {
+    /// Base address of the device on the MMIO bus.
+    pub mmio_addr: u64,
+    /// The IRQ the device interrupts the guest with.
+    pub irq: u32,
+    /// The virtio device type.
+    pub device_type: u32,
+    /// Whether the guest driver finished setting up the device.
+    pub device_activated: bool,
+    /// Feature bits written by the guest driver.
+    pub driver_features: u64,
+    /// Value of the device features selection register.
+    pub features_select: u32,
+    /// Value of the driver features selection register.
+    pub acked_features_select: u32,
+    /// Value of the queue selection register.
+    pub queue_select: u32,
+    /// Pending interrupt flags.
+    pub interrupt_status: usize,
+    /// Value of the device status register.
+    pub driver_status: u32,
+    /// Configuration atomicity value.
+    pub config_generation: u32,
+    /// Configuration of each of the device queues.
+    pub queues: Vec<QueueState>,
+    /// Number of queue pairs in use, for multi-queue devices.
+    pub active_queue_pairs: Option<u16>,
 }
 
 /// Implements the
@@ -90,6 +149,7 @@ pub struct MmioDevice {
 
     features_select: u32,
     acked_features_select: u32,
+    driver_features: u64,
     queue_select: u32,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: Option<EventFd>,
@@ -117,6 +177,7 @@ impl MmioDevice {
             device_activated: false,
             features_select: 0,
             acked_features_select: 0,
+            driver_features: 0,
             queue_select: 0,
             interrupt_status: Arc::new(AtomicUsize::new(0)),
             interrupt_evt: Some(EventFd::new()?),
@@ -140,6 +201,82 @@ impl MmioDevice {
         self.interrupt_evt.as_ref()
     }
 
+    /// Returns the transport state of this device, which the guest finds at `mmio_addr` and
+    /// which interrupts it with `irq`.
+    pub fn save_state(&self, mmio_addr: u64, irq: u32) -> MmioDeviceState {
+        MmioDeviceState {
+            mmio_addr,
+            irq,
+            device_type: self.device.device_type(),
+            device_activated: self.device_activated,
+            driver_features: self.driver_features,
+            features_select: self.features_select,
+            acked_features_select: self.acked_features_select,
+            queue_select: self.queue_select,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            driver_status: self.driver_status,
+            config_generation: self.config_generation,
+            queues: self.queues.iter().map(|q| q.save_state()).collect(),
+            active_queue_pairs: self.device.active_queue_pairs(),
+        }
+    }
+
+    /// Restores a state saved with `save_state` and activates the device if it was active when
+    /// the state was saved. This has to be done before the guest gets to run.
+    pub fn restore_state(&mut self, state: &MmioDeviceState) -> result::Result<(), RestoreError> {
+        if self.device_activated {
+            return Err(RestoreError::AlreadyActivated);
+        }
+        if state.device_type != self.device.device_type() {
+            return Err(RestoreError::DeviceTypeMismatch(state.device_type));
+        }
+        if state.queues.len() != self.queues.len() {
+            return Err(RestoreError::QueueCountMismatch(state.queues.len()));
+        }
+        if let Some(pairs) = state.active_queue_pairs {
+            self.device.set_active_queue_pairs(pairs)?;
+        }
+
+        // Replaying the writes of the driver lets the device filter the features on its own.
+        self.device.ack_features(0, state.driver_features as u32);
+        self.device
+            .ack_features(1, (state.driver_features >> 32) as u32);
+        self.driver_features = state.driver_features;
+        self.features_select = state.features_select;
+        self.acked_features_select = state.acked_features_select;
+        self.queue_select = state.queue_select;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        self.driver_status = state.driver_status;
+        self.config_generation = state.config_generation;
+        if let Some(mem) = self.mem.as_ref() {
+            for (queue, queue_state) in self.queues.iter_mut().zip(state.queues.iter()) {
+                queue.restore_state(queue_state, mem);
+            }
+        }
+
+        if state.device_activated {
+            self.activate().map_err(RestoreError::Activate)?;
+        }
+        Ok(())
+    }
+
+    fn activate(&mut self) -> ActivateResult {
+        if let Some(ref interrupt_evt) = self.interrupt_evt {
+            if let Some(mem) = self.mem.take() {
+                self.device.activate(
+                    mem,
+                    interrupt_evt.try_clone().map_err(ActivateError::TryClone)?,
+                    self.interrupt_status.clone(),
+                    self.queues.clone(),
+                    self.queue_evts.split_off(0),
+                )?;
+                self.device_activated = true;
+            }
+        }
+        Ok(())
+    }
+
     fn is_driver_ready(&self) -> bool {
         let ready_bits = DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK;
         self.driver_status == ready_bits && self.driver_status & DEVICE_FAILED == 0
@@ -224,7 +361,14 @@ impl BusDevice for MmioDevice {
                 let v = LittleEndian::read_u32(data);
                 match offset {
                     0x14 => self.features_select = v,
-                    0x20 => self.device.ack_features(self.acked_features_select, v),
+                    0x20 => {
+                        match self.acked_features_select {
+                            0 => self.driver_features |= v as u64,
+                            1 => self.driver_features |= (v as u64) << 32,
+                            _ => (),
+                        }
+                        self.device.ack_features(self.acked_features_select, v)
+                    }
                     0x24 => self.acked_features_select = v,
                     0x30 => self.queue_select = v,
                     0x38 => mut_q = self.with_queue_mut(|q| q.size = v as u16),
@@ -262,20 +406,7 @@ impl BusDevice for MmioDevice {
         }
 
         if !self.device_activated && self.is_driver_ready() && self.are_queues_valid() {
-            if let Some(ref interrupt_evt) = self.interrupt_evt {
-                if let Some(mem) = self.mem.take() {
-                    self.device
-                        .activate(
-                            mem,
-                            interrupt_evt.try_clone().expect("Failed to clone eventfd"),
-                            self.interrupt_status.clone(),
-                            self.queues.clone(),
-                            self.queue_evts.split_off(0),
-                        )
-                        .expect("Failed to activate device");
-                    self.device_activated = true;
-                }
-            }
+            self.activate().expect("Failed to activate device");
         }
     }
 
@@ -588,4 +719,54 @@ mod tests {
         // a warning path.
         d.write(0x44, &buf[..]);
     }
+
+    #[test]
+    fn test_save_restore_state() {
+        let m = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        let mut d = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+
+        let mut buf = vec![0; 4];
+        d.acked_features_select = 1;
+        LittleEndian::write_u32(&mut buf[..], 0x10);
+        d.write(0x20, &buf[..]);
+        for q in d.queues.iter_mut() {
+            q.size = 16;
+            q.ready = true;
+        }
+        d.driver_status =
+            DEVICE_ACKNOWLEDGE | DEVICE_DRIVER | DEVICE_DRIVER_OK | DEVICE_FEATURES_OK;
+        d.config_generation = 3;
+        // This write activates the device.
+        LittleEndian::write_u32(&mut buf[..], 0);
+        d.write(0x30, &buf[..]);
+        assert!(d.device_activated);
+
+        let state = d.save_state(0xd000_0000, 5);
+        assert_eq!(state.driver_features, 0x10 << 32);
+        assert_eq!(state.queues.len(), 2);
+
+        let mut restored = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        restored.restore_state(&state).unwrap();
+        assert!(restored.device_activated);
+        assert_eq!(restored.save_state(0xd000_0000, 5), state);
+        // An activated device cannot be restored again.
+        assert!(restored.restore_state(&state).is_err());
+
+        let mut bad_state = state.clone();
+        bad_state.device_type += 1;
+        let mut other = MmioDevice::new(m.clone(), Box::new(DummyDevice::new())).unwrap();
+        assert!(other.restore_state(&bad_state).is_err());
+        bad_state = state.clone();
+        bad_state.queues.pop();
+        assert!(other.restore_state(&bad_state).is_err());
+        // The device has no control queue.
+        assert_eq!(state.active_queue_pairs, None);
+        bad_state = state.clone();
+        bad_state.active_queue_pairs = Some(2);
+        match other.restore_state(&bad_state) {
+            Err(RestoreError::InvalidQueuePairs(2)) => (),
+            _ => panic!("Unexpected result"),
+        }
+        assert!(!other.device_activated);
+    }
 }
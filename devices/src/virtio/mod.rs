@@ -11,16 +11,31 @@ use std::io::Error as IOError;
 use sys_util::Error as SysError;
 
 pub mod block;
+mod direct;
+mod firewall;
 mod mmio;
 pub mod net;
+mod overlay;
+mod pcap;
+mod qcow;
 mod queue;
-#[cfg(feature = "vsock")]
+mod spoof_filter;
+#[cfg(feature = "vhost")]
 pub mod vhost;
 
 pub use self::block::*;
+pub use self::direct::DirectFile;
+pub use self::firewall::{
+    Firewall, FirewallAction, FirewallConfig, FirewallError, FirewallProtocol, FirewallRule,
+    Ipv4Cidr, PortRange,
+};
 pub use self::mmio::*;
 pub use self::net::*;
+pub use self::overlay::Overlay;
+pub use self::pcap::{CaptureConfig, PcapWriter};
+pub use self::qcow::QcowFile;
 pub use self::queue::*;
+pub use self::spoof_filter::{SpoofError, SpoofFilter};
 #[cfg(feature = "vsock")]
 pub use self::vhost::vsock::*;
 
@@ -51,7 +66,7 @@ pub enum ActivateError {
     TryClone(SysError),
     EpollCtl(IOError),
     BadActivate,
-    #[cfg(feature = "vsock")]
+    #[cfg(feature = "vhost")]
     BadVhostActivate(self::vhost::Error),
 }
 
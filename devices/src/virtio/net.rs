@@ -5,6 +5,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the THIRD-PARTY file.
 
+use byteorder::{ByteOrder, LittleEndian};
 use epoll;
 use libc::EAGAIN;
 use std::cmp;
@@ -17,19 +18,19 @@ use std::os::unix::io::{AsRawFd, RawFd};
 use std::result;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::vec::Vec;
 
 use super::{
-    ActivateError, ActivateResult, EpollHandlerPayload, Queue, VirtioDevice, TYPE_NET,
-    VIRTIO_MMIO_INT_VRING,
+    ActivateError, ActivateResult, DescriptorChain, EpollHandlerPayload, Firewall, PcapWriter,
+    Queue, RestoreError, SpoofError, SpoofFilter, VirtioDevice, TYPE_NET, VIRTIO_MMIO_INT_VRING,
 };
 use dumbo::ns::MmdsNetworkStack;
 use logger::{Metric, METRICS};
 use memory_model::{GuestAddress, GuestMemory};
 use net_gen;
 use net_util::{MacAddr, Tap, TapError, MAC_ADDR_LEN};
-use rate_limiter::{RateLimiter, TokenType};
+use rate_limiter::{RateLimiter, RateLimiterUpdate, TokenType};
 use sys_util::EventFd;
 use virtio_gen::virtio_config::*;
 use virtio_gen::virtio_net::*;
@@ -40,8 +41,17 @@ use {DeviceEventT, EpollHandler};
 /// http://docs.oasis-open.org/virtio/virtio/v1.0/virtio-v1.0.html#x1-1740003
 const MAX_BUFFER_SIZE: usize = 65562;
 const QUEUE_SIZE: u16 = 256;
-const NUM_QUEUES: usize = 2;
-const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE; NUM_QUEUES];
+/// The largest number of queue pairs of a network device. Each pair takes two of the ioeventfds
+/// KVM supports and a handler in the device event loop.
+pub const MAX_QUEUE_PAIRS: u16 = 8;
+// Offset of the number of queue pairs in the config space, after the MAC address and the status.
+const CONFIG_MAX_VIRTQUEUE_PAIRS: usize = 8;
+const CONFIG_SPACE_SIZE: usize = 10;
+// The size of the only control command supported, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: the class
+// and the command, followed by the number of queue pairs.
+const CTRL_CMD_LEN: usize = 4;
+// The offset of the `num_buffers` field in the VNET header.
+const VNET_HDR_NUM_BUFFERS_OFFSET: usize = 10;
 
 // A frame is available for reading from the tap device to receive in the guest.
 const RX_TAP_EVENT: DeviceEventT = 0;
@@ -53,8 +63,14 @@ const TX_QUEUE_EVENT: DeviceEventT = 2;
 const RX_RATE_LIMITER_EVENT: DeviceEventT = 3;
 // tx rate limiter budget is now available.
 const TX_RATE_LIMITER_EVENT: DeviceEventT = 4;
+// The control queue has a command from the driver.
+const CTRL_QUEUE_EVENT: DeviceEventT = 5;
+// The frames are captured to a new file, or no longer captured.
+pub const CAPTURE_UPDATE_EVENT: DeviceEventT = 6;
+// The firewall rules are replaced.
+pub const FIREWALL_UPDATE_EVENT: DeviceEventT = 7;
 // Number of DeviceEventT events supported by this implementation.
-pub const NET_EVENTS_COUNT: usize = 5;
+pub const NET_EVENTS_COUNT: usize = 8;
 
 #[derive(Debug)]
 pub enum Error {
@@ -70,13 +86,18 @@ pub enum Error {
     TapSetVnetHdrSize(TapError),
     /// Enabling tap interface failed.
     TapEnable(TapError),
+    /// Detaching a queue of the tap interface failed.
+    TapSetQueue(TapError),
+    /// The number of queue pairs is out of range.
+    InvalidNumQueuePairs,
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
 struct TxVirtio {
     queue_evt: EventFd,
-    rate_limiter: RateLimiter,
+    // Shared by the queue pairs of the device.
+    rate_limiter: Arc<Mutex<RateLimiter>>,
     queue: Queue,
     iovec: Vec<(GuestAddress, usize)>,
     used_desc_heads: [u16; QUEUE_SIZE as usize],
@@ -84,7 +105,7 @@ struct TxVirtio {
 }
 
 impl TxVirtio {
-    fn new(queue: Queue, queue_evt: EventFd, rate_limiter: RateLimiter) -> Self {
+    fn new(queue: Queue, queue_evt: EventFd, rate_limiter: Arc<Mutex<RateLimiter>>) -> Self {
         let tx_queue_max_size = queue.get_max_size() as usize;
         TxVirtio {
             queue_evt,
@@ -99,28 +120,147 @@ impl TxVirtio {
 
 struct RxVirtio {
     queue_evt: EventFd,
-    rate_limiter: RateLimiter,
+    // Shared by the queue pairs of the device.
+    rate_limiter: Arc<Mutex<RateLimiter>>,
     deferred_frame: bool,
     deferred_irqs: bool,
     queue: Queue,
+    // The guest buffers a frame is written to, when they are mergeable.
+    iovec: Vec<(GuestAddress, usize)>,
+    used_desc_heads: [(u16, u32); QUEUE_SIZE as usize],
     bytes_read: usize,
     frame_buf: [u8; MAX_BUFFER_SIZE],
 }
 
 impl RxVirtio {
-    fn new(queue: Queue, queue_evt: EventFd, rate_limiter: RateLimiter) -> Self {
+    fn new(queue: Queue, queue_evt: EventFd, rate_limiter: Arc<Mutex<RateLimiter>>) -> Self {
+        let rx_queue_max_size = queue.get_max_size() as usize;
         RxVirtio {
             queue_evt,
             rate_limiter,
             deferred_frame: false,
             deferred_irqs: false,
             queue,
+            iovec: Vec::with_capacity(rx_queue_max_size),
+            used_desc_heads: [(0u16, 0u32); QUEUE_SIZE as usize],
             bytes_read: 0,
             frame_buf: [0u8; MAX_BUFFER_SIZE],
         }
     }
 }
 
+// The control queue, serviced by the handler of the first queue pair.
+struct CtrlVirtio {
+    queue_evt: EventFd,
+    queue: Queue,
+    queue_pairs: QueuePairs,
+}
+
+impl CtrlVirtio {
+    fn new(
+        queue: Queue,
+        queue_evt: EventFd,
+        taps: Vec<Tap>,
+        active_pairs: Arc<AtomicUsize>,
+    ) -> Self {
+        CtrlVirtio {
+            queue_evt,
+            queue,
+            queue_pairs: QueuePairs { taps, active_pairs },
+        }
+    }
+}
+
+// The state changed by the commands of the control queue.
+struct QueuePairs {
+    // The queues of the tap interface, one per queue pair, attached or detached on
+    // VIRTIO_NET_CTRL_MQ commands.
+    taps: Vec<Tap>,
+    // The number of queue pairs in use, set by the driver. Shared with the device, which saves
+    // it along with the state of the transport.
+    active_pairs: Arc<AtomicUsize>,
+}
+
+impl QueuePairs {
+    // Attaches the tap queues of the first `pairs` queue pairs and detaches the others.
+    fn set_active_pairs(&mut self, pairs: usize) -> result::Result<(), TapError> {
+        let active_pairs = self.active_pairs.load(Ordering::SeqCst);
+        for (i, tap) in self.taps.iter().enumerate() {
+            // The kernel refuses to attach an attached queue or to detach a detached one.
+            if (i < pairs) != (i < active_pairs) {
+                tap.set_queue_attached(i < pairs)?;
+            }
+        }
+        self.active_pairs.store(pairs, Ordering::SeqCst);
+        Ok(())
+    }
+
+    // Executes the command described by `head` and returns the length written to the guest.
+    fn execute(&mut self, head: DescriptorChain, mem: &GuestMemory) -> u32 {
+        // The command is in the device-readable descriptors, the acknowledgement goes to the
+        // first device-writable one. Only the bytes of the supported command are read.
+        let mut command = [0u8; CTRL_CMD_LEN];
+        let mut command_len = 0;
+        let mut ack_addr = None;
+        let mut next_desc = Some(head);
+        while let Some(desc) = next_desc {
+            if desc.is_write_only() {
+                ack_addr = Some(desc.addr);
+                break;
+            }
+            let len = cmp::min(desc.len as usize, CTRL_CMD_LEN - command_len);
+            if let Err(e) =
+                mem.read_slice_at_addr(&mut command[command_len..command_len + len], desc.addr)
+            {
+                error!("Failed to read control command: {:?}", e);
+                METRICS.net.ctrl_fails.inc();
+                return 0;
+            }
+            command_len += len;
+            next_desc = desc.next_descriptor();
+        }
+        let ack_addr = match ack_addr {
+            Some(ack_addr) => ack_addr,
+            None => {
+                error!("Control command without a writable descriptor.");
+                METRICS.net.ctrl_fails.inc();
+                return 0;
+            }
+        };
+
+        let ack = if command_len == CTRL_CMD_LEN
+            && u32::from(command[0]) == VIRTIO_NET_CTRL_MQ
+            && u32::from(command[1]) == VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET
+        {
+            let pairs = LittleEndian::read_u16(&command[2..]) as usize;
+            if pairs == 0 || pairs > self.taps.len() {
+                error!("Invalid number of queue pairs: {}", pairs);
+                METRICS.net.ctrl_fails.inc();
+                VIRTIO_NET_ERR
+            } else if let Err(e) = self.set_active_pairs(pairs) {
+                error!("Failed to set {} queue pairs: {:?}", pairs, e);
+                METRICS.net.ctrl_fails.inc();
+                VIRTIO_NET_ERR
+            } else {
+                VIRTIO_NET_OK
+            }
+        } else {
+            // None of the other command classes is advertised.
+            warn!("Received unsupported control command.");
+            METRICS.net.ctrl_fails.inc();
+            VIRTIO_NET_ERR
+        };
+        match mem.write_obj_at_addr(ack as u8, ack_addr) {
+            Ok(()) => 1,
+            Err(e) => {
+                error!("Failed to write control acknowledgement: {:?}", e);
+                METRICS.net.ctrl_fails.inc();
+                0
+            }
+        }
+    }
+}
+
 fn vnet_hdr_len() -> usize {
     mem::size_of::<virtio_net_hdr_v1>()
 }
@@ -144,6 +284,89 @@ fn init_vnet_hdr(buf: &mut [u8]) {
     }
 }
 
+// Returns the offloads of the tap matching the `acked_features` of the driver. The tap may only
+// hand us frames with partial checksums or which need segmentation when the driver accepts them.
+pub(crate) fn tap_offload_flags(acked_features: u64) -> u32 {
+    // The kernel ignores segmentation offloads without checksum offload.
+    if acked_features & (1 << VIRTIO_NET_F_GUEST_CSUM) == 0 {
+        return 0;
+    }
+    let mut flags = net_gen::TUN_F_CSUM;
+    if acked_features & (1 << VIRTIO_NET_F_GUEST_TSO4) != 0 {
+        flags |= net_gen::TUN_F_TSO4;
+    }
+    if acked_features & (1 << VIRTIO_NET_F_GUEST_TSO6) != 0 {
+        flags |= net_gen::TUN_F_TSO6;
+    }
+    if acked_features & (1 << VIRTIO_NET_F_GUEST_UFO) != 0 {
+        flags |= net_gen::TUN_F_UFO;
+    }
+    flags
+}
+
+// Writes the frame in `buf`, past its VNET header, to the capture file if there is one. The
+// capture stops when the file cannot be written to.
+fn capture_frame(capture: &Mutex<Option<PcapWriter>>, buf: &[u8]) {
+    let mut capture = capture.lock().expect("Failed to acquire capture lock");
+    let result = match *capture {
+        Some(ref mut writer) if buf.len() > vnet_hdr_len() => {
+            writer.write_frame(&buf[vnet_hdr_len()..])
+        }
+        _ => return,
+    };
+    if let Err(e) = result {
+        error!("Failed to capture frame, the capture stops: {:?}", e);
+        METRICS.net.capture_fails.inc();
+        *capture = None;
+    }
+}
+
+// Returns whether the frame in `buf`, with its VNET header, is sent from an address the guest was
+// not given, in which case it is dropped and counted in the metrics.
+fn is_spoofed(spoof_filter: Option<&SpoofFilter>, buf: &[u8]) -> bool {
+    let spoof_filter = match spoof_filter {
+        Some(spoof_filter) => spoof_filter,
+        None => return false,
+    };
+    let result = if buf.len() < vnet_hdr_len() {
+        Err(SpoofError::MacAddress)
+    } else {
+        spoof_filter.check(frame_bytes_from_buf(buf))
+    };
+    match result {
+        Ok(()) => false,
+        Err(SpoofError::MacAddress) => {
+            METRICS.net.tx_spoofed_mac_drops.inc();
+            true
+        }
+        Err(SpoofError::IpAddress) => {
+            METRICS.net.tx_spoofed_ip_drops.inc();
+            true
+        }
+    }
+}
+
+// Returns whether the firewall lets through the frame in `buf`, with its VNET header, which the
+// guest receives if `received` is set, and sends otherwise. The dropped frames are counted in the
+// metrics.
+fn firewall_allows(firewall: &Mutex<Firewall>, buf: &[u8], received: bool) -> bool {
+    let frame = buf.get(vnet_hdr_len()..).unwrap_or(&[]);
+    let firewall = firewall.lock().expect("Failed to acquire firewall lock");
+    if received {
+        let allowed = firewall.allows_rx(frame);
+        if !allowed {
+            METRICS.net.rx_firewall_drops.inc();
+        }
+        allowed
+    } else {
+        let allowed = firewall.allows_tx(frame);
+        if !allowed {
+            METRICS.net.tx_firewall_drops.inc();
+        }
+        allowed
+    }
+}
+
 struct NetEpollHandler {
     rx: RxVirtio,
     tap: Tap,
@@ -151,11 +374,25 @@ struct NetEpollHandler {
     tx: TxVirtio,
     interrupt_status: Arc<AtomicUsize>,
     interrupt_evt: EventFd,
-    // TODO(smbarber): http://crbug.com/753630
-    // Remove once MRG_RXBUF is supported and this variable is actually used.
-    #[allow(dead_code)]
     acked_features: u64,
-    mmds_ns: Option<MmdsNetworkStack>,
+    // Shared by the queue pairs of the device.
+    mmds_ns: Option<Arc<Mutex<MmdsNetworkStack>>>,
+    // Shared by the queue pairs of the device.
+    capture: Arc<Mutex<Option<PcapWriter>>>,
+    spoof_filter: Option<SpoofFilter>,
+    // Shared by the queue pairs of the device.
+    firewall: Arc<Mutex<Firewall>>,
+    // Only the handler of the first queue pair is notified when a rate limiter unblocks. It then
+    // kicks the queue events of the other pairs, which may be blocked as well.
+    rx_rate_limiter_kicks: Vec<EventFd>,
+    tx_rate_limiter_kicks: Vec<EventFd>,
+    // The epoll fd polling the timers of the rate limiters, and the tokens of the rx and tx
+    // limiters, so that the timers created by an update are polled as well.
+    epoll_raw_fd: RawFd,
+    rx_rate_limiter_token: u64,
+    tx_rate_limiter_token: u64,
+    // Only the handler of the first queue pair services the control queue.
+    ctrl: Option<CtrlVirtio>,
 
     #[cfg(test)]
     test_mutators: tests::TestMutators,
@@ -175,20 +412,20 @@ impl NetEpollHandler {
     // rate limiting budget.
     // Returns true on successful frame delivery.
     fn rate_limited_rx_single_frame(&mut self) -> bool {
+        let rate_limiter = self.rx.rate_limiter.clone();
+        let mut rate_limiter = rate_limiter
+            .lock()
+            .expect("Failed to acquire rate limiter lock");
         // If limiter.consume() fails it means there is no more TokenType::Ops
         // budget and rate limiting is in effect.
-        if !self.rx.rate_limiter.consume(1, TokenType::Ops) {
+        if !rate_limiter.consume(1, TokenType::Ops) {
             return false;
         }
         // If limiter.consume() fails it means there is no more TokenType::Bytes
         // budget and rate limiting is in effect.
-        if !self
-            .rx
-            .rate_limiter
-            .consume(self.rx.bytes_read as u64, TokenType::Bytes)
-        {
+        if !rate_limiter.consume(self.rx.bytes_read as u64, TokenType::Bytes) {
             // revert the OPS consume()
-            self.rx.rate_limiter.manual_replenish(1, TokenType::Ops);
+            rate_limiter.manual_replenish(1, TokenType::Ops);
             return false;
         }
 
@@ -198,11 +435,9 @@ impl NetEpollHandler {
         // Undo the tokens consumption if guest delivery failed.
         if !success {
             // revert the OPS consume()
-            self.rx.rate_limiter.manual_replenish(1, TokenType::Ops);
+            rate_limiter.manual_replenish(1, TokenType::Ops);
             // revert the BYTES consume()
-            self.rx
-                .rate_limiter
-                .manual_replenish(self.rx.bytes_read as u64, TokenType::Bytes);
+            rate_limiter.manual_replenish(self.rx.bytes_read as u64, TokenType::Bytes);
         }
         return success;
     }
@@ -211,6 +446,10 @@ impl NetEpollHandler {
     // if a buffer was used, and false if the frame must be deferred until a buffer
     // is made available by the driver.
     fn rx_single_frame(&mut self) -> bool {
+        if self.acked_features & (1 << VIRTIO_NET_F_MRG_RXBUF) != 0 {
+            return self.rx_mergeable_frame();
+        }
+
         let mut next_desc = self.rx.queue.iter(&self.mem).next();
 
         if next_desc.is_none() {
@@ -272,18 +511,123 @@ impl NetEpollHandler {
         }
     }
 
+    // Copies a single frame from `self.rx.frame_buf` into as many descriptor chains as it takes,
+    // when the driver negotiated mergeable RX buffers. Returns true if the frame was consumed,
+    // and false if it must be deferred until the driver makes enough buffers available.
+    fn rx_mergeable_frame(&mut self) -> bool {
+        let bytes_read = self.rx.bytes_read;
+        let max_buffers = cmp::min(
+            self.rx.queue.actual_size() as usize,
+            self.rx.used_desc_heads.len(),
+        );
+        let mut num_buffers = 0;
+        let mut capacity = 0;
+        // Chains which cannot hold any part of the frame, such as those starting with a read-only
+        // descriptor. They are handed back empty along with the frame.
+        let mut empty_heads = Vec::new();
+
+        // Gather the buffers before writing anything, there may not be enough of them.
+        self.rx.iovec.clear();
+        {
+            let mut avail_iter = self.rx.queue.iter(&self.mem);
+            while capacity < bytes_read && num_buffers < max_buffers {
+                let head = match avail_iter.next() {
+                    Some(head) => head,
+                    None => break,
+                };
+                let head_index = head.index;
+                let mut chain_len = 0;
+                let mut next_desc = Some(head);
+                while let Some(desc) = next_desc {
+                    if !desc.is_write_only() || capacity + chain_len >= bytes_read {
+                        break;
+                    }
+                    let len = cmp::min(desc.len as usize, bytes_read - capacity - chain_len);
+                    self.rx.iovec.push((desc.addr, len));
+                    chain_len += len;
+                    next_desc = desc.next_descriptor();
+                }
+                if chain_len == 0 {
+                    empty_heads.push(head_index);
+                    continue;
+                }
+                capacity += chain_len;
+                self.rx.used_desc_heads[num_buffers] = (head_index, chain_len as u32);
+                num_buffers += 1;
+            }
+        }
+        if capacity < bytes_read {
+            // Give the buffers back to the queue.
+            for _ in 0..num_buffers + empty_heads.len() {
+                self.rx.queue.go_to_previous_position();
+            }
+            if num_buffers < max_buffers {
+                return false;
+            }
+            // Even a full queue cannot hold the frame.
+            warn!("Receiving buffers are too small to hold frame of current size");
+            METRICS.net.rx_fails.inc();
+            return true;
+        }
+
+        // The header tells the driver how many buffers the frame spans.
+        LittleEndian::write_u16(
+            &mut self.rx.frame_buf[VNET_HDR_NUM_BUFFERS_OFFSET..VNET_HDR_NUM_BUFFERS_OFFSET + 2],
+            num_buffers as u16,
+        );
+        let mut write_count = 0;
+        for &(addr, len) in self.rx.iovec.iter() {
+            let source_slice = &self.rx.frame_buf[write_count..write_count + len];
+            match self.mem.write_slice_at_addr(source_slice, addr) {
+                Ok(sz) => write_count += sz,
+                Err(e) => {
+                    error!("Failed to write slice: {:?}", e);
+                    METRICS.net.rx_fails.inc();
+                    break;
+                }
+            }
+        }
+
+        for &head_index in empty_heads.iter() {
+            warn!("Receiving buffer cannot be written to");
+            METRICS.net.rx_fails.inc();
+            self.rx.queue.add_used(&self.mem, head_index, 0);
+        }
+        // The driver must see all the buffers of the frame at once.
+        self.rx
+            .queue
+            .add_used_batch(&self.mem, &self.rx.used_desc_heads[..num_buffers]);
+
+        // Mark that we have at least one pending packet and we need to interrupt the guest.
+        self.rx.deferred_irqs = true;
+
+        if write_count >= bytes_read {
+            METRICS.net.rx_bytes_count.add(write_count);
+            METRICS.net.rx_packets_count.inc();
+            true
+        } else {
+            false
+        }
+    }
+
     // Tries to detour the frame to MMDS and if MMDS doesn't accept it, sends it on the host TAP.
     //
-    // `frame_buf` should contain the frame bytes in a slice of exact length.
+    // `frame_buf` should contain the frame bytes in a slice of exact length. With the checksum and
+    // segmentation offloads, the frame may be larger than the MTU and carry a partial checksum,
+    // neither of which MMDS minds since it skips the VNET header and does not verify checksums.
     // Returns whether MMDS consumed the frame.
     fn write_to_mmds_or_tap(
-        mmds_ns: Option<&mut MmdsNetworkStack>,
+        mmds_ns: Option<&Arc<Mutex<MmdsNetworkStack>>>,
         rate_limiter: &mut RateLimiter,
         frame_buf: &[u8],
         tap: &mut Tap,
     ) -> bool {
         if let Some(ns) = mmds_ns {
-            if ns.detour_frame(frame_bytes_from_buf(frame_buf)) {
+            if ns
+                .lock()
+                .expect("Failed to acquire MMDS lock")
+                .detour_frame(frame_bytes_from_buf(frame_buf))
+            {
                 METRICS.mmds.rx_accepted.inc();
 
                 // MMDS frames are not accounted by the rate limiter.
@@ -311,8 +655,11 @@ impl NetEpollHandler {
 
     // We currently prioritize packets from the MMDS over regular network packets.
     fn read_from_mmds_or_tap(&mut self) -> io::Result<usize> {
-        if let Some(ns) = self.mmds_ns.as_mut() {
-            if let Some(len) = ns.write_next_frame(frame_bytes_from_buf_mut(&mut self.rx.frame_buf))
+        if let Some(ref ns) = self.mmds_ns {
+            if let Some(len) = ns
+                .lock()
+                .expect("Failed to acquire MMDS lock")
+                .write_next_frame(frame_bytes_from_buf_mut(&mut self.rx.frame_buf))
             {
                 let len = len.get();
                 METRICS.mmds.tx_frames.inc();
@@ -329,6 +676,13 @@ impl NetEpollHandler {
         loop {
             match self.read_from_mmds_or_tap() {
                 Ok(count) => {
+                    // The frame is checked once, before it is deferred if it cannot be received
+                    // yet, so that it counts once in the rule hits.
+                    if !firewall_allows(&self.firewall, &self.rx.frame_buf[..count], true) {
+                        continue;
+                    }
+                    // The frame is captured whether it comes from MMDS or from the tap.
+                    capture_frame(&self.capture, &self.rx.frame_buf[..count]);
                     self.rx.bytes_read = count;
                     if !self.rate_limited_rx_single_frame() {
                         self.rx.deferred_frame = true;
@@ -379,10 +733,14 @@ impl NetEpollHandler {
         // with the MMDS network stack.
         let mut process_rx_for_mmds = false;
 
+        let rate_limiter = self.tx.rate_limiter.clone();
+        let mut rate_limiter = rate_limiter
+            .lock()
+            .expect("Failed to acquire rate limiter lock");
         for avail_desc in self.tx.queue.iter(&self.mem) {
             // If limiter.consume() fails it means there is no more TokenType::Ops
             // budget and rate limiting is in effect.
-            if !self.tx.rate_limiter.consume(1, TokenType::Ops) {
+            if !rate_limiter.consume(1, TokenType::Ops) {
                 rate_limited = true;
                 // Stop processing the queue.
                 break;
@@ -411,14 +769,10 @@ impl NetEpollHandler {
 
             // If limiter.consume() fails it means there is no more TokenType::Bytes
             // budget and rate limiting is in effect.
-            if !self
-                .tx
-                .rate_limiter
-                .consume(read_count as u64, TokenType::Bytes)
-            {
+            if !rate_limiter.consume(read_count as u64, TokenType::Bytes) {
                 rate_limited = true;
                 // revert the OPS consume()
-                self.tx.rate_limiter.manual_replenish(1, TokenType::Ops);
+                rate_limiter.manual_replenish(1, TokenType::Ops);
                 // stop processing the queue
                 break;
             }
@@ -446,12 +800,20 @@ impl NetEpollHandler {
                 }
             }
 
-            if Self::write_to_mmds_or_tap(
-                self.mmds_ns.as_mut(),
-                &mut self.tx.rate_limiter,
-                &mut self.tx.frame_buf[..read_count],
-                &mut self.tap,
-            ) && !self.rx.deferred_frame
+            let dropped = is_spoofed(self.spoof_filter.as_ref(), &self.tx.frame_buf[..read_count])
+                || !firewall_allows(&self.firewall, &self.tx.frame_buf[..read_count], false);
+            // The frame is captured whether it goes to MMDS or to the tap, unless it is dropped.
+            if !dropped {
+                capture_frame(&self.capture, &self.tx.frame_buf[..read_count]);
+            }
+            if !dropped
+                && Self::write_to_mmds_or_tap(
+                    self.mmds_ns.as_ref(),
+                    &mut rate_limiter,
+                    &mut self.tx.frame_buf[..read_count],
+                    &mut self.tap,
+                )
+                && !self.rx.deferred_frame
             {
                 // MMDS consumed this frame/request, let's also try to process the response.
                 process_rx_for_mmds = true;
@@ -465,6 +827,7 @@ impl NetEpollHandler {
             // processing; go back one element so it can be processed next time.
             self.tx.queue.go_to_previous_position();
         }
+        drop(rate_limiter);
 
         if used_count != 0 {
             // TODO(performance - Issue #425): find a way around RUST mutability enforcements to
@@ -482,6 +845,57 @@ impl NetEpollHandler {
         }
     }
 
+    fn process_ctrl(&mut self) -> bool {
+        let ctrl = match self.ctrl {
+            Some(ref mut ctrl) => ctrl,
+            None => return false,
+        };
+
+        let mut used_desc_heads = Vec::new();
+        for avail_desc in ctrl.queue.iter(&self.mem) {
+            let index = avail_desc.index;
+            let len = ctrl.queue_pairs.execute(avail_desc, &self.mem);
+            used_desc_heads.push((index, len));
+        }
+        for &(desc_index, len) in &used_desc_heads {
+            ctrl.queue.add_used(&self.mem, desc_index, len);
+        }
+        !used_desc_heads.is_empty()
+    }
+
+    // The queue pairs share the rate limiters, so they are updated for all of them.
+    fn update_rate_limiter(
+        &self,
+        rate_limiter: &Mutex<RateLimiter>,
+        update: RateLimiterUpdate,
+        token: u64,
+    ) -> io::Result<()> {
+        let mut rate_limiter = rate_limiter
+            .lock()
+            .expect("Failed to acquire rate limiter lock");
+        let old_rawfd = rate_limiter.as_raw_fd();
+        rate_limiter.update(update)?;
+        let rawfd = rate_limiter.as_raw_fd();
+        if rawfd != -1 && rawfd != old_rawfd {
+            epoll::ctl(
+                self.epoll_raw_fd,
+                epoll::EPOLL_CTL_ADD,
+                rawfd,
+                epoll::Event::new(epoll::EPOLLIN, token),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn kick(kicks: &[EventFd]) {
+        for kick in kicks {
+            if let Err(e) = kick.write(1) {
+                error!("Failed to kick queue: {:?}", e);
+                METRICS.net.event_fails.inc();
+            }
+        }
+    }
+
     #[cfg(not(test))]
     fn read_tap(&mut self) -> io::Result<usize> {
         self.tap.read(&mut self.rx.frame_buf)
@@ -489,13 +903,19 @@ impl NetEpollHandler {
 }
 
 impl EpollHandler for NetEpollHandler {
-    fn handle_event(&mut self, device_event: DeviceEventT, _: u32, _: EpollHandlerPayload) {
+    fn handle_event(&mut self, device_event: DeviceEventT, _: u32, payload: EpollHandlerPayload) {
         match device_event {
             RX_TAP_EVENT => {
                 METRICS.net.rx_tap_event_count.inc();
 
                 // While limiter is blocked, don't process any more incoming.
-                if self.rx.rate_limiter.is_blocked() {
+                if self
+                    .rx
+                    .rate_limiter
+                    .lock()
+                    .expect("Failed to acquire rate limiter lock")
+                    .is_blocked()
+                {
                     return;
                 }
                 // Process a deferred frame first if available. Don't read from tap again
@@ -521,7 +941,13 @@ impl EpollHandler for NetEpollHandler {
                     // Shouldn't we return here?
                 }
                 // If the limiter is not blocked, resume the receiving of bytes.
-                if !self.rx.rate_limiter.is_blocked() {
+                let blocked = self
+                    .rx
+                    .rate_limiter
+                    .lock()
+                    .expect("Failed to acquire rate limiter lock")
+                    .is_blocked();
+                if !blocked {
                     // There should be a buffer available now to receive the frame into.
                     self.resume_rx();
                 }
@@ -534,7 +960,13 @@ impl EpollHandler for NetEpollHandler {
                     METRICS.net.event_fails.inc();
                 }
                 // If the limiter is not blocked, continue transmitting bytes.
-                if !self.tx.rate_limiter.is_blocked() {
+                let blocked = self
+                    .tx
+                    .rate_limiter
+                    .lock()
+                    .expect("Failed to acquire rate limiter lock")
+                    .is_blocked();
+                if !blocked {
                     self.process_tx();
                 }
             }
@@ -542,8 +974,15 @@ impl EpollHandler for NetEpollHandler {
                 METRICS.net.rx_event_rate_limiter_count.inc();
                 // Upon rate limiter event, call the rate limiter handler
                 // and restart processing the queue.
-                match self.rx.rate_limiter.event_handler() {
+                let result = self
+                    .rx
+                    .rate_limiter
+                    .lock()
+                    .expect("Failed to acquire rate limiter lock")
+                    .event_handler();
+                match result {
                     Ok(_) => {
+                        Self::kick(&self.rx_rate_limiter_kicks);
                         // There might be enough budget now to receive the frame.
                         self.resume_rx();
                     }
@@ -557,8 +996,15 @@ impl EpollHandler for NetEpollHandler {
                 METRICS.net.tx_rate_limiter_event_count.inc();
                 // Upon rate limiter event, call the rate limiter handler
                 // and restart processing the queue.
-                match self.tx.rate_limiter.event_handler() {
+                let result = self
+                    .tx
+                    .rate_limiter
+                    .lock()
+                    .expect("Failed to acquire rate limiter lock")
+                    .event_handler();
+                match result {
                     Ok(_) => {
+                        Self::kick(&self.tx_rate_limiter_kicks);
                         // There might be enough budget now to send the frame.
                         self.process_tx();
                     }
@@ -568,9 +1014,53 @@ impl EpollHandler for NetEpollHandler {
                     }
                 }
             }
+            CAPTURE_UPDATE_EVENT => {
+                if let EpollHandlerPayload::NetCapturePayload(capture) = payload {
+                    // The queue pairs share the capture, so it is updated for all of them.
+                    *self.capture.lock().expect("Failed to acquire capture lock") = capture;
+                } else {
+                    // This path can only be reached if we have a logical problem in our code.
+                    panic!("Received update capture event with empty payload.")
+                }
+            }
+            FIREWALL_UPDATE_EVENT => {
+                if let EpollHandlerPayload::NetFirewallPayload(firewall) = payload {
+                    // The queue pairs share the firewall, so it is updated for all of them.
+                    *self
+                        .firewall
+                        .lock()
+                        .expect("Failed to acquire firewall lock") = firewall;
+                } else {
+                    // This path can only be reached if we have a logical problem in our code.
+                    panic!("Received update firewall event with empty payload.")
+                }
+            }
+            CTRL_QUEUE_EVENT => {
+                METRICS.net.ctrl_queue_event_count.inc();
+                if let Some(ref ctrl) = self.ctrl {
+                    if let Err(e) = ctrl.queue_evt.read() {
+                        error!("Failed to get ctrl queue event: {:?}", e);
+                        METRICS.net.event_fails.inc();
+                        return;
+                    }
+                }
+                if self.process_ctrl() {
+                    self.signal_used_queue();
+                }
+            }
             _ => panic!("Unknown event type was received."),
         }
     }
+
+    fn update_rate_limiters(&mut self, payload: EpollHandlerPayload) -> io::Result<()> {
+        if let EpollHandlerPayload::NetRateLimiterPayload(rx_update, tx_update) = payload {
+            self.update_rate_limiter(&self.rx.rate_limiter, rx_update, self.rx_rate_limiter_token)?;
+            self.update_rate_limiter(&self.tx.rate_limiter, tx_update, self.tx_rate_limiter_token)
+        } else {
+            // This path can only be reached if we have a logical problem in our code.
+            panic!("Received rate limiter update with an invalid payload.")
+        }
+    }
 }
 
 pub struct EpollConfig {
@@ -579,6 +1069,7 @@ pub struct EpollConfig {
     tx_queue_token: u64,
     rx_rate_limiter_token: u64,
     tx_rate_limiter_token: u64,
+    ctrl_queue_token: u64,
     epoll_raw_fd: RawFd,
     sender: mpsc::Sender<Box<EpollHandler>>,
 }
@@ -595,6 +1086,7 @@ impl EpollConfig {
             tx_queue_token: first_token + TX_QUEUE_EVENT as u64,
             rx_rate_limiter_token: first_token + RX_RATE_LIMITER_EVENT as u64,
             tx_rate_limiter_token: first_token + TX_RATE_LIMITER_EVENT as u64,
+            ctrl_queue_token: first_token + CTRL_QUEUE_EVENT as u64,
             epoll_raw_fd,
             sender,
         }
@@ -602,44 +1094,84 @@ impl EpollConfig {
 }
 
 pub struct Net {
-    tap: Option<Tap>,
+    // One per queue pair.
+    taps: Vec<Tap>,
     avail_features: u64,
     acked_features: u64,
     // The config space will only consist of the MAC address specified by the user,
-    // or nothing, if no such address if provided.
+    // or nothing, if no such address if provided. Devices with several queue pairs also
+    // expose their number.
     config_space: Vec<u8>,
-    epoll_config: EpollConfig,
+    // One per queue pair.
+    epoll_configs: Vec<EpollConfig>,
     rx_rate_limiter: Option<RateLimiter>,
     tx_rate_limiter: Option<RateLimiter>,
     allow_mmds_requests: bool,
+    hooks: FrameHooks,
+    queue_sizes: Vec<u16>,
+    // The number of queue pairs in use, which the control queue changes once activated.
+    active_pairs: Arc<AtomicUsize>,
+}
+
+/// What the frames of a network device go through, besides being moved between the guest and the
+/// tap device.
+#[derive(Default)]
+pub struct FrameHooks {
+    /// The capture the frames received and sent by the guest are written to.
+    pub capture: Option<PcapWriter>,
+    /// The filter dropping the frames sent by the guest from addresses it was not given.
+    pub spoof_filter: Option<SpoofFilter>,
+    /// The firewall the frames received and sent by the guest must get through.
+    pub firewall: Option<Firewall>,
 }
 
 impl Net {
     /// Create a new virtio network device with the given TAP interface.
-    pub fn new_with_tap(
-        tap: Tap,
+    ///
+    /// The device has one RX/TX queue pair for each of the `taps`, which must be the queues of
+    /// the same interface, along with the `epoll_configs` of their handlers. Devices with several
+    /// queue pairs also have a control queue, through which the driver sets how many pairs it
+    /// uses. The frames received and sent by the guest go through the `hooks`.
+    pub fn new_with_taps(
+        taps: Vec<Tap>,
         guest_mac: Option<&MacAddr>,
-        epoll_config: EpollConfig,
+        epoll_configs: Vec<EpollConfig>,
         rx_rate_limiter: Option<RateLimiter>,
         tx_rate_limiter: Option<RateLimiter>,
         allow_mmds_requests: bool,
+        hooks: FrameHooks,
     ) -> Result<Self> {
-        // Set offload flags to match the virtio features below.
-        tap.set_offload(
-            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6,
-        )
-        .map_err(Error::TapSetOffload)?;
+        let num_queue_pairs = taps.len();
+        if num_queue_pairs == 0
+            || num_queue_pairs > MAX_QUEUE_PAIRS as usize
+            || epoll_configs.len() != num_queue_pairs
+        {
+            return Err(Error::InvalidNumQueuePairs);
+        }
+
+        for tap in taps.iter() {
+            // The offloads follow the features acked by the driver, set on activation. Until
+            // then, the tap only hands us complete frames.
+            tap.set_offload(0).map_err(Error::TapSetOffload)?;
 
-        let vnet_hdr_size = vnet_hdr_len() as i32;
-        tap.set_vnet_hdr_size(vnet_hdr_size)
-            .map_err(Error::TapSetVnetHdrSize)?;
+            let vnet_hdr_size = vnet_hdr_len() as i32;
+            tap.set_vnet_hdr_size(vnet_hdr_size)
+                .map_err(Error::TapSetVnetHdrSize)?;
+        }
+        // Only the first queue pair is used until the driver sets the number of pairs.
+        for tap in taps.iter().skip(1) {
+            tap.set_queue_attached(false).map_err(Error::TapSetQueue)?;
+        }
 
         let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
             | 1 << VIRTIO_NET_F_CSUM
             | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_TSO6
             | 1 << VIRTIO_NET_F_GUEST_UFO
             | 1 << VIRTIO_NET_F_HOST_TSO4
+            | 1 << VIRTIO_NET_F_HOST_TSO6
             | 1 << VIRTIO_NET_F_HOST_UFO
+            | 1 << VIRTIO_NET_F_MRG_RXBUF
             | 1 << VIRTIO_F_VERSION_1;
 
         let mut config_space;
@@ -655,15 +1187,33 @@ impl Net {
             config_space = Vec::new();
         }
 
+        let mut queue_sizes = vec![QUEUE_SIZE; 2 * num_queue_pairs];
+        if num_queue_pairs > 1 {
+            avail_features |= 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ;
+            // The number of queue pairs follows the MAC address and the status, which are
+            // zero when not advertised. The config space is little endian.
+            config_space.resize(CONFIG_SPACE_SIZE, 0);
+            LittleEndian::write_u16(
+                &mut config_space[CONFIG_MAX_VIRTQUEUE_PAIRS..CONFIG_SPACE_SIZE],
+                num_queue_pairs as u16,
+            );
+            // The control queue comes after the queue pairs.
+            queue_sizes.push(QUEUE_SIZE);
+        }
+
         Ok(Net {
-            tap: Some(tap),
+            taps,
             avail_features,
             acked_features: 0u64,
             config_space,
-            epoll_config,
+            epoll_configs,
             rx_rate_limiter,
             tx_rate_limiter,
             allow_mmds_requests,
+            hooks,
+            queue_sizes,
+            // Only the first queue pair is used until the driver sets the number of pairs.
+            active_pairs: Arc::new(AtomicUsize::new(1)),
         })
     }
 
@@ -683,13 +1233,14 @@ impl Net {
         tap.set_netmask(netmask).map_err(Error::TapSetNetmask)?;
         tap.enable().map_err(Error::TapEnable)?;
 
-        Self::new_with_tap(
-            tap,
+        Self::new_with_taps(
+            vec![tap],
             guest_mac,
-            epoll_config,
+            vec![epoll_config],
             rx_rate_limiter,
             tx_rate_limiter,
             allow_mmds_requests,
+            FrameHooks::default(),
         )
     }
 }
@@ -700,7 +1251,7 @@ impl VirtioDevice for Net {
     }
 
     fn queue_max_sizes(&self) -> &[u16] {
-        QUEUE_SIZES
+        &self.queue_sizes
     }
 
     fn features(&self, page: u32) -> u32 {
@@ -757,7 +1308,7 @@ impl VirtioDevice for Net {
             return;
         }
         let (_, right) = self.config_space.split_at_mut(offset as usize);
-        right.copy_from_slice(&data[..]);
+        right[..data.len()].copy_from_slice(&data[..]);
     }
 
     fn activate(
@@ -768,10 +1319,11 @@ impl VirtioDevice for Net {
         mut queues: Vec<Queue>,
         mut queue_evts: Vec<EventFd>,
     ) -> ActivateResult {
-        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+        let num_queues = self.queue_sizes.len();
+        if queues.len() != num_queues || queue_evts.len() != num_queues {
             error!(
                 "Cannot perform activate. Expected {} queue(s), got {}",
-                NUM_QUEUES,
+                num_queues,
                 queues.len()
             );
             METRICS.net.activate_fails.inc();
@@ -779,32 +1331,127 @@ impl VirtioDevice for Net {
             return Err(ActivateError::BadActivate);
         }
 
-        if let Some(tap) = self.tap.take() {
+        if self.taps.is_empty() {
+            METRICS.net.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+        let offload_flags = tap_offload_flags(self.acked_features);
+        for tap in self.taps.iter() {
+            if let Err(e) = tap.set_offload(offload_flags) {
+                error!("Failed to set tap offloads: {:?}", e);
+                METRICS.net.activate_fails.inc();
+                return Err(ActivateError::BadActivate);
+            }
+        }
+        // A restored driver keeps using the queue pairs it had set. The others stay detached.
+        let active_pairs = self.active_pairs.load(Ordering::SeqCst);
+        for tap in self.taps.iter().take(active_pairs).skip(1) {
+            if let Err(e) = tap.set_queue_attached(true) {
+                error!("Failed to attach tap queue: {:?}", e);
+                METRICS.net.activate_fails.inc();
+                return Err(ActivateError::BadActivate);
+            }
+        }
+        let taps = mem::replace(&mut self.taps, Vec::new());
+        let num_queue_pairs = taps.len();
+
+        let mut ctrl = None;
+        if num_queue_pairs > 1 {
+            // The control queue is the last one.
+            let ctrl_taps = taps
+                .iter()
+                .map(Tap::try_clone)
+                .collect::<result::Result<Vec<_>, _>>()
+                .map_err(|e| {
+                    error!("Failed to clone tap: {:?}", e);
+                    METRICS.net.activate_fails.inc();
+                    ActivateError::BadActivate
+                })?;
+            // We just checked the number of queues.
+            let ctrl_queue = queues.pop().unwrap();
+            let ctrl_queue_evt = queue_evts.pop().unwrap();
+            ctrl = Some(CtrlVirtio::new(
+                ctrl_queue,
+                ctrl_queue_evt,
+                ctrl_taps,
+                self.active_pairs.clone(),
+            ));
+        }
+
+        let mut rx_rate_limiter = self.rx_rate_limiter.take().unwrap_or_default();
+        let mut tx_rate_limiter = self.tx_rate_limiter.take().unwrap_or_default();
+        // Each direction resumes processing when its rate-limit group unblocks. The RX queues
+        // have even indices and the TX queues odd ones.
+        let mut rx_rate_limiter_kicks = Vec::new();
+        let mut tx_rate_limiter_kicks = Vec::new();
+        for (i, queue_evt) in queue_evts.iter().enumerate() {
+            let (rate_limiter, kicks) = if i % 2 == 0 {
+                (&mut rx_rate_limiter, &mut rx_rate_limiter_kicks)
+            } else {
+                (&mut tx_rate_limiter, &mut tx_rate_limiter_kicks)
+            };
+            rate_limiter.add_group_kick(queue_evt).map_err(|e| {
+                METRICS.net.activate_fails.inc();
+                ActivateError::TryClone(e)
+            })?;
+            // The handler of the first queue pair kicks the other ones.
+            if i >= 2 {
+                kicks.push(queue_evt.try_clone().map_err(|e| {
+                    METRICS.net.activate_fails.inc();
+                    ActivateError::TryClone(e)
+                })?);
+            }
+        }
+        let rx_rate_limiter_rawfd = rx_rate_limiter.as_raw_fd();
+        let tx_rate_limiter_rawfd = tx_rate_limiter.as_raw_fd();
+        let rx_rate_limiter = Arc::new(Mutex::new(rx_rate_limiter));
+        let tx_rate_limiter = Arc::new(Mutex::new(tx_rate_limiter));
+
+        let mut mmds_ns = None;
+        if self.allow_mmds_requests {
+            mmds_ns = Some(Arc::new(Mutex::new(MmdsNetworkStack::new_with_defaults())));
+        }
+        let capture = Arc::new(Mutex::new(self.hooks.capture.take()));
+        let firewall = Arc::new(Mutex::new(self.hooks.firewall.take().unwrap_or_default()));
+
+        // Every queue pair gets its own handler, but all the handlers run on the thread of the
+        // device event loop. The pairs spread the load of the guest across its vCPUs; they don't
+        // add parallelism on the host.
+        for (i, tap) in taps.into_iter().enumerate() {
+            let epoll_config = &self.epoll_configs[i];
             let rx_queue = queues.remove(0);
             let tx_queue = queues.remove(0);
             let rx_queue_evt = queue_evts.remove(0);
             let tx_queue_evt = queue_evts.remove(0);
-            let mut mmds_ns = None;
-            if self.allow_mmds_requests {
-                mmds_ns = Some(MmdsNetworkStack::new_with_defaults());
-            }
             let handler = NetEpollHandler {
-                rx: RxVirtio::new(
-                    rx_queue,
-                    rx_queue_evt,
-                    self.rx_rate_limiter.take().unwrap_or_default(),
-                ),
+                rx: RxVirtio::new(rx_queue, rx_queue_evt, rx_rate_limiter.clone()),
                 tap,
-                mem,
-                tx: TxVirtio::new(
-                    tx_queue,
-                    tx_queue_evt,
-                    self.tx_rate_limiter.take().unwrap_or_default(),
-                ),
-                interrupt_status: status,
-                interrupt_evt,
+                mem: mem.clone(),
+                tx: TxVirtio::new(tx_queue, tx_queue_evt, tx_rate_limiter.clone()),
+                interrupt_status: status.clone(),
+                interrupt_evt: interrupt_evt.try_clone().map_err(|e| {
+                    METRICS.net.activate_fails.inc();
+                    ActivateError::TryClone(e)
+                })?,
                 acked_features: self.acked_features,
-                mmds_ns,
+                mmds_ns: mmds_ns.clone(),
+                capture: capture.clone(),
+                spoof_filter: self.hooks.spoof_filter.clone(),
+                firewall: firewall.clone(),
+                rx_rate_limiter_kicks: if i == 0 {
+                    mem::replace(&mut rx_rate_limiter_kicks, Vec::new())
+                } else {
+                    Vec::new()
+                },
+                tx_rate_limiter_kicks: if i == 0 {
+                    mem::replace(&mut tx_rate_limiter_kicks, Vec::new())
+                } else {
+                    Vec::new()
+                },
+                epoll_raw_fd: epoll_config.epoll_raw_fd,
+                rx_rate_limiter_token: epoll_config.rx_rate_limiter_token,
+                tx_rate_limiter_token: epoll_config.tx_rate_limiter_token,
+                ctrl: if i == 0 { ctrl.take() } else { None },
 
                 #[cfg(test)]
                 test_mutators: tests::TestMutators::default(),
@@ -813,12 +1460,10 @@ impl VirtioDevice for Net {
             let tap_raw_fd = handler.tap.as_raw_fd();
             let rx_queue_raw_fd = handler.rx.queue_evt.as_raw_fd();
             let tx_queue_raw_fd = handler.tx.queue_evt.as_raw_fd();
-
-            let rx_rate_limiter_rawfd = handler.rx.rate_limiter.as_raw_fd();
-            let tx_rate_limiter_rawfd = handler.tx.rate_limiter.as_raw_fd();
+            let ctrl_queue_raw_fd = handler.ctrl.as_ref().map(|ctrl| ctrl.queue_evt.as_raw_fd());
 
             //channel should be open and working
-            self.epoll_config
+            epoll_config
                 .sender
                 .send(Box::new(handler))
                 .expect("Failed to send through the channel");
@@ -826,10 +1471,10 @@ impl VirtioDevice for Net {
             //TODO: barrier needed here maybe?
 
             epoll::ctl(
-                self.epoll_config.epoll_raw_fd,
+                epoll_config.epoll_raw_fd,
                 epoll::EPOLL_CTL_ADD,
                 tap_raw_fd,
-                epoll::Event::new(epoll::EPOLLIN, self.epoll_config.rx_tap_token),
+                epoll::Event::new(epoll::EPOLLIN, epoll_config.rx_tap_token),
             )
             .map_err(|e| {
                 METRICS.net.activate_fails.inc();
@@ -837,10 +1482,10 @@ impl VirtioDevice for Net {
             })?;
 
             epoll::ctl(
-                self.epoll_config.epoll_raw_fd,
+                epoll_config.epoll_raw_fd,
                 epoll::EPOLL_CTL_ADD,
                 rx_queue_raw_fd,
-                epoll::Event::new(epoll::EPOLLIN, self.epoll_config.rx_queue_token),
+                epoll::Event::new(epoll::EPOLLIN, epoll_config.rx_queue_token),
             )
             .map_err(|e| {
                 METRICS.net.activate_fails.inc();
@@ -848,46 +1493,79 @@ impl VirtioDevice for Net {
             })?;
 
             epoll::ctl(
-                self.epoll_config.epoll_raw_fd,
+                epoll_config.epoll_raw_fd,
                 epoll::EPOLL_CTL_ADD,
                 tx_queue_raw_fd,
-                epoll::Event::new(epoll::EPOLLIN, self.epoll_config.tx_queue_token),
+                epoll::Event::new(epoll::EPOLLIN, epoll_config.tx_queue_token),
             )
             .map_err(|e| {
                 METRICS.net.activate_fails.inc();
                 ActivateError::EpollCtl(e)
             })?;
 
-            if rx_rate_limiter_rawfd != -1 {
+            if let Some(ctrl_queue_raw_fd) = ctrl_queue_raw_fd {
                 epoll::ctl(
-                    self.epoll_config.epoll_raw_fd,
+                    epoll_config.epoll_raw_fd,
+                    epoll::EPOLL_CTL_ADD,
+                    ctrl_queue_raw_fd,
+                    epoll::Event::new(epoll::EPOLLIN, epoll_config.ctrl_queue_token),
+                )
+                .map_err(|e| {
+                    METRICS.net.activate_fails.inc();
+                    ActivateError::EpollCtl(e)
+                })?;
+            }
+
+            // The rate limiters are shared by the queue pairs, their events go to the first one.
+            if i == 0 && rx_rate_limiter_rawfd != -1 {
+                epoll::ctl(
+                    epoll_config.epoll_raw_fd,
                     epoll::EPOLL_CTL_ADD,
                     rx_rate_limiter_rawfd,
-                    epoll::Event::new(epoll::EPOLLIN, self.epoll_config.rx_rate_limiter_token),
+                    epoll::Event::new(epoll::EPOLLIN, epoll_config.rx_rate_limiter_token),
                 )
                 .map_err(ActivateError::EpollCtl)?;
             }
 
-            if tx_rate_limiter_rawfd != -1 {
+            if i == 0 && tx_rate_limiter_rawfd != -1 {
                 epoll::ctl(
-                    self.epoll_config.epoll_raw_fd,
+                    epoll_config.epoll_raw_fd,
                     epoll::EPOLL_CTL_ADD,
                     tx_rate_limiter_rawfd,
-                    epoll::Event::new(epoll::EPOLLIN, self.epoll_config.tx_rate_limiter_token),
+                    epoll::Event::new(epoll::EPOLLIN, epoll_config.tx_rate_limiter_token),
                 )
                 .map_err(ActivateError::EpollCtl)?;
             }
+        }
+
+        Ok(())
+    }
+
+    fn active_queue_pairs(&self) -> Option<u16> {
+        // Only the devices with several queue pairs have a control queue, after the pairs.
+        if self.queue_sizes.len() % 2 == 1 {
+            Some(self.active_pairs.load(Ordering::SeqCst) as u16)
+        } else {
+            None
+        }
+    }
 
-            return Ok(());
+    fn set_active_queue_pairs(&mut self, pairs: u16) -> result::Result<(), RestoreError> {
+        if self.queue_sizes.len() % 2 == 0 || pairs == 0 || pairs as usize > self.taps.len() {
+            return Err(RestoreError::InvalidQueuePairs(pairs));
         }
-        METRICS.net.activate_fails.inc();
-        Err(ActivateError::BadActivate)
+        self.active_pairs.store(pairs as usize, Ordering::SeqCst);
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    extern crate tempfile;
+
+    use std::fs;
     use std::sync::mpsc::Receiver;
+    use std::sync::MutexGuard;
     use std::thread;
     use std::time::Duration;
     use std::u32;
@@ -896,9 +1574,13 @@ mod tests {
 
     use super::*;
     use memory_model::GuestAddress;
+    use rate_limiter::TokenBucket;
     use virtio::queue::tests::*;
 
-    use dumbo::pdu::{arp, ethernet};
+    use dumbo::pdu::{arp, ethernet, ipv4, tcp};
+    use virtio::{CaptureConfig, FirewallAction, FirewallConfig, FirewallProtocol, FirewallRule};
+
+    use self::tempfile::tempdir;
 
     /// Will read $metric, run the code in $block, then assert metric has increased by $delta.
     macro_rules! check_metric_after_block {
@@ -982,12 +1664,18 @@ mod tests {
     }
 
     impl NetEpollHandler {
-        fn get_rx_rate_limiter(&self) -> &RateLimiter {
-            &self.rx.rate_limiter
+        fn get_rx_rate_limiter(&self) -> MutexGuard<RateLimiter> {
+            self.rx
+                .rate_limiter
+                .lock()
+                .expect("Failed to acquire rate limiter lock")
         }
 
-        fn get_tx_rate_limiter(&self) -> &RateLimiter {
-            &self.tx.rate_limiter
+        fn get_tx_rate_limiter(&self) -> MutexGuard<RateLimiter> {
+            self.tx
+                .rate_limiter
+                .lock()
+                .expect("Failed to acquire rate limiter lock")
         }
 
         // This needs to be public to be accessible from the non-cfg-test `impl NetEpollHandler`.
@@ -1017,11 +1705,11 @@ mod tests {
         }
 
         fn set_rx_rate_limiter(&mut self, rx_rate_limiter: RateLimiter) {
-            self.rx.rate_limiter = rx_rate_limiter;
+            self.rx.rate_limiter = Arc::new(Mutex::new(rx_rate_limiter));
         }
 
         fn set_tx_rate_limiter(&mut self, tx_rate_limiter: RateLimiter) {
-            self.tx.rate_limiter = tx_rate_limiter;
+            self.tx.rate_limiter = Arc::new(Mutex::new(tx_rate_limiter));
         }
     }
 
@@ -1070,14 +1758,31 @@ mod tests {
 
         (
             NetEpollHandler {
-                rx: RxVirtio::new(rx_queue, rx_queue_evt, RateLimiter::default()),
-                tap: n.tap.take().unwrap(),
+                rx: RxVirtio::new(
+                    rx_queue,
+                    rx_queue_evt,
+                    Arc::new(Mutex::new(RateLimiter::default())),
+                ),
+                tap: n.taps.remove(0),
                 mem: mem.clone(),
-                tx: TxVirtio::new(tx_queue, tx_queue_evt, RateLimiter::default()),
+                tx: TxVirtio::new(
+                    tx_queue,
+                    tx_queue_evt,
+                    Arc::new(Mutex::new(RateLimiter::default())),
+                ),
                 interrupt_status,
                 interrupt_evt,
                 acked_features: n.acked_features,
-                mmds_ns: Some(MmdsNetworkStack::new_with_defaults()),
+                mmds_ns: Some(Arc::new(Mutex::new(MmdsNetworkStack::new_with_defaults()))),
+                capture: Arc::new(Mutex::new(None)),
+                spoof_filter: None,
+                firewall: Arc::new(Mutex::new(Firewall::default())),
+                rx_rate_limiter_kicks: Vec::new(),
+                tx_rate_limiter_kicks: Vec::new(),
+                epoll_raw_fd: -1,
+                rx_rate_limiter_token: RX_RATE_LIMITER_EVENT as u64,
+                tx_rate_limiter_token: TX_RATE_LIMITER_EVENT as u64,
+                ctrl: None,
                 test_mutators,
             },
             txq,
@@ -1106,6 +1811,27 @@ mod tests {
         assert_eq!(frame_buf[vnet_hdr_len_], 15);
     }
 
+    #[test]
+    fn test_tap_offload_flags() {
+        let tso = 1 << VIRTIO_NET_F_GUEST_TSO4 | 1 << VIRTIO_NET_F_GUEST_TSO6;
+        // Segmentation offloads require checksum offload.
+        assert_eq!(tap_offload_flags(0), 0);
+        assert_eq!(tap_offload_flags(tso), 0);
+
+        let csum = 1 << VIRTIO_NET_F_GUEST_CSUM;
+        assert_eq!(tap_offload_flags(csum), net_gen::TUN_F_CSUM);
+        assert_eq!(
+            tap_offload_flags(csum | tso),
+            net_gen::TUN_F_CSUM | net_gen::TUN_F_TSO4 | net_gen::TUN_F_TSO6
+        );
+        assert_eq!(
+            tap_offload_flags(csum | 1 << VIRTIO_NET_F_GUEST_UFO),
+            net_gen::TUN_F_CSUM | net_gen::TUN_F_UFO
+        );
+        // The host offloads do not concern the tap.
+        assert_eq!(tap_offload_flags(1 << VIRTIO_NET_F_HOST_TSO4), 0);
+    }
+
     #[test]
     fn test_virtio_device() {
         let mac = MacAddr::parse_str("11:22:33:44:55:66").unwrap();
@@ -1120,7 +1846,7 @@ mod tests {
         // Test `queue_max_sizes()`.
         {
             let x = n.queue_max_sizes();
-            assert_eq!(x, QUEUE_SIZES);
+            assert_eq!(x, [QUEUE_SIZE; 2]);
 
             // power of 2?
             for &y in x {
@@ -1133,10 +1859,13 @@ mod tests {
             let features = 1 << VIRTIO_NET_F_GUEST_CSUM
                 | 1 << VIRTIO_NET_F_CSUM
                 | 1 << VIRTIO_NET_F_GUEST_TSO4
+                | 1 << VIRTIO_NET_F_GUEST_TSO6
                 | 1 << VIRTIO_NET_F_MAC
                 | 1 << VIRTIO_NET_F_GUEST_UFO
                 | 1 << VIRTIO_NET_F_HOST_TSO4
+                | 1 << VIRTIO_NET_F_HOST_TSO6
                 | 1 << VIRTIO_NET_F_HOST_UFO
+                | 1 << VIRTIO_NET_F_MRG_RXBUF
                 | 1 << VIRTIO_F_VERSION_1;
 
             assert_eq!(n.features(0), features as u32);
@@ -1320,8 +2049,8 @@ mod tests {
             &METRICS.mmds.rx_accepted,
             1,
             assert!(NetEpollHandler::write_to_mmds_or_tap(
-                h.mmds_ns.as_mut(),
-                &mut h.tx.rate_limiter,
+                h.mmds_ns.as_ref(),
+                &mut h.tx.rate_limiter.lock().unwrap(),
                 &h.tx.frame_buf[..packet_len],
                 &mut h.tap,
             ))
@@ -1335,6 +2064,348 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_mmds_detour_offloads() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+
+        let src_mac = MacAddr::parse_str("11:11:11:11:11:11").unwrap();
+        let dst_mac = MacAddr::parse_str("22:22:22:22:22:22").unwrap();
+        let src_addr = Ipv4Addr::new(10, 1, 2, 3);
+        let dst_addr = Ipv4Addr::new(169, 254, 169, 254);
+        // A segment larger than the MTU, as sent by a driver which negotiated TSO.
+        let payload = [0u8; 4000];
+
+        let frame_len;
+        {
+            let mut eth = ethernet::EthernetFrame::write_incomplete(
+                frame_bytes_from_buf_mut(&mut h.tx.frame_buf),
+                dst_mac,
+                src_mac,
+                ethernet::ETHERTYPE_IPV4,
+            )
+            .ok()
+            .unwrap();
+            let ip_len;
+            {
+                let mut ip = ipv4::IPv4Packet::write_header(
+                    eth.inner_mut().payload_mut(),
+                    ipv4::PROTOCOL_TCP,
+                    src_addr,
+                    dst_addr,
+                )
+                .ok()
+                .unwrap();
+                // The checksum of the segment is left to the device.
+                let segment_len = tcp::TcpSegment::write_segment(
+                    ip.inner_mut().payload_mut(),
+                    1234,
+                    80,
+                    1,
+                    1,
+                    tcp::Flags::ACK,
+                    10000,
+                    None,
+                    payload.len() as u16,
+                    Some((&payload[..], payload.len())),
+                    None,
+                )
+                .unwrap()
+                .len();
+                ip_len = ip.with_payload_len_unchecked(segment_len, true).len();
+            }
+            frame_len = vnet_hdr_len() + eth.with_payload_len_unchecked(ip_len).len();
+        }
+        h.tx.frame_buf[0] = VIRTIO_NET_HDR_F_NEEDS_CSUM as u8;
+        h.tx.frame_buf[1] = VIRTIO_NET_HDR_GSO_TCPV4 as u8;
+        LittleEndian::write_u16(&mut h.tx.frame_buf[4..6], 1448);
+
+        assert!(NetEpollHandler::write_to_mmds_or_tap(
+            h.mmds_ns.as_ref(),
+            &mut h.tx.rate_limiter.lock().unwrap(),
+            &h.tx.frame_buf[..frame_len],
+            &mut h.tap,
+        ));
+
+        // The segment does not belong to a connection, so MMDS answers with a reset, which has
+        // a complete checksum.
+        let len = h.read_from_mmds_or_tap().unwrap();
+        assert_eq!(&h.rx.frame_buf[..vnet_hdr_len()], &[0u8; 12][..]);
+        let eth = ethernet::EthernetFrame::from_bytes(frame_bytes_from_buf(&h.rx.frame_buf[..len]))
+            .ok()
+            .unwrap();
+        assert_eq!(eth.dst_mac(), src_mac);
+        let ip = ipv4::IPv4Packet::from_bytes(eth.payload(), true)
+            .ok()
+            .unwrap();
+        assert_eq!(ip.destination_address(), src_addr);
+        let segment = tcp::TcpSegment::from_bytes(
+            ip.payload(),
+            Some((ip.source_address(), ip.destination_address())),
+        )
+        .unwrap();
+        assert_eq!(segment.destination_port(), 1234);
+        assert!(segment.flags_after_ns().contains(tcp::Flags::RST));
+    }
+
+    // Writes an ARP request for the MMDS address from `mac` to `frame_buf`, past its VNET header,
+    // and returns the length of the frame.
+    fn write_mmds_arp_request(frame_buf: &mut [u8], mac: &str) -> usize {
+        let eth_frame_i = ethernet::EthernetFrame::write_incomplete(
+            frame_bytes_from_buf_mut(frame_buf),
+            MacAddr::parse_str("ff:ff:ff:ff:ff:ff").unwrap(),
+            MacAddr::parse_str(mac).unwrap(),
+            ethernet::ETHERTYPE_ARP,
+        )
+        .ok()
+        .unwrap();
+        let mut eth_frame_complete =
+            eth_frame_i.with_payload_len_unchecked(arp::ETH_IPV4_FRAME_LEN);
+        arp::EthIPv4ArpFrame::write_request(
+            eth_frame_complete.payload_mut(),
+            MacAddr::parse_str(mac).unwrap(),
+            Ipv4Addr::new(10, 1, 2, 3),
+            MacAddr::parse_str("00:00:00:00:00:00").unwrap(),
+            Ipv4Addr::new(169, 254, 169, 254),
+        )
+        .ok()
+        .unwrap();
+        eth_frame_complete.payload_offset() + arp::ETH_IPV4_FRAME_LEN
+    }
+
+    // Makes `buf` the `index`-th frame sent by the guest and has the handler process it.
+    fn send_tx_frame(
+        h: &mut NetEpollHandler,
+        txq: &VirtQueue,
+        mem: &GuestMemory,
+        buf: &[u8],
+        index: u16,
+    ) {
+        let daddr = 0x2000;
+        mem.write_slice_at_addr(buf, GuestAddress(daddr)).unwrap();
+        txq.avail.idx.set(index + 1);
+        txq.avail.ring[index as usize].set(index);
+        txq.dtable[index as usize].set(daddr as u64, buf.len() as u32, 0, 0);
+        h.tx.queue_evt.write(1).unwrap();
+        h.handle_event(TX_QUEUE_EVENT, 0, EpollHandlerPayload::Empty);
+    }
+
+    #[test]
+    fn test_capture() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("capture.pcap");
+        let capture = PcapWriter::new(&CaptureConfig {
+            path: path.clone(),
+            snap_len: None,
+            max_file_size: None,
+        })
+        .unwrap();
+        h.handle_event(
+            CAPTURE_UPDATE_EVENT,
+            0,
+            EpollHandlerPayload::NetCapturePayload(Some(capture)),
+        );
+
+        // The guest sends an ARP request for the MMDS address.
+        let mut frame_buf = [0u8; 100];
+        let frame_len = write_mmds_arp_request(&mut frame_buf, "11:11:11:11:11:11");
+        send_tx_frame(
+            &mut h,
+            &txq,
+            &mem,
+            &frame_buf[..vnet_hdr_len() + frame_len],
+            0,
+        );
+        assert_eq!(txq.used.idx.get(), 1);
+
+        // Both the request detoured to MMDS and the reply of MMDS are captured, without their
+        // VNET header.
+        let bytes = fs::read(&path).unwrap();
+        let (file_header, records) = bytes.split_at(24);
+        assert_eq!(LittleEndian::read_u32(&file_header[20..24]), 1);
+        let (request, reply) = records.split_at(16 + frame_len);
+        assert_eq!(LittleEndian::read_u32(&request[8..12]), frame_len as u32);
+        assert_eq!(
+            &request[16..],
+            &frame_buf[vnet_hdr_len()..vnet_hdr_len() + frame_len]
+        );
+        assert_eq!(reply.len(), 16 + frame_len);
+        let arp_reply = ethernet::EthernetFrame::from_bytes(&reply[16..])
+            .ok()
+            .unwrap();
+        assert_eq!(arp_reply.ethertype(), ethernet::ETHERTYPE_ARP);
+
+        // An empty capture stops it.
+        h.handle_event(
+            CAPTURE_UPDATE_EVENT,
+            0,
+            EpollHandlerPayload::NetCapturePayload(None),
+        );
+        assert!(h.capture.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_spoof_filter() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        h.spoof_filter = Some(SpoofFilter::new(
+            MacAddr::parse_str("11:11:11:11:11:11").unwrap(),
+            Some(vec![Ipv4Addr::new(10, 1, 2, 3)]),
+        ));
+        let mut frame_buf = [0u8; 100];
+
+        // A frame from another MAC address is dropped before reaching MMDS, so there is no reply.
+        let mac_drops = METRICS.net.tx_spoofed_mac_drops.count();
+        let frame_len = write_mmds_arp_request(&mut frame_buf, "22:22:22:22:22:22");
+        send_tx_frame(
+            &mut h,
+            &txq,
+            &mem,
+            &frame_buf[..vnet_hdr_len() + frame_len],
+            0,
+        );
+        assert_eq!(txq.used.idx.get(), 1);
+        assert_eq!(METRICS.net.tx_spoofed_mac_drops.count(), mac_drops + 1);
+        assert!(!h.rx.deferred_frame);
+
+        // The frames from the guest go through, and the reply of MMDS waits for a buffer.
+        let frame_len = write_mmds_arp_request(&mut frame_buf, "11:11:11:11:11:11");
+        send_tx_frame(
+            &mut h,
+            &txq,
+            &mem,
+            &frame_buf[..vnet_hdr_len() + frame_len],
+            1,
+        );
+        assert_eq!(txq.used.idx.get(), 2);
+        assert_eq!(METRICS.net.tx_spoofed_mac_drops.count(), mac_drops + 1);
+        assert!(h.rx.deferred_frame);
+    }
+
+    // Returns a rule with the `action` for the ARP frames to or from MMDS.
+    fn mmds_arp_rule(action: FirewallAction) -> FirewallRule {
+        FirewallRule {
+            action,
+            protocol: Some(FirewallProtocol::Arp),
+            cidr: Some("169.254.169.254/32".parse().unwrap()),
+            ports: None,
+        }
+    }
+
+    #[test]
+    fn test_firewall() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        // The tap has no frames, so only the reply of MMDS may be deferred.
+        let test_mutators = TestMutators {
+            tap_read_fail: true,
+        };
+        let (mut h, txq, _rxq) = default_test_netepollhandler(&mem, test_mutators);
+        let mut frame_buf = [0u8; 100];
+        let frame_len = write_mmds_arp_request(&mut frame_buf, "11:11:11:11:11:11");
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("capture.pcap");
+        let capture = PcapWriter::new(&CaptureConfig {
+            path: path.clone(),
+            snap_len: None,
+            max_file_size: None,
+        })
+        .unwrap();
+        h.handle_event(
+            CAPTURE_UPDATE_EVENT,
+            0,
+            EpollHandlerPayload::NetCapturePayload(Some(capture)),
+        );
+        // The pcap file header, and a record header and frame per captured frame.
+        let capture_len = |frames: u64| 24 + frames * (16 + frame_len as u64);
+
+        // The request goes to MMDS, but its reply is dropped before waiting for a buffer.
+        let firewall = Firewall::new(&FirewallConfig {
+            rx_rules: vec![mmds_arp_rule(FirewallAction::Deny)],
+            tx_rules: vec![mmds_arp_rule(FirewallAction::Allow)],
+        });
+        let metrics = firewall.metrics();
+        h.handle_event(
+            FIREWALL_UPDATE_EVENT,
+            0,
+            EpollHandlerPayload::NetFirewallPayload(firewall),
+        );
+        let rx_drops = METRICS.net.rx_firewall_drops.count();
+        send_tx_frame(
+            &mut h,
+            &txq,
+            &mem,
+            &frame_buf[..vnet_hdr_len() + frame_len],
+            0,
+        );
+        assert_eq!(txq.used.idx.get(), 1);
+        assert_eq!(METRICS.net.rx_firewall_drops.count(), rx_drops + 1);
+        assert_eq!(metrics.rx_rule_hits[0].count(), 1);
+        assert_eq!(metrics.tx_rule_hits[0].count(), 1);
+        assert!(!h.rx.deferred_frame);
+        // Only the request is captured.
+        assert_eq!(fs::metadata(&path).unwrap().len(), capture_len(1));
+
+        // The new rules drop the request, so MMDS does not reply.
+        h.handle_event(
+            FIREWALL_UPDATE_EVENT,
+            0,
+            EpollHandlerPayload::NetFirewallPayload(Firewall::new(&FirewallConfig {
+                rx_rules: Vec::new(),
+                tx_rules: vec![mmds_arp_rule(FirewallAction::Deny)],
+            })),
+        );
+        let tx_drops = METRICS.net.tx_firewall_drops.count();
+        send_tx_frame(
+            &mut h,
+            &txq,
+            &mem,
+            &frame_buf[..vnet_hdr_len() + frame_len],
+            1,
+        );
+        assert_eq!(txq.used.idx.get(), 2);
+        assert_eq!(METRICS.net.tx_firewall_drops.count(), tx_drops + 1);
+        assert!(!h.rx.deferred_frame);
+        assert_eq!(fs::metadata(&path).unwrap().len(), capture_len(1));
+
+        // Without rules, the reply waits for a buffer.
+        h.handle_event(
+            FIREWALL_UPDATE_EVENT,
+            0,
+            EpollHandlerPayload::NetFirewallPayload(Firewall::default()),
+        );
+        send_tx_frame(
+            &mut h,
+            &txq,
+            &mem,
+            &frame_buf[..vnet_hdr_len() + frame_len],
+            2,
+        );
+        assert_eq!(txq.used.idx.get(), 3);
+        assert!(h.rx.deferred_frame);
+        // Both the request and the reply are captured.
+        assert_eq!(fs::metadata(&path).unwrap().len(), capture_len(3));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_firewall_update_event_error() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        // This should panic because payload is empty for event type FIREWALL_UPDATE_EVENT.
+        h.handle_event(FIREWALL_UPDATE_EVENT, 0, EpollHandlerPayload::Empty);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_capture_update_event_error() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        // This should panic because payload is empty for event type CAPTURE_UPDATE_EVENT.
+        h.handle_event(CAPTURE_UPDATE_EVENT, 0, EpollHandlerPayload::Empty);
+    }
+
     #[test]
     fn test_handler_error_cases() {
         let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
@@ -1357,6 +2428,199 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_multi_queue() {
+        let epoll_raw_fd = epoll::create(true).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let new_net = |taps: Vec<Tap>, num_epoll_configs: usize| {
+            let epoll_configs = (0..num_epoll_configs)
+                .map(|i| {
+                    EpollConfig::new((i * NET_EVENTS_COUNT) as u64, epoll_raw_fd, sender.clone())
+                })
+                .collect();
+            Net::new_with_taps(
+                taps,
+                None,
+                epoll_configs,
+                None,
+                None,
+                false,
+                FrameHooks::default(),
+            )
+        };
+
+        // The number of queue pairs is checked.
+        assert!(match new_net(Vec::new(), 0) {
+            Err(Error::InvalidNumQueuePairs) => true,
+            _ => false,
+        });
+        assert!(
+            match new_net(Tap::open_named_queues("mqnet%d", 2).unwrap(), 1) {
+                Err(Error::InvalidNumQueuePairs) => true,
+                _ => false,
+            }
+        );
+
+        let mut n = new_net(Tap::open_named_queues("mqnet%d", 2).unwrap(), 2).unwrap();
+        // Two queue pairs and the control queue.
+        assert_eq!(n.queue_max_sizes(), [QUEUE_SIZE; 5]);
+        let features = 1 << VIRTIO_NET_F_CTRL_VQ | 1 << VIRTIO_NET_F_MQ;
+        assert_eq!(n.features(0) & features, features);
+        // Without a MAC address, the config space starts with zeros.
+        let mut config = [0xffu8; CONFIG_SPACE_SIZE];
+        n.read_config(0, &mut config);
+        assert_eq!(config, [0, 0, 0, 0, 0, 0, 0, 0, 2, 0]);
+
+        // A restored device uses the queue pairs the driver had set.
+        assert_eq!(n.active_queue_pairs(), Some(1));
+        assert!(n.set_active_queue_pairs(0).is_err());
+        assert!(n.set_active_queue_pairs(3).is_err());
+        n.set_active_queue_pairs(2).unwrap();
+        assert_eq!(n.active_queue_pairs(), Some(2));
+
+        // Each queue pair gets its own handler.
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vqs: Vec<_> = (0..5)
+            .map(|i| VirtQueue::new(GuestAddress(i * 0x1000), &mem, 16))
+            .collect();
+        let queues = vqs.iter().map(VirtQueue::create_queue).collect();
+        let queue_evts = (0..5).map(|_| EventFd::new().unwrap()).collect();
+        let status = Arc::new(AtomicUsize::new(0));
+        n.activate(
+            mem.clone(),
+            EventFd::new().unwrap(),
+            status,
+            queues,
+            queue_evts,
+        )
+        .unwrap();
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(n.active_queue_pairs(), Some(2));
+        unsafe { libc::close(epoll_raw_fd) };
+
+        // The control queue sets the number of queue pairs in use.
+        let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        let ctrlq = VirtQueue::new(GuestAddress(0x3000), &mem, 16);
+        let taps = Tap::open_named_queues("mqctl%d", 2).unwrap();
+        taps[1].set_queue_attached(false).unwrap();
+        let active_pairs = Arc::new(AtomicUsize::new(1));
+        h.ctrl = Some(CtrlVirtio::new(
+            ctrlq.create_queue(),
+            EventFd::new().unwrap(),
+            taps,
+            active_pairs.clone(),
+        ));
+
+        let cmd_addr = GuestAddress(0x4000);
+        let ack_addr = GuestAddress(0x4100);
+        let mut send_command = |h: &mut NetEpollHandler, command: [u8; CTRL_CMD_LEN]| {
+            mem.write_slice_at_addr(&command, cmd_addr).unwrap();
+            mem.write_obj_at_addr(0xffu8, ack_addr).unwrap();
+            let idx = ctrlq.avail.idx.get();
+            let desc_idx = (idx % 8) * 2;
+            ctrlq.dtable[desc_idx as usize].set(
+                cmd_addr.0 as u64,
+                CTRL_CMD_LEN as u32,
+                VIRTQ_DESC_F_NEXT,
+                desc_idx + 1,
+            );
+            ctrlq.dtable[desc_idx as usize + 1].set(ack_addr.0 as u64, 1, VIRTQ_DESC_F_WRITE, 0);
+            ctrlq.avail.ring[idx as usize % 16].set(desc_idx);
+            ctrlq.avail.idx.set(idx + 1);
+
+            h.ctrl.as_ref().unwrap().queue_evt.write(1).unwrap();
+            h.handle_event(CTRL_QUEUE_EVENT, 0, EpollHandlerPayload::Empty);
+            assert_eq!(ctrlq.used.idx.get(), idx + 1);
+            assert_eq!(ctrlq.used.ring[idx as usize % 16].get().len, 1);
+            u32::from(mem.read_obj_from_addr::<u8>(ack_addr).unwrap())
+        };
+        let mq = VIRTIO_NET_CTRL_MQ as u8;
+        let pairs_set = VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8;
+
+        assert_eq!(send_command(&mut h, [mq, pairs_set, 2, 0]), VIRTIO_NET_OK);
+        assert_eq!(active_pairs.load(Ordering::SeqCst), 2);
+
+        // Invalid numbers of queue pairs and unsupported commands are refused.
+        check_metric_after_block!(&METRICS.net.ctrl_fails, 3, {
+            assert_eq!(send_command(&mut h, [mq, pairs_set, 3, 0]), VIRTIO_NET_ERR);
+            assert_eq!(send_command(&mut h, [mq, pairs_set, 0, 0]), VIRTIO_NET_ERR);
+            assert_eq!(send_command(&mut h, [0, 0, 0, 0]), VIRTIO_NET_ERR);
+        });
+        assert_eq!(active_pairs.load(Ordering::SeqCst), 2);
+
+        assert_eq!(send_command(&mut h, [mq, pairs_set, 1, 0]), VIRTIO_NET_OK);
+        assert_eq!(active_pairs.load(Ordering::SeqCst), 1);
+        // The second queue is detached again, so it can be attached.
+        assert!(h.ctrl.as_ref().unwrap().queue_pairs.taps[1]
+            .set_queue_attached(true)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_update_rate_limiters() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        h.epoll_raw_fd = epoll::create(true).unwrap();
+
+        let rx_update = RateLimiterUpdate {
+            bandwidth: Some(TokenBucket::new(1000, None, 100)),
+            ops: None,
+        };
+        let tx_update = RateLimiterUpdate {
+            bandwidth: None,
+            ops: Some(TokenBucket::new(1, None, 100)),
+        };
+        h.update_rate_limiters(EpollHandlerPayload::NetRateLimiterPayload(
+            rx_update, tx_update,
+        ))
+        .unwrap();
+        let mut rx_rate_limiter = h.get_rx_rate_limiter();
+        assert!(rx_rate_limiter.consume(1000, TokenType::Bytes));
+        assert!(!rx_rate_limiter.consume(1, TokenType::Bytes));
+        assert!(rx_rate_limiter.consume(u64::max_value(), TokenType::Ops));
+        let mut tx_rate_limiter = h.get_tx_rate_limiter();
+        assert!(tx_rate_limiter.consume(1, TokenType::Ops));
+        assert!(!tx_rate_limiter.consume(1, TokenType::Ops));
+        assert!(tx_rate_limiter.consume(u64::max_value(), TokenType::Bytes));
+
+        // The timers created by the update are polled.
+        for rawfd in &[rx_rate_limiter.as_raw_fd(), tx_rate_limiter.as_raw_fd()] {
+            assert!(epoll::ctl(
+                h.epoll_raw_fd,
+                epoll::EPOLL_CTL_ADD,
+                *rawfd,
+                epoll::Event::new(epoll::EPOLLIN, 0),
+            )
+            .is_err());
+        }
+        unsafe { libc::close(h.epoll_raw_fd) };
+
+        // The update fails when the timer of a new limiter cannot be polled.
+        let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        h.epoll_raw_fd = -1;
+        let rx_update = RateLimiterUpdate {
+            bandwidth: Some(TokenBucket::new(1000, None, 100)),
+            ops: None,
+        };
+        assert!(h
+            .update_rate_limiters(EpollHandlerPayload::NetRateLimiterPayload(
+                rx_update,
+                RateLimiterUpdate::default(),
+            ))
+            .is_err());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_update_rate_limiters_payload_error() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _txq, _rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        // This should panic because the payload is not a rate limiter update.
+        let _ = h.update_rate_limiters(EpollHandlerPayload::Empty);
+    }
+
     #[test]
     #[should_panic]
     fn test_invalid_event_handler() {
@@ -1513,6 +2777,73 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_mergeable_rx_buffers() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (mut h, _txq, rxq) = default_test_netepollhandler(&mem, TestMutators::default());
+        h.acked_features |= 1 << VIRTIO_NET_F_MRG_RXBUF;
+
+        let daddr = 0x2000;
+        for i in 0..3 {
+            rxq.dtable[i].set(daddr + i as u64 * 0x200, 0x200, VIRTQ_DESC_F_WRITE, 0);
+            rxq.avail.ring[i].set(i as u16);
+        }
+        h.rx.bytes_read = h.read_tap().unwrap();
+
+        // Two buffers cannot hold the frame, so it is deferred.
+        rxq.avail.idx.set(2);
+        assert!(!h.rx_single_frame_no_irq_coalescing());
+        assert_eq!(rxq.used.idx.get(), 0);
+
+        // The frame spans all three buffers.
+        rxq.avail.idx.set(3);
+        assert!(h.rx_single_frame_no_irq_coalescing());
+        assert_eq!(rxq.used.idx.get(), 3);
+        for i in 0..3 {
+            assert_eq!(rxq.used.ring[i].get().id, i as u32);
+        }
+        assert_eq!(rxq.used.ring[0].get().len, 0x200);
+        assert_eq!(rxq.used.ring[1].get().len, 0x200);
+        assert_eq!(rxq.used.ring[2].get().len, 1234 - 0x400);
+        let num_buffers_addr = GuestAddress(daddr as usize + VNET_HDR_NUM_BUFFERS_OFFSET);
+        assert_eq!(mem.read_obj_from_addr::<u16>(num_buffers_addr).unwrap(), 3);
+        let last_byte_addr = GuestAddress(daddr as usize + 1233);
+        assert_eq!(mem.read_obj_from_addr::<u8>(last_byte_addr).unwrap(), 5);
+
+        // The frame is dropped when even a full queue of buffers cannot hold it.
+        for i in 0..16 {
+            rxq.dtable[i].set(daddr + i as u64 * 0x10, 0x10, VIRTQ_DESC_F_WRITE, 0);
+            rxq.avail.ring[i].set(i as u16);
+        }
+        rxq.avail.idx.set(3 + 16);
+        assert!(h.rx_single_frame_no_irq_coalescing());
+        assert_eq!(rxq.used.idx.get(), 3);
+
+        // A chain starting with a read-only descriptor is handed back empty and is not part of
+        // the frame.
+        rxq.dtable[0].set(daddr, 0x200, 0, 0);
+        for i in 1..4 {
+            rxq.dtable[i].set(daddr + i as u64 * 0x200, 0x200, VIRTQ_DESC_F_WRITE, 0);
+        }
+        for i in 0..4 {
+            rxq.avail.ring[(3 + i) % 16].set(i as u16);
+        }
+        rxq.avail.idx.set(3 + 4);
+        check_metric_after_block!(
+            &METRICS.net.rx_fails,
+            1,
+            assert!(h.rx_single_frame_no_irq_coalescing())
+        );
+        assert_eq!(rxq.used.idx.get(), 3 + 4);
+        assert_eq!(rxq.used.ring[3].get().id, 0);
+        assert_eq!(rxq.used.ring[3].get().len, 0);
+        for i in 1..4 {
+            assert_eq!(rxq.used.ring[3 + i].get().id, i as u32);
+        }
+        let num_buffers_addr = GuestAddress(daddr as usize + 0x200 + VNET_HDR_NUM_BUFFERS_OFFSET);
+        assert_eq!(mem.read_obj_from_addr::<u16>(num_buffers_addr).unwrap(), 3);
+    }
+
     #[test]
     fn test_bandwidth_rate_limiter() {
         let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
@@ -0,0 +1,437 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A copy-on-write overlay on top of a read-only base image.
+//!
+//! The overlay file starts with a header, followed by a bitmap with one bit per cluster of the
+//! disk and by the data area, where cluster `n` of the disk lives at `data_offset + n *
+//! cluster_size`. The data area is sparse: only the clusters written by the guest take up space.
+//! A cluster whose bit is set is read from the overlay, any other one from the base image, which
+//! is never written to.
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::block::zero_file_range;
+use sys_util::{fallocate, FallocateMode};
+
+const OVERLAY_MAGIC: &[u8; 8] = b"FCOVERLY";
+const OVERLAY_VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 4096;
+const CLUSTER_SIZE: u64 = 4096;
+
+// Offsets of the header fields.
+const MAGIC_OFFSET: usize = 0;
+const VERSION_OFFSET: usize = 8;
+const CLUSTER_SIZE_OFFSET: usize = 12;
+const DISK_SIZE_OFFSET: usize = 16;
+const BITMAP_OFFSET_OFFSET: usize = 24;
+const DATA_OFFSET_OFFSET: usize = 32;
+const HEADER_FIELDS_SIZE: usize = 40;
+
+fn invalid_overlay(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// A disk image made of a read-only base image and of an overlay file holding every cluster
+/// written by the guest.
+pub struct Overlay {
+    base: File,
+    overlay: File,
+    disk_size: u64,
+    bitmap_offset: u64,
+    data_offset: u64,
+    // One bit per cluster, set when the cluster lives in the overlay.
+    bitmap: Vec<u8>,
+    position: u64,
+}
+
+impl Overlay {
+    /// Puts `overlay` on top of `base`. An empty overlay file is formatted for the size of the
+    /// base image, otherwise it has to have been created on top of an image of the same size.
+    pub fn new(base: File, overlay: File) -> io::Result<Overlay> {
+        let disk_size = base.metadata()?.len();
+        let num_clusters = (disk_size + CLUSTER_SIZE - 1) / CLUSTER_SIZE;
+        let bitmap_size = (num_clusters + 7) / 8;
+
+        if overlay.metadata()?.len() == 0 {
+            let bitmap_offset = HEADER_SIZE;
+            let data_offset =
+                (bitmap_offset + bitmap_size + CLUSTER_SIZE - 1) & !(CLUSTER_SIZE - 1);
+            let mut header = [0u8; HEADER_FIELDS_SIZE];
+            header[MAGIC_OFFSET..VERSION_OFFSET].copy_from_slice(OVERLAY_MAGIC);
+            LittleEndian::write_u32(&mut header[VERSION_OFFSET..], OVERLAY_VERSION);
+            LittleEndian::write_u32(&mut header[CLUSTER_SIZE_OFFSET..], CLUSTER_SIZE as u32);
+            LittleEndian::write_u64(&mut header[DISK_SIZE_OFFSET..], disk_size);
+            LittleEndian::write_u64(&mut header[BITMAP_OFFSET_OFFSET..], bitmap_offset);
+            LittleEndian::write_u64(&mut header[DATA_OFFSET_OFFSET..], data_offset);
+            // The bitmap and the data area start out as holes, which read as zeroes.
+            overlay.set_len(data_offset + disk_size)?;
+            overlay.write_all_at(&header, 0)?;
+
+            return Ok(Overlay {
+                base,
+                overlay,
+                disk_size,
+                bitmap_offset,
+                data_offset,
+                bitmap: vec![0u8; bitmap_size as usize],
+                position: 0,
+            });
+        }
+
+        let mut header = [0u8; HEADER_FIELDS_SIZE];
+        overlay.read_exact_at(&mut header, 0)?;
+        if &header[MAGIC_OFFSET..VERSION_OFFSET] != OVERLAY_MAGIC {
+            return Err(invalid_overlay("not an overlay file"));
+        }
+        if LittleEndian::read_u32(&header[VERSION_OFFSET..]) != OVERLAY_VERSION
+            || u64::from(LittleEndian::read_u32(&header[CLUSTER_SIZE_OFFSET..])) != CLUSTER_SIZE
+        {
+            return Err(invalid_overlay("unsupported overlay version"));
+        }
+        if LittleEndian::read_u64(&header[DISK_SIZE_OFFSET..]) != disk_size {
+            return Err(invalid_overlay(
+                "the overlay was created on top of an image of a different size",
+            ));
+        }
+        let bitmap_offset = LittleEndian::read_u64(&header[BITMAP_OFFSET_OFFSET..]);
+        let data_offset = LittleEndian::read_u64(&header[DATA_OFFSET_OFFSET..]);
+        if bitmap_offset < HEADER_SIZE
+            || bitmap_offset + bitmap_size > data_offset
+            || data_offset % CLUSTER_SIZE != 0
+            || overlay.metadata()?.len() < data_offset + disk_size
+        {
+            return Err(invalid_overlay("corrupted overlay header"));
+        }
+
+        let mut bitmap = vec![0u8; bitmap_size as usize];
+        overlay.read_exact_at(&mut bitmap, bitmap_offset)?;
+        Ok(Overlay {
+            base,
+            overlay,
+            disk_size,
+            bitmap_offset,
+            data_offset,
+            bitmap,
+            position: 0,
+        })
+    }
+
+    /// Returns the overlay file, which is what tells this disk apart from the other disks
+    /// sharing the same base image.
+    pub fn overlay_file(&self) -> &File {
+        &self.overlay
+    }
+
+    fn is_allocated(&self, cluster: u64) -> bool {
+        self.bitmap[(cluster / 8) as usize] & (1 << (cluster % 8)) != 0
+    }
+
+    // Marks `count` clusters starting with `first` as living in the overlay, and persists the
+    // change.
+    fn mark_allocated(&mut self, first: u64, count: u64) -> io::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        for cluster in first..first + count {
+            self.bitmap[(cluster / 8) as usize] |= 1 << (cluster % 8);
+        }
+        let start = (first / 8) as usize;
+        let end = ((first + count - 1) / 8) as usize + 1;
+        self.overlay
+            .write_all_at(&self.bitmap[start..end], self.bitmap_offset + start as u64)
+    }
+
+    fn cluster_len(&self, cluster: u64) -> u64 {
+        cmp::min(CLUSTER_SIZE, self.disk_size - cluster * CLUSTER_SIZE)
+    }
+
+    fn cluster_offset(&self, cluster: u64) -> u64 {
+        self.data_offset + cluster * CLUSTER_SIZE
+    }
+
+    // Copies a cluster from the base image to the overlay, so that it can be partially written.
+    fn copy_up(&mut self, cluster: u64) -> io::Result<()> {
+        let mut data = vec![0u8; self.cluster_len(cluster) as usize];
+        self.base.read_exact_at(&mut data, cluster * CLUSTER_SIZE)?;
+        self.overlay
+            .write_all_at(&data, self.cluster_offset(cluster))?;
+        self.mark_allocated(cluster, 1)
+    }
+
+    // Writes `data` at `offset`, which has to fall in a single cluster.
+    fn write_in_cluster(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let cluster = offset / CLUSTER_SIZE;
+        let whole_cluster =
+            offset % CLUSTER_SIZE == 0 && data.len() as u64 == self.cluster_len(cluster);
+        if !self.is_allocated(cluster) && !whole_cluster {
+            self.copy_up(cluster)?;
+        }
+        let overlay_offset = self.cluster_offset(cluster) + offset % CLUSTER_SIZE;
+        self.overlay.write_all_at(data, overlay_offset)?;
+        if !self.is_allocated(cluster) {
+            self.mark_allocated(cluster, 1)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the range read back as zeroes. With `punch_hole` set, the whole clusters in the
+    /// range are deallocated from the overlay. The base image is left untouched either way.
+    pub fn zero_range(&mut self, offset: u64, len: u64, punch_hole: bool) -> io::Result<()> {
+        let end = offset + len;
+        if end > self.disk_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range past the end of the disk",
+            ));
+        }
+
+        let first_whole = (offset + CLUSTER_SIZE - 1) / CLUSTER_SIZE;
+        let end_whole = if end == self.disk_size {
+            (end + CLUSTER_SIZE - 1) / CLUSTER_SIZE
+        } else {
+            end / CLUSTER_SIZE
+        };
+        if first_whole >= end_whole {
+            // The range does not cover any cluster completely.
+            return self.write_zeroes_in_clusters(offset, end);
+        }
+
+        self.write_zeroes_in_clusters(offset, first_whole * CLUSTER_SIZE)?;
+        let whole_start = self.cluster_offset(first_whole);
+        let whole_len =
+            cmp::min(end_whole * CLUSTER_SIZE, self.disk_size) - first_whole * CLUSTER_SIZE;
+        if punch_hole {
+            fallocate(
+                &self.overlay,
+                FallocateMode::PunchHole,
+                whole_start,
+                whole_len,
+            )
+            .map_err(|e| io::Error::from_raw_os_error(e.errno()))?;
+        } else {
+            zero_file_range(&mut self.overlay, whole_start, whole_len)?;
+        }
+        self.mark_allocated(first_whole, end_whole - first_whole)?;
+        self.write_zeroes_in_clusters(cmp::min(end_whole * CLUSTER_SIZE, end), end)
+    }
+
+    fn write_zeroes_in_clusters(&mut self, start: u64, end: u64) -> io::Result<()> {
+        let mut offset = start;
+        while offset < end {
+            let count = cmp::min(end - offset, CLUSTER_SIZE - offset % CLUSTER_SIZE);
+            self.write_in_cluster(offset, &vec![0u8; count as usize])?;
+            offset += count;
+        }
+        Ok(())
+    }
+}
+
+impl Read for Overlay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.disk_size {
+            return Ok(0);
+        }
+        let cluster = self.position / CLUSTER_SIZE;
+        let in_cluster = self.position % CLUSTER_SIZE;
+        let count = cmp::min(
+            buf.len() as u64,
+            cmp::min(CLUSTER_SIZE - in_cluster, self.disk_size - self.position),
+        ) as usize;
+        let read = if self.is_allocated(cluster) {
+            self.overlay
+                .read_at(&mut buf[..count], self.cluster_offset(cluster) + in_cluster)?
+        } else {
+            self.base.read_at(&mut buf[..count], self.position)?
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for Overlay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The disk cannot grow.
+        if self.position >= self.disk_size {
+            return Ok(0);
+        }
+        let in_cluster = self.position % CLUSTER_SIZE;
+        let count = cmp::min(
+            buf.len() as u64,
+            cmp::min(CLUSTER_SIZE - in_cluster, self.disk_size - self.position),
+        ) as usize;
+        let position = self.position;
+        self.write_in_cluster(position, &buf[..count])?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.overlay.flush()
+    }
+}
+
+impl Seek for Overlay {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_offset(self.disk_size, offset),
+            SeekFrom::Current(offset) => add_offset(self.position, offset),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn add_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::tempfile;
+    use super::*;
+
+    fn new_base(size: usize) -> File {
+        let mut base = tempfile().unwrap();
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8 + 1).collect();
+        base.write_all(&data).unwrap();
+        base
+    }
+
+    fn read_disk(disk: &mut Overlay, offset: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.read_exact(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        let base = new_base(3 * CLUSTER_SIZE as usize + 512);
+        let mut base_copy = base.try_clone().unwrap();
+        let mut expected = Vec::new();
+        base_copy.seek(SeekFrom::Start(0)).unwrap();
+        base_copy.read_to_end(&mut expected).unwrap();
+        let original = expected.clone();
+
+        let mut disk = Overlay::new(base, tempfile().unwrap()).unwrap();
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), expected.len() as u64);
+        assert_eq!(read_disk(&mut disk, 0, expected.len()), expected);
+
+        // A partial write in the first cluster, a write across the second and third ones and a
+        // write in the last, partial, cluster.
+        for &(offset, len) in [
+            (100, 10),
+            (CLUSTER_SIZE + 10, CLUSTER_SIZE as usize),
+            (3 * CLUSTER_SIZE, 512),
+        ]
+        .iter()
+        {
+            disk.seek(SeekFrom::Start(offset)).unwrap();
+            disk.write_all(&vec![0xaa; len]).unwrap();
+            for byte in expected[offset as usize..offset as usize + len].iter_mut() {
+                *byte = 0xaa;
+            }
+        }
+        assert_eq!(read_disk(&mut disk, 0, expected.len()), expected);
+        assert!(disk.is_allocated(0) && disk.is_allocated(1) && disk.is_allocated(3));
+
+        // The disk cannot grow.
+        disk.seek(SeekFrom::End(0)).unwrap();
+        assert!(disk.write_all(&[0xaa]).is_err());
+
+        // Writes never reach the base image.
+        let mut base_data = Vec::new();
+        base_copy.seek(SeekFrom::Start(0)).unwrap();
+        base_copy.read_to_end(&mut base_data).unwrap();
+        assert_eq!(base_data, original);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let base = new_base(4 * CLUSTER_SIZE as usize);
+        let overlay = tempfile().unwrap();
+
+        {
+            let mut disk =
+                Overlay::new(base.try_clone().unwrap(), overlay.try_clone().unwrap()).unwrap();
+            disk.seek(SeekFrom::Start(2 * CLUSTER_SIZE + 1)).unwrap();
+            disk.write_all(&[0xaa; 2]).unwrap();
+        }
+
+        let mut disk =
+            Overlay::new(base.try_clone().unwrap(), overlay.try_clone().unwrap()).unwrap();
+        assert!(disk.is_allocated(2));
+        assert!(!disk.is_allocated(1));
+        let first = (2 * CLUSTER_SIZE % 251) as u8 + 1;
+        assert_eq!(
+            read_disk(&mut disk, 2 * CLUSTER_SIZE, 4),
+            vec![first, 0xaa, 0xaa, first + 3]
+        );
+
+        // The overlay only fits images of the size it was created for.
+        base.set_len(5 * CLUSTER_SIZE).unwrap();
+        assert_eq!(
+            Overlay::new(base, overlay).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let mut not_overlay = tempfile().unwrap();
+        not_overlay.write_all(&[0u8; 64]).unwrap();
+        assert_eq!(
+            Overlay::new(new_base(512), not_overlay)
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_zero_range() {
+        let size = 4 * CLUSTER_SIZE as usize;
+        let mut disk = Overlay::new(new_base(size), tempfile().unwrap()).unwrap();
+
+        for &punch_hole in [false, true].iter() {
+            disk.zero_range(100, 2 * CLUSTER_SIZE, punch_hole).unwrap();
+            let data = read_disk(&mut disk, 0, size);
+            assert_eq!(data[99], 100);
+            assert!(data[100..100 + 2 * CLUSTER_SIZE as usize]
+                .iter()
+                .all(|b| *b == 0));
+            assert_ne!(data[100 + 2 * CLUSTER_SIZE as usize], 0);
+        }
+        assert!(disk
+            .zero_range(CLUSTER_SIZE, 4 * CLUSTER_SIZE, true)
+            .is_err());
+
+        // Zeroing up to the end of the disk.
+        disk.zero_range(3 * CLUSTER_SIZE, CLUSTER_SIZE, true)
+            .unwrap();
+        assert!(
+            read_disk(&mut disk, 3 * CLUSTER_SIZE, CLUSTER_SIZE as usize)
+                .iter()
+                .all(|b| *b == 0)
+        );
+    }
+}
@@ -0,0 +1,220 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Writes the frames of a network device to a file in the pcap format, as read by tcpdump and
+//! wireshark.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+use libc;
+
+// The timestamps of the records are in microseconds.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_VERSION_MAJOR: u16 = 2;
+const PCAP_VERSION_MINOR: u16 = 4;
+const LINKTYPE_ETHERNET: u32 = 1;
+const FILE_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+/// The number of bytes kept of each frame when the capture has no snap length.
+pub const DEFAULT_SNAP_LEN: u32 = 262_144;
+
+/// The configuration of a capture of the frames of a network device.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConfig {
+    /// Path of the pcap file, truncated if it exists.
+    pub path: PathBuf,
+    /// The number of bytes kept of each frame, `DEFAULT_SNAP_LEN` when missing.
+    pub snap_len: Option<u32>,
+    /// When the file would grow past this size, it is moved to `<path>.1`, replacing the file
+    /// moved there before, and the capture goes on in a new file at `path`.
+    pub max_file_size: Option<u64>,
+}
+
+/// Writes frames to a pcap file, one record per frame.
+pub struct PcapWriter {
+    file: File,
+    path: PathBuf,
+    snap_len: u32,
+    max_file_size: Option<u64>,
+    file_size: u64,
+    record_buf: Vec<u8>,
+}
+
+impl PcapWriter {
+    /// Creates the capture file described by `config` and writes its header.
+    pub fn new(config: &CaptureConfig) -> io::Result<PcapWriter> {
+        let snap_len = config.snap_len.unwrap_or(DEFAULT_SNAP_LEN);
+        if snap_len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The snap length must be positive.",
+            ));
+        }
+        let mut writer = PcapWriter {
+            file: Self::create_file(&config.path, snap_len)?,
+            path: config.path.clone(),
+            snap_len,
+            max_file_size: config.max_file_size,
+            file_size: FILE_HEADER_LEN as u64,
+            record_buf: Vec::new(),
+        };
+        writer
+            .record_buf
+            .reserve(RECORD_HEADER_LEN + snap_len as usize);
+        Ok(writer)
+    }
+
+    /// Appends `frame`, an ethernet frame without its VNET header, to the capture, truncated to
+    /// the snap length and timestamped with the current time.
+    pub fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        let captured_len = cmp::min(frame.len(), self.snap_len as usize);
+        let record_len = (RECORD_HEADER_LEN + captured_len) as u64;
+        if let Some(max_file_size) = self.max_file_size {
+            // A file always holds at least one record, however large the frame.
+            if self.file_size > FILE_HEADER_LEN as u64
+                && self.file_size + record_len > max_file_size
+            {
+                self.rotate()?;
+            }
+        }
+
+        // The clock of the host is not expected to be set before 1970.
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        LittleEndian::write_u32(&mut header[0..4], timestamp.as_secs() as u32);
+        LittleEndian::write_u32(&mut header[4..8], timestamp.subsec_micros());
+        LittleEndian::write_u32(&mut header[8..12], captured_len as u32);
+        LittleEndian::write_u32(&mut header[12..16], frame.len() as u32);
+
+        // The record is written at once, so a reader never sees half of it.
+        self.record_buf.clear();
+        self.record_buf.extend_from_slice(&header);
+        self.record_buf.extend_from_slice(&frame[..captured_len]);
+        self.file.write_all(&self.record_buf)?;
+        self.file_size += record_len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let mut rotated_path = self.path.clone().into_os_string();
+        rotated_path.push(".1");
+        fs::rename(&self.path, &rotated_path)?;
+        self.file = Self::create_file(&self.path, self.snap_len)?;
+        self.file_size = FILE_HEADER_LEN as u64;
+        Ok(())
+    }
+
+    fn create_file(path: &PathBuf, snap_len: u32) -> io::Result<File> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .custom_flags(libc::O_CLOEXEC)
+            .open(path)?;
+
+        let mut header = [0u8; FILE_HEADER_LEN];
+        LittleEndian::write_u32(&mut header[0..4], PCAP_MAGIC);
+        LittleEndian::write_u16(&mut header[4..6], PCAP_VERSION_MAJOR);
+        LittleEndian::write_u16(&mut header[6..8], PCAP_VERSION_MINOR);
+        // The timestamps are in UTC and the time zone offset and accuracy fields are left to 0.
+        LittleEndian::write_u32(&mut header[16..20], snap_len);
+        LittleEndian::write_u32(&mut header[20..24], LINKTYPE_ETHERNET);
+        file.write_all(&header)?;
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+
+    use self::tempfile::tempdir;
+
+    fn read_records(path: &PathBuf) -> Vec<(u32, u32, Vec<u8>)> {
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(LittleEndian::read_u32(&bytes[0..4]), PCAP_MAGIC);
+        assert_eq!(LittleEndian::read_u32(&bytes[20..24]), LINKTYPE_ETHERNET);
+
+        let mut records = Vec::new();
+        let mut offset = FILE_HEADER_LEN;
+        while offset < bytes.len() {
+            let header = &bytes[offset..offset + RECORD_HEADER_LEN];
+            let captured_len = LittleEndian::read_u32(&header[8..12]);
+            let frame_len = LittleEndian::read_u32(&header[12..16]);
+            offset += RECORD_HEADER_LEN;
+            records.push((
+                captured_len,
+                frame_len,
+                bytes[offset..offset + captured_len as usize].to_vec(),
+            ));
+            offset += captured_len as usize;
+        }
+        records
+    }
+
+    #[test]
+    fn test_write_frame() {
+        let dir = tempdir().unwrap();
+        let mut config = CaptureConfig {
+            path: dir.path().join("capture.pcap"),
+            snap_len: Some(0),
+            max_file_size: None,
+        };
+        assert_eq!(
+            PcapWriter::new(&config).err().unwrap().kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        config.snap_len = Some(4);
+        let mut writer = PcapWriter::new(&config).unwrap();
+        let bytes = fs::read(&config.path).unwrap();
+        assert_eq!(bytes.len(), FILE_HEADER_LEN);
+        assert_eq!(LittleEndian::read_u32(&bytes[16..20]), 4);
+
+        writer.write_frame(&[1, 2, 3]).unwrap();
+        writer.write_frame(&[1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(
+            read_records(&config.path),
+            vec![(3, 3, vec![1, 2, 3]), (4, 6, vec![1, 2, 3, 4])]
+        );
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = tempdir().unwrap();
+        let config = CaptureConfig {
+            path: dir.path().join("capture.pcap"),
+            snap_len: None,
+            // Room for the header and two records of 4 bytes.
+            max_file_size: Some((FILE_HEADER_LEN + 2 * (RECORD_HEADER_LEN + 4)) as u64),
+        };
+        let rotated_path = dir.path().join("capture.pcap.1");
+        let mut writer = PcapWriter::new(&config).unwrap();
+
+        writer.write_frame(&[1; 4]).unwrap();
+        writer.write_frame(&[2; 4]).unwrap();
+        assert!(!rotated_path.exists());
+        writer.write_frame(&[3; 4]).unwrap();
+        assert_eq!(read_records(&rotated_path).len(), 2);
+        assert_eq!(read_records(&config.path), vec![(4, 4, vec![3; 4])]);
+
+        // A frame larger than the file size cap still gets a file of its own.
+        writer.write_frame(&[4; 100]).unwrap();
+        assert_eq!(read_records(&rotated_path), vec![(4, 4, vec![3; 4])]);
+        writer.write_frame(&[5; 4]).unwrap();
+        assert_eq!(read_records(&rotated_path), vec![(100, 100, vec![4; 100])]);
+        assert_eq!(read_records(&config.path), vec![(4, 4, vec![5; 4])]);
+    }
+}
@@ -0,0 +1,775 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A disk backend for images in the qcow2 format, versions 2 and 3.
+//!
+//! Guest clusters are mapped to host clusters through two levels of tables: the L1 table, kept in
+//! memory, points to L2 tables, which point to the data clusters. A cluster which is not mapped
+//! is read from the backing file, if there is one, or as zeroes. New clusters are appended to the
+//! image and their reference counts are updated before they are linked into the tables.
+//! Compressed clusters, encryption, internal snapshots and external data files are not supported.
+
+use std::cmp;
+use std::ffi::OsStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use sys_util::{fallocate, FallocateMode};
+
+const QCOW_MAGIC: u32 = 0x5146_49fb;
+const V2_HEADER_SIZE: usize = 72;
+const V3_HEADER_SIZE: usize = 104;
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+// Only 16 bit reference counts, the default, are supported.
+const REFCOUNT_ORDER: u32 = 4;
+const REFCOUNT_BYTES: u64 = 2;
+const MAX_BACKING_FILE_NAME: u32 = 1023;
+// Deep enough for any sane chain, and it stops loops of backing files.
+const MAX_BACKING_DEPTH: u32 = 16;
+
+// Offsets of the header fields.
+const VERSION_OFFSET: usize = 4;
+const BACKING_FILE_OFFSET_OFFSET: usize = 8;
+const BACKING_FILE_SIZE_OFFSET: usize = 16;
+const CLUSTER_BITS_OFFSET: usize = 20;
+const SIZE_OFFSET: usize = 24;
+const CRYPT_METHOD_OFFSET: usize = 32;
+const L1_SIZE_OFFSET: usize = 36;
+const L1_TABLE_OFFSET_OFFSET: usize = 40;
+const REFCOUNT_TABLE_OFFSET_OFFSET: usize = 48;
+const REFCOUNT_TABLE_CLUSTERS_OFFSET: usize = 56;
+const NB_SNAPSHOTS_OFFSET: usize = 60;
+const INCOMPATIBLE_FEATURES_OFFSET: usize = 72;
+const REFCOUNT_ORDER_OFFSET: usize = 96;
+
+// Bits of the L1, L2 and refcount table entries.
+const L1_L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = !0x1ff;
+const COPIED_FLAG: u64 = 1 << 63;
+const COMPRESSED_FLAG: u64 = 1 << 62;
+const ZERO_FLAG: u64 = 1;
+
+fn invalid_image(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn unsupported(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+fn read_u64_at(file: &File, offset: u64) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    file.read_exact_at(&mut bytes, offset)?;
+    Ok(BigEndian::read_u64(&bytes))
+}
+
+fn write_u64_at(file: &File, offset: u64, val: u64) -> io::Result<()> {
+    let mut bytes = [0u8; 8];
+    BigEndian::write_u64(&mut bytes, val);
+    file.write_all_at(&bytes, offset)
+}
+
+// Reads a table of `entries` big endian 64 bit values.
+fn read_table(file: &File, offset: u64, entries: u64) -> io::Result<Vec<u64>> {
+    let mut bytes = vec![0u8; (entries * 8) as usize];
+    file.read_exact_at(&mut bytes, offset)?;
+    Ok(bytes.chunks(8).map(BigEndian::read_u64).collect())
+}
+
+// Where a guest cluster lives.
+enum ClusterState {
+    // Read from the backing file, or as zeroes.
+    Unallocated,
+    // Reads as zeroes, the host cluster is kept allocated when its offset is not 0.
+    Zero(u64),
+    // At the given offset in the image.
+    Data(u64),
+}
+
+// The image on top of which a qcow2 image is created.
+enum Backing {
+    Raw(File),
+    Qcow(Box<QcowFile>),
+}
+
+impl Backing {
+    fn open(path: &Path, depth: u32) -> io::Result<Backing> {
+        let file = OpenOptions::new().read(true).open(path)?;
+        let mut magic = [0u8; 4];
+        let is_qcow = file.metadata()?.len() >= 4
+            && file.read_exact_at(&mut magic, 0).is_ok()
+            && BigEndian::read_u32(&magic) == QCOW_MAGIC;
+        if is_qcow {
+            Ok(Backing::Qcow(Box::new(QcowFile::open(file, path, depth)?)))
+        } else {
+            Ok(Backing::Raw(file))
+        }
+    }
+
+    // Fills `buf` with the data at `offset`. Whatever lies past the end reads as zeroes.
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let size = match self {
+            Backing::Raw(file) => file.metadata()?.len(),
+            Backing::Qcow(qcow) => qcow.size,
+        };
+        let available = cmp::min(buf.len() as u64, size.saturating_sub(offset)) as usize;
+        for byte in buf[available..].iter_mut() {
+            *byte = 0;
+        }
+        match self {
+            Backing::Raw(file) => file.read_exact_at(&mut buf[..available], offset),
+            Backing::Qcow(qcow) => qcow.read_guest(&mut buf[..available], offset),
+        }
+    }
+}
+
+/// A disk image in the qcow2 format.
+pub struct QcowFile {
+    file: File,
+    backing: Option<Backing>,
+    version: u32,
+    cluster_bits: u32,
+    // The size of the disk seen by the guest.
+    size: u64,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    // Where the next cluster gets allocated, right after the last one of the image.
+    next_cluster: u64,
+    position: u64,
+}
+
+impl QcowFile {
+    /// Opens the qcow2 image held by `file`. `path` is where the image lives, relative backing
+    /// file names are looked up next to it.
+    pub fn new(file: File, path: &Path) -> io::Result<QcowFile> {
+        QcowFile::open(file, path, 0)
+    }
+
+    fn open(file: File, path: &Path, depth: u32) -> io::Result<QcowFile> {
+        let file_len = file.metadata()?.len();
+        let mut header = [0u8; V3_HEADER_SIZE];
+        if file_len < V2_HEADER_SIZE as u64 {
+            return Err(invalid_image("not a qcow2 image"));
+        }
+        let header_len = cmp::min(file_len, V3_HEADER_SIZE as u64) as usize;
+        file.read_exact_at(&mut header[..header_len], 0)?;
+        if BigEndian::read_u32(&header) != QCOW_MAGIC {
+            return Err(invalid_image("not a qcow2 image"));
+        }
+
+        let version = BigEndian::read_u32(&header[VERSION_OFFSET..]);
+        let (incompatible_features, refcount_order) = match version {
+            2 => (0, REFCOUNT_ORDER),
+            3 if header_len == V3_HEADER_SIZE => (
+                BigEndian::read_u64(&header[INCOMPATIBLE_FEATURES_OFFSET..]),
+                BigEndian::read_u32(&header[REFCOUNT_ORDER_OFFSET..]),
+            ),
+            _ => return Err(unsupported("unsupported qcow2 version")),
+        };
+        // The dirty and corrupt bits are incompatible features too, the refcounts of such images
+        // cannot be trusted.
+        if incompatible_features != 0 {
+            return Err(unsupported("unsupported qcow2 incompatible features"));
+        }
+        if refcount_order != REFCOUNT_ORDER {
+            return Err(unsupported("only 16 bit qcow2 refcounts are supported"));
+        }
+        if BigEndian::read_u32(&header[CRYPT_METHOD_OFFSET..]) != 0 {
+            return Err(unsupported("encrypted qcow2 images are not supported"));
+        }
+        if BigEndian::read_u32(&header[NB_SNAPSHOTS_OFFSET..]) != 0 {
+            return Err(unsupported("qcow2 internal snapshots are not supported"));
+        }
+
+        let cluster_bits = BigEndian::read_u32(&header[CLUSTER_BITS_OFFSET..]);
+        if cluster_bits < MIN_CLUSTER_BITS || cluster_bits > MAX_CLUSTER_BITS {
+            return Err(invalid_image("invalid qcow2 cluster size"));
+        }
+        let cluster_size = 1u64 << cluster_bits;
+        let size = BigEndian::read_u64(&header[SIZE_OFFSET..]);
+
+        // Each L2 table maps a cluster worth of 8 byte entries.
+        let l2_coverage = cluster_size * (cluster_size / 8);
+        let l1_size = u64::from(BigEndian::read_u32(&header[L1_SIZE_OFFSET..]));
+        let l1_table_offset = BigEndian::read_u64(&header[L1_TABLE_OFFSET_OFFSET..]);
+        let refcount_table_offset = BigEndian::read_u64(&header[REFCOUNT_TABLE_OFFSET_OFFSET..]);
+        let refcount_table_entries = u64::from(BigEndian::read_u32(
+            &header[REFCOUNT_TABLE_CLUSTERS_OFFSET..],
+        )) << (cluster_bits - 3);
+        let fits = |offset: u64, entries: u64| {
+            offset % cluster_size == 0
+                && offset
+                    .checked_add(entries * 8)
+                    .map_or(false, |end| end <= file_len)
+        };
+        if l1_size < size / l2_coverage + u64::from(size % l2_coverage != 0)
+            || !fits(l1_table_offset, l1_size)
+            || !fits(refcount_table_offset, refcount_table_entries)
+        {
+            return Err(invalid_image("corrupted qcow2 header"));
+        }
+
+        let backing_file_offset = BigEndian::read_u64(&header[BACKING_FILE_OFFSET_OFFSET..]);
+        let backing = if backing_file_offset != 0 {
+            let name_len = BigEndian::read_u32(&header[BACKING_FILE_SIZE_OFFSET..]);
+            if name_len > MAX_BACKING_FILE_NAME {
+                return Err(invalid_image("invalid qcow2 backing file name"));
+            }
+            if depth >= MAX_BACKING_DEPTH {
+                return Err(unsupported("too many qcow2 backing files"));
+            }
+            let mut name = vec![0u8; name_len as usize];
+            file.read_exact_at(&mut name, backing_file_offset)?;
+            let mut backing_path = PathBuf::from(OsStr::from_bytes(&name));
+            if backing_path.is_relative() {
+                if let Some(dir) = path.parent() {
+                    backing_path = dir.join(backing_path);
+                }
+            }
+            Some(Backing::open(&backing_path, depth + 1)?)
+        } else {
+            None
+        };
+
+        Ok(QcowFile {
+            l1_table: read_table(&file, l1_table_offset, l1_size)?,
+            refcount_table: read_table(&file, refcount_table_offset, refcount_table_entries)?,
+            next_cluster: (file_len + cluster_size - 1) & !(cluster_size - 1),
+            file,
+            backing,
+            version,
+            cluster_bits,
+            size,
+            l1_table_offset,
+            refcount_table_offset,
+            position: 0,
+        })
+    }
+
+    /// Returns the file holding the image.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    // Returns the index in the L1 table and the offset of the L2 entry in its table for the guest
+    // cluster holding `offset`.
+    fn l2_position(&self, offset: u64) -> (usize, u64) {
+        let l2_bits = self.cluster_bits - 3;
+        let l1_index = (offset >> (self.cluster_bits + l2_bits)) as usize;
+        let l2_index = (offset >> self.cluster_bits) & ((1 << l2_bits) - 1);
+        (l1_index, l2_index * 8)
+    }
+
+    fn cluster_state(&self, offset: u64) -> io::Result<ClusterState> {
+        let (l1_index, l2_entry_offset) = self.l2_position(offset);
+        let l2_table = self.l1_table[l1_index] & L1_L2_OFFSET_MASK;
+        if l2_table == 0 {
+            return Ok(ClusterState::Unallocated);
+        }
+        let entry = read_u64_at(&self.file, l2_table + l2_entry_offset)?;
+        if entry & COMPRESSED_FLAG != 0 {
+            return Err(unsupported("compressed qcow2 clusters are not supported"));
+        }
+        let host = entry & L1_L2_OFFSET_MASK;
+        Ok(if self.version >= 3 && entry & ZERO_FLAG != 0 {
+            ClusterState::Zero(host)
+        } else if host == 0 {
+            ClusterState::Unallocated
+        } else {
+            ClusterState::Data(host)
+        })
+    }
+
+    fn set_l2_entry(&mut self, offset: u64, entry: u64) -> io::Result<()> {
+        let (l1_index, l2_entry_offset) = self.l2_position(offset);
+        let mut l2_table = self.l1_table[l1_index] & L1_L2_OFFSET_MASK;
+        if l2_table == 0 {
+            l2_table = self.allocate_cluster()?;
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size() as usize], l2_table)?;
+            write_u64_at(
+                &self.file,
+                self.l1_table_offset + l1_index as u64 * 8,
+                l2_table | COPIED_FLAG,
+            )?;
+            self.l1_table[l1_index] = l2_table | COPIED_FLAG;
+        }
+        write_u64_at(&self.file, l2_table + l2_entry_offset, entry)
+    }
+
+    // Appends a cluster to the image and takes a reference to it. Its contents are up to the
+    // caller.
+    fn allocate_cluster(&mut self) -> io::Result<u64> {
+        let cluster = self.next_cluster;
+        self.next_cluster += self.cluster_size();
+        self.set_refcount(cluster, 1)?;
+        Ok(cluster)
+    }
+
+    fn set_refcount(&mut self, cluster: u64, refcount: u16) -> io::Result<()> {
+        let cluster_index = cluster >> self.cluster_bits;
+        let block_entries = self.cluster_size() / REFCOUNT_BYTES;
+        let table_index = (cluster_index / block_entries) as usize;
+        if table_index >= self.refcount_table.len() {
+            return Err(unsupported("the qcow2 refcount table is full"));
+        }
+
+        let mut block = self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block == 0 {
+            // The new refcount block may have to count itself.
+            block = self.next_cluster;
+            self.next_cluster += self.cluster_size();
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size() as usize], block)?;
+            write_u64_at(
+                &self.file,
+                self.refcount_table_offset + table_index as u64 * 8,
+                block,
+            )?;
+            self.refcount_table[table_index] = block;
+            self.set_refcount(block, 1)?;
+        }
+
+        let mut bytes = [0u8; REFCOUNT_BYTES as usize];
+        BigEndian::write_u16(&mut bytes, refcount);
+        self.file.write_all_at(
+            &bytes,
+            block + (cluster_index % block_entries) * REFCOUNT_BYTES,
+        )
+    }
+
+    // Drops the reference to a cluster and gives its space back to the host. The cluster itself
+    // is not reused.
+    fn free_cluster(&mut self, cluster: u64) -> io::Result<()> {
+        self.set_refcount(cluster, 0)?;
+        fallocate(
+            &self.file,
+            FallocateMode::PunchHole,
+            cluster,
+            self.cluster_size(),
+        )
+        .map_err(|e| io::Error::from_raw_os_error(e.errno()))
+    }
+
+    // Fills `buf` with the guest data at `offset`, which has to be within the disk.
+    fn read_guest(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        let mut done = 0;
+        while done < buf.len() {
+            let position = offset + done as u64;
+            let in_cluster = position & (self.cluster_size() - 1);
+            let count = cmp::min(
+                buf.len() - done,
+                (self.cluster_size() - in_cluster) as usize,
+            );
+            let chunk = &mut buf[done..done + count];
+            match self.cluster_state(position)? {
+                ClusterState::Data(host) => self.file.read_exact_at(chunk, host + in_cluster)?,
+                ClusterState::Zero(_) => {
+                    for byte in chunk.iter_mut() {
+                        *byte = 0;
+                    }
+                }
+                ClusterState::Unallocated => self.read_backing(chunk, position)?,
+            }
+            done += count;
+        }
+        Ok(())
+    }
+
+    fn read_backing(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        match self.backing {
+            Some(ref mut backing) => backing.read_at(buf, offset),
+            None => {
+                for byte in buf.iter_mut() {
+                    *byte = 0;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Writes `data` at `offset`, which has to fall in a single cluster.
+    fn write_in_cluster(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let in_cluster = offset & (self.cluster_size() - 1);
+        let state = self.cluster_state(offset)?;
+        if let ClusterState::Data(host) = state {
+            return self.file.write_all_at(data, host + in_cluster);
+        }
+
+        // The rest of a new cluster keeps what the guest saw before.
+        let cluster_start = offset - in_cluster;
+        let mut contents = vec![0u8; self.cluster_size() as usize];
+        if let ClusterState::Unallocated = state {
+            self.read_backing(&mut contents, cluster_start)?;
+        }
+        contents[in_cluster as usize..in_cluster as usize + data.len()].copy_from_slice(data);
+        let host = match state {
+            ClusterState::Zero(host) if host != 0 => host,
+            _ => self.allocate_cluster()?,
+        };
+        self.file.write_all_at(&contents, host)?;
+        self.set_l2_entry(offset, host | COPIED_FLAG)
+    }
+
+    fn write_zeroes_in_clusters(&mut self, start: u64, end: u64) -> io::Result<()> {
+        let mut offset = start;
+        while offset < end {
+            let in_cluster = offset & (self.cluster_size() - 1);
+            let count = cmp::min(end - offset, self.cluster_size() - in_cluster);
+            self.write_in_cluster(offset, &vec![0u8; count as usize])?;
+            offset += count;
+        }
+        Ok(())
+    }
+
+    /// Makes the range read back as zeroes. With `punch_hole` set, the whole clusters in the
+    /// range are deallocated from the image. The backing file is left untouched either way.
+    pub fn zero_range(&mut self, offset: u64, len: u64, punch_hole: bool) -> io::Result<()> {
+        let end = offset + len;
+        if end > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "range past the end of the disk",
+            ));
+        }
+        // Version 2 images cannot flag clusters as zeroed.
+        if self.version < 3 {
+            return self.write_zeroes_in_clusters(offset, end);
+        }
+
+        let cluster_size = self.cluster_size();
+        let first_whole = (offset + cluster_size - 1) >> self.cluster_bits;
+        let end_whole = if end == self.size {
+            (end + cluster_size - 1) >> self.cluster_bits
+        } else {
+            end >> self.cluster_bits
+        };
+        if first_whole >= end_whole {
+            // The range does not cover any cluster completely.
+            return self.write_zeroes_in_clusters(offset, end);
+        }
+
+        self.write_zeroes_in_clusters(offset, first_whole << self.cluster_bits)?;
+        for cluster in first_whole..end_whole {
+            let cluster_offset = cluster << self.cluster_bits;
+            let host = match self.cluster_state(cluster_offset)? {
+                // Nothing to hide.
+                ClusterState::Unallocated if self.backing.is_none() => continue,
+                ClusterState::Unallocated => 0,
+                ClusterState::Zero(host) if host == 0 || !punch_hole => continue,
+                ClusterState::Zero(host) | ClusterState::Data(host) => host,
+            };
+            if host != 0 && !punch_hole {
+                self.set_l2_entry(cluster_offset, host | COPIED_FLAG | ZERO_FLAG)?;
+            } else {
+                self.set_l2_entry(cluster_offset, ZERO_FLAG)?;
+                if host != 0 {
+                    self.free_cluster(host)?;
+                }
+            }
+        }
+        self.write_zeroes_in_clusters(cmp::min(end_whole << self.cluster_bits, end), end)
+    }
+}
+
+impl Read for QcowFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = cmp::min(buf.len() as u64, self.size.saturating_sub(self.position)) as usize;
+        let position = self.position;
+        self.read_guest(&mut buf[..count], position)?;
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for QcowFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The disk cannot grow.
+        if self.position >= self.size {
+            return Ok(0);
+        }
+        let in_cluster = self.position & (self.cluster_size() - 1);
+        let count = cmp::min(
+            buf.len() as u64,
+            cmp::min(self.cluster_size() - in_cluster, self.size - self.position),
+        ) as usize;
+        let position = self.position;
+        self.write_in_cluster(position, &buf[..count])?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for QcowFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_position = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => add_offset(self.size, offset),
+            SeekFrom::Current(offset) => add_offset(self.position, offset),
+        };
+        match new_position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn add_offset(base: u64, offset: i64) -> Option<u64> {
+    if offset >= 0 {
+        base.checked_add(offset as u64)
+    } else {
+        base.checked_sub(offset.wrapping_neg() as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::{tempdir, tempfile};
+    use super::*;
+
+    const TEST_CLUSTER_BITS: u32 = 16;
+    const TEST_CLUSTER_SIZE: u64 = 1 << TEST_CLUSTER_BITS;
+
+    // Formats `file` the way `qemu-img create -f qcow2` does: the header, the refcount table,
+    // a refcount block and the L1 table each take up a cluster.
+    fn format_image(file: &File, size: u64, backing_file: Option<&str>) {
+        let mut header = vec![0u8; TEST_CLUSTER_SIZE as usize];
+        BigEndian::write_u32(&mut header, QCOW_MAGIC);
+        BigEndian::write_u32(&mut header[VERSION_OFFSET..], 3);
+        if let Some(name) = backing_file {
+            BigEndian::write_u64(&mut header[BACKING_FILE_OFFSET_OFFSET..], 512);
+            BigEndian::write_u32(&mut header[BACKING_FILE_SIZE_OFFSET..], name.len() as u32);
+            header[512..512 + name.len()].copy_from_slice(name.as_bytes());
+        }
+        BigEndian::write_u32(&mut header[CLUSTER_BITS_OFFSET..], TEST_CLUSTER_BITS);
+        BigEndian::write_u64(&mut header[SIZE_OFFSET..], size);
+        let l2_coverage = TEST_CLUSTER_SIZE * TEST_CLUSTER_SIZE / 8;
+        let l1_size = (size + l2_coverage - 1) / l2_coverage;
+        BigEndian::write_u32(&mut header[L1_SIZE_OFFSET..], l1_size as u32);
+        BigEndian::write_u64(&mut header[L1_TABLE_OFFSET_OFFSET..], 3 * TEST_CLUSTER_SIZE);
+        BigEndian::write_u64(
+            &mut header[REFCOUNT_TABLE_OFFSET_OFFSET..],
+            TEST_CLUSTER_SIZE,
+        );
+        BigEndian::write_u32(&mut header[REFCOUNT_TABLE_CLUSTERS_OFFSET..], 1);
+        BigEndian::write_u32(&mut header[REFCOUNT_ORDER_OFFSET..], REFCOUNT_ORDER);
+        BigEndian::write_u32(&mut header[100..], V3_HEADER_SIZE as u32);
+        file.write_all_at(&header, 0).unwrap();
+
+        file.set_len(4 * TEST_CLUSTER_SIZE).unwrap();
+        write_u64_at(file, TEST_CLUSTER_SIZE, 2 * TEST_CLUSTER_SIZE).unwrap();
+        for cluster in 0..4 {
+            file.write_all_at(&[0, 1], 2 * TEST_CLUSTER_SIZE + cluster * REFCOUNT_BYTES)
+                .unwrap();
+        }
+    }
+
+    fn new_image(size: u64) -> QcowFile {
+        let file = tempfile().unwrap();
+        format_image(&file, size, None);
+        QcowFile::new(file, Path::new("/")).unwrap()
+    }
+
+    fn read_disk(disk: &mut QcowFile, offset: u64, len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.read_exact(&mut data).unwrap();
+        data
+    }
+
+    fn refcount(disk: &QcowFile, cluster: u64) -> u16 {
+        let block = disk.refcount_table[0];
+        let mut bytes = [0u8; 2];
+        disk.file
+            .read_exact_at(&mut bytes, block + (cluster >> TEST_CLUSTER_BITS) * 2)
+            .unwrap();
+        BigEndian::read_u16(&bytes)
+    }
+
+    #[test]
+    fn test_read_write() {
+        let size = 3 * TEST_CLUSTER_SIZE + 512;
+        let mut disk = new_image(size);
+        assert_eq!(disk.seek(SeekFrom::End(0)).unwrap(), size);
+        assert!(read_disk(&mut disk, 0, size as usize)
+            .iter()
+            .all(|b| *b == 0));
+
+        let data: Vec<u8> = (0..TEST_CLUSTER_SIZE + 20)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        disk.seek(SeekFrom::Start(TEST_CLUSTER_SIZE - 10)).unwrap();
+        disk.write_all(&data).unwrap();
+        disk.seek(SeekFrom::Start(size - 1)).unwrap();
+        disk.write_all(&[0xaa]).unwrap();
+        // The disk cannot grow.
+        assert!(disk.write_all(&[0xaa]).is_err());
+
+        assert_eq!(
+            read_disk(&mut disk, TEST_CLUSTER_SIZE - 10, data.len()),
+            data
+        );
+        assert_eq!(read_disk(&mut disk, size - 2, 2), vec![0, 0xaa]);
+        assert!(read_disk(&mut disk, 0, 100).iter().all(|b| *b == 0));
+
+        // Four data clusters and an L2 table, right after the first data cluster, were appended
+        // to the metadata, and are counted.
+        let image_len = disk.file.metadata().unwrap().len();
+        assert_eq!(image_len, 9 * TEST_CLUSTER_SIZE);
+        for cluster in 0..9 {
+            assert_eq!(refcount(&disk, cluster * TEST_CLUSTER_SIZE), 1);
+        }
+        assert_eq!(disk.l1_table[0], 5 * TEST_CLUSTER_SIZE | COPIED_FLAG);
+
+        // The mapping is persisted.
+        let mut disk = QcowFile::new(disk.file.try_clone().unwrap(), Path::new("/")).unwrap();
+        assert_eq!(
+            read_disk(&mut disk, TEST_CLUSTER_SIZE - 10, data.len()),
+            data
+        );
+        assert_eq!(read_disk(&mut disk, size - 1, 1), vec![0xaa]);
+    }
+
+    #[test]
+    fn test_backing_file() {
+        let dir = tempdir().unwrap();
+        let size = 2 * TEST_CLUSTER_SIZE;
+        let backing_data: Vec<u8> = (0..size).map(|i| (i % 251) as u8 + 1).collect();
+        let mut backing = File::create(dir.path().join("base.raw")).unwrap();
+        backing.write_all(&backing_data).unwrap();
+
+        let image_path = dir.path().join("image.qcow2");
+        let image = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&image_path)
+            .unwrap();
+        format_image(&image, size, Some("base.raw"));
+        let mut disk = QcowFile::new(image, &image_path).unwrap();
+        assert_eq!(read_disk(&mut disk, 0, size as usize), backing_data);
+
+        // A partial write keeps the rest of the cluster from the backing file, which is left
+        // untouched.
+        disk.seek(SeekFrom::Start(100)).unwrap();
+        disk.write_all(&[0xaa; 10]).unwrap();
+        let mut expected = backing_data.clone();
+        for byte in expected[100..110].iter_mut() {
+            *byte = 0xaa;
+        }
+        assert_eq!(read_disk(&mut disk, 0, size as usize), expected);
+        let mut base_data = Vec::new();
+        File::open(dir.path().join("base.raw"))
+            .unwrap()
+            .read_to_end(&mut base_data)
+            .unwrap();
+        assert_eq!(base_data, backing_data);
+
+        // The backing file can be a qcow2 image too.
+        let top_path = dir.path().join("top.qcow2");
+        let top = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(&top_path)
+            .unwrap();
+        format_image(&top, size, Some("image.qcow2"));
+        let mut top = QcowFile::new(top, &top_path).unwrap();
+        assert_eq!(read_disk(&mut top, 0, size as usize), expected);
+
+        // A missing backing file is an error.
+        let broken = tempfile().unwrap();
+        format_image(&broken, size, Some("missing.raw"));
+        assert!(QcowFile::new(broken, &image_path).is_err());
+    }
+
+    #[test]
+    fn test_zero_range() {
+        let size = 4 * TEST_CLUSTER_SIZE;
+        let mut disk = new_image(size);
+        let data = vec![0xaa; size as usize];
+
+        for &punch_hole in [false, true].iter() {
+            disk.seek(SeekFrom::Start(0)).unwrap();
+            disk.write_all(&data).unwrap();
+            disk.zero_range(100, 2 * TEST_CLUSTER_SIZE, punch_hole)
+                .unwrap();
+            let contents = read_disk(&mut disk, 0, size as usize);
+            assert_eq!(contents[99], 0xaa);
+            assert!(contents[100..100 + 2 * TEST_CLUSTER_SIZE as usize]
+                .iter()
+                .all(|b| *b == 0));
+            assert_eq!(contents[100 + 2 * TEST_CLUSTER_SIZE as usize], 0xaa);
+
+            // The only whole cluster in the range is flagged as zeroed, and freed on discard.
+            match disk.cluster_state(TEST_CLUSTER_SIZE).unwrap() {
+                ClusterState::Zero(0) => assert!(punch_hole),
+                ClusterState::Zero(_) => assert!(!punch_hole),
+                _ => panic!("Expected a zeroed cluster"),
+            }
+        }
+
+        // Writing to a zeroed cluster reallocates it.
+        disk.seek(SeekFrom::Start(TEST_CLUSTER_SIZE + 1)).unwrap();
+        disk.write_all(&[0xbb]).unwrap();
+        assert_eq!(read_disk(&mut disk, TEST_CLUSTER_SIZE, 3), vec![0, 0xbb, 0]);
+
+        assert!(disk.zero_range(size - 1, 2, true).is_err());
+    }
+
+    #[test]
+    fn test_invalid_images() {
+        let file = tempfile().unwrap();
+        file.write_all_at(&[0u8; 512], 0).unwrap();
+        assert_eq!(
+            QcowFile::new(file, Path::new("/")).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        let file = tempfile().unwrap();
+        format_image(&file, TEST_CLUSTER_SIZE, None);
+        file.write_all_at(&[0, 0, 0, 1], NB_SNAPSHOTS_OFFSET as u64)
+            .unwrap();
+        assert!(QcowFile::new(file, Path::new("/")).is_err());
+
+        let file = tempfile().unwrap();
+        format_image(&file, TEST_CLUSTER_SIZE, None);
+        // Mark the image as dirty.
+        file.write_all_at(&[1], INCOMPATIBLE_FEATURES_OFFSET as u64 + 7)
+            .unwrap();
+        assert!(QcowFile::new(file, Path::new("/")).is_err());
+
+        // A compressed cluster cannot be read.
+        let mut disk = new_image(TEST_CLUSTER_SIZE);
+        disk.write_all(&[0xaa]).unwrap();
+        let l2_table = disk.l1_table[0] & L1_L2_OFFSET_MASK;
+        let entry = read_u64_at(&disk.file, l2_table).unwrap();
+        write_u64_at(&disk.file, l2_table, entry | COMPRESSED_FLAG).unwrap();
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        assert!(disk.read(&mut [0u8; 1]).is_err());
+    }
+}
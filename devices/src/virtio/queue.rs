@@ -211,6 +211,23 @@ impl<'a, 'b> Iterator for AvailIter<'a, 'b> {
     }
 }
 
+/// The driver-visible configuration of a virtio queue, as saved in a snapshot.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct QueueState {
+    /// The maximal size in elements offered by the device.
+    pub max_size: u16,
+    /// The queue size in elements the driver selected.
+    pub size: u16,
+    /// Indicates if the queue is finished with configuration.
+    pub ready: bool,
+    /// Guest physical address of the descriptor table.
+    pub desc_table: u64,
+    /// Guest physical address of the available ring.
+    pub avail_ring: u64,
+    /// Guest physical address of the used ring.
+    pub used_ring: u64,
+}
+
 #[derive(Clone)]
 /// A virtio queue's parameters.
 pub struct Queue {
@@ -261,6 +278,11 @@ impl Queue {
         min(self.size, self.max_size)
     }
 
+    /// Returns the index of the next element of the available ring the device will process.
+    pub fn next_avail(&self) -> u16 {
+        self.next_avail.0
+    }
+
     pub fn is_valid(&self, mem: &GuestMemory) -> bool {
         let queue_size = self.actual_size() as usize;
         let desc_table = self.desc_table;
@@ -346,24 +368,36 @@ impl Queue {
 
     /// Puts an available descriptor head into the used ring for use by the guest.
     pub fn add_used(&mut self, mem: &GuestMemory, desc_index: u16, len: u32) {
-        if desc_index >= self.actual_size() {
-            error!(
-                "attempted to add out of bounds descriptor to used ring: {}",
-                desc_index
-            );
-            return;
-        }
+        self.add_used_batch(mem, &[(desc_index, len)]);
+    }
 
+    /// Puts several available descriptor heads, along with the lengths written to them, into the
+    /// used ring. The guest sees either all of them or none.
+    pub fn add_used_batch(&mut self, mem: &GuestMemory, used: &[(u16, u32)]) {
         let used_ring = self.used_ring;
-        let next_used = (self.next_used.0 % self.actual_size()) as usize;
-        let used_elem = used_ring.unchecked_add(4 + next_used * 8);
+        let next_used_before = self.next_used;
+        for &(desc_index, len) in used {
+            if desc_index >= self.actual_size() {
+                error!(
+                    "attempted to add out of bounds descriptor to used ring: {}",
+                    desc_index
+                );
+                continue;
+            }
 
-        // These writes can't fail as we are guaranteed to be within the descriptor ring.
-        mem.write_obj_at_addr(desc_index as u32, used_elem).unwrap();
-        mem.write_obj_at_addr(len as u32, used_elem.unchecked_add(4))
-            .unwrap();
+            let next_used = (self.next_used.0 % self.actual_size()) as usize;
+            let used_elem = used_ring.unchecked_add(4 + next_used * 8);
+
+            // These writes can't fail as we are guaranteed to be within the descriptor ring.
+            mem.write_obj_at_addr(desc_index as u32, used_elem).unwrap();
+            mem.write_obj_at_addr(len as u32, used_elem.unchecked_add(4))
+                .unwrap();
 
-        self.next_used += Wrapping(1);
+            self.next_used += Wrapping(1);
+        }
+        if self.next_used == next_used_before {
+            return;
+        }
 
         // This fence ensures all descriptor writes are visible before the index update is.
         fence(Ordering::Release);
@@ -372,6 +406,44 @@ impl Queue {
             .unwrap();
     }
 
+    /// Returns the configuration of this queue.
+    pub fn save_state(&self) -> QueueState {
+        QueueState {
+            max_size: self.max_size,
+            size: self.size,
+            ready: self.ready,
+            desc_table: self.desc_table.offset() as u64,
+            avail_ring: self.avail_ring.offset() as u64,
+            used_ring: self.used_ring.offset() as u64,
+        }
+    }
+
+    /// Restores a queue configuration saved with `save_state`.
+    ///
+    /// The position of the device in the rings is not part of `QueueState`, so every request
+    /// popped from the available ring has to be in the used ring when the state is saved. Our
+    /// devices complete their synchronous requests before going back to the event loop, and the
+    /// block devices are drained of their asynchronous requests before a snapshot is created.
+    /// The next available and the next used elements are then both given by the index of the
+    /// used ring, as found in the restored `mem`.
+    pub fn restore_state(&mut self, state: &QueueState, mem: &GuestMemory) {
+        self.max_size = state.max_size;
+        self.size = state.size;
+        self.ready = state.ready;
+        self.desc_table = GuestAddress(state.desc_table as usize);
+        self.avail_ring = GuestAddress(state.avail_ring as usize);
+        self.used_ring = GuestAddress(state.used_ring as usize);
+
+        let used_idx = if self.is_valid(mem) {
+            mem.read_obj_from_addr::<u16>(self.used_ring.unchecked_add(2))
+                .unwrap_or(0)
+        } else {
+            0
+        };
+        self.next_avail = Wrapping(used_idx);
+        self.next_used = Wrapping(used_idx);
+    }
+
     /// Goes back one position in the available descriptor chain offered by the driver.
     /// Rust does not support bidirectional iterators. This is the only way to revert the effect
     /// of an iterator increment on the queue.
@@ -796,4 +868,47 @@ pub(crate) mod tests {
         assert_eq!(x.id, 1);
         assert_eq!(x.len, 0x1000);
     }
+
+    #[test]
+    fn test_add_used_batch() {
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+        q.add_used_batch(m, &[]);
+        assert_eq!(vq.used.idx.get(), 0);
+
+        // The index is only updated once, out of bounds descriptors are skipped.
+        q.add_used_batch(m, &[(1, 0x1000), (16, 0x1000), (3, 0x200)]);
+        assert_eq!(vq.used.idx.get(), 2);
+        let x = vq.used.ring[0].get();
+        assert_eq!(x.id, 1);
+        assert_eq!(x.len, 0x1000);
+        let x = vq.used.ring[1].get();
+        assert_eq!(x.id, 3);
+        assert_eq!(x.len, 0x200);
+    }
+
+    #[test]
+    fn test_save_restore_state() {
+        let m = &GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let vq = VirtQueue::new(GuestAddress(0), m, 16);
+
+        let mut q = vq.create_queue();
+        q.add_used(m, 1, 0x1000);
+        q.add_used(m, 2, 0x1000);
+        let state = q.save_state();
+
+        let mut restored = Queue::new(16);
+        restored.restore_state(&state, m);
+        assert_eq!(restored.save_state(), state);
+        assert_eq!(restored.next_avail, Wrapping(2));
+        assert_eq!(restored.next_used, Wrapping(2));
+
+        // The position in the rings is not recovered from an invalid queue.
+        let mut invalid_state = state.clone();
+        invalid_state.ready = false;
+        restored.restore_state(&invalid_state, m);
+        assert_eq!(restored.next_used, Wrapping(0));
+    }
 }
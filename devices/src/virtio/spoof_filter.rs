@@ -0,0 +1,191 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Checks the source addresses of the frames sent by a guest, so that it cannot impersonate other
+//! hosts on the network of the tap device.
+
+use std::net::Ipv4Addr;
+use std::result;
+
+use dumbo::pdu::arp::EthIPv4ArpFrame;
+use dumbo::pdu::ethernet::{EthernetFrame, ETHERTYPE_ARP, ETHERTYPE_IPV4};
+use dumbo::pdu::ipv4::IPv4Packet;
+use net_util::MacAddr;
+
+// The tag protocol identifiers of 802.1Q and 802.1ad, found in place of the EtherType of a tagged
+// frame.
+const ETHERTYPE_VLAN: u16 = 0x8100;
+const ETHERTYPE_QINQ: u16 = 0x88a8;
+
+/// The reasons for dropping a frame sent by the guest.
+#[derive(Debug, PartialEq)]
+pub enum SpoofError {
+    /// The source MAC address of the frame, or the sender hardware address of an ARP frame, is
+    /// not the MAC address of the guest, or the frame is too short to hold an Ethernet header.
+    MacAddress,
+    /// The source address of an IPv4 packet, or the sender protocol address of an ARP frame, is
+    /// not allowed, or the packet is malformed so its sender cannot be told.
+    IpAddress,
+}
+
+/// Drops the frames sent by the guest with a source address it was not given.
+#[derive(Clone)]
+pub struct SpoofFilter {
+    guest_mac: MacAddr,
+    allowed_ips: Option<Vec<Ipv4Addr>>,
+}
+
+impl SpoofFilter {
+    /// Creates a filter letting through the frames sent from `guest_mac`. Unless `allowed_ips` is
+    /// `None`, the IPv4 packets and the ARP frames must also be sent from one of its addresses.
+    pub fn new(guest_mac: MacAddr, allowed_ips: Option<Vec<Ipv4Addr>>) -> Self {
+        SpoofFilter {
+            guest_mac,
+            allowed_ips,
+        }
+    }
+
+    /// Checks the L2 `frame`, without its VNET header. The frames of other protocols than IPv4
+    /// and ARP only have their source MAC address checked. The addresses behind a VLAN tag are
+    /// not parsed, so tagged frames are dropped when only some IPv4 addresses are allowed.
+    pub fn check(&self, frame: &[u8]) -> result::Result<(), SpoofError> {
+        let eth = EthernetFrame::from_bytes(frame).map_err(|_| SpoofError::MacAddress)?;
+        if eth.src_mac() != self.guest_mac {
+            return Err(SpoofError::MacAddress);
+        }
+
+        match eth.ethertype() {
+            ETHERTYPE_IPV4 => {
+                if self.allowed_ips.is_some() {
+                    // The checksum is left to the receiving hosts.
+                    let ip = IPv4Packet::from_bytes(eth.payload(), false)
+                        .map_err(|_| SpoofError::IpAddress)?;
+                    self.check_ip(ip.source_address())?;
+                }
+            }
+            ETHERTYPE_ARP => {
+                let arp = EthIPv4ArpFrame::from_bytes(eth.payload())
+                    .map_err(|_| SpoofError::IpAddress)?;
+                if arp.sha() != self.guest_mac {
+                    return Err(SpoofError::MacAddress);
+                }
+                self.check_ip(arp.spa())?;
+            }
+            ETHERTYPE_VLAN | ETHERTYPE_QINQ if self.allowed_ips.is_some() => {
+                return Err(SpoofError::IpAddress);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn check_ip(&self, addr: Ipv4Addr) -> result::Result<(), SpoofError> {
+        match self.allowed_ips {
+            Some(ref allowed_ips) if !allowed_ips.contains(&addr) => Err(SpoofError::IpAddress),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dumbo::pdu::arp::ETH_IPV4_FRAME_LEN;
+
+    const GUEST_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const OTHER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+
+    fn guest_mac() -> MacAddr {
+        MacAddr::parse_str("12:34:56:78:9a:bc").unwrap()
+    }
+
+    fn other_mac() -> MacAddr {
+        MacAddr::parse_str("12:34:56:78:9a:bd").unwrap()
+    }
+
+    // Writes a frame from `src_mac` carrying an IPv4 packet without payload from `src_ip` to
+    // `buf`, and returns its length.
+    fn write_ipv4_frame(buf: &mut [u8], src_mac: MacAddr, src_ip: Ipv4Addr) -> usize {
+        let mut eth = EthernetFrame::write_incomplete(buf, other_mac(), src_mac, ETHERTYPE_IPV4)
+            .ok()
+            .unwrap();
+        let ip_len = IPv4Packet::write_header(eth.inner_mut().payload_mut(), 6, src_ip, OTHER_IP)
+            .ok()
+            .unwrap()
+            .with_payload_len_unchecked(0, true)
+            .len();
+        eth.with_payload_len_unchecked(ip_len).len()
+    }
+
+    // Writes an ARP request from `sha` and `spa` to `buf`, and returns its length.
+    fn write_arp_frame(buf: &mut [u8], sha: MacAddr, spa: Ipv4Addr) -> usize {
+        let mut eth = EthernetFrame::write_incomplete(buf, other_mac(), guest_mac(), ETHERTYPE_ARP)
+            .ok()
+            .unwrap();
+        let arp_len = EthIPv4ArpFrame::write_request(
+            &mut eth.inner_mut().payload_mut()[..ETH_IPV4_FRAME_LEN],
+            sha,
+            spa,
+            other_mac(),
+            OTHER_IP,
+        )
+        .ok()
+        .unwrap()
+        .len();
+        eth.with_payload_len_unchecked(arp_len).len()
+    }
+
+    #[test]
+    fn test_check_mac() {
+        let filter = SpoofFilter::new(guest_mac(), None);
+        let mut buf = [0u8; 100];
+
+        let len = write_ipv4_frame(&mut buf, guest_mac(), OTHER_IP);
+        assert_eq!(filter.check(&buf[..len]), Ok(()));
+        let len = write_ipv4_frame(&mut buf, other_mac(), GUEST_IP);
+        assert_eq!(filter.check(&buf[..len]), Err(SpoofError::MacAddress));
+        assert_eq!(filter.check(&buf[..10]), Err(SpoofError::MacAddress));
+
+        // The sender hardware address of ARP frames is checked as well.
+        let len = write_arp_frame(&mut buf, guest_mac(), OTHER_IP);
+        assert_eq!(filter.check(&buf[..len]), Ok(()));
+        let len = write_arp_frame(&mut buf, other_mac(), OTHER_IP);
+        assert_eq!(filter.check(&buf[..len]), Err(SpoofError::MacAddress));
+    }
+
+    #[test]
+    fn test_check_ip() {
+        let filter = SpoofFilter::new(guest_mac(), Some(vec![GUEST_IP]));
+        let mut buf = [0u8; 100];
+
+        let len = write_ipv4_frame(&mut buf, guest_mac(), GUEST_IP);
+        assert_eq!(filter.check(&buf[..len]), Ok(()));
+        let len = write_ipv4_frame(&mut buf, guest_mac(), OTHER_IP);
+        assert_eq!(filter.check(&buf[..len]), Err(SpoofError::IpAddress));
+        // A packet which cannot be parsed is dropped.
+        assert_eq!(filter.check(&buf[..len - 1]), Err(SpoofError::IpAddress));
+
+        let len = write_arp_frame(&mut buf, guest_mac(), GUEST_IP);
+        assert_eq!(filter.check(&buf[..len]), Ok(()));
+        let len = write_arp_frame(&mut buf, guest_mac(), OTHER_IP);
+        assert_eq!(filter.check(&buf[..len]), Err(SpoofError::IpAddress));
+        assert_eq!(filter.check(&buf[..len - 1]), Err(SpoofError::IpAddress));
+
+        // Other protocols only have their MAC address checked.
+        let len = write_ipv4_frame(&mut buf, guest_mac(), OTHER_IP);
+        buf[12] = 0x86;
+        buf[13] = 0xdd;
+        assert_eq!(filter.check(&buf[..len]), Ok(()));
+
+        // The packets behind a VLAN tag cannot evade the check.
+        for tpid in &[[0x81, 0x00], [0x88, 0xa8]] {
+            let len = write_ipv4_frame(&mut buf, guest_mac(), OTHER_IP);
+            buf[12..14].copy_from_slice(tpid);
+            assert_eq!(filter.check(&buf[..len]), Err(SpoofError::IpAddress));
+            assert_eq!(
+                SpoofFilter::new(guest_mac(), None).check(&buf[..len]),
+                Ok(())
+            );
+        }
+    }
+}
@@ -8,11 +8,19 @@
 //! Implements vhost-based virtio devices.
 
 use std;
+
+use net_util::TapError;
 use sys_util::Error as SysError;
 
+use super::ActivateError;
+
 pub mod handle;
+pub mod net;
+#[cfg(feature = "vsock")]
 pub mod vsock;
 
+pub use self::net::Net;
+
 #[derive(Debug)]
 pub enum Error {
     /// Creating kill eventfd failed.
@@ -43,6 +51,10 @@ pub enum Error {
     VhostSetVringKick(vhost_backend::Error),
     /// Net set backend failed.
     VhostNetSetBackend(vhost_backend::Error),
+    /// Setting the offloads of the tap device failed.
+    TapSetOffload(TapError),
+    /// Setting the vnet header size of the tap device failed.
+    TapSetVnetHdrSize(TapError),
     /// Failed to set CID for guest.
     VhostVsockSetCid(vhost_backend::Error),
     /// Failed to start vhost-vsock driver.
@@ -54,4 +66,11 @@ pub enum Error {
 }
 type Result<T> = std::result::Result<T, Error>;
 const INTERRUPT_STATUS_USED_RING: u32 = 0x1;
+#[cfg(feature = "vsock")]
 const TYPE_VSOCK: u32 = 19;
+
+impl std::convert::From<Error> for ActivateError {
+    fn from(error: Error) -> Self {
+        ActivateError::BadVhostActivate(error)
+    }
+}
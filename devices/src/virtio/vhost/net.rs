@@ -0,0 +1,431 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Portions Copyright 2017 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the THIRD-PARTY file.
+
+use super::super::net::tap_offload_flags;
+use super::super::{ActivateError, ActivateResult, Queue, VirtioDevice, TYPE_NET};
+use super::handle::*;
+use super::*;
+
+use logger::{Metric, METRICS};
+use memory_model::GuestMemory;
+use net_util::{MacAddr, Tap, MAC_ADDR_LEN};
+use sys_util::EventFd;
+use vhost_backend::Net as VhostNetFd;
+use vhost_backend::Vhost;
+use virtio_gen::virtio_config::*;
+use virtio_gen::virtio_net::*;
+use virtio_gen::virtio_ring::*;
+
+use epoll;
+use std::cmp;
+use std::io::Write;
+use std::mem;
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
+
+const QUEUE_SIZE: u16 = 256;
+const NUM_QUEUES: usize = 2;
+const QUEUE_SIZES: &'static [u16] = &[QUEUE_SIZE; NUM_QUEUES];
+
+// The features the kernel has to support for the device to advertise them. The offloads are not
+// among them, the tap device takes care of those.
+const VHOST_NET_FEATURES: u64 = 1 << VIRTIO_NET_F_MRG_RXBUF
+    | 1 << VIRTIO_RING_F_INDIRECT_DESC
+    | 1 << VIRTIO_RING_F_EVENT_IDX
+    | 1 << VIRTIO_F_VERSION_1;
+
+// Returns the size of the vnet header the driver puts in front of the frames. The kernel hands
+// the header over to the tap device unchanged, so the tap has to expect the same size.
+fn vnet_hdr_size(acked_features: u64) -> usize {
+    if acked_features & (1 << VIRTIO_NET_F_MRG_RXBUF | 1 << VIRTIO_F_VERSION_1) != 0 {
+        mem::size_of::<virtio_net_hdr_v1>()
+    } else {
+        mem::size_of::<virtio_net_hdr>()
+    }
+}
+
+/// A virtio network device whose queues are serviced by the vhost-net driver of the host
+/// kernel, which moves the frames between the guest memory and the tap device. There is no
+/// rate limiting and no MMDS on this path.
+pub struct Net {
+    tap: Tap,
+    net_fd: Option<VhostNetFd>,
+    avail_features: u64,
+    acked_features: u64,
+    config_space: Vec<u8>,
+    epoll_config: VhostEpollConfig,
+    interrupt: Option<EventFd>,
+}
+
+impl Net {
+    /// Create a new vhost-net device that transfers frames through `tap`.
+    pub fn new_with_tap(
+        tap: Tap,
+        guest_mac: Option<&MacAddr>,
+        mem: &GuestMemory,
+        epoll_config: VhostEpollConfig,
+    ) -> Result<Net> {
+        let fd = VhostNetFd::new(mem).map_err(Error::VhostOpen)?;
+        let vhost_features = fd.get_features().map_err(Error::VhostGetFeatures)?;
+        Net::new(tap, Some(fd), vhost_features, guest_mac, epoll_config)
+    }
+
+    // Creates the device around `net_fd`, the vhost-net handle supporting `vhost_features`. The
+    // device without a handle cannot be activated.
+    fn new(
+        tap: Tap,
+        net_fd: Option<VhostNetFd>,
+        vhost_features: u64,
+        guest_mac: Option<&MacAddr>,
+        epoll_config: VhostEpollConfig,
+    ) -> Result<Net> {
+        // The offloads follow the features acked by the driver, set on activation.
+        tap.set_offload(0).map_err(Error::TapSetOffload)?;
+
+        let mut avail_features = 1 << VIRTIO_NET_F_GUEST_CSUM
+            | 1 << VIRTIO_NET_F_CSUM
+            | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_TSO6
+            | 1 << VIRTIO_NET_F_GUEST_UFO
+            | 1 << VIRTIO_NET_F_HOST_TSO4
+            | 1 << VIRTIO_NET_F_HOST_TSO6
+            | 1 << VIRTIO_NET_F_HOST_UFO
+            | vhost_features & VHOST_NET_FEATURES;
+
+        let config_space = match guest_mac {
+            Some(mac) => {
+                avail_features |= 1 << VIRTIO_NET_F_MAC;
+                mac.get_bytes().to_vec()
+            }
+            None => Vec::with_capacity(MAC_ADDR_LEN),
+        };
+
+        Ok(Net {
+            tap,
+            net_fd,
+            avail_features,
+            acked_features: 0,
+            config_space,
+            epoll_config,
+            interrupt: Some(EventFd::new().map_err(Error::VhostIrqCreate)?),
+        })
+    }
+}
+
+impl VirtioDevice for Net {
+    fn device_type(&self) -> u32 {
+        TYPE_NET
+    }
+
+    fn queue_max_sizes(&self) -> &[u16] {
+        QUEUE_SIZES
+    }
+
+    fn features(&self, page: u32) -> u32 {
+        match page {
+            0 => self.avail_features as u32,
+            1 => (self.avail_features >> 32) as u32,
+            _ => {
+                warn!(
+                    "vhost-net: Received request for unknown features page: {}",
+                    page
+                );
+                0u32
+            }
+        }
+    }
+
+    fn ack_features(&mut self, page: u32, value: u32) {
+        let mut v = match page {
+            0 => value as u64,
+            1 => (value as u64) << 32,
+            _ => {
+                warn!(
+                    "vhost-net: Cannot acknowledge unknown features page: {}",
+                    page
+                );
+                0u64
+            }
+        };
+
+        // Check if the guest is ACK'ing a feature that we didn't claim to have.
+        let unrequested_features = v & !self.avail_features;
+        if unrequested_features != 0 {
+            warn!(
+                "vhost-net: Received acknowledge request for unknown feature: {:x}",
+                v
+            );
+            // Don't count these features as acked.
+            v &= !unrequested_features;
+        }
+        self.acked_features |= v;
+    }
+
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) {
+        let config_len = self.config_space.len() as u64;
+        if offset >= config_len {
+            error!("vhost-net: Failed to read config space");
+            METRICS.net.cfg_fails.inc();
+            return;
+        }
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            // This write can't fail, offset and end are checked against config_len.
+            data.write(&self.config_space[offset as usize..cmp::min(end, config_len) as usize])
+                .unwrap();
+        }
+    }
+
+    fn write_config(&mut self, offset: u64, data: &[u8]) {
+        let data_len = data.len() as u64;
+        let config_len = self.config_space.len() as u64;
+        if offset + data_len > config_len {
+            error!("vhost-net: Failed to write config space");
+            METRICS.net.cfg_fails.inc();
+            return;
+        }
+        let (_, right) = self.config_space.split_at_mut(offset as usize);
+        right[..data.len()].copy_from_slice(&data[..]);
+    }
+
+    fn activate(
+        &mut self,
+        _: GuestMemory,
+        interrupt_evt: EventFd,
+        interrupt_status: Arc<AtomicUsize>,
+        queues: Vec<Queue>,
+        queue_evts: Vec<EventFd>,
+    ) -> ActivateResult {
+        if queues.len() != NUM_QUEUES || queue_evts.len() != NUM_QUEUES {
+            error!(
+                "Cannot perform activate. Expected {} queue(s), got {}",
+                NUM_QUEUES,
+                queues.len()
+            );
+            METRICS.net.activate_fails.inc();
+            return Err(ActivateError::BadActivate);
+        }
+
+        if let Some(net_fd) = self.net_fd.take() {
+            if let Some(interrupt) = self.interrupt.take() {
+                self.tap
+                    .set_offload(tap_offload_flags(self.acked_features))
+                    .map_err(Error::TapSetOffload)?;
+                self.tap
+                    .set_vnet_hdr_size(vnet_hdr_size(self.acked_features) as i32)
+                    .map_err(Error::TapSetVnetHdrSize)?;
+
+                net_fd.set_owner().map_err(Error::VhostSetOwner)?;
+
+                let avail_features = net_fd.get_features().map_err(Error::VhostGetFeatures)?;
+                let features: u64 = self.acked_features & avail_features;
+                net_fd
+                    .set_features(features)
+                    .map_err(Error::VhostSetFeatures)?;
+
+                net_fd.set_mem_table().map_err(Error::VhostSetMemTable)?;
+
+                for (queue_index, ref queue) in queues.iter().enumerate() {
+                    net_fd
+                        .set_vring_num(queue_index, queue.actual_size())
+                        .map_err(Error::VhostSetVringNum)?;
+                    net_fd
+                        .set_vring_addr(
+                            QUEUE_SIZES[queue_index],
+                            queue.actual_size(),
+                            queue_index,
+                            0,
+                            queue.desc_table,
+                            queue.used_ring,
+                            queue.avail_ring,
+                            None,
+                        )
+                        .map_err(Error::VhostSetVringAddr)?;
+                    // The queue resumes where the restored driver expects it to.
+                    net_fd
+                        .set_vring_base(queue_index, queue.next_avail())
+                        .map_err(Error::VhostSetVringBase)?;
+                    net_fd
+                        .set_vring_call(queue_index, &interrupt)
+                        .map_err(Error::VhostSetVringCall)?;
+                    net_fd
+                        .set_vring_kick(queue_index, &queue_evts[queue_index])
+                        .map_err(Error::VhostSetVringKick)?;
+                    // The kernel starts servicing the queue once it has a backend.
+                    net_fd
+                        .set_backend(queue_index, Some(&self.tap))
+                        .map_err(Error::VhostNetSetBackend)?;
+                }
+
+                let handler =
+                    VhostEpollHandler::new(net_fd, interrupt_status, interrupt_evt, interrupt);
+
+                let queue_evt_raw_fd = handler.get_queue_evt();
+                //channel should be open and working
+                self.epoll_config
+                    .get_sender()
+                    .send(Box::new(handler))
+                    .unwrap();
+
+                epoll::ctl(
+                    self.epoll_config.get_raw_epoll_fd(),
+                    epoll::EPOLL_CTL_ADD,
+                    queue_evt_raw_fd,
+                    epoll::Event::new(epoll::EPOLLIN, self.epoll_config.get_queue_evt_token()),
+                )
+                .map_err(ActivateError::EpollCtl)?;
+
+                return Ok(());
+            }
+        }
+        METRICS.net.activate_fails.inc();
+        Err(ActivateError::BadActivate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::mpsc;
+
+    use memory_model::GuestAddress;
+    use virtio::queue::tests::VirtQueue;
+
+    // Returns a device with the features of `vhost_features`, and no vhost-net handle. Such a
+    // device never gets to poll its events.
+    fn create_net(vhost_features: u64, guest_mac: Option<&MacAddr>) -> Net {
+        let (sender, _) = mpsc::channel();
+        let epoll_config = VhostEpollConfig::new(0, -1, sender);
+        Net::new(
+            Tap::new().unwrap(),
+            None,
+            vhost_features,
+            guest_mac,
+            epoll_config,
+        )
+        .unwrap()
+    }
+
+    fn activate_net(net: &mut Net, num_queues: usize) -> ActivateResult {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let rxq = VirtQueue::new(GuestAddress(0), &mem, 16);
+        let txq = VirtQueue::new(GuestAddress(0x1000), &mem, 16);
+        let mut queues = vec![rxq.create_queue(), txq.create_queue()];
+        queues.truncate(num_queues);
+        let queue_evts = queues.iter().map(|_| EventFd::new().unwrap()).collect();
+        net.activate(
+            mem.clone(),
+            EventFd::new().unwrap(),
+            Arc::new(AtomicUsize::new(0)),
+            queues,
+            queue_evts,
+        )
+    }
+
+    #[test]
+    fn test_features() {
+        let mac = MacAddr::parse_str("11:22:33:44:55:66").unwrap();
+        // The kernel supports version 1 and a feature the device doesn't offer.
+        let vhost_features = 1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_NET_F_CTRL_VQ;
+        let mut net = create_net(vhost_features, Some(&mac));
+
+        let offloads: u64 = 1 << VIRTIO_NET_F_GUEST_CSUM
+            | 1 << VIRTIO_NET_F_CSUM
+            | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_TSO6
+            | 1 << VIRTIO_NET_F_GUEST_UFO
+            | 1 << VIRTIO_NET_F_HOST_TSO4
+            | 1 << VIRTIO_NET_F_HOST_TSO6
+            | 1 << VIRTIO_NET_F_HOST_UFO;
+        let avail_features = offloads | 1 << VIRTIO_NET_F_MAC | 1 << VIRTIO_F_VERSION_1;
+        assert_eq!(net.features(0), avail_features as u32);
+        assert_eq!(net.features(1), (avail_features >> 32) as u32);
+        assert_eq!(net.features(2), 0);
+
+        // The features the device didn't offer are not acked.
+        net.ack_features(
+            0,
+            (1 << VIRTIO_NET_F_CSUM | 1 << VIRTIO_NET_F_MRG_RXBUF) as u32,
+        );
+        net.ack_features(1, 1 << (VIRTIO_F_VERSION_1 - 32));
+        net.ack_features(2, 0xffff_ffff);
+        assert_eq!(
+            net.acked_features,
+            1 << VIRTIO_NET_F_CSUM | 1 << VIRTIO_F_VERSION_1
+        );
+
+        // Without a MAC address, the device has no config space to offer.
+        let net = create_net(vhost_features, None);
+        assert_eq!(net.features(0), (offloads | 1 << VIRTIO_F_VERSION_1) as u32);
+    }
+
+    #[test]
+    fn test_config() {
+        let mac = MacAddr::parse_str("11:22:33:44:55:66").unwrap();
+        let mut net = create_net(VHOST_NET_FEATURES, Some(&mac));
+
+        let mut data = [0u8; MAC_ADDR_LEN];
+        net.read_config(0, &mut data);
+        assert_eq!(data, [0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        net.write_config(4, &[0x77, 0x88]);
+        net.read_config(4, &mut data[..2]);
+        assert_eq!(data[..2], [0x77, 0x88]);
+
+        // The accesses past the config space are ignored.
+        let cfg_fails = METRICS.net.cfg_fails.count();
+        net.write_config(5, &[0x99, 0x99]);
+        net.read_config(MAC_ADDR_LEN as u64, &mut data);
+        assert_eq!(METRICS.net.cfg_fails.count(), cfg_fails + 2);
+        net.read_config(0, &mut data);
+        assert_eq!(data, [0x11, 0x22, 0x33, 0x44, 0x77, 0x88]);
+    }
+
+    #[test]
+    fn test_activate() {
+        let mut net = create_net(VHOST_NET_FEATURES, None);
+        let activate_fails = METRICS.net.activate_fails.count();
+
+        // The device has one receive and one transmit queue.
+        match activate_net(&mut net, 1) {
+            Err(ActivateError::BadActivate) => (),
+            _ => assert!(false),
+        }
+        // The device cannot be activated without a vhost-net handle.
+        match activate_net(&mut net, NUM_QUEUES) {
+            Err(ActivateError::BadActivate) => (),
+            _ => assert!(false),
+        }
+        assert_eq!(METRICS.net.activate_fails.count(), activate_fails + 2);
+    }
+
+    #[test]
+    #[ignore = "requires /dev/vhost-net"]
+    fn test_activate_vhost_net() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let (sender, receiver) = mpsc::channel();
+        let epoll_config = VhostEpollConfig::new(0, epoll::create(true).unwrap(), sender);
+        let mut net = Net::new_with_tap(Tap::new().unwrap(), None, &mem, epoll_config).unwrap();
+
+        net.ack_features(0, net.features(0));
+        net.ack_features(1, net.features(1));
+        assert!(activate_net(&mut net, NUM_QUEUES).is_ok());
+        // The handler is handed to the event loop, and the device can only be activated once.
+        assert!(receiver.try_recv().is_ok());
+        match activate_net(&mut net, NUM_QUEUES) {
+            Err(ActivateError::BadActivate) => (),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_vnet_hdr_size() {
+        assert_eq!(vnet_hdr_size(0), 10);
+        assert_eq!(vnet_hdr_size(1 << VIRTIO_NET_F_CSUM), 10);
+        assert_eq!(vnet_hdr_size(1 << VIRTIO_NET_F_MRG_RXBUF), 12);
+        assert_eq!(vnet_hdr_size(1 << VIRTIO_F_VERSION_1), 12);
+    }
+}
@@ -87,6 +87,19 @@ impl<'a, T: NetworkBytes> EthIPv4ArpFrame<'a, T> {
     /// If no error occurs, it guarantees accessor methods (which make use of various `_unchecked`
     /// functions) are safe to call on the result, because all predefined offsets will be valid.
     pub fn request_from_bytes(bytes: T) -> Result<Self, Error> {
+        let maybe = EthIPv4ArpFrame::from_bytes(bytes)?;
+
+        if maybe.operation() != OPER_REQUEST {
+            return Err(Error::Operation);
+        }
+
+        Ok(maybe)
+    }
+
+    /// Tries to interpret a byte slice as a valid IPv4 over Ethernet ARP request or reply.
+    ///
+    /// The same guarantees as for `request_from_bytes` apply to the result.
+    pub fn from_bytes(bytes: T) -> Result<Self, Error> {
         // This kind of frame has a fixed length, so we know what to expect.
         if bytes.len() != ETH_IPV4_FRAME_LEN {
             return Err(Error::SliceExactLen);
@@ -111,7 +124,7 @@ impl<'a, T: NetworkBytes> EthIPv4ArpFrame<'a, T> {
             return Err(Error::PLen);
         }
 
-        if maybe.operation() != OPER_REQUEST {
+        if maybe.operation() != OPER_REQUEST && maybe.operation() != OPER_REPLY {
             return Err(Error::Operation);
         }
 
@@ -176,7 +189,7 @@ impl<'a, T: NetworkBytes> EthIPv4ArpFrame<'a, T> {
     #[inline]
     pub fn len(&self) -> usize {
         // This might as well return ETH_IPV4_FRAME_LEN directly, since we check this is the actual
-        // length in request_from_bytes(). For some reason it seems nicer leaving it as is.
+        // length in from_bytes(). For some reason it seems nicer leaving it as is.
         self.bytes.len()
     }
 }
@@ -401,6 +414,12 @@ mod tests {
             EthIPv4ArpFrame::request_from_bytes(&a[..ETH_IPV4_FRAME_LEN]).unwrap_err(),
             Error::Operation
         );
+        assert_eq!(
+            EthIPv4ArpFrame::from_bytes(&a[..ETH_IPV4_FRAME_LEN])
+                .unwrap()
+                .spa(),
+            spa
+        );
 
         // TODO: The following test code is way more verbose than it should've been. Make it
         // prettier at some point.
@@ -420,9 +439,29 @@ mod tests {
         )
         .unwrap();
         assert!(EthIPv4ArpFrame::request_from_bytes(&a[..ETH_IPV4_FRAME_LEN]).is_ok());
+        assert!(EthIPv4ArpFrame::from_bytes(&a[..ETH_IPV4_FRAME_LEN]).is_ok());
 
         // Now we start writing invalid requests. We've already tried with an invalid operation.
 
+        // Neither a request nor a reply.
+        EthIPv4ArpFrame::write_raw(
+            &mut a[..ETH_IPV4_FRAME_LEN],
+            HTYPE_ETHERNET,
+            ETHERTYPE_IPV4,
+            MAC_ADDR_LEN as u8,
+            IPV4_ADDR_LEN as u8,
+            OPER_REPLY + 1,
+            sha,
+            spa,
+            tha,
+            tpa,
+        )
+        .unwrap();
+        assert_eq!(
+            EthIPv4ArpFrame::from_bytes(&a[..ETH_IPV4_FRAME_LEN]).unwrap_err(),
+            Error::Operation
+        );
+
         // Invalid htype.
         EthIPv4ArpFrame::write_raw(
             &mut a[..ETH_IPV4_FRAME_LEN],
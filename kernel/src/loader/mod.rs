@@ -6,6 +6,7 @@
 // found in the THIRD-PARTY file.
 
 use std;
+use std::cmp;
 use std::ffi::CStr;
 use std::io::{Read, Seek, SeekFrom};
 use std::mem;
@@ -13,6 +14,7 @@ use std::mem;
 use memory_model::{GuestAddress, GuestMemory};
 use sys_util;
 use x86_64;
+use x86_64::{BootProtocol, InitrdConfig, SetupHeader};
 
 #[allow(non_camel_case_types)]
 mod elf;
@@ -20,31 +22,177 @@ mod elf;
 #[derive(Debug, PartialEq)]
 pub enum Error {
     BigEndianElfOnLittle,
+    BzImageTooLarge,
     CommandLineCopy,
     CommandLineOverflow,
+    InitrdImageTooLarge,
+    InvalidBzImageLoadAddress,
+    InvalidBzImageProtocol,
     InvalidElfMagicNumber,
     InvalidEntryAddress,
     InvalidProgramHeaderSize,
     InvalidProgramHeaderOffset,
     InvalidProgramHeaderAddress,
+    InvalidPvhNote,
     ReadElfHeader,
+    ReadInitrdImage,
     ReadKernelImage,
+    ReadNoteHeader,
     ReadProgramHeader,
+    ReadSetupHeader,
     SeekKernelStart,
     SeekElfStart,
+    SeekInitrdImage,
+    SeekNoteHeader,
     SeekProgramHeader,
+    SeekSetupHeader,
 }
 pub type Result<T> = std::result::Result<T, Error>;
 
-/// Loads a kernel from a vmlinux elf image to a slice
+// Offset of the setup header in a bzImage.
+const SETUP_HEADER_OFFSET: u64 = 0x1f1;
+// "HdrS", found in the `header` field of the setup header.
+const SETUP_HEADER_MAGIC: u32 = 0x5372_6448;
+// The oldest boot protocol which advertises a 64-bit entry point in `xloadflags`.
+const MIN_BOOT_PROTOCOL: u16 = 0x020c;
+// The protected mode kernel must be loaded high.
+const LOADED_HIGH: u8 = 0x1;
+// The kernel has a 64-bit entry point, 0x200 bytes past its load address.
+const XLF_KERNEL_64: u16 = 0x1;
+const KERNEL_64BIT_ENTRY_OFFSET: usize = 0x200;
+
+// The ELF note which holds the 32-bit entry point of a kernel that can be booted through PVH.
+const XEN_ELFNOTE_PHYS32_ENTRY: u32 = 18;
+
+/// Where a kernel image was loaded and what it needs from the boot parameters.
+#[derive(Debug)]
+pub struct KernelLoaderResult {
+    /// Address at which the vCPUs start executing the kernel.
+    pub entry_addr: GuestAddress,
+    /// End of the memory the kernel occupies, past which the initrd can be placed.
+    pub kernel_end: GuestAddress,
+    /// The protocol the kernel expects to be booted through.
+    pub boot_prot: BootProtocol,
+    /// The setup header of a bzImage, to be copied into the zero page. `None` for ELF images.
+    pub setup_header: Option<SetupHeader>,
+}
+
+/// Loads a kernel from either a vmlinux elf image or a bzImage to a slice. The format is
+/// detected from the magic numbers of the image.
 ///
 /// # Arguments
 ///
 /// * `guest_mem` - The guest memory region the kernel is written to.
-/// * `kernel_image` - Input vmlinux image.
-///
-/// Returns the entry address of the kernel.
-pub fn load_kernel<F>(guest_mem: &GuestMemory, kernel_image: &mut F) -> Result<GuestAddress>
+/// * `kernel_image` - Input vmlinux image or bzImage.
+pub fn load_kernel<F>(guest_mem: &GuestMemory, kernel_image: &mut F) -> Result<KernelLoaderResult>
+where
+    F: Read + Seek,
+{
+    match read_setup_header(kernel_image)? {
+        Some(setup_header) => load_bzimage(guest_mem, kernel_image, setup_header),
+        None => load_elf(guest_mem, kernel_image),
+    }
+}
+
+/// Reads the setup header of a bzImage. Returns `None` if the image is too short to hold one or
+/// if the header magic number does not match.
+fn read_setup_header<F>(kernel_image: &mut F) -> Result<Option<SetupHeader>>
+where
+    F: Read + Seek,
+{
+    let mut setup_header: SetupHeader = Default::default();
+    kernel_image
+        .seek(SeekFrom::Start(SETUP_HEADER_OFFSET))
+        .map_err(|_| Error::SeekSetupHeader)?;
+    unsafe {
+        // read_struct is safe when reading a POD struct.  It can be used and dropped without issue.
+        if sys_util::read_struct(kernel_image, &mut setup_header).is_err() {
+            return Ok(None);
+        }
+    }
+
+    if setup_header.header != SETUP_HEADER_MAGIC {
+        return Ok(None);
+    }
+    Ok(Some(setup_header))
+}
+
+/// Loads the protected mode part of a bzImage. The kernel goes at its preferred address, unless
+/// it is relocatable and that address does not leave room for `init_size` bytes, in which case
+/// it goes at the first address past HIMEM_START with the minimum alignment it supports.
+fn load_bzimage<F>(
+    guest_mem: &GuestMemory,
+    kernel_image: &mut F,
+    mut setup_header: SetupHeader,
+) -> Result<KernelLoaderResult>
+where
+    F: Read + Seek,
+{
+    if setup_header.version < MIN_BOOT_PROTOCOL
+        || setup_header.loadflags & LOADED_HIGH == 0
+        || setup_header.xloadflags & XLF_KERNEL_64 == 0
+    {
+        return Err(Error::InvalidBzImageProtocol);
+    }
+
+    // A value of 0 stands for the historical 4 sectors. The boot sector comes before them.
+    let setup_sects = match setup_header.setup_sects {
+        0 => 4,
+        sects => sects as u64,
+    };
+    let kernel_offset = (setup_sects + 1) * 512;
+    let kernel_size = kernel_image
+        .seek(SeekFrom::End(0))
+        .map_err(|_| Error::SeekKernelStart)?
+        .checked_sub(kernel_offset)
+        .ok_or(Error::ReadKernelImage)? as usize;
+    // The kernel decompresses itself in place and needs `init_size` bytes from where it's loaded.
+    let load_size = cmp::max(kernel_size, setup_header.init_size as usize);
+
+    let lowmem_end = cmp::min(guest_mem.end_addr().offset(), x86_64::get_32bit_gap_start());
+    let fits = |addr: usize| {
+        addr >= x86_64::layout::HIMEM_START
+            && addr
+                .checked_add(load_size)
+                .map_or(false, |end| end <= lowmem_end)
+    };
+
+    let mut load_addr = setup_header.pref_address as usize;
+    if !fits(load_addr) {
+        if setup_header.relocatable_kernel == 0 {
+            return Err(Error::InvalidBzImageLoadAddress);
+        }
+        let align = 1usize
+            .checked_shl(u32::from(setup_header.min_alignment))
+            .ok_or(Error::InvalidBzImageLoadAddress)?;
+        load_addr = x86_64::layout::HIMEM_START
+            .checked_add(align - 1)
+            .ok_or(Error::InvalidBzImageLoadAddress)?
+            & !(align - 1);
+        if !fits(load_addr) {
+            return Err(Error::BzImageTooLarge);
+        }
+    }
+
+    kernel_image
+        .seek(SeekFrom::Start(kernel_offset))
+        .map_err(|_| Error::SeekKernelStart)?;
+    guest_mem
+        .read_to_memory(GuestAddress(load_addr), kernel_image, kernel_size)
+        .map_err(|_| Error::ReadKernelImage)?;
+
+    setup_header.code32_start = load_addr as u32;
+    Ok(KernelLoaderResult {
+        entry_addr: GuestAddress(load_addr + KERNEL_64BIT_ENTRY_OFFSET),
+        kernel_end: GuestAddress(load_addr + load_size),
+        boot_prot: BootProtocol::LinuxBoot,
+        setup_header: Some(setup_header),
+    })
+}
+
+/// Loads a kernel from a vmlinux elf image to a slice. If the image has a PVH entry point, the
+/// kernel is booted through PVH, otherwise through the 64-bit Linux boot protocol.
+fn load_elf<F>(guest_mem: &GuestMemory, kernel_image: &mut F) -> Result<KernelLoaderResult>
 where
     F: Read + Seek,
 {
@@ -108,7 +256,138 @@ where
             .map_err(|_| Error::ReadKernelImage)?;
     }
 
-    Ok(GuestAddress(ehdr.e_entry as usize))
+    // The segments without data in the image are still part of the kernel memory.
+    let kernel_end = phdrs
+        .iter()
+        .filter(|phdr| (phdr.p_type & elf::PT_LOAD) != 0)
+        .map(|phdr| phdr.p_paddr.saturating_add(phdr.p_memsz) as usize)
+        .max()
+        .unwrap_or(x86_64::layout::HIMEM_START);
+
+    for phdr in phdrs.iter().filter(|phdr| phdr.p_type == elf::PT_NOTE) {
+        if let Some(pvh_entry_addr) = parse_pvh_note(phdr, kernel_image)? {
+            if pvh_entry_addr.offset() < x86_64::layout::HIMEM_START {
+                return Err(Error::InvalidEntryAddress);
+            }
+            return Ok(KernelLoaderResult {
+                entry_addr: pvh_entry_addr,
+                kernel_end: GuestAddress(kernel_end),
+                boot_prot: BootProtocol::PvhBoot,
+                setup_header: None,
+            });
+        }
+    }
+
+    Ok(KernelLoaderResult {
+        entry_addr: GuestAddress(ehdr.e_entry as usize),
+        kernel_end: GuestAddress(kernel_end),
+        boot_prot: BootProtocol::LinuxBoot,
+        setup_header: None,
+    })
+}
+
+/// Looks for the XEN_ELFNOTE_PHYS32_ENTRY note in the note segment described by `phdr`.
+///
+/// Returns the PVH entry point, if the note is found.
+fn parse_pvh_note<F>(phdr: &elf::Elf64_Phdr, kernel_image: &mut F) -> Result<Option<GuestAddress>>
+where
+    F: Read + Seek,
+{
+    // The name and the descriptor of a note are both padded to 4 bytes.
+    fn align4(size: u32) -> usize {
+        (size as usize + 3) & !3
+    }
+
+    kernel_image
+        .seek(SeekFrom::Start(phdr.p_offset))
+        .map_err(|_| Error::SeekNoteHeader)?;
+
+    let mut read_size = 0;
+    while read_size < phdr.p_filesz as usize {
+        let mut nhdr: elf::Elf64_Nhdr = Default::default();
+        unsafe {
+            // read_struct is safe when reading a POD struct.  It can be used and dropped without issue.
+            sys_util::read_struct(kernel_image, &mut nhdr).map_err(|_| Error::ReadNoteHeader)?;
+        }
+        let mut name = vec![0u8; align4(nhdr.n_namesz)];
+        kernel_image
+            .read_exact(&mut name)
+            .map_err(|_| Error::ReadNoteHeader)?;
+        read_size += mem::size_of::<elf::Elf64_Nhdr>() + name.len() + align4(nhdr.n_descsz);
+
+        if nhdr.n_type == XEN_ELFNOTE_PHYS32_ENTRY && name.starts_with(b"Xen\0") {
+            // The descriptor is the address itself, 32 or 64 bits wide depending on the kernel.
+            if nhdr.n_descsz < mem::size_of::<u32>() as u32 {
+                return Err(Error::InvalidPvhNote);
+            }
+            let mut entry_addr: u32 = 0;
+            unsafe {
+                // read_struct is safe when reading a POD struct.  It can be used and dropped without issue.
+                sys_util::read_struct(kernel_image, &mut entry_addr)
+                    .map_err(|_| Error::ReadNoteHeader)?;
+            }
+            return Ok(Some(GuestAddress(entry_addr as usize)));
+        }
+
+        kernel_image
+            .seek(SeekFrom::Current(align4(nhdr.n_descsz) as i64))
+            .map_err(|_| Error::SeekNoteHeader)?;
+    }
+
+    Ok(None)
+}
+
+/// Loads an initrd image right below the end of the guest memory that is addressable with 32 bits,
+/// so that the kernel can access it early during boot.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The guest memory region the initrd is written to.
+/// * `initrd_image` - Input initrd (or initramfs) image.
+/// * `kernel_end` - The end of the memory occupied by the kernel, which the initrd must not
+///                  overlap.
+/// * `initrd_addr_max` - The highest address the initrd may occupy, as declared by the
+///                       `initrd_addr_max` field of the setup header of a bzImage.
+///
+/// Returns the location of the initrd in guest memory.
+pub fn load_initrd<F>(
+    guest_mem: &GuestMemory,
+    initrd_image: &mut F,
+    kernel_end: GuestAddress,
+    initrd_addr_max: Option<usize>,
+) -> Result<InitrdConfig>
+where
+    F: Read + Seek,
+{
+    const PAGE_SIZE: usize = 0x1000;
+
+    let size = initrd_image
+        .seek(SeekFrom::End(0))
+        .map_err(|_| Error::SeekInitrdImage)? as usize;
+    initrd_image
+        .seek(SeekFrom::Start(0))
+        .map_err(|_| Error::SeekInitrdImage)?;
+
+    // The initrd goes at the end of the low memory, aligned down to a page boundary. It must not
+    // overlap with anything placed below HIMEM_START, nor with the kernel.
+    let lowmem_start = cmp::max(x86_64::layout::HIMEM_START, kernel_end.offset());
+    let mut lowmem_end = cmp::min(guest_mem.end_addr().offset(), x86_64::get_32bit_gap_start());
+    if let Some(addr_max) = initrd_addr_max {
+        lowmem_end = cmp::min(lowmem_end, addr_max.saturating_add(1));
+    }
+    let address = match lowmem_end.checked_sub(size) {
+        Some(addr) if addr & !(PAGE_SIZE - 1) >= lowmem_start => addr & !(PAGE_SIZE - 1),
+        _ => return Err(Error::InitrdImageTooLarge),
+    };
+
+    guest_mem
+        .read_to_memory(GuestAddress(address), initrd_image, size)
+        .map_err(|_| Error::ReadInitrdImage)?;
+
+    Ok(InitrdConfig {
+        address: GuestAddress(address),
+        size,
+    })
 }
 
 /// Writes the command line string to the given memory slice.
@@ -207,9 +486,182 @@ mod tests {
     fn load_elf() {
         let gm = create_guest_mem();
         let image = make_elf_bin();
+        let result = load_kernel(&gm, &mut Cursor::new(&image)).unwrap();
+        assert_eq!(GuestAddress(0x100000), result.entry_addr);
+        assert!(result.kernel_end > result.entry_addr);
+        assert_eq!(BootProtocol::LinuxBoot, result.boot_prot);
+        assert!(result.setup_header.is_none());
+    }
+
+    #[test]
+    fn pvh_note() {
+        fn push_note(notes: &mut Vec<u8>, name: &[u8], n_type: u32, desc: &[u8]) {
+            for word in &[name.len() as u32, desc.len() as u32, n_type] {
+                for i in 0..4 {
+                    notes.push((word >> (8 * i)) as u8);
+                }
+            }
+            for field in &[name, desc] {
+                notes.extend_from_slice(field);
+                while notes.len() % 4 != 0 {
+                    notes.push(0);
+                }
+            }
+        }
+
+        let mut notes = Vec::new();
+        push_note(&mut notes, b"GNU\0", 3, &[0xaa; 20]);
+        push_note(&mut notes, b"Xen\0", 5, b"linux");
+        let mut phdr = elf::Elf64_Phdr {
+            p_type: elf::PT_NOTE,
+            p_filesz: notes.len() as u64,
+            ..Default::default()
+        };
+        assert_eq!(Ok(None), parse_pvh_note(&phdr, &mut Cursor::new(&notes)));
+
+        // A 64-bit kernel stores the entry point as a quad word.
+        push_note(
+            &mut notes,
+            b"Xen\0",
+            XEN_ELFNOTE_PHYS32_ENTRY,
+            &[0x00, 0x10, 0x20, 0x01, 0, 0, 0, 0],
+        );
+        phdr.p_filesz = notes.len() as u64;
+        assert_eq!(
+            Ok(Some(GuestAddress(0x0120_1000))),
+            parse_pvh_note(&phdr, &mut Cursor::new(&notes))
+        );
+    }
+
+    // Minimal bzImage made of one setup sector and a protected mode kernel of `kernel_size` bytes.
+    fn make_bzimage(kernel_size: usize, pref_address: u64, relocatable: bool) -> Vec<u8> {
+        fn put(image: &mut [u8], offset: usize, val: u64, len: usize) {
+            for i in 0..len {
+                image[offset + i] = (val >> (8 * i)) as u8;
+            }
+        }
+
+        let mut image = vec![0u8; 2 * 512 + kernel_size];
+        put(&mut image, 0x1f1, 1, 1); // setup_sects
+        put(&mut image, 0x202, u64::from(SETUP_HEADER_MAGIC), 4); // header
+        put(&mut image, 0x206, 0x020f, 2); // version
+        put(&mut image, 0x211, u64::from(LOADED_HIGH), 1); // loadflags
+        put(&mut image, 0x234, relocatable as u64, 1); // relocatable_kernel
+        put(&mut image, 0x235, 21, 1); // min_alignment
+        put(&mut image, 0x236, u64::from(XLF_KERNEL_64), 2); // xloadflags
+        put(&mut image, 0x258, pref_address, 8); // pref_address
+        put(&mut image, 0x260, 0x4000, 4); // init_size
+        for byte in image[2 * 512..].iter_mut() {
+            *byte = 0xcc;
+        }
+        image
+    }
+
+    #[test]
+    fn load_bzimage() {
+        let gm = GuestMemory::new(&vec![(GuestAddress(0x0), 0x40_0000)]).unwrap();
+
+        // The kernel goes at its preferred address.
+        let image = make_bzimage(0x1000, 0x10_0000, false);
+        let result = load_kernel(&gm, &mut Cursor::new(&image)).unwrap();
+        assert_eq!(GuestAddress(0x10_0200), result.entry_addr);
+        // The kernel needs `init_size` bytes to decompress itself.
+        assert_eq!(GuestAddress(0x10_4000), result.kernel_end);
+        let setup_header = result.setup_header.unwrap();
+        assert_eq!({ setup_header.code32_start }, 0x10_0000);
+        assert_eq!({ setup_header.init_size }, 0x4000);
+        let val: u8 = gm.read_obj_from_addr(GuestAddress(0x10_0fff)).unwrap();
+        assert_eq!(val, 0xcc);
+        let val: u8 = gm.read_obj_from_addr(GuestAddress(0x10_1000)).unwrap();
+        assert_eq!(val, 0);
+
+        // A relocatable kernel whose preferred address is past the end of the memory is moved
+        // down, honouring its minimum alignment.
+        let image = make_bzimage(0x1000, 0x100_0000, true);
+        let result = load_kernel(&gm, &mut Cursor::new(&image)).unwrap();
+        assert_eq!(GuestAddress(0x20_0200), result.entry_addr);
+
+        // A kernel that is not relocatable has to fit at its preferred address.
+        let image = make_bzimage(0x1000, 0x100_0000, false);
+        assert_eq!(
+            Some(Error::InvalidBzImageLoadAddress),
+            load_kernel(&gm, &mut Cursor::new(&image)).err()
+        );
+
+        // Not enough room for the kernel to decompress itself.
+        let image = make_bzimage(0x20_1000, 0x100_0000, true);
+        assert_eq!(
+            Some(Error::BzImageTooLarge),
+            load_kernel(&gm, &mut Cursor::new(&image)).err()
+        );
+
+        // Kernels without a 64-bit entry point are rejected.
+        let mut image = make_bzimage(0x1000, 0x10_0000, false);
+        image[0x236] = 0;
+        assert_eq!(
+            Some(Error::InvalidBzImageProtocol),
+            load_kernel(&gm, &mut Cursor::new(&image)).err()
+        );
+    }
+
+    #[test]
+    fn load_initrd_image() {
+        let gm = create_guest_mem();
+        let kernel_end = GuestAddress(x86_64::layout::HIMEM_START);
+        let image = vec![0xaau8; 0x1800];
+        let initrd = load_initrd(&gm, &mut Cursor::new(&image), kernel_end, None).unwrap();
+        // The image is placed at the end of the memory, page aligned.
+        assert_eq!(initrd.address, GuestAddress(MEM_SIZE - 0x2000));
+        assert_eq!(initrd.size, 0x1800);
+        let val: u8 = gm
+            .read_obj_from_addr(initrd.address.unchecked_add(0x17ff))
+            .unwrap();
+        assert_eq!(val, 0xaa);
+
+        // The image stays below the highest address the kernel accepts.
+        let initrd =
+            load_initrd(&gm, &mut Cursor::new(&image), kernel_end, Some(0x14_0fff)).unwrap();
+        assert_eq!(initrd.address, GuestAddress(0x13_f000));
+        let initrd = load_initrd(
+            &gm,
+            &mut Cursor::new(&image),
+            kernel_end,
+            Some(usize::max_value()),
+        )
+        .unwrap();
+        assert_eq!(initrd.address, GuestAddress(MEM_SIZE - 0x2000));
+
+        // The image does not fit above HIMEM_START.
+        let image = vec![0u8; MEM_SIZE];
+        assert_eq!(
+            Err(Error::InitrdImageTooLarge),
+            load_initrd(&gm, &mut Cursor::new(&image), kernel_end, None)
+        );
+        let image = vec![0u8; 0x1000];
+        assert_eq!(
+            Err(Error::InitrdImageTooLarge),
+            load_initrd(&gm, &mut Cursor::new(&image), kernel_end, Some(0x10_0ffe))
+        );
+    }
+
+    #[test]
+    fn load_initrd_above_kernel() {
+        let gm = create_guest_mem();
+        let kernel_end = load_kernel(&gm, &mut Cursor::new(&make_elf_bin()))
+            .unwrap()
+            .kernel_end;
+
+        // The largest image that fits in the pages between the kernel and the end of the memory.
+        let room = (MEM_SIZE - kernel_end.offset()) & !0xfff;
+        let image = vec![0u8; room];
+        let initrd = load_initrd(&gm, &mut Cursor::new(&image), kernel_end, None).unwrap();
+        assert!(initrd.address >= kernel_end);
+
+        // One more page would overlap with the kernel.
+        let image = vec![0u8; room + 0x1000];
         assert_eq!(
-            Ok(GuestAddress(0x100000)),
-            load_kernel(&gm, &mut Cursor::new(&image))
+            Err(Error::InitrdImageTooLarge),
+            load_initrd(&gm, &mut Cursor::new(&image), kernel_end, None)
         );
     }
 
@@ -219,8 +671,8 @@ mod tests {
         let mut bad_image = make_elf_bin();
         bad_image[0x1] = 0x33;
         assert_eq!(
-            Err(Error::InvalidElfMagicNumber),
-            load_kernel(&gm, &mut Cursor::new(&bad_image))
+            Some(Error::InvalidElfMagicNumber),
+            load_kernel(&gm, &mut Cursor::new(&bad_image)).err()
         );
     }
 
@@ -231,8 +683,8 @@ mod tests {
         let mut bad_image = make_elf_bin();
         bad_image[0x5] = 2;
         assert_eq!(
-            Err(Error::BigEndianElfOnLittle),
-            load_kernel(&gm, &mut Cursor::new(&bad_image))
+            Some(Error::BigEndianElfOnLittle),
+            load_kernel(&gm, &mut Cursor::new(&bad_image)).err()
         );
     }
 
@@ -243,8 +695,8 @@ mod tests {
         let mut bad_image = make_elf_bin();
         bad_image[0x20] = 0x10;
         assert_eq!(
-            Err(Error::InvalidProgramHeaderOffset),
-            load_kernel(&gm, &mut Cursor::new(&bad_image))
+            Some(Error::InvalidProgramHeaderOffset),
+            load_kernel(&gm, &mut Cursor::new(&bad_image)).err()
         );
     }
 }
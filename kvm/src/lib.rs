@@ -326,6 +326,127 @@ impl VmFd {
         }
     }
 
+    /// X86 specific call to retrieve the state of an in-kernel interrupt controller.
+    ///
+    /// See the documentation for `KVM_GET_IRQCHIP`.
+    ///
+    /// # Arguments
+    ///
+    /// * `irqchip` - Interrupt controller state. Its `chip_id` selects which controller
+    ///               (master PIC, slave PIC or IOAPIC) is read.
+    ///
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_irqchip(&self, irqchip: &mut kvm_irqchip) -> Result<()> {
+        let ret = unsafe {
+            // Here we trust the kernel not to write past the end of the kvm_irqchip struct.
+            ioctl_with_mut_ref(self, KVM_GET_IRQCHIP(), irqchip)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
+    /// X86 specific call to set the state of an in-kernel interrupt controller.
+    ///
+    /// See the documentation for `KVM_SET_IRQCHIP`.
+    ///
+    /// # Arguments
+    ///
+    /// * `irqchip` - Interrupt controller state, as previously returned by `get_irqchip`.
+    ///
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_irqchip(&self, irqchip: &kvm_irqchip) -> Result<()> {
+        let ret = unsafe {
+            // The ioctl is safe because the kernel will only read from the kvm_irqchip struct.
+            ioctl_with_ref(self, KVM_SET_IRQCHIP(), irqchip)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
+    /// X86 specific call to retrieve the state of the in-kernel PIT model.
+    ///
+    /// See the documentation for `KVM_GET_PIT2`.
+    ///
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_pit2(&self) -> Result<kvm_pit_state2> {
+        let mut pitstate = kvm_pit_state2::default();
+        let ret = unsafe {
+            // Here we trust the kernel not to write past the end of the kvm_pit_state2 struct.
+            ioctl_with_mut_ref(self, KVM_GET_PIT2(), &mut pitstate)
+        };
+        if ret == 0 {
+            Ok(pitstate)
+        } else {
+            errno_result()
+        }
+    }
+
+    /// X86 specific call to set the state of the in-kernel PIT model.
+    ///
+    /// See the documentation for `KVM_SET_PIT2`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pitstate` - PIT state, as previously returned by `get_pit2`.
+    ///
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_pit2(&self, pitstate: &kvm_pit_state2) -> Result<()> {
+        let ret = unsafe {
+            // The ioctl is safe because the kernel will only read from the kvm_pit_state2 struct.
+            ioctl_with_ref(self, KVM_SET_PIT2(), pitstate)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
+    /// Retrieves the current timestamp of kvmclock as seen by the guest.
+    ///
+    /// See the documentation for `KVM_GET_CLOCK`.
+    ///
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_clock(&self) -> Result<kvm_clock_data> {
+        let mut clock = kvm_clock_data::default();
+        let ret = unsafe {
+            // Here we trust the kernel not to write past the end of the kvm_clock_data struct.
+            ioctl_with_mut_ref(self, KVM_GET_CLOCK(), &mut clock)
+        };
+        if ret == 0 {
+            Ok(clock)
+        } else {
+            errno_result()
+        }
+    }
+
+    /// Sets the current timestamp of kvmclock to the value in `clock`.
+    ///
+    /// See the documentation for `KVM_SET_CLOCK`.
+    ///
+    /// # Arguments
+    ///
+    /// * `clock` - Clock data, as previously returned by `get_clock`.
+    ///
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_clock(&self, clock: &kvm_clock_data) -> Result<()> {
+        let ret = unsafe {
+            // The ioctl is safe because the kernel will only read from the kvm_clock_data struct.
+            ioctl_with_ref(self, KVM_SET_CLOCK(), clock)
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            errno_result()
+        }
+    }
+
     /// Registers an event to be signaled whenever a certain address is written to.
     ///
     /// # Arguments
@@ -343,7 +464,33 @@ impl VmFd {
         addr: &IoeventAddress,
         datamatch: T,
     ) -> Result<()> {
-        let mut flags = 0;
+        self.ioeventfd(evt, addr, datamatch, 0)
+    }
+
+    /// Unregisters an event previously registered with `register_ioevent`.
+    ///
+    /// # Arguments
+    ///
+    /// * `evt` - EventFd which was registered.
+    /// * `addr` - Address the event was registered for.
+    /// * `datamatch` - The datamatch the event was registered with, of the same size.
+    ///
+    pub fn unregister_ioevent<T: Into<u64>>(
+        &self,
+        evt: &EventFd,
+        addr: &IoeventAddress,
+        datamatch: T,
+    ) -> Result<()> {
+        self.ioeventfd(evt, addr, datamatch, 1 << kvm_ioeventfd_flag_nr_deassign)
+    }
+
+    fn ioeventfd<T: Into<u64>>(
+        &self,
+        evt: &EventFd,
+        addr: &IoeventAddress,
+        datamatch: T,
+        mut flags: u32,
+    ) -> Result<()> {
         if std::mem::size_of::<T>() > 0 {
             flags |= 1 << kvm_ioeventfd_flag_nr_datamatch
         }
@@ -434,9 +581,37 @@ impl VmFd {
         target_arch = "aarch64"
     ))]
     pub fn register_irqfd(&self, evt: &EventFd, gsi: u32) -> Result<()> {
+        self.irqfd(evt, gsi, 0)
+    }
+
+    /// Unregisters an event previously registered with `register_irqfd`.
+    ///
+    /// # Arguments
+    ///
+    /// * `evt` - Event which was registered.
+    /// * `gsi` - IRQ the event was registered for.
+    ///
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    pub fn unregister_irqfd(&self, evt: &EventFd, gsi: u32) -> Result<()> {
+        self.irqfd(evt, gsi, KVM_IRQFD_FLAG_DEASSIGN)
+    }
+
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    fn irqfd(&self, evt: &EventFd, gsi: u32, flags: u32) -> Result<()> {
         let irqfd = kvm_irqfd {
             fd: evt.as_raw_fd() as u32,
             gsi: gsi,
+            flags,
             ..Default::default()
         };
         // Safe because we know that our file is a VM fd, we know the kernel will only read the
@@ -1076,6 +1251,26 @@ mod tests {
         assert!(vm.create_pit2().is_ok());
     }
 
+    #[test]
+    fn get_set_irqchip_and_pit2() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mut irqchip = kvm_irqchip::default();
+        irqchip.chip_id = KVM_IRQCHIP_IOAPIC;
+        // Without an in-kernel irqchip there is nothing to retrieve.
+        assert!(vm.get_irqchip(&mut irqchip).is_err());
+        assert!(vm.get_pit2().is_err());
+
+        vm.create_irq_chip().unwrap();
+        vm.create_pit2().unwrap();
+        vm.get_irqchip(&mut irqchip).unwrap();
+        assert!(vm.set_irqchip(&irqchip).is_ok());
+        let pitstate = vm.get_pit2().unwrap();
+        assert!(vm.set_pit2(&pitstate).is_ok());
+        let clock = vm.get_clock().unwrap();
+        assert!(vm.set_clock(&clock).is_ok());
+    }
+
     #[test]
     fn register_ioevent() {
         assert_eq!(std::mem::size_of::<NoDatamatch>(), 0);
@@ -1101,6 +1296,17 @@ mod tests {
         vm_fd
             .register_ioevent(&evtfd, &IoeventAddress::Pio(0xc8), 0xdeadbeefdeadbeefu64)
             .unwrap();
+
+        vm_fd
+            .unregister_ioevent(&evtfd, &IoeventAddress::Mmio(0x1000), NoDatamatch)
+            .unwrap();
+        vm_fd
+            .unregister_ioevent(&evtfd, &IoeventAddress::Pio(0xc2), 0x1337u16)
+            .unwrap();
+        // Only registered events can be unregistered.
+        vm_fd
+            .unregister_ioevent(&evtfd, &IoeventAddress::Pio(0xc2), 0x1337u16)
+            .unwrap_err();
     }
 
     #[test]
@@ -1115,6 +1321,9 @@ mod tests {
         vm_fd.register_irqfd(&evtfd3, 4).unwrap();
         vm_fd.register_irqfd(&evtfd3, 4).unwrap_err();
         vm_fd.register_irqfd(&evtfd3, 5).unwrap_err();
+
+        vm_fd.unregister_irqfd(&evtfd3, 4).unwrap();
+        vm_fd.register_irqfd(&evtfd3, 5).unwrap();
     }
 
     #[test]
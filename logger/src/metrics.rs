@@ -24,7 +24,9 @@
 //! If if turns out this approach is not really what we want, it's pretty easy to resort to
 //! something else, while working behind the same interface.
 
+use std::collections::BTreeMap;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
 use chrono;
 use serde::{Serialize, Serializer};
@@ -135,6 +137,15 @@ pub struct ApiServerMetrics {
     pub sync_vmm_send_timeout_count: SharedMetric,
 }
 
+/// Metrics specific to DELETE API Requests for counting user triggered actions and/or failures.
+#[derive(Default, Serialize)]
+pub struct DeleteRequestsMetrics {
+    /// Number of tries to DELETE a block device.
+    pub drive_count: SharedMetric,
+    /// Number of failures in DELETEing a block device.
+    pub drive_fails: SharedMetric,
+}
+
 /// Metrics specific to GET API Requests for counting user triggered actions and/or failures.
 #[derive(Default, Serialize)]
 pub struct GetRequestsMetrics {
@@ -175,6 +186,18 @@ pub struct PutRequestsMetrics {
     pub network_count: SharedMetric,
     /// Number of failures in creating a new network interface.
     pub network_fails: SharedMetric,
+    /// Number of PUTs for creating a new rate-limit group.
+    pub rate_limit_group_count: SharedMetric,
+    /// Number of failures in creating a new rate-limit group.
+    pub rate_limit_group_fails: SharedMetric,
+    /// Number of PUTs for creating a snapshot of the microVM.
+    pub snapshot_create_count: SharedMetric,
+    /// Number of failures in creating a snapshot of the microVM.
+    pub snapshot_create_fails: SharedMetric,
+    /// Number of PUTs for restoring the microVM from a snapshot.
+    pub snapshot_load_count: SharedMetric,
+    /// Number of failures in restoring the microVM from a snapshot.
+    pub snapshot_load_fails: SharedMetric,
 }
 
 /// Metrics specific to PATCH API Requests for counting user triggered actions and/or failures.
@@ -184,6 +207,14 @@ pub struct PatchRequestsMetrics {
     pub drive_count: SharedMetric,
     /// Number of failures in PATCHing a block device.
     pub drive_fails: SharedMetric,
+    /// Number of tries to PATCH a net device.
+    pub network_count: SharedMetric,
+    /// Number of failures in PATCHing a net device.
+    pub network_fails: SharedMetric,
+    /// Number of tries to pause or resume the microVM.
+    pub vm_count: SharedMetric,
+    /// Number of failures in pausing or resuming the microVM.
+    pub vm_fails: SharedMetric,
 }
 
 /// Block Device associated metrics.
@@ -201,10 +232,20 @@ pub struct BlockDeviceMetrics {
     pub invalid_reqs_count: SharedMetric,
     /// Number of flushes operation triggered on this block device.
     pub flush_count: SharedMetric,
+    /// Number of discard requests handled by this block device.
+    pub discard_count: SharedMetric,
+    /// Number of write zeroes requests handled by this block device.
+    pub write_zeroes_count: SharedMetric,
     /// Number of events triggerd on the queue of this block device.
     pub queue_event_count: SharedMetric,
     /// Number of events ratelimiter-related.
     pub rate_limiter_event_count: SharedMetric,
+    /// Number of requests throttled by the rate limiter shared by reads and writes.
+    pub rate_limiter_throttled_events: SharedMetric,
+    /// Number of reads throttled by the read rate limiter.
+    pub read_rate_limiter_throttled_events: SharedMetric,
+    /// Number of writes throttled by the write rate limiter.
+    pub write_rate_limiter_throttled_events: SharedMetric,
     /// Number of update operation triggered on this block device.
     pub update_count: SharedMetric,
     /// Number of failures while doing update on this block device.
@@ -275,6 +316,12 @@ pub struct NetDeviceMetrics {
     pub activate_fails: SharedMetric,
     /// Number of times when interacting with the space config of a network device failed.
     pub cfg_fails: SharedMetric,
+    /// Number of frames that could not be written to the capture file, each stopping the capture.
+    pub capture_fails: SharedMetric,
+    /// Number of events associated with the control queue.
+    pub ctrl_queue_event_count: SharedMetric,
+    /// Number of control queue commands that failed or were not supported.
+    pub ctrl_fails: SharedMetric,
     /// Number of times when handling events on a network device failed.
     pub event_fails: SharedMetric,
     /// Number of events associated with the receiving queue.
@@ -295,10 +342,63 @@ pub struct NetDeviceMetrics {
     pub tx_fails: SharedMetric,
     /// Number of transmitted packets.
     pub tx_packets_count: SharedMetric,
+    /// Number of frames sent by the guest which were dropped for an IPv4 or ARP sender address
+    /// it was not allowed to use.
+    pub tx_spoofed_ip_drops: SharedMetric,
+    /// Number of frames sent by the guest which were dropped for a source MAC address other than
+    /// its own.
+    pub tx_spoofed_mac_drops: SharedMetric,
     /// Number of events associated with the transmitting queue.
     pub tx_queue_event_count: SharedMetric,
     /// Number of events associated with the rate limiter installed on the transmitting path.
     pub tx_rate_limiter_event_count: SharedMetric,
+    /// Number of frames received from the tap or MMDS which were dropped by a firewall rule.
+    pub rx_firewall_drops: SharedMetric,
+    /// Number of frames sent by the guest which were dropped by a firewall rule.
+    pub tx_firewall_drops: SharedMetric,
+}
+
+/// The hit counters of the firewall rules of a network interface, in the order of the rules.
+#[derive(Default, Serialize)]
+pub struct NetFirewallRuleMetrics {
+    /// Number of received frames matched by each RX rule.
+    #[serde(serialize_with = "serialize_counters")]
+    pub rx_rule_hits: Vec<Arc<SharedMetric>>,
+    /// Number of sent frames matched by each TX rule.
+    #[serde(serialize_with = "serialize_counters")]
+    pub tx_rule_hits: Vec<Arc<SharedMetric>>,
+}
+
+fn serialize_counters<S: Serializer>(
+    counters: &[Arc<SharedMetric>],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(counters.iter().map(|counter| &**counter))
+}
+
+/// The hit counters of the firewall rules of the network interfaces, by interface ID. Unlike the
+/// other metrics, the set of counters changes with the rules.
+#[derive(Default)]
+pub struct NetFirewallMetrics(RwLock<BTreeMap<String, NetFirewallRuleMetrics>>);
+
+impl NetFirewallMetrics {
+    /// Sets the counters of the rules of the `iface_id` network interface, replacing the ones
+    /// of its previous rules.
+    pub fn set(&self, iface_id: &str, metrics: NetFirewallRuleMetrics) {
+        self.0
+            .write()
+            .expect("Failed to set firewall metrics due to poisoned lock")
+            .insert(iface_id.to_string(), metrics);
+    }
+}
+
+impl Serialize for NetFirewallMetrics {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0
+            .read()
+            .expect("Failed to serialize firewall metrics due to poisoned lock")
+            .serialize(serializer)
+    }
 }
 
 /// Metrics for the seccomp filtering.
@@ -362,6 +462,8 @@ pub struct VmmMetrics {
     pub device_events: SharedMetric,
     /// Metric for signaling a panic has occurred.
     pub panic_count: SharedMetric,
+    /// Number of times a rate-limit group was unblocked by its timer.
+    pub rate_limit_group_events: SharedMetric,
 }
 
 /// Memory usage metrics.
@@ -389,6 +491,8 @@ pub struct FirecrackerMetrics {
     pub api_server: ApiServerMetrics,
     /// A block device's related metrics.
     pub block: BlockDeviceMetrics,
+    /// Metrics related to API DELETE requests.
+    pub delete_api_requests: DeleteRequestsMetrics,
     /// Metrics related to API GET requests.
     pub get_api_requests: GetRequestsMetrics,
     /// Metrics relaetd to the i8042 device.
@@ -399,6 +503,8 @@ pub struct FirecrackerMetrics {
     pub mmds: MmdsMetrics,
     /// A network device's related metrics.
     pub net: NetDeviceMetrics,
+    /// Metrics related to the firewall rules of the network interfaces.
+    pub net_firewall: NetFirewallMetrics,
     /// Metrics related to API PATCH requests.
     pub patch_api_requests: PatchRequestsMetrics,
     /// Metrics related to API PUT requests.
@@ -473,6 +579,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_net_firewall_metrics() {
+        let metrics = NetFirewallMetrics::default();
+        let rule_metrics = NetFirewallRuleMetrics {
+            rx_rule_hits: vec![Arc::new(SharedMetric::default())],
+            tx_rule_hits: vec![
+                Arc::new(SharedMetric::default()),
+                Arc::new(SharedMetric::default()),
+            ],
+        };
+        rule_metrics.tx_rule_hits[1].add(3);
+        let counter = rule_metrics.tx_rule_hits[1].clone();
+        metrics.set("eth0", rule_metrics);
+        assert_eq!(
+            serde_json::to_string(&metrics).unwrap(),
+            r#"{"eth0":{"rx_rule_hits":[0],"tx_rule_hits":[0,3]}}"#
+        );
+        // The counters are flushed like the other shared metrics.
+        counter.inc();
+        assert_eq!(
+            serde_json::to_string(&metrics).unwrap(),
+            r#"{"eth0":{"rx_rule_hits":[0],"tx_rule_hits":[0,1]}}"#
+        );
+
+        metrics.set("eth0", NetFirewallRuleMetrics::default());
+        assert_eq!(
+            serde_json::to_string(&metrics).unwrap(),
+            r#"{"eth0":{"rx_rule_hits":[],"tx_rule_hits":[]}}"#
+        );
+    }
+
     #[test]
     fn test_serialize() {
         let s = serde_json::to_string(&FirecrackerMetrics::default());
@@ -27,6 +27,8 @@ pub enum Error {
     /// Failed to create a socket.
     NetUtil(NetUtilError),
     InvalidIfname,
+    /// Couldn't duplicate the tap file descriptor.
+    CloneTap(IoError),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -70,6 +72,33 @@ fn build_terminated_if_name(if_name: &str) -> Result<Vec<u8>> {
 
 impl Tap {
     pub fn open_named(if_name: &str) -> Result<Tap> {
+        Self::open_with_flags(if_name, 0)
+    }
+
+    /// Opens `num_queues` queues of the tap interface `if_name`, each with its own file
+    /// descriptor. A single queue is opened without `IFF_MULTI_QUEUE`, so that interfaces created
+    /// without multi-queue support can still be used.
+    pub fn open_named_queues(if_name: &str, num_queues: usize) -> Result<Vec<Tap>> {
+        if num_queues == 1 {
+            return Ok(vec![Self::open_named(if_name)?]);
+        }
+        let first = Self::open_with_flags(if_name, net_gen::IFF_MULTI_QUEUE)?;
+        // `if_name` may be a template such as `vmtap%d`, so the other queues are opened by the
+        // name the kernel picked.
+        let len = first
+            .if_name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(first.if_name.len());
+        let if_name = String::from_utf8_lossy(&first.if_name[..len]).into_owned();
+        let mut taps = vec![first];
+        for _ in 1..num_queues {
+            taps.push(Self::open_with_flags(&if_name, net_gen::IFF_MULTI_QUEUE)?);
+        }
+        Ok(taps)
+    }
+
+    fn open_with_flags(if_name: &str, flags: c_uint) -> Result<Tap> {
         let terminated_if_name = build_terminated_if_name(if_name)?;
 
         let fd = unsafe {
@@ -97,7 +126,7 @@ impl Tap {
             let name_slice = &mut ifrn_name[..terminated_if_name.len()];
             name_slice.copy_from_slice(terminated_if_name.as_slice());
             *ifru_flags =
-                (net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR) as c_short;
+                (net_gen::IFF_TAP | net_gen::IFF_NO_PI | net_gen::IFF_VNET_HDR | flags) as c_short;
         }
 
         // ioctl is safe since we call it with a valid tap fd and check the return
@@ -212,6 +241,37 @@ impl Tap {
         Ok(())
     }
 
+    /// Attaches or detaches the queue of a multi-queue tap interface. The kernel only queues
+    /// frames on the attached queues, and a detached queue can neither be read nor written.
+    pub fn set_queue_attached(&self, attached: bool) -> Result<()> {
+        let mut ifreq: net_gen::ifreq = Default::default();
+        // Only the flags are used by TUNSETQUEUE.
+        unsafe {
+            let ifru_flags = ifreq.ifr_ifru.ifru_flags.as_mut();
+            *ifru_flags = if attached {
+                net_gen::IFF_ATTACH_QUEUE
+            } else {
+                net_gen::IFF_DETACH_QUEUE
+            } as c_short;
+        }
+
+        // ioctl is safe. Called with a valid tap fd, and we check the return.
+        let ret = unsafe { ioctl_with_ref(&self.tap_file, net_gen::TUNSETQUEUE(), &ifreq) };
+        if ret < 0 {
+            return Err(Error::IoctlError(IoError::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Creates a handle to the same queue of the tap interface, with a new file descriptor.
+    pub fn try_clone(&self) -> Result<Tap> {
+        Ok(Tap {
+            tap_file: self.tap_file.try_clone().map_err(Error::CloneTap)?,
+            if_name: self.if_name,
+        })
+    }
+
     fn get_ifreq(&self) -> net_gen::ifreq {
         let mut ifreq: net_gen::ifreq = Default::default();
 
@@ -416,6 +476,25 @@ mod tests {
         println!("created tap: {:?}", t);
     }
 
+    #[test]
+    fn test_tap_multi_queue() {
+        let taps = Tap::open_named_queues("mqtap%d", 2).unwrap();
+        assert_eq!(taps.len(), 2);
+        // Both queues belong to the same interface.
+        assert_eq!(taps[0], taps[1]);
+
+        let tap = taps[1].try_clone().unwrap();
+        assert_ne!(tap.as_raw_fd(), taps[1].as_raw_fd());
+        tap.set_queue_attached(false).unwrap();
+        // The queue is already detached.
+        assert!(taps[1].set_queue_attached(false).is_err());
+        taps[1].set_queue_attached(true).unwrap();
+
+        // A single queue is opened without IFF_MULTI_QUEUE, so it cannot be detached.
+        let taps = Tap::open_named_queues("sqtap%d", 1).unwrap();
+        assert!(taps[0].set_queue_attached(false).is_err());
+    }
+
     #[test]
     fn test_tap_configure() {
         // This should be the first thing to be called inside the function, so everything else
@@ -34,6 +34,20 @@
 //! The granularity for 'wake up' events when the rate limiter is blocked is
 //! currently hardcoded to `100 milliseconds`.
 //!
+//! The token buckets can be reconfigured at any time through `update()`. The
+//! tokens already consumed from a bucket stay consumed under its new configuration.
+//!
+//! Several rate limiters can share a single budget by joining the same `RateLimiterGroup`.
+//! The tokens are then consumed both from the buckets of the member and from the buckets of
+//! the group, and the members stay blocked while the group is blocked. The group has its own
+//! timer, which wakes up the members through the event fds they registered with
+//! `add_group_kick()`.
+//!
+//! The token buckets of a group can also be shared with the groups of other processes, such
+//! as the microVMs of a same tenant, by building the groups with
+//! `RateLimiterGroup::with_shared_state()` on the same file. The budget left in the buckets is
+//! then kept in that file, which is locked while the tokens are consumed.
+//!
 //! ## Limitations
 //!
 //! This rate limiter implementation relies on the *Linux kernel's timerfd* so its
@@ -45,6 +59,8 @@
 //! needs to be called by the user on every event on the rate limiter's `AsRawFd` FD.
 //!
 
+extern crate byteorder;
+extern crate libc;
 extern crate serde;
 extern crate time;
 extern crate timerfd;
@@ -53,11 +69,17 @@ extern crate serde_derive;
 
 #[macro_use]
 extern crate logger;
+extern crate sys_util;
 
+use byteorder::{ByteOrder, LittleEndian};
 use serde::de::{self, Deserialize, Deserializer, MapAccess, Visitor};
+use std::fs::File;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
 use std::time::Duration;
 use std::{fmt, io};
+use sys_util::EventFd;
 use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
 
 #[derive(Debug)]
@@ -74,6 +96,13 @@ const TIMER_REFILL_STATE: TimerState =
 
 const NANOSEC_IN_ONE_MILLISEC: u64 = 1_000_000;
 
+// Length of the state of a token bucket shared with other processes: its budget, the one time
+// burst left and the time of its last refill.
+const SHARED_BUCKET_STATE_LEN: usize = 24;
+// Length of the state of the token buckets of a group shared with other processes: the state
+// of the bandwidth bucket followed by the state of the ops bucket.
+const SHARED_STATE_LEN: usize = 2 * SHARED_BUCKET_STATE_LEN;
+
 // Euclid's two-thousand-year-old algorithm for finding the greatest common divisor.
 fn gcd(x: u64, y: u64) -> u64 {
     let mut x = x;
@@ -86,6 +115,15 @@ fn gcd(x: u64, y: u64) -> u64 {
     x
 }
 
+fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    // Safe because the fd is valid for the lifetime of `file`, and we check the return value.
+    let ret = unsafe { libc::flock(file.as_raw_fd(), operation) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
 /// TokenBucket provides a lower level interface to rate limiting with a
 /// configurable capacity, refill-rate and initial burst.
 #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
@@ -204,6 +242,40 @@ impl TokenBucket {
         true
     }
 
+    // Takes over the budget left in `old` and the time it was last refilled, so that the
+    // tokens consumed under the previous configuration are still accounted for.
+    fn carry_over(&mut self, old: &TokenBucket) {
+        self.budget = std::cmp::min(old.budget, self.size);
+        self.last_update = old.last_update;
+    }
+
+    // Takes over the budget, the one time burst left and the time of the last refill from the
+    // `state` of the bucket shared with other processes.
+    fn load_shared(&mut self, state: &[u8]) {
+        // The bucket was never stored by a process in which it is enabled.
+        if LittleEndian::read_u64(&state[16..]) == 0 {
+            return;
+        }
+        self.budget = std::cmp::min(LittleEndian::read_u64(&state[0..]), self.size);
+        if let Some(otb) = self.one_time_burst.as_mut() {
+            *otb = LittleEndian::read_u64(&state[8..]);
+        }
+        // The time of the last refill cannot be in the future, as all the processes sharing
+        // the bucket use the same monotonic clock.
+        self.last_update = std::cmp::min(
+            LittleEndian::read_u64(&state[16..]),
+            time::precise_time_ns(),
+        );
+    }
+
+    // Writes the budget, the one time burst left and the time of the last refill to the
+    // `state` of the bucket shared with other processes.
+    fn store_shared(&self, state: &mut [u8]) {
+        LittleEndian::write_u64(&mut state[0..], self.budget);
+        LittleEndian::write_u64(&mut state[8..], self.one_time_burst.unwrap_or(0));
+        LittleEndian::write_u64(&mut state[16..], self.last_update);
+    }
+
     /// "Manually" adds tokens to bucket.
     pub fn replenish(&mut self, tokens: u64) {
         // This means we are still during the burst interval.
@@ -221,6 +293,7 @@ impl TokenBucket {
 }
 
 /// Enum that describes the type of token used.
+#[derive(Clone, Copy, Debug)]
 pub enum TokenType {
     /// Token type used for bandwidth limiting.
     Bytes,
@@ -228,6 +301,20 @@ pub enum TokenType {
     Ops,
 }
 
+/// New configurations for the token buckets of a `RateLimiter`, as passed to
+/// `RateLimiter::update()`.
+///
+/// A bucket that is not specified is left unchanged. A bucket with a *size* or *refill_time*
+/// of **zero** disables limiting for its token type.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimiterUpdate {
+    /// New configuration of the `TokenType::Bytes` token bucket.
+    pub bandwidth: Option<TokenBucket>,
+    /// New configuration of the `TokenType::Ops` token bucket.
+    pub ops: Option<TokenBucket>,
+}
+
 /// Rate Limiter that works on both bandwidth and ops/s limiting.
 ///
 /// Bandwidth (bytes/s) and ops/s limiting can be used at the same time or individually.
@@ -250,6 +337,12 @@ pub struct RateLimiter {
     timer_fd: Option<TimerFd>,
     // Internal flag that quickly determines timer state.
     timer_active: bool,
+
+    // The group this limiter is a member of, if any.
+    group: Option<Arc<RateLimiterGroup>>,
+    // The event fds written when the group unblocks. The group only keeps weak references to
+    // them, so that they are unregistered when the limiter is dropped.
+    group_kicks: Vec<Arc<EventFd>>,
 }
 
 impl PartialEq for RateLimiter {
@@ -384,8 +477,7 @@ impl RateLimiter {
 
         // If limiting is disabled on all token types, don't even create a timer fd.
         let timer_fd = if bytes_token_bucket.is_some() || ops_token_bucket.is_some() {
-            // create TimerFd using monotonic clock, as nonblocking FD and set close-on-exec
-            Some(TimerFd::new_custom(ClockId::Monotonic, true, true)?)
+            Some(RateLimiter::new_timer_fd()?)
         } else {
             None
         };
@@ -395,9 +487,90 @@ impl RateLimiter {
             ops: ops_token_bucket,
             timer_fd,
             timer_active: false,
+            group: None,
+            group_kicks: Vec::new(),
         })
     }
 
+    /// Makes this rate limiter a member of `group`.
+    ///
+    /// From then on, the tokens are consumed both from the buckets of this limiter and from
+    /// the buckets of the group.
+    pub fn set_group(&mut self, group: Arc<RateLimiterGroup>) {
+        self.group = Some(group);
+        self.group_kicks.clear();
+    }
+
+    /// Registers an event fd that is written every time the group of this limiter unblocks,
+    /// so that the user can resume its processing.
+    ///
+    /// Does nothing if the limiter is not a member of a group.
+    ///
+    /// # Errors
+    ///
+    /// If the event fd cannot be cloned, an error is returned.
+    pub fn add_group_kick(&mut self, kick: &EventFd) -> sys_util::Result<()> {
+        if let Some(ref group) = self.group {
+            let kick = Arc::new(kick.try_clone()?);
+            group.add_kick(&kick);
+            self.group_kicks.push(kick);
+        }
+        Ok(())
+    }
+
+    /// Reconfigures the token buckets of this rate limiter.
+    ///
+    /// A bucket that replaces an existing one starts with the budget left in the old bucket,
+    /// capped to its new *size*, and the new *one_time_burst*. If the limiter is blocked, it
+    /// stays blocked until its next event. The timer fd is created when the update enables the
+    /// first bucket, so the user must then start monitoring the FD provided by the `AsRawFd`
+    /// trait implementation.
+    ///
+    /// # Errors
+    ///
+    /// If the timerfd creation fails, an error is returned and the limiter is left unchanged.
+    pub fn update(&mut self, update: RateLimiterUpdate) -> io::Result<()> {
+        let enables_bucket = |new_config: &Option<TokenBucket>| {
+            new_config
+                .as_ref()
+                .map_or(false, |config| config.size != 0 && config.refill_time != 0)
+        };
+        if self.timer_fd.is_none()
+            && (enables_bucket(&update.bandwidth) || enables_bucket(&update.ops))
+        {
+            self.timer_fd = Some(RateLimiter::new_timer_fd()?);
+        }
+        RateLimiter::update_bucket(&mut self.bandwidth, update.bandwidth);
+        RateLimiter::update_bucket(&mut self.ops, update.ops);
+        Ok(())
+    }
+
+    fn new_timer_fd() -> io::Result<TimerFd> {
+        // create TimerFd using monotonic clock, as nonblocking FD and set close-on-exec
+        TimerFd::new_custom(ClockId::Monotonic, true, true)
+    }
+
+    fn update_bucket(bucket: &mut Option<TokenBucket>, new_config: Option<TokenBucket>) {
+        let new_config = match new_config {
+            Some(new_config) => new_config,
+            None => return,
+        };
+        // The deserialized bucket only holds the configuration, so build a complete one.
+        if new_config.size == 0 || new_config.refill_time == 0 {
+            *bucket = None;
+            return;
+        }
+        let mut new_bucket = TokenBucket::new(
+            new_config.size,
+            new_config.one_time_burst,
+            new_config.refill_time,
+        );
+        if let Some(old_bucket) = bucket.as_ref() {
+            new_bucket.carry_over(old_bucket);
+        }
+        *bucket = Some(new_bucket);
+    }
+
     /// Attempts to consume tokens and returns whether that is possible.
     ///
     /// If rate limiting is disabled on provided `token_type`, this function will always succeed.
@@ -426,7 +599,18 @@ impl RateLimiter {
                 .set_state(TIMER_REFILL_STATE, SetTimeFlags::Default);
             self.timer_active = true;
         }
-        success
+        if !success {
+            return false;
+        }
+        // The group arms its own timer when it runs out of budget, in which case the tokens
+        // are given back to the buckets of this limiter.
+        if let Some(group) = self.group.clone() {
+            if !group.consume(tokens, token_type) {
+                self.replenish_own(tokens, token_type);
+                return false;
+            }
+        }
+        true
     }
 
     /// Adds tokens of `token_type` to their respective bucket.
@@ -434,6 +618,35 @@ impl RateLimiter {
     /// Can be used to *manually* add tokens to a bucket. Useful for reverting a
     /// `consume()` if needed.
     pub fn manual_replenish(&mut self, tokens: u64, token_type: TokenType) {
+        self.replenish_own(tokens, token_type);
+        if let Some(ref group) = self.group {
+            group.manual_replenish(tokens, token_type);
+        }
+    }
+
+    // Loads the state of the token buckets shared with other processes. The buckets that are
+    // disabled in this limiter are left out.
+    fn load_shared(&mut self, state: &[u8]) {
+        if let Some(bucket) = self.bandwidth.as_mut() {
+            bucket.load_shared(&state[..SHARED_BUCKET_STATE_LEN]);
+        }
+        if let Some(bucket) = self.ops.as_mut() {
+            bucket.load_shared(&state[SHARED_BUCKET_STATE_LEN..]);
+        }
+    }
+
+    // Stores the state of the token buckets shared with other processes.
+    fn store_shared(&self, state: &mut [u8]) {
+        let (bandwidth_state, ops_state) = state.split_at_mut(SHARED_BUCKET_STATE_LEN);
+        if let Some(bucket) = self.bandwidth.as_ref() {
+            bucket.store_shared(bandwidth_state);
+        }
+        if let Some(bucket) = self.ops.as_ref() {
+            bucket.store_shared(ops_state);
+        }
+    }
+
+    fn replenish_own(&mut self, tokens: u64, token_type: TokenType) {
         // Identify the required token bucket.
         let token_bucket = match token_type {
             TokenType::Bytes => self.bandwidth.as_mut(),
@@ -449,9 +662,14 @@ impl RateLimiter {
     ///
     /// The limiter 'blocks' when a `consume()` operation fails because there was not enough
     /// budget for it.
-    /// An event will be generated on the exported FD when the limiter 'unblocks'.
+    /// An event will be generated on the exported FD when the limiter 'unblocks'. A member
+    /// of a blocked group is blocked as well, and is kicked when the group 'unblocks'.
     pub fn is_blocked(&self) -> bool {
         self.timer_active
+            || self
+                .group
+                .as_ref()
+                .map_or(false, |group| group.is_blocked())
     }
 
     /// This function needs to be called every time there is an event on the
@@ -486,8 +704,7 @@ impl AsRawFd for RateLimiter {
     ///
     /// This object's `event_handler()` method must be called on such events.
     ///
-    /// Will return a negative value if rate limiting is disabled on both
-    /// token types.
+    /// Will return a negative value if the limiter has no timer.
     fn as_raw_fd(&self) -> RawFd {
         match self.timer_fd.as_ref() {
             Some(timer_fd) => timer_fd.as_raw_fd(),
@@ -499,13 +716,157 @@ impl AsRawFd for RateLimiter {
 impl Default for RateLimiter {
     /// Default RateLimiter is a no-op limiter with infinite budget.
     fn default() -> Self {
-        // Safe to unwrap since this will not attempt to create timer_fd.
         RateLimiter::new(0, None, 0, 0, None, 0).expect("Failed to build default RateLimiter")
     }
 }
 
+/// Token buckets and a timer shared by the rate limiters of several devices.
+///
+/// Rate limiters join the group through `RateLimiter::set_group()`. When a member runs out of
+/// the budget of the group, the timer of the group is armed and all the members are blocked.
+///
+/// Like a `RateLimiter`, the group generates events on the FD provided by its `AsRawFd` trait
+/// implementation, and the user must call the `event_handler()` method on each such event.
+pub struct RateLimiterGroup {
+    limiter: Mutex<RateLimiter>,
+    kicks: Mutex<Vec<Weak<EventFd>>>,
+    // The file holding the state of the token buckets when they are shared with other processes.
+    shared_state: Option<File>,
+}
+
+impl RateLimiterGroup {
+    /// Creates a group whose members share the token buckets of `limiter`.
+    pub fn new(limiter: RateLimiter) -> Self {
+        RateLimiterGroup {
+            limiter: Mutex::new(limiter),
+            kicks: Mutex::new(Vec::new()),
+            shared_state: None,
+        }
+    }
+
+    /// Creates a group whose members share the token buckets of `limiter` with the members of
+    /// the groups built on the same `state` file, possibly in other processes.
+    ///
+    /// The budget left in the buckets is kept in `state`, which must be opened for reading and
+    /// writing. An empty file starts off with the buckets of `limiter`. The groups sharing the
+    /// file are expected to be built with the same buckets; each of them arms its own timer when
+    /// the buckets run out of budget.
+    pub fn with_shared_state(limiter: RateLimiter, state: File) -> Self {
+        RateLimiterGroup {
+            limiter: Mutex::new(limiter),
+            kicks: Mutex::new(Vec::new()),
+            shared_state: Some(state),
+        }
+    }
+
+    /// Returns whether the group, and therefore each of its members, is blocked.
+    pub fn is_blocked(&self) -> bool {
+        self.lock().is_blocked()
+    }
+
+    /// This function needs to be called every time there is an event on the
+    /// FD provided by this object's `AsRawFd` trait implementation.
+    ///
+    /// Unblocks the group and writes the event fds registered by its members.
+    ///
+    /// # Errors
+    ///
+    /// If the group is not blocked, an error is returned.
+    pub fn event_handler(&self) -> Result<(), Error> {
+        self.lock().event_handler()?;
+        // If the lock is poisoned, it's OK to panic.
+        let mut kicks = self
+            .kicks
+            .lock()
+            .expect("Failed to kick the rate limiter group members due to poisoned lock");
+        // The kicks of the members that were dropped are removed along the way.
+        kicks.retain(|kick| match kick.upgrade() {
+            Some(kick) => {
+                if let Err(e) = kick.write(1) {
+                    error!("Failed to kick a rate limiter group member: {:?}", e);
+                }
+                true
+            }
+            None => false,
+        });
+        Ok(())
+    }
+
+    fn add_kick(&self, kick: &Arc<EventFd>) {
+        // If the lock is poisoned, it's OK to panic.
+        self.kicks
+            .lock()
+            .expect("Failed to add a rate limiter group kick due to poisoned lock")
+            .push(Arc::downgrade(kick));
+    }
+
+    fn consume(&self, tokens: u64, token_type: TokenType) -> bool {
+        self.with_shared_state_locked(|limiter| limiter.consume(tokens, token_type))
+    }
+
+    fn manual_replenish(&self, tokens: u64, token_type: TokenType) {
+        self.with_shared_state_locked(|limiter| limiter.manual_replenish(tokens, token_type))
+    }
+
+    // Runs `f` on the limiter of the group. If the token buckets are shared with other
+    // processes, their state is loaded from the shared file before and stored back after,
+    // while the file is locked. On errors, the group falls back to the state it last saw.
+    fn with_shared_state_locked<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut RateLimiter) -> T,
+    {
+        let mut limiter = self.lock();
+        let state_file = match self.shared_state {
+            Some(ref state_file) => state_file,
+            None => return f(&mut limiter),
+        };
+        if let Err(e) = flock(state_file, libc::LOCK_EX) {
+            error!("Failed to lock the shared rate limiter state: {}", e);
+            return f(&mut limiter);
+        }
+
+        let mut state = [0u8; SHARED_STATE_LEN];
+        match state_file.read_at(&mut state, 0) {
+            Ok(SHARED_STATE_LEN) => limiter.load_shared(&state),
+            // The first group to use the file stores its own state.
+            Ok(_) => (),
+            Err(e) => error!("Failed to read the shared rate limiter state: {}", e),
+        }
+        let result = f(&mut limiter);
+        limiter.store_shared(&mut state);
+        if let Err(e) = state_file.write_all_at(&state, 0) {
+            error!("Failed to write the shared rate limiter state: {}", e);
+        }
+
+        if let Err(e) = flock(state_file, libc::LOCK_UN) {
+            error!("Failed to unlock the shared rate limiter state: {}", e);
+        }
+        result
+    }
+
+    fn lock(&self) -> MutexGuard<RateLimiter> {
+        // If the lock is poisoned, it's OK to panic.
+        self.limiter
+            .lock()
+            .expect("Failed to access the rate limiter group due to poisoned lock")
+    }
+}
+
+impl AsRawFd for RateLimiterGroup {
+    /// Provides a FD which needs to be monitored for POLLIN events.
+    ///
+    /// This object's `event_handler()` method must be called on such events.
+    ///
+    /// Will return a negative value if the group has no token bucket.
+    fn as_raw_fd(&self) -> RawFd {
+        self.lock().as_raw_fd()
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    extern crate tempfile;
+
     use super::*;
     use std::thread;
     use std::time::Duration;
@@ -762,6 +1123,198 @@ mod tests {
         //assert!(!l.consume(u64::max_value(), TokenType::Bytes));
     }
 
+    #[test]
+    fn test_rate_limiter_update() {
+        // rate limiter with limit of 1000 bytes/s and 1000 ops/s
+        let mut l = RateLimiter::new(1000, None, 1000, 1000, None, 1000).unwrap();
+        assert!(l.consume(800, TokenType::Bytes));
+        assert!(l.consume(100, TokenType::Ops));
+
+        // an empty update changes nothing
+        assert!(l.update(RateLimiterUpdate::default()).is_ok());
+        assert_eq!(
+            l.get_token_bucket(TokenType::Bytes)
+                .unwrap()
+                .get_current_budget(),
+            200
+        );
+
+        // the consumed tokens are still accounted for after resizing the bucket
+        let jstr = r#"{
+            "bandwidth": { "size": 2000, "one_time_burst": 100, "refill_time": 1000 }
+        }"#;
+        assert!(l.update(serde_json::from_str(jstr).unwrap()).is_ok());
+        {
+            let bytes_tb = l.get_token_bucket(TokenType::Bytes).unwrap();
+            assert_eq!(bytes_tb.get_capacity(), 2000);
+            assert_eq!(bytes_tb.get_current_budget(), 200);
+            assert_eq!(bytes_tb.get_one_time_burst(), 100);
+            assert_eq!(bytes_tb.get_processed_capacity(), 1);
+            assert_eq!(bytes_tb.get_processed_refill_time(), 500_000);
+            let ops_tb = l.get_token_bucket(TokenType::Ops).unwrap();
+            assert_eq!(ops_tb.get_capacity(), 1000);
+            assert_eq!(ops_tb.get_current_budget(), 900);
+        }
+
+        // the budget left is capped to the new size
+        let jstr = r#"{
+            "ops": { "size": 10, "refill_time": 1000 }
+        }"#;
+        assert!(l.update(serde_json::from_str(jstr).unwrap()).is_ok());
+        assert_eq!(
+            l.get_token_bucket(TokenType::Ops)
+                .unwrap()
+                .get_current_budget(),
+            10
+        );
+        assert!(l.consume(10, TokenType::Ops));
+        assert!(!l.consume(1, TokenType::Ops));
+        assert!(l.is_blocked());
+
+        // a bucket of size 0 disables limiting, but the limiter stays blocked until its event
+        let jstr = r#"{
+            "ops": { "size": 0, "refill_time": 1000 }
+        }"#;
+        assert!(l.update(serde_json::from_str(jstr).unwrap()).is_ok());
+        assert!(l.get_token_bucket(TokenType::Ops).is_none());
+        assert!(l.is_blocked());
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS));
+        assert!(l.event_handler().is_ok());
+        assert!(l.consume(u64::max_value(), TokenType::Ops));
+
+        // a disabled limiter can be enabled, which creates its timer
+        let mut l = RateLimiter::default();
+        let jstr = r#"{
+            "bandwidth": { "size": 1000, "refill_time": 0 }
+        }"#;
+        assert!(l.update(serde_json::from_str(jstr).unwrap()).is_ok());
+        assert_eq!(l.as_raw_fd(), -1);
+        let jstr = r#"{
+            "bandwidth": { "size": 1000, "refill_time": 1000 }
+        }"#;
+        assert!(l.update(serde_json::from_str(jstr).unwrap()).is_ok());
+        assert!(l.as_raw_fd() > 0);
+        assert!(l.consume(1000, TokenType::Bytes));
+        assert!(!l.consume(100, TokenType::Bytes));
+        assert!(l.is_blocked());
+
+        let jstr = r#"{
+            "bandwidth": { "size": 1000, "refill_time": 1000 },
+            "opz": { "size": 10, "refill_time": 1000 }
+        }"#;
+        assert!(serde_json::from_str::<RateLimiterUpdate>(jstr).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_group() {
+        // group with a limit of 10 ops/s shared by two members
+        let group = Arc::new(RateLimiterGroup::new(
+            RateLimiter::new(0, None, 0, 10, None, 1000).unwrap(),
+        ));
+        // first member has its own limit of 8 ops/s
+        let mut l1 = RateLimiter::new(0, None, 0, 8, None, 1000).unwrap();
+        let mut l2 = RateLimiter::default();
+        l1.set_group(group.clone());
+        l2.set_group(group.clone());
+
+        let kick1 = EventFd::new().unwrap();
+        let kick2 = EventFd::new().unwrap();
+        l1.add_group_kick(&kick1).unwrap();
+        l2.add_group_kick(&kick2).unwrap();
+
+        // members consume from the budget of the group
+        assert!(l1.consume(6, TokenType::Ops));
+        assert!(l2.consume(4, TokenType::Ops));
+        // the group is out of budget, so both members are blocked
+        assert!(!l1.consume(1, TokenType::Ops));
+        assert!(group.is_blocked());
+        assert!(l1.is_blocked());
+        assert!(l2.is_blocked());
+        // the own budget of the first member was given back
+        assert_eq!(
+            l1.get_token_bucket(TokenType::Ops)
+                .unwrap()
+                .get_current_budget(),
+            2
+        );
+        // the bytes are not limited
+        assert!(l2.consume(u64::max_value(), TokenType::Bytes));
+
+        // the members are kicked once the timer of the group expires
+        assert!(group.event_handler().is_err());
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS));
+        assert!(group.event_handler().is_ok());
+        assert!(!l1.is_blocked());
+        assert!(!l2.is_blocked());
+        assert_eq!(kick1.read().unwrap(), 1);
+        assert_eq!(kick2.read().unwrap(), 1);
+        assert!(l2.consume(1, TokenType::Ops));
+
+        // the kicks of the dropped members are unregistered
+        drop(l2);
+        let mut l3 = RateLimiter::default();
+        l3.set_group(group.clone());
+        while l3.consume(1, TokenType::Ops) {}
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS));
+        assert!(group.event_handler().is_ok());
+        assert_eq!(group.kicks.lock().unwrap().len(), 1);
+        assert_eq!(kick1.read().unwrap(), 1);
+
+        // limiters outside of a group do not register kicks
+        let mut l4 = RateLimiter::default();
+        l4.add_group_kick(&kick1).unwrap();
+        assert!(l4.group_kicks.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limiter_group_shared_state() {
+        let state_file = tempfile::tempfile().unwrap();
+        // two groups in different processes, with a shared limit of 10 ops/s
+        let group1 = Arc::new(RateLimiterGroup::with_shared_state(
+            RateLimiter::new(0, None, 0, 10, None, 1000).unwrap(),
+            state_file.try_clone().unwrap(),
+        ));
+        let group2 = Arc::new(RateLimiterGroup::with_shared_state(
+            RateLimiter::new(0, None, 0, 10, None, 1000).unwrap(),
+            state_file.try_clone().unwrap(),
+        ));
+        let mut l1 = RateLimiter::default();
+        let mut l2 = RateLimiter::default();
+        l1.set_group(group1.clone());
+        l2.set_group(group2.clone());
+
+        // the first group to consume initializes the shared state
+        assert!(l1.consume(6, TokenType::Ops));
+        assert_eq!(
+            state_file.metadata().unwrap().len(),
+            SHARED_STATE_LEN as u64
+        );
+        // the members of both groups consume from the same budget
+        assert!(!l2.consume(5, TokenType::Ops));
+        assert!(group2.is_blocked());
+        assert!(!group1.is_blocked());
+        assert!(l2.consume(4, TokenType::Ops));
+        assert!(!l1.consume(1, TokenType::Ops));
+        assert!(group1.is_blocked());
+
+        // tokens given back are seen by the other group
+        l1.manual_replenish(3, TokenType::Ops);
+        assert!(l2.consume(3, TokenType::Ops));
+
+        // the bytes are not limited and leave the state of the ops untouched
+        assert!(l1.consume(u64::max_value(), TokenType::Bytes));
+        let mut state = [0u8; SHARED_STATE_LEN];
+        state_file.read_at(&mut state, 0).unwrap();
+        assert_eq!(LittleEndian::read_u64(&state[SHARED_BUCKET_STATE_LEN..]), 0);
+        assert_eq!(LittleEndian::read_u64(&state[16..]), 0);
+
+        // both groups are unblocked by their own timers
+        thread::sleep(Duration::from_millis(REFILL_TIMER_INTERVAL_MS));
+        assert!(group1.event_handler().is_ok());
+        assert!(group2.event_handler().is_ok());
+        assert!(l1.consume(1, TokenType::Ops));
+    }
+
     #[test]
     fn test_rate_limiter_deserialization() {
         let jstr = r#"{
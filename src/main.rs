@@ -17,9 +17,11 @@ extern crate vmm;
 use backtrace::Backtrace;
 use clap::{App, Arg};
 
+use std::fs;
 use std::io::ErrorKind;
 use std::panic;
 use std::path::PathBuf;
+use std::process;
 use std::sync::mpsc::channel;
 use std::sync::{Arc, RwLock};
 
@@ -27,6 +29,7 @@ use api_server::{ApiServer, Error, UnixDomainSocket};
 use jailer::FirecrackerContext;
 use logger::{Metric, LOGGER, METRICS};
 use mmds::MMDS;
+use vmm::vmm_config::config_file::ConfigFile;
 use vmm::vmm_config::instance_info::{InstanceInfo, InstanceState};
 
 const DEFAULT_API_SOCK_PATH: &str = "/tmp/firecracker.socket";
@@ -75,6 +78,14 @@ fn main() {
                 .help("Additional parameters sent to Firecracker.")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("config_file")
+                .long("config-file")
+                .help(
+                    "Path to a JSON file describing the microVM, which is then booted right away.",
+                )
+                .takes_value(true),
+        )
         .get_matches();
 
     let bind_path = cmd_arguments
@@ -98,9 +109,17 @@ fn main() {
         start_time_cpu_us = Some(context.start_time_cpu_us);
     }
 
+    let config_file = cmd_arguments.value_of("config_file").map(|path| {
+        read_config_file(path).unwrap_or_else(|e| {
+            error!("{}", e);
+            process::exit(1);
+        })
+    });
+
     let shared_info = Arc::new(RwLock::new(InstanceInfo {
         state: InstanceState::Uninitialized,
         id: instance_id,
+        pending_drive_ejects: vec![],
     }));
     let mmds_info = MMDS.clone();
     let (to_vmm, from_api) = channel();
@@ -117,8 +136,14 @@ fn main() {
         None
     };
 
-    let _vmm_thread_handle =
-        vmm::start_vmm_thread(shared_info, api_event_fd, from_api, seccomp_level, kvm_fd);
+    let _vmm_thread_handle = vmm::start_vmm_thread(
+        shared_info,
+        api_event_fd,
+        from_api,
+        seccomp_level,
+        kvm_fd,
+        config_file,
+    );
 
     let uds_path_or_fd = if is_jailed {
         UnixDomainSocket::Fd(jailer::LISTENER_FD)
@@ -145,6 +170,14 @@ fn main() {
     }
 }
 
+// Reads the description of the microVM from the JSON file at `path`.
+fn read_config_file(path: &str) -> Result<ConfigFile, String> {
+    let config_json = fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read the configuration file {}: {}", path, e))?;
+    serde_json::from_str::<ConfigFile>(&config_json)
+        .map_err(|e| format!("Invalid configuration file format: {}", e))
+}
+
 #[cfg(test)]
 mod tests {
     extern crate tempfile;
@@ -191,6 +224,22 @@ mod tests {
         assert!(pass);
     }
 
+    #[test]
+    fn test_read_config_file() {
+        assert!(read_config_file("/nonexistent/vm_config.json")
+            .unwrap_err()
+            .starts_with("Cannot read the configuration file /nonexistent/vm_config.json"));
+
+        let config_file = NamedTempFile::new().unwrap();
+        let path = config_file.path().to_str().unwrap();
+        fs::write(path, "{}").unwrap();
+        assert!(read_config_file(path)
+            .unwrap_err()
+            .starts_with("Invalid configuration file format"));
+        fs::write(path, r#"{"boot-source": {"kernel_image_path": "vmlinux"}}"#).unwrap();
+        assert!(read_config_file(path).is_ok());
+    }
+
     #[test]
     fn test_main() {
         const FIRECRACKER_INIT_TIMEOUT_MILLIS: u64 = 100;
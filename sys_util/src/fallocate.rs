@@ -0,0 +1,77 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::os::unix::io::AsRawFd;
+
+use libc::{self, off64_t, FALLOC_FL_KEEP_SIZE, FALLOC_FL_PUNCH_HOLE, FALLOC_FL_ZERO_RANGE};
+
+use {errno_result, Result};
+
+/// Operation to be performed by `fallocate` on a range of a file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FallocateMode {
+    /// Deallocates the range. Reading it back returns zeroes.
+    PunchHole,
+    /// Zeroes the range, preferably by converting it to unwritten extents.
+    ZeroRange,
+}
+
+/// Safe wrapper around the `fallocate` syscall (man 2 fallocate). The file size is never
+/// changed.
+pub fn fallocate(file: &AsRawFd, mode: FallocateMode, offset: u64, len: u64) -> Result<()> {
+    let mode = FALLOC_FL_KEEP_SIZE
+        | match mode {
+            FallocateMode::PunchHole => FALLOC_FL_PUNCH_HOLE,
+            FallocateMode::ZeroRange => FALLOC_FL_ZERO_RANGE,
+        };
+    // This is safe because the syscall only operates on the file descriptor and does not touch
+    // our memory, and we check the return value.
+    let ret =
+        unsafe { libc::fallocate64(file.as_raw_fd(), mode, offset as off64_t, len as off64_t) };
+    if ret < 0 {
+        return errno_result();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    // Returns whether the file system supports `mode`. Those that don't have to say so.
+    fn try_fallocate(file: &AsRawFd, mode: FallocateMode, offset: u64, len: u64) -> bool {
+        match fallocate(file, mode, offset, len) {
+            Ok(()) => true,
+            Err(e) => {
+                assert_eq!(e.errno(), libc::EOPNOTSUPP);
+                false
+            }
+        }
+    }
+
+    #[test]
+    fn test_fallocate() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&[0xff; 0x3000]).unwrap();
+
+        let punched = try_fallocate(&file, FallocateMode::PunchHole, 0x1000, 0x1000);
+        let zeroed = try_fallocate(&file, FallocateMode::ZeroRange, 0x2000, 0x1000);
+
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+        assert_eq!(data.len(), 0x3000);
+        let expected = |done: bool| if done { 0 } else { 0xff };
+        assert!(data[..0x1000].iter().all(|b| *b == 0xff));
+        assert!(data[0x1000..0x2000].iter().all(|b| *b == expected(punched)));
+        assert!(data[0x2000..].iter().all(|b| *b == expected(zeroed)));
+
+        // The file is never extended.
+        try_fallocate(&file, FallocateMode::PunchHole, 0x3000, 0x1000);
+        try_fallocate(&file, FallocateMode::ZeroRange, 0x3000, 0x1000);
+        assert_eq!(file.metadata().unwrap().len(), 0x3000);
+    }
+}
@@ -0,0 +1,582 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A minimal wrapper around an io_uring instance (see linux/io_uring.h), able to submit vectored
+//! reads and writes and fsyncs and to reap their completions.
+
+use std::fs::File;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr::null_mut;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use libc::{self, c_long, c_uint, c_void, iovec, off64_t};
+
+use {errno_result, Error, EventFd, Result};
+
+/// The io_uring syscall numbers, which are the same on every architecture.
+pub const SYS_IO_URING_SETUP: c_long = 425;
+/// See `SYS_IO_URING_SETUP`.
+pub const SYS_IO_URING_ENTER: c_long = 426;
+/// See `SYS_IO_URING_SETUP`.
+pub const SYS_IO_URING_REGISTER: c_long = 427;
+
+const IORING_OFF_SQ_RING: off64_t = 0;
+const IORING_OFF_CQ_RING: off64_t = 0x800_0000;
+const IORING_OFF_SQES: off64_t = 0x1000_0000;
+const IORING_ENTER_GETEVENTS: c_uint = 1;
+const IORING_REGISTER_EVENTFD: c_uint = 4;
+
+const IORING_OP_READV: u8 = 1;
+const IORING_OP_WRITEV: u8 = 2;
+const IORING_OP_FSYNC: u8 = 3;
+const IOSQE_IO_DRAIN: u8 = 1 << 1;
+const IORING_FSYNC_DATASYNC: u32 = 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct io_sqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct io_cqring_offsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct io_uring_params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: io_sqring_offsets,
+    cq_off: io_cqring_offsets,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct io_uring_sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    pad: [u64; 3],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct io_uring_cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// An operation submitted to an `IoUring`.
+pub enum Operation {
+    /// Reads from `fd` at `offset` into the buffers described by `count` iovecs at `iovecs`.
+    Readv {
+        /// The file to read from.
+        fd: RawFd,
+        /// The buffers to fill.
+        iovecs: *const iovec,
+        /// The number of buffers.
+        count: u32,
+        /// Where to read in the file.
+        offset: u64,
+    },
+    /// Writes the buffers described by `count` iovecs at `iovecs` to `fd` at `offset`.
+    Writev {
+        /// The file to write to.
+        fd: RawFd,
+        /// The buffers to write.
+        iovecs: *const iovec,
+        /// The number of buffers.
+        count: u32,
+        /// Where to write in the file.
+        offset: u64,
+    },
+    /// Flushes `fd` to the disk, once every operation submitted before it has completed.
+    Fsync {
+        /// The file to flush.
+        fd: RawFd,
+        /// Like `fdatasync`, only flushes the metadata needed to read the data back.
+        datasync: bool,
+    },
+}
+
+/// The outcome of an operation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Completion {
+    /// The value given when the operation was pushed.
+    pub user_data: u64,
+    /// What the equivalent syscall would have returned, or the negated errno.
+    pub result: i32,
+}
+
+// A ring shared with the kernel.
+struct Mapping {
+    addr: *mut c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: off64_t) -> Result<Mapping> {
+        // This is safe because we map a new region, whose size and offset come from the kernel,
+        // and check the return value.
+        let addr = unsafe {
+            libc::mmap64(
+                null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return errno_result();
+        }
+        Ok(Mapping { addr, len })
+    }
+
+    // Returns a pointer to the `T` at `offset`, which the kernel gave us.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // This is safe because the offsets come from the kernel and are within the mapping.
+        unsafe { (self.addr as *mut u8).offset(offset as isize) as *mut T }
+    }
+
+    // Returns the atomic `u32` at `offset`, which the kernel updates concurrently.
+    fn atomic_at(&self, offset: u32) -> &AtomicU32 {
+        // This is safe because the offset is that of an aligned `u32` within the mapping.
+        unsafe { &*self.at::<AtomicU32>(offset) }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // This is safe because we mapped the region ourselves and nothing refers to it anymore.
+        unsafe {
+            libc::munmap(self.addr, self.len);
+        }
+    }
+}
+
+/// A safe wrapper around an io_uring instance.
+///
+/// Pushing operations is unsafe, as the kernel accesses their buffers asynchronously: they have
+/// to stay valid until the operation completes.
+pub struct IoUring {
+    sq_ring: Mapping,
+    cq_ring: Mapping,
+    sqes: Mapping,
+    // Closed once the rings are unmapped, fields being dropped in order.
+    fd: File,
+    sq_off: io_sqring_offsets,
+    cq_off: io_cqring_offsets,
+    sq_mask: u32,
+    sq_entries: u32,
+    cq_mask: u32,
+    // The operations pushed since the last submission.
+    to_submit: u32,
+}
+
+// The rings are only accessed through `&mut self` or atomics, so the instance can be moved to
+// another thread.
+unsafe impl Send for IoUring {}
+
+impl IoUring {
+    /// Sets up an io_uring instance with room for at least `entries` operations in flight.
+    pub fn new(entries: u32) -> Result<IoUring> {
+        let mut params = io_uring_params::default();
+        // This is safe because the kernel only writes to `params`, which we own, and we check the
+        // return value.
+        let ret = unsafe {
+            libc::syscall(
+                SYS_IO_URING_SETUP,
+                entries,
+                &mut params as *mut io_uring_params,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        // This is safe because we checked ret for success and know the kernel gave us an fd that
+        // we own.
+        let fd = unsafe { File::from_raw_fd(ret as RawFd) };
+
+        let sq_ring = Mapping::new(
+            fd.as_raw_fd(),
+            params.sq_off.array as usize + params.sq_entries as usize * mem::size_of::<u32>(),
+            IORING_OFF_SQ_RING,
+        )?;
+        let cq_ring = Mapping::new(
+            fd.as_raw_fd(),
+            params.cq_off.cqes as usize
+                + params.cq_entries as usize * mem::size_of::<io_uring_cqe>(),
+            IORING_OFF_CQ_RING,
+        )?;
+        let sqes = Mapping::new(
+            fd.as_raw_fd(),
+            params.sq_entries as usize * mem::size_of::<io_uring_sqe>(),
+            IORING_OFF_SQES,
+        )?;
+
+        // This is safe because the masks are plain `u32`s within the rings, which the kernel
+        // never changes.
+        let (sq_mask, cq_mask) = unsafe {
+            (
+                *sq_ring.at::<u32>(params.sq_off.ring_mask),
+                *cq_ring.at::<u32>(params.cq_off.ring_mask),
+            )
+        };
+        Ok(IoUring {
+            sq_ring,
+            cq_ring,
+            sqes,
+            fd,
+            sq_mask,
+            sq_entries: params.sq_entries,
+            cq_mask,
+            sq_off: params.sq_off,
+            cq_off: params.cq_off,
+            to_submit: 0,
+        })
+    }
+
+    /// Makes the kernel signal `evt` whenever an operation completes.
+    pub fn register_eventfd(&self, evt: &EventFd) -> Result<()> {
+        let fd = evt.as_raw_fd();
+        // This is safe because the kernel only reads the fd, which lives on our stack, and we
+        // check the return value.
+        let ret = unsafe {
+            libc::syscall(
+                SYS_IO_URING_REGISTER,
+                self.fd.as_raw_fd(),
+                IORING_REGISTER_EVENTFD,
+                &fd as *const RawFd,
+                1,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
+    /// Queues `op` for the next `submit`. `user_data` is handed back in its completion. Fails
+    /// with `EBUSY` when the submission queue is full.
+    ///
+    /// # Safety
+    ///
+    /// The iovecs of the operation, and the buffers they describe, have to stay valid until the
+    /// operation completes.
+    pub unsafe fn push(&mut self, op: Operation, user_data: u64) -> Result<()> {
+        let head = self
+            .sq_ring
+            .atomic_at(self.sq_off.head)
+            .load(Ordering::Acquire);
+        let tail = self
+            .sq_ring
+            .atomic_at(self.sq_off.tail)
+            .load(Ordering::Relaxed);
+        if tail.wrapping_sub(head) == self.sq_entries {
+            return Err(Error::new(libc::EBUSY));
+        }
+
+        let mut sqe = io_uring_sqe {
+            user_data,
+            ..Default::default()
+        };
+        match op {
+            Operation::Readv {
+                fd,
+                iovecs,
+                count,
+                offset,
+            } => {
+                sqe.opcode = IORING_OP_READV;
+                sqe.fd = fd;
+                sqe.addr = iovecs as u64;
+                sqe.len = count;
+                sqe.off = offset;
+            }
+            Operation::Writev {
+                fd,
+                iovecs,
+                count,
+                offset,
+            } => {
+                sqe.opcode = IORING_OP_WRITEV;
+                sqe.fd = fd;
+                sqe.addr = iovecs as u64;
+                sqe.len = count;
+                sqe.off = offset;
+            }
+            Operation::Fsync { fd, datasync } => {
+                sqe.opcode = IORING_OP_FSYNC;
+                sqe.flags = IOSQE_IO_DRAIN;
+                sqe.fd = fd;
+                if datasync {
+                    sqe.rw_flags = IORING_FSYNC_DATASYNC;
+                }
+            }
+        }
+
+        let index = tail & self.sq_mask;
+        *self.sqes.at::<io_uring_sqe>(0).offset(index as isize) = sqe;
+        *self
+            .sq_ring
+            .at::<u32>(self.sq_off.array)
+            .offset(index as isize) = index;
+        // The entry has to be visible to the kernel before the new tail.
+        self.sq_ring
+            .atomic_at(self.sq_off.tail)
+            .store(tail.wrapping_add(1), Ordering::Release);
+        self.to_submit += 1;
+        Ok(())
+    }
+
+    /// Hands the operations pushed since the last call to the kernel, without waiting for them
+    /// to complete. Returns how many were submitted.
+    pub fn submit(&mut self) -> Result<u32> {
+        if self.to_submit == 0 {
+            return Ok(0);
+        }
+        // This is safe because the kernel only accesses the rings, and the buffers the caller
+        // vouched for when pushing the operations, and we check the return value.
+        let ret = unsafe {
+            libc::syscall(
+                SYS_IO_URING_ENTER,
+                self.fd.as_raw_fd(),
+                self.to_submit,
+                0,
+                0,
+                null_mut::<c_void>(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        self.to_submit -= ret as u32;
+        Ok(ret as u32)
+    }
+
+    /// Blocks until at least `count` operations have completed.
+    pub fn wait(&mut self, count: u32) -> Result<()> {
+        // This is safe because the kernel only accesses the rings, and we check the return value.
+        let ret = unsafe {
+            libc::syscall(
+                SYS_IO_URING_ENTER,
+                self.fd.as_raw_fd(),
+                0,
+                count,
+                IORING_ENTER_GETEVENTS,
+                null_mut::<c_void>(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return errno_result();
+        }
+        Ok(())
+    }
+
+    /// Returns the outcome of the oldest completed operation which was not returned yet.
+    pub fn pop_completion(&mut self) -> Option<Completion> {
+        let head = self
+            .cq_ring
+            .atomic_at(self.cq_off.head)
+            .load(Ordering::Relaxed);
+        let tail = self
+            .cq_ring
+            .atomic_at(self.cq_off.tail)
+            .load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        // This is safe because the entry at `head` was filled in by the kernel, which does not
+        // reuse it until we move the head past it.
+        let cqe = unsafe {
+            *self
+                .cq_ring
+                .at::<io_uring_cqe>(self.cq_off.cqes)
+                .offset((head & self.cq_mask) as isize)
+        };
+        self.cq_ring
+            .atomic_at(self.cq_off.head)
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(Completion {
+            user_data: cqe.user_data,
+            result: cqe.res,
+        })
+    }
+}
+
+impl AsRawFd for IoUring {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use super::*;
+
+    #[test]
+    fn test_sizes() {
+        assert_eq!(mem::size_of::<io_uring_params>(), 120);
+        assert_eq!(mem::size_of::<io_uring_sqe>(), 64);
+        assert_eq!(mem::size_of::<io_uring_cqe>(), 16);
+    }
+
+    #[test]
+    fn test_read_write() {
+        let file = tempfile::tempfile().unwrap();
+        let mut ring = IoUring::new(4).unwrap();
+        let evt = EventFd::new().unwrap();
+        ring.register_eventfd(&evt).unwrap();
+
+        let data = [0xaau8; 512];
+        let mut read_data = [0u8; 512];
+        let write_iovec = iovec {
+            iov_base: data.as_ptr() as *mut c_void,
+            iov_len: data.len(),
+        };
+        let read_iovec = iovec {
+            iov_base: read_data.as_mut_ptr() as *mut c_void,
+            iov_len: read_data.len(),
+        };
+        let fd = file.as_raw_fd();
+        // The buffers outlive the operations, which are waited for.
+        unsafe {
+            ring.push(
+                Operation::Writev {
+                    fd,
+                    iovecs: &write_iovec,
+                    count: 1,
+                    offset: 512,
+                },
+                1,
+            )
+            .unwrap();
+            ring.push(Operation::Fsync { fd, datasync: true }, 2)
+                .unwrap();
+        }
+        assert_eq!(ring.submit().unwrap(), 2);
+        ring.wait(2).unwrap();
+        assert_eq!(
+            ring.pop_completion(),
+            Some(Completion {
+                user_data: 1,
+                result: 512
+            })
+        );
+        assert_eq!(
+            ring.pop_completion(),
+            Some(Completion {
+                user_data: 2,
+                result: 0
+            })
+        );
+        assert_eq!(ring.pop_completion(), None);
+        assert!(evt.read().unwrap() > 0);
+
+        unsafe {
+            ring.push(
+                Operation::Readv {
+                    fd,
+                    iovecs: &read_iovec,
+                    count: 1,
+                    offset: 512,
+                },
+                3,
+            )
+            .unwrap();
+        }
+        ring.submit().unwrap();
+        ring.wait(1).unwrap();
+        assert_eq!(ring.pop_completion().unwrap().result, 512);
+        assert_eq!(&read_data[..], &data[..]);
+
+        // Errors are reported through the completions.
+        unsafe {
+            ring.push(
+                Operation::Fsync {
+                    fd: -1,
+                    datasync: false,
+                },
+                4,
+            )
+            .unwrap();
+        }
+        ring.submit().unwrap();
+        ring.wait(1).unwrap();
+        assert_eq!(ring.pop_completion().unwrap().result, -libc::EBADF);
+    }
+
+    #[test]
+    fn test_full_queue() {
+        let mut ring = IoUring::new(2).unwrap();
+        let file = tempfile::tempfile().unwrap();
+        let fd = file.as_raw_fd();
+        for i in 0..2 {
+            unsafe {
+                ring.push(
+                    Operation::Fsync {
+                        fd,
+                        datasync: false,
+                    },
+                    i,
+                )
+                .unwrap()
+            };
+        }
+        match unsafe {
+            ring.push(
+                Operation::Fsync {
+                    fd,
+                    datasync: false,
+                },
+                2,
+            )
+        } {
+            Err(e) => assert_eq!(e.errno(), libc::EBUSY),
+            Ok(()) => panic!("Expected a full submission queue"),
+        }
+        assert_eq!(ring.submit().unwrap(), 2);
+        ring.wait(2).unwrap();
+        assert!(ring.pop_completion().is_some());
+        assert!(ring.pop_completion().is_some());
+    }
+}
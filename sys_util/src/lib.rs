@@ -14,12 +14,16 @@ pub mod ioctl;
 
 mod errno;
 mod eventfd;
+mod fallocate;
+mod io_uring;
 mod signal;
 mod struct_util;
 mod terminal;
 
 pub use errno::{errno_result, Error, Result};
 pub use eventfd::*;
+pub use fallocate::*;
+pub use io_uring::*;
 pub use ioctl::*;
 pub use signal::*;
 pub use struct_util::*;
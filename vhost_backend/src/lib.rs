@@ -8,7 +8,9 @@ extern crate memory_model;
 extern crate sys_util;
 extern crate vhost_gen;
 
+mod net;
 mod vsock;
+pub use net::Net;
 pub use vsock::Vsock;
 
 use std::mem;
@@ -0,0 +1,164 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+//
+// Portions Copyright 2017 The Chromium OS Authors. All rights reserved.
+// Use of this source code is governed by a BSD-style license that can be
+// found in the THIRD-PARTY file.
+
+use libc;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use super::{ioctl_error, Error, Result, Vhost};
+use memory_model::GuestMemory;
+use sys_util::ioctl_with_ref;
+use vhost_gen::*;
+
+const VHOST_PATH: &'static str = "/dev/vhost-net";
+
+/// Handle for running VHOST_NET ioctls.
+pub struct Net {
+    fd: File,
+    mem: GuestMemory,
+}
+
+impl Net {
+    /// Open a handle to a new VHOST-NET instance.
+    pub fn new(mem: &GuestMemory) -> Result<Net> {
+        Ok(Net {
+            fd: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_CLOEXEC | libc::O_NONBLOCK)
+                .open(VHOST_PATH)
+                .map_err(Error::VhostOpen)?,
+            mem: mem.clone(),
+        })
+    }
+
+    /// Set the tap device the vring transfers data to and from. The kernel takes its own
+    /// reference to the tap device.
+    ///
+    /// # Arguments
+    /// * `queue_index` - Index of the queue to modify.
+    /// * `fd` - Tap device to attach, or `None` to detach the current one.
+    pub fn set_backend(&self, queue_index: usize, fd: Option<&AsRawFd>) -> Result<()> {
+        let vring_file = vhost_vring_file {
+            index: queue_index as u32,
+            fd: fd.map_or(-1, |fd| fd.as_raw_fd()),
+        };
+
+        // This ioctl is called on a valid vhost-net fd and has its
+        // return value checked.
+        let ret = unsafe { ioctl_with_ref(&self.fd, VHOST_NET_SET_BACKEND(), &vring_file) };
+        if ret < 0 {
+            return ioctl_error();
+        }
+        Ok(())
+    }
+}
+
+impl Vhost for Net {
+    fn mem(&self) -> &GuestMemory {
+        &self.mem
+    }
+}
+
+impl AsRawFd for Net {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memory_model::GuestAddress;
+    use vhost_gen::vhost::VHOST_F_LOG_ALL;
+
+    // Returns a handle on a file that is not a vhost-net device, so that its ioctls fail.
+    fn create_bad_net(mem: &GuestMemory) -> Net {
+        Net {
+            fd: File::open("/dev/null").unwrap(),
+            mem: mem.clone(),
+        }
+    }
+
+    #[test]
+    fn test_ioctl_errors() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let net = create_bad_net(&mem);
+
+        match net.get_features() {
+            Err(Error::IoctlError(_)) => (),
+            _ => assert!(false),
+        }
+        match net.set_features(0) {
+            Err(Error::IoctlError(_)) => (),
+            _ => assert!(false),
+        }
+        match net.set_backend(0, Some(&net.fd)) {
+            Err(Error::IoctlError(_)) => (),
+            _ => assert!(false),
+        }
+        match net.set_backend(0, None) {
+            Err(Error::IoctlError(_)) => (),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_set_vring_addr() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let net = create_bad_net(&mem);
+
+        // The rings are checked against the guest memory before reaching the kernel.
+        let set_vring_addr = |queue_size, desc_table_addr| {
+            net.set_vring_addr(
+                256,
+                queue_size,
+                0,
+                0,
+                GuestAddress(desc_table_addr),
+                GuestAddress(0x2000),
+                GuestAddress(0x3000),
+                None,
+            )
+        };
+        match set_vring_addr(512, 0x1000) {
+            Err(Error::InvalidQueue) => (),
+            _ => assert!(false),
+        }
+        match set_vring_addr(3, 0x1000) {
+            Err(Error::InvalidQueue) => (),
+            _ => assert!(false),
+        }
+        match set_vring_addr(256, 0xf000) {
+            Err(Error::InvalidQueue) => (),
+            _ => assert!(false),
+        }
+        match set_vring_addr(256, 0x1000) {
+            Err(Error::IoctlError(_)) => (),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    #[ignore = "requires /dev/vhost-net"]
+    fn test_feature_negotiation() {
+        let mem = GuestMemory::new(&[(GuestAddress(0), 0x10000)]).unwrap();
+        let net = Net::new(&mem).unwrap();
+        net.set_owner().unwrap();
+
+        // The features of the kernel can be enabled, but dirty page logging which needs a log.
+        let features = net.get_features().unwrap();
+        net.set_features(features & !(1 << VHOST_F_LOG_ALL))
+            .unwrap();
+        net.set_mem_table().unwrap();
+        net.set_vring_num(0, 256).unwrap();
+        net.set_vring_base(0, 0).unwrap();
+        net.set_backend(0, None).unwrap();
+    }
+}
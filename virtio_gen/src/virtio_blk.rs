@@ -26,6 +26,8 @@ pub const VIRTIO_BLK_F_RO: ::std::os::raw::c_uint = 5;
 pub const VIRTIO_BLK_F_BLK_SIZE: ::std::os::raw::c_uint = 6;
 pub const VIRTIO_BLK_F_TOPOLOGY: ::std::os::raw::c_uint = 10;
 pub const VIRTIO_BLK_F_MQ: ::std::os::raw::c_uint = 12;
+pub const VIRTIO_BLK_F_DISCARD: ::std::os::raw::c_uint = 13;
+pub const VIRTIO_BLK_F_WRITE_ZEROES: ::std::os::raw::c_uint = 14;
 pub const VIRTIO_BLK_F_BARRIER: ::std::os::raw::c_uint = 0;
 pub const VIRTIO_BLK_F_SCSI: ::std::os::raw::c_uint = 7;
 pub const VIRTIO_BLK_F_FLUSH: ::std::os::raw::c_uint = 9;
@@ -37,6 +39,9 @@ pub const VIRTIO_BLK_T_OUT: ::std::os::raw::c_uint = 1;
 pub const VIRTIO_BLK_T_SCSI_CMD: ::std::os::raw::c_uint = 2;
 pub const VIRTIO_BLK_T_FLUSH: ::std::os::raw::c_uint = 4;
 pub const VIRTIO_BLK_T_GET_ID: ::std::os::raw::c_uint = 8;
+pub const VIRTIO_BLK_T_DISCARD: ::std::os::raw::c_uint = 11;
+pub const VIRTIO_BLK_T_WRITE_ZEROES: ::std::os::raw::c_uint = 13;
+pub const VIRTIO_BLK_WRITE_ZEROES_FLAG_UNMAP: ::std::os::raw::c_uint = 1;
 pub const VIRTIO_BLK_T_BARRIER: ::std::os::raw::c_uint = 2147483648;
 pub const VIRTIO_BLK_S_OK: ::std::os::raw::c_uint = 0;
 pub const VIRTIO_BLK_S_IOERR: ::std::os::raw::c_uint = 1;
@@ -343,6 +348,13 @@ pub struct virtio_blk_config {
     pub wce: __u8,
     pub unused: __u8,
     pub num_queues: __u16,
+    pub max_discard_sectors: __u32,
+    pub max_discard_seg: __u32,
+    pub discard_sector_alignment: __u32,
+    pub max_write_zeroes_sectors: __u32,
+    pub max_write_zeroes_seg: __u32,
+    pub write_zeroes_may_unmap: __u8,
+    pub unused1: [__u8; 3usize],
 }
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
@@ -374,7 +386,7 @@ fn bindgen_test_layout_virtio_blk_config_virtio_blk_geometry() {
 fn bindgen_test_layout_virtio_blk_config() {
     assert_eq!(
         ::std::mem::size_of::<virtio_blk_config>(),
-        36usize,
+        60usize,
         concat!("Size of: ", stringify!(virtio_blk_config))
     );
     assert_eq!(
@@ -405,6 +417,26 @@ fn bindgen_test_layout_virtio_blk_outhdr() {
 }
 #[repr(C)]
 #[derive(Debug, Default, Copy, Clone)]
+pub struct virtio_blk_discard_write_zeroes {
+    pub sector: __le64,
+    pub num_sectors: __le32,
+    pub flags: __le32,
+}
+#[test]
+fn bindgen_test_layout_virtio_blk_discard_write_zeroes() {
+    assert_eq!(
+        ::std::mem::size_of::<virtio_blk_discard_write_zeroes>(),
+        16usize,
+        concat!("Size of: ", stringify!(virtio_blk_discard_write_zeroes))
+    );
+    assert_eq!(
+        ::std::mem::align_of::<virtio_blk_discard_write_zeroes>(),
+        8usize,
+        concat!("Alignment of ", stringify!(virtio_blk_discard_write_zeroes))
+    );
+}
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
 pub struct virtio_scsi_inhdr {
     pub errors: __virtio32,
     pub data_len: __virtio32,
@@ -6,6 +6,7 @@ extern crate libc;
 use seccomp::{
     Error, SeccompAction, SeccompCmpOp, SeccompCondition, SeccompFilterContext, SeccompRule,
 };
+use sys_util::{SYS_IO_URING_ENTER, SYS_IO_URING_REGISTER, SYS_IO_URING_SETUP};
 
 /// List of allowed syscalls, necessary for Firecracker to function correctly.
 pub const ALLOWED_SYSCALLS: &[i64] = &[
@@ -24,8 +25,11 @@ pub const ALLOWED_SYSCALLS: &[i64] = &[
     libc::SYS_rt_sigprocmask,
     libc::SYS_rt_sigreturn,
     libc::SYS_ioctl,
+    libc::SYS_pread64,
+    libc::SYS_pwrite64,
     libc::SYS_readv,
     libc::SYS_writev,
+    libc::SYS_rename,
     libc::SYS_pipe,
     libc::SYS_dup,
     libc::SYS_socket,
@@ -36,6 +40,8 @@ pub const ALLOWED_SYSCALLS: &[i64] = &[
     libc::SYS_execve,
     libc::SYS_exit,
     libc::SYS_fcntl,
+    libc::SYS_fdatasync,
+    libc::SYS_flock,
     libc::SYS_readlink,
     libc::SYS_sigaltstack,
     libc::SYS_prctl,
@@ -47,15 +53,22 @@ pub const ALLOWED_SYSCALLS: &[i64] = &[
     libc::SYS_epoll_ctl,
     libc::SYS_epoll_pwait,
     libc::SYS_timerfd_create,
+    libc::SYS_fallocate,
     libc::SYS_eventfd2,
     libc::SYS_epoll_create1,
     libc::SYS_getrandom,
+    SYS_IO_URING_SETUP,
+    SYS_IO_URING_ENTER,
+    SYS_IO_URING_REGISTER,
 ];
 
 // See /usr/include/x86_64-linux-gnu/sys/epoll.h
 const EPOLL_CTL_ADD: u64 = 1;
 const EPOLL_CTL_DEL: u64 = 2;
 
+// See include/uapi/linux/io_uring.h
+const IORING_REGISTER_EVENTFD: u64 = 4;
+
 // See /usr/include/x86_64-linux-gnu/bits/fcntl-linux.h
 const O_RDONLY: u64 = 0x00000000;
 const O_RDWR: u64 = 0x00000002;
@@ -66,6 +79,17 @@ const F_SETFD: u64 = 2;
 const F_SETFL: u64 = 4;
 const FD_CLOEXEC: u64 = 1;
 
+// See /usr/include/x86_64-linux-gnu/sys/file.h
+const LOCK_EX: u64 = 2;
+const LOCK_UN: u64 = 8;
+
+// See /usr/include/linux/time.h
+const CLOCK_MONOTONIC: u64 = 1;
+
+// See /usr/include/x86_64-linux-gnu/sys/timerfd.h
+const TFD_NONBLOCK: u64 = 0x00000800;
+const TFD_CLOEXEC: u64 = 0x00080000;
+
 // See /usr/include/linux/futex.h
 const FUTEX_WAIT: u64 = 0;
 const FUTEX_WAKE: u64 = 1;
@@ -105,10 +129,26 @@ const KVM_GET_SREGS: u64 = 0x8138ae83;
 const KVM_GET_LAPIC: u64 = 0x8400ae8e;
 const KVM_GET_SUPPORTED_CPUID: u64 = 0xc008ae05;
 
+// See /usr/include/linux/fs.h
+const BLKSSZGET: u64 = 0x1268;
+
 // See /usr/include/linux/if_tun.h
 const TUNSETIFF: u64 = 0x400454ca;
 const TUNSETOFFLOAD: u64 = 0x400454d0;
 const TUNSETVNETHDRSZ: u64 = 0x400454d8;
+const TUNSETQUEUE: u64 = 0x400454d9;
+
+// See /usr/include/linux/vhost.h
+const VHOST_SET_OWNER: u64 = 0xaf01;
+const VHOST_SET_FEATURES: u64 = 0x4008af00;
+const VHOST_SET_MEM_TABLE: u64 = 0x4008af03;
+const VHOST_SET_VRING_NUM: u64 = 0x4008af10;
+const VHOST_SET_VRING_ADDR: u64 = 0x4028af11;
+const VHOST_SET_VRING_BASE: u64 = 0x4008af12;
+const VHOST_SET_VRING_KICK: u64 = 0x4008af20;
+const VHOST_SET_VRING_CALL: u64 = 0x4008af21;
+const VHOST_NET_SET_BACKEND: u64 = 0x4008af30;
+const VHOST_GET_FEATURES: u64 = 0x8008af00;
 
 // See /usr/include/asm-generic/mman-common.h and /usr/include/asm-generic/mman.h
 const PROT_NONE: u64 = 0x0;
@@ -186,6 +226,10 @@ pub fn default_context() -> Result<SeccompFilterContext, Error> {
                     )],
                 ),
             ),
+            (
+                libc::SYS_fallocate,
+                (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
+            ),
             (
                 libc::SYS_fcntl,
                 (
@@ -216,6 +260,26 @@ pub fn default_context() -> Result<SeccompFilterContext, Error> {
                     ],
                 ),
             ),
+            (
+                libc::SYS_fdatasync,
+                (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
+            ),
+            (
+                libc::SYS_flock,
+                (
+                    0,
+                    vec![
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(1, SeccompCmpOp::Eq, LOCK_EX)?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(1, SeccompCmpOp::Eq, LOCK_UN)?],
+                            SeccompAction::Allow,
+                        ),
+                    ],
+                ),
+            ),
             (
                 libc::SYS_fstat,
                 (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
@@ -252,6 +316,28 @@ pub fn default_context() -> Result<SeccompFilterContext, Error> {
                     ],
                 ),
             ),
+            (
+                SYS_IO_URING_SETUP,
+                (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
+            ),
+            (
+                SYS_IO_URING_ENTER,
+                (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
+            ),
+            (
+                SYS_IO_URING_REGISTER,
+                (
+                    0,
+                    vec![SeccompRule::new(
+                        vec![SeccompCondition::new(
+                            1,
+                            SeccompCmpOp::Eq,
+                            IORING_REGISTER_EVENTFD,
+                        )?],
+                        SeccompAction::Allow,
+                    )],
+                ),
+            ),
             (
                 libc::SYS_ioctl,
                 (
@@ -353,6 +439,10 @@ pub fn default_context() -> Result<SeccompFilterContext, Error> {
                             vec![SeccompCondition::new(1, SeccompCmpOp::Eq, FIONBIO)?],
                             SeccompAction::Allow,
                         ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(1, SeccompCmpOp::Eq, BLKSSZGET)?],
+                            SeccompAction::Allow,
+                        ),
                         SeccompRule::new(
                             vec![SeccompCondition::new(1, SeccompCmpOp::Eq, TUNSETIFF)?],
                             SeccompAction::Allow,
@@ -365,6 +455,86 @@ pub fn default_context() -> Result<SeccompFilterContext, Error> {
                             vec![SeccompCondition::new(1, SeccompCmpOp::Eq, TUNSETVNETHDRSZ)?],
                             SeccompAction::Allow,
                         ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(1, SeccompCmpOp::Eq, TUNSETQUEUE)?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(1, SeccompCmpOp::Eq, VHOST_SET_OWNER)?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(
+                                1,
+                                SeccompCmpOp::Eq,
+                                VHOST_GET_FEATURES,
+                            )?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(
+                                1,
+                                SeccompCmpOp::Eq,
+                                VHOST_SET_FEATURES,
+                            )?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(
+                                1,
+                                SeccompCmpOp::Eq,
+                                VHOST_SET_MEM_TABLE,
+                            )?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(
+                                1,
+                                SeccompCmpOp::Eq,
+                                VHOST_SET_VRING_NUM,
+                            )?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(
+                                1,
+                                SeccompCmpOp::Eq,
+                                VHOST_SET_VRING_ADDR,
+                            )?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(
+                                1,
+                                SeccompCmpOp::Eq,
+                                VHOST_SET_VRING_BASE,
+                            )?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(
+                                1,
+                                SeccompCmpOp::Eq,
+                                VHOST_SET_VRING_KICK,
+                            )?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(
+                                1,
+                                SeccompCmpOp::Eq,
+                                VHOST_SET_VRING_CALL,
+                            )?],
+                            SeccompAction::Allow,
+                        ),
+                        SeccompRule::new(
+                            vec![SeccompCondition::new(
+                                1,
+                                SeccompCmpOp::Eq,
+                                VHOST_NET_SET_BACKEND,
+                            )?],
+                            SeccompAction::Allow,
+                        ),
                         SeccompRule::new(
                             vec![SeccompCondition::new(1, SeccompCmpOp::Eq, KVM_GET_LAPIC)?],
                             SeccompAction::Allow,
@@ -566,6 +736,14 @@ pub fn default_context() -> Result<SeccompFilterContext, Error> {
                 libc::SYS_pipe,
                 (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
             ),
+            (
+                libc::SYS_pread64,
+                (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
+            ),
+            (
+                libc::SYS_pwrite64,
+                (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
+            ),
             (
                 libc::SYS_read,
                 (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
@@ -578,6 +756,10 @@ pub fn default_context() -> Result<SeccompFilterContext, Error> {
                 libc::SYS_readv,
                 (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
             ),
+            (
+                libc::SYS_rename,
+                (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
+            ),
             (
                 libc::SYS_socket,
                 (
@@ -592,6 +774,19 @@ pub fn default_context() -> Result<SeccompFilterContext, Error> {
                 libc::SYS_stat,
                 (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
             ),
+            (
+                libc::SYS_timerfd_create,
+                (
+                    0,
+                    vec![SeccompRule::new(
+                        vec![
+                            SeccompCondition::new(0, SeccompCmpOp::Eq, CLOCK_MONOTONIC)?,
+                            SeccompCondition::new(1, SeccompCmpOp::Eq, TFD_NONBLOCK | TFD_CLOEXEC)?,
+                        ],
+                        SeccompAction::Allow,
+                    )],
+                ),
+            ),
             (
                 libc::SYS_timerfd_settime,
                 (0, vec![SeccompRule::new(vec![], SeccompAction::Allow)]),
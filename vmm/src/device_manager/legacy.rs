@@ -10,6 +10,7 @@ use std::sync::{Arc, Mutex};
 
 use devices;
 use sys_util::{self, EventFd, Terminal};
+use x86_64;
 
 /// Errors corresponding to the `LegacyDeviceManager`.
 #[derive(Debug)]
@@ -25,20 +26,27 @@ pub enum Error {
 type Result<T> = ::std::result::Result<T, Error>;
 
 /// The `LegacyDeviceManager` is a wrapper that is used for registering legacy devices
-/// on an I/O Bus. It currently manages the uart and i8042 devices.
+/// on an I/O Bus. It currently manages the uart, i8042, ACPI power management and ACPI hot-plug
+/// devices.
 /// The `LegacyDeviceManger` should be initialized only by using the constructor.
 pub struct LegacyDeviceManager {
     pub io_bus: devices::Bus,
     pub stdio_serial: Arc<Mutex<devices::legacy::Serial>>,
     pub i8042: Arc<Mutex<devices::legacy::I8042Device>>,
+    pub acpi_pm: Arc<Mutex<devices::legacy::AcpiPmDevice>>,
+    pub acpi_hotplug: Arc<Mutex<devices::legacy::AcpiHotplugDevice>>,
 
     pub com_evt_1_3: EventFd,
     pub com_evt_2_4: EventFd,
+    pub kbd_evt: EventFd,
+    pub acpi_hotplug_evt: EventFd,
+    pub acpi_eject_evt: EventFd,
     pub stdin_handle: io::Stdin,
 }
 
 impl LegacyDeviceManager {
-    /// Create a new DeviceManager handling legacy devices (uart, i8042).
+    /// Create a new DeviceManager handling legacy devices (uart, i8042, ACPI power management and
+    /// hot-plug).
     pub fn new() -> Result<Self> {
         let io_bus = devices::Bus::new();
         let com_evt_1_3 = EventFd::new().map_err(Error::EventFd)?;
@@ -48,16 +56,33 @@ impl LegacyDeviceManager {
             Box::new(stdout()),
         )));
 
-        // Create exit event for i8042
+        // Create exit and keyboard interrupt events for i8042
         let exit_evt = EventFd::new().map_err(Error::EventFd)?;
-        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(exit_evt)));
+        let kbd_evt = EventFd::new().map_err(Error::EventFd)?;
+        let i8042 = Arc::new(Mutex::new(devices::legacy::I8042Device::new(
+            exit_evt.try_clone().map_err(Error::EventFd)?,
+            kbd_evt.try_clone().map_err(Error::EventFd)?,
+        )));
+        // An ACPI power off exits Firecracker just like a reset through the i8042 does.
+        let acpi_pm = Arc::new(Mutex::new(devices::legacy::AcpiPmDevice::new(exit_evt)));
+        let acpi_hotplug_evt = EventFd::new().map_err(Error::EventFd)?;
+        let acpi_eject_evt = EventFd::new().map_err(Error::EventFd)?;
+        let acpi_hotplug = Arc::new(Mutex::new(devices::legacy::AcpiHotplugDevice::new(
+            acpi_hotplug_evt.try_clone().map_err(Error::EventFd)?,
+            acpi_eject_evt.try_clone().map_err(Error::EventFd)?,
+        )));
 
         Ok(LegacyDeviceManager {
             io_bus,
             stdio_serial,
             i8042,
+            acpi_pm,
+            acpi_hotplug,
             com_evt_1_3,
             com_evt_2_4,
+            kbd_evt,
+            acpi_hotplug_evt,
+            acpi_eject_evt,
             stdin_handle: io::stdin(),
         })
     }
@@ -99,7 +124,21 @@ impl LegacyDeviceManager {
             .set_raw_mode()
             .map_err(|e| Error::StdinHandle(e))?;
         self.io_bus
-            .insert(self.i8042.clone(), 0x064, 0x1)
+            .insert(self.i8042.clone(), 0x060, 0x5)
+            .map_err(|err| Error::BusError(err))?;
+        self.io_bus
+            .insert(
+                self.acpi_pm.clone(),
+                u64::from(x86_64::SLEEP_CONTROL_PORT),
+                0x1,
+            )
+            .map_err(|err| Error::BusError(err))?;
+        self.io_bus
+            .insert(
+                self.acpi_hotplug.clone(),
+                u64::from(x86_64::ACPI_HOTPLUG_PORT),
+                u64::from(x86_64::ACPI_HOTPLUG_LEN),
+            )
             .map_err(|err| Error::BusError(err))?;
         Ok(())
     }
@@ -10,11 +10,13 @@ use std::fmt;
 use std::sync::{Arc, Mutex};
 
 use devices;
+use devices::virtio::{MmioDevice, MmioDeviceState, RestoreError};
 use kernel_cmdline;
-use kvm::IoeventAddress;
+use kvm::{IoeventAddress, VmFd};
 use memory_model::GuestMemory;
-use sys_util;
-use vm_control::VmRequest;
+use sys_util::{self, EventFd};
+use vm_control::{VmRequest, VmResponse};
+use x86_64::MmioDeviceInfo;
 
 /// Errors for MMIO device manager.
 #[derive(Debug)]
@@ -29,8 +31,22 @@ pub enum Error {
     CloneIrqFd(sys_util::Error),
     /// Appending to kernel command line failed.
     Cmdline(kernel_cmdline::Error),
+    /// The number of saved device states (second value) does not match the number of
+    /// registered devices (first value).
+    DeviceCountMismatch(usize, usize),
+    /// No registered device has the given id.
+    DeviceNotFound,
+    /// The device was announced on the kernel command line, so the guest cannot forget it.
+    DeviceOnCmdline,
     /// No more IRQs are available.
     IrqsExhausted,
+    /// Failed to register or unregister the ioevents or the irqfd of a device.
+    RegisterEvent(sys_util::Error),
+    /// Failed to restore the state of a mmio device.
+    RestoreState(RestoreError),
+    /// The saved state belongs to a device at another address (first value) or IRQ (second
+    /// value) than the registered one.
+    SlotMismatch(u64, u32),
     /// Failed to update the mmio device.
     UpdateFailed,
 }
@@ -45,7 +61,25 @@ impl fmt::Display for Error {
             &Error::Cmdline(ref e) => {
                 write!(f, "unable to add device to kernel command line: {}", e)
             }
+            &Error::DeviceCountMismatch(registered, saved) => write!(
+                f,
+                "found {} saved device states for {} registered devices",
+                saved, registered
+            ),
+            &Error::DeviceNotFound => write!(f, "no device with this id is registered"),
+            &Error::DeviceOnCmdline => {
+                write!(f, "the device was announced on the kernel command line")
+            }
             &Error::IrqsExhausted => write!(f, "no more IRQs are available"),
+            &Error::RegisterEvent(ref e) => {
+                write!(f, "failed to register the device events: {:?}", e)
+            }
+            &Error::RestoreState(ref e) => write!(f, "failed to restore device state: {:?}", e),
+            &Error::SlotMismatch(addr, irq) => write!(
+                f,
+                "the saved device at 0x{:08x} with IRQ {} is not registered there",
+                addr, irq
+            ),
             &Error::UpdateFailed => write!(f, "failed to update the mmio device"),
         }
     }
@@ -68,89 +102,295 @@ const MMIO_LEN: u64 = 0x1000;
 /// to its configuration space.
 const MMIO_CFG_SPACE_OFF: u64 = 0x100;
 
+// A place on the bus for a device. The vCPUs get their own copy of the bus when they start, so
+// the devices plugged and unplugged afterwards are put in and taken out of the slots instead.
+struct MmioSlot {
+    device: Option<Arc<Mutex<MmioDevice>>>,
+}
+
+impl devices::BusDevice for MmioSlot {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        match self.device {
+            // If the lock is poisoned, it's OK to panic.
+            Some(ref device) => device
+                .lock()
+                .expect("Failed to acquire mmio device lock")
+                .read(offset, data),
+            // The guest reads a zero magic value, and doesn't find any device there.
+            None => {
+                for byte in data.iter_mut() {
+                    *byte = 0;
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        if let Some(ref device) = self.device {
+            // If the lock is poisoned, it's OK to panic.
+            device
+                .lock()
+                .expect("Failed to acquire mmio device lock")
+                .write(offset, data);
+        }
+    }
+
+    fn interrupt(&self, irq_mask: u32) {
+        if let Some(ref device) = self.device {
+            // If the lock is poisoned, it's OK to panic.
+            device
+                .lock()
+                .expect("Failed to acquire mmio device lock")
+                .interrupt(irq_mask);
+        }
+    }
+}
+
+// A device in a slot, with copies of the events registered for it so that they can be
+// unregistered when it is unplugged.
+struct SlotDevice {
+    device: Arc<Mutex<MmioDevice>>,
+    id: Option<String>,
+    queue_evts: Vec<EventFd>,
+    interrupt_evt: Option<EventFd>,
+    // Whether the device was announced on the kernel command line rather than through ACPI.
+    on_cmdline: bool,
+}
+
 /// Manages the complexities of registering a MMIO device.
 pub struct MMIODeviceManager {
     pub bus: devices::Bus,
     pub vm_requests: Vec<VmRequest>,
     guest_mem: GuestMemory,
-    mmio_base: u64,
-    irq: u32,
     id_to_addr_map: HashMap<String, u64>,
+    // The slots on the bus, one per IRQ, and the devices they hold.
+    slots: Vec<Arc<Mutex<MmioSlot>>>,
+    devices: Vec<Option<SlotDevice>>,
+    // Where each slot sits, so that it can be described to the guest through ACPI.
+    slot_info: Vec<MmioDeviceInfo>,
 }
 
 impl MMIODeviceManager {
     /// Create a new DeviceManager handling mmio devices (virtio net, block).
     pub fn new(guest_mem: GuestMemory, mmio_base: u64) -> MMIODeviceManager {
+        let mut bus = devices::Bus::new();
+        let mut slots = Vec::new();
+        let mut slot_info = Vec::new();
+        for irq in IRQ_BASE..(MAX_IRQ + 1) {
+            let addr = mmio_base + u64::from(irq - IRQ_BASE) * MMIO_LEN;
+            let slot = Arc::new(Mutex::new(MmioSlot { device: None }));
+            // It is safe to unwrap because the slots don't overlap.
+            bus.insert(slot.clone(), addr, MMIO_LEN).unwrap();
+            slots.push(slot);
+            slot_info.push(MmioDeviceInfo {
+                addr,
+                len: MMIO_LEN,
+                irq,
+            });
+        }
+
         MMIODeviceManager {
             guest_mem: guest_mem,
             vm_requests: Vec::new(),
-            mmio_base: mmio_base,
-            irq: IRQ_BASE,
-            bus: devices::Bus::new(),
+            bus,
             id_to_addr_map: HashMap::new(),
+            devices: slot_info.iter().map(|_| None).collect(),
+            slots,
+            slot_info,
         }
     }
 
     /// Register a device to be used via MMIO transport.
+    /// The guest finds the device through the ACPI tables, unless `add_cmdline_devices` is
+    /// called.
     pub fn register_device(
         &mut self,
         device: Box<devices::virtio::VirtioDevice>,
-        cmdline: &mut kernel_cmdline::Cmdline,
         id: Option<String>,
     ) -> Result<u64> {
-        if self.irq > MAX_IRQ {
-            return Err(Error::IrqsExhausted);
+        let (slot, requests) = self.add_device(device, id)?;
+        self.vm_requests.extend(requests);
+        Ok(self.slot_info[slot].addr)
+    }
+
+    /// Announces the registered devices on the kernel command line, for guests that don't
+    /// read the ACPI tables. These devices can no longer be unplugged.
+    pub fn add_cmdline_devices(&mut self, cmdline: &mut kernel_cmdline::Cmdline) -> Result<()> {
+        for (slot, device) in self.devices.iter_mut().enumerate() {
+            if let Some(ref mut device) = *device {
+                let info = self.slot_info[slot];
+                // as per doc, [virtio_mmio.]device=<size>@<baseaddr>:<irq> needs to be appended
+                // to kernel commandline for virtio mmio devices to get recognized
+                // the size parameter has to be transformed to KiB, so dividing hexadecimal value
+                // in bytes to 1024; further, the '{}' formatting rust construct will
+                // automatically transform it to decimal
+                cmdline
+                    .insert(
+                        "virtio_mmio.device",
+                        &format!("{}K@0x{:08x}:{}", MMIO_LEN / 1024, info.addr, info.irq),
+                    )
+                    .map_err(Error::Cmdline)?;
+                device.on_cmdline = true;
+            }
         }
+        Ok(())
+    }
+
+    /// Plugs a device into a free slot while the guest is running, and registers its events
+    /// with `vm`. Returns the slot, which the guest has yet to be told about.
+    pub fn hotplug_device(
+        &mut self,
+        device: Box<devices::virtio::VirtioDevice>,
+        id: Option<String>,
+        vm: &VmFd,
+    ) -> Result<usize> {
+        let (slot, requests) = self.add_device(device, id)?;
+        for request in &requests {
+            if let VmResponse::Err(e) = request.execute(vm) {
+                // Unregistering the events that weren't registered fails too, so the result
+                // doesn't tell anything.
+                let _ = self.remove_device(slot, vm);
+                return Err(Error::RegisterEvent(e));
+            }
+        }
+        Ok(slot)
+    }
+
+    /// Returns the slot of the device with the given id, if the guest can be asked to eject it.
+    pub fn unpluggable_slot(&self, id: &str) -> Result<usize> {
+        let slot = self
+            .devices
+            .iter()
+            .position(|device| match *device {
+                Some(SlotDevice {
+                    id: Some(ref device_id),
+                    ..
+                }) => device_id == id,
+                _ => false,
+            })
+            .ok_or(Error::DeviceNotFound)?;
+        if self.devices[slot]
+            .as_ref()
+            .map_or(false, |device| device.on_cmdline)
+        {
+            return Err(Error::DeviceOnCmdline);
+        }
+        Ok(slot)
+    }
+
+    /// Unplugs the device in `slot`, which the guest ejected, and unregisters its events from
+    /// `vm`. Returns the id of the device.
+    pub fn unplug_device(&mut self, slot: usize, vm: &VmFd) -> Result<Option<String>> {
+        let id = self.devices[slot]
+            .as_ref()
+            .ok_or(Error::DeviceNotFound)?
+            .id
+            .clone();
+        self.remove_device(slot, vm)?;
+        Ok(id)
+    }
+
+    // Puts `device` in the first free slot. Returns the slot and the requests registering the
+    // events of the device.
+    fn add_device(
+        &mut self,
+        device: Box<devices::virtio::VirtioDevice>,
+        id: Option<String>,
+    ) -> Result<(usize, Vec<VmRequest>)> {
+        let slot = self
+            .devices
+            .iter()
+            .position(Option::is_none)
+            .ok_or(Error::IrqsExhausted)?;
+        let info = self.slot_info[slot];
 
         let mmio_device = devices::virtio::MmioDevice::new(self.guest_mem.clone(), device)
             .map_err(Error::CreateMmioDevice)?;
+        let mut requests = Vec::new();
+        let mut queue_evts = Vec::new();
         for (i, queue_evt) in mmio_device.queue_evts().iter().enumerate() {
             let io_addr =
-                IoeventAddress::Mmio(self.mmio_base + devices::virtio::NOTIFY_REG_OFFSET as u64);
-            self.vm_requests.push(VmRequest::RegisterIoevent(
+                IoeventAddress::Mmio(info.addr + devices::virtio::NOTIFY_REG_OFFSET as u64);
+            requests.push(VmRequest::RegisterIoevent(
                 queue_evt.try_clone().map_err(Error::CloneIoeventFd)?,
                 io_addr,
                 i as u32,
             ));
+            queue_evts.push(queue_evt.try_clone().map_err(Error::CloneIoeventFd)?);
         }
 
-        if let Some(interrupt_evt) = mmio_device.interrupt_evt() {
-            self.vm_requests.push(VmRequest::RegisterIrqfd(
-                interrupt_evt.try_clone().map_err(Error::CloneIrqFd)?,
-                self.irq,
-            ));
+        let interrupt_evt = match mmio_device.interrupt_evt() {
+            Some(interrupt_evt) => {
+                requests.push(VmRequest::RegisterIrqfd(
+                    interrupt_evt.try_clone().map_err(Error::CloneIrqFd)?,
+                    info.irq,
+                ));
+                Some(interrupt_evt.try_clone().map_err(Error::CloneIrqFd)?)
+            }
+            None => None,
+        };
+
+        let mmio_device = Arc::new(Mutex::new(mmio_device));
+        // If the lock is poisoned, it's OK to panic.
+        self.slots[slot]
+            .lock()
+            .expect("Failed to acquire mmio slot lock")
+            .device = Some(mmio_device.clone());
+        if let Some(ref device_id) = id {
+            self.id_to_addr_map.insert(device_id.clone(), info.addr);
         }
+        self.devices[slot] = Some(SlotDevice {
+            device: mmio_device,
+            id,
+            queue_evts,
+            interrupt_evt,
+            on_cmdline: false,
+        });
+
+        Ok((slot, requests))
+    }
 
-        self.bus
-            .insert(Arc::new(Mutex::new(mmio_device)), self.mmio_base, MMIO_LEN)
-            .map_err(|err| Error::BusError(err))?;
-
-        // as per doc, [virtio_mmio.]device=<size>@<baseaddr>:<irq> needs to be appended
-        // to kernel commandline for virtio mmio devices to get recognized
-        // the size parameter has to be transformed to KiB, so dividing hexadecimal value in
-        // bytes to 1024; further, the '{}' formatting rust construct will automatically
-        // transform it to decimal
-        cmdline
-            .insert(
-                "virtio_mmio.device",
-                &format!("{}K@0x{:08x}:{}", MMIO_LEN / 1024, self.mmio_base, self.irq),
-            )
-            .map_err(Error::Cmdline)?;
-        let ret = self.mmio_base;
-        self.mmio_base += MMIO_LEN;
-        self.irq += 1;
-
-        if let Some(device_id) = id {
-            self.id_to_addr_map.insert(device_id.clone(), ret);
+    // Empties `slot` and unregisters the events of the device it held.
+    fn remove_device(&mut self, slot: usize, vm: &VmFd) -> Result<()> {
+        let device = match self.devices[slot].take() {
+            Some(device) => device,
+            None => return Err(Error::DeviceNotFound),
+        };
+        // If the lock is poisoned, it's OK to panic.
+        self.slots[slot]
+            .lock()
+            .expect("Failed to acquire mmio slot lock")
+            .device = None;
+        if let Some(ref device_id) = device.id {
+            self.id_to_addr_map.remove(device_id);
         }
 
-        Ok(ret)
+        let info = self.slot_info[slot];
+        let mut requests = Vec::new();
+        for (i, queue_evt) in device.queue_evts.into_iter().enumerate() {
+            let io_addr =
+                IoeventAddress::Mmio(info.addr + devices::virtio::NOTIFY_REG_OFFSET as u64);
+            requests.push(VmRequest::UnregisterIoevent(queue_evt, io_addr, i as u32));
+        }
+        if let Some(interrupt_evt) = device.interrupt_evt {
+            requests.push(VmRequest::UnregisterIrqfd(interrupt_evt, info.irq));
+        }
+        // Unregister all the events even if some of them fail, and report the first failure.
+        let mut result = Ok(());
+        for request in &requests {
+            if let VmResponse::Err(e) = request.execute(vm) {
+                if result.is_ok() {
+                    result = Err(Error::RegisterEvent(e));
+                }
+            }
+        }
+        result
     }
 
     /// Update a drive by rebuilding its config space and rewriting it on the bus.
-    pub fn update_drive(&self, addr: u64, new_size: u64) -> Result<()> {
+    pub fn update_drive(&self, addr: u64, new_size: u64, num_queues: u16) -> Result<()> {
         if let Some((_, device)) = self.bus.get_device(addr) {
-            let data = devices::virtio::build_config_space(new_size);
+            let data = devices::virtio::build_config_space(new_size, num_queues);
             let mut busdev = device.lock().map_err(|_| Error::UpdateFailed)?;
 
             busdev.write(MMIO_CFG_SPACE_OFF, &data[..]);
@@ -167,6 +407,80 @@ impl MMIODeviceManager {
         return self.id_to_addr_map.get(id.as_str());
     }
 
+    /// Returns the location and interrupt of every slot, whether it holds a device or not.
+    pub fn slot_info(&self) -> &[MmioDeviceInfo] {
+        &self.slot_info
+    }
+
+    /// Returns the slots that hold a device.
+    pub fn occupied_slots(&self) -> Vec<usize> {
+        self.devices
+            .iter()
+            .enumerate()
+            .filter(|&(_, device)| device.is_some())
+            .map(|(slot, _)| slot)
+            .collect()
+    }
+
+    /// Returns the slots that hold a device the guest finds through the ACPI tables, i.e. one
+    /// that isn't announced on the kernel command line as well.
+    pub fn acpi_slots(&self) -> Vec<usize> {
+        self.devices
+            .iter()
+            .enumerate()
+            .filter(|&(_, device)| device.as_ref().map_or(false, |device| !device.on_cmdline))
+            .map(|(slot, _)| slot)
+            .collect()
+    }
+
+    /// Returns the state of every registered device, in slot order.
+    pub fn save_state(&self) -> Vec<MmioDeviceState> {
+        self.devices
+            .iter()
+            .zip(self.slot_info.iter())
+            .filter_map(|(device, info)| device.as_ref().map(|device| (device, info)))
+            // If the lock is poisoned, it's OK to panic.
+            .map(|(slot_device, info)| {
+                slot_device
+                    .device
+                    .lock()
+                    .expect("Failed to save device state due to poisoned lock")
+                    .save_state(info.addr, info.irq)
+            })
+            .collect()
+    }
+
+    /// Restores the states returned by `save_state`. The same devices have to be registered,
+    /// in the same slots, as when the states were saved, so that the guest finds them at the
+    /// same addresses and IRQs.
+    pub fn restore_state(&self, states: &[MmioDeviceState]) -> Result<()> {
+        let devices: Vec<(&SlotDevice, &MmioDeviceInfo)> = self
+            .devices
+            .iter()
+            .zip(self.slot_info.iter())
+            .filter_map(|(device, info)| device.as_ref().map(|device| (device, info)))
+            .collect();
+        if states.len() != devices.len() {
+            return Err(Error::DeviceCountMismatch(devices.len(), states.len()));
+        }
+        // The devices are only restored once all of them are known to be in their slots.
+        for (&(_, info), state) in devices.iter().zip(states) {
+            if state.mmio_addr != info.addr || state.irq != info.irq {
+                return Err(Error::SlotMismatch(state.mmio_addr, state.irq));
+            }
+        }
+        for ((slot_device, _), state) in devices.into_iter().zip(states) {
+            // If the lock is poisoned, it's OK to panic.
+            slot_device
+                .device
+                .lock()
+                .expect("Failed to restore device state due to poisoned lock")
+                .restore_state(state)
+                .map_err(Error::RestoreState)?;
+        }
+        Ok(())
+    }
+
     /// Removing the address of a device will generate an error when you try to update the
     /// drive. The purpose of this method is to test error scenarios and should otherwise
     /// not be used.
@@ -181,6 +495,7 @@ mod tests {
     use super::*;
     use devices::virtio::{ActivateResult, VirtioDevice};
     use kernel_cmdline;
+    use kvm::Kvm;
     use memory_model::{GuestAddress, GuestMemory};
     use std::sync::atomic::AtomicUsize;
     use sys_util::EventFd;
@@ -241,9 +556,98 @@ mod tests {
         let mut cmdline = kernel_cmdline::Cmdline::new(4096);
         let dummy_box = Box::new(DummyDevice { dummy: 0 });
 
-        assert!(device_manager
-            .register_device(dummy_box, &mut cmdline, None)
-            .is_ok());
+        assert!(device_manager.register_device(dummy_box, None).is_ok());
+        assert_eq!(
+            device_manager.slot_info().len(),
+            (MAX_IRQ - IRQ_BASE + 1) as usize
+        );
+        assert_eq!(
+            device_manager.slot_info()[1],
+            MmioDeviceInfo {
+                addr: 0xd0000000 + MMIO_LEN,
+                len: MMIO_LEN,
+                irq: IRQ_BASE + 1,
+            }
+        );
+        assert_eq!(device_manager.occupied_slots(), vec![0]);
+        assert_eq!(device_manager.acpi_slots(), vec![0]);
+        assert_eq!(device_manager.vm_requests.len(), 2);
+
+        // The devices are only announced on the command line when asked to.
+        assert_eq!(cmdline.as_str(), "");
+        device_manager.add_cmdline_devices(&mut cmdline).unwrap();
+        assert_eq!(
+            cmdline.as_str(),
+            format!("virtio_mmio.device=4K@0x{:08x}:{}", 0xd0000000u64, IRQ_BASE)
+        );
+        // The guest must not find them through the ACPI tables too.
+        assert_eq!(device_manager.occupied_slots(), vec![0]);
+        assert!(device_manager.acpi_slots().is_empty());
+    }
+
+    #[test]
+    fn test_hotplug_device() {
+        let guest_mem = GuestMemory::new(&vec![(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager = MMIODeviceManager::new(guest_mem, 0xd0000000);
+        let dummy_box = Box::new(DummyDevice { dummy: 0 });
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        // The irqfds need the in-kernel irqchip.
+        vm.create_irq_chip().unwrap();
+
+        device_manager
+            .register_device(dummy_box.clone(), Some(String::from("foo")))
+            .unwrap();
+        let slot = device_manager
+            .hotplug_device(dummy_box.clone(), Some(String::from("bar")), &vm)
+            .unwrap();
+        assert_eq!(slot, 1);
+        assert_eq!(device_manager.occupied_slots(), vec![0, 1]);
+        assert_eq!(
+            device_manager.get_address(&String::from("bar")),
+            Some(&(0xd0000000 + MMIO_LEN))
+        );
+        // The device is reachable through the bus, which the vCPUs got a copy of.
+        let bus = device_manager.bus.clone();
+        let mut magic = [0u8; 4];
+        assert!(bus.read(0xd0000000 + MMIO_LEN, &mut magic));
+        assert_eq!(&magic, b"virt");
+
+        assert_eq!(device_manager.unpluggable_slot("bar").unwrap(), 1);
+        assert_eq!(
+            device_manager.unplug_device(1, &vm).unwrap(),
+            Some(String::from("bar"))
+        );
+        assert_eq!(device_manager.occupied_slots(), vec![0]);
+        assert_eq!(device_manager.get_address(&String::from("bar")), None);
+        assert!(bus.read(0xd0000000 + MMIO_LEN, &mut magic));
+        assert_eq!(magic, [0; 4]);
+        match device_manager.unpluggable_slot("bar") {
+            Err(Error::DeviceNotFound) => (),
+            _ => panic!("Unexpected result"),
+        }
+        match device_manager.unplug_device(1, &vm) {
+            Err(Error::DeviceNotFound) => (),
+            _ => panic!("Unexpected result"),
+        }
+
+        // The slot can be used again, and the events can be registered again.
+        assert_eq!(
+            device_manager
+                .hotplug_device(dummy_box, Some(String::from("baz")), &vm)
+                .unwrap(),
+            1
+        );
+
+        // The guest doesn't forget the devices announced on the command line.
+        device_manager
+            .add_cmdline_devices(&mut kernel_cmdline::Cmdline::new(4096))
+            .unwrap();
+        match device_manager.unpluggable_slot("foo") {
+            Err(Error::DeviceOnCmdline) => (),
+            _ => panic!("Unexpected result"),
+        }
+        assert_eq!(device_manager.occupied_slots(), vec![0, 1]);
     }
 
     #[test]
@@ -254,18 +658,17 @@ mod tests {
             GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
         let mut device_manager = MMIODeviceManager::new(guest_mem, 0xd0000000);
 
-        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
         let dummy_box = Box::new(DummyDevice { dummy: 0 });
         for _i in IRQ_BASE..(MAX_IRQ + 1) {
             device_manager
-                .register_device(dummy_box.clone(), &mut cmdline, None)
+                .register_device(dummy_box.clone(), None)
                 .unwrap();
         }
         assert_eq!(
             format!(
                 "{}",
                 device_manager
-                    .register_device(dummy_box.clone(), &mut cmdline, None)
+                    .register_device(dummy_box.clone(), None)
                     .unwrap_err()
             ),
             "no more IRQs are available".to_string()
@@ -302,8 +705,8 @@ mod tests {
                     &format!(
                         "{}K@0x{:08x}:{}",
                         MMIO_LEN / 1024,
-                        device_manager.mmio_base,
-                        device_manager.irq
+                        device_manager.slot_info()[0].addr,
+                        device_manager.slot_info()[0].irq
                     ),
                 )
                 .unwrap_err(),
@@ -318,6 +721,33 @@ mod tests {
         assert_eq!(format!("{}", e), "failed to clone irqfd: Error(0)");
         let e = Error::UpdateFailed;
         assert_eq!(format!("{}", e), "failed to update the mmio device");
+        let e = Error::DeviceNotFound;
+        assert_eq!(format!("{}", e), "no device with this id is registered");
+        let e = Error::DeviceOnCmdline;
+        assert_eq!(
+            format!("{}", e),
+            "the device was announced on the kernel command line"
+        );
+        let e = Error::RegisterEvent(sys_util::Error::new(0));
+        assert_eq!(
+            format!("{}", e),
+            "failed to register the device events: Error(0)"
+        );
+        let e = Error::DeviceCountMismatch(1, 2);
+        assert_eq!(
+            format!("{}", e),
+            "found 2 saved device states for 1 registered devices"
+        );
+        let e = Error::RestoreState(RestoreError::AlreadyActivated);
+        assert_eq!(
+            format!("{}", e),
+            "failed to restore device state: AlreadyActivated"
+        );
+        let e = Error::SlotMismatch(0xd0000000, 5);
+        assert_eq!(
+            format!("{}", e),
+            "the saved device at 0xd0000000 with IRQ 5 is not registered there"
+        );
     }
 
     #[test]
@@ -327,15 +757,12 @@ mod tests {
         let guest_mem =
             GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
         let mut device_manager = MMIODeviceManager::new(guest_mem, 0xd0000000);
-        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
         let dummy_box = Box::new(DummyDevice { dummy: 0 });
 
-        if let Ok(addr) =
-            device_manager.register_device(dummy_box, &mut cmdline, Some(String::from("foo")))
-        {
-            assert!(device_manager.update_drive(addr, 1048576).is_ok());
+        if let Ok(addr) = device_manager.register_device(dummy_box, Some(String::from("foo"))) {
+            assert!(device_manager.update_drive(addr, 1048576, 1).is_ok());
         }
-        assert!(device_manager.update_drive(0xbeef, 1048576).is_err());
+        assert!(device_manager.update_drive(0xbeef, 1048576, 1).is_err());
     }
 
     #[test]
@@ -345,14 +772,52 @@ mod tests {
         let guest_mem =
             GuestMemory::new(&vec![(start_addr1, 0x1000), (start_addr2, 0x1000)]).unwrap();
         let mut device_manager = MMIODeviceManager::new(guest_mem, 0xd0000000);
-        let mut cmdline = kernel_cmdline::Cmdline::new(4096);
         let dummy_box = Box::new(DummyDevice { dummy: 0 });
 
         let id = String::from("foo");
-        if let Ok(addr) = device_manager.register_device(dummy_box, &mut cmdline, Some(id.clone()))
-        {
+        if let Ok(addr) = device_manager.register_device(dummy_box, Some(id.clone())) {
             assert_eq!(Some(&addr), device_manager.get_address(&id));
         }
         assert_eq!(None, device_manager.get_address(&String::from("bar")));
     }
+
+    #[test]
+    fn test_save_restore_state() {
+        let guest_mem = GuestMemory::new(&vec![(GuestAddress(0x0), 0x1000)]).unwrap();
+        let mut device_manager = MMIODeviceManager::new(guest_mem, 0xd0000000);
+        let dummy_box = Box::new(DummyDevice { dummy: 0 });
+        device_manager
+            .register_device(dummy_box.clone(), None)
+            .unwrap();
+        device_manager.register_device(dummy_box, None).unwrap();
+
+        let states = device_manager.save_state();
+        assert_eq!(states.len(), 2);
+        assert!(device_manager.restore_state(&states).is_ok());
+        match device_manager.restore_state(&states[..1]) {
+            Err(Error::DeviceCountMismatch(2, 1)) => (),
+            _ => panic!("Unexpected result"),
+        }
+
+        // The states of the devices are saved with the slots they are in.
+        assert_eq!(states[0].mmio_addr, 0xd0000000);
+        assert_eq!(states[0].irq, IRQ_BASE);
+        assert_eq!(states[1].mmio_addr, 0xd0000000 + MMIO_LEN);
+        assert_eq!(states[1].irq, IRQ_BASE + 1);
+        let mut moved_states = states.clone();
+        moved_states[1].mmio_addr += MMIO_LEN;
+        match device_manager.restore_state(&moved_states) {
+            Err(Error::SlotMismatch(addr, irq)) => {
+                assert_eq!(addr, 0xd0000000 + 2 * MMIO_LEN);
+                assert_eq!(irq, IRQ_BASE + 1);
+            }
+            _ => panic!("Unexpected result"),
+        }
+        moved_states = states.clone();
+        moved_states[0].irq = MAX_IRQ;
+        match device_manager.restore_state(&moved_states) {
+            Err(Error::SlotMismatch(_, MAX_IRQ)) => (),
+            _ => panic!("Unexpected result"),
+        }
+    }
 }
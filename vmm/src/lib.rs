@@ -25,9 +25,11 @@ extern crate devices;
 extern crate fc_util;
 extern crate kernel;
 extern crate kvm;
+extern crate kvm_gen;
 #[macro_use]
 extern crate logger;
 extern crate memory_model;
+extern crate mmds;
 extern crate net_util;
 extern crate rate_limiter;
 extern crate seccomp;
@@ -38,6 +40,8 @@ mod default_syscalls;
 mod device_manager;
 /// Signal handling utilities for seccomp violations.
 mod sigsys_handler;
+/// Serialization of the microVM state to and from snapshot files.
+mod snapshot;
 mod vm_control;
 /// Wrappers over structures used to configure the VMM.
 pub mod vmm_config;
@@ -48,11 +52,13 @@ use std::collections::HashMap;
 use std::ffi::CString;
 use std::fmt::{Display, Formatter};
 use std::fs::{metadata, File, OpenOptions};
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::PathBuf;
 use std::result;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
-use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender, TryRecvError};
 use std::sync::{Arc, Barrier, RwLock};
 use std::thread;
 use std::time::Duration;
@@ -62,14 +68,20 @@ use timerfd::{ClockId, SetTimeFlags, TimerFd, TimerState};
 
 use device_manager::legacy::LegacyDeviceManager;
 use device_manager::mmio::MMIODeviceManager;
+use devices::legacy::I8042DeviceError;
 use devices::virtio;
+use devices::virtio::{
+    BlockRateLimiter, BlockRateLimiterUpdate, CacheType, Firewall, FrameHooks, PcapWriter,
+};
 use devices::{DeviceEventT, EpollHandler, EpollHandlerPayload};
 use fc_util::now_cputime_us;
 use kernel::cmdline as kernel_cmdline;
 use kernel::loader as kernel_loader;
 use kvm::*;
+use kvm_gen::kvm_clock_data;
 use logger::{Level, LogOption, Metric, LOGGER, METRICS};
 use memory_model::{GuestAddress, GuestMemory};
+use rate_limiter::{RateLimiter, RateLimiterGroup};
 use seccomp::{
     setup_seccomp, SeccompLevel, SECCOMP_LEVEL_ADVANCED, SECCOMP_LEVEL_BASIC, SECCOMP_LEVEL_NONE,
 };
@@ -78,21 +90,34 @@ pub use sigsys_handler::setup_sigsys_handler;
 use sys_util::{register_signal_handler, EventFd, Killable, Terminal};
 use vm_control::VmResponse;
 use vmm_config::boot_source::{BootSourceConfig, BootSourceConfigError};
-use vmm_config::drive::{BlockDeviceConfig, BlockDeviceConfigs, DriveError};
+use vmm_config::config_file::ConfigFile;
+use vmm_config::drive::{BlockDeviceConfig, BlockDeviceConfigs, DriveError, DriveFormat};
 use vmm_config::instance_info::{InstanceInfo, InstanceState, StartMicrovmError};
 use vmm_config::logger::{LoggerConfig, LoggerConfigError, LoggerLevel};
 use vmm_config::machine_config::{VmConfig, VmConfigError};
-use vmm_config::net::{NetworkInterfaceConfig, NetworkInterfaceConfigs, NetworkInterfaceError};
+use vmm_config::net::{
+    NetBackend, NetworkInterfaceConfig, NetworkInterfaceConfigs, NetworkInterfaceError,
+    NetworkInterfaceUpdateConfig,
+};
+use vmm_config::rate_limit_group::{
+    RateLimitGroupConfig, RateLimitGroupConfigs, RateLimitGroupError,
+};
+use vmm_config::snapshot::{SnapshotConfig, SnapshotError};
+use vmm_config::vm_state::{VmStateConfig, VmStateError, VmStateUpdate};
 #[cfg(feature = "vsock")]
 use vmm_config::vsock::{VsockDeviceConfig, VsockDeviceConfigs, VsockError};
-use vstate::{Vcpu, Vm};
+use vstate::{Vcpu, VcpuEvent, VcpuResponse, VcpuState, Vm};
 
 const MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE: u16 = 0x03f0;
 const MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE: u8 = 123;
 
-const DEFAULT_KERNEL_CMDLINE: &str = "reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0";
+const DEFAULT_KERNEL_CMDLINE: &str = "reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0 \
+                                      i8042.noaux i8042.nomux i8042.nopnp i8042.dumbkbd";
 const VCPU_RTSIG_OFFSET: i32 = 0;
 const WRITE_METRICS_PERIOD_SECONDS: u64 = 60;
+// How long to wait for a vCPU to respond before kicking it again, and how many times to kick it.
+const VCPU_EVENT_TIMEOUT_MS: u64 = 10;
+const VCPU_EVENT_RETRIES: u32 = 100;
 static START_INSTANCE_REQUEST_TS: AtomicUsize = ATOMIC_USIZE_INIT;
 static START_INSTANCE_REQUEST_CPU_TS: AtomicUsize = ATOMIC_USIZE_INIT;
 
@@ -145,9 +170,9 @@ pub enum VmmActionError {
     /// The action `ConfigureBootSource` failed either because of bad user input (`ErrorKind::User`)
     /// or an internal error (`ErrorKind::Internal`).
     BootSource(ErrorKind, BootSourceConfigError),
-    /// One of the actions `InsertBlockDevice`, `RescanBlockDevice` or `UpdateBlockDevicePath`
-    /// failed either because of bad user input (`ErrorKind::User`) or an
-    /// internal error (`ErrorKind::Internal`).
+    /// One of the actions `InsertBlockDevice`, `RemoveBlockDevice`, `RescanBlockDevice`,
+    /// `UpdateBlockDevicePath` or `UpdateBlockDeviceRateLimiter` failed either because of bad user
+    /// input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
     DriveConfig(ErrorKind, DriveError),
     /// The action `ConfigureLogger` failed either because of bad user input (`ErrorKind::User`) or
     /// an internal error (`ErrorKind::Internal`).
@@ -155,12 +180,24 @@ pub enum VmmActionError {
     /// One of the actions `GetVmConfiguration` or `SetVmConfiguration` failed either because of bad
     /// input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
     MachineConfig(ErrorKind, VmConfigError),
-    /// The action `InsertNetworkDevice` failed either because of bad user input (`ErrorKind::User`)
-    /// or an internal error (`ErrorKind::Internal`).
+    /// One of the actions `InsertNetworkDevice` or `UpdateNetworkInterface` failed either because
+    /// of bad user input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
     NetworkConfig(ErrorKind, NetworkInterfaceError),
+    /// The action `InsertRateLimitGroup` failed either because of bad user input
+    /// (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    RateLimitGroup(ErrorKind, RateLimitGroupError),
+    /// One of the actions `CreateSnapshot` or `LoadSnapshot` failed either because of bad user
+    /// input (`ErrorKind::User`) or an internal error (`ErrorKind::Internal`).
+    Snapshot(ErrorKind, SnapshotError),
+    /// The action `SendCtrlAltDel` failed because the i8042 device could not deliver the keys to
+    /// the guest (`ErrorKind::Internal`).
+    SendCtrlAltDel(ErrorKind, I8042DeviceError),
     /// The action `StartMicroVm` failed either because of bad user input (`ErrorKind::User`) or
     /// an internal error (`ErrorKind::Internal`).
     StartMicrovm(ErrorKind, StartMicrovmError),
+    /// The action `UpdateVmState` failed either because of bad user input (`ErrorKind::User`) or
+    /// an internal error (`ErrorKind::Internal`).
+    VmState(ErrorKind, VmStateError),
     #[cfg(feature = "vsock")]
     /// The action `insert_vsock_device` failed either because of bad user input (`ErrorKind::User`)
     /// or an internal error (`ErrorKind::Internal`).
@@ -178,7 +215,11 @@ impl VmmActionError {
             Logger(ref kind, _) => kind,
             MachineConfig(ref kind, _) => kind,
             NetworkConfig(ref kind, _) => kind,
+            RateLimitGroup(ref kind, _) => kind,
+            SendCtrlAltDel(ref kind, _) => kind,
+            Snapshot(ref kind, _) => kind,
             StartMicrovm(ref kind, _) => kind,
+            VmState(ref kind, _) => kind,
             #[cfg(feature = "vsock")]
             VsockConfig(ref kind, _) => kind,
         }
@@ -195,7 +236,16 @@ impl Display for VmmActionError {
             Logger(_, ref err) => write!(f, "{}", err.to_string()),
             MachineConfig(_, ref err) => write!(f, "{}", err.to_string()),
             NetworkConfig(_, ref err) => write!(f, "{}", err.to_string()),
+            RateLimitGroup(_, ref err) => write!(f, "{}", err.to_string()),
+            SendCtrlAltDel(_, ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "Cannot send Ctrl+Alt+Del to the guest. {}", err_msg)
+            }
+            Snapshot(_, ref err) => write!(f, "{}", err.to_string()),
             StartMicrovm(_, ref err) => write!(f, "{}", err.to_string()),
+            VmState(_, ref err) => write!(f, "{}", err.to_string()),
             #[cfg(feature = "vsock")]
             VsockConfig(_, ref err) => write!(f, "{}", err.to_string()),
         }
@@ -212,25 +262,47 @@ pub enum VmmAction {
     /// Configure the logger using as input the `LoggerConfig`. This action can only be called
     /// before the microVM has booted. The response is sent using the `OutcomeSender`.
     ConfigureLogger(LoggerConfig, OutcomeSender),
+    /// Save the state of the running microVM, including the guest memory, to the file described
+    /// by `SnapshotConfig`. The microVM is paused while the snapshot is created and then resumed.
+    /// The response is sent using the `OutcomeSender`.
+    CreateSnapshot(SnapshotConfig, OutcomeSender),
     /// Get the configuration of the microVM. The action response is sent using the `OutcomeSender`.
     GetVmConfiguration(OutcomeSender),
     /// Add a new block device or update one that already exists using the `BlockDeviceConfig` as
-    /// input. This action can only be called before the microVM has booted. The response
-    /// is sent using the `OutcomeSender`.
+    /// input. After the microVM has booted, only new non-root block devices can be added, and they
+    /// are hot-plugged into the guest. The response is sent using the `OutcomeSender`.
     InsertBlockDevice(BlockDeviceConfig, OutcomeSender),
     /// Add a new network interface config or update one that already exists using the
     /// `NetworkInterfaceConfig` as input. This action can only be called before the microVM has
     /// booted. The response is sent using the `OutcomeSender`.
     InsertNetworkDevice(NetworkInterfaceConfig, OutcomeSender),
+    /// Add a new rate-limit group or replace one that already exists using the
+    /// `RateLimitGroupConfig` as input. Drives and network interfaces reference the group by its
+    /// ID. This action can only be called before the microVM has booted. The response is sent
+    /// using the `OutcomeSender`.
+    InsertRateLimitGroup(RateLimitGroupConfig, OutcomeSender),
     #[cfg(feature = "vsock")]
     /// Add a new vsock device or update one that already exists using the
     /// `VsockDeviceConfig` as input. This action can only be called before the microVM has
     /// booted. The response is sent using the `OutcomeSender`.
     InsertVsockDevice(VsockDeviceConfig, OutcomeSender),
+    /// Restore the microVM from the snapshot file described by `SnapshotConfig` and resume it.
+    /// This action can only be called before the microVM has booted, after the same devices as
+    /// in the snapshotted microVM have been configured. The response is sent using the
+    /// `OutcomeSender`.
+    LoadSnapshot(SnapshotConfig, OutcomeSender),
+    /// Remove the block device specified by an ID. After the microVM has booted, the block device
+    /// is hot-unplugged from the guest, which is only told about it afterwards. The root block
+    /// device cannot be hot-unplugged. The response is sent using the `OutcomeSender`.
+    RemoveBlockDevice(String, OutcomeSender),
     /// Update the size of an existing block device specified by an ID. The ID is the first data
     /// associated with this enum variant. This action can only be called after the microVM is
     /// started. The response is sent using the `OutcomeSender`.
     RescanBlockDevice(String, OutcomeSender),
+    /// Inject the Ctrl+Alt+Del key combination through the i8042 keyboard controller, asking the
+    /// guest to shut down in an orderly fashion. This action can only be called after the microVM
+    /// is started. The response is sent using the `OutcomeSender`.
+    SendCtrlAltDel(OutcomeSender),
     /// Set the microVM configuration (memory & vcpu) using `VmConfig` as input. This
     /// action can only be called before the microVM has booted. The action
     /// response is sent using the `OutcomeSender`.
@@ -242,6 +314,18 @@ pub enum VmmAction {
     /// represents the `drive_id` and the `path_on_host`. The response is sent using
     /// the `OutcomeSender`.
     UpdateBlockDevicePath(String, String, OutcomeSender),
+    /// Update the rate limiter of an existing block device. The data associated with this variant
+    /// represents the `drive_id` and the new configuration of the rate limiters. After the
+    /// microVM has booted, the running device is updated. The response is sent using the
+    /// `OutcomeSender`.
+    UpdateBlockDeviceRateLimiter(String, BlockRateLimiterUpdate, OutcomeSender),
+    /// Update the rate limiters of an existing network interface using the
+    /// `NetworkInterfaceUpdateConfig` as input. After the microVM has booted, the running device
+    /// is updated. The response is sent using the `OutcomeSender`.
+    UpdateNetworkInterface(NetworkInterfaceUpdateConfig, OutcomeSender),
+    /// Pause or resume the microVM according to the `VmStateConfig`. This action can only be
+    /// called after the microVM is started. The response is sent using the `OutcomeSender`.
+    UpdateVmState(VmStateConfig, OutcomeSender),
 }
 
 /// The enum represents the response sent by the VMM in case of success. The response is either
@@ -250,6 +334,8 @@ pub enum VmmAction {
 pub enum VmmData {
     /// No data is sent on the channel.
     Empty,
+    /// The request was accepted, but the guest has yet to act on it.
+    Accepted,
     /// The microVM configuration represented by `VmConfig`.
     MachineConfiguration(VmConfig),
 }
@@ -315,7 +401,9 @@ impl KvmContext {
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum EpollDispatch {
     Exit,
+    AcpiEject,
     Stdin,
+    DeviceEvents,
     DeviceHandler(usize, DeviceEventT),
     VmmActionRequest,
     WriteMetrics,
@@ -345,6 +433,10 @@ struct EpollEvent<T: AsRawFd> {
 // and duping of file descriptors. This issue will be solved when we also implement device removal.
 struct EpollContext {
     epoll_raw_fd: RawFd,
+    // The devices register their events with a separate epoll instance, which is itself
+    // registered with the main one. This allows pausing all the devices at once.
+    device_epoll_raw_fd: RawFd,
+    device_events_index: u64,
     stdin_index: u64,
     // FIXME: find a different design as this does not scale. This Vec can only grow.
     dispatch_table: Vec<Option<EpollDispatch>>,
@@ -354,18 +446,32 @@ struct EpollContext {
 impl EpollContext {
     fn new() -> Result<Self> {
         let epoll_raw_fd = epoll::create(true).map_err(Error::EpollFd)?;
+        let device_epoll_raw_fd = epoll::create(true).map_err(Error::EpollFd)?;
 
         // Initial capacity needs to be large enough to hold:
         // * 1 exit event
         // * 1 stdin event
+        // * 1 event for the device epoll instance
         // * 2 queue events for virtio block
         // * 4 for virtio net
-        // The total is 8 elements; allowing spare capacity to avoid reallocations.
+        // The total is 9 elements; allowing spare capacity to avoid reallocations.
         let mut dispatch_table = Vec::with_capacity(20);
         let stdin_index = dispatch_table.len() as u64;
         dispatch_table.push(None);
+        let device_events_index = dispatch_table.len() as u64;
+        dispatch_table.push(Some(EpollDispatch::DeviceEvents));
+        epoll::ctl(
+            epoll_raw_fd,
+            epoll::EPOLL_CTL_ADD,
+            device_epoll_raw_fd,
+            epoll::Event::new(epoll::EPOLLIN, device_events_index),
+        )
+        .map_err(Error::EpollFd)?;
+
         Ok(EpollContext {
             epoll_raw_fd,
+            device_epoll_raw_fd,
+            device_events_index,
             stdin_index,
             dispatch_table,
             device_handlers: Vec::with_capacity(6),
@@ -410,6 +516,27 @@ impl EpollContext {
         Ok(())
     }
 
+    // Resumes the processing of device events.
+    fn enable_device_events(&mut self) -> std::io::Result<()> {
+        epoll::ctl(
+            self.epoll_raw_fd,
+            epoll::EPOLL_CTL_ADD,
+            self.device_epoll_raw_fd,
+            epoll::Event::new(epoll::EPOLLIN, self.device_events_index),
+        )
+    }
+
+    // Stops the processing of device events. The events are not lost, they are processed once
+    // enable_device_events() is called.
+    fn disable_device_events(&mut self) -> std::io::Result<()> {
+        epoll::ctl(
+            self.epoll_raw_fd,
+            epoll::EPOLL_CTL_DEL,
+            self.device_epoll_raw_fd,
+            epoll::Event::new(epoll::EPOLLIN, self.device_events_index),
+        )
+    }
+
     fn add_event<T>(&mut self, fd: T, token: EpollDispatch) -> Result<EpollEvent<T>>
     where
         T: AsRawFd,
@@ -460,23 +587,85 @@ impl EpollContext {
         (dispatch_base, sender)
     }
 
-    fn allocate_virtio_block_tokens(&mut self) -> (virtio::block::EpollConfig, usize) {
-        let (dispatch_base, sender) = self.allocate_tokens(virtio::block::BLOCK_EVENTS_COUNT);
-        (
-            virtio::block::EpollConfig::new(dispatch_base, self.epoll_raw_fd, sender),
-            self.device_handlers.len(),
-        )
+    // Every queue of a block device is serviced by its own handler. Returns the index of the
+    // handler of the first queue along with the epoll configs.
+    fn allocate_virtio_block_tokens(
+        &mut self,
+        num_queues: u16,
+    ) -> (Vec<virtio::block::EpollConfig>, usize) {
+        let device_idx = self.device_handlers.len();
+        let epoll_configs = (0..num_queues)
+            .map(|_| {
+                let (dispatch_base, sender) =
+                    self.allocate_tokens(virtio::block::BLOCK_EVENTS_COUNT);
+                virtio::block::EpollConfig::new(dispatch_base, self.device_epoll_raw_fd, sender)
+            })
+            .collect();
+        (epoll_configs, device_idx)
     }
 
-    fn allocate_virtio_net_tokens(&mut self) -> virtio::net::EpollConfig {
-        let (dispatch_base, sender) = self.allocate_tokens(virtio::net::NET_EVENTS_COUNT);
-        virtio::net::EpollConfig::new(dispatch_base, self.epoll_raw_fd, sender)
+    // Every queue pair of a network device is serviced by its own handler. Returns the index of
+    // the handler of the first queue pair along with the epoll configs.
+    fn allocate_virtio_net_tokens(
+        &mut self,
+        num_queue_pairs: u16,
+    ) -> (Vec<virtio::net::EpollConfig>, usize) {
+        let device_idx = self.device_handlers.len();
+        let epoll_configs = (0..num_queue_pairs)
+            .map(|_| {
+                let (dispatch_base, sender) = self.allocate_tokens(virtio::net::NET_EVENTS_COUNT);
+                virtio::net::EpollConfig::new(dispatch_base, self.device_epoll_raw_fd, sender)
+            })
+            .collect();
+        (epoll_configs, device_idx)
     }
 
-    #[cfg(feature = "vsock")]
-    fn allocate_virtio_vsock_tokens(&mut self) -> virtio::vhost::handle::VhostEpollConfig {
+    // The timer of a rate-limit group is polled along with the device events, so that the
+    // members of the group are not kicked while the microVM is paused. A group without token
+    // buckets has no timer.
+    fn add_rate_limit_group(&mut self, group: Arc<RateLimiterGroup>) -> Result<()> {
+        let group_raw_fd = group.as_raw_fd();
+        if group_raw_fd == -1 {
+            return Ok(());
+        }
+        let (dispatch_base, sender) = self.allocate_tokens(1);
+        sender
+            .send(Box::new(RateLimitGroupHandler { group }))
+            .expect("Failed to send through the channel");
+        epoll::ctl(
+            self.device_epoll_raw_fd,
+            epoll::EPOLL_CTL_ADD,
+            group_raw_fd,
+            epoll::Event::new(epoll::EPOLLIN, dispatch_base),
+        )
+        .map_err(Error::EpollFd)
+    }
+
+    #[cfg(feature = "vhost")]
+    fn allocate_virtio_vhost_tokens(&mut self) -> virtio::vhost::handle::VhostEpollConfig {
         let (dispatch_base, sender) = self.allocate_tokens(2);
-        virtio::vhost::handle::VhostEpollConfig::new(dispatch_base, self.epoll_raw_fd, sender)
+        virtio::vhost::handle::VhostEpollConfig::new(
+            dispatch_base,
+            self.device_epoll_raw_fd,
+            sender,
+        )
+    }
+
+    // Drops `count` handlers starting at `device_idx`, along with the events they registered,
+    // and stops dispatching events to them. Their tokens are not reused.
+    fn remove_device_handlers(&mut self, device_idx: usize, count: usize) {
+        for dispatch in self.dispatch_table.iter_mut() {
+            if let Some(EpollDispatch::DeviceHandler(idx, _)) = *dispatch {
+                if idx >= device_idx && idx < device_idx + count {
+                    *dispatch = None;
+                }
+            }
+        }
+        for maybe in &mut self.device_handlers[device_idx..device_idx + count] {
+            // Closing the event fds of a handler removes them from the epoll set.
+            let (_, receiver) = channel();
+            *maybe = MaybeHandler::new(receiver);
+        }
     }
 
     fn get_device_handler(&mut self, device_idx: usize) -> Result<&mut EpollHandler> {
@@ -505,15 +694,41 @@ impl Drop for EpollContext {
         if rc != 0 {
             warn!("Cannot close epoll.");
         }
+        let rc = unsafe { libc::close(self.device_epoll_raw_fd) };
+        if rc != 0 {
+            warn!("Cannot close the device epoll.");
+        }
+    }
+}
+
+// Unblocks a rate-limit group when its timer expires. The group then kicks its members.
+struct RateLimitGroupHandler {
+    group: Arc<RateLimiterGroup>,
+}
+
+impl EpollHandler for RateLimitGroupHandler {
+    fn handle_event(&mut self, _: DeviceEventT, _: u32, _: EpollHandlerPayload) {
+        METRICS.vmm.rate_limit_group_events.inc();
+        if let Err(e) = self.group.event_handler() {
+            error!("Failed to handle rate-limit group event: {:?}", e);
+        }
     }
 }
 
 struct KernelConfig {
     cmdline: kernel_cmdline::Cmdline,
     kernel_file: File,
+    initrd_file: Option<File>,
     cmdline_addr: GuestAddress,
 }
 
+// The VMM end of a vCPU thread.
+struct VcpuHandle {
+    thread: thread::JoinHandle<()>,
+    event_sender: Sender<VcpuEvent>,
+    response_receiver: Receiver<VcpuResponse>,
+}
+
 struct Vmm {
     kvm: KvmContext,
 
@@ -524,20 +739,27 @@ struct Vmm {
     guest_memory: Option<GuestMemory>,
     kernel_config: Option<KernelConfig>,
     kill_signaled: Option<Arc<AtomicBool>>,
-    vcpu_handles: Option<Vec<thread::JoinHandle<()>>>,
+    vcpu_handles: Option<Vec<VcpuHandle>>,
     exit_evt: Option<EpollEvent<EventFd>>,
+    acpi_eject_evt: Option<EpollEvent<EventFd>>,
     vm: Vm,
+    // The guest clock at the time the microVM was paused, so that the time spent paused is not
+    // visible to the guest.
+    paused_clock: Option<kvm_clock_data>,
 
     // Guest VM devices.
     mmio_device_manager: Option<MMIODeviceManager>,
     legacy_device_manager: LegacyDeviceManager,
     drive_handler_id_map: HashMap<String, usize>,
+    net_handler_id_map: HashMap<String, usize>,
+    rate_limit_groups: HashMap<String, Arc<RateLimiterGroup>>,
 
     // Device configurations.
     // If there is a Root Block Device, this should be added as the first element of the list.
     // This is necessary because we want the root to always be mounted on /dev/vda.
     block_device_configs: BlockDeviceConfigs,
     network_interface_configs: NetworkInterfaceConfigs,
+    rate_limit_group_configs: RateLimitGroupConfigs,
     #[cfg(feature = "vsock")]
     vsock_device_configs: VsockDeviceConfigs,
 
@@ -589,12 +811,17 @@ impl Vmm {
             kill_signaled: None,
             vcpu_handles: None,
             exit_evt: None,
+            acpi_eject_evt: None,
             vm,
+            paused_clock: None,
             mmio_device_manager: None,
             legacy_device_manager: LegacyDeviceManager::new().map_err(Error::CreateLegacyDevice)?,
             block_device_configs,
             drive_handler_id_map: HashMap::new(),
+            net_handler_id_map: HashMap::new(),
+            rate_limit_groups: HashMap::new(),
             network_interface_configs: NetworkInterfaceConfigs::new(),
+            rate_limit_group_configs: RateLimitGroupConfigs::new(),
             #[cfg(feature = "vsock")]
             vsock_device_configs: VsockDeviceConfigs::new(),
             epoll_context,
@@ -610,23 +837,53 @@ impl Vmm {
         drive_id: &String,
         disk_image: File,
     ) -> result::Result<(), DriveError> {
-        if let Some(device_idx) = self.drive_handler_id_map.get(drive_id) {
-            match self.epoll_context.get_device_handler(*device_idx) {
-                Ok(handler) => {
-                    handler.handle_event(
-                        virtio::block::FS_UPDATE_EVENT,
-                        *device_idx as u32,
-                        EpollHandlerPayload::DrivePayload(disk_image),
-                    );
-                    Ok(())
-                }
-                Err(e) => {
-                    warn!("invalid handler for device {}: {:?}", device_idx, e);
-                    Err(DriveError::BlockDeviceUpdateFailed)
-                }
+        match self.drive_handler_id_map.get(drive_id).cloned() {
+            Some(device_idx)
+                if self.send_handler_event(
+                    device_idx,
+                    virtio::block::FS_UPDATE_EVENT,
+                    EpollHandlerPayload::DrivePayload(disk_image),
+                ) =>
+            {
+                Ok(())
+            }
+            _ => Err(DriveError::BlockDeviceUpdateFailed),
+        }
+    }
+
+    // Hands `payload` to the handler of a running device. Returns whether the device had a
+    // handler to handle it.
+    fn send_handler_event(
+        &mut self,
+        device_idx: usize,
+        device_event: DeviceEventT,
+        payload: EpollHandlerPayload,
+    ) -> bool {
+        match self.epoll_context.get_device_handler(device_idx) {
+            Ok(handler) => {
+                handler.handle_event(device_event, device_idx as u32, payload);
+                true
+            }
+            Err(e) => {
+                warn!("invalid handler for device {}: {:?}", device_idx, e);
+                false
+            }
+        }
+    }
+
+    // Hands the rate limiter update in `payload` to the handler of a running device. Returns
+    // whether the device had a handler to update.
+    fn update_handler_rate_limiters(
+        &mut self,
+        device_idx: usize,
+        payload: EpollHandlerPayload,
+    ) -> std::result::Result<bool, std::io::Error> {
+        match self.epoll_context.get_device_handler(device_idx) {
+            Ok(handler) => handler.update_rate_limiters(payload).map(|_| true),
+            Err(e) => {
+                warn!("invalid handler for device {}: {:?}", device_idx, e);
+                Ok(false)
             }
-        } else {
-            Err(DriveError::BlockDeviceUpdateFailed)
         }
     }
 
@@ -634,42 +891,26 @@ impl Vmm {
     fn attach_block_devices(
         &mut self,
         device_manager: &mut MMIODeviceManager,
+        cmdline: &mut kernel_cmdline::Cmdline,
     ) -> std::result::Result<(), StartMicrovmError> {
-        // We rely on check_health function for making sure kernel_config is not None.
-        let kernel_config = self
-            .kernel_config
-            .as_mut()
-            .ok_or(StartMicrovmError::MissingKernelConfig)?;
-
         if self.block_device_configs.has_root_block_device() {
             // If no PARTUUID was specified for the root device, try with the /dev/vda.
             if !self.block_device_configs.has_partuuid_root() {
-                kernel_config
-                    .cmdline
+                cmdline
                     .insert_str(" root=/dev/vda")
                     .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))?;
 
                 if self.block_device_configs.has_read_only_root() {
-                    kernel_config
-                        .cmdline
+                    cmdline
                         .insert_str(" ro")
                         .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))?;
                 }
             }
         }
 
-        let epoll_context = &mut self.epoll_context;
         for drive_config in self.block_device_configs.config_list.iter_mut() {
-            // Add the block device from file.
-            let block_file = OpenOptions::new()
-                .read(true)
-                .write(!drive_config.is_read_only)
-                .open(&drive_config.path_on_host)
-                .map_err(|e| StartMicrovmError::OpenBlockDevice(e))?;
-
             if drive_config.is_root_device && drive_config.get_partuuid().is_some() {
-                kernel_config
-                    .cmdline
+                cmdline
                     .insert_str(format!(
                         " root=PARTUUID={}",
                         //The unwrap is safe as we are firstly checking that partuuid is_some().
@@ -677,70 +918,133 @@ impl Vmm {
                     ))
                     .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))?;
                 if drive_config.is_read_only {
-                    kernel_config
-                        .cmdline
+                    cmdline
                         .insert_str(" ro")
                         .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))?;
                 }
             }
 
-            let (epoll_config, curr_device_idx) = epoll_context.allocate_virtio_block_tokens();
-            self.drive_handler_id_map
-                .insert(drive_config.drive_id.clone(), curr_device_idx - 1);
-
-            let block_box = Box::new(
-                devices::virtio::Block::new(
-                    block_file,
-                    drive_config.is_read_only,
-                    epoll_config,
-                    drive_config.rate_limiter.take(),
-                )
-                .map_err(StartMicrovmError::CreateBlockDevice)?,
-            );
+            let block_box = create_block_device(
+                drive_config,
+                &mut self.epoll_context,
+                &mut self.drive_handler_id_map,
+                &self.rate_limit_groups,
+            )?;
             device_manager
-                .register_device(
-                    block_box,
-                    &mut kernel_config.cmdline,
-                    Some(drive_config.drive_id.clone()),
-                )
+                .register_device(block_box, Some(drive_config.drive_id.clone()))
                 .map_err(StartMicrovmError::RegisterBlockDevice)?;
         }
 
         Ok(())
     }
 
+    // Builds the rate-limit groups, before the devices that reference them.
+    fn create_rate_limit_groups(&mut self) -> std::result::Result<(), StartMicrovmError> {
+        for cfg in self.rate_limit_group_configs.iter() {
+            let group = Arc::new(
+                cfg.build_group()
+                    .map_err(StartMicrovmError::CreateRateLimitGroup)?,
+            );
+            self.epoll_context
+                .add_rate_limit_group(group.clone())
+                .map_err(|_| StartMicrovmError::RegisterEvent)?;
+            self.rate_limit_groups.insert(cfg.group_id.clone(), group);
+        }
+        Ok(())
+    }
+
+    // The guest memory is only needed by the vhost-net devices.
+    #[cfg_attr(not(feature = "vhost"), allow(unused_variables))]
     fn attach_net_devices(
         &mut self,
         device_manager: &mut MMIODeviceManager,
+        guest_mem: &GuestMemory,
     ) -> std::result::Result<(), StartMicrovmError> {
-        // We rely on check_health function for making sure kernel_config is not None.
-        let kernel_config = self
-            .kernel_config
-            .as_mut()
-            .ok_or(StartMicrovmError::MissingKernelConfig)?;
-
         for cfg in self.network_interface_configs.iter_mut() {
-            let epoll_config = self.epoll_context.allocate_virtio_net_tokens();
+            if cfg.backend == Some(NetBackend::VhostNet) && cfg.backend() != NetBackend::VhostNet {
+                warn!(
+                    "The network interface {} uses the userspace backend instead of vhost-net, \
+                     which is either not built in or does not support MMDS, rate limiting, \
+                     multiple queue pairs, captures, anti-spoofing or firewall rules.",
+                    cfg.iface_id
+                );
+            }
+            #[cfg(feature = "vhost")]
+            {
+                if cfg.backend() == NetBackend::VhostNet {
+                    let epoll_config = self.epoll_context.allocate_virtio_vhost_tokens();
+                    let tap = cfg
+                        .take_taps()
+                        .pop()
+                        .ok_or(StartMicrovmError::NetDeviceNotConfigured)?;
+                    let net_box = Box::new(
+                        devices::virtio::vhost::Net::new_with_tap(
+                            tap,
+                            cfg.guest_mac(),
+                            guest_mem,
+                            epoll_config,
+                        )
+                        .map_err(StartMicrovmError::CreateVhostNetDevice)?,
+                    );
 
-            let allow_mmds_requests = cfg.allow_mmds_requests();
-            let rx_rate_limiter = cfg.rx_rate_limiter.take();
-            let tx_rate_limiter = cfg.tx_rate_limiter.take();
+                    device_manager
+                        .register_device(net_box, None)
+                        .map_err(StartMicrovmError::RegisterNetDevice)?;
+                    continue;
+                }
+            }
+
+            let (epoll_configs, device_idx) = self
+                .epoll_context
+                .allocate_virtio_net_tokens(cfg.num_queue_pairs());
+            self.net_handler_id_map
+                .insert(cfg.iface_id.clone(), device_idx);
 
-            if let Some(tap) = cfg.take_tap() {
+            let allow_mmds_requests = cfg.allow_mmds_requests();
+            let rx_rate_limiter = with_rate_limit_group(
+                cfg.rx_rate_limiter.take(),
+                cfg.rx_rate_limit_group.as_ref(),
+                &self.rate_limit_groups,
+            );
+            let tx_rate_limiter = with_rate_limit_group(
+                cfg.tx_rate_limiter.take(),
+                cfg.tx_rate_limit_group.as_ref(),
+                &self.rate_limit_groups,
+            );
+            let capture = match cfg.capture {
+                Some(ref capture) => {
+                    Some(PcapWriter::new(capture).map_err(StartMicrovmError::OpenNetCapture)?)
+                }
+                None => None,
+            };
+            // The rules were validated when the interface was configured.
+            let firewall = cfg.firewall.as_ref().map(|firewall| {
+                let firewall = Firewall::new(firewall);
+                METRICS.net_firewall.set(&cfg.iface_id, firewall.metrics());
+                firewall
+            });
+
+            let taps = cfg.take_taps();
+            if !taps.is_empty() {
                 let net_box = Box::new(
-                    devices::virtio::Net::new_with_tap(
-                        tap,
+                    devices::virtio::Net::new_with_taps(
+                        taps,
                         cfg.guest_mac(),
-                        epoll_config,
+                        epoll_configs,
                         rx_rate_limiter,
                         tx_rate_limiter,
                         allow_mmds_requests,
+                        FrameHooks {
+                            capture,
+                            spoof_filter: cfg.spoof_filter(),
+                            firewall,
+                        },
                     )
                     .map_err(StartMicrovmError::CreateNetDevice)?,
                 );
 
                 device_manager
-                    .register_device(net_box, &mut kernel_config.cmdline, None)
+                    .register_device(net_box, None)
                     .map_err(StartMicrovmError::RegisterNetDevice)?;
             } else {
                 return Err(StartMicrovmError::NetDeviceNotConfigured)?;
@@ -755,20 +1059,15 @@ impl Vmm {
         device_manager: &mut MMIODeviceManager,
         guest_mem: &GuestMemory,
     ) -> std::result::Result<(), StartMicrovmError> {
-        let kernel_config = self
-            .kernel_config
-            .as_mut()
-            .ok_or(StartMicrovmError::MissingKernelConfig)?;
-
         for cfg in self.vsock_device_configs.iter() {
-            let epoll_config = self.epoll_context.allocate_virtio_vsock_tokens();
+            let epoll_config = self.epoll_context.allocate_virtio_vhost_tokens();
 
             let vsock_box = Box::new(
                 devices::virtio::Vsock::new(cfg.guest_cid as u64, guest_mem, epoll_config)
                     .map_err(StartMicrovmError::CreateVsockDevice)?,
             );
             device_manager
-                .register_device(vsock_box, &mut kernel_config.cmdline, None)
+                .register_device(vsock_box, None)
                 .map_err(StartMicrovmError::RegisterVsockDevice)?;
         }
         Ok(())
@@ -812,11 +1111,29 @@ impl Vmm {
         let mut device_manager =
             MMIODeviceManager::new(guest_mem.clone(), x86_64::get_32bit_gap_start() as u64);
 
-        self.attach_block_devices(&mut device_manager)?;
-        self.attach_net_devices(&mut device_manager)?;
+        // When restoring from a snapshot there is no kernel to boot, so the device parameters
+        // are appended to a scratch command line which is then discarded.
+        let mut cmdline = match self.kernel_config {
+            Some(ref kernel_config) => kernel_config.cmdline.clone(),
+            None => kernel_cmdline::Cmdline::new(x86_64::layout::CMDLINE_MAX_SIZE),
+        };
+
+        self.create_rate_limit_groups()?;
+        self.attach_block_devices(&mut device_manager, &mut cmdline)?;
+        self.attach_net_devices(&mut device_manager, &guest_mem)?;
         #[cfg(feature = "vsock")]
         self.attach_vsock_devices(&mut device_manager, &guest_mem)?;
+        // The DSDT describes the devices as well. They are only left out of the command line for
+        // guests which rely on the ACPI tables alone and have not been told to ignore them.
+        if !self.vm_config.acpi_only.unwrap_or(false) || acpi_disabled(&cmdline) {
+            device_manager
+                .add_cmdline_devices(&mut cmdline)
+                .map_err(|e| StartMicrovmError::KernelCmdline(e.to_string()))?;
+        }
 
+        if let Some(ref mut kernel_config) = self.kernel_config {
+            kernel_config.cmdline = cmdline;
+        }
         self.mmio_device_manager = Some(device_manager);
         Ok(())
     }
@@ -836,6 +1153,8 @@ impl Vmm {
             .setup_irqchip(
                 &self.legacy_device_manager.com_evt_1_3,
                 &self.legacy_device_manager.com_evt_2_4,
+                &self.legacy_device_manager.kbd_evt,
+                &self.legacy_device_manager.acpi_hotplug_evt,
             )
             .map_err(|e| StartMicrovmError::ConfigureVm(e))?;
         self.vm
@@ -845,13 +1164,25 @@ impl Vmm {
         // mmio_device_manager is instantiated in init_devices, which is called before init_microvm.
         let device_manager = self
             .mmio_device_manager
-            .as_ref()
+            .as_mut()
             .ok_or(StartMicrovmError::DeviceManager)?;
-        for request in &device_manager.vm_requests {
+        // The requests hold copies of the event fds, which would outlive an unplugged device.
+        for request in device_manager.vm_requests.drain(..) {
             if let VmResponse::Err(e) = request.execute(self.vm.get_fd()) {
                 return Err(StartMicrovmError::DeviceVmRequest(e))?;
             }
         }
+        // The guest finds the slots that hold a device through the ACPI hot-plug registers. The
+        // devices announced on the command line are left out, or the guest would probe them
+        // twice.
+        for slot in device_manager.acpi_slots() {
+            // If the lock is poisoned, it's OK to panic.
+            self.legacy_device_manager
+                .acpi_hotplug
+                .lock()
+                .expect("Failed to set the present devices due to poisoned lock")
+                .set_present(slot, true);
+        }
 
         self.legacy_device_manager
             .register_devices()
@@ -863,22 +1194,58 @@ impl Vmm {
     fn start_vcpus(
         &mut self,
         entry_addr: GuestAddress,
+        boot_prot: x86_64::BootProtocol,
     ) -> std::result::Result<(), StartMicrovmError> {
         // vm_config has a default value for vcpu_count.
         let vcpu_count = self
             .vm_config
             .vcpu_count
             .ok_or(StartMicrovmError::VcpusNotConfigured)?;
-        self.vcpu_handles = Some(Vec::with_capacity(vcpu_count as usize));
+        let mut vcpus = Vec::with_capacity(vcpu_count as usize);
+        for cpu_id in 0..vcpu_count {
+            let mut vcpu = Vcpu::new(cpu_id, &self.vm).map_err(StartMicrovmError::Vcpu)?;
+
+            // It is safe to unwrap the ht_enabled flag because the machine configure
+            // has default values for all fields.
+            vcpu.configure(&self.vm_config, entry_addr, boot_prot, &self.vm)
+                .map_err(StartMicrovmError::VcpuConfigure)?;
+            vcpus.push(vcpu);
+        }
+        self.run_vcpus(vcpus)
+    }
+
+    // Creates the vCPUs and brings them to the saved states, instead of configuring them for
+    // booting a kernel.
+    fn restore_vcpus(
+        &mut self,
+        vcpu_states: &[VcpuState],
+    ) -> std::result::Result<(), SnapshotError> {
+        let mut vcpus = Vec::with_capacity(vcpu_states.len());
+        for (cpu_id, vcpu_state) in vcpu_states.iter().enumerate() {
+            let mut vcpu = Vcpu::new(cpu_id as u8, &self.vm)
+                .map_err(|e| SnapshotError::Restore(StartMicrovmError::Vcpu(e)))?;
+            vcpu.configure_cpuid(&self.vm_config)
+                .map_err(|e| SnapshotError::Restore(StartMicrovmError::VcpuConfigure(e)))?;
+            vcpu.restore_state(vcpu_state)
+                .map_err(SnapshotError::VcpuState)?;
+            vcpus.push(vcpu);
+        }
+        self.run_vcpus(vcpus).map_err(SnapshotError::Restore)
+    }
+
+    // Spawns a thread for each of the configured vCPUs and starts running guest code.
+    fn run_vcpus(&mut self, vcpus: Vec<Vcpu>) -> std::result::Result<(), StartMicrovmError> {
+        let vcpu_count = vcpus.len();
+        self.vcpu_handles = Some(Vec::with_capacity(vcpu_count));
         // It is safe to unwrap since it's set just above.
         let vcpu_handles = self.vcpu_handles.as_mut().unwrap();
         self.kill_signaled = Some(Arc::new(AtomicBool::new(false)));
         // It is safe to unwrap since it's set just above.
         let kill_signaled = self.kill_signaled.as_mut().unwrap();
 
-        let vcpu_thread_barrier = Arc::new(Barrier::new((vcpu_count + 1) as usize));
+        let vcpu_thread_barrier = Arc::new(Barrier::new(vcpu_count + 1));
 
-        for cpu_id in 0..vcpu_count {
+        for (cpu_id, vcpu) in vcpus.into_iter().enumerate() {
             let io_bus = self.legacy_device_manager.io_bus.clone();
             // mmio_device_manager is instantiated in init_devices, which is called before
             // start_vcpus.
@@ -897,124 +1264,127 @@ impl Vmm {
                 .expect("Failed to start VCPUs due to poisoned i8042 lock")
                 .get_eventfd_clone()
                 .map_err(|_| StartMicrovmError::EventFd)?;
+            let (event_sender, event_receiver) = channel();
+            let (response_sender, response_receiver) = channel();
+
+            let thread = thread::Builder::new()
+                .name(format!("fc_vcpu{}", cpu_id))
+                .spawn(move || {
+                    unsafe {
+                        extern "C" fn handle_signal(_: i32, _: *mut siginfo_t, _: *mut c_void) {}
+                        // This uses an async signal safe handler to kill the vcpu handles.
+                        register_signal_handler(
+                            VCPU_RTSIG_OFFSET,
+                            sys_util::SignalHandler::Siginfo(handle_signal),
+                            true,
+                        )
+                        .expect("Failed to register vcpu signal handler");
+                    }
 
-            let mut vcpu = Vcpu::new(cpu_id, &self.vm).map_err(StartMicrovmError::Vcpu)?;
-
-            // It is safe to unwrap the ht_enabled flag because the machine configure
-            // has default values for all fields.
-            vcpu.configure(&self.vm_config, entry_addr, &self.vm)
-                .map_err(StartMicrovmError::VcpuConfigure)?;
-            vcpu_handles.push(
-                thread::Builder::new()
-                    .name(format!("fc_vcpu{}", cpu_id))
-                    .spawn(move || {
-                        unsafe {
-                            extern "C" fn handle_signal(_: i32, _: *mut siginfo_t, _: *mut c_void) {
-                            }
-                            // This uses an async signal safe handler to kill the vcpu handles.
-                            register_signal_handler(
-                                VCPU_RTSIG_OFFSET,
-                                sys_util::SignalHandler::Siginfo(handle_signal),
-                                true,
-                            )
-                            .expect("Failed to register vcpu signal handler");
-                        }
-
-                        vcpu_thread_barrier.wait();
+                    vcpu_thread_barrier.wait();
 
-                        loop {
-                            match vcpu.run() {
-                                Ok(run) => match run {
-                                    VcpuExit::IoIn(addr, data) => {
-                                        io_bus.read(addr as u64, data);
-                                        METRICS.vcpu.exit_io_in.inc();
-                                    }
-                                    VcpuExit::IoOut(addr, data) => {
-                                        if addr == MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE
-                                            && data[0] == MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE
-                                        {
-                                            let now_cpu_us = now_cputime_us();
-                                            let now_us =
-                                                chrono::Utc::now().timestamp_nanos() / 1000;
-
-                                            let boot_time_us = now_us as usize
-                                                - START_INSTANCE_REQUEST_TS.load(Ordering::Acquire);
-                                            let boot_time_cpu_us = now_cpu_us as usize
-                                                - START_INSTANCE_REQUEST_CPU_TS
-                                                    .load(Ordering::Acquire);
-                                            warn!(
-                                                "Guest-boot-time = {:>6} us {} ms, \
+                    loop {
+                        match vcpu.run() {
+                            Ok(run) => match run {
+                                VcpuExit::IoIn(addr, data) => {
+                                    io_bus.read(addr as u64, data);
+                                    METRICS.vcpu.exit_io_in.inc();
+                                }
+                                VcpuExit::IoOut(addr, data) => {
+                                    if addr == MAGIC_IOPORT_SIGNAL_GUEST_BOOT_COMPLETE
+                                        && data[0] == MAGIC_VALUE_SIGNAL_GUEST_BOOT_COMPLETE
+                                    {
+                                        let now_cpu_us = now_cputime_us();
+                                        let now_us = chrono::Utc::now().timestamp_nanos() / 1000;
+
+                                        let boot_time_us = now_us as usize
+                                            - START_INSTANCE_REQUEST_TS.load(Ordering::Acquire);
+                                        let boot_time_cpu_us = now_cpu_us as usize
+                                            - START_INSTANCE_REQUEST_CPU_TS.load(Ordering::Acquire);
+                                        warn!(
+                                            "Guest-boot-time = {:>6} us {} ms, \
                                                  {:>6} CPU us {} CPU ms",
-                                                boot_time_us,
-                                                boot_time_us / 1000,
-                                                boot_time_cpu_us,
-                                                boot_time_cpu_us / 1000
-                                            );
-                                        }
-                                        io_bus.write(addr as u64, data);
-                                        METRICS.vcpu.exit_io_out.inc();
-                                    }
-                                    VcpuExit::MmioRead(addr, data) => {
-                                        mmio_bus.read(addr, data);
-                                        METRICS.vcpu.exit_mmio_read.inc();
-                                    }
-                                    VcpuExit::MmioWrite(addr, data) => {
-                                        mmio_bus.write(addr, data);
-                                        METRICS.vcpu.exit_mmio_write.inc();
-                                    }
-                                    VcpuExit::Hlt => {
-                                        info!("Received KVM_EXIT_HLT signal");
-                                        break;
+                                            boot_time_us,
+                                            boot_time_us / 1000,
+                                            boot_time_cpu_us,
+                                            boot_time_cpu_us / 1000
+                                        );
                                     }
-                                    VcpuExit::Shutdown => {
-                                        info!("Received KVM_EXIT_SHUTDOWN signal");
-                                        break;
-                                    }
-                                    // Documentation specifies that below kvm exits are considered
-                                    // errors.
-                                    VcpuExit::FailEntry => {
-                                        METRICS.vcpu.failures.inc();
-                                        error!("Received KVM_EXIT_FAIL_ENTRY signal");
-                                        break;
-                                    }
-                                    VcpuExit::InternalError => {
-                                        METRICS.vcpu.failures.inc();
-                                        error!("Received KVM_EXIT_INTERNAL_ERROR signal");
-                                        break;
-                                    }
-                                    r => {
-                                        METRICS.vcpu.failures.inc();
-                                        // TODO: Are we sure we want to finish running a vcpu upon
-                                        // receiving a vm exit that is not necessarily an error?
-                                        error!("Unexpected exit reason on vcpu run: {:?}", r);
-                                        break;
-                                    }
-                                },
-                                Err(vstate::Error::VcpuRun(ref e)) => match e.errno() {
-                                    // Why do we check for these if we only return EINVAL?
-                                    libc::EAGAIN | libc::EINTR => {}
-                                    _ => {
-                                        METRICS.vcpu.failures.inc();
-                                        error!("Failure during vcpu run: {:?}", e);
+                                    io_bus.write(addr as u64, data);
+                                    METRICS.vcpu.exit_io_out.inc();
+                                }
+                                VcpuExit::MmioRead(addr, data) => {
+                                    mmio_bus.read(addr, data);
+                                    METRICS.vcpu.exit_mmio_read.inc();
+                                }
+                                VcpuExit::MmioWrite(addr, data) => {
+                                    mmio_bus.write(addr, data);
+                                    METRICS.vcpu.exit_mmio_write.inc();
+                                }
+                                VcpuExit::Hlt => {
+                                    info!("Received KVM_EXIT_HLT signal");
+                                    break;
+                                }
+                                VcpuExit::Shutdown => {
+                                    info!("Received KVM_EXIT_SHUTDOWN signal");
+                                    break;
+                                }
+                                // Documentation specifies that below kvm exits are considered
+                                // errors.
+                                VcpuExit::FailEntry => {
+                                    METRICS.vcpu.failures.inc();
+                                    error!("Received KVM_EXIT_FAIL_ENTRY signal");
+                                    break;
+                                }
+                                VcpuExit::InternalError => {
+                                    METRICS.vcpu.failures.inc();
+                                    error!("Received KVM_EXIT_INTERNAL_ERROR signal");
+                                    break;
+                                }
+                                r => {
+                                    METRICS.vcpu.failures.inc();
+                                    // TODO: Are we sure we want to finish running a vcpu upon
+                                    // receiving a vm exit that is not necessarily an error?
+                                    error!("Unexpected exit reason on vcpu run: {:?}", r);
+                                    break;
+                                }
+                            },
+                            Err(vstate::Error::VcpuRun(ref e)) => match e.errno() {
+                                // The VMM thread kicks the vcpu out of KVM_RUN with a signal
+                                // when it has a request for it. Only serve requests here,
+                                // where the vcpu state is guaranteed to be consistent.
+                                libc::EAGAIN | libc::EINTR => {
+                                    if !handle_vcpu_events(&vcpu, &event_receiver, &response_sender)
+                                    {
                                         break;
                                     }
-                                },
-                                _ => (),
-                            }
-
-                            if kill_signaled.load(Ordering::SeqCst) {
-                                break;
-                            }
+                                }
+                                _ => {
+                                    METRICS.vcpu.failures.inc();
+                                    error!("Failure during vcpu run: {:?}", e);
+                                    break;
+                                }
+                            },
+                            _ => (),
                         }
 
-                        // Nothing we need do for the success case.
-                        if let Err(e) = vcpu_exit_evt.write(1) {
-                            METRICS.vcpu.failures.inc();
-                            error!("Failed signaling vcpu exit event: {:?}", e);
+                        if kill_signaled.load(Ordering::SeqCst) {
+                            break;
                         }
-                    })
-                    .map_err(StartMicrovmError::VcpuSpawn)?,
-            );
+                    }
+
+                    // Nothing we need do for the success case.
+                    if let Err(e) = vcpu_exit_evt.write(1) {
+                        METRICS.vcpu.failures.inc();
+                        error!("Failed signaling vcpu exit event: {:?}", e);
+                    }
+                })
+                .map_err(StartMicrovmError::VcpuSpawn)?;
+            vcpu_handles.push(VcpuHandle {
+                thread,
+                event_sender,
+                response_receiver,
+            });
         }
 
         // Load seccomp filters before executing guest code.
@@ -1042,7 +1412,9 @@ impl Vmm {
         Ok(())
     }
 
-    fn load_kernel(&mut self) -> std::result::Result<GuestAddress, StartMicrovmError> {
+    fn load_kernel(
+        &mut self,
+    ) -> std::result::Result<kernel_loader::KernelLoaderResult, StartMicrovmError> {
         // This is the easy way out of consuming the value of the kernel_cmdline.
         // TODO: refactor the kernel_cmdline struct in order to have a CString instead of a String.
         let kernel_config = self
@@ -1057,10 +1429,28 @@ impl Vmm {
         let vm_memory = self.vm.get_memory().ok_or(StartMicrovmError::GuestMemory(
             memory_model::GuestMemoryError::MemoryNotInitialized,
         ))?;
-        let entry_addr = kernel_loader::load_kernel(vm_memory, &mut kernel_config.kernel_file)
-            .map_err(|e| StartMicrovmError::Loader(e))?;
+        let kernel_load_result =
+            kernel_loader::load_kernel(vm_memory, &mut kernel_config.kernel_file)
+                .map_err(|e| StartMicrovmError::Loader(e))?;
         kernel_loader::load_cmdline(vm_memory, kernel_config.cmdline_addr, &cmdline_cstring)
             .map_err(|e| StartMicrovmError::Loader(e))?;
+        // A bzImage declares the highest address its initrd may occupy.
+        let initrd_addr_max = kernel_load_result
+            .setup_header
+            .as_ref()
+            .map(|setup_header| setup_header.initrd_addr_max as usize);
+        let initrd = match kernel_config.initrd_file {
+            Some(ref mut initrd_file) => Some(
+                kernel_loader::load_initrd(
+                    vm_memory,
+                    initrd_file,
+                    kernel_load_result.kernel_end,
+                    initrd_addr_max,
+                )
+                .map_err(|e| StartMicrovmError::Loader(e))?,
+            ),
+            None => None,
+        };
 
         // The vcpu_count has a default value. We shouldn't have gotten to this point without
         // having set the vcpu count.
@@ -1072,10 +1462,35 @@ impl Vmm {
             vm_memory,
             kernel_config.cmdline_addr,
             cmdline_cstring.to_bytes().len() + 1,
-            vcpu_count,
+            &initrd,
+            &kernel_load_result.setup_header,
+            kernel_load_result.boot_prot,
         )
         .map_err(|e| StartMicrovmError::ConfigureSystem(e))?;
-        Ok(entry_addr)
+
+        self.setup_system_tables(vcpu_count)?;
+        Ok(kernel_load_result)
+    }
+
+    // Describes the vCPUs and the devices to the guest through the ACPI tables and, unless the
+    // guest relies on ACPI alone, through the legacy MP table.
+    fn setup_system_tables(&self, vcpu_count: u8) -> std::result::Result<(), StartMicrovmError> {
+        let vm_memory = self.vm.get_memory().ok_or(StartMicrovmError::GuestMemory(
+            memory_model::GuestMemoryError::MemoryNotInitialized,
+        ))?;
+        // mmio_device_manager is instantiated in init_devices, which is called before
+        // load_kernel.
+        let mmio_devices = self
+            .mmio_device_manager
+            .as_ref()
+            .map_or(&[][..], |device_manager| device_manager.slot_info());
+        x86_64::setup_acpi(vm_memory, vcpu_count, mmio_devices)
+            .map_err(|e| StartMicrovmError::ConfigureSystem(e))?;
+        if !self.vm_config.acpi_only.unwrap_or(false) {
+            x86_64::setup_mptable(vm_memory, vcpu_count)
+                .map_err(|e| StartMicrovmError::ConfigureSystem(e))?;
+        }
+        Ok(())
     }
 
     fn register_events(&mut self) -> std::result::Result<(), StartMicrovmError> {
@@ -1093,6 +1508,18 @@ impl Vmm {
             .map_err(|_| StartMicrovmError::RegisterEvent)?;
         self.exit_evt = Some(exit_epoll_evt);
 
+        // The devices the guest ejected are removed from the main loop.
+        let event_fd = self
+            .legacy_device_manager
+            .acpi_eject_evt
+            .try_clone()
+            .map_err(|_| StartMicrovmError::EventFd)?;
+        let acpi_eject_epoll_evt = self
+            .epoll_context
+            .add_event(event_fd, EpollDispatch::AcpiEject)
+            .map_err(|_| StartMicrovmError::RegisterEvent)?;
+        self.acpi_eject_evt = Some(acpi_eject_epoll_evt);
+
         self.epoll_context
             .enable_stdin_event()
             .map_err(|_| StartMicrovmError::RegisterEvent)?;
@@ -1130,15 +1557,22 @@ impl Vmm {
         self.init_microvm()
             .map_err(|e| VmmActionError::StartMicrovm(ErrorKind::Internal, e))?;
 
-        let entry_addr = self
+        let kernel_load_result = self
             .load_kernel()
             .map_err(|e| VmmActionError::StartMicrovm(ErrorKind::Internal, e))?;
 
         self.register_events()
             .map_err(|e| VmmActionError::StartMicrovm(ErrorKind::Internal, e))?;
-        self.start_vcpus(entry_addr)
+        self.start_vcpus(kernel_load_result.entry_addr, kernel_load_result.boot_prot)
             .map_err(|e| VmmActionError::StartMicrovm(ErrorKind::Internal, e))?;
 
+        self.set_running();
+
+        Ok(VmmData::Empty)
+    }
+
+    // Marks the microVM as running and starts writing the metrics periodically.
+    fn set_running(&mut self) {
         // Use expect() to crash if the other thread poisoned this lock.
         self.shared_info
             .write()
@@ -1159,54 +1593,428 @@ impl Vmm {
         if let Err(_) = LOGGER.log_metrics() {
             METRICS.logger.missed_metrics_count.inc();
         }
-
-        Ok(VmmData::Empty)
     }
 
-    /// Waits for all vCPUs to exit and terminates the Firecracker process.
-    fn stop(&mut self, exit_code: i32) {
-        info!("Vmm is stopping.");
-
-        if let Some(v) = self.kill_signaled.take() {
-            v.store(true, Ordering::SeqCst);
-        };
+    // Sends `event` to every vCPU and collects their responses, in the order of the vCPU ids.
+    // A vCPU only looks for events when it is kicked out of KVM_RUN, so it gets kicked until it
+    // responds.
+    fn send_vcpu_event(
+        &self,
+        event: VcpuEvent,
+    ) -> std::result::Result<Vec<VcpuResponse>, VmStateError> {
+        let handles = self
+            .vcpu_handles
+            .as_ref()
+            .ok_or(VmStateError::MicroVMNotRunning)?;
+        for handle in handles.iter() {
+            handle
+                .event_sender
+                .send(event)
+                .map_err(|_| VmStateError::VcpuResponse)?;
+        }
 
-        if let Some(handles) = self.vcpu_handles.take() {
-            for handle in handles {
-                match handle.kill(VCPU_RTSIG_OFFSET) {
-                    Ok(_) => {
-                        if let Err(e) = handle.join() {
-                            warn!("Failed to join vcpu thread: {:?}", e);
-                            METRICS.vcpu.failures.inc();
-                        }
-                    }
-                    Err(e) => {
-                        METRICS.vcpu.failures.inc();
-                        warn!("Failed to kill vcpu thread: {:?}", e)
+        let mut responses = Vec::with_capacity(handles.len());
+        for handle in handles.iter() {
+            let mut retries = 0;
+            loop {
+                handle
+                    .thread
+                    .kill(VCPU_RTSIG_OFFSET)
+                    .map_err(|_| VmStateError::VcpuResponse)?;
+                match handle
+                    .response_receiver
+                    .recv_timeout(Duration::from_millis(VCPU_EVENT_TIMEOUT_MS))
+                {
+                    Ok(response) => {
+                        responses.push(response);
+                        break;
                     }
+                    Err(RecvTimeoutError::Timeout) if retries < VCPU_EVENT_RETRIES => retries += 1,
+                    Err(_) => return Err(VmStateError::VcpuResponse),
                 }
             }
-        };
+        }
+        Ok(responses)
+    }
 
-        if let Some(evt) = self.exit_evt.take() {
-            if let Err(e) = self.epoll_context.remove_event(evt) {
-                warn!(
-                    "Cannot remove the exit event from the Epoll Context. {:?}",
-                    e
-                );
+    fn pause_vcpus(&self) -> std::result::Result<(), VmStateError> {
+        for response in self.send_vcpu_event(VcpuEvent::Pause)? {
+            match response {
+                VcpuResponse::Paused => (),
+                VcpuResponse::Error(e) => return Err(VmStateError::Vcpu(e)),
+                _ => return Err(VmStateError::VcpuResponse),
             }
         }
+        Ok(())
+    }
 
-        if let Err(e) = self.epoll_context.disable_stdin_event() {
-            warn!("Cannot disable the STDIN event. {:?}", e);
+    fn resume_vcpus(&self) -> std::result::Result<(), VmStateError> {
+        for response in self.send_vcpu_event(VcpuEvent::Resume)? {
+            match response {
+                VcpuResponse::Resumed => (),
+                VcpuResponse::Error(e) => return Err(VmStateError::Vcpu(e)),
+                _ => return Err(VmStateError::VcpuResponse),
+            }
         }
+        Ok(())
+    }
 
-        if let Err(e) = self
-            .legacy_device_manager
-            .stdin_handle
-            .lock()
-            .set_canon_mode()
-        {
+    fn is_instance_paused(&self) -> bool {
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .read()
+            .expect("Failed to determine if instance is paused because shared info couldn't be read due to poisoned lock")
+            .state
+            == InstanceState::Paused
+    }
+
+    fn update_vm_state(
+        &mut self,
+        vm_state_config: VmStateConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        match vm_state_config.state {
+            VmStateUpdate::Paused => self.pause_vm(),
+            VmStateUpdate::Resumed => self.resume_vm(),
+        }
+        .map(|_| VmmData::Empty)
+    }
+
+    // Parks the vCPUs outside of KVM_RUN, stops the devices from processing their queues and
+    // freezes the guest clock.
+    fn pause_vm(&mut self) -> std::result::Result<(), VmmActionError> {
+        info!("VMM received pause command");
+        if self.is_instance_paused() {
+            return Err(VmmActionError::VmState(
+                ErrorKind::User,
+                VmStateError::MicroVMAlreadyPaused,
+            ));
+        }
+        if self.vcpu_handles.is_none() {
+            return Err(VmmActionError::VmState(
+                ErrorKind::User,
+                VmStateError::MicroVMNotRunning,
+            ));
+        }
+
+        if let Err(e) = self.pause_all() {
+            // Some vCPUs may have been parked before the failure. Resuming the others is
+            // harmless.
+            self.paused_clock = None;
+            if let Err(e) = self.resume_vcpus() {
+                error!("Cannot resume the vCPUs after a failed pause. {:?}", e);
+            }
+            return Err(VmmActionError::VmState(ErrorKind::Internal, e));
+        }
+
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .write()
+            .expect("Failed to pause microVM because shared info couldn't be written due to poisoned lock")
+            .state = InstanceState::Paused;
+        Ok(())
+    }
+
+    // Parks the vCPUs, saves the guest clock and stops the processing of the device events.
+    fn pause_all(&mut self) -> std::result::Result<(), VmStateError> {
+        self.pause_vcpus()?;
+        self.paused_clock = Some(self.vm.get_clock().map_err(VmStateError::VmClock)?);
+        self.epoll_context
+            .disable_device_events()
+            .map_err(VmStateError::DeviceEvents)
+    }
+
+    fn resume_vm(&mut self) -> std::result::Result<(), VmmActionError> {
+        info!("VMM received resume command");
+        if !self.is_instance_paused() {
+            return Err(VmmActionError::VmState(
+                ErrorKind::User,
+                VmStateError::MicroVMNotPaused,
+            ));
+        }
+
+        if let Some(clock) = self.paused_clock.take() {
+            self.vm.set_clock(&clock).map_err(|e| {
+                VmmActionError::VmState(ErrorKind::Internal, VmStateError::VmClock(e))
+            })?;
+        }
+        if let Err(e) = self.epoll_context.enable_device_events() {
+            warn!("Cannot resume the device events. {:?}", e);
+        }
+        self.resume_vcpus()
+            .map_err(|e| VmmActionError::VmState(ErrorKind::Internal, e))?;
+
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .write()
+            .expect("Failed to resume microVM because shared info couldn't be written due to poisoned lock")
+            .state = InstanceState::Running;
+        Ok(())
+    }
+
+    fn create_snapshot(
+        &mut self,
+        snapshot_config: SnapshotConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        info!("VMM received snapshot create command");
+        if self.vcpu_handles.is_none() {
+            return Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                SnapshotError::MicroVMNotRunning,
+            ));
+        }
+        if self.has_vhost_net_interface() {
+            return Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                SnapshotError::VhostNet,
+            ));
+        }
+        // A microVM paused through the API stays paused.
+        let paused = self.is_instance_paused();
+        if !paused {
+            self.pause_vcpus().map_err(|e| {
+                VmmActionError::Snapshot(ErrorKind::Internal, SnapshotError::Vcpus(e))
+            })?;
+        }
+        // A snapshot of drives with requests in flight would be inconsistent, so the snapshot
+        // file is only written once the drives are drained.
+        let result = self
+            .drain_block_devices()
+            .map_err(|e| VmmActionError::Snapshot(ErrorKind::Internal, e))
+            .and_then(|_| {
+                let mut snapshot_file =
+                    File::create(&snapshot_config.snapshot_path).map_err(|e| {
+                        VmmActionError::Snapshot(ErrorKind::User, SnapshotError::SnapshotFile(e))
+                    })?;
+                self.save_microvm(&mut snapshot_file)
+                    .map_err(|e| VmmActionError::Snapshot(ErrorKind::Internal, e))
+            });
+        // The microVM has to be resumed even if the snapshot could not be created.
+        if !paused {
+            self.resume_vcpus().map_err(|e| {
+                VmmActionError::Snapshot(ErrorKind::Internal, SnapshotError::Vcpus(e))
+            })?;
+        }
+        result?;
+
+        Ok(VmmData::Empty)
+    }
+
+    // Whether a network interface of the booted microVM is serviced by vhost-net, which has no
+    // handler in the VMM.
+    fn has_vhost_net_interface(&self) -> bool {
+        self.network_interface_configs
+            .iter()
+            .any(|cfg| !self.net_handler_id_map.contains_key(&cfg.iface_id))
+    }
+
+    // Waits for the requests the drives have in flight to complete, since the state of their
+    // queues doesn't hold them. The device handlers don't run meanwhile.
+    fn drain_block_devices(&mut self) -> std::result::Result<(), SnapshotError> {
+        let handlers: Vec<(usize, u16)> = self
+            .block_device_configs
+            .config_list
+            .iter()
+            .filter_map(|drive_config| {
+                self.drive_handler_id_map
+                    .get(&drive_config.drive_id)
+                    .map(|&device_idx| (device_idx, drive_config.num_queues()))
+            })
+            .collect();
+        for (device_idx, num_queues) in handlers {
+            for idx in device_idx..device_idx + num_queues as usize {
+                // The queues of a device the guest didn't activate have no handler, nor requests.
+                if let Ok(handler) = self.epoll_context.get_device_handler(idx) {
+                    handler.drain().map_err(SnapshotError::DrainBlockDevice)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the state of the paused microVM to `snapshot_file`.
+    fn save_microvm(&self, snapshot_file: &mut File) -> std::result::Result<(), SnapshotError> {
+        let mut vcpu_states = Vec::new();
+        for response in self
+            .send_vcpu_event(VcpuEvent::SaveState)
+            .map_err(SnapshotError::Vcpus)?
+        {
+            match response {
+                VcpuResponse::SavedState(vcpu_state) => vcpu_states.push(*vcpu_state),
+                VcpuResponse::Error(e) => return Err(SnapshotError::VcpuState(e)),
+                _ => return Err(SnapshotError::Vcpus(VmStateError::VcpuResponse)),
+            }
+        }
+        let vm_state = self.vm.save_state().map_err(SnapshotError::VmState)?;
+        let device_states = self
+            .mmio_device_manager
+            .as_ref()
+            .ok_or(SnapshotError::MicroVMNotRunning)?
+            .save_state();
+        let i8042_state = self
+            .legacy_device_manager
+            .i8042
+            .lock()
+            .expect("Failed to save the i8042 state due to poisoned lock")
+            .save_state();
+        let guest_memory = self
+            .guest_memory
+            .as_ref()
+            .ok_or(SnapshotError::MicroVMNotRunning)?;
+
+        let microvm_state = snapshot::MicrovmState {
+            vm_config: self.vm_config.clone(),
+            vm_state,
+            vcpu_states,
+            device_states,
+            i8042_state,
+        };
+        snapshot::write_snapshot(snapshot_file, &microvm_state, guest_memory)
+    }
+
+    fn load_snapshot(
+        &mut self,
+        snapshot_config: SnapshotConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        info!("VMM received snapshot load command");
+        if self.is_instance_initialized() {
+            return Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                SnapshotError::MicroVMAlreadyRunning,
+            ));
+        }
+        let mut snapshot_file = File::open(&snapshot_config.snapshot_path).map_err(|e| {
+            VmmActionError::Snapshot(ErrorKind::User, SnapshotError::SnapshotFile(e))
+        })?;
+        let microvm_state = snapshot::read_state(&mut snapshot_file)
+            .map_err(|e| VmmActionError::Snapshot(ErrorKind::User, e))?;
+        if microvm_state.vm_config.vcpu_count != Some(microvm_state.vcpu_states.len() as u8) {
+            return Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                SnapshotError::InvalidSnapshot(String::from("vCPU count mismatch")),
+            ));
+        }
+
+        // Nothing is registered with KVM until the guest memory is read back, so a truncated
+        // snapshot file leaves the VMM as it was.
+        let old_vm_config = std::mem::replace(&mut self.vm_config, microvm_state.vm_config.clone());
+        if let Err(e) = self.load_guest_memory(&mut snapshot_file) {
+            self.vm_config = old_vm_config;
+            self.guest_memory = None;
+            return Err(e);
+        }
+
+        // Use expect() to crash if the other thread poisoned this lock.
+        self.shared_info
+            .write()
+            .expect("Failed to load snapshot because shared info couldn't be written due to poisoned lock")
+            .state = InstanceState::Starting;
+
+        // From here on a failure leaves a partially built microVM behind. The caller has to
+        // terminate the process.
+        self.restore_microvm(microvm_state)
+            .map_err(|e| VmmActionError::Snapshot(ErrorKind::Internal, e))?;
+
+        self.set_running();
+
+        Ok(VmmData::Empty)
+    }
+
+    // Allocates the guest memory for the configured size and fills it from `snapshot_file`.
+    fn load_guest_memory(
+        &mut self,
+        snapshot_file: &mut File,
+    ) -> std::result::Result<(), VmmActionError> {
+        self.init_guest_memory().map_err(|e| {
+            VmmActionError::Snapshot(ErrorKind::Internal, SnapshotError::Restore(e))
+        })?;
+        // It is safe to unwrap because the guest memory was initialized above.
+        let guest_memory = self.guest_memory.as_ref().unwrap();
+        snapshot::read_guest_memory(snapshot_file, guest_memory)
+            .map_err(|e| VmmActionError::Snapshot(ErrorKind::User, e))
+    }
+
+    // Rebuilds the microVM described by `microvm_state` on top of the loaded guest memory and
+    // starts running it.
+    fn restore_microvm(
+        &mut self,
+        microvm_state: snapshot::MicrovmState,
+    ) -> std::result::Result<(), SnapshotError> {
+        self.init_devices().map_err(SnapshotError::Restore)?;
+        self.init_microvm().map_err(SnapshotError::Restore)?;
+        self.vm
+            .restore_state(&microvm_state.vm_state)
+            .map_err(SnapshotError::VmState)?;
+        // It is safe to unwrap because the device manager was created in init_devices().
+        self.mmio_device_manager
+            .as_ref()
+            .unwrap()
+            .restore_state(&microvm_state.device_states)
+            .map_err(SnapshotError::DeviceState)?;
+        self.legacy_device_manager
+            .i8042
+            .lock()
+            .expect("Failed to restore the i8042 state due to poisoned lock")
+            .restore_state(&microvm_state.i8042_state);
+
+        self.register_events().map_err(SnapshotError::Restore)?;
+        self.restore_vcpus(&microvm_state.vcpu_states)
+    }
+
+    /// Waits for all vCPUs to exit and terminates the Firecracker process.
+    fn stop(&mut self, exit_code: i32) {
+        info!("Vmm is stopping.");
+
+        if let Some(v) = self.kill_signaled.take() {
+            v.store(true, Ordering::SeqCst);
+        };
+
+        if let Some(handles) = self.vcpu_handles.take() {
+            for handle in handles {
+                // Dropping the event sender wakes up the vcpu thread if it is paused.
+                drop(handle.event_sender);
+                match handle.thread.kill(VCPU_RTSIG_OFFSET) {
+                    Ok(_) => {
+                        if let Err(e) = handle.thread.join() {
+                            warn!("Failed to join vcpu thread: {:?}", e);
+                            METRICS.vcpu.failures.inc();
+                        }
+                    }
+                    Err(e) => {
+                        METRICS.vcpu.failures.inc();
+                        warn!("Failed to kill vcpu thread: {:?}", e)
+                    }
+                }
+            }
+        };
+
+        if let Some(evt) = self.exit_evt.take() {
+            if let Err(e) = self.epoll_context.remove_event(evt) {
+                warn!(
+                    "Cannot remove the exit event from the Epoll Context. {:?}",
+                    e
+                );
+            }
+        }
+
+        if let Some(evt) = self.acpi_eject_evt.take() {
+            if let Err(e) = self.epoll_context.remove_event(evt) {
+                warn!(
+                    "Cannot remove the ACPI eject event from the Epoll Context. {:?}",
+                    e
+                );
+            }
+        }
+
+        if let Err(e) = self.epoll_context.disable_stdin_event() {
+            warn!("Cannot disable the STDIN event. {:?}", e);
+        }
+
+        if let Err(e) = self
+            .legacy_device_manager
+            .stdin_handle
+            .lock()
+            .set_canon_mode()
+        {
             warn!("Cannot set canonical mode for the terminal. {:?}", e);
         }
 
@@ -1238,6 +2046,8 @@ impl Vmm {
         let mut events = Vec::<epoll::Event>::with_capacity(EPOLL_EVENTS_LEN);
         // Safe as we pass to set_len the value passed to with_capacity.
         unsafe { events.set_len(EPOLL_EVENTS_LEN) };
+        let mut device_events =
+            vec![epoll::Event::new(epoll::Events::empty(), 0); EPOLL_EVENTS_LEN];
 
         let epoll_raw_fd = self.epoll_context.epoll_raw_fd;
 
@@ -1259,6 +2069,13 @@ impl Vmm {
                             }
                             self.stop(0);
                         }
+                        EpollDispatch::AcpiEject => {
+                            self.legacy_device_manager
+                                .acpi_eject_evt
+                                .read()
+                                .map_err(Error::EventFd)?;
+                            self.remove_ejected_devices();
+                        }
                         EpollDispatch::Stdin => {
                             let mut out = [0u8; 64];
                             let stdin_lock = self.legacy_device_manager.stdin_handle.lock();
@@ -1286,15 +2103,33 @@ impl Vmm {
                             }
                         }
                         EpollDispatch::DeviceHandler(device_idx, device_token) => {
-                            METRICS.vmm.device_events.inc();
-                            match self.epoll_context.get_device_handler(device_idx) {
-                                Ok(handler) => handler.handle_event(
+                            self.handle_device_event(
+                                device_idx,
+                                device_token,
+                                events[i].events().bits(),
+                            );
+                        }
+                        EpollDispatch::DeviceEvents => {
+                            // The device handlers are polled through their own epoll fd, so that
+                            // they can be taken out of the main loop while the microVM is paused.
+                            let num_device_events = epoll::wait(
+                                self.epoll_context.device_epoll_raw_fd,
+                                0,
+                                &mut device_events[..],
+                            )
+                            .map_err(Error::Poll)?;
+                            for device_event in device_events.iter().take(num_device_events) {
+                                let device_dispatch_idx = device_event.data() as usize;
+                                if let Some(EpollDispatch::DeviceHandler(
+                                    device_idx,
                                     device_token,
-                                    events[i].events().bits(),
-                                    EpollHandlerPayload::Empty,
-                                ),
-                                Err(e) => {
-                                    warn!("invalid handler for device {}: {:?}", device_idx, e)
+                                )) = self.epoll_context.dispatch_table[device_dispatch_idx]
+                                {
+                                    self.handle_device_event(
+                                        device_idx,
+                                        device_token,
+                                        device_event.events().bits(),
+                                    );
                                 }
                             }
                         }
@@ -1314,6 +2149,14 @@ impl Vmm {
         }
     }
 
+    fn handle_device_event(&mut self, device_idx: usize, device_token: DeviceEventT, evset: u32) {
+        METRICS.vmm.device_events.inc();
+        match self.epoll_context.get_device_handler(device_idx) {
+            Ok(handler) => handler.handle_event(device_token, evset, EpollHandlerPayload::Empty),
+            Err(e) => warn!("invalid handler for device {}: {:?}", device_idx, e),
+        }
+    }
+
     // Count the number of pages dirtied since the last call to this function.
     // Because this is used for metrics, it swallows most errors and simply doesn't count dirty
     // pages if the KVM operation fails.
@@ -1356,8 +2199,7 @@ impl Vmm {
 
     fn configure_boot_source(
         &mut self,
-        kernel_image_path: String,
-        kernel_cmdline: Option<String>,
+        boot_source_cfg: BootSourceConfig,
     ) -> std::result::Result<VmmData, VmmActionError> {
         if self.is_instance_initialized() {
             return Err(VmmActionError::BootSource(
@@ -1366,12 +2208,25 @@ impl Vmm {
             ));
         }
 
-        let kernel_file = File::open(kernel_image_path).map_err(|_| {
+        let kernel_file = File::open(boot_source_cfg.kernel_image_path).map_err(|_| {
             VmmActionError::BootSource(ErrorKind::User, BootSourceConfigError::InvalidKernelPath)
         })?;
+        let initrd_file = match boot_source_cfg.initrd_path {
+            Some(initrd_path) => Some(File::open(initrd_path).map_err(|_| {
+                VmmActionError::BootSource(
+                    ErrorKind::User,
+                    BootSourceConfigError::InvalidInitrdPath,
+                )
+            })?),
+            None => None,
+        };
         let mut cmdline = kernel_cmdline::Cmdline::new(x86_64::layout::CMDLINE_MAX_SIZE);
         cmdline
-            .insert_str(kernel_cmdline.unwrap_or(String::from(DEFAULT_KERNEL_CMDLINE)))
+            .insert_str(
+                boot_source_cfg
+                    .boot_args
+                    .unwrap_or(String::from(DEFAULT_KERNEL_CMDLINE)),
+            )
             .map_err(|_| {
                 VmmActionError::BootSource(
                     ErrorKind::User,
@@ -1381,6 +2236,7 @@ impl Vmm {
 
         let kernel_config = KernelConfig {
             kernel_file,
+            initrd_file,
             cmdline,
             cmdline_addr: GuestAddress(x86_64::layout::CMDLINE_START),
         };
@@ -1451,6 +2307,10 @@ impl Vmm {
             self.vm_config.cpu_template = machine_config.cpu_template;
         }
 
+        if machine_config.acpi_only.is_some() {
+            self.vm_config.acpi_only = machine_config.acpi_only;
+        }
+
         Ok(VmmData::Empty)
     }
 
@@ -1464,12 +2324,38 @@ impl Vmm {
                 NetworkInterfaceError::UpdateNotAllowedPostBoot,
             ));
         }
+        for group_id in body
+            .rx_rate_limit_group
+            .iter()
+            .chain(body.tx_rate_limit_group.iter())
+        {
+            if !self.rate_limit_group_configs.contains(group_id) {
+                return Err(VmmActionError::NetworkConfig(
+                    ErrorKind::User,
+                    NetworkInterfaceError::InvalidRateLimitGroup,
+                ));
+            }
+        }
         self.network_interface_configs
             .insert(body)
             .map(|_| VmmData::Empty)
             .map_err(|e| VmmActionError::NetworkConfig(ErrorKind::User, e))
     }
 
+    fn insert_rate_limit_group(
+        &mut self,
+        body: RateLimitGroupConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if self.is_instance_initialized() {
+            return Err(VmmActionError::RateLimitGroup(
+                ErrorKind::User,
+                RateLimitGroupError::UpdateNotAllowedPostBoot,
+            ));
+        }
+        self.rate_limit_group_configs.insert(body);
+        Ok(VmmData::Empty)
+    }
+
     #[cfg(feature = "vsock")]
     fn insert_vsock_device(
         &mut self,
@@ -1501,15 +2387,49 @@ impl Vmm {
                 DriveError::InvalidBlockDeviceID,
             ))?;
 
+        // The overlay only makes sense on top of the base image it was created for.
+        let has_overlay = self.block_device_configs.config_list[block_device_index]
+            .overlay_path()
+            .is_some();
+        if has_overlay && self.is_instance_initialized() {
+            return Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::UpdateNotAllowedWithOverlay,
+            ));
+        }
+        // The running device only knows how to swap raw images.
+        if self.block_device_configs.config_list[block_device_index].format() != DriveFormat::Raw
+            && self.is_instance_initialized()
+        {
+            return Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::UpdateNotAllowedWithQcow2,
+            ));
+        }
+
         let file_path = PathBuf::from(path_on_host);
         // Try to open the file specified by path_on_host using the permissions of the block_device.
         let disk_file = OpenOptions::new()
             .read(true)
-            .write(!self.block_device_configs.config_list[block_device_index].is_read_only())
+            .write(
+                !self.block_device_configs.config_list[block_device_index].is_read_only()
+                    && !has_overlay,
+            )
+            .custom_flags(open_flags(
+                self.block_device_configs.config_list[block_device_index].cache_type(),
+            ))
             .open(&file_path)
             .map_err(|_| {
                 VmmActionError::DriveConfig(ErrorKind::User, DriveError::CannotOpenBlockDevice)
             })?;
+        // Writing the last block of an unaligned image with direct I/O would grow it.
+        if self.block_device_configs.config_list[block_device_index].cache_type()
+            == CacheType::Direct
+        {
+            devices::virtio::DirectFile::check_size(&disk_file).map_err(|_| {
+                VmmActionError::DriveConfig(ErrorKind::User, DriveError::DirectRequiresAlignedSize)
+            })?;
+        }
 
         // Update the path of the block device with the specified path_on_host.
         self.block_device_configs.config_list[block_device_index].path_on_host = file_path;
@@ -1524,42 +2444,229 @@ impl Vmm {
         Ok(VmmData::Empty)
     }
 
-    fn rescan_block_device(
+    fn update_block_device_rate_limiter(
         &mut self,
-        drive_id: &String,
+        drive_id: String,
+        update: BlockRateLimiterUpdate,
     ) -> std::result::Result<VmmData, VmmActionError> {
-        // Rescan can only happen after the guest is booted.
+        let block_device_index = self
+            .block_device_configs
+            .get_index_of_drive_id(&drive_id)
+            .ok_or(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::InvalidBlockDeviceID,
+            ))?;
+
+        // Before boot, the rate limiter is still in the configuration of the drive.
         if !self.is_instance_initialized() {
-            return Err(VmmActionError::DriveConfig(
+            return self.block_device_configs.config_list[block_device_index]
+                .rate_limiter
+                .get_or_insert_with(BlockRateLimiter::default)
+                .update(update)
+                .map(|_| VmmData::Empty)
+                .map_err(|_| {
+                    VmmActionError::DriveConfig(
+                        ErrorKind::Internal,
+                        DriveError::BlockDeviceUpdateFailed,
+                    )
+                });
+        }
+
+        let updated = match self.drive_handler_id_map.get(&drive_id).cloned() {
+            Some(device_idx) => self
+                .update_handler_rate_limiters(
+                    device_idx,
+                    EpollHandlerPayload::DriveRateLimiterPayload(update),
+                )
+                .map_err(|e| {
+                    error!(
+                        "Failed to update the rate limiters of a block device: {:?}",
+                        e
+                    );
+                    VmmActionError::DriveConfig(
+                        ErrorKind::Internal,
+                        DriveError::BlockDeviceUpdateFailed,
+                    )
+                })?,
+            None => false,
+        };
+        if updated {
+            Ok(VmmData::Empty)
+        } else {
+            Err(VmmActionError::DriveConfig(
                 ErrorKind::User,
-                DriveError::OperationNotAllowedPreBoot,
-            ));
+                DriveError::BlockDeviceUpdateFailed,
+            ))
         }
+    }
 
-        // Safe to unwrap() because mmio_device_manager is initialized in init_devices(), which is
-        // called before the guest boots, and this function is called after boot.
-        let device_manager = self.mmio_device_manager.as_ref().unwrap();
-        match device_manager.get_address(drive_id) {
-            Some(&address) => {
-                for drive_config in self.block_device_configs.config_list.iter() {
-                    if drive_config.drive_id == *drive_id {
-                        let metadata = metadata(&drive_config.path_on_host).map_err(|_| {
-                            VmmActionError::DriveConfig(
-                                ErrorKind::User,
-                                DriveError::BlockDeviceUpdateFailed,
-                            )
-                        })?;
-                        let new_size = metadata.len();
-                        if new_size % virtio::block::SECTOR_SIZE != 0 {
-                            warn!(
-                                "Disk size {} is not a multiple of sector size {}; \
-                                 the remainder will not be visible to the guest.",
-                                new_size,
-                                virtio::block::SECTOR_SIZE
-                            );
+    fn update_net_device(
+        &mut self,
+        update_config: NetworkInterfaceUpdateConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        // Before boot, the rate limiters, the capture and the firewall rules are still in the
+        // configuration of the interface.
+        if !self.is_instance_initialized() {
+            return self
+                .network_interface_configs
+                .apply_update(update_config)
+                .map(|_| VmmData::Empty)
+                .map_err(|e| VmmActionError::NetworkConfig(ErrorKind::User, e));
+        }
+
+        if !self
+            .network_interface_configs
+            .contains(&update_config.iface_id)
+        {
+            return Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::InvalidIfaceId,
+            ));
+        }
+        let device_idx = match self.net_handler_id_map.get(&update_config.iface_id) {
+            Some(device_idx) => *device_idx,
+            None => {
+                return Err(VmmActionError::NetworkConfig(
+                    ErrorKind::User,
+                    NetworkInterfaceError::DeviceUpdateFailed,
+                ));
+            }
+        };
+        // The new capture file is created first, so a bad path leaves the device untouched.
+        let capture = match update_config.capture {
+            Some(Some(ref capture)) => Some(Some(PcapWriter::new(capture).map_err(|e| {
+                VmmActionError::NetworkConfig(
+                    ErrorKind::User,
+                    NetworkInterfaceError::OpenCapture(e),
+                )
+            })?)),
+            Some(None) => Some(None),
+            None => None,
+        };
+        if let Some(ref firewall) = update_config.firewall {
+            firewall.validate().map_err(|e| {
+                VmmActionError::NetworkConfig(
+                    ErrorKind::User,
+                    NetworkInterfaceError::InvalidFirewallRule(e),
+                )
+            })?;
+        }
+
+        let mut updated = self
+            .update_handler_rate_limiters(
+                device_idx,
+                EpollHandlerPayload::NetRateLimiterPayload(
+                    update_config.rx_rate_limiter.unwrap_or_default(),
+                    update_config.tx_rate_limiter.unwrap_or_default(),
+                ),
+            )
+            .map_err(|e| {
+                error!(
+                    "Failed to update the rate limiters of a network device: {:?}",
+                    e
+                );
+                VmmActionError::NetworkConfig(
+                    ErrorKind::Internal,
+                    NetworkInterfaceError::DeviceUpdateFailed,
+                )
+            })?;
+        if let Some(capture) = capture {
+            updated = updated
+                && self.send_handler_event(
+                    device_idx,
+                    virtio::net::CAPTURE_UPDATE_EVENT,
+                    EpollHandlerPayload::NetCapturePayload(capture),
+                );
+        }
+        if let Some(ref firewall) = update_config.firewall {
+            // The counters of the previous rules are dropped along with them.
+            let firewall = Firewall::new(firewall);
+            let metrics = firewall.metrics();
+            updated = updated
+                && self.send_handler_event(
+                    device_idx,
+                    virtio::net::FIREWALL_UPDATE_EVENT,
+                    EpollHandlerPayload::NetFirewallPayload(firewall),
+                );
+            if updated {
+                METRICS.net_firewall.set(&update_config.iface_id, metrics);
+            }
+        }
+        if updated {
+            Ok(VmmData::Empty)
+        } else {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::DeviceUpdateFailed,
+            ))
+        }
+    }
+
+    fn send_ctrl_alt_del(&mut self) -> std::result::Result<VmmData, VmmActionError> {
+        // The keyboard interrupt is only wired up once the guest is booted.
+        if self.vcpu_handles.is_none() {
+            return Err(VmmActionError::VmState(
+                ErrorKind::User,
+                VmStateError::MicroVMNotRunning,
+            ));
+        }
+
+        self.legacy_device_manager
+            .i8042
+            .lock()
+            .expect("Failed to send Ctrl+Alt+Del due to poisoned lock")
+            .trigger_ctrl_alt_del()
+            .map_err(|e| VmmActionError::SendCtrlAltDel(ErrorKind::Internal, e))?;
+        Ok(VmmData::Empty)
+    }
+
+    fn rescan_block_device(
+        &mut self,
+        drive_id: &String,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        // Rescan can only happen after the guest is booted.
+        if !self.is_instance_initialized() {
+            return Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::OperationNotAllowedPreBoot,
+            ));
+        }
+
+        // Safe to unwrap() because mmio_device_manager is initialized in init_devices(), which is
+        // called before the guest boots, and this function is called after boot.
+        let device_manager = self.mmio_device_manager.as_ref().unwrap();
+        match device_manager.get_address(drive_id) {
+            Some(&address) => {
+                for drive_config in self.block_device_configs.config_list.iter() {
+                    if drive_config.drive_id == *drive_id {
+                        let update_failed = |_| {
+                            VmmActionError::DriveConfig(
+                                ErrorKind::User,
+                                DriveError::BlockDeviceUpdateFailed,
+                            )
+                        };
+                        // The guest sees the virtual size of a qcow2 image.
+                        let new_size = match drive_config.format() {
+                            DriveFormat::Raw => metadata(&drive_config.path_on_host)
+                                .map_err(update_failed)?
+                                .len(),
+                            DriveFormat::Qcow2 => File::open(&drive_config.path_on_host)
+                                .and_then(|file| {
+                                    devices::virtio::QcowFile::new(file, &drive_config.path_on_host)
+                                })
+                                .and_then(|mut qcow| qcow.seek(SeekFrom::End(0)))
+                                .map_err(update_failed)?,
+                        };
+                        if new_size % virtio::block::SECTOR_SIZE != 0 {
+                            warn!(
+                                "Disk size {} is not a multiple of sector size {}; \
+                                 the remainder will not be visible to the guest.",
+                                new_size,
+                                virtio::block::SECTOR_SIZE
+                            );
                         }
                         return device_manager
-                            .update_drive(address, new_size)
+                            .update_drive(address, new_size, drive_config.num_queues())
                             .map(|_| VmmData::Empty)
                             .map_err(|_| {
                                 VmmActionError::DriveConfig(
@@ -1587,17 +2694,278 @@ impl Vmm {
         &mut self,
         block_device_config: BlockDeviceConfig,
     ) -> std::result::Result<VmmData, VmmActionError> {
+        if let Some(ref group_id) = block_device_config.rate_limit_group {
+            if !self.rate_limit_group_configs.contains(group_id) {
+                return Err(VmmActionError::DriveConfig(
+                    ErrorKind::User,
+                    DriveError::InvalidRateLimitGroup,
+                ));
+            }
+        }
         if self.is_instance_initialized() {
+            return self.hotplug_block_device(block_device_config);
+        }
+
+        self.block_device_configs
+            .insert(block_device_config)
+            .map(|_| VmmData::Empty)
+            .map_err(|e| VmmActionError::DriveConfig(ErrorKind::User, e))
+    }
+
+    // Adds a new block device to the running microVM and tells the guest about it.
+    fn hotplug_block_device(
+        &mut self,
+        block_device_config: BlockDeviceConfig,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        // The drives that already exist can only have their path updated after boot.
+        if self
+            .block_device_configs
+            .get_index_of_drive_id(&block_device_config.drive_id)
+            .is_some()
+        {
             return Err(VmmActionError::DriveConfig(
                 ErrorKind::User,
                 DriveError::UpdateNotAllowedPostBoot,
             ));
         }
+        // The root file system is mounted before anything can be plugged.
+        if block_device_config.is_root_device {
+            return Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::RootBlockDeviceHotplug,
+            ));
+        }
+        if self.is_acpi_disabled() {
+            return Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::AcpiDisabledHotplug,
+            ));
+        }
 
+        let drive_id = block_device_config.drive_id.clone();
         self.block_device_configs
             .insert(block_device_config)
-            .map(|_| VmmData::Empty)
-            .map_err(|e| VmmActionError::DriveConfig(ErrorKind::User, e))
+            .map_err(|e| VmmActionError::DriveConfig(ErrorKind::User, e))?;
+        let result = self.plug_block_device(&drive_id);
+        if result.is_err() {
+            // The drive was just inserted, so removing it cannot fail.
+            let _ = self.block_device_configs.remove(&drive_id);
+        }
+        result.map(|_| VmmData::Empty)
+    }
+
+    fn plug_block_device(&mut self, drive_id: &String) -> std::result::Result<(), VmmActionError> {
+        let hotplug_failed = || {
+            VmmActionError::DriveConfig(ErrorKind::Internal, DriveError::BlockDeviceHotplugFailed)
+        };
+        // It is safe to unwrap because the drive was just inserted.
+        let index = self
+            .block_device_configs
+            .get_index_of_drive_id(drive_id)
+            .unwrap();
+        let drive_config = &mut self.block_device_configs.config_list[index];
+        let num_queues = drive_config.num_queues();
+        let block_box = create_block_device(
+            drive_config,
+            &mut self.epoll_context,
+            &mut self.drive_handler_id_map,
+            &self.rate_limit_groups,
+        )
+        .map_err(|e| match e {
+            StartMicrovmError::OpenBlockDevice(_) => {
+                VmmActionError::DriveConfig(ErrorKind::User, DriveError::CannotOpenBlockDevice)
+            }
+            e => {
+                error!("Failed to create block device {}: {}", drive_id, e);
+                hotplug_failed()
+            }
+        });
+        let block_box = match block_box {
+            Ok(block_box) => block_box,
+            Err(e) => {
+                self.remove_drive_handlers(drive_id, num_queues);
+                return Err(e);
+            }
+        };
+
+        // Safe to unwrap() because mmio_device_manager is initialized in init_devices(), which is
+        // called before the guest boots, and this function is called after boot.
+        let slot = match self.mmio_device_manager.as_mut().unwrap().hotplug_device(
+            block_box,
+            Some(drive_id.clone()),
+            self.vm.get_fd(),
+        ) {
+            Ok(slot) => slot,
+            Err(e) => {
+                error!("Failed to plug block device {}: {}", drive_id, e);
+                self.remove_drive_handlers(drive_id, num_queues);
+                return Err(hotplug_failed());
+            }
+        };
+
+        // If the lock is poisoned, it's OK to panic.
+        self.legacy_device_manager
+            .acpi_hotplug
+            .lock()
+            .expect("Failed to plug block device due to poisoned lock")
+            .plug(slot)
+            .map_err(|e| {
+                error!(
+                    "Failed to notify the guest of block device {}: {:?}",
+                    drive_id, e
+                );
+                hotplug_failed()
+            })
+    }
+
+    // Removes a block device before boot, or asks the guest to eject it from the running
+    // microVM. The device is only removed once the guest ejected it, and is listed in the
+    // pending drive ejects of the instance info until then.
+    fn remove_block_device(
+        &mut self,
+        drive_id: String,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if !self.is_instance_initialized() {
+            return self
+                .block_device_configs
+                .remove(&drive_id)
+                .map(|_| VmmData::Empty)
+                .map_err(|e| VmmActionError::DriveConfig(ErrorKind::User, e));
+        }
+
+        match self.block_device_configs.get_index_of_drive_id(&drive_id) {
+            Some(index) => {
+                if self.block_device_configs.config_list[index].is_root_device {
+                    return Err(VmmActionError::DriveConfig(
+                        ErrorKind::User,
+                        DriveError::RootBlockDeviceHotplug,
+                    ));
+                }
+            }
+            None => {
+                return Err(VmmActionError::DriveConfig(
+                    ErrorKind::User,
+                    DriveError::InvalidBlockDeviceID,
+                ));
+            }
+        }
+        if self.is_acpi_disabled() {
+            return Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::AcpiDisabledHotplug,
+            ));
+        }
+
+        // Safe to unwrap() because mmio_device_manager is initialized in init_devices(), which is
+        // called before the guest boots, and this function is called after boot.
+        let slot = self
+            .mmio_device_manager
+            .as_ref()
+            .unwrap()
+            .unpluggable_slot(&drive_id)
+            .map_err(|e| match e {
+                device_manager::mmio::Error::DeviceOnCmdline => VmmActionError::DriveConfig(
+                    ErrorKind::User,
+                    DriveError::CmdlineBlockDeviceUnplug,
+                ),
+                e => {
+                    error!("Failed to unplug block device {}: {}", drive_id, e);
+                    VmmActionError::DriveConfig(
+                        ErrorKind::Internal,
+                        DriveError::BlockDeviceHotplugFailed,
+                    )
+                }
+            })?;
+
+        // If the lock is poisoned, it's OK to panic.
+        self.legacy_device_manager
+            .acpi_hotplug
+            .lock()
+            .expect("Failed to unplug block device due to poisoned lock")
+            .request_eject(slot)
+            .map_err(|e| {
+                error!(
+                    "Failed to notify the guest of block device {}: {:?}",
+                    drive_id, e
+                );
+                VmmActionError::DriveConfig(
+                    ErrorKind::Internal,
+                    DriveError::BlockDeviceHotplugFailed,
+                )
+            })?;
+
+        // Use expect() to crash if the other thread poisoned this lock.
+        let mut shared_info = self
+            .shared_info
+            .write()
+            .expect("Failed to unplug block device due to poisoned lock");
+        if !shared_info.pending_drive_ejects.contains(&drive_id) {
+            shared_info.pending_drive_ejects.push(drive_id);
+        }
+        Ok(VmmData::Accepted)
+    }
+
+    // Removes the devices the guest ejected after being asked to.
+    fn remove_ejected_devices(&mut self) {
+        // If the lock is poisoned, it's OK to panic.
+        let ejected = self
+            .legacy_device_manager
+            .acpi_hotplug
+            .lock()
+            .expect("Failed to remove ejected devices due to poisoned lock")
+            .take_ejected();
+        // Safe to unwrap() because mmio_device_manager is initialized in init_devices(), which is
+        // called before the guest boots, and the guest ejects devices after boot.
+        let slots: Vec<usize> = self
+            .mmio_device_manager
+            .as_ref()
+            .unwrap()
+            .occupied_slots()
+            .into_iter()
+            .filter(|&slot| ejected & (1 << slot) != 0)
+            .collect();
+        for slot in slots {
+            let drive_id = match self
+                .mmio_device_manager
+                .as_mut()
+                .unwrap()
+                .unplug_device(slot, self.vm.get_fd())
+            {
+                Ok(Some(drive_id)) => drive_id,
+                Ok(None) => continue,
+                Err(e) => {
+                    error!("Failed to unplug the device in slot {}: {}", slot, e);
+                    continue;
+                }
+            };
+            if let Some(index) = self.block_device_configs.get_index_of_drive_id(&drive_id) {
+                let num_queues = self.block_device_configs.config_list[index].num_queues();
+                self.remove_drive_handlers(&drive_id, num_queues);
+                // The drive was just found, so removing it cannot fail.
+                let _ = self.block_device_configs.remove(&drive_id);
+            }
+            // Use expect() to crash if the other thread poisoned this lock.
+            self.shared_info
+                .write()
+                .expect("Failed to remove ejected devices due to poisoned lock")
+                .pending_drive_ejects
+                .retain(|id| *id != drive_id);
+        }
+    }
+
+    // Whether the guest was told to ignore ACPI, and so cannot be told about hot-plug events.
+    fn is_acpi_disabled(&self) -> bool {
+        self.kernel_config
+            .as_ref()
+            .map_or(false, |kernel_config| acpi_disabled(&kernel_config.cmdline))
+    }
+
+    // Drops the epoll handlers of the queues of a block device.
+    fn remove_drive_handlers(&mut self, drive_id: &String, num_queues: u16) {
+        if let Some(device_idx) = self.drive_handler_id_map.remove(drive_id) {
+            self.epoll_context
+                .remove_device_handlers(device_idx, num_queues as usize);
+        }
     }
 
     fn init_logger(
@@ -1665,6 +3033,42 @@ impl Vmm {
             .expect("one-shot channel closed");
     }
 
+    // Applies every resource described by the configuration file, in the same way the
+    // corresponding API requests would, and then starts the microVM.
+    fn configure_from_file(
+        &mut self,
+        config_file: ConfigFile,
+    ) -> std::result::Result<VmmData, VmmActionError> {
+        if let Some(logger_config) = config_file.logger {
+            self.init_logger(logger_config)?;
+        }
+        if let Some(machine_config) = config_file.machine_config {
+            self.set_vm_configuration(machine_config)?;
+        }
+        self.configure_boot_source(config_file.boot_source)?;
+        // The groups are referenced by the drives and network interfaces.
+        for group_config in config_file.rate_limit_groups {
+            self.insert_rate_limit_group(group_config)?;
+        }
+        for block_device_config in config_file.drives {
+            self.insert_block_device(block_device_config)?;
+        }
+        for netif_config in config_file.network_interfaces {
+            self.insert_net_device(netif_config)?;
+        }
+        #[cfg(feature = "vsock")]
+        for vsock_config in config_file.vsocks {
+            self.insert_vsock_device(vsock_config)?;
+        }
+        if let Some(mmds_data) = config_file.mmds {
+            mmds::MMDS
+                .lock()
+                .expect("Failed to acquire lock on MMDS info")
+                .put_data(mmds_data);
+        }
+        self.start_microvm()
+    }
+
     fn run_vmm_action(&mut self) -> Result<()> {
         let request = match self.from_api.try_recv() {
             Ok(t) => *t,
@@ -1678,17 +3082,14 @@ impl Vmm {
 
         match request {
             VmmAction::ConfigureBootSource(boot_source_body, sender) => {
-                Vmm::send_response(
-                    self.configure_boot_source(
-                        boot_source_body.kernel_image_path,
-                        boot_source_body.boot_args,
-                    ),
-                    sender,
-                );
+                Vmm::send_response(self.configure_boot_source(boot_source_body), sender);
             }
             VmmAction::ConfigureLogger(logger_description, sender) => {
                 Vmm::send_response(self.init_logger(logger_description), sender);
             }
+            VmmAction::CreateSnapshot(snapshot_config, sender) => {
+                Vmm::send_response(self.create_snapshot(snapshot_config), sender);
+            }
             VmmAction::GetVmConfiguration(sender) => {
                 Vmm::send_response(
                     Ok(VmmData::MachineConfiguration(self.vm_config.clone())),
@@ -1701,13 +3102,32 @@ impl Vmm {
             VmmAction::InsertNetworkDevice(netif_body, sender) => {
                 Vmm::send_response(self.insert_net_device(netif_body), sender);
             }
+            VmmAction::InsertRateLimitGroup(group_cfg, sender) => {
+                Vmm::send_response(self.insert_rate_limit_group(group_cfg), sender);
+            }
             #[cfg(feature = "vsock")]
             VmmAction::InsertVsockDevice(vsock_cfg, sender) => {
                 Vmm::send_response(self.insert_vsock_device(vsock_cfg), sender);
             }
+            VmmAction::LoadSnapshot(snapshot_config, sender) => {
+                let response = self.load_snapshot(snapshot_config);
+                // The KVM resources of a microVM that failed to be restored cannot be released
+                // in place, so the process goes away once the error has been reported.
+                let restore_failed = response.is_err() && self.is_instance_initialized();
+                Vmm::send_response(response, sender);
+                if restore_failed {
+                    self.stop(1);
+                }
+            }
+            VmmAction::RemoveBlockDevice(drive_id, sender) => {
+                Vmm::send_response(self.remove_block_device(drive_id), sender);
+            }
             VmmAction::RescanBlockDevice(drive_id, sender) => {
                 Vmm::send_response(self.rescan_block_device(&drive_id), sender);
             }
+            VmmAction::SendCtrlAltDel(sender) => {
+                Vmm::send_response(self.send_ctrl_alt_del(), sender);
+            }
             VmmAction::StartMicroVm(sender) => {
                 Vmm::send_response(self.start_microvm(), sender);
             }
@@ -1717,11 +3137,172 @@ impl Vmm {
             VmmAction::UpdateBlockDevicePath(drive_id, path_on_host, sender) => {
                 Vmm::send_response(self.set_block_device_path(drive_id, path_on_host), sender);
             }
+            VmmAction::UpdateBlockDeviceRateLimiter(drive_id, update, sender) => {
+                Vmm::send_response(
+                    self.update_block_device_rate_limiter(drive_id, update),
+                    sender,
+                );
+            }
+            VmmAction::UpdateNetworkInterface(update_config, sender) => {
+                Vmm::send_response(self.update_net_device(update_config), sender);
+            }
+            VmmAction::UpdateVmState(vm_state_config, sender) => {
+                Vmm::send_response(self.update_vm_state(vm_state_config), sender);
+            }
         };
         Ok(())
     }
 }
 
+// Whether the guest is told to ignore the ACPI tables, and so has to find the devices on the
+// command line.
+fn acpi_disabled(cmdline: &kernel_cmdline::Cmdline) -> bool {
+    cmdline
+        .as_str()
+        .split_whitespace()
+        .any(|param| param == "acpi=off")
+}
+
+// Returns the extra flags the image of a drive is opened with.
+fn open_flags(cache_type: CacheType) -> i32 {
+    match cache_type {
+        CacheType::Direct => libc::O_DIRECT,
+        _ => 0,
+    }
+}
+
+// Opens the image of a drive and builds its virtio-blk device, with one epoll handler per queue.
+fn create_block_device(
+    drive_config: &mut BlockDeviceConfig,
+    epoll_context: &mut EpollContext,
+    drive_handler_id_map: &mut HashMap<String, usize>,
+    rate_limit_groups: &HashMap<String, Arc<RateLimiterGroup>>,
+) -> std::result::Result<Box<devices::virtio::Block>, StartMicrovmError> {
+    // Add the block device from file. A drive with an overlay never writes to its base.
+    let block_file = OpenOptions::new()
+        .read(true)
+        .write(!drive_config.is_read_only && drive_config.overlay_path.is_none())
+        .custom_flags(open_flags(drive_config.cache_type()))
+        .open(&drive_config.path_on_host)
+        .map_err(|e| StartMicrovmError::OpenBlockDevice(e))?;
+    let disk_image = match drive_config.overlay_path {
+        Some(ref overlay_path) => {
+            let overlay_file = OpenOptions::new()
+                .read(true)
+                .write(!drive_config.is_read_only)
+                .create(!drive_config.is_read_only)
+                .open(overlay_path)
+                .map_err(StartMicrovmError::OpenBlockDevice)?;
+            devices::virtio::DiskImage::Overlay(
+                devices::virtio::Overlay::new(block_file, overlay_file)
+                    .map_err(StartMicrovmError::OpenBlockDevice)?,
+            )
+        }
+        None => match drive_config.format() {
+            DriveFormat::Raw if drive_config.cache_type() == CacheType::Direct => {
+                devices::virtio::DiskImage::Direct(
+                    devices::virtio::DirectFile::new(block_file)
+                        .map_err(StartMicrovmError::OpenBlockDevice)?,
+                )
+            }
+            DriveFormat::Raw => devices::virtio::DiskImage::Raw(block_file),
+            DriveFormat::Qcow2 => devices::virtio::DiskImage::Qcow(
+                devices::virtio::QcowFile::new(block_file, &drive_config.path_on_host)
+                    .map_err(StartMicrovmError::OpenBlockDevice)?,
+            ),
+        },
+    };
+
+    let (epoll_configs, device_idx) =
+        epoll_context.allocate_virtio_block_tokens(drive_config.num_queues());
+    drive_handler_id_map.insert(drive_config.drive_id.clone(), device_idx);
+
+    // Only the rate limiter shared by reads and writes is a member of the rate-limit group. The
+    // references to the groups are checked when the drives are configured.
+    let mut rate_limiter = drive_config.rate_limiter.take();
+    if let Some(group) = drive_config
+        .rate_limit_group
+        .as_ref()
+        .and_then(|group_id| rate_limit_groups.get(group_id))
+    {
+        rate_limiter
+            .get_or_insert_with(BlockRateLimiter::default)
+            .shared
+            .set_group(group.clone());
+    }
+
+    let block = devices::virtio::Block::new(
+        disk_image,
+        drive_config.is_read_only,
+        epoll_configs,
+        rate_limiter,
+        drive_config.io_engine(),
+        drive_config.queue_size(),
+        drive_config.cache_type(),
+    )
+    .map_err(StartMicrovmError::CreateBlockDevice)?;
+    Ok(Box::new(block))
+}
+
+// Makes the rate limiter of a device a member of its rate-limit group, if it references one.
+fn with_rate_limit_group(
+    rate_limiter: Option<RateLimiter>,
+    group_id: Option<&String>,
+    rate_limit_groups: &HashMap<String, Arc<RateLimiterGroup>>,
+) -> Option<RateLimiter> {
+    // The references to the groups are checked when the devices are configured.
+    match group_id.and_then(|group_id| rate_limit_groups.get(group_id)) {
+        Some(group) => {
+            let mut rate_limiter = rate_limiter.unwrap_or_default();
+            rate_limiter.set_group(group.clone());
+            Some(rate_limiter)
+        }
+        None => rate_limiter,
+    }
+}
+
+// Serves the requests sent by the VMM thread to a vcpu thread. While paused, the vcpu thread
+// blocks here until it is resumed. Returns false if the vcpu thread should exit.
+fn handle_vcpu_events(
+    vcpu: &Vcpu,
+    events: &Receiver<VcpuEvent>,
+    responses: &Sender<VcpuResponse>,
+) -> bool {
+    let mut paused = false;
+    loop {
+        let event = if paused {
+            match events.recv() {
+                Ok(event) => event,
+                Err(_) => return false,
+            }
+        } else {
+            match events.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Empty) => return true,
+                Err(TryRecvError::Disconnected) => return false,
+            }
+        };
+
+        let response = match event {
+            VcpuEvent::Pause => {
+                paused = true;
+                VcpuResponse::Paused
+            }
+            VcpuEvent::Resume => {
+                paused = false;
+                VcpuResponse::Resumed
+            }
+            VcpuEvent::SaveState => match vcpu.save_state() {
+                Ok(vcpu_state) => VcpuResponse::SavedState(Box::new(vcpu_state)),
+                Err(e) => VcpuResponse::Error(e),
+            },
+        };
+        if responses.send(response).is_err() {
+            return false;
+        }
+    }
+}
+
 // Can't derive PartialEq directly because the sender members can't be compared.
 // This implementation is only used in tests, but cannot be moved to mod tests,
 // because it is used in tests outside of the vmm crate (api_server).
@@ -1732,6 +3313,14 @@ impl PartialEq for VmmAction {
                 &VmmAction::UpdateBlockDevicePath(ref drive_id, ref path_on_host, _),
                 &VmmAction::UpdateBlockDevicePath(ref other_drive_id, ref other_path_on_host, _),
             ) => drive_id == other_drive_id && path_on_host == other_path_on_host,
+            (
+                &VmmAction::UpdateBlockDeviceRateLimiter(ref drive_id, ref update, _),
+                &VmmAction::UpdateBlockDeviceRateLimiter(ref other_drive_id, ref other_update, _),
+            ) => drive_id == other_drive_id && update == other_update,
+            (
+                &VmmAction::UpdateNetworkInterface(ref update_config, _),
+                &VmmAction::UpdateNetworkInterface(ref other_update_config, _),
+            ) => update_config == other_update_config,
             (
                 &VmmAction::ConfigureBootSource(ref boot_source, _),
                 &VmmAction::ConfigureBootSource(ref other_boot_source, _),
@@ -1752,10 +3341,31 @@ impl PartialEq for VmmAction {
                 &VmmAction::InsertNetworkDevice(ref net_dev, _),
                 &VmmAction::InsertNetworkDevice(ref other_net_dev, _),
             ) => net_dev == other_net_dev,
+            (
+                &VmmAction::InsertRateLimitGroup(ref group_cfg, _),
+                &VmmAction::InsertRateLimitGroup(ref other_group_cfg, _),
+            ) => group_cfg == other_group_cfg,
             (
                 &VmmAction::RescanBlockDevice(ref req, _),
                 &VmmAction::RescanBlockDevice(ref other_req, _),
             ) => req == other_req,
+            (
+                &VmmAction::RemoveBlockDevice(ref drive_id, _),
+                &VmmAction::RemoveBlockDevice(ref other_drive_id, _),
+            ) => drive_id == other_drive_id,
+            (
+                &VmmAction::CreateSnapshot(ref snapshot_config, _),
+                &VmmAction::CreateSnapshot(ref other_snapshot_config, _),
+            ) => snapshot_config == other_snapshot_config,
+            (
+                &VmmAction::LoadSnapshot(ref snapshot_config, _),
+                &VmmAction::LoadSnapshot(ref other_snapshot_config, _),
+            ) => snapshot_config == other_snapshot_config,
+            (
+                &VmmAction::UpdateVmState(ref vm_state_config, _),
+                &VmmAction::UpdateVmState(ref other_vm_state_config, _),
+            ) => vm_state_config == other_vm_state_config,
+            (&VmmAction::SendCtrlAltDel(_), &VmmAction::SendCtrlAltDel(_)) => true,
             (&VmmAction::StartMicroVm(_), &VmmAction::StartMicroVm(_)) => true,
             _ => false,
         }
@@ -1780,6 +3390,7 @@ pub fn start_vmm_thread(
     from_api: Receiver<Box<VmmAction>>,
     seccomp_level: u32,
     kvm_fd: Option<RawFd>,
+    config_file: Option<ConfigFile>,
 ) -> thread::JoinHandle<()> {
     thread::Builder::new()
         .name("fc_vmm".to_string())
@@ -1793,6 +3404,12 @@ pub fn start_vmm_thread(
                 kvm_fd,
             )
             .expect("Cannot create VMM.");
+            if let Some(config_file) = config_file {
+                if let Err(e) = vmm.configure_from_file(config_file) {
+                    error!("Cannot boot the microVM from the configuration file: {}", e);
+                    vmm.stop(1);
+                }
+            }
             match vmm.run_control() {
                 Ok(()) => {
                     info!("Gracefully terminated VMM control loop");
@@ -1814,12 +3431,16 @@ mod tests {
     use super::*;
 
     use std::fs::File;
+    use std::path::Path;
     use std::sync::atomic::AtomicUsize;
 
     use self::tempfile::NamedTempFile;
-    use devices::virtio::ActivateResult;
+    use devices::legacy::I8042State;
+    use devices::virtio::{ActivateResult, IoEngine};
     use net_util::MacAddr;
+    use rate_limiter::RateLimiterUpdate;
     use vmm_config::machine_config::CpuFeaturesTemplate;
+    use vstate::VmState;
 
     impl Vmm {
         fn get_kernel_cmdline_str(&self) -> &str {
@@ -1848,6 +3469,7 @@ mod tests {
             let kernel_cfg = KernelConfig {
                 cmdline,
                 kernel_file,
+                initrd_file: None,
                 cmdline_addr: GuestAddress(x86_64::layout::CMDLINE_START),
             };
             self.configure_kernel(kernel_cfg);
@@ -1893,6 +3515,22 @@ mod tests {
             self.flags = Some(event_flags);
             self.payload = Some(payload);
         }
+
+        fn update_rate_limiters(&mut self, payload: EpollHandlerPayload) -> std::io::Result<()> {
+            self.payload = Some(payload);
+            Ok(())
+        }
+    }
+
+    // The handler of a device whose rate limiters cannot be updated.
+    struct FailingEpollHandler;
+
+    impl EpollHandler for FailingEpollHandler {
+        fn handle_event(&mut self, _: DeviceEventT, _: u32, _: EpollHandlerPayload) {}
+
+        fn update_rate_limiters(&mut self, _: EpollHandlerPayload) -> std::io::Result<()> {
+            Err(std::io::Error::new(std::io::ErrorKind::Other, "timerfd"))
+        }
     }
 
     #[allow(dead_code)]
@@ -1943,6 +3581,7 @@ mod tests {
         let shared_info = Arc::new(RwLock::new(InstanceInfo {
             state,
             id: "TEST_ID".to_string(),
+            pending_drive_ejects: vec![],
         }));
 
         let (_to_vmm, from_api) = channel();
@@ -1962,7 +3601,7 @@ mod tests {
         let mut ep = EpollContext::new().unwrap();
         let (base, sender) = ep.allocate_tokens(1);
         assert_eq!(ep.device_handlers.len(), 1);
-        assert_eq!(base, 1);
+        assert_eq!(base, 2);
 
         let handler = DummyEpollHandler {
             evt: None,
@@ -1973,6 +3612,16 @@ mod tests {
         assert!(ep.get_device_handler(0).is_ok());
     }
 
+    #[test]
+    fn test_device_events() {
+        let mut ep = EpollContext::new().unwrap();
+        // The device events are polled by default.
+        assert!(ep.enable_device_events().is_err());
+        assert!(ep.disable_device_events().is_ok());
+        assert!(ep.disable_device_events().is_err());
+        assert!(ep.enable_device_events().is_ok());
+    }
+
     #[test]
     fn test_insert_block_device() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -1984,7 +3633,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
         assert!(vmm
@@ -1999,7 +3655,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: true,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
         assert!(vmm
@@ -2014,7 +3677,14 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: true,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         assert!(vmm.insert_block_device(root_block_device.clone()).is_err());
 
@@ -2027,7 +3697,14 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         assert!(vmm.insert_block_device(non_root).is_ok());
 
@@ -2039,7 +3716,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         assert!(vmm.insert_block_device(non_root).is_err());
 
@@ -2051,7 +3735,14 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: true,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         assert!(vmm.insert_block_device(root_block_device).is_err())
     }
@@ -2067,8 +3758,16 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
             allow_mmds_requests: false,
-            tap: None,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
         };
         assert!(vmm.insert_net_device(network_interface).is_ok());
 
@@ -2080,8 +3779,16 @@ mod tests {
             guest_mac: Some(mac.clone()),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
             allow_mmds_requests: false,
-            tap: None,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
         };
         assert!(vmm.insert_net_device(network_interface).is_ok());
 
@@ -2092,8 +3799,16 @@ mod tests {
             guest_mac: Some(mac),
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
             allow_mmds_requests: false,
-            tap: None,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
         };
         assert!(vmm.insert_net_device(network_interface).is_err());
 
@@ -2105,12 +3820,314 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
             allow_mmds_requests: false,
-            tap: None,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
         };
         assert!(vmm.insert_net_device(network_interface).is_err());
     }
 
+    #[test]
+    fn test_update_rate_limiters() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let f = NamedTempFile::new().unwrap();
+        let block_device = BlockDeviceConfig {
+            drive_id: String::from("scratch"),
+            path_on_host: f.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            rate_limiter: None,
+            rate_limit_group: None,
+        };
+        assert!(vmm.insert_block_device(block_device).is_ok());
+        let network_interface = NetworkInterfaceConfig {
+            iface_id: String::from("netif"),
+            host_dev_name: String::from("hostname4"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
+            allow_mmds_requests: false,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
+        };
+        assert!(vmm.insert_net_device(network_interface).is_ok());
+
+        let update: RateLimiterUpdate =
+            serde_json::from_str(r#"{"ops": {"size": 10, "refill_time": 1000}}"#).unwrap();
+        let drive_update: BlockRateLimiterUpdate = serde_json::from_str(
+            r#"{
+                "ops": {"size": 10, "refill_time": 1000},
+                "read": {"ops": {"size": 5, "refill_time": 1000}}
+            }"#,
+        )
+        .unwrap();
+        let net_update = NetworkInterfaceUpdateConfig {
+            iface_id: String::from("netif"),
+            rx_rate_limiter: None,
+            tx_rate_limiter: Some(update.clone()),
+            capture: None,
+            firewall: None,
+        };
+        let mut invalid_net_update = net_update.clone();
+        invalid_net_update.iface_id = String::from("foo");
+
+        // Before boot, the configurations are updated.
+        assert!(vmm
+            .update_block_device_rate_limiter(String::from("scratch"), drive_update.clone())
+            .is_ok());
+        {
+            let rate_limiter = vmm.block_device_configs.config_list[0]
+                .rate_limiter
+                .as_mut()
+                .unwrap();
+            assert!(rate_limiter
+                .shared
+                .consume(10, rate_limiter::TokenType::Ops));
+            assert!(!rate_limiter.shared.consume(1, rate_limiter::TokenType::Ops));
+            let read_limiter = rate_limiter.read.as_mut().unwrap();
+            assert!(read_limiter.consume(5, rate_limiter::TokenType::Ops));
+            assert!(!read_limiter.consume(1, rate_limiter::TokenType::Ops));
+        }
+        match vmm.update_block_device_rate_limiter(String::from("foo"), drive_update.clone()) {
+            Err(VmmActionError::DriveConfig(ErrorKind::User, DriveError::InvalidBlockDeviceID)) => {
+                ()
+            }
+            _ => assert!(false),
+        }
+        assert!(vmm.update_net_device(net_update.clone()).is_ok());
+        match vmm.update_net_device(invalid_net_update.clone()) {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::InvalidIfaceId,
+            )) => (),
+            _ => assert!(false),
+        }
+
+        // After boot, the devices need a handler to be updated.
+        vmm.set_instance_state(InstanceState::Running);
+        match vmm.update_block_device_rate_limiter(String::from("scratch"), drive_update.clone()) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::BlockDeviceUpdateFailed,
+            )) => (),
+            _ => assert!(false),
+        }
+        match vmm.update_net_device(net_update.clone()) {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::DeviceUpdateFailed,
+            )) => (),
+            _ => assert!(false),
+        }
+        match vmm.update_net_device(invalid_net_update) {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::InvalidIfaceId,
+            )) => (),
+            _ => assert!(false),
+        }
+
+        for (drive, id) in vec![(true, "scratch"), (false, "netif")] {
+            let device_idx = vmm.epoll_context.device_handlers.len();
+            let (_, sender) = vmm.epoll_context.allocate_tokens(1);
+            let handler = DummyEpollHandler {
+                evt: None,
+                flags: None,
+                payload: None,
+            };
+            assert!(sender.send(Box::new(handler)).is_ok());
+            if drive {
+                vmm.drive_handler_id_map.insert(id.to_string(), device_idx);
+            } else {
+                vmm.net_handler_id_map.insert(id.to_string(), device_idx);
+            }
+        }
+        assert!(vmm
+            .update_block_device_rate_limiter(String::from("scratch"), drive_update.clone())
+            .is_ok());
+        assert!(vmm.update_net_device(net_update.clone()).is_ok());
+
+        // A capture file that cannot be created fails the update.
+        let mut capture_update = net_update;
+        capture_update.capture = Some(Some(devices::virtio::CaptureConfig {
+            path: PathBuf::from("/nonexistent/netif.pcap"),
+            snap_len: None,
+            max_file_size: None,
+        }));
+        match vmm.update_net_device(capture_update.clone()) {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::OpenCapture(_),
+            )) => (),
+            _ => assert!(false),
+        }
+        capture_update.capture = Some(None);
+        assert!(vmm.update_net_device(capture_update.clone()).is_ok());
+
+        // Inconsistent firewall rules fail the update, and the others replace the previous ones.
+        let mut firewall_update = capture_update;
+        firewall_update.firewall = Some(
+            serde_json::from_str(
+                r#"{"tx_rules": [{"action": "Deny", "ports": {"start": 22, "end": 22}}]}"#,
+            )
+            .unwrap(),
+        );
+        match vmm.update_net_device(firewall_update.clone()) {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::InvalidFirewallRule(_),
+            )) => (),
+            _ => assert!(false),
+        }
+        firewall_update.firewall = Some(devices::virtio::FirewallConfig::default());
+        assert!(vmm.update_net_device(firewall_update.clone()).is_ok());
+
+        // The failures of the devices to update their rate limiters are reported.
+        for (drive, id) in vec![(true, "scratch"), (false, "netif")] {
+            let device_idx = vmm.epoll_context.device_handlers.len();
+            let (_, sender) = vmm.epoll_context.allocate_tokens(1);
+            assert!(sender.send(Box::new(FailingEpollHandler)).is_ok());
+            if drive {
+                vmm.drive_handler_id_map.insert(id.to_string(), device_idx);
+            } else {
+                vmm.net_handler_id_map.insert(id.to_string(), device_idx);
+            }
+        }
+        match vmm.update_block_device_rate_limiter(String::from("scratch"), drive_update) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::Internal,
+                DriveError::BlockDeviceUpdateFailed,
+            )) => (),
+            _ => assert!(false),
+        }
+        match vmm.update_net_device(firewall_update) {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::Internal,
+                NetworkInterfaceError::DeviceUpdateFailed,
+            )) => (),
+            _ => assert!(false),
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_groups() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let f = NamedTempFile::new().unwrap();
+        let block_device = BlockDeviceConfig {
+            drive_id: String::from("scratch"),
+            path_on_host: f.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            rate_limiter: None,
+            rate_limit_group: Some(String::from("tenant")),
+        };
+        let network_interface = NetworkInterfaceConfig {
+            iface_id: String::from("netif"),
+            host_dev_name: String::from("hostname5"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: Some(String::from("tenant")),
+            allow_mmds_requests: false,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
+        };
+
+        // The devices can only reference existing groups.
+        match vmm.insert_block_device(block_device.clone()) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::InvalidRateLimitGroup,
+            )) => (),
+            _ => assert!(false),
+        }
+        match vmm.insert_net_device(network_interface.clone()) {
+            Err(VmmActionError::NetworkConfig(
+                ErrorKind::User,
+                NetworkInterfaceError::InvalidRateLimitGroup,
+            )) => (),
+            _ => assert!(false),
+        }
+
+        let group_config: RateLimitGroupConfig = serde_json::from_str(
+            r#"{"group_id": "tenant", "ops": {"size": 10, "refill_time": 1000}}"#,
+        )
+        .unwrap();
+        assert!(vmm.insert_rate_limit_group(group_config.clone()).is_ok());
+        assert!(vmm.insert_block_device(block_device).is_ok());
+        assert!(vmm.insert_net_device(network_interface).is_ok());
+
+        // The groups are built at boot, and their timer is polled with the device events.
+        let device_idx = vmm.epoll_context.device_handlers.len();
+        assert!(vmm.create_rate_limit_groups().is_ok());
+        assert_eq!(vmm.epoll_context.device_handlers.len(), device_idx + 1);
+        assert!(vmm.epoll_context.get_device_handler(device_idx).is_ok());
+
+        // The members of a group share its budget.
+        let mut drive_limiter =
+            with_rate_limit_group(None, Some(&String::from("tenant")), &vmm.rate_limit_groups)
+                .unwrap();
+        let mut net_limiter = with_rate_limit_group(
+            Some(RateLimiter::default()),
+            Some(&String::from("tenant")),
+            &vmm.rate_limit_groups,
+        )
+        .unwrap();
+        assert!(drive_limiter.consume(6, rate_limiter::TokenType::Ops));
+        assert!(net_limiter.consume(4, rate_limiter::TokenType::Ops));
+        assert!(!drive_limiter.consume(1, rate_limiter::TokenType::Ops));
+        assert!(net_limiter.is_blocked());
+        assert!(vmm.rate_limit_groups["tenant"].is_blocked());
+        assert!(
+            with_rate_limit_group(None, Some(&String::from("foo")), &vmm.rate_limit_groups)
+                .is_none()
+        );
+
+        // The groups cannot be changed after boot.
+        vmm.set_instance_state(InstanceState::Running);
+        match vmm.insert_rate_limit_group(group_config) {
+            Err(VmmActionError::RateLimitGroup(
+                ErrorKind::User,
+                RateLimitGroupError::UpdateNotAllowedPostBoot,
+            )) => (),
+            _ => assert!(false),
+        }
+    }
+
     #[test]
     fn test_machine_configuration() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2132,6 +4149,7 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            acpi_only: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2144,6 +4162,7 @@ mod tests {
             mem_size_mib: Some(256),
             ht_enabled: None,
             cpu_template: None,
+            acpi_only: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2157,6 +4176,7 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: None,
             cpu_template: None,
+            acpi_only: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2168,6 +4188,7 @@ mod tests {
             mem_size_mib: Some(0),
             ht_enabled: Some(false),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            acpi_only: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.vcpu_count, Some(3));
@@ -2183,6 +4204,7 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: None,
+            acpi_only: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
         assert_eq!(vmm.vm_config.ht_enabled, Some(false));
@@ -2193,11 +4215,23 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            acpi_only: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_ok());
         assert_eq!(vmm.vm_config.vcpu_count, Some(2));
         assert_eq!(vmm.vm_config.ht_enabled, Some(true));
         assert_eq!(vmm.vm_config.cpu_template, Some(CpuFeaturesTemplate::T2));
+        // The legacy MP table and command line devices are only dropped when asked for.
+        assert_eq!(vmm.vm_config.acpi_only, Some(false));
+        let machine_config = VmConfig {
+            vcpu_count: None,
+            mem_size_mib: None,
+            ht_enabled: None,
+            cpu_template: None,
+            acpi_only: Some(true),
+        };
+        assert!(vmm.set_vm_configuration(machine_config).is_ok());
+        assert_eq!(vmm.vm_config.acpi_only, Some(true));
 
         // 3. Test update vm configuration after boot.
         vmm.set_instance_state(InstanceState::Running);
@@ -2206,6 +4240,7 @@ mod tests {
             mem_size_mib: None,
             ht_enabled: Some(true),
             cpu_template: Some(CpuFeaturesTemplate::T2),
+            acpi_only: None,
         };
         assert!(vmm.set_vm_configuration(machine_config).is_err());
     }
@@ -2370,6 +4405,7 @@ mod tests {
             cmdline_addr: dummy_addr,
             cmdline: kernel_cmdline::Cmdline::new(10),
             kernel_file: tempfile::tempfile().unwrap(),
+            initrd_file: None,
         });
         assert!(vmm.check_health().is_ok());
     }
@@ -2392,6 +4428,46 @@ mod tests {
         assert_eq!(vmm.is_instance_initialized(), true);
     }
 
+    // Returns the config of a data drive backed by the image at `path`.
+    fn data_block_device(path: &Path) -> BlockDeviceConfig {
+        BlockDeviceConfig {
+            drive_id: String::from("data"),
+            path_on_host: path.to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            rate_limiter: None,
+            rate_limit_group: None,
+        }
+    }
+
+    // Attaches the block devices of a new microVM which only holds `block_device`.
+    fn attach_block_device(
+        block_device: BlockDeviceConfig,
+    ) -> (
+        Vmm,
+        MMIODeviceManager,
+        std::result::Result<(), StartMicrovmError>,
+    ) {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.insert_block_device(block_device).is_ok());
+        assert!(vmm.init_guest_memory().is_ok());
+        vmm.default_kernel_config();
+
+        let guest_mem = vmm.guest_memory.clone().unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem, x86_64::get_32bit_gap_start() as u64);
+        let mut cmdline = kernel_cmdline::Cmdline::new(x86_64::layout::CMDLINE_MAX_SIZE);
+        let result = vmm.attach_block_devices(&mut device_manager, &mut cmdline);
+        (vmm, device_manager, result)
+    }
+
     #[test]
     fn test_attach_block_devices() {
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2404,7 +4480,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         // Test that creating a new block device returns the correct output.
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
@@ -2416,8 +4499,11 @@ mod tests {
         let guest_mem = vmm.guest_memory.clone().unwrap();
         let mut device_manager =
             MMIODeviceManager::new(guest_mem.clone(), x86_64::get_32bit_gap_start() as u64);
-        assert!(vmm.attach_block_devices(&mut device_manager).is_ok());
-        assert!(vmm.get_kernel_cmdline_str().contains("root=/dev/vda"));
+        let mut cmdline = kernel_cmdline::Cmdline::new(x86_64::layout::CMDLINE_MAX_SIZE);
+        assert!(vmm
+            .attach_block_devices(&mut device_manager, &mut cmdline)
+            .is_ok());
+        assert!(cmdline.as_str().contains("root=/dev/vda"));
 
         // Use Case 2: Root Block Device is specified through PARTUUID.
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2427,7 +4513,14 @@ mod tests {
             is_root_device: true,
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         // Test that creating a new block device returns the correct output.
@@ -2440,10 +4533,11 @@ mod tests {
         let guest_mem = vmm.guest_memory.clone().unwrap();
         let mut device_manager =
             MMIODeviceManager::new(guest_mem.clone(), x86_64::get_32bit_gap_start() as u64);
-        assert!(vmm.attach_block_devices(&mut device_manager).is_ok());
+        let mut cmdline = kernel_cmdline::Cmdline::new(x86_64::layout::CMDLINE_MAX_SIZE);
         assert!(vmm
-            .get_kernel_cmdline_str()
-            .contains("root=PARTUUID=0eaa91a0-01"));
+            .attach_block_devices(&mut device_manager, &mut cmdline)
+            .is_ok());
+        assert!(cmdline.as_str().contains("root=PARTUUID=0eaa91a0-01"));
 
         // Use Case 3: Root Block Device is not added at all.
         let mut vmm = create_vmm_object(InstanceState::Uninitialized);
@@ -2453,7 +4547,14 @@ mod tests {
             is_root_device: false,
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         // Test that creating a new block device returns the correct output.
@@ -2468,10 +4569,13 @@ mod tests {
         let guest_mem = vmm.guest_memory.clone().unwrap();
         let mut device_manager =
             MMIODeviceManager::new(guest_mem.clone(), x86_64::get_32bit_gap_start() as u64);
-        assert!(vmm.attach_block_devices(&mut device_manager).is_ok());
+        let mut cmdline = kernel_cmdline::Cmdline::new(x86_64::layout::CMDLINE_MAX_SIZE);
+        assert!(vmm
+            .attach_block_devices(&mut device_manager, &mut cmdline)
+            .is_ok());
         // Test that kernel commandline does not contain either /dev/vda or PARTUUID.
-        assert!(!vmm.get_kernel_cmdline_str().contains("root=PARTUUID="));
-        assert!(!vmm.get_kernel_cmdline_str().contains("root=/dev/vda"));
+        assert!(!cmdline.as_str().contains("root=PARTUUID="));
+        assert!(!cmdline.as_str().contains("root=/dev/vda"));
 
         // Test that the non root device is attached.
         assert!(device_manager
@@ -2485,10 +4589,78 @@ mod tests {
             .set_block_device_path("not_root".to_string(), path)
             .is_ok());
 
-        // Test partial update of block device fails due to invalid file.
-        assert!(vmm
-            .set_block_device_path("not_root".to_string(), String::from("dummy_path"))
-            .is_err());
+        // Test partial update of block device fails due to invalid file.
+        assert!(vmm
+            .set_block_device_path("not_root".to_string(), String::from("dummy_path"))
+            .is_err());
+    }
+
+    #[test]
+    fn test_attach_block_devices_overlay() {
+        let base_file = NamedTempFile::new().unwrap();
+        base_file.as_file().set_len(0x10000).unwrap();
+        let overlay_file = NamedTempFile::new().unwrap();
+
+        // The overlay gets formatted on attach.
+        let (mut vmm, device_manager, result) = attach_block_device(BlockDeviceConfig {
+            overlay_path: Some(overlay_file.path().to_path_buf()),
+            ..data_block_device(base_file.path())
+        });
+        assert!(result.is_ok());
+        assert!(device_manager.get_address(&String::from("data")).is_some());
+        assert!(overlay_file.as_file().metadata().unwrap().len() > 0x10000);
+
+        // The base image of a drive with an overlay cannot be swapped after boot.
+        vmm.set_instance_state(InstanceState::Running);
+        let path = String::from(base_file.path().to_str().unwrap());
+        match vmm.set_block_device_path("data".to_string(), path) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::UpdateNotAllowedWithOverlay,
+            )) => (),
+            _ => panic!("Expected UpdateNotAllowedWithOverlay"),
+        }
+    }
+
+    #[test]
+    fn test_attach_block_devices_qcow2() {
+        // The image of a qcow2 block device is validated on attach.
+        let block_file = NamedTempFile::new().unwrap();
+        block_file.as_file().set_len(0x10000).unwrap();
+        let (_, _, result) = attach_block_device(BlockDeviceConfig {
+            format: Some(DriveFormat::Qcow2),
+            ..data_block_device(block_file.path())
+        });
+        match result {
+            Err(StartMicrovmError::OpenBlockDevice(_)) => (),
+            _ => panic!("Expected OpenBlockDevice"),
+        }
+    }
+
+    #[test]
+    fn test_attach_block_devices_async() {
+        // A block device can execute its requests through io_uring.
+        let block_file = NamedTempFile::new().unwrap();
+        let (_, device_manager, result) = attach_block_device(BlockDeviceConfig {
+            io_engine: Some(IoEngine::Async),
+            ..data_block_device(block_file.path())
+        });
+        assert!(result.is_ok());
+        assert!(device_manager.get_address(&String::from("data")).is_some());
+    }
+
+    #[test]
+    fn test_attach_block_devices_multi_queue() {
+        // A block device with several queues has one handler per queue.
+        let block_file = NamedTempFile::new().unwrap();
+        let (vmm, _, result) = attach_block_device(BlockDeviceConfig {
+            num_queues: Some(4),
+            queue_size: Some(128),
+            ..data_block_device(block_file.path())
+        });
+        assert!(result.is_ok());
+        let first_handler = *vmm.drive_handler_id_map.get("data").unwrap();
+        assert_eq!(vmm.epoll_context.device_handlers.len(), first_handler + 4);
     }
 
     #[test]
@@ -2510,16 +4682,56 @@ mod tests {
             guest_mac: None,
             rx_rate_limiter: None,
             tx_rate_limiter: None,
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
             allow_mmds_requests: false,
-            tap: None,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
         };
 
         assert!(vmm.insert_net_device(network_interface).is_ok());
 
-        assert!(vmm.attach_net_devices(&mut device_manager).is_ok());
+        assert!(vmm
+            .attach_net_devices(&mut device_manager, &guest_mem)
+            .is_ok());
+        assert!(!vmm.has_vhost_net_interface());
         // a second call to attach_net_devices should fail because when
         // we are creating the virtio::Net object, we are taking the tap.
-        assert!(vmm.attach_net_devices(&mut device_manager).is_err());
+        assert!(vmm
+            .attach_net_devices(&mut device_manager, &guest_mem)
+            .is_err());
+
+        // An interface answering MMDS requests falls back to the userspace backend.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let network_interface = NetworkInterfaceConfig {
+            iface_id: String::from("netif"),
+            host_dev_name: String::from("hostname4"),
+            guest_mac: None,
+            rx_rate_limiter: None,
+            tx_rate_limiter: None,
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
+            allow_mmds_requests: true,
+            num_queue_pairs: None,
+            backend: Some(NetBackend::VhostNet),
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
+        };
+        assert!(vmm.insert_net_device(network_interface).is_ok());
+        assert!(vmm
+            .attach_net_devices(&mut device_manager, &guest_mem)
+            .is_ok());
+        assert!(vmm.net_handler_id_map.contains_key("netif"));
+        // Such an interface doesn't prevent snapshots.
+        assert!(!vmm.has_vhost_net_interface());
     }
 
     #[test]
@@ -2529,6 +4741,69 @@ mod tests {
         assert!(vmm.init_guest_memory().is_ok());
 
         assert!(vmm.init_devices().is_ok());
+
+        // By default the devices end up on the kernel command line and the vCPUs in the legacy MP
+        // table, next to the ACPI tables.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let block_file = NamedTempFile::new().unwrap();
+        let root_block_device = BlockDeviceConfig {
+            drive_id: String::from("root"),
+            path_on_host: block_file.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            rate_limiter: None,
+            rate_limit_group: None,
+        };
+        assert!(vmm.insert_block_device(root_block_device).is_ok());
+        vmm.default_kernel_config();
+        assert!(vmm.init_guest_memory().is_ok());
+
+        assert!(vmm.init_devices().is_ok());
+        assert!(vmm.get_kernel_cmdline_str().contains("root=/dev/vda"));
+        assert!(vmm.get_kernel_cmdline_str().contains("virtio_mmio.device="));
+        let guest_memory = vmm.guest_memory.clone().unwrap();
+        assert!(vmm.vm.memory_init(guest_memory, &vmm.kvm).is_ok());
+        assert!(vmm.setup_system_tables(1).is_ok());
+        let mp_signature: u32 = vmm
+            .vm
+            .get_memory()
+            .unwrap()
+            .read_obj_from_addr(GuestAddress(x86_64::layout::MPTABLE_START))
+            .unwrap();
+        // "_MP_"
+        assert_eq!(mp_signature, 0x5f50_4d5f);
+
+        // A guest which relies on ACPI alone finds the devices through the DSDT only.
+        vmm.vm_config.acpi_only = Some(true);
+        vmm.kernel_config.as_mut().unwrap().cmdline =
+            kernel_cmdline::Cmdline::new(x86_64::layout::CMDLINE_MAX_SIZE);
+        assert!(vmm.init_devices().is_ok());
+        assert!(vmm.get_kernel_cmdline_str().contains("root=/dev/vda"));
+        assert!(!vmm.get_kernel_cmdline_str().contains("virtio_mmio.device="));
+
+        // Unless it was told to ignore the ACPI tables.
+        vmm.kernel_config
+            .as_mut()
+            .unwrap()
+            .cmdline
+            .insert_str("acpi=off")
+            .unwrap();
+        assert!(vmm.init_devices().is_ok());
+        assert!(vmm.get_kernel_cmdline_str().contains("virtio_mmio.device="));
+
+        // Without a kernel configuration, e.g. when restoring from a snapshot, the devices are
+        // still attached.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        assert!(vmm.init_guest_memory().is_ok());
+        assert!(vmm.init_devices().is_ok());
+        assert!(vmm.mmio_device_manager.is_some());
     }
 
     #[test]
@@ -2537,7 +4812,11 @@ mod tests {
 
         // Test invalid kernel path.
         assert!(vmm
-            .configure_boot_source(String::from("dummy-path"), None)
+            .configure_boot_source(BootSourceConfig {
+                kernel_image_path: String::from("dummy-path"),
+                initrd_path: None,
+                boot_args: None,
+            })
             .is_err());
 
         // Test valid kernel path and invalid cmdline.
@@ -2546,19 +4825,51 @@ mod tests {
         let invalid_cmdline =
             String::from_utf8(vec![b'X'; x86_64::layout::CMDLINE_MAX_SIZE + 1]).unwrap();
         assert!(vmm
-            .configure_boot_source(kernel_path.clone(), Some(invalid_cmdline))
+            .configure_boot_source(BootSourceConfig {
+                kernel_image_path: kernel_path.clone(),
+                initrd_path: None,
+                boot_args: Some(invalid_cmdline),
+            })
             .is_err());
 
+        // Test valid kernel path and invalid initrd path.
+        match vmm.configure_boot_source(BootSourceConfig {
+            kernel_image_path: kernel_path.clone(),
+            initrd_path: Some(String::from("dummy-path")),
+            boot_args: None,
+        }) {
+            Err(VmmActionError::BootSource(
+                ErrorKind::User,
+                BootSourceConfigError::InvalidInitrdPath,
+            )) => (),
+            _ => panic!("Unexpected result"),
+        }
+
         // Test valid configuration.
-        assert!(vmm.configure_boot_source(kernel_path.clone(), None).is_ok());
         assert!(vmm
-            .configure_boot_source(kernel_path.clone(), Some(String::from("reboot=k")))
+            .configure_boot_source(BootSourceConfig {
+                kernel_image_path: kernel_path.clone(),
+                initrd_path: None,
+                boot_args: None,
+            })
+            .is_ok());
+        assert!(vmm
+            .configure_boot_source(BootSourceConfig {
+                kernel_image_path: kernel_path.clone(),
+                initrd_path: Some(kernel_path.clone()),
+                boot_args: Some(String::from("reboot=k")),
+            })
             .is_ok());
+        assert!(vmm.kernel_config.as_ref().unwrap().initrd_file.is_some());
 
         // Test valid configuration after boot (should fail).
         vmm.set_instance_state(InstanceState::Running);
         assert!(vmm
-            .configure_boot_source(kernel_path.clone(), None)
+            .configure_boot_source(BootSourceConfig {
+                kernel_image_path: kernel_path.clone(),
+                initrd_path: None,
+                boot_args: None,
+            })
             .is_err());
     }
 
@@ -2577,7 +4888,14 @@ mod tests {
             is_root_device: true,
             partuuid: None,
             is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         let non_root_block_device = BlockDeviceConfig {
             drive_id: scratch_id.clone(),
@@ -2585,7 +4903,14 @@ mod tests {
             is_root_device: false,
             partuuid: None,
             is_read_only: true,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
@@ -2601,13 +4926,8 @@ mod tests {
             MMIODeviceManager::new(guest_mem.clone(), x86_64::get_32bit_gap_start() as u64);
 
         let dummy_box = Box::new(DummyDevice { dummy: 0 });
-        // Use a dummy command line as it is not used in this test.
         let _addr = device_manager
-            .register_device(
-                dummy_box,
-                &mut kernel_cmdline::Cmdline::new(x86_64::layout::CMDLINE_MAX_SIZE),
-                Some(scratch_id.clone()),
-            )
+            .register_device(dummy_box, Some(scratch_id.clone()))
             .unwrap();
 
         vmm.mmio_device_manager = Some(device_manager);
@@ -2672,6 +4992,240 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_hotplug_block_device() {
+        use devices::BusDevice;
+
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        vmm.default_kernel_config();
+
+        let root_file = NamedTempFile::new().unwrap();
+        let scratch_file = NamedTempFile::new().unwrap();
+        let scratch_id = "scratch".to_string();
+
+        let root_block_device = BlockDeviceConfig {
+            drive_id: String::from("root"),
+            path_on_host: root_file.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: None,
+            is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            rate_limiter: None,
+            rate_limit_group: None,
+        };
+        let scratch_block_device = BlockDeviceConfig {
+            drive_id: scratch_id.clone(),
+            path_on_host: scratch_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: Some(2),
+            queue_size: None,
+            rate_limiter: None,
+            rate_limit_group: None,
+        };
+
+        // Before boot, removing a drive only drops its config.
+        assert!(vmm.insert_block_device(root_block_device.clone()).is_ok());
+        assert!(vmm
+            .insert_block_device(scratch_block_device.clone())
+            .is_ok());
+        assert!(vmm.remove_block_device(scratch_id.clone()).is_ok());
+        assert_eq!(vmm.block_device_configs.config_list.len(), 1);
+        match vmm.remove_block_device(scratch_id.clone()) {
+            Err(VmmActionError::DriveConfig(ErrorKind::User, DriveError::InvalidBlockDeviceID)) => {
+                ()
+            }
+            _ => assert!(false),
+        }
+
+        assert!(vmm.init_guest_memory().is_ok());
+        let guest_mem = vmm.guest_memory.clone().unwrap();
+        let mut device_manager =
+            MMIODeviceManager::new(guest_mem.clone(), x86_64::get_32bit_gap_start() as u64);
+        let mut cmdline = kernel_cmdline::Cmdline::new(x86_64::layout::CMDLINE_MAX_SIZE);
+        assert!(vmm
+            .attach_block_devices(&mut device_manager, &mut cmdline)
+            .is_ok());
+        vmm.mmio_device_manager = Some(device_manager);
+        vmm.vm
+            .setup_irqchip(
+                &vmm.legacy_device_manager.com_evt_1_3,
+                &vmm.legacy_device_manager.com_evt_2_4,
+                &vmm.legacy_device_manager.kbd_evt,
+                &vmm.legacy_device_manager.acpi_hotplug_evt,
+            )
+            .unwrap();
+        vmm.set_instance_state(InstanceState::Running);
+        // The interrupt is consumed by KVM, so look at the slots the guest is told about.
+        let changed_slots = |vmm: &Vmm| {
+            let mut data = [0u8; 4];
+            vmm.legacy_device_manager
+                .acpi_hotplug
+                .lock()
+                .unwrap()
+                .read(4, &mut data);
+            data
+        };
+
+        // Hot-plug a drive.
+        assert!(vmm
+            .insert_block_device(scratch_block_device.clone())
+            .is_ok());
+        assert_eq!(changed_slots(&vmm), [0b10, 0, 0, 0]);
+        assert_eq!(
+            vmm.mmio_device_manager.as_ref().unwrap().occupied_slots(),
+            vec![0, 1]
+        );
+        let device_idx = vmm.drive_handler_id_map[&scratch_id];
+
+        // A drive that is already plugged cannot be replaced.
+        match vmm.insert_block_device(scratch_block_device.clone()) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::UpdateNotAllowedPostBoot,
+            )) => (),
+            _ => assert!(false),
+        }
+
+        // A root drive cannot be hot-plugged.
+        let mut new_root_block_device = root_block_device.clone();
+        new_root_block_device.drive_id = String::from("new_root");
+        match vmm.insert_block_device(new_root_block_device) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::RootBlockDeviceHotplug,
+            )) => (),
+            _ => assert!(false),
+        }
+
+        // A drive that cannot be opened is not kept.
+        let mut bad_block_device = scratch_block_device.clone();
+        bad_block_device.drive_id = String::from("bad");
+        bad_block_device.path_on_host = std::env::temp_dir();
+        match vmm.insert_block_device(bad_block_device) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::CannotOpenBlockDevice,
+            )) => (),
+            _ => assert!(false),
+        }
+        assert_eq!(vmm.block_device_configs.config_list.len(), 2);
+        assert!(!vmm.drive_handler_id_map.contains_key("bad"));
+
+        // The root drive cannot be hot-unplugged.
+        match vmm.remove_block_device(String::from("root")) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::RootBlockDeviceHotplug,
+            )) => (),
+            _ => assert!(false),
+        }
+        match vmm.remove_block_device(String::from("foo")) {
+            Err(VmmActionError::DriveConfig(ErrorKind::User, DriveError::InvalidBlockDeviceID)) => {
+                ()
+            }
+            _ => assert!(false),
+        }
+
+        // Hot-unplug the drive. The guest is asked to eject it first.
+        match vmm.remove_block_device(scratch_id.clone()) {
+            Ok(VmmData::Accepted) => (),
+            _ => assert!(false),
+        }
+        assert_eq!(changed_slots(&vmm), [0b10, 0, 0, 0]);
+        assert_eq!(
+            vmm.shared_info.read().unwrap().pending_drive_ejects,
+            vec![scratch_id.clone()]
+        );
+        assert_eq!(
+            vmm.mmio_device_manager.as_ref().unwrap().occupied_slots(),
+            vec![0, 1]
+        );
+        assert!(vmm.drive_handler_id_map.contains_key(&scratch_id));
+        // The guest runs `_EJ0`, which writes the slot to the eject register.
+        vmm.legacy_device_manager
+            .acpi_hotplug
+            .lock()
+            .unwrap()
+            .write(8, &[0b10, 0, 0, 0]);
+        assert_eq!(vmm.legacy_device_manager.acpi_eject_evt.read(), Ok(1));
+        vmm.remove_ejected_devices();
+        assert_eq!(
+            vmm.mmio_device_manager.as_ref().unwrap().occupied_slots(),
+            vec![0]
+        );
+        assert_eq!(vmm.block_device_configs.config_list.len(), 1);
+        assert!(vmm
+            .shared_info
+            .read()
+            .unwrap()
+            .pending_drive_ejects
+            .is_empty());
+        assert!(!vmm.drive_handler_id_map.contains_key(&scratch_id));
+        assert!(!vmm.epoll_context.dispatch_table.iter().any(|d| match *d {
+            Some(EpollDispatch::DeviceHandler(idx, _)) => idx >= device_idx && idx < device_idx + 2,
+            _ => false,
+        }));
+
+        // The slot is free again.
+        assert!(vmm
+            .insert_block_device(scratch_block_device.clone())
+            .is_ok());
+        assert_eq!(
+            vmm.mmio_device_manager.as_ref().unwrap().occupied_slots(),
+            vec![0, 1]
+        );
+
+        // A drive announced on the kernel command line cannot be hot-unplugged.
+        vmm.mmio_device_manager
+            .as_mut()
+            .unwrap()
+            .add_cmdline_devices(&mut cmdline)
+            .unwrap();
+        match vmm.remove_block_device(scratch_id.clone()) {
+            Err(VmmActionError::DriveConfig(
+                ErrorKind::User,
+                DriveError::CmdlineBlockDeviceUnplug,
+            )) => (),
+            _ => assert!(false),
+        }
+        assert_eq!(vmm.block_device_configs.config_list.len(), 2);
+
+        // A guest that ignores ACPI cannot be told about hot-plug events.
+        let mut kernel_cmdline = kernel_cmdline::Cmdline::new(x86_64::layout::CMDLINE_MAX_SIZE);
+        kernel_cmdline.insert_str("acpi=off").unwrap();
+        vmm.configure_kernel(KernelConfig {
+            cmdline: kernel_cmdline,
+            kernel_file: tempfile::tempfile().unwrap(),
+            initrd_file: None,
+            cmdline_addr: GuestAddress(x86_64::layout::CMDLINE_START),
+        });
+        let mut other_block_device = scratch_block_device.clone();
+        other_block_device.drive_id = String::from("other");
+        match vmm.insert_block_device(other_block_device) {
+            Err(VmmActionError::DriveConfig(ErrorKind::User, DriveError::AcpiDisabledHotplug)) => {
+                ()
+            }
+            _ => assert!(false),
+        }
+        match vmm.remove_block_device(scratch_id.clone()) {
+            Err(VmmActionError::DriveConfig(ErrorKind::User, DriveError::AcpiDisabledHotplug)) => {
+                ()
+            }
+            _ => assert!(false),
+        }
+    }
+
     #[test]
     fn test_init_logger_from_api() {
         // Error case: update after instance is running
@@ -2735,4 +5289,206 @@ mod tests {
         assert_eq!(vmm.get_dirty_page_count(), 0);
         // Booting an actual guest and getting real data is covered by `kvm::tests::run_code_test`.
     }
+
+    #[test]
+    fn test_snapshot_errors() {
+        let snapshot_file = NamedTempFile::new().unwrap();
+        let snapshot_config = SnapshotConfig {
+            snapshot_path: String::from(snapshot_file.path().to_str().unwrap()),
+        };
+
+        // A snapshot cannot be created before the microVM is started.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.create_snapshot(snapshot_config.clone()) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::MicroVMNotRunning)) => (),
+            _ => panic!("Unexpected result"),
+        }
+
+        // A snapshot cannot be loaded after the microVM is started.
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        match vmm.load_snapshot(snapshot_config.clone()) {
+            Err(VmmActionError::Snapshot(
+                ErrorKind::User,
+                SnapshotError::MicroVMAlreadyRunning,
+            )) => (),
+            _ => panic!("Unexpected result"),
+        }
+
+        // The snapshot file does not exist.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let missing_config = SnapshotConfig {
+            snapshot_path: String::from("/invalid/snapshot/path"),
+        };
+        match vmm.load_snapshot(missing_config) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::SnapshotFile(_))) => (),
+            _ => panic!("Unexpected result"),
+        }
+
+        // The snapshot file is not a snapshot.
+        snapshot_file.as_file().set_len(0x100).unwrap();
+        match vmm.load_snapshot(snapshot_config.clone()) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::InvalidSnapshot(_))) => (),
+            _ => panic!("Unexpected result"),
+        }
+        assert!(!vmm.is_instance_initialized());
+
+        // The guest memory contents are truncated. The configuration is left untouched.
+        let mut state = snapshot::MicrovmState {
+            vm_config: VmConfig::default(),
+            vm_state: VmState::default(),
+            vcpu_states: vec![],
+            device_states: vec![],
+            i8042_state: I8042State::default(),
+        };
+        state.vm_config.vcpu_count = Some(0);
+        state.vm_config.mem_size_mib = Some(1);
+        let small_mem = GuestMemory::new(&[(GuestAddress(0), 0x1000)]).unwrap();
+        snapshot::write_snapshot(
+            &mut File::create(snapshot_file.path()).unwrap(),
+            &state,
+            &small_mem,
+        )
+        .unwrap();
+        let vm_config = vmm.vm_config.clone();
+        match vmm.load_snapshot(snapshot_config) {
+            Err(VmmActionError::Snapshot(ErrorKind::User, SnapshotError::GuestMemory(_))) => (),
+            _ => panic!("Unexpected result"),
+        }
+        assert!(!vmm.is_instance_initialized());
+        assert!(vmm.guest_memory.is_none());
+        assert_eq!(vmm.vm_config, vm_config);
+    }
+
+    #[test]
+    fn test_vm_state_errors() {
+        let paused = VmStateConfig {
+            state: VmStateUpdate::Paused,
+        };
+        let resumed = VmStateConfig {
+            state: VmStateUpdate::Resumed,
+        };
+
+        // The microVM cannot be paused or resumed before it is started.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.update_vm_state(paused.clone()) {
+            Err(VmmActionError::VmState(ErrorKind::User, VmStateError::MicroVMNotRunning)) => (),
+            _ => panic!("Unexpected result"),
+        }
+        match vmm.update_vm_state(resumed.clone()) {
+            Err(VmmActionError::VmState(ErrorKind::User, VmStateError::MicroVMNotPaused)) => (),
+            _ => panic!("Unexpected result"),
+        }
+
+        // A paused microVM cannot be paused again.
+        let mut vmm = create_vmm_object(InstanceState::Paused);
+        match vmm.update_vm_state(paused) {
+            Err(VmmActionError::VmState(ErrorKind::User, VmStateError::MicroVMAlreadyPaused)) => (),
+            _ => panic!("Unexpected result"),
+        }
+
+        // A running microVM cannot be resumed.
+        let mut vmm = create_vmm_object(InstanceState::Running);
+        match vmm.update_vm_state(resumed) {
+            Err(VmmActionError::VmState(ErrorKind::User, VmStateError::MicroVMNotPaused)) => (),
+            _ => panic!("Unexpected result"),
+        }
+    }
+
+    #[test]
+    fn test_configure_from_file() {
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        let config_file: ConfigFile = serde_json::from_str(
+            r#"{
+                "boot-source": {
+                    "kernel_image_path": "/invalid/kernel/path"
+                },
+                "machine-config": {
+                    "vcpu_count": 2,
+                    "mem_size_mib": 256
+                }
+            }"#,
+        )
+        .unwrap();
+
+        // The resources are applied in order and the first error is reported as by the API.
+        match vmm.configure_from_file(config_file) {
+            Err(VmmActionError::BootSource(
+                ErrorKind::User,
+                BootSourceConfigError::InvalidKernelPath,
+            )) => (),
+            _ => panic!("Unexpected result"),
+        }
+        assert_eq!(vmm.vm_config.vcpu_count, Some(2));
+        assert_eq!(vmm.vm_config.mem_size_mib, Some(256));
+        assert!(!vmm.is_instance_initialized());
+    }
+
+    #[test]
+    fn test_send_ctrl_alt_del() {
+        // The keys cannot be sent before the microVM is started.
+        let mut vmm = create_vmm_object(InstanceState::Uninitialized);
+        match vmm.send_ctrl_alt_del() {
+            Err(VmmActionError::VmState(ErrorKind::User, VmStateError::MicroVMNotRunning)) => (),
+            _ => panic!("Unexpected result"),
+        }
+
+        assert_eq!(
+            format!(
+                "{}",
+                VmmActionError::SendCtrlAltDel(
+                    ErrorKind::Internal,
+                    I8042DeviceError::InternalBufferFull
+                )
+            ),
+            "Cannot send Ctrl+Alt+Del to the guest. InternalBufferFull"
+        );
+    }
+
+    #[test]
+    fn test_handle_vcpu_events() {
+        let vmm = create_vmm_object(InstanceState::Uninitialized);
+        // Saving the LAPIC state requires the in-kernel irqchip.
+        vmm.vm
+            .setup_irqchip(
+                &vmm.legacy_device_manager.com_evt_1_3,
+                &vmm.legacy_device_manager.com_evt_2_4,
+                &vmm.legacy_device_manager.kbd_evt,
+                &vmm.legacy_device_manager.acpi_hotplug_evt,
+            )
+            .unwrap();
+        let vcpu = Vcpu::new(0, &vmm.vm).unwrap();
+        let (event_sender, event_receiver) = channel();
+        let (response_sender, response_receiver) = channel();
+
+        // No pending events.
+        assert!(handle_vcpu_events(&vcpu, &event_receiver, &response_sender));
+        assert!(response_receiver.try_recv().is_err());
+
+        // The state is saved while paused and the vcpu runs again once resumed.
+        event_sender.send(VcpuEvent::Pause).unwrap();
+        event_sender.send(VcpuEvent::SaveState).unwrap();
+        event_sender.send(VcpuEvent::Resume).unwrap();
+        assert!(handle_vcpu_events(&vcpu, &event_receiver, &response_sender));
+        match response_receiver.try_recv() {
+            Ok(VcpuResponse::Paused) => (),
+            _ => panic!("Unexpected response"),
+        }
+        match response_receiver.try_recv() {
+            Ok(VcpuResponse::SavedState(_)) => (),
+            _ => panic!("Unexpected response"),
+        }
+        match response_receiver.try_recv() {
+            Ok(VcpuResponse::Resumed) => (),
+            _ => panic!("Unexpected response"),
+        }
+
+        // The vcpu thread exits once the VMM drops its end of the channel, even if paused.
+        event_sender.send(VcpuEvent::Pause).unwrap();
+        drop(event_sender);
+        assert!(!handle_vcpu_events(
+            &vcpu,
+            &event_receiver,
+            &response_sender
+        ));
+    }
 }
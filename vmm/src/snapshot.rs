@@ -0,0 +1,278 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! A snapshot file consists of a header made of a magic number and the length of the serialized
+//! microVM state, followed by the microVM state as JSON and finally by the contents of every
+//! guest memory region, in order.
+
+use std::io::{Read, Write};
+use std::result;
+
+use devices::legacy::I8042State;
+use devices::virtio::MmioDeviceState;
+use memory_model::GuestMemory;
+use serde_json;
+use vmm_config::machine_config::VmConfig;
+use vmm_config::snapshot::SnapshotError;
+use vstate::{VcpuState, VmState};
+
+const SNAPSHOT_MAGIC: u64 = 0x0001_5041_4e53_4346;
+// Upper bound for the serialized state, so that a corrupted header cannot make us allocate
+// an arbitrary amount of memory.
+const MAX_STATE_LEN: u64 = 64 << 20;
+
+type Result<T> = result::Result<T, SnapshotError>;
+
+/// Everything, except for the guest memory, that is needed to bring back a microVM.
+#[derive(Deserialize, Serialize)]
+pub struct MicrovmState {
+    /// The machine configuration the microVM was started with.
+    pub vm_config: VmConfig,
+    /// The state of the in-kernel irqchip, PIT and clock.
+    pub vm_state: VmState,
+    /// The state of each vCPU, in the order of their ids.
+    pub vcpu_states: Vec<VcpuState>,
+    /// The state of each MMIO device, in the order they were registered on the bus.
+    pub device_states: Vec<MmioDeviceState>,
+    /// The state of the i8042 controller.
+    pub i8042_state: I8042State,
+}
+
+/// Writes `state` and the contents of `guest_mem` to `dst`.
+pub fn write_snapshot<W: Write>(
+    dst: &mut W,
+    state: &MicrovmState,
+    guest_mem: &GuestMemory,
+) -> Result<()> {
+    let state_bytes =
+        serde_json::to_vec(state).map_err(|e| SnapshotError::InvalidSnapshot(e.to_string()))?;
+
+    write_u64(dst, SNAPSHOT_MAGIC)?;
+    write_u64(dst, state_bytes.len() as u64)?;
+    dst.write_all(&state_bytes)
+        .map_err(SnapshotError::SnapshotFile)?;
+
+    guest_mem.with_regions_mut(|_, guest_base, size, _| {
+        guest_mem
+            .write_from_memory(guest_base, dst, size)
+            .map_err(SnapshotError::GuestMemory)
+    })?;
+    dst.flush().map_err(SnapshotError::SnapshotFile)
+}
+
+/// Reads the header and the microVM state from `src`. The guest memory is left to be read with
+/// `read_guest_memory` once it is allocated according to the returned machine configuration.
+pub fn read_state<R: Read>(src: &mut R) -> Result<MicrovmState> {
+    if read_u64(src)? != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::InvalidSnapshot(String::from(
+            "bad magic number",
+        )));
+    }
+    let state_len = read_u64(src)?;
+    if state_len > MAX_STATE_LEN {
+        return Err(SnapshotError::InvalidSnapshot(format!(
+            "state too large ({} bytes)",
+            state_len
+        )));
+    }
+
+    let mut state_bytes = vec![0u8; state_len as usize];
+    src.read_exact(&mut state_bytes)
+        .map_err(SnapshotError::SnapshotFile)?;
+    serde_json::from_slice(&state_bytes).map_err(|e| SnapshotError::InvalidSnapshot(e.to_string()))
+}
+
+/// Fills `guest_mem` with the memory contents that follow the state in `src`.
+pub fn read_guest_memory<R: Read>(src: &mut R, guest_mem: &GuestMemory) -> Result<()> {
+    guest_mem.with_regions_mut(|_, guest_base, size, _| {
+        guest_mem
+            .read_to_memory(guest_base, src, size)
+            .map_err(SnapshotError::GuestMemory)
+    })
+}
+
+fn write_u64<W: Write>(dst: &mut W, val: u64) -> Result<()> {
+    dst.write_all(&val.to_le_bytes())
+        .map_err(SnapshotError::SnapshotFile)
+}
+
+fn read_u64<R: Read>(src: &mut R) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    src.read_exact(&mut bytes)
+        .map_err(SnapshotError::SnapshotFile)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// (De)serializers for the plain data structures generated by bindgen, for use with
+/// `#[serde(with = "pod")]`. The structures are stored as their raw bytes.
+///
+/// The types used with these helpers must be plain old data: `Copy`, without pointers, and valid
+/// for any bit pattern.
+pub mod pod {
+    use std::mem;
+    use std::ptr;
+    use std::slice;
+
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    fn as_bytes<T: Copy>(val: &T) -> &[u8] {
+        // Safe because the slice covers exactly the memory of `val` and lives as long as it.
+        unsafe { slice::from_raw_parts(val as *const T as *const u8, mem::size_of::<T>()) }
+    }
+
+    fn from_bytes<T: Copy + Default>(bytes: &[u8]) -> Option<T> {
+        if bytes.len() != mem::size_of::<T>() {
+            return None;
+        }
+        let mut val = T::default();
+        // Safe because the length was checked above and `T` is plain old data.
+        unsafe {
+            ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                &mut val as *mut T as *mut u8,
+                mem::size_of::<T>(),
+            )
+        };
+        Some(val)
+    }
+
+    /// Serializes `val` as a sequence of bytes.
+    pub fn serialize<T: Copy, S: Serializer>(val: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(as_bytes(val))
+    }
+
+    /// Deserializes a `T` from a sequence of bytes of the same size.
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: Copy + Default,
+        D: Deserializer<'de>,
+    {
+        let bytes = Vec::<u8>::deserialize(deserializer)?;
+        from_bytes(&bytes).ok_or_else(|| D::Error::invalid_length(bytes.len(), &"the struct size"))
+    }
+
+    /// Same as the parent module, for vectors of plain data structures.
+    pub mod vec {
+        use serde::de::Error;
+        use serde::ser::SerializeSeq;
+        use serde::{Deserialize, Deserializer, Serializer};
+
+        /// Serializes `vals` as a sequence of byte sequences.
+        pub fn serialize<T: Copy, S: Serializer>(
+            vals: &[T],
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let mut seq = serializer.serialize_seq(Some(vals.len()))?;
+            for val in vals {
+                seq.serialize_element(super::as_bytes(val))?;
+            }
+            seq.end()
+        }
+
+        /// Deserializes a vector of `T` from a sequence of byte sequences.
+        pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Vec<T>, D::Error>
+        where
+            T: Copy + Default,
+            D: Deserializer<'de>,
+        {
+            Vec::<Vec<u8>>::deserialize(deserializer)?
+                .iter()
+                .map(|bytes| {
+                    super::from_bytes(bytes)
+                        .ok_or_else(|| D::Error::invalid_length(bytes.len(), &"the struct size"))
+                })
+                .collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use memory_model::GuestAddress;
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    #[repr(C)]
+    struct Pod {
+        a: u64,
+        b: u64,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Wrapper {
+        #[serde(with = "pod")]
+        one: Pod,
+        #[serde(with = "pod::vec")]
+        many: Vec<Pod>,
+    }
+
+    #[test]
+    fn test_pod_serde() {
+        let wrapper = Wrapper {
+            one: Pod { a: 1, b: 2 },
+            many: vec![Pod { a: 3, b: 4 }, Pod { a: 5, b: 6 }],
+        };
+        let json = serde_json::to_string(&wrapper).unwrap();
+        assert_eq!(serde_json::from_str::<Wrapper>(&json).unwrap(), wrapper);
+
+        // The size of the serialized data has to match the size of the structure.
+        assert!(serde_json::from_str::<Wrapper>(r#"{"one": [1, 2, 3], "many": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_read_write_snapshot() {
+        let gm =
+            GuestMemory::new(&[(GuestAddress(0), 0x1000), (GuestAddress(0x2000), 0x1000)]).unwrap();
+        gm.write_obj_at_addr(0xdead_beef_u32, GuestAddress(0x10))
+            .unwrap();
+        gm.write_obj_at_addr(0xcafe_babe_u32, GuestAddress(0x2ff0))
+            .unwrap();
+
+        let mut buf = Vec::new();
+        let mut state = MicrovmState {
+            vm_config: VmConfig::default(),
+            vm_state: VmState::default(),
+            vcpu_states: vec![],
+            device_states: vec![],
+            i8042_state: I8042State::default(),
+        };
+        state.vm_config.vcpu_count = Some(2);
+        write_snapshot(&mut buf, &state, &gm).unwrap();
+
+        let mut src = buf.as_slice();
+        let restored = read_state(&mut src).unwrap();
+        assert_eq!(restored.vm_config, state.vm_config);
+        assert!(restored.vcpu_states.is_empty());
+
+        let restored_gm =
+            GuestMemory::new(&[(GuestAddress(0), 0x1000), (GuestAddress(0x2000), 0x1000)]).unwrap();
+        read_guest_memory(&mut src, &restored_gm).unwrap();
+        assert_eq!(
+            restored_gm
+                .read_obj_from_addr::<u32>(GuestAddress(0x10))
+                .unwrap(),
+            0xdead_beef
+        );
+        assert_eq!(
+            restored_gm
+                .read_obj_from_addr::<u32>(GuestAddress(0x2ff0))
+                .unwrap(),
+            0xcafe_babe
+        );
+        assert!(src.is_empty());
+
+        // Truncated memory contents.
+        let mut src = &buf[..buf.len() - 1];
+        read_state(&mut src).unwrap();
+        assert!(read_guest_memory(&mut src, &restored_gm).is_err());
+
+        // Bad magic number.
+        buf[0] ^= 0xff;
+        match read_state(&mut buf.as_slice()) {
+            Err(SnapshotError::InvalidSnapshot(_)) => (),
+            _ => panic!("Unexpected result"),
+        }
+    }
+}
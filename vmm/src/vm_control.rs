@@ -27,6 +27,10 @@ pub enum VmRequest {
     RegisterIoevent(EventFd, IoeventAddress, u32),
     /// Register the given IRQ number to be triggered when the `EventFd` is triggered.
     RegisterIrqfd(EventFd, u32),
+    /// Unregister an ioevent registered through `RegisterIoevent` with the same arguments.
+    UnregisterIoevent(EventFd, IoeventAddress, u32),
+    /// Unregister an IRQ registered through `RegisterIrqfd` with the same arguments.
+    UnregisterIrqfd(EventFd, u32),
 }
 
 impl VmRequest {
@@ -50,6 +54,16 @@ impl VmRequest {
                 Ok(_) => VmResponse::Ok,
                 Err(e) => return VmResponse::Err(e),
             },
+            &VmRequest::UnregisterIoevent(ref evt, ref addr, datamatch) => {
+                match vm.unregister_ioevent(evt, addr, datamatch) {
+                    Ok(_) => VmResponse::Ok,
+                    Err(e) => VmResponse::Err(e),
+                }
+            }
+            &VmRequest::UnregisterIrqfd(ref evt, irq) => match vm.unregister_irqfd(evt, irq) {
+                Ok(_) => VmResponse::Ok,
+                Err(e) => VmResponse::Err(e),
+            },
         }
     }
 }
@@ -10,8 +10,13 @@ use std::fmt::{Display, Formatter, Result};
 pub struct BootSourceConfig {
     /// Path of the kernel image.
     pub kernel_image_path: String,
+    /// Path of the initrd image, if any. The image is loaded in guest memory and handed over to
+    /// the kernel through the boot parameters.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initrd_path: Option<String>,
     /// The boot arguments to pass to the kernel. If this field is uninitialized, the default
-    /// kernel command line is used: `reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0`.
+    /// kernel command line is used: `reboot=k panic=1 pci=off nomodules 8250.nr_uarts=0
+    /// i8042.noaux i8042.nomux i8042.nopnp i8042.dumbkbd`.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub boot_args: Option<String>,
 }
@@ -19,6 +24,8 @@ pub struct BootSourceConfig {
 /// Errors associated with actions on `BootSourceConfig`.
 #[derive(Debug)]
 pub enum BootSourceConfigError {
+    /// The initrd file cannot be opened.
+    InvalidInitrdPath,
     /// The kernel file cannot be opened.
     InvalidKernelPath,
     /// The kernel command line is invalid.
@@ -31,6 +38,10 @@ impl Display for BootSourceConfigError {
     fn fmt(&self, f: &mut Formatter) -> Result {
         use self::BootSourceConfigError::*;
         match *self {
+            InvalidInitrdPath => write!(
+                f,
+                "The initrd file cannot be opened due to invalid path or invalid permissions.",
+            ),
             InvalidKernelPath => write!(
                 f,
                 "The kernel file cannot be opened due to invalid kernel path or \
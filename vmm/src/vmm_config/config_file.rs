@@ -0,0 +1,98 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use serde_json::Value;
+
+use vmm_config::boot_source::BootSourceConfig;
+use vmm_config::drive::BlockDeviceConfig;
+use vmm_config::logger::LoggerConfig;
+use vmm_config::machine_config::VmConfig;
+use vmm_config::net::NetworkInterfaceConfig;
+use vmm_config::rate_limit_group::RateLimitGroupConfig;
+#[cfg(feature = "vsock")]
+use vmm_config::vsock::VsockDeviceConfig;
+
+/// Strongly typed data structure describing a whole microVM, used to boot it without going
+/// through the API. Each field has the same format as the body of the corresponding API request.
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// The boot source, as for `PUT /boot-source`.
+    #[serde(rename = "boot-source")]
+    pub boot_source: BootSourceConfig,
+    /// The block devices, as for `PUT /drives/{drive_id}`.
+    #[serde(default)]
+    pub drives: Vec<BlockDeviceConfig>,
+    /// The network interfaces, as for `PUT /network-interfaces/{iface_id}`.
+    #[serde(rename = "network-interfaces", default)]
+    pub network_interfaces: Vec<NetworkInterfaceConfig>,
+    /// The memory and CPU configuration, as for `PUT /machine-config`.
+    #[serde(rename = "machine-config")]
+    pub machine_config: Option<VmConfig>,
+    /// The logger configuration, as for `PUT /logger`.
+    pub logger: Option<LoggerConfig>,
+    /// The rate-limit groups, as for `PUT /rate-limit-groups/{group_id}`.
+    #[serde(rename = "rate-limit-groups", default)]
+    pub rate_limit_groups: Vec<RateLimitGroupConfig>,
+    #[cfg(feature = "vsock")]
+    /// The vsock devices, as for `PUT /vsocks/{id}`.
+    #[serde(default)]
+    pub vsocks: Vec<VsockDeviceConfig>,
+    /// The initial contents of the microVM metadata store, as for `PUT /mmds`.
+    pub mmds: Option<Value>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json;
+
+    #[test]
+    fn test_deserialize() {
+        let json = r#"{
+            "boot-source": {
+                "kernel_image_path": "/foo/vmlinux"
+            },
+            "drives": [{
+                "drive_id": "rootfs",
+                "path_on_host": "/foo/rootfs.ext4",
+                "is_root_device": true,
+                "is_read_only": false
+            }],
+            "machine-config": {
+                "vcpu_count": 2
+            },
+            "mmds": {
+                "latest": {}
+            }
+        }"#;
+        let config: ConfigFile = serde_json::from_str(json).unwrap();
+        assert_eq!(config.boot_source.kernel_image_path, "/foo/vmlinux");
+        assert_eq!(config.drives.len(), 1);
+        assert!(config.network_interfaces.is_empty());
+        assert_eq!(config.machine_config.unwrap().vcpu_count, Some(2));
+        assert!(config.logger.is_none());
+        assert!(config.rate_limit_groups.is_empty());
+        assert!(config.mmds.is_some());
+
+        // The boot source is mandatory.
+        assert!(serde_json::from_str::<ConfigFile>(r#"{"drives": []}"#).is_err());
+        // Unknown resources are rejected.
+        assert!(serde_json::from_str::<ConfigFile>(
+            r#"{"boot-source": {"kernel_image_path": "/foo"}, "foo": {}}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "vsock"))]
+    fn test_deserialize_vsocks() {
+        // Without the vsock support, the vsock devices are an unknown resource.
+        let err = serde_json::from_str::<ConfigFile>(
+            r#"{"boot-source": {"kernel_image_path": "/foo"}, "vsocks": []}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unknown field `vsocks`"));
+    }
+}
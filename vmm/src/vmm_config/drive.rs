@@ -7,7 +7,9 @@ use std::fmt::{Display, Formatter};
 use std::path::PathBuf;
 use std::result;
 
-use rate_limiter::RateLimiter;
+/// The strongly typed equivalent of the rate limiter of a drive update request.
+pub use devices::virtio::BlockRateLimiterUpdate;
+use devices::virtio::{block, BlockRateLimiter, CacheType, IoEngine};
 
 type Result<T> = result::Result<T, DriveError>;
 
@@ -30,6 +32,32 @@ pub enum DriveError {
     UpdateNotAllowedPostBoot,
     /// A root block device was already added.
     RootBlockDeviceAlreadyAdded,
+    /// Cannot swap the base image of a drive with an overlay after boot.
+    UpdateNotAllowedWithOverlay,
+    /// Cannot swap the image of a qcow2 drive after boot.
+    UpdateNotAllowedWithQcow2,
+    /// An overlay can only be put on top of a raw image.
+    OverlayRequiresRawFormat,
+    /// The Direct cache type can only be used with a raw image.
+    DirectRequiresRawFormat,
+    /// The Direct cache type can only be used with an image whose size is a multiple of its
+    /// block size.
+    DirectRequiresAlignedSize,
+    /// The number of queues is out of range.
+    InvalidNumQueues,
+    /// The queue size is not a power of two within range.
+    InvalidQueueSize,
+    /// The root block device cannot be plugged or unplugged after boot.
+    RootBlockDeviceHotplug,
+    /// Cannot plug or unplug the block device after boot.
+    BlockDeviceHotplugFailed,
+    /// The block device was announced on the kernel command line, so it cannot be unplugged.
+    CmdlineBlockDeviceUnplug,
+    /// The guest ignores ACPI, so it cannot be told about a hot-plugged or hot-unplugged block
+    /// device.
+    AcpiDisabledHotplug,
+    /// The rate-limit group does not exist.
+    InvalidRateLimitGroup,
 }
 
 impl Display for DriveError {
@@ -51,10 +79,63 @@ impl Display for DriveError {
             UpdateNotAllowedPostBoot => {
                 write!(f, "The update operation is not allowed after boot.")
             }
+            UpdateNotAllowedWithOverlay => write!(
+                f,
+                "The path of a drive with an overlay cannot be updated after boot."
+            ),
+            UpdateNotAllowedWithQcow2 => {
+                write!(f, "The path of a qcow2 drive cannot be updated after boot.")
+            }
+            OverlayRequiresRawFormat => {
+                write!(f, "An overlay can only be used with a Raw drive format.")
+            }
+            DirectRequiresRawFormat => write!(
+                f,
+                "The Direct cache type can only be used with a Raw drive without an overlay."
+            ),
+            DirectRequiresAlignedSize => write!(
+                f,
+                "The Direct cache type can only be used with an image whose size is a multiple \
+                 of its block size."
+            ),
+            InvalidNumQueues => write!(
+                f,
+                "The number of queues must be between 1 and {}.",
+                block::MAX_QUEUES
+            ),
+            InvalidQueueSize => write!(
+                f,
+                "The queue size must be a power of 2 no larger than {}.",
+                block::MAX_QUEUE_SIZE
+            ),
+            RootBlockDeviceHotplug => write!(
+                f,
+                "The root block device cannot be hot-plugged or hot-unplugged."
+            ),
+            BlockDeviceHotplugFailed => write!(f, "The hot-plug operation failed!"),
+            CmdlineBlockDeviceUnplug => write!(
+                f,
+                "A block device announced on the kernel command line cannot be hot-unplugged. \
+                 Only the drives of acpi_only microVMs can be hot-unplugged after boot."
+            ),
+            AcpiDisabledHotplug => write!(
+                f,
+                "Block devices cannot be hot-plugged or hot-unplugged with acpi=off."
+            ),
+            InvalidRateLimitGroup => write!(f, "Invalid rate-limit group ID!"),
         }
     }
 }
 
+/// The format of the image backing a drive.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum DriveFormat {
+    /// The image is used as is.
+    Raw,
+    /// The image is in the qcow2 format, version 2 or 3.
+    Qcow2,
+}
+
 /// Use this structure to set up the Block Device before booting the kernel.
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
@@ -73,8 +154,25 @@ pub struct BlockDeviceConfig {
     /// If set to true, the drive is opened in read-only mode. Otherwise, the
     /// drive is opened as read-write.
     pub is_read_only: bool,
-    /// Rate Limiter for I/O operations.
-    pub rate_limiter: Option<RateLimiter>,
+    /// Path of a sparse file on top of `path_on_host`. When present, the clusters written by the
+    /// guest go to this file, created if missing, and `path_on_host` is only ever read.
+    pub overlay_path: Option<PathBuf>,
+    /// The format of the image at `path_on_host`, `Raw` when missing.
+    pub format: Option<DriveFormat>,
+    /// The engine executing the requests of the guest, `Sync` when missing.
+    pub io_engine: Option<IoEngine>,
+    /// The way the data written by the guest is cached on the host, `Unsafe` when missing.
+    pub cache_type: Option<CacheType>,
+    /// The number of queues of the device, 1 when missing. Each queue is serviced by its own
+    /// handler. The handlers all run on the device event loop, so the queues only spread the
+    /// load inside the guest.
+    pub num_queues: Option<u16>,
+    /// The number of descriptors of each queue, 256 when missing.
+    pub queue_size: Option<u16>,
+    /// Rate Limiters for I/O operations, shared by reads and writes or specific to each.
+    pub rate_limiter: Option<BlockRateLimiter>,
+    /// ID of the rate-limit group that also limits the I/O operations.
+    pub rate_limit_group: Option<String>,
 }
 
 impl BlockDeviceConfig {
@@ -92,6 +190,58 @@ impl BlockDeviceConfig {
     pub fn path_on_host(&self) -> &PathBuf {
         &self.path_on_host
     }
+
+    /// Returns a reference to `overlay_path`.
+    pub fn overlay_path(&self) -> Option<&PathBuf> {
+        self.overlay_path.as_ref()
+    }
+
+    /// Returns the format of the image.
+    pub fn format(&self) -> DriveFormat {
+        self.format.unwrap_or(DriveFormat::Raw)
+    }
+
+    /// Returns the engine executing the requests of the guest.
+    pub fn io_engine(&self) -> IoEngine {
+        self.io_engine.unwrap_or(IoEngine::Sync)
+    }
+
+    /// Returns the way the data written by the guest is cached on the host.
+    pub fn cache_type(&self) -> CacheType {
+        self.cache_type.unwrap_or(CacheType::Unsafe)
+    }
+
+    /// Returns the number of queues of the device.
+    pub fn num_queues(&self) -> u16 {
+        self.num_queues.unwrap_or(1)
+    }
+
+    /// Returns the number of descriptors of each queue.
+    pub fn queue_size(&self) -> u16 {
+        self.queue_size.unwrap_or(block::DEFAULT_QUEUE_SIZE)
+    }
+
+    // Checks that the image supports the cache type.
+    fn validate_cache_type(&self) -> Result<()> {
+        if self.cache_type() == CacheType::Direct
+            && (self.format() != DriveFormat::Raw || self.overlay_path.is_some())
+        {
+            return Err(DriveError::DirectRequiresRawFormat);
+        }
+        Ok(())
+    }
+
+    // Checks the number and size of the queues.
+    fn validate_queues(&self) -> Result<()> {
+        if self.num_queues() == 0 || self.num_queues() > block::MAX_QUEUES {
+            return Err(DriveError::InvalidNumQueues);
+        }
+        let queue_size = self.queue_size();
+        if queue_size == 0 || queue_size > block::MAX_QUEUE_SIZE || !queue_size.is_power_of_two() {
+            return Err(DriveError::InvalidQueueSize);
+        }
+        Ok(())
+    }
 }
 
 /// Wrapper for the collection that holds all the Block Devices Configs
@@ -162,6 +312,15 @@ impl BlockDeviceConfigs {
             return Err(DriveError::InvalidBlockDevicePath);
         }
 
+        if block_device_config.overlay_path.is_some()
+            && block_device_config.format() != DriveFormat::Raw
+        {
+            return Err(DriveError::OverlayRequiresRawFormat);
+        }
+
+        block_device_config.validate_cache_type()?;
+        block_device_config.validate_queues()?;
+
         if self
             .get_index_of_drive_path(&block_device_config.path_on_host)
             .is_some()
@@ -190,6 +349,21 @@ impl BlockDeviceConfigs {
         Ok(())
     }
 
+    /// Removes the Block Device Config with the specified `drive_id` and returns it.
+    pub fn remove(&mut self, drive_id: &String) -> Result<BlockDeviceConfig> {
+        let index = self
+            .get_index_of_drive_id(drive_id)
+            .ok_or(DriveError::InvalidBlockDeviceID)?;
+        // It is safe to unwrap because the index was just found.
+        let config = self.config_list.remove(index).unwrap();
+        if config.is_root_device {
+            self.has_root_block = false;
+            self.read_only_root = false;
+            self.has_partuuid_root = false;
+        }
+        Ok(config)
+    }
+
     /// Updates a Block Device Config. The update fails if it would result in two
     /// root block devices.
     fn update(&mut self, mut index: usize, new_config: BlockDeviceConfig) -> Result<()> {
@@ -198,6 +372,13 @@ impl BlockDeviceConfigs {
             return Err(DriveError::InvalidBlockDevicePath);
         }
 
+        if new_config.overlay_path.is_some() && new_config.format() != DriveFormat::Raw {
+            return Err(DriveError::OverlayRequiresRawFormat);
+        }
+
+        new_config.validate_cache_type()?;
+        new_config.validate_queues()?;
+
         // Check if the root block device is being updated.
         if self.config_list[index].is_root_device {
             self.has_root_block = new_config.is_root_device;
@@ -243,7 +424,14 @@ mod tests {
                 partuuid: self.partuuid.clone(),
                 is_read_only: self.is_read_only,
                 drive_id: self.drive_id.clone(),
+                overlay_path: self.overlay_path.clone(),
+                format: self.format,
+                io_engine: self.io_engine,
+                cache_type: self.cache_type,
+                num_queues: self.num_queues,
+                queue_size: self.queue_size,
                 rate_limiter: None,
+                rate_limit_group: self.rate_limit_group.clone(),
             }
         }
     }
@@ -266,7 +454,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: dummy_id.clone(),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -298,7 +493,14 @@ mod tests {
             partuuid: None,
             is_read_only: true,
             drive_id: String::from("1"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -323,7 +525,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("1"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -334,7 +543,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("2"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -347,6 +563,71 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_remove_block_device() {
+        let dummy_file_1 = NamedTempFile::new().unwrap();
+        let root_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file_1.path().to_path_buf(),
+            is_root_device: true,
+            partuuid: Some(String::from("0eaa91a0-01")),
+            is_read_only: true,
+            drive_id: String::from("1"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            rate_limiter: None,
+            rate_limit_group: None,
+        };
+        let dummy_file_2 = NamedTempFile::new().unwrap();
+        let dummy_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file_2.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            drive_id: String::from("2"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            rate_limiter: None,
+            rate_limit_group: None,
+        };
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert!(block_devices_configs
+            .create(root_block_device.clone())
+            .is_ok());
+        assert!(block_devices_configs
+            .create(dummy_block_device.clone())
+            .is_ok());
+
+        assert_eq!(
+            block_devices_configs.remove(&String::from("3")),
+            Err(DriveError::InvalidBlockDeviceID)
+        );
+        assert_eq!(
+            block_devices_configs.remove(&String::from("2")),
+            Ok(dummy_block_device)
+        );
+        assert!(block_devices_configs.has_root_block_device());
+        assert_eq!(
+            block_devices_configs.remove(&String::from("1")),
+            Ok(root_block_device.clone())
+        );
+        assert!(!block_devices_configs.has_root_block_device());
+        assert!(!block_devices_configs.has_read_only_root());
+        assert!(!block_devices_configs.has_partuuid_root());
+        assert!(block_devices_configs.config_list.is_empty());
+
+        // Another root block device can be added.
+        assert!(block_devices_configs.create(root_block_device).is_ok());
+    }
+
     #[test]
     // Test BlockDevicesConfigs::add when you first add the root device and then the other devices.
     fn test_add_root_block_device_first() {
@@ -358,7 +639,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("1"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -369,7 +657,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("2"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let dummy_file_3 = NamedTempFile::new().unwrap();
@@ -380,7 +675,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("3"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -415,7 +717,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("1"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -426,7 +735,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("2"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let dummy_file_3 = NamedTempFile::new().unwrap();
@@ -437,7 +753,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("3"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -473,7 +796,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("1"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let dummy_file_2 = NamedTempFile::new().unwrap();
@@ -484,7 +814,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("2"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
 
         let mut block_devices_configs = BlockDeviceConfigs::new();
@@ -536,8 +873,34 @@ mod tests {
             Err(DriveError::InvalidBlockDevicePath)
         );
 
-        // Update with 2 root block devices.
+        // Update with an invalid number of queues.
         dummy_block_device_2.path_on_host = dummy_path_2.clone();
+        dummy_block_device_2.num_queues = Some(0);
+        assert_eq!(
+            block_devices_configs.update(index, dummy_block_device_2.clone()),
+            Err(DriveError::InvalidNumQueues)
+        );
+        dummy_block_device_2.num_queues = Some(block::MAX_QUEUES + 1);
+        assert_eq!(
+            block_devices_configs.update(index, dummy_block_device_2.clone()),
+            Err(DriveError::InvalidNumQueues)
+        );
+        dummy_block_device_2.num_queues = Some(4);
+
+        // Update with an invalid queue size.
+        dummy_block_device_2.queue_size = Some(100);
+        assert_eq!(
+            block_devices_configs.update(index, dummy_block_device_2.clone()),
+            Err(DriveError::InvalidQueueSize)
+        );
+        dummy_block_device_2.queue_size = Some(64);
+        assert!(block_devices_configs
+            .update(index, dummy_block_device_2.clone())
+            .is_ok());
+        dummy_block_device_2.num_queues = None;
+        dummy_block_device_2.queue_size = None;
+
+        // Update with 2 root block devices.
         dummy_block_device_2.is_root_device = true;
         assert_eq!(
             block_devices_configs.update(index, dummy_block_device_2.clone()),
@@ -551,7 +914,14 @@ mod tests {
             partuuid: None,
             is_read_only: false,
             drive_id: String::from("1"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         let root_block_device_new = BlockDeviceConfig {
             path_on_host: dummy_path_2,
@@ -559,7 +929,14 @@ mod tests {
             partuuid: Some("0eaa91a0-01".to_string()),
             is_read_only: false,
             drive_id: String::from("2"),
+            overlay_path: None,
+            format: None,
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
             rate_limiter: None,
+            rate_limit_group: None,
         };
         let index1 = block_devices_configs
             .get_index_of_drive_id(&root_block_device_old.drive_id)
@@ -575,4 +952,59 @@ mod tests {
             .is_ok());
         assert!(block_devices_configs.has_partuuid_root);
     }
+
+    #[test]
+    fn test_update_qcow2() {
+        let dummy_file = NamedTempFile::new().unwrap();
+        let mut qcow2_block_device = BlockDeviceConfig {
+            path_on_host: dummy_file.path().to_path_buf(),
+            is_root_device: false,
+            partuuid: None,
+            is_read_only: false,
+            drive_id: String::from("1"),
+            overlay_path: None,
+            format: Some(DriveFormat::Qcow2),
+            io_engine: None,
+            cache_type: None,
+            num_queues: None,
+            queue_size: None,
+            rate_limiter: None,
+            rate_limit_group: None,
+        };
+
+        let mut block_devices_configs = BlockDeviceConfigs::new();
+        assert!(block_devices_configs
+            .create(qcow2_block_device.clone())
+            .is_ok());
+        let index = block_devices_configs
+            .get_index_of_drive_id(&qcow2_block_device.drive_id)
+            .unwrap();
+
+        // Update with an overlay on top of a qcow2 image.
+        qcow2_block_device.overlay_path = Some(PathBuf::from("overlay"));
+        assert_eq!(
+            block_devices_configs.update(index, qcow2_block_device.clone()),
+            Err(DriveError::OverlayRequiresRawFormat)
+        );
+        qcow2_block_device.overlay_path = None;
+
+        // Update with the Direct cache type on top of a qcow2 image.
+        qcow2_block_device.cache_type = Some(CacheType::Direct);
+        assert_eq!(
+            block_devices_configs.update(index, qcow2_block_device.clone()),
+            Err(DriveError::DirectRequiresRawFormat)
+        );
+
+        // The overlay is allowed once the image is raw.
+        qcow2_block_device.overlay_path = Some(PathBuf::from("overlay"));
+        qcow2_block_device.cache_type = None;
+        qcow2_block_device.format = Some(DriveFormat::Raw);
+        assert!(block_devices_configs
+            .update(index, qcow2_block_device.clone())
+            .is_ok());
+        assert_eq!(
+            block_devices_configs.config_list[index].format(),
+            DriveFormat::Raw
+        );
+    }
 }
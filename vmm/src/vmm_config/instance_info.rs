@@ -16,6 +16,7 @@ use x86_64;
 /// The microvm state. When Firecracker starts, the instance state is Uninitialized.
 /// Once start_microvm method is called, the state goes from Uninitialized to Starting.
 /// The state is changed to Running before ending the start_microvm method.
+/// A running microvm can be Paused and later brought back to Running.
 /// Halting and Halted are currently unsupported.
 #[derive(Clone, Debug, PartialEq, Serialize)]
 pub enum InstanceState {
@@ -25,6 +26,8 @@ pub enum InstanceState {
     Starting,
     /// Microvm is running.
     Running,
+    /// Microvm is paused.
+    Paused,
     /// Microvm received a halt instruction.
     Halting,
     /// Microvm is halted.
@@ -38,6 +41,8 @@ pub struct InstanceInfo {
     pub id: String,
     /// The state of the microVM.
     pub state: InstanceState,
+    /// The drives the guest was asked to eject and has not ejected yet.
+    pub pending_drive_ejects: Vec<String>,
 }
 
 /// Errors associated with starting the instance.
@@ -56,6 +61,12 @@ pub enum StartMicrovmError {
     /// Internal errors are due to resource exhaustion.
     /// Users errors are due to invalid permissions.
     CreateNetDevice(devices::virtio::Error),
+    #[cfg(feature = "vhost")]
+    /// Creating a vhost-net device fails if /dev/vhost-net cannot be used or the tap device
+    /// cannot be configured.
+    CreateVhostNetDevice(devices::virtio::vhost::Error),
+    /// Cannot create the timer of a rate-limit group.
+    CreateRateLimitGroup(std::io::Error),
     #[cfg(feature = "vsock")]
     /// Creating a vsock device can only fail if the /dev/vhost-vsock device cannot be open.
     CreateVsockDevice(devices::virtio::vhost::Error),
@@ -81,6 +92,8 @@ pub enum StartMicrovmError {
     NetDeviceNotConfigured,
     /// Cannot open the block device backing file.
     OpenBlockDevice(std::io::Error),
+    /// Cannot create the capture file of a network interface.
+    OpenNetCapture(std::io::Error),
     /// Cannot initialize a MMIO Block Device or add a device to the MMIO Bus.
     RegisterBlockDevice(device_manager::mmio::Error),
     /// Cannot add event to Epoll.
@@ -137,6 +150,19 @@ impl Display for StartMicrovmError {
 
                 write!(f, "Cannot create network device. {}", err_msg)
             }
+            #[cfg(feature = "vhost")]
+            CreateVhostNetDevice(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "Cannot create vhost-net device. {}", err_msg)
+            }
+            CreateRateLimitGroup(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "Cannot create rate-limit group. {}", err_msg)
+            }
             DeviceVmRequest(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace("\"", "");
@@ -180,6 +206,16 @@ impl Display for StartMicrovmError {
 
                 write!(f, "Cannot open the block device backing file. {}", err_msg)
             }
+            OpenNetCapture(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(
+                    f,
+                    "Cannot create the capture file of a network interface. {}",
+                    err_msg
+                )
+            }
             RegisterBlockDevice(ref err) => {
                 let mut err_msg = format!("{:?}", err);
                 err_msg = err_msg.replace("\"", "");
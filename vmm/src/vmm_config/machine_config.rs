@@ -49,6 +49,10 @@ pub struct VmConfig {
     /// A CPU template that it is used to filter the CPU features exposed to the guest.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cpu_template: Option<CpuFeaturesTemplate>,
+    /// Describes the microVM only through the ACPI tables, without the legacy MP table and
+    /// without listing the virtio-mmio devices on the kernel command line.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub acpi_only: Option<bool>,
 }
 
 impl Default for VmConfig {
@@ -58,6 +62,7 @@ impl Default for VmConfig {
             mem_size_mib: Some(128),
             ht_enabled: Some(false),
             cpu_template: None,
+            acpi_only: Some(false),
         }
     }
 }
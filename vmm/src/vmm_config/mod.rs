@@ -3,6 +3,8 @@
 
 /// Wrapper for configuring the microVM boot source.
 pub mod boot_source;
+/// Wrapper for booting the microVM from a configuration file.
+pub mod config_file;
 /// Wrapper for configuring the block devices.
 pub mod drive;
 /// Wrapper over the microVM general information attached to the microVM.
@@ -13,6 +15,12 @@ pub mod logger;
 pub mod machine_config;
 /// Wrapper for configuring the network devices attached to the microVM.
 pub mod net;
+/// Wrapper for configuring the rate-limit groups shared by devices.
+pub mod rate_limit_group;
+/// Wrapper for creating and loading microVM snapshots.
+pub mod snapshot;
+/// Wrapper for pausing and resuming the microVM.
+pub mod vm_state;
 #[cfg(feature = "vsock")]
 /// Wrapper for configuring the vsock devices attached to the microVM.
 pub mod vsock;
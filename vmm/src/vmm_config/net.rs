@@ -2,10 +2,24 @@
 // SPDX-License-Identifier: Apache-2.0
 
 use std::fmt::{Display, Formatter, Result};
+use std::io;
+use std::net::Ipv4Addr;
 use std::result;
 
+use devices::virtio::net::MAX_QUEUE_PAIRS;
+use devices::virtio::{CaptureConfig, FirewallConfig, FirewallError, SpoofFilter};
 use net_util::{MacAddr, Tap, TapError};
-use rate_limiter::RateLimiter;
+use rate_limiter::{RateLimiter, RateLimiterUpdate};
+use serde::{Deserialize, Deserializer};
+
+/// The backend moving the frames of a network interface between the guest and the tap device.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum NetBackend {
+    /// The frames are handled by the device model.
+    Userspace,
+    /// The frames are handled by the vhost-net driver of the host kernel.
+    VhostNet,
+}
 
 /// This struct represents the strongly typed equivalent of the json body from net iface
 /// related requests.
@@ -22,6 +36,10 @@ pub struct NetworkInterfaceConfig {
     pub rx_rate_limiter: Option<RateLimiter>,
     /// Rate Limiter for transmitted packages.
     pub tx_rate_limiter: Option<RateLimiter>,
+    /// ID of the rate-limit group that also limits the received packages.
+    pub rx_rate_limit_group: Option<String>,
+    /// ID of the rate-limit group that also limits the transmitted packages.
+    pub tx_rate_limit_group: Option<String>,
     #[serde(default = "default_allow_mmds_requests")]
     /// If this field is set, the device model will reply to HTTP GET
     /// requests sent to the MMDS address via this interface. In this case,
@@ -29,9 +47,58 @@ pub struct NetworkInterfaceConfig {
     /// same address are intercepted by the device model, and do not reach
     /// the associated TAP device.
     pub allow_mmds_requests: bool,
-    /// Handle for a network tap interface created using `host_dev_name`.
+    /// The number of RX/TX queue pairs of the device, 1 when missing. Each pair is serviced by
+    /// its own handler and uses its own queue of the tap interface. The handlers all run on the
+    /// device event loop, so the pairs only spread the load inside the guest.
+    pub num_queue_pairs: Option<u16>,
+    /// The backend of the device, `Userspace` when missing.
+    pub backend: Option<NetBackend>,
+    /// Capture of the frames received and sent by the guest, MMDS traffic included.
+    pub capture: Option<CaptureConfig>,
+    /// If this field is set, the frames sent by the guest from another MAC address than
+    /// `guest_mac`, which is then required, are dropped, along with the IPv4 packets and the ARP
+    /// frames sent from an address missing from `allowed_ips`.
+    #[serde(default)]
+    pub anti_spoofing: bool,
+    /// The IPv4 addresses the guest may send from, which requires `anti_spoofing` to be set.
+    /// When missing, any address is allowed.
+    pub allowed_ips: Option<Vec<Ipv4Addr>>,
+    /// The rules the frames received and sent by the guest, MMDS traffic included, are checked
+    /// against. The frames go through when missing.
+    pub firewall: Option<FirewallConfig>,
+    /// Handles for the queues of the network tap interface created using `host_dev_name`.
     #[serde(skip)]
-    pub tap: Option<Tap>,
+    pub taps: Vec<Tap>,
+}
+
+/// The strongly typed equivalent of the json body of a PATCH request on a net iface, which
+/// updates its rate limiters, its capture and its firewall rules.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NetworkInterfaceUpdateConfig {
+    /// ID of the guest network interface.
+    pub iface_id: String,
+    /// New configuration of the rate limiter for received packages.
+    pub rx_rate_limiter: Option<RateLimiterUpdate>,
+    /// New configuration of the rate limiter for transmitted packages.
+    pub tx_rate_limiter: Option<RateLimiterUpdate>,
+    /// New capture of the frames of the interface. `Some(None)`, from a `null` value, stops the
+    /// capture, while a missing field leaves it unchanged.
+    #[serde(default, deserialize_with = "deserialize_capture")]
+    pub capture: Option<Option<CaptureConfig>>,
+    /// New firewall rules of the interface, replacing all of the current ones. A missing field
+    /// leaves them unchanged.
+    pub firewall: Option<FirewallConfig>,
+}
+
+// A missing field takes the default value, `None`, and this is only called for present ones.
+fn deserialize_capture<'de, D>(
+    deserializer: D,
+) -> result::Result<Option<Option<CaptureConfig>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<CaptureConfig>::deserialize(deserializer).map(Some)
 }
 
 // Serde does not allow specifying a default value for a field
@@ -42,10 +109,39 @@ fn default_allow_mmds_requests() -> bool {
 }
 
 impl NetworkInterfaceConfig {
-    /// Returns the tap device if it was configured. This function has side effects as it takes
-    /// the value from `self.tap` and leaves None in its place.
-    pub fn take_tap(&mut self) -> Option<Tap> {
-        self.tap.take()
+    /// Returns the queues of the tap device if it was configured. This function has side
+    /// effects as it takes the value from `self.taps` and leaves an empty list in its place.
+    pub fn take_taps(&mut self) -> Vec<Tap> {
+        ::std::mem::replace(&mut self.taps, Vec::new())
+    }
+
+    /// Returns the number of RX/TX queue pairs of the device.
+    pub fn num_queue_pairs(&self) -> u16 {
+        self.num_queue_pairs.unwrap_or(1)
+    }
+
+    /// Returns the backend the device is created with. MMDS, rate limiting, multiple queue pairs,
+    /// captures, anti-spoofing and firewall rules are only implemented by the userspace backend,
+    /// so an interface asking for any of them falls back to it. So does every interface when
+    /// Firecracker is built without the `vhost` feature.
+    pub fn backend(&self) -> NetBackend {
+        match self.backend.unwrap_or(NetBackend::Userspace) {
+            NetBackend::VhostNet if !self.supports_vhost_net() => NetBackend::Userspace,
+            backend => backend,
+        }
+    }
+
+    fn supports_vhost_net(&self) -> bool {
+        cfg!(feature = "vhost")
+            && !self.allow_mmds_requests
+            && self.rx_rate_limiter.is_none()
+            && self.tx_rate_limiter.is_none()
+            && self.rx_rate_limit_group.is_none()
+            && self.tx_rate_limit_group.is_none()
+            && self.num_queue_pairs() == 1
+            && self.capture.is_none()
+            && !self.anti_spoofing
+            && self.firewall.is_none()
     }
 
     /// Returns a reference to the mac address. It the mac address is not configured, it
@@ -58,17 +154,69 @@ impl NetworkInterfaceConfig {
     pub fn allow_mmds_requests(&self) -> bool {
         self.allow_mmds_requests
     }
+
+    /// Returns the filter of the frames sent by the guest, if anti-spoofing is enabled.
+    pub fn spoof_filter(&self) -> Option<SpoofFilter> {
+        match self.guest_mac {
+            Some(guest_mac) if self.anti_spoofing => {
+                Some(SpoofFilter::new(guest_mac, self.allowed_ips.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    fn validate_num_queue_pairs(&self) -> result::Result<(), NetworkInterfaceError> {
+        if self.num_queue_pairs() == 0 || self.num_queue_pairs() > MAX_QUEUE_PAIRS {
+            return Err(NetworkInterfaceError::InvalidNumQueuePairs);
+        }
+        Ok(())
+    }
+
+    fn validate_anti_spoofing(&self) -> result::Result<(), NetworkInterfaceError> {
+        if self.anti_spoofing && self.guest_mac.is_none() {
+            return Err(NetworkInterfaceError::AntiSpoofingWithoutGuestMac);
+        }
+        if !self.anti_spoofing && self.allowed_ips.is_some() {
+            return Err(NetworkInterfaceError::AllowedIpsWithoutAntiSpoofing);
+        }
+        Ok(())
+    }
+
+    fn validate_firewall(&self) -> result::Result<(), NetworkInterfaceError> {
+        match self.firewall {
+            Some(ref firewall) => firewall
+                .validate()
+                .map_err(NetworkInterfaceError::InvalidFirewallRule),
+            None => Ok(()),
+        }
+    }
 }
 
 /// Errors associated with `NetworkInterfaceConfig`.
 #[derive(Debug)]
 pub enum NetworkInterfaceError {
+    /// The allowed IP addresses are set without enabling anti-spoofing.
+    AllowedIpsWithoutAntiSpoofing,
+    /// Anti-spoofing is enabled without a guest MAC address.
+    AntiSpoofingWithoutGuestMac,
     /// The MAC address is already in use.
     GuestMacAddressInUse(String),
     /// The host device name is already in use.
     HostDeviceNameInUse(String),
+    /// The iface ID is invalid.
+    InvalidIfaceId,
+    /// A firewall rule is inconsistent.
+    InvalidFirewallRule(FirewallError),
+    /// The rate-limit group does not exist.
+    InvalidRateLimitGroup,
+    /// The number of queue pairs is out of range.
+    InvalidNumQueuePairs,
+    /// Cannot create the capture file.
+    OpenCapture(io::Error),
     /// Cannot open/create tap device.
     OpenTap(TapError),
+    /// Updating the running network device failed.
+    DeviceUpdateFailed,
     /// The update is not allowed after booting the microvm.
     UpdateNotAllowedPostBoot,
 }
@@ -77,6 +225,15 @@ impl Display for NetworkInterfaceError {
     fn fmt(&self, f: &mut Formatter) -> Result {
         use self::NetworkInterfaceError::*;
         match *self {
+            AllowedIpsWithoutAntiSpoofing => {
+                write!(
+                    f,
+                    "The allowed IP addresses require anti-spoofing to be enabled."
+                )
+            }
+            AntiSpoofingWithoutGuestMac => {
+                write!(f, "Anti-spoofing requires the guest MAC address to be set.")
+            }
             GuestMacAddressInUse(ref mac_addr) => write!(
                 f,
                 "{}",
@@ -87,6 +244,15 @@ impl Display for NetworkInterfaceError {
                 "{}",
                 format!("The host device name {} is already in use.", host_dev_name)
             ),
+            InvalidIfaceId => write!(f, "Invalid network interface ID!"),
+            InvalidFirewallRule(ref e) => write!(f, "Invalid firewall rule. {}", e),
+            InvalidRateLimitGroup => write!(f, "Invalid rate-limit group ID!"),
+            InvalidNumQueuePairs => write!(
+                f,
+                "The number of queue pairs must be between 1 and {}.",
+                MAX_QUEUE_PAIRS
+            ),
+            OpenCapture(ref e) => write!(f, "Cannot create the capture file. {}", e),
             OpenTap(ref e) => {
                 // We are propagating the Tap Error. This error can contain
                 // imbricated quotes which would result in an invalid json.
@@ -100,6 +266,7 @@ impl Display for NetworkInterfaceError {
                     tap_err
                 )
             }
+            DeviceUpdateFailed => write!(f, "The update operation failed!"),
             UpdateNotAllowedPostBoot => {
                 write!(f, "The update operation is not allowed after boot.",)
             }
@@ -120,6 +287,11 @@ impl NetworkInterfaceConfigs {
         }
     }
 
+    /// Returns an iterator over the network interfaces.
+    pub fn iter(&self) -> ::std::slice::Iter<NetworkInterfaceConfig> {
+        self.if_list.iter()
+    }
+
     /// Returns a mutable iterator over the network interfaces.
     pub fn iter_mut(&mut self) -> ::std::slice::IterMut<NetworkInterfaceConfig> {
         self.if_list.iter_mut()
@@ -142,6 +314,48 @@ impl NetworkInterfaceConfigs {
         }
     }
 
+    /// Returns whether a network interface with the id `iface_id` is configured.
+    pub fn contains(&self, iface_id: &str) -> bool {
+        self.if_list.iter().any(|netif| netif.iface_id == iface_id)
+    }
+
+    /// Updates the rate limiters, the capture and the firewall rules of a network interface that
+    /// is yet to be attached.
+    pub fn apply_update(
+        &mut self,
+        update_config: NetworkInterfaceUpdateConfig,
+    ) -> result::Result<(), NetworkInterfaceError> {
+        let netif = self
+            .if_list
+            .iter_mut()
+            .find(|netif| netif.iface_id == update_config.iface_id)
+            .ok_or(NetworkInterfaceError::InvalidIfaceId)?;
+        if let Some(update) = update_config.rx_rate_limiter {
+            netif
+                .rx_rate_limiter
+                .get_or_insert_with(RateLimiter::default)
+                .update(update)
+                .map_err(|_| NetworkInterfaceError::DeviceUpdateFailed)?;
+        }
+        if let Some(update) = update_config.tx_rate_limiter {
+            netif
+                .tx_rate_limiter
+                .get_or_insert_with(RateLimiter::default)
+                .update(update)
+                .map_err(|_| NetworkInterfaceError::DeviceUpdateFailed)?;
+        }
+        if let Some(firewall) = update_config.firewall {
+            firewall
+                .validate()
+                .map_err(NetworkInterfaceError::InvalidFirewallRule)?;
+            netif.firewall = Some(firewall);
+        }
+        if let Some(capture) = update_config.capture {
+            netif.capture = capture;
+        }
+        Ok(())
+    }
+
     fn get_index_of_mac(&self, mac: &MacAddr) -> Option<usize> {
         return self
             .if_list
@@ -179,6 +393,9 @@ impl NetworkInterfaceConfigs {
                 new_config.host_dev_name.clone(),
             ));
         }
+        new_config.validate_num_queue_pairs()?;
+        new_config.validate_anti_spoofing()?;
+        new_config.validate_firewall()?;
 
         Ok(())
     }
@@ -190,19 +407,38 @@ impl NetworkInterfaceConfigs {
     ) -> result::Result<(), NetworkInterfaceError> {
         self.validate_update(index, &updated_netif_config)?;
 
-        // We are ignoring the tap field of the network interface we want to update. We are
-        // manually setting this field to a newly created tap (corresponding to the host_dev_name)
-        // or to the old tap device of the network interface we are trying to update.
-        updated_netif_config.tap =
-            if self.if_list[index].host_dev_name != updated_netif_config.host_dev_name {
-                Some(
-                    Tap::open_named(&updated_netif_config.host_dev_name.as_str())
-                        .map_err(NetworkInterfaceError::OpenTap)?,
-                )
-            } else {
-                self.if_list[index].tap.take()
-            };
-        self.if_list[index] = updated_netif_config;
+        // We are ignoring the taps field of the network interface we want to update. We are
+        // manually setting this field to newly created taps (corresponding to the host_dev_name
+        // and the number of queue pairs) or to the old taps of the network interface we are
+        // trying to update. The old taps are kept if the new ones cannot be opened.
+        let netif = &mut self.if_list[index];
+        updated_netif_config.taps = if netif.host_dev_name != updated_netif_config.host_dev_name
+            || netif.num_queue_pairs() != updated_netif_config.num_queue_pairs()
+        {
+            let host_dev_name = updated_netif_config.host_dev_name.as_str();
+            let num_queues = updated_netif_config.num_queue_pairs() as usize;
+            match Tap::open_named_queues(host_dev_name, num_queues) {
+                Ok(taps) => taps,
+                // The interface may be the same, and then its queues can only be opened with
+                // other flags once the old ones are closed.
+                Err(_) if netif.host_dev_name == updated_netif_config.host_dev_name => {
+                    let old_num_queues = netif.taps.len();
+                    netif.taps.clear();
+                    match Tap::open_named_queues(host_dev_name, num_queues) {
+                        Ok(taps) => taps,
+                        Err(e) => {
+                            netif.taps = Tap::open_named_queues(host_dev_name, old_num_queues)
+                                .map_err(NetworkInterfaceError::OpenTap)?;
+                            return Err(NetworkInterfaceError::OpenTap(e));
+                        }
+                    }
+                }
+                Err(e) => return Err(NetworkInterfaceError::OpenTap(e)),
+            }
+        } else {
+            netif.take_taps()
+        };
+        *netif = updated_netif_config;
 
         Ok(())
     }
@@ -231,21 +467,24 @@ impl NetworkInterfaceConfigs {
                 new_config.host_dev_name.clone(),
             ));
         }
+        new_config.validate_num_queue_pairs()?;
+        new_config.validate_anti_spoofing()?;
+        new_config.validate_firewall()?;
 
         Ok(())
     }
 
     fn create(
         &mut self,
-        netif_config: NetworkInterfaceConfig,
+        mut netif_config: NetworkInterfaceConfig,
     ) -> result::Result<(), NetworkInterfaceError> {
         self.validate_create(&netif_config)?;
-        let tap = Tap::open_named(netif_config.host_dev_name.as_str())
-            .map_err(NetworkInterfaceError::OpenTap)?;
+        netif_config.taps = Tap::open_named_queues(
+            netif_config.host_dev_name.as_str(),
+            netif_config.num_queue_pairs() as usize,
+        )
+        .map_err(NetworkInterfaceError::OpenTap)?;
         self.if_list.push(netif_config);
-
-        let index = self.if_list.len() - 1;
-        self.if_list[index].tap = Some(tap);
         Ok(())
     }
 }
@@ -256,6 +495,8 @@ mod tests {
 
     use super::*;
     use net_util::MacAddr;
+    use rate_limiter::TokenType;
+    use serde_json;
 
     fn create_netif(id: &str, name: &str, mac: &str) -> NetworkInterfaceConfig {
         NetworkInterfaceConfig {
@@ -264,8 +505,16 @@ mod tests {
             guest_mac: Some(MacAddr::parse_str(mac).unwrap()),
             rx_rate_limiter: Some(RateLimiter::default()),
             tx_rate_limiter: Some(RateLimiter::default()),
+            rx_rate_limit_group: None,
+            tx_rate_limit_group: None,
             allow_mmds_requests: false,
-            tap: None,
+            num_queue_pairs: None,
+            backend: None,
+            capture: None,
+            anti_spoofing: false,
+            allowed_ips: None,
+            firewall: None,
+            taps: Vec::new(),
         }
     }
 
@@ -279,8 +528,16 @@ mod tests {
                 guest_mac: self.guest_mac.clone(),
                 rx_rate_limiter: None,
                 tx_rate_limiter: None,
+                rx_rate_limit_group: self.rx_rate_limit_group.clone(),
+                tx_rate_limit_group: self.tx_rate_limit_group.clone(),
                 allow_mmds_requests: self.allow_mmds_requests.clone(),
-                tap: None,
+                num_queue_pairs: self.num_queue_pairs,
+                backend: self.backend,
+                capture: self.capture.clone(),
+                anti_spoofing: self.anti_spoofing,
+                allowed_ips: self.allowed_ips.clone(),
+                firewall: self.firewall.clone(),
+                taps: Vec::new(),
             }
         }
     }
@@ -392,4 +649,264 @@ mod tests {
             expected_error
         );
     }
+
+    #[test]
+    fn test_num_queue_pairs() {
+        let mut netif_configs = NetworkInterfaceConfigs::new();
+        let mut netif = create_netif("id_1", "mqdev%d", "01:23:45:67:89:0d");
+        assert_eq!(netif.num_queue_pairs(), 1);
+
+        for num_queue_pairs in vec![0, MAX_QUEUE_PAIRS + 1] {
+            netif.num_queue_pairs = Some(num_queue_pairs);
+            assert_eq!(
+                netif_configs.insert(netif.clone()).unwrap_err().to_string(),
+                format!(
+                    "The number of queue pairs must be between 1 and {}.",
+                    MAX_QUEUE_PAIRS
+                )
+            );
+        }
+
+        // A queue of the tap interface is opened for each pair.
+        netif.num_queue_pairs = Some(2);
+        assert!(netif_configs.insert(netif.clone()).is_ok());
+        assert_eq!(netif_configs.if_list[0].taps.len(), 2);
+
+        // Updating the number of queue pairs reopens the interface.
+        netif.num_queue_pairs = Some(3);
+        assert!(netif_configs.insert(netif.clone()).is_ok());
+        assert_eq!(netif_configs.if_list[0].taps.len(), 3);
+
+        // The interface keeps its taps when the new ones cannot be opened.
+        let mut bad_netif = netif.clone();
+        bad_netif.host_dev_name = String::from("a_very_long_tap_name%d");
+        assert!(netif_configs.insert(bad_netif).is_err());
+        assert_eq!(netif_configs.if_list[0].host_dev_name, netif.host_dev_name);
+        assert_eq!(netif_configs.if_list[0].taps.len(), 3);
+
+        assert_eq!(netif_configs.if_list[0].take_taps().len(), 3);
+        assert!(netif_configs.if_list[0].taps.is_empty());
+    }
+
+    #[test]
+    fn test_anti_spoofing() {
+        let mut netif_configs = NetworkInterfaceConfigs::new();
+        let mut netif: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id_1",
+                "host_dev_name": "dev6",
+                "anti_spoofing": true,
+                "allowed_ips": ["10.0.0.2", "0.0.0.0"]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            netif.allowed_ips,
+            Some(vec![Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(0, 0, 0, 0)])
+        );
+
+        // The MAC address the guest sends from has to be known.
+        assert!(netif.spoof_filter().is_none());
+        assert_eq!(
+            netif_configs.insert(netif.clone()).unwrap_err().to_string(),
+            "Anti-spoofing requires the guest MAC address to be set."
+        );
+
+        netif.guest_mac = Some(MacAddr::parse_str("01:23:45:67:89:0e").unwrap());
+        assert!(netif.spoof_filter().is_some());
+        assert!(netif_configs.insert(netif.clone()).is_ok());
+
+        // The allowed addresses are only checked with anti-spoofing.
+        netif.anti_spoofing = false;
+        assert!(netif.spoof_filter().is_none());
+        assert_eq!(
+            netif_configs.insert(netif.clone()).unwrap_err().to_string(),
+            "The allowed IP addresses require anti-spoofing to be enabled."
+        );
+        netif.allowed_ips = None;
+        assert!(netif_configs.insert(netif.clone()).is_ok());
+
+        assert!(serde_json::from_str::<NetworkInterfaceConfig>(
+            r#"{"iface_id": "id_1", "host_dev_name": "dev6", "allowed_ips": ["10.0.0"]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_firewall() {
+        let mut netif_configs = NetworkInterfaceConfigs::new();
+        let netif: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id_1",
+                "host_dev_name": "dev7",
+                "firewall": {
+                    "tx_rules": [
+                        {"action": "Allow", "protocol": "Udp", "ports": {"start": 53, "end": 53}},
+                        {"action": "Deny", "cidr": "10.0.0.0/8"}
+                    ]
+                }
+            }"#,
+        )
+        .unwrap();
+        let firewall = netif.firewall.clone().unwrap();
+        assert!(firewall.rx_rules.is_empty());
+        assert_eq!(firewall.tx_rules.len(), 2);
+        assert!(netif_configs.insert(netif).is_ok());
+
+        // The rules are replaced as a whole, and checked before.
+        let update_config: NetworkInterfaceUpdateConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id_1",
+                "firewall": {
+                    "rx_rules": [{"action": "Deny", "protocol": "Icmp", "ports": {"start": 1, "end": 1}}]
+                }
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            netif_configs
+                .apply_update(update_config)
+                .unwrap_err()
+                .to_string(),
+            "Invalid firewall rule. Ports can only be matched for TCP and UDP."
+        );
+        assert_eq!(netif_configs.if_list[0].firewall, Some(firewall));
+        let update_config: NetworkInterfaceUpdateConfig =
+            serde_json::from_str(r#"{"iface_id": "id_1", "firewall": {}}"#).unwrap();
+        assert!(netif_configs.apply_update(update_config).is_ok());
+        assert_eq!(
+            netif_configs.if_list[0].firewall,
+            Some(FirewallConfig::default())
+        );
+
+        let mut netif = create_netif("id_2", "dev8", "01:23:45:67:89:0f");
+        netif.firewall = Some(
+            serde_json::from_str(
+                r#"{"rx_rules": [{"action": "Allow", "protocol": "Tcp", "ports": {"start": 2, "end": 1}}]}"#,
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            netif_configs.insert(netif).unwrap_err().to_string(),
+            "Invalid firewall rule. A port range cannot start after its end."
+        );
+        assert!(
+            serde_json::from_str::<FirewallConfig>(r#"{"tx_rules": [{"action": "Reject"}]}"#)
+                .is_err()
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "vhost")]
+    fn test_backend() {
+        let mut netif: NetworkInterfaceConfig = serde_json::from_str(
+            r#"{
+                "iface_id": "id_1",
+                "host_dev_name": "dev1",
+                "backend": "VhostNet"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(netif.backend, Some(NetBackend::VhostNet));
+        assert_eq!(netif.backend(), NetBackend::VhostNet);
+
+        // The features of the userspace backend take precedence over vhost-net.
+        netif.allow_mmds_requests = true;
+        assert_eq!(netif.backend(), NetBackend::Userspace);
+        netif.allow_mmds_requests = false;
+        netif.rx_rate_limiter = Some(RateLimiter::default());
+        assert_eq!(netif.backend(), NetBackend::Userspace);
+        netif.rx_rate_limiter = None;
+        netif.tx_rate_limit_group = Some(String::from("tenant"));
+        assert_eq!(netif.backend(), NetBackend::Userspace);
+        netif.tx_rate_limit_group = None;
+        netif.num_queue_pairs = Some(2);
+        assert_eq!(netif.backend(), NetBackend::Userspace);
+        netif.num_queue_pairs = None;
+        netif.capture = Some(CaptureConfig {
+            path: "/tmp/netif.pcap".into(),
+            snap_len: None,
+            max_file_size: None,
+        });
+        assert_eq!(netif.backend(), NetBackend::Userspace);
+        netif.capture = None;
+        netif.anti_spoofing = true;
+        assert_eq!(netif.backend(), NetBackend::Userspace);
+        netif.anti_spoofing = false;
+        netif.firewall = Some(FirewallConfig::default());
+        assert_eq!(netif.backend(), NetBackend::Userspace);
+        netif.firewall = None;
+        assert_eq!(netif.backend(), NetBackend::VhostNet);
+
+        netif.backend = None;
+        assert_eq!(netif.backend(), NetBackend::Userspace);
+        assert!(serde_json::from_str::<NetworkInterfaceConfig>(
+            r#"{"iface_id": "id_1", "host_dev_name": "dev1", "backend": "vhost"}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_apply_update() {
+        let mut netif_configs = NetworkInterfaceConfigs::new();
+        let mut netif = create_netif("id_1", "dev5", "01:23:45:67:89:0c");
+        netif.tx_rate_limiter = None;
+        assert!(netif_configs.insert(netif).is_ok());
+        assert!(netif_configs.contains("id_1"));
+        assert!(!netif_configs.contains("id_2"));
+
+        let update: RateLimiterUpdate =
+            serde_json::from_str(r#"{"ops": {"size": 10, "refill_time": 1000}}"#).unwrap();
+        let update_config = NetworkInterfaceUpdateConfig {
+            iface_id: String::from("id_1"),
+            rx_rate_limiter: Some(update.clone()),
+            tx_rate_limiter: Some(update),
+            capture: None,
+            firewall: None,
+        };
+        assert!(netif_configs.apply_update(update_config.clone()).is_ok());
+        let netif = &mut netif_configs.if_list[0];
+        for rate_limiter in vec![
+            netif.rx_rate_limiter.as_mut().unwrap(),
+            netif.tx_rate_limiter.as_mut().unwrap(),
+        ] {
+            assert!(rate_limiter.consume(10, TokenType::Ops));
+            assert!(!rate_limiter.consume(1, TokenType::Ops));
+        }
+        assert!(netif.capture.is_none());
+
+        // A missing capture leaves the current one in place, while a null one removes it.
+        let update_config: NetworkInterfaceUpdateConfig = serde_json::from_str(
+            r#"{"iface_id": "id_1", "capture": {"path": "/tmp/netif.pcap", "snap_len": 128}}"#,
+        )
+        .unwrap();
+        assert!(netif_configs.apply_update(update_config).is_ok());
+        assert_eq!(
+            netif_configs.if_list[0].capture,
+            Some(CaptureConfig {
+                path: "/tmp/netif.pcap".into(),
+                snap_len: Some(128),
+                max_file_size: None,
+            })
+        );
+        let update_config: NetworkInterfaceUpdateConfig =
+            serde_json::from_str(r#"{"iface_id": "id_1"}"#).unwrap();
+        assert_eq!(update_config.capture, None);
+        assert!(netif_configs.apply_update(update_config).is_ok());
+        assert!(netif_configs.if_list[0].capture.is_some());
+        let mut update_config: NetworkInterfaceUpdateConfig =
+            serde_json::from_str(r#"{"iface_id": "id_1", "capture": null}"#).unwrap();
+        assert_eq!(update_config.capture, Some(None));
+        assert!(netif_configs.apply_update(update_config.clone()).is_ok());
+        assert!(netif_configs.if_list[0].capture.is_none());
+
+        update_config.iface_id = String::from("id_2");
+        assert_eq!(
+            netif_configs
+                .apply_update(update_config)
+                .unwrap_err()
+                .to_string(),
+            "Invalid network interface ID!"
+        );
+    }
 }
@@ -0,0 +1,192 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::fs::OpenOptions;
+use std::io;
+
+use rate_limiter::{RateLimiter, RateLimiterGroup, RateLimiterUpdate, TokenBucket};
+
+/// The strongly typed equivalent of the json body of a rate-limit group request. The drives and
+/// network interfaces that reference the group share its token buckets.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitGroupConfig {
+    /// ID of the rate-limit group.
+    pub group_id: String,
+    /// Token bucket shared by the members of the group for bytes.
+    pub bandwidth: Option<TokenBucket>,
+    /// Token bucket shared by the members of the group for operations.
+    pub ops: Option<TokenBucket>,
+    /// Path of a file holding the budget left in the token buckets, which is shared with the
+    /// groups of the other microVMs using the same file.
+    pub shared_state_path: Option<String>,
+}
+
+impl RateLimitGroupConfig {
+    /// Builds the rate limiter holding the token buckets of the group.
+    pub fn build_rate_limiter(&self) -> io::Result<RateLimiter> {
+        let mut rate_limiter = RateLimiter::default();
+        rate_limiter.update(RateLimiterUpdate {
+            bandwidth: self.bandwidth.clone(),
+            ops: self.ops.clone(),
+        })?;
+        Ok(rate_limiter)
+    }
+
+    /// Builds the group, whose token buckets are shared with other microVMs if a
+    /// `shared_state_path` is given.
+    pub fn build_group(&self) -> io::Result<RateLimiterGroup> {
+        let rate_limiter = self.build_rate_limiter()?;
+        match self.shared_state_path {
+            Some(ref path) => {
+                let state = OpenOptions::new().read(true).write(true).open(path)?;
+                Ok(RateLimiterGroup::with_shared_state(rate_limiter, state))
+            }
+            None => Ok(RateLimiterGroup::new(rate_limiter)),
+        }
+    }
+}
+
+/// Errors associated with `RateLimitGroupConfig`.
+#[derive(Debug)]
+pub enum RateLimitGroupError {
+    /// The update is not allowed after booting the microvm.
+    UpdateNotAllowedPostBoot,
+}
+
+impl Display for RateLimitGroupError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::RateLimitGroupError::*;
+        match *self {
+            UpdateNotAllowedPostBoot => {
+                write!(f, "The update operation is not allowed after boot.")
+            }
+        }
+    }
+}
+
+/// A list with all the rate-limit groups.
+pub struct RateLimitGroupConfigs {
+    configs: Vec<RateLimitGroupConfig>,
+}
+
+impl Default for RateLimitGroupConfigs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimitGroupConfigs {
+    /// Creates an empty list of RateLimitGroupConfig.
+    pub fn new() -> Self {
+        RateLimitGroupConfigs {
+            configs: Vec::new(),
+        }
+    }
+
+    /// Adds `cfg` in the list of rate-limit groups. If a group with the same id already
+    /// exists, it is replaced.
+    pub fn insert(&mut self, cfg: RateLimitGroupConfig) {
+        match self
+            .configs
+            .iter()
+            .position(|cfg_from_list| cfg_from_list.group_id == cfg.group_id)
+        {
+            Some(index) => self.configs[index] = cfg,
+            None => self.configs.push(cfg),
+        }
+    }
+
+    /// Checks whether a group with the id `group_id` exists.
+    pub fn contains(&self, group_id: &str) -> bool {
+        self.configs.iter().any(|cfg| cfg.group_id == group_id)
+    }
+
+    /// Returns an immutable iterator over the rate-limit groups.
+    pub fn iter(&self) -> ::std::slice::Iter<RateLimitGroupConfig> {
+        self.configs.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate tempfile;
+
+    use self::tempfile::NamedTempFile;
+    use super::*;
+
+    use rate_limiter::TokenType;
+    use serde_json;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_insert() {
+        let mut configs = RateLimitGroupConfigs::new();
+        let mut cfg: RateLimitGroupConfig = serde_json::from_str(
+            r#"{
+                "group_id": "tenant",
+                "ops": {"size": 10, "refill_time": 1000}
+            }"#,
+        )
+        .unwrap();
+        configs.insert(cfg.clone());
+        assert!(configs.contains("tenant"));
+        assert!(!configs.contains("foo"));
+
+        cfg.ops = None;
+        configs.insert(cfg.clone());
+        assert_eq!(configs.iter().count(), 1);
+        assert_eq!(configs.iter().next(), Some(&cfg));
+
+        // Unknown fields are rejected.
+        assert!(serde_json::from_str::<RateLimitGroupConfig>(
+            r#"{"group_id": "tenant", "foo": 1}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_build_rate_limiter() {
+        let cfg = RateLimitGroupConfig {
+            group_id: String::from("tenant"),
+            bandwidth: None,
+            ops: Some(TokenBucket::new(10, None, 1000)),
+            shared_state_path: None,
+        };
+        let mut rate_limiter = cfg.build_rate_limiter().unwrap();
+        assert!(rate_limiter.consume(u64::max_value(), TokenType::Bytes));
+        assert!(rate_limiter.consume(10, TokenType::Ops));
+        assert!(!rate_limiter.consume(1, TokenType::Ops));
+    }
+
+    #[test]
+    fn test_build_group() {
+        let state_file = NamedTempFile::new().unwrap();
+        let mut cfg = RateLimitGroupConfig {
+            group_id: String::from("tenant"),
+            bandwidth: None,
+            ops: Some(TokenBucket::new(10, None, 1000)),
+            shared_state_path: Some(String::from("/nonexistent/state")),
+        };
+        assert!(cfg.build_group().is_err());
+
+        cfg.shared_state_path = Some(state_file.path().to_str().unwrap().to_string());
+        let group1 = Arc::new(cfg.build_group().unwrap());
+        let group2 = Arc::new(cfg.build_group().unwrap());
+        let mut l1 = RateLimiter::default();
+        let mut l2 = RateLimiter::default();
+        l1.set_group(group1);
+        l2.set_group(group2);
+        assert!(l1.consume(10, TokenType::Ops));
+        assert!(!l2.consume(1, TokenType::Ops));
+    }
+
+    #[test]
+    fn test_error_messages() {
+        assert_eq!(
+            format!("{}", RateLimitGroupError::UpdateNotAllowedPostBoot),
+            "The update operation is not allowed after boot."
+        );
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::io;
+
+use device_manager;
+use memory_model::GuestMemoryError;
+use sys_util;
+use vmm_config::instance_info::StartMicrovmError;
+use vmm_config::vm_state::VmStateError;
+use vstate;
+
+/// Strongly typed data structure used to create a snapshot of the microVM or to restore the
+/// microVM from a snapshot.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SnapshotConfig {
+    /// Path of the snapshot file on the host.
+    pub snapshot_path: String,
+}
+
+/// Errors associated with creating or loading a snapshot.
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// Cannot restore the state of the MMIO devices.
+    DeviceState(device_manager::mmio::Error),
+    /// Cannot complete the requests a drive has in flight.
+    DrainBlockDevice(sys_util::Error),
+    /// Cannot save or restore the guest memory.
+    GuestMemory(GuestMemoryError),
+    /// The snapshot file is not a valid microVM snapshot.
+    InvalidSnapshot(String),
+    /// A snapshot can only be loaded before the microVM is started.
+    MicroVMAlreadyRunning,
+    /// A snapshot can only be created while the microVM is running.
+    MicroVMNotRunning,
+    /// Cannot restore the microVM from the snapshot.
+    Restore(StartMicrovmError),
+    /// Cannot create, read from or write to the snapshot file.
+    SnapshotFile(io::Error),
+    /// Cannot save or restore the state of a vCPU.
+    VcpuState(vstate::Error),
+    /// The vCPUs could not be paused, resumed or queried.
+    Vcpus(VmStateError),
+    /// The position of the host kernel in the queues of a vhost-net interface is not saved.
+    VhostNet,
+    /// Cannot save or restore the state of the in-kernel irqchip, PIT or clock.
+    VmState(vstate::Error),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::SnapshotError::*;
+        match *self {
+            DeviceState(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "Cannot restore the state of the devices. {}", err_msg)
+            }
+            DrainBlockDevice(ref err) => write!(
+                f,
+                "Cannot complete the requests in flight of a drive. {:?}",
+                err
+            ),
+            GuestMemory(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "Cannot save or restore the guest memory. {}", err_msg)
+            }
+            InvalidSnapshot(ref err) => write!(f, "Invalid snapshot file: {}", err),
+            MicroVMAlreadyRunning => write!(
+                f,
+                "A snapshot can only be loaded before the microVM is started."
+            ),
+            MicroVMNotRunning => write!(
+                f,
+                "A snapshot can only be created while the microVM is running."
+            ),
+            Restore(ref err) => write!(f, "Cannot restore the microVM. {}", err),
+            SnapshotFile(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "Cannot access the snapshot file. {}", err_msg)
+            }
+            VcpuState(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "Cannot save or restore the state of a vCPU. {}", err_msg)
+            }
+            Vcpus(ref err) => write!(f, "Cannot pause, resume or query the vCPUs. {}", err),
+            VhostNet => write!(
+                f,
+                "A snapshot cannot be created while a network interface uses vhost-net."
+            ),
+            VmState(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "Cannot save or restore the VM state. {}", err_msg)
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+use std::fmt::{Display, Formatter, Result};
+use std::io;
+
+use vstate;
+
+/// The states a running microVM can be put in through the API.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum VmStateUpdate {
+    /// The vCPUs are parked and the devices stop processing their queues.
+    Paused,
+    /// The microVM continues running from where it was paused.
+    Resumed,
+}
+
+/// Strongly typed data structure used to pause or resume the microVM.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct VmStateConfig {
+    /// The requested state of the microVM.
+    pub state: VmStateUpdate,
+}
+
+/// Errors associated with pausing and resuming the microVM.
+#[derive(Debug)]
+pub enum VmStateError {
+    /// The microVM is already paused.
+    MicroVMAlreadyPaused,
+    /// The microVM is not paused.
+    MicroVMNotPaused,
+    /// The microVM was not started.
+    MicroVMNotRunning,
+    /// A vCPU could not carry out the request.
+    Vcpu(vstate::Error),
+    /// The vCPUs did not respond in time.
+    VcpuResponse,
+    /// Cannot save or restore the guest clock.
+    VmClock(vstate::Error),
+    /// Cannot stop or resume the processing of the device events.
+    DeviceEvents(io::Error),
+}
+
+impl Display for VmStateError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        use self::VmStateError::*;
+        match *self {
+            MicroVMAlreadyPaused => write!(f, "The microVM is already paused."),
+            MicroVMNotPaused => write!(f, "The microVM is not paused."),
+            MicroVMNotRunning => write!(f, "The microVM is not running."),
+            Vcpu(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "A vCPU could not carry out the request. {}", err_msg)
+            }
+            VcpuResponse => write!(f, "The vCPUs did not respond in time."),
+            VmClock(ref err) => {
+                let mut err_msg = format!("{:?}", err);
+                err_msg = err_msg.replace("\"", "");
+
+                write!(f, "Cannot save or restore the guest clock. {}", err_msg)
+            }
+            DeviceEvents(ref err) => write!(
+                f,
+                "Cannot stop or resume the processing of the device events. {}",
+                err
+            ),
+        }
+    }
+}
@@ -15,11 +15,16 @@ use std::result;
 use super::KvmContext;
 use cpuid::{c3_template, filter_cpuid, t2_template};
 use kvm::*;
+use kvm_gen::{
+    kvm_clock_data, kvm_fpu, kvm_irqchip, kvm_lapic_state, kvm_msr_entry, kvm_pit_state2, kvm_regs,
+    kvm_sregs, KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE,
+};
 use logger::{LogOption, LOGGER};
 use memory_model::{GuestAddress, GuestMemory, GuestMemoryError};
+use snapshot::pod;
 use sys_util::EventFd;
 use vmm_config::machine_config::{CpuFeaturesTemplate, VmConfig};
-use x86_64::{interrupts, regs};
+use x86_64::{interrupts, regs, BootProtocol};
 
 pub const KVM_TSS_ADDRESS: usize = 0xfffbd000;
 const KVM_MEM_LOG_DIRTY_PAGES: u32 = 0x1;
@@ -61,6 +66,16 @@ pub enum Error {
     FPUConfiguration(regs::Error),
     /// Cannot configure the IRQ.
     Irq(sys_util::Error),
+    /// Cannot retrieve the state of the VCPU.
+    VcpuGetState(sys_util::Error),
+    /// Cannot restore the state of the VCPU.
+    VcpuSetState(sys_util::Error),
+    /// Error reading or writing the MSR registers of a VCPU.
+    MSRSState(regs::Error),
+    /// Cannot retrieve the state of the in-kernel irqchip, PIT or clock.
+    VmGetState(sys_util::Error),
+    /// Cannot restore the state of the in-kernel irqchip, PIT or clock.
+    VmSetState(sys_util::Error),
 }
 pub type Result<T> = result::Result<T, Error>;
 
@@ -70,6 +85,59 @@ impl ::std::convert::From<sys_util::Error> for Error {
     }
 }
 
+/// The state of the in-kernel devices of a VM.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct VmState {
+    #[serde(with = "pod")]
+    pic_master: kvm_irqchip,
+    #[serde(with = "pod")]
+    pic_slave: kvm_irqchip,
+    #[serde(with = "pod")]
+    ioapic: kvm_irqchip,
+    #[serde(with = "pod")]
+    pit: kvm_pit_state2,
+    #[serde(with = "pod")]
+    clock: kvm_clock_data,
+}
+
+/// The architectural state of a VCPU.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct VcpuState {
+    #[serde(with = "pod")]
+    regs: kvm_regs,
+    #[serde(with = "pod")]
+    sregs: kvm_sregs,
+    #[serde(with = "pod")]
+    fpu: kvm_fpu,
+    #[serde(with = "pod")]
+    lapic: kvm_lapic_state,
+    #[serde(with = "pod::vec")]
+    msrs: Vec<kvm_msr_entry>,
+}
+
+/// Requests sent by the VMM thread to a VCPU thread.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VcpuEvent {
+    /// Stop running guest code until a `Resume` is received.
+    Pause,
+    /// Continue running guest code.
+    Resume,
+    /// Send back the state of the VCPU. Only valid while paused.
+    SaveState,
+}
+
+/// Replies sent by a VCPU thread to the VMM thread, one for each `VcpuEvent`.
+pub enum VcpuResponse {
+    /// The VCPU is paused.
+    Paused,
+    /// The VCPU is running again.
+    Resumed,
+    /// The state of the VCPU.
+    SavedState(Box<VcpuState>),
+    /// The request could not be carried out.
+    Error(Error),
+}
+
 /// A wrapper around creating and using a VM.
 pub struct Vm {
     fd: VmFd,
@@ -121,12 +189,22 @@ impl Vm {
         Ok(())
     }
 
-    /// This function creates the irq chip and adds 2 interrupt events to the IRQ.
-    pub fn setup_irqchip(&self, com_evt_1_3: &EventFd, com_evt_2_4: &EventFd) -> Result<()> {
+    /// This function creates the irq chip and adds 4 interrupt events to the IRQ.
+    pub fn setup_irqchip(
+        &self,
+        com_evt_1_3: &EventFd,
+        com_evt_2_4: &EventFd,
+        kbd_evt: &EventFd,
+        acpi_hotplug_evt: &EventFd,
+    ) -> Result<()> {
         self.fd.create_irq_chip().map_err(Error::VmSetup)?;
 
         self.fd.register_irqfd(com_evt_1_3, 4).map_err(Error::Irq)?;
         self.fd.register_irqfd(com_evt_2_4, 3).map_err(Error::Irq)?;
+        self.fd.register_irqfd(kbd_evt, 1).map_err(Error::Irq)?;
+        self.fd
+            .register_irqfd(acpi_hotplug_evt, x86_64::ACPI_HOTPLUG_IRQ)
+            .map_err(Error::Irq)?;
 
         Ok(())
     }
@@ -150,6 +228,66 @@ impl Vm {
     pub fn get_fd(&self) -> &VmFd {
         &self.fd
     }
+
+    /// Retrieves the state of the in-kernel irqchip, PIT and clock. The VCPUs should not be
+    /// running while this is called.
+    pub fn save_state(&self) -> Result<VmState> {
+        let mut pic_master = kvm_irqchip::default();
+        pic_master.chip_id = KVM_IRQCHIP_PIC_MASTER;
+        self.fd
+            .get_irqchip(&mut pic_master)
+            .map_err(Error::VmGetState)?;
+
+        let mut pic_slave = kvm_irqchip::default();
+        pic_slave.chip_id = KVM_IRQCHIP_PIC_SLAVE;
+        self.fd
+            .get_irqchip(&mut pic_slave)
+            .map_err(Error::VmGetState)?;
+
+        let mut ioapic = kvm_irqchip::default();
+        ioapic.chip_id = KVM_IRQCHIP_IOAPIC;
+        self.fd
+            .get_irqchip(&mut ioapic)
+            .map_err(Error::VmGetState)?;
+
+        Ok(VmState {
+            pic_master,
+            pic_slave,
+            ioapic,
+            pit: self.fd.get_pit2().map_err(Error::VmGetState)?,
+            clock: self.get_clock()?,
+        })
+    }
+
+    /// Restores a state saved with `save_state`. The irqchip and the PIT have to be created first.
+    pub fn restore_state(&self, state: &VmState) -> Result<()> {
+        self.fd
+            .set_irqchip(&state.pic_master)
+            .map_err(Error::VmSetState)?;
+        self.fd
+            .set_irqchip(&state.pic_slave)
+            .map_err(Error::VmSetState)?;
+        self.fd
+            .set_irqchip(&state.ioapic)
+            .map_err(Error::VmSetState)?;
+        self.fd.set_pit2(&state.pit).map_err(Error::VmSetState)?;
+        self.set_clock(&state.clock)
+    }
+
+    /// Retrieves the value of the guest clock.
+    pub fn get_clock(&self) -> Result<kvm_clock_data> {
+        self.fd.get_clock().map_err(Error::VmGetState)
+    }
+
+    /// Sets the guest clock to a value returned by `get_clock`.
+    pub fn set_clock(&self, clock: &kvm_clock_data) -> Result<()> {
+        // The clock is only allowed to be set while the flags are clear.
+        let clock = kvm_clock_data {
+            clock: clock.clock,
+            ..Default::default()
+        };
+        self.fd.set_clock(&clock).map_err(Error::VmSetState)
+    }
 }
 
 /// A wrapper around creating and using a kvm-based VCPU.
@@ -178,13 +316,34 @@ impl Vcpu {
     /// # Arguments
     ///
     /// * `kernel_load_offset` - Offset from `guest_mem` at which the kernel starts.
+    /// * `boot_prot` - The protocol through which the kernel is booted.
     /// nr cpus is required for checking populating the kvm_cpuid2 entry for ebx and edx registers
     pub fn configure(
         &mut self,
         machine_config: &VmConfig,
         kernel_start_addr: GuestAddress,
+        boot_prot: BootProtocol,
         vm: &Vm,
     ) -> Result<()> {
+        self.configure_cpuid(machine_config)?;
+
+        regs::setup_msrs(&self.fd).map_err(Error::MSRSConfiguration)?;
+        // Safe to unwrap because this method is called after the VM is configured
+        let vm_memory = vm
+            .get_memory()
+            .ok_or(Error::GuestMemory(GuestMemoryError::MemoryNotInitialized))?;
+        regs::setup_regs(&self.fd, kernel_start_addr.offset() as u64, boot_prot)
+            .map_err(Error::REGSConfiguration)?;
+        regs::setup_fpu(&self.fd).map_err(Error::FPUConfiguration)?;
+        regs::setup_sregs(vm_memory, &self.fd, boot_prot).map_err(Error::SREGSConfiguration)?;
+        interrupts::set_lint(&self.fd).map_err(Error::LocalIntConfiguration)?;
+        Ok(())
+    }
+
+    /// Sets up the CPUID of the vcpu according to `machine_config`. This is the only part of
+    /// `configure` that also applies to a vcpu restored from a snapshot, as all the registers
+    /// are overwritten by `restore_state`.
+    pub fn configure_cpuid(&mut self, machine_config: &VmConfig) -> Result<()> {
         // the MachineConfiguration has defaults for ht_enabled and vcpu_count.
         filter_cpuid(
             self.id,
@@ -210,22 +369,33 @@ impl Vcpu {
         self.fd
             .set_cpuid2(&self.cpuid)
             .map_err(Error::SetSupportedCpusFailed)?;
+        Ok(())
+    }
 
-        regs::setup_msrs(&self.fd).map_err(Error::MSRSConfiguration)?;
-        // Safe to unwrap because this method is called after the VM is configured
-        let vm_memory = vm
-            .get_memory()
-            .ok_or(Error::GuestMemory(GuestMemoryError::MemoryNotInitialized))?;
-        regs::setup_regs(
-            &self.fd,
-            kernel_start_addr.offset() as u64,
-            x86_64::layout::BOOT_STACK_POINTER as u64,
-            x86_64::layout::ZERO_PAGE_START as u64,
-        )
-        .map_err(Error::REGSConfiguration)?;
-        regs::setup_fpu(&self.fd).map_err(Error::FPUConfiguration)?;
-        regs::setup_sregs(vm_memory, &self.fd).map_err(Error::SREGSConfiguration)?;
-        interrupts::set_lint(&self.fd).map_err(Error::LocalIntConfiguration)?;
+    /// Retrieves the registers, the LAPIC state and the MSRs of the vcpu. Has to be called from
+    /// the vcpu's thread while it is not running guest code.
+    pub fn save_state(&self) -> Result<VcpuState> {
+        Ok(VcpuState {
+            regs: self.fd.get_regs().map_err(Error::VcpuGetState)?,
+            sregs: self.fd.get_sregs().map_err(Error::VcpuGetState)?,
+            fpu: self.fd.get_fpu().map_err(Error::VcpuGetState)?,
+            lapic: self.fd.get_lapic().map_err(Error::VcpuGetState)?,
+            msrs: regs::get_msrs(&self.fd).map_err(Error::MSRSState)?,
+        })
+    }
+
+    /// Restores a state saved with `save_state`. The CPUID has to be configured first.
+    pub fn restore_state(&self, state: &VcpuState) -> Result<()> {
+        // The special registers carry the LAPIC base, so they go before the LAPIC itself.
+        self.fd
+            .set_sregs(&state.sregs)
+            .map_err(Error::VcpuSetState)?;
+        self.fd.set_regs(&state.regs).map_err(Error::VcpuSetState)?;
+        self.fd.set_fpu(&state.fpu).map_err(Error::VcpuSetState)?;
+        regs::set_msrs(&self.fd, &state.msrs).map_err(Error::MSRSState)?;
+        self.fd
+            .set_lapic(&state.lapic)
+            .map_err(Error::VcpuSetState)?;
         Ok(())
     }
 
@@ -286,24 +456,109 @@ mod tests {
         assert!(vm.memory_init(gm, &kvm).is_ok());
         let dummy_eventfd_1 = EventFd::new().unwrap();
         let dummy_eventfd_2 = EventFd::new().unwrap();
-
-        vm.setup_irqchip(&dummy_eventfd_1, &dummy_eventfd_2)
-            .unwrap();
+        let dummy_kbd_eventfd = EventFd::new().unwrap();
+        let dummy_hotplug_eventfd = EventFd::new().unwrap();
+
+        vm.setup_irqchip(
+            &dummy_eventfd_1,
+            &dummy_eventfd_2,
+            &dummy_kbd_eventfd,
+            &dummy_hotplug_eventfd,
+        )
+        .unwrap();
         vm.create_pit().unwrap();
 
         let mut vcpu = Vcpu::new(1, &vm).unwrap();
         let vm_config = VmConfig::default();
-        assert!(vcpu.configure(&vm_config, GuestAddress(0), &vm).is_ok());
+        assert!(vcpu
+            .configure(&vm_config, GuestAddress(0), BootProtocol::LinuxBoot, &vm)
+            .is_ok());
 
         // Test configure while using the T2 template.
         let mut vm_config = VmConfig::default();
         vm_config.cpu_template = Some(CpuFeaturesTemplate::T2);
-        assert!(vcpu.configure(&vm_config, GuestAddress(0), &vm).is_ok());
+        assert!(vcpu
+            .configure(&vm_config, GuestAddress(0), BootProtocol::LinuxBoot, &vm)
+            .is_ok());
 
         // Test configure while using the C3 template.
         let mut vm_config = VmConfig::default();
         vm_config.cpu_template = Some(CpuFeaturesTemplate::C3);
-        assert!(vcpu.configure(&vm_config, GuestAddress(0), &vm).is_ok());
+        assert!(vcpu
+            .configure(&vm_config, GuestAddress(0), BootProtocol::LinuxBoot, &vm)
+            .is_ok());
+
+        // Test configure for booting through PVH.
+        let vm_config = VmConfig::default();
+        assert!(vcpu
+            .configure(&vm_config, GuestAddress(0), BootProtocol::PvhBoot, &vm)
+            .is_ok());
+        let sregs = vcpu.fd.get_sregs().unwrap();
+        assert_eq!(sregs.cr0 & 0x8000_0001, 0x1);
+        assert_eq!(sregs.efer, 0);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_save_restore_state() {
+        let kvm_fd = Kvm::new().unwrap();
+        let kvm = KvmContext::new(Some(kvm_fd.as_raw_fd())).unwrap();
+        let gm = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        let mut vm = Vm::new(&kvm_fd).expect("new vm failed");
+        assert!(vm.memory_init(gm.clone(), &kvm).is_ok());
+
+        // The irqchip and the PIT have to exist.
+        assert!(vm.save_state().is_err());
+
+        let dummy_eventfd_1 = EventFd::new().unwrap();
+        let dummy_eventfd_2 = EventFd::new().unwrap();
+        let dummy_kbd_eventfd = EventFd::new().unwrap();
+        let dummy_hotplug_eventfd = EventFd::new().unwrap();
+        vm.setup_irqchip(
+            &dummy_eventfd_1,
+            &dummy_eventfd_2,
+            &dummy_kbd_eventfd,
+            &dummy_hotplug_eventfd,
+        )
+        .unwrap();
+        vm.create_pit().unwrap();
+        let vm_state = vm.save_state().unwrap();
+
+        let mut vcpu = Vcpu::new(0, &vm).unwrap();
+        let vm_config = VmConfig::default();
+        assert!(vcpu
+            .configure(&vm_config, GuestAddress(0), BootProtocol::LinuxBoot, &vm)
+            .is_ok());
+        let vcpu_state = vcpu.save_state().unwrap();
+
+        // Restore in a fresh VM, without configuring the registers of the vcpu.
+        let mut restored_vm = Vm::new(&kvm_fd).expect("new vm failed");
+        assert!(restored_vm.memory_init(gm, &kvm).is_ok());
+        let dummy_eventfd_3 = EventFd::new().unwrap();
+        let dummy_eventfd_4 = EventFd::new().unwrap();
+        let dummy_kbd_eventfd_2 = EventFd::new().unwrap();
+        let dummy_hotplug_eventfd_2 = EventFd::new().unwrap();
+        restored_vm
+            .setup_irqchip(
+                &dummy_eventfd_3,
+                &dummy_eventfd_4,
+                &dummy_kbd_eventfd_2,
+                &dummy_hotplug_eventfd_2,
+            )
+            .unwrap();
+        restored_vm.create_pit().unwrap();
+        assert!(restored_vm.restore_state(&vm_state).is_ok());
+
+        let mut restored_vcpu = Vcpu::new(0, &restored_vm).unwrap();
+        assert!(restored_vcpu.configure_cpuid(&vm_config).is_ok());
+        assert!(restored_vcpu.restore_state(&vcpu_state).is_ok());
+
+        let restored_state = restored_vcpu.save_state().unwrap();
+        assert_eq!(restored_state.regs.rip, vcpu_state.regs.rip);
+        assert_eq!(restored_state.regs.rsp, vcpu_state.regs.rsp);
+        assert_eq!(restored_state.sregs.cr0, vcpu_state.sregs.cr0);
+        assert_eq!(restored_state.sregs.cr3, vcpu_state.sregs.cr3);
+        assert_eq!(restored_state.msrs.len(), vcpu_state.msrs.len());
     }
 
     #[test]
@@ -0,0 +1,395 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! Encoding of the few ACPI Machine Language (AML) objects needed to describe the microVM in
+//! the DSDT. See chapter 20 of the ACPI specification for the grammar.
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+const ZERO_OP: u8 = 0x00;
+const ONE_OP: u8 = 0x01;
+const NAME_OP: u8 = 0x08;
+const BYTE_PREFIX: u8 = 0x0a;
+const WORD_PREFIX: u8 = 0x0b;
+const DWORD_PREFIX: u8 = 0x0c;
+const STRING_PREFIX: u8 = 0x0d;
+const QWORD_PREFIX: u8 = 0x0e;
+const SCOPE_OP: u8 = 0x10;
+const BUFFER_OP: u8 = 0x11;
+const PACKAGE_OP: u8 = 0x12;
+const METHOD_OP: u8 = 0x14;
+const ROOT_CHAR: u8 = 0x5c;
+const EXT_OP_PREFIX: u8 = 0x5b;
+const LOCAL0_OP: u8 = 0x60;
+const STORE_OP: u8 = 0x70;
+const AND_OP: u8 = 0x7b;
+const OP_REGION_OP: u8 = 0x80;
+const FIELD_OP: u8 = 0x81;
+const DEVICE_OP: u8 = 0x82;
+const NOTIFY_OP: u8 = 0x86;
+const IF_OP: u8 = 0xa0;
+const ELSE_OP: u8 = 0xa1;
+const RETURN_OP: u8 = 0xa4;
+
+// The address space of an operation region in the I/O port space.
+const SYSTEM_IO: u8 = 0x01;
+// Field flags: the field is accessed 32 bits at a time.
+const DWORD_ACC: u8 = 0x03;
+
+// Resource descriptors, see section 6.4 of the ACPI specification.
+const END_TAG: u8 = 0x79;
+const MEMORY32_FIXED: u8 = 0x86;
+const EXTENDED_INTERRUPT: u8 = 0x89;
+
+/// Encodes the length of a package, which counts the bytes of the encoding itself too.
+fn pkg_length(len: usize) -> Vec<u8> {
+    // The first byte holds the number of bytes that follow in its two top bits. When there are
+    // any, it only holds the low nibble of the length.
+    if len + 1 < 1 << 6 {
+        return vec![(len + 1) as u8];
+    }
+    let (count, total) = if len + 2 < 1 << 12 {
+        (1, len + 2)
+    } else if len + 3 < 1 << 20 {
+        (2, len + 3)
+    } else {
+        (3, len + 4)
+    };
+    let mut bytes = vec![(count << 6) as u8 | (total & 0xf) as u8];
+    for i in 0..count {
+        bytes.push((total >> (4 + 8 * i)) as u8);
+    }
+    bytes
+}
+
+fn name_seg(name: &str) -> Vec<u8> {
+    assert_eq!(name.len(), 4, "AML names are made of exactly 4 characters");
+    name.as_bytes().to_vec()
+}
+
+// Prepends `op` and the length of `body` to `body`.
+fn with_pkg_length(op: &[u8], body: Vec<u8>) -> Vec<u8> {
+    let mut bytes = op.to_vec();
+    bytes.extend(pkg_length(body.len()));
+    bytes.extend(body);
+    bytes
+}
+
+/// An integer, in its shortest encoding.
+pub fn integer(val: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    // Writing to a vector cannot fail.
+    match val {
+        0 => bytes.push(ZERO_OP),
+        1 => bytes.push(ONE_OP),
+        v if v <= u64::from(u8::max_value()) => {
+            bytes.push(BYTE_PREFIX);
+            bytes.push(v as u8);
+        }
+        v if v <= u64::from(u16::max_value()) => {
+            bytes.push(WORD_PREFIX);
+            bytes.write_u16::<LittleEndian>(v as u16).unwrap();
+        }
+        v if v <= u64::from(u32::max_value()) => {
+            bytes.push(DWORD_PREFIX);
+            bytes.write_u32::<LittleEndian>(v as u32).unwrap();
+        }
+        v => {
+            bytes.push(QWORD_PREFIX);
+            bytes.write_u64::<LittleEndian>(v).unwrap();
+        }
+    }
+    bytes
+}
+
+/// A null terminated ASCII string.
+pub fn string(val: &str) -> Vec<u8> {
+    let mut bytes = vec![STRING_PREFIX];
+    bytes.extend_from_slice(val.as_bytes());
+    bytes.push(0);
+    bytes
+}
+
+/// `Name(name, value)`, which binds `value` to `name` in the current scope.
+pub fn name(name: &str, value: Vec<u8>) -> Vec<u8> {
+    let mut bytes = vec![NAME_OP];
+    bytes.extend(name_seg(name));
+    bytes.extend(value);
+    bytes
+}
+
+/// `Package() { elements }`.
+pub fn package(elements: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut body = vec![elements.len() as u8];
+    for element in elements {
+        body.extend(element);
+    }
+    with_pkg_length(&[PACKAGE_OP], body)
+}
+
+/// `Scope(\name) { children }`, for a scope right below the root of the namespace.
+pub fn scope(name: &str, children: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut body = vec![ROOT_CHAR];
+    body.extend(name_seg(name));
+    for child in children {
+        body.extend(child);
+    }
+    with_pkg_length(&[SCOPE_OP], body)
+}
+
+/// `Device(name) { children }`.
+pub fn device(name: &str, children: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut body = name_seg(name);
+    for child in children {
+        body.extend(child);
+    }
+    with_pkg_length(&[EXT_OP_PREFIX, DEVICE_OP], body)
+}
+
+/// A name in the current scope, or in one of its parents, used as a value or a target.
+pub fn path(name: &str) -> Vec<u8> {
+    name_seg(name)
+}
+
+/// The first local variable of a method, `Local0`.
+pub fn local0() -> Vec<u8> {
+    vec![LOCAL0_OP]
+}
+
+/// `Method(name, args, Serialized/NotSerialized) { terms }`.
+pub fn method(name: &str, args: u8, serialized: bool, terms: Vec<Vec<u8>>) -> Vec<u8> {
+    assert!(args < 8, "AML methods take at most 7 arguments");
+    let mut body = name_seg(name);
+    body.push(args | if serialized { 1 << 3 } else { 0 });
+    for term in terms {
+        body.extend(term);
+    }
+    with_pkg_length(&[METHOD_OP], body)
+}
+
+/// `If(predicate) { terms }`.
+pub fn if_(predicate: Vec<u8>, terms: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut body = predicate;
+    for term in terms {
+        body.extend(term);
+    }
+    with_pkg_length(&[IF_OP], body)
+}
+
+/// `Else { terms }`, which has to follow an `If`.
+pub fn else_(terms: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut body = Vec::new();
+    for term in terms {
+        body.extend(term);
+    }
+    with_pkg_length(&[ELSE_OP], body)
+}
+
+/// `And(left, right)`, without storing the result anywhere.
+pub fn and(left: Vec<u8>, right: Vec<u8>) -> Vec<u8> {
+    let mut bytes = vec![AND_OP];
+    bytes.extend(left);
+    bytes.extend(right);
+    // A null name as the target discards the result.
+    bytes.push(ZERO_OP);
+    bytes
+}
+
+/// `Store(value, target)`.
+pub fn store(value: Vec<u8>, target: Vec<u8>) -> Vec<u8> {
+    let mut bytes = vec![STORE_OP];
+    bytes.extend(value);
+    bytes.extend(target);
+    bytes
+}
+
+/// `Notify(name, value)`, which sends a notification about the device `name` to the OS.
+pub fn notify(name: &str, value: u64) -> Vec<u8> {
+    let mut bytes = vec![NOTIFY_OP];
+    bytes.extend(name_seg(name));
+    bytes.extend(integer(value));
+    bytes
+}
+
+/// `Return(value)`.
+pub fn return_(value: Vec<u8>) -> Vec<u8> {
+    let mut bytes = vec![RETURN_OP];
+    bytes.extend(value);
+    bytes
+}
+
+/// `OperationRegion(name, SystemIO, port, len)`.
+pub fn io_region(name: &str, port: u16, len: u8) -> Vec<u8> {
+    let mut bytes = vec![EXT_OP_PREFIX, OP_REGION_OP];
+    bytes.extend(name_seg(name));
+    bytes.push(SYSTEM_IO);
+    bytes.extend(integer(u64::from(port)));
+    bytes.extend(integer(u64::from(len)));
+    bytes
+}
+
+/// `Field(region, DWordAcc, NoLock, Preserve) { name, bits, ... }`.
+pub fn field(region: &str, fields: &[(&str, u8)]) -> Vec<u8> {
+    let mut body = name_seg(region);
+    body.push(DWORD_ACC);
+    for &(name, bits) in fields {
+        assert!(
+            bits < 1 << 6,
+            "the field lengths are encoded on a single byte"
+        );
+        body.extend(name_seg(name));
+        body.push(bits);
+    }
+    with_pkg_length(&[EXT_OP_PREFIX, FIELD_OP], body)
+}
+
+/// `ResourceTemplate() { descriptors }`, a buffer of resource descriptors.
+pub fn resource_template(descriptors: Vec<Vec<u8>>) -> Vec<u8> {
+    let mut resources = Vec::new();
+    for descriptor in descriptors {
+        resources.extend(descriptor);
+    }
+    // A zero checksum tells the OS not to verify it.
+    resources.extend_from_slice(&[END_TAG, 0]);
+
+    let mut body = integer(resources.len() as u64);
+    body.extend(resources);
+    with_pkg_length(&[BUFFER_OP], body)
+}
+
+/// `Memory32Fixed(ReadWrite, base, len)`.
+pub fn memory32_fixed(base: u32, len: u32) -> Vec<u8> {
+    let mut bytes = vec![MEMORY32_FIXED, 9, 0, 1];
+    bytes.write_u32::<LittleEndian>(base).unwrap();
+    bytes.write_u32::<LittleEndian>(len).unwrap();
+    bytes
+}
+
+/// `Interrupt(ResourceConsumer, Edge, ActiveHigh, Exclusive) { irq }`.
+pub fn interrupt(irq: u32) -> Vec<u8> {
+    // The flags are: consumer (bit 0) and edge triggered (bit 1).
+    let mut bytes = vec![EXTENDED_INTERRUPT, 6, 0, 0x03, 1];
+    bytes.write_u32::<LittleEndian>(irq).unwrap();
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkg_length() {
+        assert_eq!(pkg_length(0x3e), vec![0x3f]);
+        assert_eq!(pkg_length(0x3f), vec![0x41, 0x04]);
+        assert_eq!(pkg_length(0xffd), vec![0x4f, 0xff]);
+        assert_eq!(pkg_length(0xffe), vec![0x81, 0x00, 0x01]);
+        assert_eq!(pkg_length(0x1_0000), vec![0x83, 0x00, 0x10]);
+    }
+
+    #[test]
+    fn test_integer() {
+        assert_eq!(integer(0), vec![0x00]);
+        assert_eq!(integer(1), vec![0x01]);
+        assert_eq!(integer(5), vec![0x0a, 0x05]);
+        assert_eq!(integer(0x1234), vec![0x0b, 0x34, 0x12]);
+        assert_eq!(integer(0xd000_0000), vec![0x0c, 0x00, 0x00, 0x00, 0xd0]);
+        assert_eq!(
+            integer(0x1_0000_0000),
+            vec![0x0e, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_device() {
+        // Device(V000) { Name(_HID, "LNRO0005") Name(_UID, Zero) Name(_CRS, ResourceTemplate() {
+        //     Memory32Fixed(ReadWrite, 0xD0000000, 0x1000)
+        //     Interrupt(ResourceConsumer, Edge, ActiveHigh, Exclusive) { 5 } }) }
+        let expected = vec![
+            0x5b, 0x82, 0x3a, 0x56, 0x30, 0x30, 0x30, 0x08, 0x5f, 0x48, 0x49, 0x44, 0x0d, 0x4c,
+            0x4e, 0x52, 0x4f, 0x30, 0x30, 0x30, 0x35, 0x00, 0x08, 0x5f, 0x55, 0x49, 0x44, 0x00,
+            0x08, 0x5f, 0x43, 0x52, 0x53, 0x11, 0x1a, 0x0a, 0x17, 0x86, 0x09, 0x00, 0x01, 0x00,
+            0x00, 0x00, 0xd0, 0x00, 0x10, 0x00, 0x00, 0x89, 0x06, 0x00, 0x03, 0x01, 0x05, 0x00,
+            0x00, 0x00, 0x79, 0x00,
+        ];
+        let device = device(
+            "V000",
+            vec![
+                name("_HID", string("LNRO0005")),
+                name("_UID", integer(0)),
+                name(
+                    "_CRS",
+                    resource_template(vec![memory32_fixed(0xd000_0000, 0x1000), interrupt(5)]),
+                ),
+            ],
+        );
+        assert_eq!(device, expected);
+    }
+
+    #[test]
+    fn test_method() {
+        // Method(_STA, 0, NotSerialized) { If(And(PRES, 0x04)) { Return(0x0F) } Return(Zero) }
+        let expected = vec![
+            0x14, 0x15, 0x5f, 0x53, 0x54, 0x41, 0x00, 0xa0, 0x0c, 0x7b, 0x50, 0x52, 0x45, 0x53,
+            0x0a, 0x04, 0x00, 0xa4, 0x0a, 0x0f, 0xa4, 0x00,
+        ];
+        let sta = method(
+            "_STA",
+            0,
+            false,
+            vec![
+                if_(and(path("PRES"), integer(4)), vec![return_(integer(0x0f))]),
+                return_(integer(0)),
+            ],
+        );
+        assert_eq!(sta, expected);
+
+        // Method(_EVT, 1, Serialized) { Store(CHNG, Local0) If(One) { Notify(V000, 1) }
+        //     Else { Notify(V000, 3) } }
+        let expected = vec![
+            0x14, 0x1e, 0x5f, 0x45, 0x56, 0x54, 0x09, 0x70, 0x43, 0x48, 0x4e, 0x47, 0x60, 0xa0,
+            0x08, 0x01, 0x86, 0x56, 0x30, 0x30, 0x30, 0x01, 0xa1, 0x08, 0x86, 0x56, 0x30, 0x30,
+            0x30, 0x0a, 0x03,
+        ];
+        let evt = method(
+            "_EVT",
+            1,
+            true,
+            vec![
+                store(path("CHNG"), local0()),
+                if_(integer(1), vec![notify("V000", 1)]),
+                else_(vec![notify("V000", 3)]),
+            ],
+        );
+        assert_eq!(evt, expected);
+    }
+
+    #[test]
+    fn test_io_region_and_field() {
+        // OperationRegion(HPRG, SystemIO, 0x0610, 8) Field(HPRG, DWordAcc, NoLock, Preserve) {
+        //     PRES, 32, CHNG, 32 }
+        assert_eq!(
+            io_region("HPRG", 0x610, 8),
+            vec![0x5b, 0x80, 0x48, 0x50, 0x52, 0x47, 0x01, 0x0b, 0x10, 0x06, 0x0a, 0x08]
+        );
+        assert_eq!(
+            field("HPRG", &[("PRES", 32), ("CHNG", 32)]),
+            vec![
+                0x5b, 0x81, 0x10, 0x48, 0x50, 0x52, 0x47, 0x03, 0x50, 0x52, 0x45, 0x53, 0x20, 0x43,
+                0x48, 0x4e, 0x47, 0x20
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scope_and_package() {
+        // Name(_S5_, Package() { 5 }) Scope(\_SB_) {}
+        assert_eq!(
+            name("_S5_", package(vec![integer(5)])),
+            vec![0x08, 0x5f, 0x53, 0x35, 0x5f, 0x12, 0x04, 0x01, 0x0a, 0x05]
+        );
+        assert_eq!(
+            scope("_SB_", vec![]),
+            vec![0x10, 0x06, 0x5c, 0x5f, 0x53, 0x42, 0x5f]
+        );
+    }
+}
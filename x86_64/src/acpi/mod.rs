@@ -0,0 +1,451 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+//! ACPI tables describing the microVM. The RSDP points to the XSDT, which lists the FADT and the
+//! MADT, and the FADT points to the DSDT. The platform is hardware-reduced: the only fixed
+//! hardware is the sleep control register, through which the guest powers itself off.
+
+mod aml;
+
+use std::cmp;
+use std::result;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use layout;
+use memory_model::{GuestAddress, GuestMemory};
+
+#[derive(Debug)]
+pub enum Error {
+    /// The ACPI tables do not fit between RSDP_START and HIMEM_START.
+    TablesTooLarge,
+    /// Failure to write an ACPI table to guest memory.
+    WriteTable,
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Location and interrupt of a virtio-mmio device, declared in the DSDT.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MmioDeviceInfo {
+    /// Guest physical address of the device registers.
+    pub addr: u64,
+    /// Size of the device registers.
+    pub len: u64,
+    /// Interrupt line of the device.
+    pub irq: u32,
+}
+
+/// I/O port of the sleep control and status registers. The guest powers off by writing the S5
+/// sleep type, shifted left by 2, together with the SLP_EN bit (0x20) to it.
+pub const SLEEP_CONTROL_PORT: u16 = 0x600;
+/// The sleep type of the S5 (soft off) state, as declared by the `\_S5_` object.
+const S5_SLEEP_TYPE: u8 = 5;
+
+/// I/O port of the hot-plug registers: the bitmap of the virtio-mmio slots that hold a device,
+/// followed by the bitmap of the slots that changed since it was last read and by the register
+/// the guest writes the slots it ejected to.
+pub const ACPI_HOTPLUG_PORT: u16 = 0x610;
+/// Size of the hot-plug registers.
+pub const ACPI_HOTPLUG_LEN: u8 = 12;
+/// Interrupt through which the guest is told that a slot changed.
+pub const ACPI_HOTPLUG_IRQ: u32 = 16;
+/// Number of virtio-mmio slots covered by the hot-plug registers. The devices in the slots past
+/// them are always present.
+pub const ACPI_HOTPLUG_SLOTS: usize = 32;
+
+const OEM_ID: &[u8; 6] = b"FIRECK";
+const OEM_TABLE_ID: &[u8; 8] = b"FCVMTBLS";
+const CREATOR_ID: &[u8; 4] = b"FCAT";
+const HEADER_SIZE: usize = 36;
+// The ACPI tables are aligned on 8 bytes.
+const TABLE_ALIGNMENT: usize = 8;
+
+const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec0_0000; // source: linux/arch/x86/include/asm/apicdef.h
+const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee0_0000; // source: linux/arch/x86/include/asm/apicdef.h
+
+// A System Description Table: the common header followed by the table specific data.
+struct Sdt {
+    data: Vec<u8>,
+}
+
+impl Sdt {
+    fn new(signature: &[u8; 4], revision: u8) -> Sdt {
+        let mut data = vec![0u8; HEADER_SIZE];
+        data[0..4].copy_from_slice(signature);
+        data[8] = revision;
+        data[10..16].copy_from_slice(OEM_ID);
+        data[16..24].copy_from_slice(OEM_TABLE_ID);
+        LittleEndian::write_u32(&mut data[24..28], 1);
+        data[28..32].copy_from_slice(CREATOR_ID);
+        LittleEndian::write_u32(&mut data[32..36], 1);
+        Sdt { data }
+    }
+
+    fn append(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    // Fills in the length and the checksum, which are only known once the table is complete.
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.data.len() as u32;
+        LittleEndian::write_u32(&mut self.data[4..8], len);
+        self.data[9] = checksum(&self.data);
+        self.data
+    }
+}
+
+// Returns the value that makes the bytes of `data` sum up to zero.
+fn checksum(data: &[u8]) -> u8 {
+    let sum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    (!sum).wrapping_add(1)
+}
+
+// A Generic Address Structure, for a register in the I/O port space.
+fn io_gas(port: u16, bit_width: u8) -> [u8; 12] {
+    let mut gas = [0u8; 12];
+    gas[0] = 1; // System I/O
+    gas[1] = bit_width;
+    gas[3] = 1; // Byte access
+    LittleEndian::write_u64(&mut gas[4..12], u64::from(port));
+    gas
+}
+
+fn mmio_device_name(slot: usize) -> String {
+    format!("V{:03X}", slot)
+}
+
+fn create_dsdt(mmio_devices: &[MmioDeviceInfo]) -> Vec<u8> {
+    let hotplug_slots = cmp::min(mmio_devices.len(), ACPI_HOTPLUG_SLOTS);
+
+    // The hot-plug registers: the bitmaps of the slots holding a device, of the slots that
+    // changed since the last read and of the slots the guest ejected.
+    let mut children = vec![
+        aml::io_region("HPRG", ACPI_HOTPLUG_PORT, ACPI_HOTPLUG_LEN),
+        aml::field("HPRG", &[("PRES", 32), ("CHNG", 32), ("EJCT", 32)]),
+    ];
+
+    for (i, info) in mmio_devices.iter().enumerate() {
+        let mut objects = vec![
+            aml::name("_HID", aml::string("LNRO0005")),
+            aml::name("_UID", aml::integer(i as u64)),
+            aml::name(
+                "_CRS",
+                aml::resource_template(vec![
+                    aml::memory32_fixed(info.addr as u32, info.len as u32),
+                    aml::interrupt(info.irq),
+                ]),
+            ),
+        ];
+        if i < hotplug_slots {
+            objects.push(aml::method(
+                "_STA",
+                0,
+                false,
+                vec![
+                    aml::if_(
+                        aml::and(aml::path("PRES"), aml::integer(1 << i)),
+                        vec![aml::return_(aml::integer(0x0f))],
+                    ),
+                    aml::return_(aml::integer(0)),
+                ],
+            ));
+            // The device is only removed once the guest reports it ejected.
+            objects.push(aml::method(
+                "_EJ0",
+                1,
+                false,
+                vec![aml::store(aml::integer(1 << i), aml::path("EJCT"))],
+            ));
+        }
+        children.push(aml::device(&mmio_device_name(i), objects));
+    }
+
+    // The Generic Event Device, whose interrupt tells the guest to look for the slots that
+    // changed and to check (1) or eject (3) their devices.
+    let mut events = vec![aml::store(aml::path("CHNG"), aml::local0())];
+    for i in 0..hotplug_slots {
+        let name = mmio_device_name(i);
+        events.push(aml::if_(
+            aml::and(aml::local0(), aml::integer(1 << i)),
+            vec![
+                aml::if_(
+                    aml::and(aml::path("PRES"), aml::integer(1 << i)),
+                    vec![aml::notify(&name, 1)],
+                ),
+                aml::else_(vec![aml::notify(&name, 3)]),
+            ],
+        ));
+    }
+    children.push(aml::device(
+        "GED0",
+        vec![
+            aml::name("_HID", aml::string("ACPI0013")),
+            aml::name("_UID", aml::integer(0)),
+            aml::name(
+                "_CRS",
+                aml::resource_template(vec![aml::interrupt(ACPI_HOTPLUG_IRQ)]),
+            ),
+            aml::method("_EVT", 1, true, events),
+        ],
+    ));
+
+    let mut dsdt = Sdt::new(b"DSDT", 2);
+    dsdt.append(&aml::name(
+        "_S5_",
+        aml::package(vec![aml::integer(u64::from(S5_SLEEP_TYPE))]),
+    ));
+    dsdt.append(&aml::scope("_SB_", children));
+    dsdt.finish()
+}
+
+fn create_fadt(dsdt_addr: u64) -> Vec<u8> {
+    const FADT_SIZE: usize = 276;
+    // IA-PC boot architecture flags: there is an 8042, but no VGA and no CMOS RTC.
+    const IAPC_BOOT_ARCH: u16 = (1 << 1) | (1 << 2) | (1 << 5);
+    // Fixed feature flags: the power and sleep buttons are absent and the platform is
+    // hardware-reduced.
+    const FLAGS: u32 = (1 << 4) | (1 << 5) | (1 << 20);
+
+    let mut fadt = Sdt::new(b"FACP", 6);
+    fadt.append(&[0u8; FADT_SIZE - HEADER_SIZE]);
+    {
+        let data = &mut fadt.data;
+        LittleEndian::write_u32(&mut data[40..44], dsdt_addr as u32);
+        LittleEndian::write_u16(&mut data[109..111], IAPC_BOOT_ARCH);
+        LittleEndian::write_u32(&mut data[112..116], FLAGS);
+        LittleEndian::write_u64(&mut data[140..148], dsdt_addr);
+        data[244..256].copy_from_slice(&io_gas(SLEEP_CONTROL_PORT, 8));
+        data[256..268].copy_from_slice(&io_gas(SLEEP_CONTROL_PORT, 8));
+        data[268..276].copy_from_slice(b"FIRECRKR");
+    }
+    fadt.finish()
+}
+
+fn create_madt(num_cpus: u8) -> Vec<u8> {
+    // Same IDs as in the MP table.
+    let ioapic_id = num_cpus + 1;
+
+    let mut madt = Sdt::new(b"APIC", 4);
+    let mut data = [0u8; 8];
+    LittleEndian::write_u32(&mut data[0..4], APIC_DEFAULT_PHYS_BASE);
+    // PCAT_COMPAT: there are dual 8259 PICs too.
+    LittleEndian::write_u32(&mut data[4..8], 1);
+    madt.append(&data);
+
+    for cpu_id in 0..num_cpus {
+        // Processor Local APIC: enabled, with the ACPI processor UID equal to the APIC ID.
+        madt.append(&[0, 8, cpu_id, cpu_id, 1, 0, 0, 0]);
+    }
+
+    // I/O APIC, with its inputs starting at global system interrupt 0.
+    let mut ioapic = [1, 12, ioapic_id, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    LittleEndian::write_u32(&mut ioapic[4..8], IO_APIC_DEFAULT_PHYS_BASE);
+    madt.append(&ioapic);
+
+    // Local APIC NMI, connected to LINT1 of all processors.
+    madt.append(&[4, 6, 0xff, 0, 0, 1]);
+
+    madt.finish()
+}
+
+fn create_xsdt(table_addrs: &[u64]) -> Vec<u8> {
+    let mut xsdt = Sdt::new(b"XSDT", 1);
+    for addr in table_addrs {
+        let mut entry = [0u8; 8];
+        LittleEndian::write_u64(&mut entry, *addr);
+        xsdt.append(&entry);
+    }
+    xsdt.finish()
+}
+
+fn create_rsdp(xsdt_addr: u64) -> Vec<u8> {
+    const RSDP_SIZE: usize = 36;
+
+    let mut rsdp = vec![0u8; RSDP_SIZE];
+    rsdp[0..8].copy_from_slice(b"RSD PTR ");
+    rsdp[9..15].copy_from_slice(OEM_ID);
+    rsdp[15] = 2; // ACPI 2.0 and later
+    LittleEndian::write_u32(&mut rsdp[20..24], RSDP_SIZE as u32);
+    LittleEndian::write_u64(&mut rsdp[24..32], xsdt_addr);
+    // The first checksum only covers the ACPI 1.0 part of the structure.
+    rsdp[8] = checksum(&rsdp[0..20]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+// Writes the tables one after the other, starting with the RSDP at RSDP_START.
+struct TableWriter<'a> {
+    guest_mem: &'a GuestMemory,
+    next_addr: usize,
+}
+
+impl<'a> TableWriter<'a> {
+    fn write(&mut self, table: &[u8]) -> Result<u64> {
+        let addr = self.next_addr;
+        let end = addr + table.len();
+        if end > layout::HIMEM_START {
+            return Err(Error::TablesTooLarge);
+        }
+        match self
+            .guest_mem
+            .write_slice_at_addr(table, GuestAddress(addr))
+        {
+            Ok(len) if len == table.len() => (),
+            _ => return Err(Error::WriteTable),
+        }
+        self.next_addr = (end + TABLE_ALIGNMENT - 1) & !(TABLE_ALIGNMENT - 1);
+        Ok(addr as u64)
+    }
+}
+
+/// Writes the ACPI tables for `num_cpus` vCPUs and the given virtio-mmio device slots to guest
+/// memory. The guest reads which slots hold a device from the hot-plug registers. The RSDP,
+/// which the guest looks for first, is placed at `layout::RSDP_START`.
+pub fn setup_acpi(
+    guest_mem: &GuestMemory,
+    num_cpus: u8,
+    mmio_devices: &[MmioDeviceInfo],
+) -> Result<()> {
+    // The RSDP points to the other tables, so it's only known once they are written. Leave room
+    // for it at the start.
+    let mut writer = TableWriter {
+        guest_mem,
+        next_addr: layout::RSDP_START + 0x40,
+    };
+    let dsdt_addr = writer.write(&create_dsdt(mmio_devices))?;
+    let fadt_addr = writer.write(&create_fadt(dsdt_addr))?;
+    let madt_addr = writer.write(&create_madt(num_cpus))?;
+    let xsdt_addr = writer.write(&create_xsdt(&[fadt_addr, madt_addr]))?;
+
+    writer.next_addr = layout::RSDP_START;
+    writer.write(&create_rsdp(xsdt_addr))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_table(gm: &GuestMemory, addr: u64) -> Vec<u8> {
+        let len: u32 = gm
+            .read_obj_from_addr(GuestAddress(addr as usize + 4))
+            .unwrap();
+        let mut table = vec![0u8; len as usize];
+        gm.read_slice_at_addr(&mut table, GuestAddress(addr as usize))
+            .unwrap();
+        // A valid table sums up to zero.
+        assert_eq!(checksum(&table), 0);
+        table
+    }
+
+    fn contains(table: &[u8], bytes: &[u8]) -> bool {
+        table.windows(bytes.len()).any(|window| window == bytes)
+    }
+
+    #[test]
+    fn test_setup_acpi() {
+        let gm = GuestMemory::new(&[(GuestAddress(0), layout::HIMEM_START)]).unwrap();
+        let devices = [
+            MmioDeviceInfo {
+                addr: 0xd000_0000,
+                len: 0x1000,
+                irq: 5,
+            },
+            MmioDeviceInfo {
+                addr: 0xd000_1000,
+                len: 0x1000,
+                irq: 6,
+            },
+        ];
+        setup_acpi(&gm, 2, &devices).unwrap();
+
+        let mut rsdp = [0u8; 36];
+        gm.read_slice_at_addr(&mut rsdp, GuestAddress(layout::RSDP_START))
+            .unwrap();
+        assert_eq!(&rsdp[0..8], b"RSD PTR ");
+        assert_eq!(checksum(&rsdp[0..20]), 0);
+        assert_eq!(checksum(&rsdp), 0);
+
+        let xsdt = read_table(&gm, LittleEndian::read_u64(&rsdp[24..32]));
+        assert_eq!(&xsdt[0..4], b"XSDT");
+        assert_eq!(xsdt.len(), HEADER_SIZE + 2 * 8);
+
+        let fadt = read_table(&gm, LittleEndian::read_u64(&xsdt[36..44]));
+        assert_eq!(&fadt[0..4], b"FACP");
+        assert_eq!(fadt.len(), 276);
+        assert_ne!(LittleEndian::read_u32(&fadt[112..116]) & (1 << 20), 0);
+        assert_eq!(
+            LittleEndian::read_u64(&fadt[248..256]),
+            u64::from(SLEEP_CONTROL_PORT)
+        );
+
+        // Two local APICs, one I/O APIC and one local APIC NMI.
+        let madt = read_table(&gm, LittleEndian::read_u64(&xsdt[44..52]));
+        assert_eq!(&madt[0..4], b"APIC");
+        assert_eq!(madt.len(), HEADER_SIZE + 8 + 2 * 8 + 12 + 6);
+        assert_eq!(&madt[44..48], &[0, 8, 0, 0]);
+        assert_eq!(&madt[52..56], &[0, 8, 1, 1]);
+        assert_eq!(&madt[60..63], &[1, 12, 3]);
+
+        let dsdt = read_table(&gm, LittleEndian::read_u64(&fadt[140..148]));
+        assert_eq!(&dsdt[0..4], b"DSDT");
+        let device = aml::device(
+            "V001",
+            vec![
+                aml::name("_HID", aml::string("LNRO0005")),
+                aml::name("_UID", aml::integer(1)),
+                aml::name(
+                    "_CRS",
+                    aml::resource_template(vec![
+                        aml::memory32_fixed(0xd000_1000, 0x1000),
+                        aml::interrupt(6),
+                    ]),
+                ),
+                aml::method(
+                    "_STA",
+                    0,
+                    false,
+                    vec![
+                        aml::if_(
+                            aml::and(aml::path("PRES"), aml::integer(2)),
+                            vec![aml::return_(aml::integer(0x0f))],
+                        ),
+                        aml::return_(aml::integer(0)),
+                    ],
+                ),
+                aml::method(
+                    "_EJ0",
+                    1,
+                    false,
+                    vec![aml::store(aml::integer(2), aml::path("EJCT"))],
+                ),
+            ],
+        );
+        assert!(contains(&dsdt, &device));
+        assert!(contains(
+            &dsdt,
+            &aml::io_region("HPRG", ACPI_HOTPLUG_PORT, ACPI_HOTPLUG_LEN)
+        ));
+        assert!(contains(&dsdt, &aml::string("ACPI0013")));
+        assert!(contains(&dsdt, &aml::notify("V001", 3)));
+        assert!(!contains(&dsdt, &aml::notify("V002", 3)));
+    }
+
+    #[test]
+    fn test_tables_too_large() {
+        let gm = GuestMemory::new(&[(GuestAddress(0), layout::HIMEM_START)]).unwrap();
+        let devices = vec![
+            MmioDeviceInfo {
+                addr: 0xd000_0000,
+                len: 0x1000,
+                irq: 5,
+            };
+            0x1000
+        ];
+        match setup_acpi(&gm, 1, &devices) {
+            Err(Error::TablesTooLarge) => (),
+            _ => panic!("Unexpected result"),
+        }
+    }
+}
@@ -7,8 +7,13 @@
 
 // Magic addresses used to lay out x86_64 VMs.
 
+// The PVH start info structure and the list of modules it points to.
+pub const PVH_INFO_START: usize = 0x6000;
+pub const MODLIST_START: usize = 0x6040;
 // The 'zero page', a.k.a linux kernel bootparams.
 pub const ZERO_PAGE_START: usize = 0x7000;
+// The PVH memory map. Only one boot protocol is used at a time, so it shares the zero page.
+pub const MEMMAP_START: usize = 0x7000;
 // Initial stack for the boot CPU.
 pub const BOOT_STACK_START: usize = 0x8000;
 pub const BOOT_STACK_POINTER: usize = 0x8ff0;
@@ -23,5 +28,7 @@ pub const CMDLINE_MAX_SIZE: usize = 0x10000;
 pub const MPTABLE_START: usize = 0x9fc00;
 // Where BIOS/VGA magic would live on a real PC.
 pub const EBDA_START: u64 = 0x9fc00;
+// ACPI tables, starting with the RSDP in the BIOS area where the guest looks for it.
+pub const RSDP_START: usize = 0xe0000;
 // 1MB.  We don't put anything above here except the kernel itself.
 pub const HIMEM_START: usize = 0x100000;
@@ -37,6 +37,14 @@ unsafe impl memory_model::DataInit for mpspec::mpc_table {}
 unsafe impl memory_model::DataInit for mpspec::mpc_lintsrc {}
 unsafe impl memory_model::DataInit for mpspec::mpf_intel {}
 
+#[allow(non_camel_case_types)]
+mod start_info;
+// The PVH boot structures are only data, reading them from data is a safe initialization.
+unsafe impl memory_model::DataInit for start_info::hvm_start_info {}
+unsafe impl memory_model::DataInit for start_info::hvm_modlist_entry {}
+unsafe impl memory_model::DataInit for start_info::hvm_memmap_table_entry {}
+
+mod acpi;
 mod gdt;
 pub mod interrupts;
 pub mod layout;
@@ -49,17 +57,32 @@ use std::result;
 use bootparam::boot_params;
 use bootparam::E820_RAM;
 use memory_model::{GuestAddress, GuestMemory};
-
+use start_info::{hvm_memmap_table_entry, hvm_modlist_entry, hvm_start_info};
+
+pub use acpi::Error as AcpiError;
+pub use acpi::{
+    MmioDeviceInfo, ACPI_HOTPLUG_IRQ, ACPI_HOTPLUG_LEN, ACPI_HOTPLUG_PORT, ACPI_HOTPLUG_SLOTS,
+    SLEEP_CONTROL_PORT,
+};
+pub use bootparam::setup_header as SetupHeader;
 pub use interrupts::Error as IntError;
 pub use mptable::Error as MpTableError;
 pub use regs::Error as RegError;
 
 #[derive(Debug)]
 pub enum Error {
+    /// Error writing the ACPI tables to memory.
+    AcpiSetup(AcpiError),
     /// Invalid e820 setup params.
     E820Configuration,
+    /// Error writing the PVH memory map to guest memory.
+    MemmapTableSetup,
+    /// Error writing the PVH module list to guest memory.
+    ModlistSetup,
     /// Error writing MP table to memory.
     MpTableSetup(MpTableError),
+    /// Error writing the PVH start info to guest memory.
+    StartInfoSetup,
     /// The zero page extends past the end of guest_mem.
     ZeroPagePastRamEnd,
     /// Error writing the zero page of guest memory.
@@ -67,6 +90,26 @@ pub enum Error {
 }
 pub type Result<T> = result::Result<T, Error>;
 
+/// Location of the initial ramdisk in guest memory.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct InitrdConfig {
+    /// Load address of the initrd in guest memory.
+    pub address: GuestAddress,
+    /// Size of the initrd in bytes.
+    pub size: usize,
+}
+
+/// The protocols through which a kernel can be booted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootProtocol {
+    /// The 64-bit Linux boot protocol: the vCPUs start in long mode with identity mapped page
+    /// tables and the boot parameters in the zero page.
+    LinuxBoot,
+    /// The PVH boot protocol: the vCPUs start in 32-bit protected mode, without paging, and
+    /// `rbx` points to an `hvm_start_info` structure.
+    PvhBoot,
+}
+
 const FIRST_ADDR_PAST_32BITS: usize = (1 << 32);
 const MEM_32BIT_GAP_SIZE: usize = (768 << 20);
 
@@ -109,70 +152,175 @@ pub fn get_32bit_gap_start() -> usize {
 /// * `guest_mem` - The memory to be used by the guest.
 /// * `cmdline_addr` - Address in `guest_mem` where the kernel command line was loaded.
 /// * `cmdline_size` - Size of the kernel command line in bytes including the null terminator.
-/// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `initrd` - Information about where the ramdisk image was loaded in the `guest_mem`.
+/// * `setup_header` - The setup header of a bzImage kernel, which is copied into the zero page.
+/// * `boot_prot` - The protocol through which the kernel is booted.
 pub fn configure_system(
     guest_mem: &GuestMemory,
     cmdline_addr: GuestAddress,
     cmdline_size: usize,
+    initrd: &Option<InitrdConfig>,
+    setup_header: &Option<SetupHeader>,
+    boot_prot: BootProtocol,
+) -> Result<()> {
+    match boot_prot {
+        BootProtocol::LinuxBoot => {
+            configure_64bit_boot(guest_mem, cmdline_addr, cmdline_size, initrd, setup_header)
+        }
+        BootProtocol::PvhBoot => configure_pvh(guest_mem, cmdline_addr, initrd),
+    }
+}
+
+/// Writes the ACPI tables describing the vCPUs and the virtio-mmio devices. Has to be called
+/// once per vm, after all the devices are registered.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The memory to be used by the guest.
+/// * `num_cpus` - Number of virtual CPUs the guest will have.
+/// * `mmio_devices` - The virtio-mmio devices the guest will have.
+pub fn setup_acpi(
+    guest_mem: &GuestMemory,
     num_cpus: u8,
+    mmio_devices: &[MmioDeviceInfo],
+) -> Result<()> {
+    acpi::setup_acpi(guest_mem, num_cpus, mmio_devices).map_err(Error::AcpiSetup)
+}
+
+/// Writes the legacy MP table, for guests that do not support ACPI.
+///
+/// # Arguments
+///
+/// * `guest_mem` - The memory to be used by the guest.
+/// * `num_cpus` - Number of virtual CPUs the guest will have.
+pub fn setup_mptable(guest_mem: &GuestMemory, num_cpus: u8) -> Result<()> {
+    // Note that this puts the mptable at the last 1k of Linux's 640k base RAM
+    mptable::setup_mptable(guest_mem, num_cpus).map_err(Error::MpTableSetup)
+}
+
+fn configure_64bit_boot(
+    guest_mem: &GuestMemory,
+    cmdline_addr: GuestAddress,
+    cmdline_size: usize,
+    initrd: &Option<InitrdConfig>,
+    setup_header: &Option<SetupHeader>,
 ) -> Result<()> {
     const KERNEL_BOOT_FLAG_MAGIC: u16 = 0xaa55;
     const KERNEL_HDR_MAGIC: u32 = 0x53726448;
     const KERNEL_LOADER_OTHER: u8 = 0xff;
     const KERNEL_MIN_ALIGNMENT_BYTES: u32 = 0x1000000; // Must be non-zero.
-    let first_addr_past_32bits = GuestAddress(FIRST_ADDR_PAST_32BITS);
-    let end_32bit_gap_start = GuestAddress(get_32bit_gap_start());
-
-    let himem_start = GuestAddress(layout::HIMEM_START);
-
-    // Note that this puts the mptable at the last 1k of Linux's 640k base RAM
-    mptable::setup_mptable(guest_mem, num_cpus).map_err(Error::MpTableSetup)?;
 
     let mut params: boot_params = Default::default();
 
+    // The boot protocol expects the setup header of a bzImage to be copied as is, after which
+    // the boot loader fills in the fields it owns.
+    match *setup_header {
+        Some(hdr) => params.hdr = hdr,
+        None => params.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES,
+    }
     params.hdr.type_of_loader = KERNEL_LOADER_OTHER;
     params.hdr.boot_flag = KERNEL_BOOT_FLAG_MAGIC;
     params.hdr.header = KERNEL_HDR_MAGIC;
     params.hdr.cmd_line_ptr = cmdline_addr.offset() as u32;
     params.hdr.cmdline_size = cmdline_size as u32;
-    params.hdr.kernel_alignment = KERNEL_MIN_ALIGNMENT_BYTES;
+    if let Some(ref initrd_config) = *initrd {
+        params.hdr.ramdisk_image = initrd_config.address.offset() as u32;
+        params.hdr.ramdisk_size = initrd_config.size as u32;
+    }
 
-    add_e820_entry(&mut params, 0, layout::EBDA_START, E820_RAM)?;
+    for (addr, size) in ram_regions(guest_mem) {
+        add_e820_entry(&mut params, addr, size, E820_RAM)?;
+    }
+
+    let zero_page_addr = GuestAddress(layout::ZERO_PAGE_START);
+    guest_mem
+        .checked_offset(zero_page_addr, mem::size_of::<boot_params>())
+        .ok_or(Error::ZeroPagePastRamEnd)?;
+    guest_mem
+        .write_obj_at_addr(params, zero_page_addr)
+        .map_err(|_| Error::ZeroPageSetup)?;
+
+    Ok(())
+}
+
+fn configure_pvh(
+    guest_mem: &GuestMemory,
+    cmdline_addr: GuestAddress,
+    initrd: &Option<InitrdConfig>,
+) -> Result<()> {
+    let mut start_info = hvm_start_info {
+        magic: start_info::XEN_HVM_START_MAGIC_VALUE,
+        version: 1,
+        cmdline_paddr: cmdline_addr.offset() as u64,
+        rsdp_paddr: layout::RSDP_START as u64,
+        memmap_paddr: layout::MEMMAP_START as u64,
+        ..Default::default()
+    };
+
+    // The initrd is handed over as the first and only module.
+    if let Some(ref initrd_config) = *initrd {
+        let modlist_entry = hvm_modlist_entry {
+            paddr: initrd_config.address.offset() as u64,
+            size: initrd_config.size as u64,
+            ..Default::default()
+        };
+        guest_mem
+            .write_obj_at_addr(modlist_entry, GuestAddress(layout::MODLIST_START))
+            .map_err(|_| Error::ModlistSetup)?;
+        start_info.nr_modules = 1;
+        start_info.modlist_paddr = layout::MODLIST_START as u64;
+    }
+
+    let mut memmap_addr = GuestAddress(layout::MEMMAP_START);
+    for (addr, size) in ram_regions(guest_mem) {
+        let memmap_entry = hvm_memmap_table_entry {
+            addr,
+            size,
+            type_: start_info::XEN_HVM_MEMMAP_TYPE_RAM,
+            reserved: 0,
+        };
+        guest_mem
+            .write_obj_at_addr(memmap_entry, memmap_addr)
+            .map_err(|_| Error::MemmapTableSetup)?;
+        memmap_addr = memmap_addr.unchecked_add(mem::size_of::<hvm_memmap_table_entry>());
+        start_info.memmap_entries += 1;
+    }
+
+    guest_mem
+        .write_obj_at_addr(start_info, GuestAddress(layout::PVH_INFO_START))
+        .map_err(|_| Error::StartInfoSetup)?;
+
+    Ok(())
+}
+
+/// Returns the address and size of each range of guest memory the guest may use as RAM.
+fn ram_regions(guest_mem: &GuestMemory) -> Vec<(u64, u64)> {
+    let first_addr_past_32bits = GuestAddress(FIRST_ADDR_PAST_32BITS);
+    let end_32bit_gap_start = GuestAddress(get_32bit_gap_start());
+    let himem_start = GuestAddress(layout::HIMEM_START);
+
+    let mut regions = vec![(0, layout::EBDA_START)];
 
     let mem_end = guest_mem.end_addr();
     if mem_end < end_32bit_gap_start {
-        add_e820_entry(
-            &mut params,
+        regions.push((
             himem_start.offset() as u64,
             mem_end.offset_from(himem_start) as u64,
-            E820_RAM,
-        )?;
+        ));
     } else {
-        add_e820_entry(
-            &mut params,
+        regions.push((
             himem_start.offset() as u64,
             end_32bit_gap_start.offset_from(himem_start) as u64,
-            E820_RAM,
-        )?;
+        ));
         if mem_end > first_addr_past_32bits {
-            add_e820_entry(
-                &mut params,
+            regions.push((
                 first_addr_past_32bits.offset() as u64,
                 mem_end.offset_from(first_addr_past_32bits) as u64,
-                E820_RAM,
-            )?;
+            ));
         }
     }
 
-    let zero_page_addr = GuestAddress(layout::ZERO_PAGE_START);
-    guest_mem
-        .checked_offset(zero_page_addr, mem::size_of::<boot_params>())
-        .ok_or(Error::ZeroPagePastRamEnd)?;
-    guest_mem
-        .write_obj_at_addr(params, zero_page_addr)
-        .map_err(|_| Error::ZeroPageSetup)?;
-
-    Ok(())
+    regions
 }
 
 /// Add an e820 region to the e820 map.
@@ -221,27 +369,142 @@ mod tests {
 
     #[test]
     fn test_system_configuration() {
-        let no_vcpus = 4;
-        let gm = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
-        assert!(configure_system(&gm, GuestAddress(0), 0, 1).is_err());
-
         // Now assigning some memory that falls before the 32bit memory hole.
         let mem_size = 128 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
         let gm = GuestMemory::new(&arch_mem_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            0,
+            &None,
+            &None,
+            BootProtocol::LinuxBoot,
+        )
+        .unwrap();
 
         // Now assigning some memory that is equal to the start of the 32bit memory hole.
         let mem_size = 3328 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
         let gm = GuestMemory::new(&arch_mem_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            0,
+            &None,
+            &None,
+            BootProtocol::LinuxBoot,
+        )
+        .unwrap();
 
         // Now assigning some memory that falls after the 32bit memory hole.
         let mem_size = 3330 << 20;
         let arch_mem_regions = arch_memory_regions(mem_size);
         let gm = GuestMemory::new(&arch_mem_regions).unwrap();
-        configure_system(&gm, GuestAddress(0), 0, no_vcpus).unwrap();
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            0,
+            &None,
+            &None,
+            BootProtocol::LinuxBoot,
+        )
+        .unwrap();
+
+        // The location of the initrd is handed over through the zero page.
+        let initrd = InitrdConfig {
+            address: GuestAddress(0x0800_0000),
+            size: 0x1000,
+        };
+        configure_system(
+            &gm,
+            GuestAddress(0),
+            0,
+            &Some(initrd),
+            &None,
+            BootProtocol::LinuxBoot,
+        )
+        .unwrap();
+        let params: boot_params = gm
+            .read_obj_from_addr(GuestAddress(layout::ZERO_PAGE_START))
+            .unwrap();
+        // Copy the fields out of the packed structure before comparing them.
+        assert_eq!({ params.hdr.ramdisk_image }, 0x0800_0000);
+        assert_eq!({ params.hdr.ramdisk_size }, 0x1000);
+
+        // The setup header of a bzImage is merged into the zero page.
+        let mut setup_header = SetupHeader::default();
+        setup_header.setup_sects = 0x1e;
+        setup_header.kernel_alignment = 0x20_0000;
+        setup_header.cmd_line_ptr = 0xdead;
+        configure_system(
+            &gm,
+            GuestAddress(0x2_0000),
+            0x10,
+            &None,
+            &Some(setup_header),
+            BootProtocol::LinuxBoot,
+        )
+        .unwrap();
+        let params: boot_params = gm
+            .read_obj_from_addr(GuestAddress(layout::ZERO_PAGE_START))
+            .unwrap();
+        assert_eq!({ params.hdr.setup_sects }, 0x1e);
+        assert_eq!({ params.hdr.kernel_alignment }, 0x20_0000);
+        assert_eq!({ params.hdr.cmd_line_ptr }, 0x2_0000);
+        assert_eq!({ params.hdr.cmdline_size }, 0x10);
+        assert_eq!({ params.hdr.type_of_loader }, 0xff);
+    }
+
+    #[test]
+    fn test_setup_mptable() {
+        let gm = GuestMemory::new(&vec![(GuestAddress(0), 0x10000)]).unwrap();
+        assert!(setup_mptable(&gm, 1).is_err());
+
+        let gm = GuestMemory::new(&arch_memory_regions(128 << 20)).unwrap();
+        setup_mptable(&gm, 4).unwrap();
+    }
+
+    #[test]
+    fn test_pvh_configuration() {
+        let gm = GuestMemory::new(&arch_memory_regions(128 << 20)).unwrap();
+        let initrd = InitrdConfig {
+            address: GuestAddress(0x0700_0000),
+            size: 0x1000,
+        };
+        configure_system(
+            &gm,
+            GuestAddress(layout::CMDLINE_START),
+            0x10,
+            &Some(initrd),
+            &None,
+            BootProtocol::PvhBoot,
+        )
+        .unwrap();
+
+        let start_info: hvm_start_info = gm
+            .read_obj_from_addr(GuestAddress(layout::PVH_INFO_START))
+            .unwrap();
+        assert_eq!(start_info.magic, start_info::XEN_HVM_START_MAGIC_VALUE);
+        assert_eq!(start_info.cmdline_paddr, layout::CMDLINE_START as u64);
+        assert_eq!(start_info.rsdp_paddr, layout::RSDP_START as u64);
+        assert_eq!(start_info.nr_modules, 1);
+        assert_eq!(start_info.memmap_entries, 2);
+
+        let modlist_entry: hvm_modlist_entry = gm
+            .read_obj_from_addr(GuestAddress(start_info.modlist_paddr as usize))
+            .unwrap();
+        assert_eq!(modlist_entry.paddr, 0x0700_0000);
+        assert_eq!(modlist_entry.size, 0x1000);
+
+        let memmap_entry: hvm_memmap_table_entry = gm
+            .read_obj_from_addr(GuestAddress(
+                start_info.memmap_paddr as usize + mem::size_of::<hvm_memmap_table_entry>(),
+            ))
+            .unwrap();
+        assert_eq!(memmap_entry.addr, layout::HIMEM_START as u64);
+        assert_eq!(memmap_entry.size, (128 << 20) - layout::HIMEM_START as u64);
+        assert_eq!(memmap_entry.type_, start_info::XEN_HVM_MEMMAP_TYPE_RAM);
     }
 
     #[test]
@@ -17,9 +17,12 @@ use kvm_gen::kvm_sregs;
 use layout;
 use memory_model::{GuestAddress, GuestMemory};
 use sys_util;
+use BootProtocol;
 
 #[derive(Debug)]
 pub enum Error {
+    /// Failed to read MSRs for this CPU.
+    GetModelSpecificRegisters(sys_util::Error),
     /// Failed to get SREGs for this CPU.
     GetStatusRegisters(sys_util::Error),
     /// Failed to set base registers for this CPU.
@@ -65,21 +68,69 @@ pub fn setup_fpu(vcpu: &kvm::VcpuFd) -> Result<()> {
 ///
 /// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
 pub fn setup_msrs(vcpu: &kvm::VcpuFd) -> Result<()> {
-    let entry_vec = create_msr_entries();
-    let vec_size_bytes =
-        mem::size_of::<kvm_msrs>() + (entry_vec.len() * mem::size_of::<kvm_msr_entry>());
-    let vec: Vec<u8> = Vec::with_capacity(vec_size_bytes);
+    set_msrs(vcpu, &create_msr_entries())
+}
+
+/// Reads the Model Specific Registers (MSRs) that need to be preserved across a snapshot of the
+/// given CPU.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+pub fn get_msrs(vcpu: &kvm::VcpuFd) -> Result<Vec<kvm_msr_entry>> {
+    let count = SNAPSHOT_MSRS.len();
+    let mut entry_vec: Vec<kvm_msr_entry> = SNAPSHOT_MSRS
+        .iter()
+        .map(|&index| kvm_msr_entry {
+            index,
+            ..Default::default()
+        })
+        .collect();
+    let mut buffer = msrs_buffer(count);
     let msrs: &mut kvm_msrs = unsafe {
-        // Converting the vector's memory to a struct is unsafe.  Carefully using the read-only
-        // vector to size and set the members ensures no out-of-bounds errors below.
-        &mut *(vec.as_ptr() as *mut kvm_msrs)
+        // The buffer was sized by `msrs_buffer` to hold the header and all the entries.
+        &mut *(buffer.as_mut_ptr() as *mut kvm_msrs)
+    };
+    msrs.nmsrs = count as u32;
+    unsafe {
+        // Mapping the unsized array to a slice is unsafe because the length isn't known.
+        // Providing the length used to create the struct guarantees the entire slice is valid.
+        let entries: &mut [kvm_msr_entry] = msrs.entries.as_mut_slice(count);
+        entries.copy_from_slice(&entry_vec);
+    }
+
+    // KVM stops at the first MSR it does not know about and returns how many it did read.
+    let nmsrs = vcpu
+        .get_msrs(msrs)
+        .map_err(Error::GetModelSpecificRegisters)? as usize;
+    unsafe {
+        // Same reasoning as above; `nmsrs` is never larger than the requested count.
+        entry_vec.copy_from_slice(msrs.entries.as_slice(count));
+    }
+    entry_vec.truncate(nmsrs);
+
+    Ok(entry_vec)
+}
+
+/// Writes a list of Model Specific Registers (MSRs) to the given CPU.
+///
+/// # Arguments
+///
+/// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
+/// * `entry_vec` - MSR indices and their values.
+pub fn set_msrs(vcpu: &kvm::VcpuFd, entry_vec: &[kvm_msr_entry]) -> Result<()> {
+    let mut buffer = msrs_buffer(entry_vec.len());
+    let msrs: &mut kvm_msrs = unsafe {
+        // Converting the buffer's memory to a struct is unsafe.  Carefully using the
+        // buffer to size and set the members ensures no out-of-bounds errors below.
+        &mut *(buffer.as_mut_ptr() as *mut kvm_msrs)
     };
 
     unsafe {
         // Mapping the unsized array to a slice is unsafe because the length isn't known.
         // Providing the length used to create the struct guarantees the entire slice is valid.
         let entries: &mut [kvm_msr_entry] = msrs.entries.as_mut_slice(entry_vec.len());
-        entries.copy_from_slice(&entry_vec);
+        entries.copy_from_slice(entry_vec);
     }
     msrs.nmsrs = entry_vec.len() as u32;
 
@@ -87,22 +138,37 @@ pub fn setup_msrs(vcpu: &kvm::VcpuFd) -> Result<()> {
         .map_err(Error::SetModelSpecificRegisters)
 }
 
+// Allocates zeroed, suitably aligned memory for a `kvm_msrs` header followed by `count` entries.
+fn msrs_buffer(count: usize) -> Vec<u64> {
+    let size_bytes = mem::size_of::<kvm_msrs>() + (count * mem::size_of::<kvm_msr_entry>());
+    vec![0u64; (size_bytes + mem::size_of::<u64>() - 1) / mem::size_of::<u64>()]
+}
+
 /// Configure base registers for a given CPU.
 ///
 /// # Arguments
 ///
 /// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
 /// * `boot_ip` - Starting instruction pointer.
-/// * `boot_sp` - Starting stack pointer.
-/// * `boot_si` - Must point to zero page address per Linux ABI.
-pub fn setup_regs(vcpu: &kvm::VcpuFd, boot_ip: u64, boot_sp: u64, boot_si: u64) -> Result<()> {
-    let regs: kvm_regs = kvm_regs {
-        rflags: 0x0000000000000002u64,
-        rip: boot_ip,
-        rsp: boot_sp,
-        rbp: boot_sp,
-        rsi: boot_si,
-        ..Default::default()
+/// * `boot_prot` - The boot protocol, which decides what the other registers point to.
+pub fn setup_regs(vcpu: &kvm::VcpuFd, boot_ip: u64, boot_prot: BootProtocol) -> Result<()> {
+    let regs: kvm_regs = match boot_prot {
+        // rsi must point to the zero page per Linux ABI.
+        BootProtocol::LinuxBoot => kvm_regs {
+            rflags: 0x0000000000000002u64,
+            rip: boot_ip,
+            rsp: layout::BOOT_STACK_POINTER as u64,
+            rbp: layout::BOOT_STACK_POINTER as u64,
+            rsi: layout::ZERO_PAGE_START as u64,
+            ..Default::default()
+        },
+        // rbx must point to the start info per PVH ABI. There is no stack.
+        BootProtocol::PvhBoot => kvm_regs {
+            rflags: 0x0000000000000002u64,
+            rip: boot_ip,
+            rbx: layout::PVH_INFO_START as u64,
+            ..Default::default()
+        },
     };
 
     vcpu.set_regs(&regs).map_err(Error::SetBaseRegisters)
@@ -114,11 +180,14 @@ pub fn setup_regs(vcpu: &kvm::VcpuFd, boot_ip: u64, boot_sp: u64, boot_si: u64)
 ///
 /// * `mem` - The memory that will be passed to the guest.
 /// * `vcpu` - Structure for the VCPU that holds the VCPU's fd.
-pub fn setup_sregs(mem: &GuestMemory, vcpu: &kvm::VcpuFd) -> Result<()> {
+/// * `boot_prot` - The boot protocol, which decides the CPU mode the guest starts in.
+pub fn setup_sregs(mem: &GuestMemory, vcpu: &kvm::VcpuFd, boot_prot: BootProtocol) -> Result<()> {
     let mut sregs: kvm_sregs = vcpu.get_sregs().map_err(Error::GetStatusRegisters)?;
 
-    configure_segments_and_sregs(mem, &mut sregs)?;
-    setup_page_tables(mem, &mut sregs)?; // TODO(dgreid) - Can this be done once per system instead?
+    configure_segments_and_sregs(mem, &mut sregs, boot_prot)?;
+    if boot_prot == BootProtocol::LinuxBoot {
+        setup_page_tables(mem, &mut sregs)?; // TODO(dgreid) - Can this be done once per system instead?
+    }
 
     vcpu.set_sregs(&sregs).map_err(Error::SetStatusRegisters)
 }
@@ -128,6 +197,29 @@ const BOOT_IDT_OFFSET: usize = 0x520;
 
 const BOOT_GDT_MAX: usize = 4;
 
+// MSRs of the KVM paravirtual clock; these are not part of the Linux `msr-index.h` header.
+const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+
+// The MSRs saved in a snapshot: the ones we configure at boot, plus the ones the guest kernel
+// sets up for itself later on.
+const SNAPSHOT_MSRS: [u32; 14] = [
+    ::msr_index::MSR_IA32_SYSENTER_CS,
+    ::msr_index::MSR_IA32_SYSENTER_ESP,
+    ::msr_index::MSR_IA32_SYSENTER_EIP,
+    ::msr_index::MSR_STAR,
+    ::msr_index::MSR_CSTAR,
+    ::msr_index::MSR_KERNEL_GS_BASE,
+    ::msr_index::MSR_SYSCALL_MASK,
+    ::msr_index::MSR_LSTAR,
+    ::msr_index::MSR_IA32_TSC,
+    ::msr_index::MSR_IA32_MISC_ENABLE,
+    ::msr_index::MSR_IA32_CR_PAT,
+    ::msr_index::MSR_TSC_AUX,
+    MSR_KVM_WALL_CLOCK_NEW,
+    MSR_KVM_SYSTEM_TIME_NEW,
+];
+
 const EFER_LMA: u64 = 0x400;
 const EFER_LME: u64 = 0x100;
 
@@ -155,13 +247,26 @@ fn write_idt_value(val: u64, guest_mem: &GuestMemory) -> Result<()> {
         .map_err(|_| Error::WriteIDT)
 }
 
-fn configure_segments_and_sregs(mem: &GuestMemory, sregs: &mut kvm_sregs) -> Result<()> {
-    let gdt_table: [u64; BOOT_GDT_MAX as usize] = [
-        gdt::gdt_entry(0, 0, 0),            // NULL
-        gdt::gdt_entry(0xa09b, 0, 0xfffff), // CODE
-        gdt::gdt_entry(0xc093, 0, 0xfffff), // DATA
-        gdt::gdt_entry(0x808b, 0, 0xfffff), // TSS
-    ];
+fn configure_segments_and_sregs(
+    mem: &GuestMemory,
+    sregs: &mut kvm_sregs,
+    boot_prot: BootProtocol,
+) -> Result<()> {
+    let gdt_table: [u64; BOOT_GDT_MAX as usize] = match boot_prot {
+        BootProtocol::LinuxBoot => [
+            gdt::gdt_entry(0, 0, 0),            // NULL
+            gdt::gdt_entry(0xa09b, 0, 0xfffff), // CODE
+            gdt::gdt_entry(0xc093, 0, 0xfffff), // DATA
+            gdt::gdt_entry(0x808b, 0, 0xfffff), // TSS
+        ],
+        // PVH requires flat 4GB, 32-bit segments.
+        BootProtocol::PvhBoot => [
+            gdt::gdt_entry(0, 0, 0),            // NULL
+            gdt::gdt_entry(0xc09b, 0, 0xfffff), // CODE
+            gdt::gdt_entry(0xc093, 0, 0xfffff), // DATA
+            gdt::gdt_entry(0x008b, 0, 0x67),    // TSS
+        ],
+    };
 
     let code_seg = gdt::kvm_segment_from_gdt(gdt_table[1], 1);
     let data_seg = gdt::kvm_segment_from_gdt(gdt_table[2], 2);
@@ -184,9 +289,19 @@ fn configure_segments_and_sregs(mem: &GuestMemory, sregs: &mut kvm_sregs) -> Res
     sregs.ss = data_seg;
     sregs.tr = tss_seg;
 
-    /* 64-bit protected mode */
-    sregs.cr0 |= X86_CR0_PE;
-    sregs.efer |= EFER_LME | EFER_LMA;
+    match boot_prot {
+        BootProtocol::LinuxBoot => {
+            /* 64-bit protected mode */
+            sregs.cr0 |= X86_CR0_PE;
+            sregs.efer |= EFER_LME | EFER_LMA;
+        }
+        BootProtocol::PvhBoot => {
+            /* 32-bit protected mode, without paging */
+            sregs.cr0 = X86_CR0_PE;
+            sregs.cr4 = 0;
+            sregs.efer = 0;
+        }
+    }
 
     Ok(())
 }
@@ -298,7 +413,7 @@ mod tests {
     fn segments_and_sregs() {
         let mut sregs: kvm_sregs = Default::default();
         let gm = create_guest_mem();
-        configure_segments_and_sregs(&gm, &mut sregs).unwrap();
+        configure_segments_and_sregs(&gm, &mut sregs, BootProtocol::LinuxBoot).unwrap();
 
         assert_eq!(0x0, read_u64(&gm, BOOT_GDT_OFFSET));
         assert_eq!(0xaf9b000000ffff, read_u64(&gm, BOOT_GDT_OFFSET + 8));
@@ -317,6 +432,21 @@ mod tests {
         assert_eq!(0, sregs.tr.avl);
         assert_eq!(X86_CR0_PE, sregs.cr0);
         assert_eq!(EFER_LME | EFER_LMA, sregs.efer);
+
+        // PVH starts in 32-bit protected mode.
+        let mut sregs: kvm_sregs = Default::default();
+        configure_segments_and_sregs(&gm, &mut sregs, BootProtocol::PvhBoot).unwrap();
+
+        assert_eq!(0xcf9b000000ffff, read_u64(&gm, BOOT_GDT_OFFSET + 8));
+        assert_eq!(0xcf93000000ffff, read_u64(&gm, BOOT_GDT_OFFSET + 16));
+        assert_eq!(0x8b0000000067, read_u64(&gm, BOOT_GDT_OFFSET + 24));
+
+        assert_eq!(1, sregs.cs.db);
+        assert_eq!(0, sregs.cs.l);
+        assert_eq!(0x67, sregs.tr.limit);
+        assert_eq!(X86_CR0_PE, sregs.cr0);
+        assert_eq!(0, sregs.cr4);
+        assert_eq!(0, sregs.efer);
     }
 
     #[test]
@@ -402,6 +532,42 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_get_set_msrs() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        setup_msrs(&vcpu).unwrap();
+
+        let mut saved_msrs = get_msrs(&vcpu).unwrap();
+        assert!(!saved_msrs.is_empty());
+        let misc_enable = saved_msrs
+            .iter()
+            .find(|entry| entry.index == ::msr_index::MSR_IA32_MISC_ENABLE)
+            .unwrap();
+        assert_ne!(
+            misc_enable.data & ::msr_index::MSR_IA32_MISC_ENABLE_FAST_STRING as u64,
+            0
+        );
+
+        // Write back a modified value and check that it sticks.
+        for entry in saved_msrs.iter_mut() {
+            if entry.index == ::msr_index::MSR_KERNEL_GS_BASE {
+                entry.data = 0x1000;
+            }
+        }
+        set_msrs(&vcpu, &saved_msrs).unwrap();
+        let restored_msrs = get_msrs(&vcpu).unwrap();
+        // The TSC keeps counting after it is written, so it can't be compared.
+        let without_tsc = |msrs: &[kvm_msr_entry]| -> Vec<kvm_msr_entry> {
+            msrs.iter()
+                .filter(|entry| entry.index != ::msr_index::MSR_IA32_TSC)
+                .cloned()
+                .collect()
+        };
+        assert_eq!(without_tsc(&saved_msrs), without_tsc(&restored_msrs));
+    }
+
     #[test]
     fn test_setup_regs() {
         let kvm = Kvm::new().unwrap();
@@ -411,19 +577,25 @@ mod tests {
         let expected_regs: kvm_regs = kvm_regs {
             rflags: 0x0000000000000002u64,
             rip: 1,
-            rsp: 2,
-            rbp: 2,
-            rsi: 3,
+            rsp: layout::BOOT_STACK_POINTER as u64,
+            rbp: layout::BOOT_STACK_POINTER as u64,
+            rsi: layout::ZERO_PAGE_START as u64,
             ..Default::default()
         };
 
-        setup_regs(
-            &vcpu,
-            expected_regs.rip,
-            expected_regs.rsp,
-            expected_regs.rsi,
-        )
-        .unwrap();
+        setup_regs(&vcpu, expected_regs.rip, BootProtocol::LinuxBoot).unwrap();
+
+        let actual_regs: kvm_regs = vcpu.get_regs().unwrap();
+        assert_eq!(actual_regs, expected_regs);
+
+        let expected_regs: kvm_regs = kvm_regs {
+            rflags: 0x0000000000000002u64,
+            rip: 1,
+            rbx: layout::PVH_INFO_START as u64,
+            ..Default::default()
+        };
+
+        setup_regs(&vcpu, expected_regs.rip, BootProtocol::PvhBoot).unwrap();
 
         let actual_regs: kvm_regs = vcpu.get_regs().unwrap();
         assert_eq!(actual_regs, expected_regs);
@@ -437,10 +609,10 @@ mod tests {
 
         let mut expected_sregs: kvm_sregs = vcpu.get_sregs().unwrap();
         let gm = create_guest_mem();
-        configure_segments_and_sregs(&gm, &mut expected_sregs).unwrap();
+        configure_segments_and_sregs(&gm, &mut expected_sregs, BootProtocol::LinuxBoot).unwrap();
         setup_page_tables(&gm, &mut expected_sregs).unwrap();
 
-        setup_sregs(&gm, &vcpu).unwrap();
+        setup_sregs(&gm, &vcpu, BootProtocol::LinuxBoot).unwrap();
         let actual_sregs: kvm_sregs = vcpu.get_sregs().unwrap();
         assert_eq!(expected_sregs, actual_sregs);
     }
@@ -0,0 +1,57 @@
+// Copyright 2018 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0
+
+/*
+ * Structures of the PVH boot ABI.
+ * From upstream xen include/public/arch-x86/hvm/start_info.h
+ */
+
+// "xEn3" with the 0x80 bit of the "E" set.
+pub const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+pub const XEN_HVM_MEMMAP_TYPE_RAM: u32 = 1;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct hvm_start_info {
+    pub magic: u32,
+    pub version: u32,
+    pub flags: u32,
+    pub nr_modules: u32,
+    pub modlist_paddr: u64,
+    pub cmdline_paddr: u64,
+    pub rsdp_paddr: u64,
+    pub memmap_paddr: u64,
+    pub memmap_entries: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct hvm_modlist_entry {
+    pub paddr: u64,
+    pub size: u64,
+    pub cmdline_paddr: u64,
+    pub reserved: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub struct hvm_memmap_table_entry {
+    pub addr: u64,
+    pub size: u64,
+    pub type_: u32,
+    pub reserved: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(mem::size_of::<hvm_start_info>(), 56);
+        assert_eq!(mem::size_of::<hvm_modlist_entry>(), 32);
+        assert_eq!(mem::size_of::<hvm_memmap_table_entry>(), 24);
+    }
+}